@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use matrix_qrcode::{ParsedQrData, QrVerificationData};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(decoded) = QrVerificationData::from_bytes(data) {
+        // Everything we accept needs to be encoded back into the same bytes.
+        assert_eq!(decoded.to_bytes().expect("Can't encode decoded QR code data"), data);
+    }
+
+    if let Ok(ParsedQrData::Unknown(unknown)) = QrVerificationData::from_bytes_lenient(data) {
+        let _ = (unknown.version(), unknown.mode());
+    }
+});
@@ -22,6 +22,18 @@ pub enum DecodingError {
     #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
     #[error(transparent)]
     Qr(#[from] rqrr::DeQRError),
+    /// The raw image buffer is too small for the given image dimensions.
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    #[error("the buffer of {len} bytes is too small for a {width}x{height} image")]
+    BufferSize {
+        /// The width of the image.
+        width: u32,
+        /// The height of the image.
+        height: u32,
+        /// The length of the buffer.
+        len: usize,
+    },
     /// The QR code data is missing the mandatory Matrix header.
     #[error("the decoded QR code is missing the Matrix header")]
     Header,
@@ -55,6 +67,18 @@ pub enum EncodingError {
     /// Error decoding the identity keys as base64.
     #[error(transparent)]
     Base64(#[from] base64::DecodeError),
+    /// The data doesn't fit into the largest QR code with the given error
+    /// correction level, the flow id is likely too long.
+    #[error(
+        "the payload of {len} bytes doesn't fit into a QR code with the {ec_level:?} error \
+         correction level"
+    )]
+    TooLarge {
+        /// The length of the payload in bytes.
+        len: usize,
+        /// The error correction level that was used.
+        ec_level: qrcode::EcLevel,
+    },
     /// Error encoding the given flow id, the flow id is too large.
     #[error("The verification flow id length can't be converted into a u16: {0}")]
     FlowId(#[from] std::num::TryFromIntError),
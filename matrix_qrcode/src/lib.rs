@@ -43,6 +43,7 @@
 )]
 
 mod error;
+mod render;
 mod types;
 mod utils;
 
@@ -51,11 +52,13 @@ pub use error::{DecodingError, EncodingError};
 #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
 pub use image;
 pub use qrcode;
+pub use render::{QrStyle, TerminalStyle};
 #[cfg(feature = "decode_image")]
 #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
 pub use rqrr;
 pub use types::{
-    QrVerificationData, SelfVerificationData, SelfVerificationNoMasterKey, VerificationData,
+    ParsedQrData, QrVerificationData, SelfVerificationData, SelfVerificationNoMasterKey,
+    UnknownQrData, VerificationData,
 };
 
 #[cfg(test)]
@@ -64,13 +67,17 @@ mod test {
     use std::{convert::TryFrom, io::Cursor};
 
     #[cfg(feature = "decode_image")]
-    use image::{ImageFormat, Luma};
+    use image::{imageops::overlay, DynamicImage, ImageBuffer, ImageFormat, Luma, Rgba};
+    use qrcode::EcLevel;
     #[cfg(feature = "decode_image")]
     use qrcode::QrCode;
 
     #[cfg(feature = "decode_image")]
-    use crate::utils::decode_qr;
-    use crate::{DecodingError, QrVerificationData};
+    use crate::{utils::decode_qr, QrStyle};
+    use crate::{
+        DecodingError, EncodingError, ParsedQrData, QrVerificationData, SelfVerificationData,
+        TerminalStyle,
+    };
 
     #[cfg(feature = "decode_image")]
     static VERIFICATION: &[u8; 4277] = include_bytes!("../data/verification.png");
@@ -169,6 +176,179 @@ mod test {
         assert!(matches!(result, Err(DecodingError::Header)))
     }
 
+    #[test]
+    #[cfg(feature = "decode_image")]
+    fn render_cycle() {
+        let image = Cursor::new(VERIFICATION);
+        let image = image::load(image, ImageFormat::Png).unwrap();
+        let result = QrVerificationData::from_image(image).unwrap();
+
+        let rendered = result
+            .to_image(3, Rgba([0x20, 0x40, 0x80, 0xff]), Rgba([0xf0, 0xf0, 0xe0, 0xff]))
+            .unwrap();
+        let width = (result.to_qr_code().unwrap().width() as u32 + 8) * 3;
+
+        assert_eq!(rendered.dimensions(), (width, width));
+        assert_eq!(rendered.get_pixel(0, 0), &Rgba([0xf0, 0xf0, 0xe0, 0xff]));
+
+        let second_result = QrVerificationData::from_image(DynamicImage::ImageRgba8(rendered));
+        assert_eq!(result, second_result.unwrap());
+
+        let style = QrStyle { module_size: 2, quiet_zone: 1, ..Default::default() };
+        let svg = result.to_svg_with_style(&style).unwrap();
+        let width = (result.to_qr_code().unwrap().width() + 2) * 2;
+
+        assert!(svg.contains(&format!("viewBox=\"0 0 {} {}\"", width, width)));
+        assert!(svg.contains("fill=\"#000000\""));
+        assert!(svg.ends_with("</svg>"));
+    }
+
+    #[test]
+    #[cfg(feature = "decode_image")]
+    fn decode_raw_frames() {
+        let image = Cursor::new(VERIFICATION);
+        let image = image::load(image, ImageFormat::Png).unwrap();
+        let result = QrVerificationData::from_image(image.clone()).unwrap();
+
+        let rgb_result = QrVerificationData::from_rgb(image.to_rgb8()).unwrap();
+        assert_eq!(result, rgb_result);
+
+        let luma = image.to_luma8();
+        let (width, height) = luma.dimensions();
+        let mut frame = luma.into_raw();
+
+        assert!(matches!(
+            QrVerificationData::from_luma_bytes(&frame, width, height + 1),
+            Err(DecodingError::BufferSize { .. })
+        ));
+
+        // Trailing chroma planes are ignored.
+        frame.extend(vec![0x80; frame.len() / 2]);
+        let frame_result = QrVerificationData::from_luma_bytes(&frame, width, height).unwrap();
+        assert_eq!(result, frame_result);
+
+        let self_verification = Cursor::new(SELF_VERIFICATION);
+        let self_verification = image::load(self_verification, ImageFormat::Png).unwrap();
+        let self_result = QrVerificationData::from_image(self_verification).unwrap();
+
+        let first = result.to_qr_code().unwrap().render::<Luma<u8>>().build();
+        let second = self_result.to_qr_code().unwrap().render::<Luma<u8>>().build();
+
+        let mut both = ImageBuffer::from_pixel(
+            first.width() + second.width(),
+            first.height().max(second.height()),
+            Luma([0xff]),
+        );
+        overlay(&mut both, &first, 0, 0);
+        overlay(&mut both, &second, first.width(), 0);
+
+        let all = QrVerificationData::all_from_luma(both).unwrap();
+
+        assert_eq!(all.len(), 2);
+        assert!(all.contains(&result));
+        assert!(all.contains(&self_result));
+    }
+
+    #[test]
+    fn terminal_rendering() {
+        let data = b"MATRIX\
+                     \x02\x02\x00\x07\
+                     FLOW_ID\
+                     AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                     BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                     SHARED_SECRET";
+        let result = QrVerificationData::from_bytes(data).unwrap();
+        let size = result.to_qr_code().unwrap().width() + 4;
+
+        let ascii = result.to_terminal_string(TerminalStyle::Ascii).unwrap();
+        let lines: Vec<&str> = ascii.lines().collect();
+
+        assert_eq!(lines.len(), size);
+        assert!(lines.iter().all(|l| l.len() == 2 * size));
+        assert_eq!(lines[0], "#".repeat(2 * size));
+        // The top left finder pattern starts after the quiet zone.
+        assert!(lines[2].starts_with("####              "));
+
+        let blocks = result.to_terminal_string(TerminalStyle::HalfBlocks).unwrap();
+        let lines: Vec<&str> = blocks.lines().collect();
+
+        assert_eq!(lines.len(), (size + 1) / 2);
+        assert!(lines.iter().all(|l| l.chars().count() == size));
+        assert_eq!(lines[0], "\u{2588}".repeat(size));
+    }
+
+    #[test]
+    fn decode_lenient() {
+        let data = b"MATRIX\
+                     \x02\x02\x00\x07\
+                     FLOW_ID\
+                     AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                     BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                     SHARED_SECRET";
+        let result = QrVerificationData::from_bytes_lenient(data).unwrap();
+        assert_eq!(result, ParsedQrData::Known(QrVerificationData::from_bytes(data).unwrap()));
+
+        let data = b"MATRIX\
+                     \x03\x07\x00\x07\
+                     FLOW_ID\
+                     AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                     BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                     SHARED_SECRET";
+        assert!(matches!(QrVerificationData::from_bytes(data), Err(DecodingError::Version(3))));
+
+        let unknown = match QrVerificationData::from_bytes_lenient(data).unwrap() {
+            ParsedQrData::Unknown(u) => u,
+            ParsedQrData::Known(_) => panic!("Parsed a QR code of an unknown version"),
+        };
+
+        assert_eq!(unknown.version(), 3);
+        assert_eq!(unknown.mode(), 7);
+        assert_eq!(unknown.flow_id(), b"FLOW_ID");
+        assert_eq!(unknown.first_key(), "QUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUE");
+
+        // Payloads that don't have the layout of a verification QR code still
+        // fail with the version error.
+        let result = QrVerificationData::from_bytes_lenient(b"MATRIX\x03\x02\x00\x07");
+        assert!(matches!(result, Err(DecodingError::Version(3))));
+    }
+
+    #[test]
+    fn encode_long_flow_ids() {
+        let data = b"MATRIX\
+                     \x02\x01\x00\x07\
+                     FLOW_ID\
+                     AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                     BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                     SHARED_SECRET";
+        let result = QrVerificationData::from_bytes(data).unwrap();
+        let width = result.to_qr_code().unwrap().width();
+
+        let long = |len: usize| -> QrVerificationData {
+            SelfVerificationData::new(
+                "a".repeat(len),
+                result.first_key().to_owned(),
+                result.second_key().to_owned(),
+                result.secret().to_owned(),
+            )
+            .into()
+        };
+
+        // A flow id that doesn't fit into the default QR code size makes the
+        // QR code larger.
+        let code = long(300).to_qr_code().unwrap();
+        assert!(code.width() > width);
+        assert_eq!(code.error_correction_level(), EcLevel::L);
+
+        let code = long(300).to_qr_code_with_ec_level(EcLevel::H).unwrap();
+        assert_eq!(code.error_correction_level(), EcLevel::H);
+
+        long(2000).to_qr_code().unwrap();
+        assert!(matches!(
+            long(2000).to_qr_code_with_ec_level(EcLevel::H),
+            Err(EncodingError::TooLarge { ec_level: EcLevel::H, .. })
+        ));
+    }
+
     #[test]
     fn decode_invalid_header() {
         let data = b"NonMatrixCode";
@@ -0,0 +1,194 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "decode_image")]
+use image::{Rgba, RgbaImage};
+use qrcode::{Color, QrCode};
+
+/// The look of a rendered QR code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QrStyle {
+    /// The size of a single module of the QR code, in pixels, at least one
+    /// pixel is used.
+    pub module_size: u32,
+    /// The width of the empty border around the QR code, in modules.
+    ///
+    /// Scanners need a quiet zone of at least 4 modules if the QR code isn't
+    /// surrounded by a light area already.
+    pub quiet_zone: u32,
+    /// The RGBA color of the dark modules.
+    pub foreground: [u8; 4],
+    /// The RGBA color of the light modules and of the quiet zone.
+    pub background: [u8; 4],
+}
+
+impl Default for QrStyle {
+    fn default() -> Self {
+        Self {
+            module_size: 8,
+            quiet_zone: 4,
+            foreground: [0x00, 0x00, 0x00, 0xff],
+            background: [0xff, 0xff, 0xff, 0xff],
+        }
+    }
+}
+
+impl QrStyle {
+    fn module_size(&self) -> u32 {
+        self.module_size.max(1)
+    }
+
+    /// The width and height of the rendered QR code in pixels.
+    fn size(&self, code: &QrCode) -> u32 {
+        (code.width() as u32 + 2 * self.quiet_zone) * self.module_size()
+    }
+
+    /// Is the module at the given pixel dark.
+    #[cfg(feature = "decode_image")]
+    fn is_dark(&self, code: &QrCode, x: u32, y: u32) -> bool {
+        let width = code.width() as u32;
+        let x = (x / self.module_size()).checked_sub(self.quiet_zone);
+        let y = (y / self.module_size()).checked_sub(self.quiet_zone);
+
+        match (x, y) {
+            (Some(x), Some(y)) if x < width && y < width => {
+                code[(x as usize, y as usize)] == Color::Dark
+            }
+            _ => false,
+        }
+    }
+}
+
+/// How a QR code is drawn as text in a terminal.
+///
+/// Scanners expect dark modules on a light background, the light modules are
+/// drawn as filled characters and the dark ones as spaces. This looks right
+/// on terminals with a light text color on a dark background, the default of
+/// most terminals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminalStyle {
+    /// Unicode half blocks, every character contains two modules on top of
+    /// each other. This is the most compact style.
+    HalfBlocks,
+    /// Two `#` characters or spaces per module, for terminals or fonts
+    /// without Unicode support.
+    Ascii,
+}
+
+/// The width of the quiet zone around a QR code drawn in a terminal, in
+/// modules. The terminal background doesn't count as quiet zone, but a
+/// smaller one is enough to scan it from a screen.
+const TERMINAL_QUIET_ZONE: usize = 2;
+
+/// Render the given QR code as text that can be printed in a terminal.
+pub(crate) fn to_terminal_string(code: &QrCode, style: TerminalStyle) -> String {
+    let width = code.width();
+    let size = width + 2 * TERMINAL_QUIET_ZONE;
+
+    let is_light = |x: usize, y: usize| {
+        let (x, y) = match (x.checked_sub(TERMINAL_QUIET_ZONE), y.checked_sub(TERMINAL_QUIET_ZONE))
+        {
+            (Some(x), Some(y)) if x < width && y < width => (x, y),
+            _ => return true,
+        };
+
+        code[(x, y)] == Color::Light
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+
+    match style {
+        TerminalStyle::HalfBlocks => {
+            for y in (0..size).step_by(2) {
+                let line = (0..size)
+                    .map(|x| match (is_light(x, y), y + 1 < size && is_light(x, y + 1)) {
+                        (true, true) => '\u{2588}',
+                        (true, false) => '\u{2580}',
+                        (false, true) => '\u{2584}',
+                        (false, false) => ' ',
+                    })
+                    .collect();
+
+                lines.push(line);
+            }
+        }
+        TerminalStyle::Ascii => {
+            for y in 0..size {
+                let line = (0..size).map(|x| if is_light(x, y) { "##" } else { "  " }).collect();
+                lines.push(line);
+            }
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Render the given QR code as a SVG document.
+pub(crate) fn to_svg(code: &QrCode, style: &QrStyle) -> String {
+    let size = style.size(code);
+    let module = style.module_size();
+    let mut path = String::new();
+
+    for y in 0..code.width() {
+        for x in 0..code.width() {
+            if code[(x, y)] == Color::Dark {
+                let left = (x as u32 + style.quiet_zone) * module;
+                let top = (y as u32 + style.quiet_zone) * module;
+                path.push_str(&format!("M{},{}h{}v{}h-{}z", left, top, module, module, module));
+            }
+        }
+    }
+
+    format!(
+        "<?xml version=\"1.0\" standalone=\"yes\"?>\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"{size}\" \
+         height=\"{size}\" viewBox=\"0 0 {size} {size}\" shape-rendering=\"crispEdges\">\
+         <rect x=\"0\" y=\"0\" width=\"{size}\" height=\"{size}\" {background}/>\
+         <path d=\"{path}\" {foreground}/>\
+         </svg>",
+        size = size,
+        background = svg_fill(style.background),
+        path = path,
+        foreground = svg_fill(style.foreground),
+    )
+}
+
+/// The SVG attributes to fill a shape with the given RGBA color.
+fn svg_fill([r, g, b, a]: [u8; 4]) -> String {
+    if a == 0xff {
+        format!("fill=\"#{:02x}{:02x}{:02x}\"", r, g, b)
+    } else {
+        format!(
+            "fill=\"#{:02x}{:02x}{:02x}\" fill-opacity=\"{:.3}\"",
+            r,
+            g,
+            b,
+            f64::from(a) / 255.0
+        )
+    }
+}
+
+/// Render the given QR code as a RGBA image.
+#[cfg(feature = "decode_image")]
+pub(crate) fn to_image(code: &QrCode, style: &QrStyle) -> RgbaImage {
+    let size = style.size(code);
+
+    RgbaImage::from_fn(size, size, |x, y| {
+        if style.is_dark(code, x, y) {
+            Rgba(style.foreground)
+        } else {
+            Rgba(style.background)
+        }
+    })
+}
@@ -19,16 +19,20 @@ use std::{
 
 use byteorder::{BigEndian, ReadBytesExt};
 #[cfg(feature = "decode_image")]
-use image::{DynamicImage, ImageBuffer, Luma};
-use qrcode::QrCode;
+use image::{DynamicImage, ImageBuffer, Luma, RgbImage, Rgba, RgbaImage};
+use qrcode::{EcLevel, QrCode};
 use ruma_identifiers::EventId;
 
 #[cfg(feature = "decode_image")]
-#[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
-use crate::utils::decode_qr;
+use crate::render::to_image;
+#[cfg(feature = "decode_image")]
+use crate::utils::{decode_qr, decode_qr_all, luma_from_bytes};
 use crate::{
     error::{DecodingError, EncodingError},
-    utils::{base_64_encode, to_bytes, to_qr_code, HEADER, MAX_MODE, MIN_SECRET_LEN, VERSION},
+    render::{to_svg, to_terminal_string, QrStyle, TerminalStyle},
+    utils::{
+        base_64_encode, encode_qr, to_bytes, to_qr_code, HEADER, MAX_MODE, MIN_SECRET_LEN, VERSION,
+    },
 };
 
 /// An enum representing the different modes a QR verification can be in.
@@ -133,6 +137,98 @@ impl QrVerificationData {
         Self::decode(image)
     }
 
+    /// Decode and parse a RGB image of a QR code into a `QrVerificationData`
+    ///
+    /// The image will be converted into a grey scale image before decoding is
+    /// attempted
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The RGB image containing the QR code.
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn from_rgb(image: RgbImage) -> Result<Self, DecodingError> {
+        Self::from_image(DynamicImage::ImageRgb8(image))
+    }
+
+    /// Decode and parse a raw grey scale buffer of a QR code into a
+    /// `QrVerificationData`
+    ///
+    /// This is useful to decode the frames of a camera directly, the buffer
+    /// contains one byte per pixel, row by row, e.g. the Y plane of a YUV
+    /// frame. Bytes after the first `width * height` bytes, like the chroma
+    /// planes of a NV21 frame, are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The grey scale pixels of the image.
+    ///
+    /// * `width` - The width of the image in pixels.
+    ///
+    /// * `height` - The height of the image in pixels.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_qrcode::{QrVerificationData, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// # let (frame, width, height) = (vec![0u8; 640 * 480 * 3 / 2], 640, 480);
+    /// let result = QrVerificationData::from_luma_bytes(&frame, width, height)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn from_luma_bytes(bytes: &[u8], width: u32, height: u32) -> Result<Self, DecodingError> {
+        Self::decode(luma_from_bytes(bytes, width, height)?)
+    }
+
+    /// Decode and parse all the QR codes in a grey scale image that contain
+    /// verification data.
+    ///
+    /// Unlike [`from_luma()`](#method.from_luma), which returns the first QR
+    /// code that was found, this returns every QR code that contains valid
+    /// verification data. Returns an error if none was found.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The grey scale image containing the QR codes.
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn all_from_luma(
+        image: ImageBuffer<Luma<u8>, Vec<u8>>,
+    ) -> Result<Vec<Self>, DecodingError> {
+        let mut error = None;
+        let mut found = Vec::new();
+
+        for decoded in decode_qr_all(image)? {
+            match Self::decode_bytes(decoded) {
+                Ok(data) => found.push(data),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match error {
+            Some(e) if found.is_empty() => Err(e),
+            _ => Ok(found),
+        }
+    }
+
+    /// Decode and parse all the QR codes in a raw grey scale buffer that
+    /// contain verification data.
+    ///
+    /// See [`from_luma_bytes()`](#method.from_luma_bytes) for the format of
+    /// the buffer and [`all_from_luma()`](#method.all_from_luma) for the
+    /// returned QR codes.
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn all_from_luma_bytes(
+        bytes: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<Self>, DecodingError> {
+        Self::all_from_luma(luma_from_bytes(bytes, width, height)?)
+    }
+
     /// Parse the decoded payload of a QR code in byte slice form as a
     /// `QrVerificationData`
     ///
@@ -162,11 +258,75 @@ impl QrVerificationData {
         Self::decode_bytes(bytes)
     }
 
+    /// Parse the decoded payload of a QR code as a `QrVerificationData`,
+    /// accepting payloads of unknown versions or modes.
+    ///
+    /// Payloads that use a version or mode this crate doesn't support, but
+    /// otherwise have the layout of a Matrix verification QR code, are
+    /// returned as [`ParsedQrData::Unknown`]. Clients can use this to ask the
+    /// user to update instead of showing a decoding error.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The raw bytes of a decoded QR code.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{ParsedQrData, QrVerificationData, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// let data = b"MATRIX\
+    ///              \x03\x02\x00\x07\
+    ///              FLOW_ID\
+    ///              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    ///              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    ///              SHARED_SECRET";
+    ///
+    /// match QrVerificationData::from_bytes_lenient(data)? {
+    ///     ParsedQrData::Known(data) => println!("Verifying with {}", data.flow_id()),
+    ///     ParsedQrData::Unknown(data) => {
+    ///         println!("Please update to scan QR codes of version {}", data.version())
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_bytes_lenient(bytes: impl AsRef<[u8]>) -> Result<ParsedQrData, DecodingError> {
+        let bytes = bytes.as_ref();
+
+        match Self::decode_bytes(bytes) {
+            Ok(data) => Ok(ParsedQrData::Known(data)),
+            Err(e @ DecodingError::Version(_)) | Err(e @ DecodingError::Mode(_)) => {
+                UnknownQrData::decode_bytes(bytes).map(ParsedQrData::Unknown).map_err(|_| e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decode and parse a grey scale image of a QR code, accepting payloads
+    /// of unknown versions or modes.
+    ///
+    /// See [`from_bytes_lenient()`](#method.from_bytes_lenient) for the
+    /// handling of unknown payloads.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The grey scale image containing the QR code.
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn from_luma_lenient(
+        image: ImageBuffer<Luma<u8>, Vec<u8>>,
+    ) -> Result<ParsedQrData, DecodingError> {
+        Self::from_bytes_lenient(decode_qr(image)?)
+    }
+
     /// Encode the `QrVerificationData` into a `QrCode`.
     ///
     /// This method turns the `QrVerificationData` into a QR code that can be
     /// rendered and presented to be scanned.
     ///
+    /// The lowest error correction level and the smallest QR code that can
+    /// hold the data are used.
+    ///
     /// The encoding can fail if the data doesn't fit into a QR code or if the
     /// identity keys that should be encoded into the QR code are not valid
     /// base64.
@@ -195,6 +355,116 @@ impl QrVerificationData {
         }
     }
 
+    /// Encode the `QrVerificationData` into a `QrCode` using the given error
+    /// correction level.
+    ///
+    /// [`to_qr_code()`](#method.to_qr_code) uses the lowest error correction
+    /// level, a higher level makes the QR code easier to scan if it's
+    /// partially covered or damaged, e.g. when printed, but also larger.
+    ///
+    /// The smallest QR code that can hold the data is used, the encoding fails
+    /// with [`EncodingError::TooLarge`] if the data, usually because of a long
+    /// flow id, doesn't fit into any QR code with the given error correction
+    /// level.
+    pub fn to_qr_code_with_ec_level(&self, ec_level: EcLevel) -> Result<QrCode, EncodingError> {
+        encode_qr(&self.to_bytes()?, ec_level)
+    }
+
+    /// Render the `QrVerificationData` as text that can be printed in a
+    /// terminal.
+    ///
+    /// # Arguments
+    ///
+    /// * `style` - The characters that should be used to draw the QR code.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{QrVerificationData, DecodingError, TerminalStyle};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// # let data = b"MATRIX\
+    /// #              \x02\x02\x00\x07\
+    /// #              FLOW_ID\
+    /// #              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    /// #              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    /// #              SHARED_SECRET";
+    /// let result = QrVerificationData::from_bytes(data)?;
+    /// println!("{}", result.to_terminal_string(TerminalStyle::HalfBlocks).unwrap());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_terminal_string(&self, style: TerminalStyle) -> Result<String, EncodingError> {
+        Ok(to_terminal_string(&self.to_qr_code()?, style))
+    }
+
+    /// Render the `QrVerificationData` as a SVG document.
+    ///
+    /// The QR code is drawn black on white with the default [`QrStyle`], use
+    /// [`to_svg_with_style()`](#method.to_svg_with_style) to change its look.
+    pub fn to_svg(&self) -> Result<String, EncodingError> {
+        self.to_svg_with_style(&QrStyle::default())
+    }
+
+    /// Render the `QrVerificationData` as a SVG document with the given
+    /// style.
+    pub fn to_svg_with_style(&self, style: &QrStyle) -> Result<String, EncodingError> {
+        Ok(to_svg(&self.to_qr_code()?, style))
+    }
+
+    /// Render the `QrVerificationData` as a RGBA image.
+    ///
+    /// The QR code is surrounded by a quiet zone of 4 modules in the
+    /// background color.
+    ///
+    /// # Arguments
+    ///
+    /// * `scale` - The size of a single module of the QR code, in pixels.
+    ///
+    /// * `foreground` - The color of the dark modules.
+    ///
+    /// * `background` - The color of the light modules and the quiet zone.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{image::Rgba, QrVerificationData, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// # let data = b"MATRIX\
+    /// #              \x02\x02\x00\x07\
+    /// #              FLOW_ID\
+    /// #              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    /// #              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    /// #              SHARED_SECRET";
+    /// let result = QrVerificationData::from_bytes(data)?;
+    /// let image = result
+    ///     .to_image(4, Rgba([0x1d, 0x1d, 0x1d, 0xff]), Rgba([0xff, 0xff, 0xff, 0xff]))
+    ///     .unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn to_image(
+        &self,
+        scale: u32,
+        foreground: Rgba<u8>,
+        background: Rgba<u8>,
+    ) -> Result<RgbaImage, EncodingError> {
+        let style = QrStyle {
+            module_size: scale,
+            foreground: foreground.0,
+            background: background.0,
+            ..Default::default()
+        };
+
+        self.to_image_with_style(&style)
+    }
+
+    /// Render the `QrVerificationData` as a RGBA image with the given style.
+    #[cfg(feature = "decode_image")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    pub fn to_image_with_style(&self, style: &QrStyle) -> Result<RgbaImage, EncodingError> {
+        Ok(to_image(&self.to_qr_code()?, style))
+    }
+
     /// Encode the `QrVerificationData` into a vector of bytes that can be
     /// encoded as a QR code.
     ///
@@ -259,8 +529,6 @@ impl QrVerificationData {
         let mut decoded = Cursor::new(bytes);
 
         let mut header = [0u8; 6];
-        let mut first_key = [0u8; 32];
-        let mut second_key = [0u8; 32];
 
         decoded.read_exact(&mut header)?;
         let version = decoded.read_u8()?;
@@ -274,20 +542,7 @@ impl QrVerificationData {
             return Err(DecodingError::Mode(mode));
         }
 
-        let flow_id_len = decoded.read_u16::<BigEndian>()?;
-        let mut flow_id = vec![0; flow_id_len.into()];
-
-        decoded.read_exact(&mut flow_id)?;
-        decoded.read_exact(&mut first_key)?;
-        decoded.read_exact(&mut second_key)?;
-
-        let mut shared_secret = Vec::new();
-
-        decoded.read_to_end(&mut shared_secret)?;
-
-        if shared_secret.len() < MIN_SECRET_LEN {
-            return Err(DecodingError::SharedSecret(shared_secret.len()));
-        }
+        let (flow_id, first_key, second_key, shared_secret) = read_payload(&mut decoded)?;
 
         QrVerificationData::new(mode, flow_id, first_key, second_key, shared_secret)
     }
@@ -367,6 +622,114 @@ impl QrVerificationData {
     }
 }
 
+/// Read the part of a QR code payload that follows the version and mode: the
+/// flow id, the two keys and the shared secret.
+fn read_payload(
+    decoded: &mut impl Read,
+) -> Result<(Vec<u8>, [u8; 32], [u8; 32], Vec<u8>), DecodingError> {
+    let mut first_key = [0u8; 32];
+    let mut second_key = [0u8; 32];
+
+    let flow_id_len = decoded.read_u16::<BigEndian>()?;
+    let mut flow_id = vec![0; flow_id_len.into()];
+
+    decoded.read_exact(&mut flow_id)?;
+    decoded.read_exact(&mut first_key)?;
+    decoded.read_exact(&mut second_key)?;
+
+    let mut shared_secret = Vec::new();
+
+    decoded.read_to_end(&mut shared_secret)?;
+
+    if shared_secret.len() < MIN_SECRET_LEN {
+        return Err(DecodingError::SharedSecret(shared_secret.len()));
+    }
+
+    Ok((flow_id, first_key, second_key, shared_secret))
+}
+
+/// The result of parsing a QR code with
+/// [`QrVerificationData::from_bytes_lenient()`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParsedQrData {
+    /// The QR code uses a version and mode this crate supports.
+    Known(QrVerificationData),
+    /// The QR code is a Matrix verification QR code, but uses a version or
+    /// mode this crate doesn't support.
+    Unknown(UnknownQrData),
+}
+
+/// The data of a Matrix verification QR code that uses an unknown version or
+/// mode.
+///
+/// The data can't be used for verification, it's meant to tell the user that
+/// the QR code was created by a newer client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownQrData {
+    version: u8,
+    mode: u8,
+    flow_id: Vec<u8>,
+    first_key: String,
+    second_key: String,
+    shared_secret: String,
+}
+
+impl UnknownQrData {
+    fn decode_bytes(bytes: &[u8]) -> Result<Self, DecodingError> {
+        let mut decoded = Cursor::new(bytes);
+        let mut header = [0u8; 6];
+
+        decoded.read_exact(&mut header)?;
+
+        if header != HEADER {
+            return Err(DecodingError::Header);
+        }
+
+        let version = decoded.read_u8()?;
+        let mode = decoded.read_u8()?;
+        let (flow_id, first_key, second_key, shared_secret) = read_payload(&mut decoded)?;
+
+        Ok(Self {
+            version,
+            mode,
+            flow_id,
+            first_key: base_64_encode(&first_key),
+            second_key: base_64_encode(&second_key),
+            shared_secret: base_64_encode(&shared_secret),
+        })
+    }
+
+    /// Get the version of the QR code.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Get the verification mode of the QR code.
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    /// Get the raw flow id of the QR code, it isn't necessarily valid UTF-8.
+    pub fn flow_id(&self) -> &[u8] {
+        &self.flow_id
+    }
+
+    /// Get the first key of the QR code, encoded as unpadded base64.
+    pub fn first_key(&self) -> &str {
+        &self.first_key
+    }
+
+    /// Get the second key of the QR code, encoded as unpadded base64.
+    pub fn second_key(&self) -> &str {
+        &self.second_key
+    }
+
+    /// Get the shared secret of the QR code, encoded as unpadded base64.
+    pub fn secret(&self) -> &str {
+        &self.shared_secret
+    }
+}
+
 /// The non-encoded data for the first mode of QR code verification.
 ///
 /// This mode is used for verification between two users using their master
@@ -27,6 +27,10 @@ pub(crate) const HEADER: &[u8] = b"MATRIX";
 pub(crate) const VERSION: u8 = 0x2;
 pub(crate) const MAX_MODE: u8 = 0x2;
 pub(crate) const MIN_SECRET_LEN: usize = 8;
+/// The smallest QR code version that is used, verification QR codes with
+/// short flow ids all have the same size this way.
+const MIN_QR_VERSION: i16 = 7;
+const MAX_QR_VERSION: i16 = 40;
 
 pub(crate) fn base_64_encode(data: &[u8]) -> String {
     encode_config(data, STANDARD_NO_PAD)
@@ -74,6 +78,15 @@ pub(crate) fn to_qr_code(
 ) -> Result<QrCode, EncodingError> {
     let data = to_bytes(mode, flow_id, first_key, second_key, shared_secret)?;
 
+    encode_qr(&data, EcLevel::L)
+}
+
+/// Encode the given payload into a QR code using the given error correction
+/// level.
+///
+/// The smallest QR code version, but at least [`MIN_QR_VERSION`], that can
+/// hold the payload is used.
+pub(crate) fn encode_qr(data: &[u8], ec_level: EcLevel) -> Result<QrCode, EncodingError> {
     // Mobile clients seem to have trouble decoding the QR code that gets
     // generated by `QrCode::new()` it seems to add a couple of data segments
     // with different data modes/types. The parsers seem to assume a single
@@ -83,19 +96,35 @@ pub(crate) fn to_qr_code(
     // We make sure that there isn't an ECI bit set and we just push the bytes,
     // this seems to help since the decoder doesn't assume an encoding and
     // treats everything as raw bytes.
-    let mut bits = Bits::new(Version::Normal(7));
-    bits.push_byte_data(&data)?;
-    bits.push_terminator(EcLevel::L)?;
+    for version in (MIN_QR_VERSION..=MAX_QR_VERSION).map(Version::Normal) {
+        let mut bits = Bits::new(version);
 
-    Ok(QrCode::with_bits(bits, EcLevel::L)?)
+        if bits.push_byte_data(data).is_ok() && bits.push_terminator(ec_level).is_ok() {
+            return Ok(QrCode::with_bits(bits, ec_level)?);
+        }
+    }
+
+    Err(EncodingError::TooLarge { len: data.len(), ec_level })
 }
 
 #[cfg(feature = "decode_image")]
 pub(crate) fn decode_qr(image: ImageBuffer<Luma<u8>, Vec<u8>>) -> Result<Vec<u8>, DecodingError> {
+    decode_qr_all(image).map(|mut decoded| decoded.swap_remove(0))
+}
+
+/// Decode all the QR codes in the given image that contain the Matrix header.
+///
+/// Returns an error if no such QR code was found, the returned list is never
+/// empty.
+#[cfg(feature = "decode_image")]
+pub(crate) fn decode_qr_all(
+    image: ImageBuffer<Luma<u8>, Vec<u8>>,
+) -> Result<Vec<Vec<u8>>, DecodingError> {
     let mut image = rqrr::PreparedImage::prepare(image);
     let grids = image.detect_grids();
 
     let mut error = None;
+    let mut found = Vec::new();
 
     for grid in grids {
         let mut decoded = Vec::new();
@@ -103,12 +132,32 @@ pub(crate) fn decode_qr(image: ImageBuffer<Luma<u8>, Vec<u8>>) -> Result<Vec<u8>
         match grid.decode_to(&mut decoded) {
             Ok(_) => {
                 if decoded.starts_with(HEADER) {
-                    return Ok(decoded);
+                    found.push(decoded);
                 }
             }
             Err(e) => error = Some(e),
         }
     }
 
-    Err(error.map(|e| e.into()).unwrap_or_else(|| DecodingError::Header))
+    if found.is_empty() {
+        Err(error.map(|e| e.into()).unwrap_or_else(|| DecodingError::Header))
+    } else {
+        Ok(found)
+    }
+}
+
+/// Create a grey scale image out of the first `width * height` bytes of the
+/// given buffer.
+#[cfg(feature = "decode_image")]
+pub(crate) fn luma_from_bytes(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<ImageBuffer<Luma<u8>, Vec<u8>>, DecodingError> {
+    let error = || DecodingError::BufferSize { width, height, len: bytes.len() };
+
+    let len = (width as usize).checked_mul(height as usize).ok_or_else(error)?;
+    let pixels = bytes.get(..len).ok_or_else(error)?;
+
+    ImageBuffer::from_raw(width, height, pixels.to_vec()).ok_or_else(error)
 }
@@ -1,13 +1,8 @@
 use std::{env, process::exit};
 
 use matrix_sdk::{
-    self, async_trait,
-    events::{
-        room::message::{MessageEventContent, MessageType, TextMessageEventContent},
-        AnyMessageEventContent, SyncMessageEvent,
-    },
-    room::Room,
-    Client, ClientConfig, EventHandler, SyncSettings,
+    events::room::message::{MessageType, TextMessageEventContent},
+    prelude::*,
 };
 use url::Url;
 
@@ -38,9 +33,7 @@ impl EventHandler for CommandBot {
             };
 
             if msg_body.contains("!party") {
-                let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
-                    "🎉🎊🥳 let's PARTY!! 🥳🎊🎉",
-                ));
+                let content = RoomMessage::text("🎉🎊🥳 let's PARTY!! 🥳🎊🎉");
 
                 println!("sending");
 
@@ -11,7 +11,7 @@ use matrix_sdk::{
     self,
     events::{room::message::MessageType, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent},
     identifiers::UserId,
-    verification::{SasVerification, Verification},
+    verification::{SasVerification, TerminalStyle, Verification},
     Client, LoopCtrl, SyncSettings,
 };
 use url::Url;
@@ -78,7 +78,8 @@ async fn login(
             let client = &client_ref;
             let initial = &initial_ref;
 
-            for event in response.to_device.events.iter().filter_map(|e| e.deserialize().ok()) {
+            for event in response.to_device.events.iter().filter_map(|e| e.event.deserialize().ok())
+            {
                 match event {
                     AnyToDeviceEvent::KeyVerificationStart(e) => {
                         if let Some(Verification::SasV1(sas)) =
@@ -136,6 +137,14 @@ async fn login(
                                             .accept()
                                             .await
                                             .expect("Can't accept verification request");
+
+                                        if let Ok(Some(qr)) = request.generate_qr_code().await {
+                                            println!(
+                                                "Scan the QR code or compare the emoji:\n{}",
+                                                qr.to_terminal_string(TerminalStyle::HalfBlocks)
+                                                    .expect("Can't render the QR code")
+                                            );
+                                        }
                                     }
                                 }
                                 AnySyncMessageEvent::KeyVerificationKey(e) => {
@@ -0,0 +1,388 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! VoIP call signaling using the `m.call.*` events.
+//!
+//! The SDK only takes care of the signaling part of a call, the media
+//! connection itself needs to be established by the client, usually using
+//! WebRTC. A call is modeled as a [PendingCall], which tracks the lifecycle of
+//! the call and sends out the `m.call.*` events with the correct `call_id`,
+//! `party_id` and `version` fields.
+//!
+//! Incoming call events are delivered through the
+//! [EventHandler](crate::EventHandler), they can be passed to the
+//! [PendingCall] they belong to:
+//!
+//! * `m.call.invite` - [PendingCall::from_invite()] creates a new incoming
+//! call, or [PendingCall::resolve_glare()] if we're calling the same room at
+//! the same time.
+//! * `m.call.answer` - [PendingCall::receive_answer()].
+//! * `m.call.hangup` - [PendingCall::receive_hangup()].
+//!
+//! **Note**: The events are sent as version `0` of the VoIP events, which
+//! every client understands, with the `party_id` of later versions added. The
+//! party id identifies our side of the call, so clients that support it can
+//! tell the devices of a user apart.
+
+use std::{
+    sync::{Arc, RwLock as SyncRwLock},
+    time::Duration,
+};
+
+use matrix_sdk_common::uuid::Uuid;
+use ruma::{
+    events::{
+        call::{
+            answer::AnswerEventContent,
+            candidates::{Candidate, CandidatesEventContent},
+            hangup::{HangupEventContent, Reason},
+            invite::InviteEventContent,
+            SessionDescription,
+        },
+        custom::CustomEventContent,
+        AnyMessageEventContent, EventContent, SyncMessageEvent,
+    },
+    MilliSecondsSinceUnixEpoch, UInt,
+};
+use serde_json::Value as JsonValue;
+
+use crate::{room::Joined, Result};
+
+/// The version of the VoIP events the SDK sends out.
+const VOIP_VERSION: u32 = 0;
+
+/// The lifetime of a call invite if none is given, one minute.
+const DEFAULT_INVITE_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The direction of a call, seen from our own side.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallDirection {
+    /// We started the call.
+    Outgoing,
+    /// The call was started by another room member.
+    Incoming,
+}
+
+/// The state of a [PendingCall].
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallState {
+    /// The outgoing call was created but the invite wasn't sent out yet.
+    Preparing,
+    /// The invite of the outgoing call was sent out, we're waiting for an
+    /// answer.
+    InviteSent,
+    /// The incoming call wasn't answered yet.
+    Ringing,
+    /// The call was answered, the media connection can be established.
+    Connected,
+    /// The call has ended, either side hung up or the invite expired.
+    Ended(Option<Reason>),
+}
+
+/// The outcome of the glare resolution between our own outgoing call and an
+/// incoming call in the same room.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GlareResolution {
+    /// There is no glare, the incoming invite belongs to a different call that
+    /// can be handled normally.
+    NoGlare,
+    /// Our own call lost and was ended, the incoming call should be answered
+    /// on behalf of the user.
+    AcceptIncoming,
+    /// Our own call won, the incoming invite should be ignored.
+    KeepOutgoing,
+}
+
+/// A VoIP call in a room.
+///
+/// The call tracks its [CallState] as the `m.call.*` events are sent and
+/// received, it's cheap to clone and all clones share the same state.
+#[derive(Clone, Debug)]
+pub struct PendingCall {
+    room: Joined,
+    call_id: Arc<str>,
+    party_id: Arc<str>,
+    direction: CallDirection,
+    state: Arc<SyncRwLock<CallState>>,
+}
+
+impl PendingCall {
+    /// Create a new outgoing call in the given room.
+    ///
+    /// The call will be in the [CallState::Preparing] state until the invite
+    /// is sent out using [invite()](#method.invite).
+    pub fn new(room: Joined) -> Self {
+        Self {
+            room,
+            call_id: Uuid::new_v4().to_string().into(),
+            party_id: Self::new_party_id(),
+            direction: CallDirection::Outgoing,
+            state: Arc::new(SyncRwLock::new(CallState::Preparing)),
+        }
+    }
+
+    /// Create a new incoming call from the given `m.call.invite` event.
+    ///
+    /// Returns `None` if the invite was sent by our own user or if the invite
+    /// already expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room the invite was received in.
+    ///
+    /// * `event` - The received `m.call.invite` event.
+    pub fn from_invite(room: Joined, event: &SyncMessageEvent<InviteEventContent>) -> Option<Self> {
+        if &event.sender == room.own_user_id() || Self::is_invite_expired(event) {
+            None
+        } else {
+            Some(Self {
+                room,
+                call_id: event.content.call_id.as_str().into(),
+                party_id: Self::new_party_id(),
+                direction: CallDirection::Incoming,
+                state: Arc::new(SyncRwLock::new(CallState::Ringing)),
+            })
+        }
+    }
+
+    fn new_party_id() -> Arc<str> {
+        Uuid::new_v4().to_simple().to_string().into()
+    }
+
+    fn is_invite_expired(event: &SyncMessageEvent<InviteEventContent>) -> bool {
+        let sent: u64 = event.origin_server_ts.get().into();
+        let lifetime: u64 = event.content.lifetime.into();
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+
+        sent.saturating_add(lifetime) < now
+    }
+
+    /// The unique id of the call.
+    pub fn call_id(&self) -> &str {
+        &self.call_id
+    }
+
+    /// The id of our own side of the call, it's sent with every event of the
+    /// call.
+    pub fn party_id(&self) -> &str {
+        &self.party_id
+    }
+
+    /// The room the call takes place in.
+    pub fn room(&self) -> &Joined {
+        &self.room
+    }
+
+    /// Did we start the call or was it started by somebody else.
+    pub fn direction(&self) -> CallDirection {
+        self.direction
+    }
+
+    /// The current state of the call.
+    pub fn state(&self) -> CallState {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Has the call ended.
+    pub fn is_ended(&self) -> bool {
+        matches!(self.state(), CallState::Ended(_))
+    }
+
+    fn set_state(&self, state: CallState) {
+        *self.state.write().unwrap() = state;
+    }
+
+    /// Send the given event of the call, with our party id added.
+    async fn send(&self, content: AnyMessageEventContent) -> Result<()> {
+        let event_type = content.event_type().to_owned();
+
+        let mut data = match serde_json::to_value(&content)? {
+            JsonValue::Object(data) => data,
+            _ => unreachable!("The content of a call event is always a JSON object"),
+        };
+        data.insert("party_id".to_owned(), self.party_id.to_string().into());
+
+        let content = CustomEventContent { event_type, data: data.into_iter().collect() };
+        self.room.send(AnyMessageEventContent::Custom(content), None).await?;
+
+        Ok(())
+    }
+
+    /// Send out the `m.call.invite` event of our outgoing call.
+    ///
+    /// Does nothing if the call isn't an outgoing call in the
+    /// [CallState::Preparing] state.
+    ///
+    /// # Arguments
+    ///
+    /// * `offer` - The SDP offer of the call.
+    ///
+    /// * `lifetime` - The time after which the invite expires if it wasn't
+    ///   answered, defaults to one minute.
+    pub async fn invite(
+        &self,
+        offer: SessionDescription,
+        lifetime: Option<Duration>,
+    ) -> Result<()> {
+        if self.state() != CallState::Preparing {
+            return Ok(());
+        }
+
+        let lifetime = lifetime.unwrap_or(DEFAULT_INVITE_LIFETIME);
+        let lifetime = UInt::new_saturating(lifetime.as_millis() as u64);
+
+        let content =
+            InviteEventContent::new(self.call_id.to_string(), lifetime, offer, VOIP_VERSION.into());
+        self.send(AnyMessageEventContent::CallInvite(content)).await?;
+        self.set_state(CallState::InviteSent);
+
+        Ok(())
+    }
+
+    /// Answer the incoming call.
+    ///
+    /// Does nothing if the call isn't an incoming call in the
+    /// [CallState::Ringing] state.
+    ///
+    /// # Arguments
+    ///
+    /// * `answer` - The SDP answer to the offer of the invite.
+    pub async fn answer(&self, answer: SessionDescription) -> Result<()> {
+        if self.state() != CallState::Ringing {
+            return Ok(());
+        }
+
+        let content =
+            AnswerEventContent::new(answer, self.call_id.to_string(), VOIP_VERSION.into());
+        self.send(AnyMessageEventContent::CallAnswer(content)).await?;
+        self.set_state(CallState::Connected);
+
+        Ok(())
+    }
+
+    /// Send ICE candidates to the other side of the call.
+    ///
+    /// # Arguments
+    ///
+    /// * `candidates` - The ICE candidates that should be sent.
+    pub async fn send_candidates(&self, candidates: Vec<Candidate>) -> Result<()> {
+        if self.is_ended() || candidates.is_empty() {
+            return Ok(());
+        }
+
+        let content =
+            CandidatesEventContent::new(self.call_id.to_string(), candidates, VOIP_VERSION.into());
+        self.send(AnyMessageEventContent::CallCandidates(content)).await
+    }
+
+    /// Hang up the call.
+    ///
+    /// This is used to reject an incoming call as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - The reason for hanging up, if any.
+    pub async fn hangup(&self, reason: Option<Reason>) -> Result<()> {
+        if self.is_ended() {
+            return Ok(());
+        }
+
+        // An outgoing call that wasn't sent out yet can just be dropped.
+        if self.state() != CallState::Preparing {
+            let mut content =
+                HangupEventContent::new(self.call_id.to_string(), VOIP_VERSION.into());
+            content.reason = reason.clone();
+
+            self.send(AnyMessageEventContent::CallHangup(content)).await?;
+        }
+
+        self.set_state(CallState::Ended(reason));
+
+        Ok(())
+    }
+
+    /// Receive an `m.call.answer` event for this call.
+    ///
+    /// Returns true if the answer belongs to our outgoing call and the call is
+    /// now connected.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the received answer.
+    pub fn receive_answer(&self, content: &AnswerEventContent) -> bool {
+        if content.call_id == *self.call_id && self.state() == CallState::InviteSent {
+            self.set_state(CallState::Connected);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Receive an `m.call.hangup` event for this call.
+    ///
+    /// Returns true if the hangup belongs to this call and the call has ended.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the received hangup.
+    pub fn receive_hangup(&self, content: &HangupEventContent) -> bool {
+        if content.call_id == *self.call_id && !self.is_ended() {
+            self.set_state(CallState::Ended(content.reason.clone()));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Resolve glare between our own outgoing call and an incoming invite.
+    ///
+    /// Glare happens if both sides of a call start calling each other at the
+    /// same time, it's resolved as described in the spec:
+    ///
+    /// * If our invite wasn't sent out yet, our call is dropped and the
+    ///   incoming call should be accepted.
+    /// * If our invite was already sent out, the call with the
+    ///   lexicographically lower call id wins, the other call gets hung up.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The incoming `m.call.invite` event.
+    pub async fn resolve_glare(
+        &self,
+        event: &SyncMessageEvent<InviteEventContent>,
+    ) -> Result<GlareResolution> {
+        if self.direction != CallDirection::Outgoing
+            || event.sender == *self.room.own_user_id()
+            || event.content.call_id == *self.call_id
+        {
+            return Ok(GlareResolution::NoGlare);
+        }
+
+        match self.state() {
+            CallState::Preparing => {
+                self.set_state(CallState::Ended(None));
+                Ok(GlareResolution::AcceptIncoming)
+            }
+            CallState::InviteSent => {
+                if event.content.call_id.as_str() < &*self.call_id {
+                    self.hangup(None).await?;
+                    Ok(GlareResolution::AcceptIncoming)
+                } else {
+                    Ok(GlareResolution::KeepOutgoing)
+                }
+            }
+            _ => Ok(GlareResolution::NoGlare),
+        }
+    }
+}
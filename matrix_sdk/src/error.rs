@@ -14,26 +14,30 @@
 
 //! Error conditions.
 
-use std::io::Error as IoError;
+use std::{io::Error as IoError, time::Duration};
 
 use http::StatusCode;
 #[cfg(feature = "encryption")]
-use matrix_sdk_base::crypto::{store::CryptoStoreError, DecryptorError};
+use matrix_sdk_base::crypto::{store::CryptoStoreError, DecryptorError, KeyExportError};
 use matrix_sdk_base::{Error as MatrixError, StoreError};
+use matrix_sdk_common::{retry::Retryable, uuid::Uuid};
 use reqwest::Error as ReqwestError;
 use ruma::{
     api::{
         client::{
+            error::ErrorKind,
             r0::uiaa::{UiaaInfo, UiaaResponse as UiaaError},
             Error as RumaClientApiError,
         },
         error::{FromHttpResponseError, IntoHttpError, MatrixError as RumaApiError, ServerError},
     },
-    identifiers::Error as IdentifierError,
+    identifiers::{Error as IdentifierError, EventId, RoomVersionId, UserId},
 };
 use serde_json::Error as JsonError;
 use thiserror::Error;
-use url::ParseError as UrlParseError;
+use url::{ParseError as UrlParseError, Url};
+
+use crate::validation::ValidationError;
 
 /// Result type of the rust-sdk.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -81,7 +85,8 @@ pub enum HttpError {
     #[error(transparent)]
     UiaaError(#[from] FromHttpResponseError<UiaaError>),
 
-    /// The server returned a status code that should be retried.
+    /// The server returned a status code that should be retried, without a
+    /// Matrix error in the body of the response.
     #[error("Server returned an error {0}")]
     Server(StatusCode),
 
@@ -92,6 +97,120 @@ pub enum HttpError {
     /// Tried to send a request without `user_id` in the `Session`
     #[error("missing user_id in session")]
     UserIdRequired,
+
+    /// The server didn't present any of the pinned certificates, the
+    /// connection was aborted before the request was sent.
+    #[error("certificate pinning failed: {0}")]
+    CertificatePinning(String),
+}
+
+/// The broad category of an error, for generic error handling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The homeserver couldn't be reached, e.g. the connection failed or
+    /// timed out.
+    Network,
+    /// The homeserver responded with an error or with a response that
+    /// couldn't be parsed.
+    Server,
+    /// The SDK was used incorrectly, e.g. a request that needs authentication
+    /// was sent before logging in, or got invalid input. Retrying won't help.
+    Client,
+    /// An error occurred in the end-to-end encryption layer.
+    Crypto,
+    /// An error occurred while reading from or writing to a store or the
+    /// filesystem.
+    Store,
+}
+
+impl HttpError {
+    /// Get the broad category of this error.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            HttpError::Reqwest(e) if e.is_builder() => ErrorCategory::Client,
+            HttpError::Reqwest(_) => ErrorCategory::Network,
+            HttpError::Api(_)
+            | HttpError::ClientApi(_)
+            | HttpError::UiaaError(_)
+            | HttpError::Server(_) => ErrorCategory::Server,
+            HttpError::AuthenticationRequired
+            | HttpError::ForcedAuthenticationWithoutAccessToken
+            | HttpError::NotClientRequest
+            | HttpError::IntoHttp(_)
+            | HttpError::UnableToCloneRequest
+            | HttpError::UserIdRequired => ErrorCategory::Client,
+            HttpError::CertificatePinning(_) => ErrorCategory::Network,
+        }
+    }
+
+    /// Try to destructure the error into a client-server API error the
+    /// homeserver responded with.
+    pub fn client_api_error(&self) -> Option<&RumaClientApiError> {
+        if let HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))) = self {
+            Some(e)
+        } else {
+            None
+        }
+    }
+
+    /// Get the HTTP status code the homeserver responded with, if this error
+    /// is a response of the homeserver.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            HttpError::Server(status) => Some(*status),
+            HttpError::Reqwest(e) => e.status(),
+            _ => self.client_api_error().map(|e| e.status_code),
+        }
+    }
+
+    /// Get the time the homeserver asked us to wait before the request is
+    /// retried, if we're being rate limited.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.client_api_error().map(|e| &e.kind) {
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => *retry_after_ms,
+            _ => None,
+        }
+    }
+
+    /// Is this error caused by missing or invalid authentication.
+    ///
+    /// The user either needs to log in (again), or, if the homeserver
+    /// requires [user-interactive authentication], the request needs to be
+    /// sent again with authentication data.
+    ///
+    /// [user-interactive authentication]: https://matrix.org/docs/spec/client_server/r0.6.1#user-interactive-authentication-api
+    pub fn is_auth_error(&self) -> bool {
+        match self {
+            HttpError::AuthenticationRequired
+            | HttpError::ForcedAuthenticationWithoutAccessToken
+            | HttpError::UserIdRequired
+            | HttpError::UiaaError(FromHttpResponseError::Http(ServerError::Known(
+                UiaaError::AuthResponse(_),
+            ))) => true,
+            _ => matches!(
+                self.client_api_error().map(|e| &e.kind),
+                Some(ErrorKind::UnknownToken { .. }) | Some(ErrorKind::MissingToken)
+            ),
+        }
+    }
+}
+
+impl Retryable for HttpError {
+    /// Connection errors and server errors, including rate limiting, are
+    /// considered to be transient.
+    fn is_retryable(&self) -> bool {
+        match self {
+            HttpError::Reqwest(e) => !e.is_builder(),
+            HttpError::Server(_) => true,
+            _ => {
+                self.retry_after().is_some()
+                    || self.client_api_error().map_or(false, |e| {
+                        e.status_code.is_server_error()
+                            || e.status_code == StatusCode::TOO_MANY_REQUESTS
+                    })
+            }
+        }
+    }
 }
 
 /// Internal representation of errors.
@@ -128,6 +247,11 @@ pub enum Error {
     #[error(transparent)]
     DecryptorError(#[from] DecryptorError),
 
+    /// An error occurred while reading or decrypting a key export.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    KeyExport(#[from] KeyExportError),
+
     /// An error occurred in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
@@ -139,9 +263,146 @@ pub enum Error {
     /// An error encountered when trying to parse a url.
     #[error(transparent)]
     Url(#[from] UrlParseError),
+
+    /// A user needs to join a room but no client acting as the user was
+    /// given.
+    #[error("no client acting as {0} was given to join the room")]
+    UserClientRequired(UserId),
+
+    /// The client was shut down.
+    #[error("the client was shut down")]
+    ShutDown,
+
+    /// The sync was stopped using
+    /// [`Client::stop_sync()`](crate::Client::stop_sync).
+    #[error("the sync was stopped")]
+    SyncStopped,
+
+    /// The request needs an identity server but none was set.
+    #[error("no identity server was set")]
+    IdentityServerRequired,
+
+    /// The identity server doesn't support hashing the addresses of a lookup.
+    #[error("the identity server doesn't support hashed lookups")]
+    HashedLookupUnsupported,
+
+    /// Only the sender of an event can edit it.
+    #[error("the event {0} can only be edited by its sender")]
+    EditNotAllowed(EventId),
+
+    /// The homeserver doesn't support the room version.
+    #[error("the homeserver doesn't support the room version {0}")]
+    UnsupportedRoomVersion(RoomVersionId),
+
+    /// A widget answered a request with an error or didn't answer it.
+    #[error("the widget didn't answer the request: {0}")]
+    InvalidWidgetResponse(String),
+
+    /// An event that should be sent exceeds the limits of the spec.
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+
+    /// The homeserver is an onion service but no proxy was set to reach it.
+    #[error("the homeserver {0} is an onion service, it can only be reached through a proxy")]
+    ProxyRequired(Url),
+
+    /// No event with the given transaction id is queued or was sent.
+    #[error("no event with the transaction id {0} is queued or was sent")]
+    UnknownTransaction(Uuid),
 }
 
 impl Error {
+    /// Get the broad category of this error.
+    ///
+    /// Together with [`is_retryable()`](#method.is_retryable) and
+    /// [`is_auth_error()`](#method.is_auth_error) this allows applications to
+    /// handle errors generically, e.g. to show a connection warning for
+    /// network errors, ask the user to log in again for authentication errors
+    /// and to report client errors as bugs.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::Http(e) => e.category(),
+            Error::Io(_) | Error::StateStore(_) => ErrorCategory::Store,
+            Error::MatrixError(e) => match e {
+                MatrixError::StateStore(_) | MatrixError::IoError(_) => ErrorCategory::Store,
+                #[cfg(feature = "encryption")]
+                MatrixError::CryptoStore(_)
+                | MatrixError::OlmError(_)
+                | MatrixError::MegolmError(_) => ErrorCategory::Crypto,
+                #[cfg(feature = "passphrase_strength")]
+                MatrixError::WeakPassphrase(_) => ErrorCategory::Client,
+                MatrixError::AuthenticationRequired
+                | MatrixError::SerdeJson(_)
+                | MatrixError::SyncTask(_) => ErrorCategory::Client,
+            },
+            #[cfg(feature = "encryption")]
+            Error::CryptoStoreError(_) | Error::DecryptorError(_) | Error::KeyExport(_) => {
+                ErrorCategory::Crypto
+            }
+            Error::AuthenticationRequired
+            | Error::SerdeJson(_)
+            | Error::Identifier(_)
+            | Error::Url(_)
+            | Error::UserClientRequired(_)
+            | Error::ShutDown
+            | Error::SyncStopped
+            | Error::IdentityServerRequired
+            | Error::HashedLookupUnsupported
+            | Error::EditNotAllowed(_)
+            | Error::UnsupportedRoomVersion(_)
+            | Error::InvalidWidgetResponse(_)
+            | Error::Validation(_)
+            | Error::ProxyRequired(_)
+            | Error::UnknownTransaction(_) => ErrorCategory::Client,
+        }
+    }
+
+    /// Is the failure transient, so that the operation may succeed if it's
+    /// retried.
+    ///
+    /// Network errors, server errors and rate limiting are considered to be
+    /// transient, the [`retry_after()`](#method.retry_after) method returns
+    /// how long the homeserver wants us to wait if we're being rate limited.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Http(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Is this error caused by missing or invalid authentication.
+    ///
+    /// This is the case if a request that needs authentication was sent
+    /// before logging in, if the access token is invalid, e.g. because the
+    /// user logged out, or if the request requires user-interactive
+    /// authentication, see [`uiaa_response()`](#method.uiaa_response).
+    pub fn is_auth_error(&self) -> bool {
+        match self {
+            Error::Http(e) => e.is_auth_error(),
+            Error::AuthenticationRequired
+            | Error::MatrixError(MatrixError::AuthenticationRequired) => true,
+            _ => false,
+        }
+    }
+
+    /// Get the time the homeserver asked us to wait before the request is
+    /// retried, if we're being rate limited.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Http(e) => e.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// Get the HTTP status code the homeserver responded with, if this error
+    /// is a response of the homeserver.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            Error::Http(e) => e.status_code(),
+            _ => None,
+        }
+    }
+
     /// Try to destructure the error into an universal interactive auth info.
     ///
     /// Some requests require universal interactive auth, doing such a request
@@ -163,6 +424,34 @@ impl Error {
             None
         }
     }
+
+    /// Try to destructure the error into a client-server API error the
+    /// homeserver responded with.
+    ///
+    /// This is a convenience method to find out why the homeserver rejected a
+    /// request, e.g. a kick or ban that failed because we don't have a high
+    /// enough power level in the room will return an error with the
+    /// `ErrorKind::Forbidden` kind.
+    pub fn client_api_error(&self) -> Option<&RumaClientApiError> {
+        match self {
+            Error::Http(e) => e.client_api_error(),
+            _ => None,
+        }
+    }
+
+    /// Get the kind of the client-server API error the homeserver responded
+    /// with, if this error is one.
+    ///
+    /// See [`client_api_error()`](#method.client_api_error) for more info.
+    pub fn client_api_error_kind(&self) -> Option<&ErrorKind> {
+        self.client_api_error().map(|e| &e.kind)
+    }
+}
+
+impl Retryable for Error {
+    fn is_retryable(&self) -> bool {
+        Error::is_retryable(self)
+    }
 }
 
 impl From<ReqwestError> for Error {
@@ -50,7 +50,9 @@ use ruma::{
 };
 use serde_json::value::RawValue as RawJsonValue;
 
-use crate::{deserialized_responses::SyncResponse, room::Room, Client};
+#[cfg(feature = "encryption")]
+use crate::DeviceListChange;
+use crate::{deserialized_responses::SyncResponse, room::Room, Client, PowerLevelsChange};
 
 pub(crate) struct Handler {
     pub(crate) inner: Box<dyn EventHandler>,
@@ -92,9 +94,7 @@ impl Handler {
                     self.handle_state_event(room.clone(), &event).await;
                 }
 
-                for event in
-                    room_info.timeline.events.iter().filter_map(|e| e.event.deserialize().ok())
-                {
+                for event in room_info.timeline.events.iter().filter_map(|e| e.deserialize().ok()) {
                     self.handle_timeline_event(room.clone(), &event).await;
                 }
             }
@@ -112,9 +112,7 @@ impl Handler {
                     self.handle_state_event(room.clone(), &event).await;
                 }
 
-                for event in
-                    room_info.timeline.events.iter().filter_map(|e| e.event.deserialize().ok())
-                {
+                for event in room_info.timeline.events.iter().filter_map(|e| e.deserialize().ok()) {
                     self.handle_timeline_event(room.clone(), &event).await;
                 }
             }
@@ -153,7 +151,10 @@ impl Handler {
                 }
                 AnySyncStateEvent::RoomAliases(e) => self.on_room_aliases(room, e).await,
                 AnySyncStateEvent::RoomAvatar(e) => self.on_room_avatar(room, e).await,
-                AnySyncStateEvent::RoomPowerLevels(e) => self.on_room_power_levels(room, e).await,
+                AnySyncStateEvent::RoomPowerLevels(e) => {
+                    self.on_room_power_levels(room.clone(), e).await;
+                    self.handle_power_levels_change(room, e).await
+                }
                 AnySyncStateEvent::RoomTombstone(e) => self.on_room_tombstone(room, e).await,
                 AnySyncStateEvent::RoomJoinRules(e) => self.on_room_join_rules(room, e).await,
                 AnySyncStateEvent::Custom(e) => {
@@ -193,7 +194,8 @@ impl Handler {
             AnySyncStateEvent::RoomAliases(aliases) => self.on_state_aliases(room, aliases).await,
             AnySyncStateEvent::RoomAvatar(avatar) => self.on_state_avatar(room, avatar).await,
             AnySyncStateEvent::RoomPowerLevels(power) => {
-                self.on_state_power_levels(room, power).await
+                self.on_state_power_levels(room.clone(), power).await;
+                self.handle_power_levels_change(room, power).await
             }
             AnySyncStateEvent::RoomJoinRules(rules) => self.on_state_join_rules(room, rules).await,
             AnySyncStateEvent::RoomTombstone(tomb) => {
@@ -207,6 +209,20 @@ impl Handler {
         }
     }
 
+    async fn handle_power_levels_change(
+        &self,
+        room: Room,
+        event: &SyncStateEvent<PowerLevelsEventContent>,
+    ) {
+        if let Some(prev_content) = &event.prev_content {
+            if let Some(change) =
+                PowerLevelsChange::new(room.own_user_id(), prev_content, &event.content)
+            {
+                self.on_own_power_levels_change(room, &change).await;
+            }
+        }
+    }
+
     pub(crate) async fn handle_stripped_state_event(
         &self,
         // TODO these events are only handled in invited rooms.
@@ -371,6 +387,9 @@ pub trait EventHandler: Send + Sync {
     async fn on_room_redaction(&self, _: Room, _: &SyncRedactionEvent) {}
     /// Fires when `Client` receives a `RoomEvent::RoomPowerLevels` event.
     async fn on_room_power_levels(&self, _: Room, _: &SyncStateEvent<PowerLevelsEventContent>) {}
+    /// Fires when `Client` receives a `m.room.power_levels` event that changes
+    /// our own power level or our ability to send messages in the room.
+    async fn on_own_power_levels_change(&self, _: Room, _: &PowerLevelsChange) {}
     /// Fires when `Client` receives a `RoomEvent::RoomJoinRules` event.
     async fn on_room_join_rules(&self, _: Room, _: &SyncStateEvent<JoinRulesEventContent>) {}
     /// Fires when `Client` receives a `RoomEvent::Tombstone` event.
@@ -492,6 +511,16 @@ pub trait EventHandler: Send + Sync {
     /// The only guarantee this method can give about the event is that it is in
     /// the shape of a valid matrix event.
     async fn on_custom_event(&self, _: Room, _: &CustomEvent<'_>) {}
+
+    /// Fires when a key query discovers that a tracked user added or deleted
+    /// a device or changed their identity.
+    ///
+    /// Changes of identities that we verified might be a man-in-the-middle
+    /// attack and should be shown to the user, see
+    /// [`DeviceListChange::is_security_alert()`].
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    async fn on_device_list_change(&self, _: &DeviceListChange) {}
 }
 
 #[cfg(test)]
@@ -0,0 +1,205 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Location messages with the extensible fields of [MSC3488].
+//!
+//! [MSC3488]: https://github.com/matrix-org/matrix-doc/pull/3488
+
+use ruma::{events::custom::CustomEventContent, MilliSecondsSinceUnixEpoch};
+use serde::{Deserialize, Serialize};
+
+use super::ExtensibleEventContent;
+
+const LOCATION_MSGTYPE: &str = "m.location";
+
+/// What a shared location belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum AssetType {
+    /// The location of the sender.
+    Self_,
+    /// A location that was picked by the sender, e.g. a meeting point.
+    Pin,
+    /// Any other kind of asset.
+    Custom(String),
+}
+
+impl Default for AssetType {
+    fn default() -> Self {
+        Self::Self_
+    }
+}
+
+impl From<String> for AssetType {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "m.self" | "org.matrix.msc3488.asset.self" => Self::Self_,
+            "m.pin" | "org.matrix.msc3488.asset.pin" => Self::Pin,
+            _ => Self::Custom(s),
+        }
+    }
+}
+
+impl From<AssetType> for String {
+    fn from(asset: AssetType) -> Self {
+        match asset {
+            AssetType::Self_ => "m.self".to_owned(),
+            AssetType::Pin => "m.pin".to_owned(),
+            AssetType::Custom(s) => s,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LocationBlock {
+    uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct AssetBlock {
+    #[serde(rename = "type")]
+    asset_type: AssetType,
+}
+
+/// The content of a `m.room.message` event with the `m.location` message
+/// type.
+///
+/// Besides the fields of the spec, which are all that older clients
+/// understand, the content contains the location, asset and timestamp blocks
+/// of [MSC3488].
+///
+/// Received location messages can be parsed with
+/// [ExtensibleEventContent::from_raw_event()], Ruma drops the extensible
+/// blocks of the message.
+///
+/// # Example
+///
+/// ```
+/// # use matrix_sdk::extensible::{AssetType, LocationContent};
+/// let content = LocationContent::new("Our meeting point", "geo:51.5008,0.1247;u=35")
+///     .description("The big clock")
+///     .asset(AssetType::Pin);
+/// ```
+///
+/// [MSC3488]: https://github.com/matrix-org/matrix-doc/pull/3488
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LocationContent {
+    body: String,
+    msgtype: String,
+    geo_uri: String,
+    #[serde(rename = "org.matrix.msc1767.text", skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    // Messages of clients that don't support MSC3488 don't have the blocks.
+    #[serde(rename = "org.matrix.msc3488.location", skip_serializing_if = "Option::is_none")]
+    location: Option<LocationBlock>,
+    #[serde(rename = "org.matrix.msc3488.asset", default)]
+    asset: AssetBlock,
+    #[serde(rename = "org.matrix.msc3488.ts", skip_serializing_if = "Option::is_none")]
+    ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl LocationContent {
+    /// Create a new location content.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - A description of the location that is shown by clients
+    /// that don't support location messages.
+    ///
+    /// * `geo_uri` - The location as a [geo URI], e.g.
+    /// `geo:51.5008,0.1247;u=35`.
+    ///
+    /// [geo URI]: https://tools.ietf.org/html/rfc5870
+    pub fn new(body: impl Into<String>, geo_uri: impl Into<String>) -> Self {
+        let body = body.into();
+        let geo_uri = geo_uri.into();
+
+        Self {
+            text: Some(body.clone()),
+            body,
+            msgtype: LOCATION_MSGTYPE.to_owned(),
+            location: Some(LocationBlock { uri: geo_uri.clone(), description: None }),
+            geo_uri,
+            asset: AssetBlock::default(),
+            ts: None,
+        }
+    }
+
+    /// Set the description of the location, e.g. the name of a place.
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        let description = Some(description.into());
+
+        match &mut self.location {
+            Some(location) => location.description = description,
+            None => self.location = Some(LocationBlock { uri: self.geo_uri.clone(), description }),
+        }
+
+        self
+    }
+
+    /// Set what the location belongs to, the sender by default.
+    pub fn asset(mut self, asset: AssetType) -> Self {
+        self.asset.asset_type = asset;
+        self
+    }
+
+    /// Set the time at which the location was valid.
+    pub fn timestamp(mut self, ts: MilliSecondsSinceUnixEpoch) -> Self {
+        self.ts = Some(ts);
+        self
+    }
+
+    /// The description of the location shown by clients that don't support
+    /// location messages.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// The location as a geo URI.
+    pub fn geo_uri(&self) -> &str {
+        self.location.as_ref().map_or(&self.geo_uri, |l| &l.uri)
+    }
+
+    /// The description of the location.
+    pub fn location_description(&self) -> Option<&str> {
+        self.location.as_ref().and_then(|l| l.description.as_deref())
+    }
+
+    /// What the location belongs to.
+    pub fn asset_type(&self) -> &AssetType {
+        &self.asset.asset_type
+    }
+
+    /// The time at which the location was valid.
+    pub fn ts(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.ts
+    }
+}
+
+impl ExtensibleEventContent for LocationContent {
+    const EVENT_TYPE: &'static str = "m.room.message";
+
+    fn from_custom(content: &CustomEventContent) -> Option<Self> {
+        let msgtype = content.data.get("msgtype").and_then(|m| m.as_str());
+
+        if content.event_type != Self::EVENT_TYPE || msgtype != Some(LOCATION_MSGTYPE) {
+            return None;
+        }
+
+        let data = content.data.clone().into_iter().collect();
+        serde_json::from_value(serde_json::Value::Object(data)).ok()
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extensible events as described in [MSC1767] and the message types built
+//! on top of them, Ruma doesn't support them yet.
+//!
+//! The content of an extensible event consists of content blocks, e.g. a text
+//! block with the plain text representation of the event that clients which
+//! don't know the event type can show. The typed contents in this module,
+//! like [LocationContent] and [PollStartContent], implement
+//! [ExtensibleEventContent] which converts them from and to the custom event
+//! contents of Ruma. They can be sent using
+//! [Joined::send_extensible()](crate::room::Joined::send_extensible).
+//!
+//! Event types that don't have a typed content yet can be built and parsed
+//! block by block using [ExtensibleContent].
+//!
+//! [MSC1767]: https://github.com/matrix-org/matrix-doc/pull/1767
+
+use std::collections::BTreeMap;
+
+use ruma::events::{custom::CustomEventContent, EventContent};
+use serde::{de::DeserializeOwned, ser::Error as _, Deserialize, Serialize};
+use serde_json::{value::RawValue as RawJsonValue, Value as JsonValue};
+
+mod location;
+mod poll;
+
+pub use location::{AssetType, LocationContent};
+pub use poll::{
+    PollAnswer, PollEndContent, PollKind, PollResponseContent, PollStartContent, POLL_END,
+    POLL_RESPONSE, POLL_START,
+};
+
+/// The key of the text block, the plain text representation of an event.
+pub const TEXT_BLOCK: &str = "org.matrix.msc1767.text";
+
+/// The key of the HTML block, the HTML representation of an event.
+pub const HTML_BLOCK: &str = "org.matrix.msc1767.html";
+
+/// The content of an extensible event with a known event type.
+pub trait ExtensibleEventContent: Serialize + DeserializeOwned {
+    /// The type of the events with this content.
+    const EVENT_TYPE: &'static str;
+
+    /// Get the typed content of the given custom event content.
+    ///
+    /// Returns `None` if the content has a different event type or if it
+    /// doesn't contain the blocks of this content.
+    fn from_custom(content: &CustomEventContent) -> Option<Self> {
+        if content.event_type != Self::EVENT_TYPE {
+            return None;
+        }
+
+        let data = content.data.clone().into_iter().collect();
+        serde_json::from_value(JsonValue::Object(data)).ok()
+    }
+
+    /// Get the typed content of the given raw event.
+    ///
+    /// This is useful for events that Ruma knows, but whose extensible
+    /// blocks it drops while deserializing them.
+    fn from_raw_event(event: &RawJsonValue) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Event<'a> {
+            #[serde(rename = "type")]
+            event_type: &'a str,
+            #[serde(borrow)]
+            content: &'a RawJsonValue,
+        }
+
+        let event: Event<'_> = serde_json::from_str(event.get()).ok()?;
+        let content = CustomEventContent::from_parts(event.event_type, event.content).ok()?;
+
+        Self::from_custom(&content)
+    }
+
+    /// Convert the content into a custom event content that can be sent.
+    fn to_custom(&self) -> serde_json::Result<CustomEventContent> {
+        match serde_json::to_value(self)? {
+            JsonValue::Object(data) => Ok(CustomEventContent {
+                event_type: Self::EVENT_TYPE.to_owned(),
+                data: data.into_iter().collect(),
+            }),
+            _ => Err(serde_json::Error::custom("the content isn't a JSON object")),
+        }
+    }
+}
+
+/// The text block of an extensible event, together with the optional HTML
+/// block.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TextContent {
+    /// The plain text representation.
+    #[serde(rename = "org.matrix.msc1767.text")]
+    pub text: String,
+    /// The HTML representation.
+    #[serde(rename = "org.matrix.msc1767.html", skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+}
+
+impl TextContent {
+    /// Create a plain text block.
+    pub fn plain(text: impl Into<String>) -> Self {
+        Self { text: text.into(), html: None }
+    }
+
+    /// Create a text block with a HTML representation.
+    pub fn html(text: impl Into<String>, html: impl Into<String>) -> Self {
+        Self { text: text.into(), html: Some(html.into()) }
+    }
+}
+
+/// The content of an extensible event of any type, made up of content
+/// blocks.
+///
+/// # Example
+///
+/// ```
+/// # use matrix_sdk::extensible::ExtensibleContent;
+/// # use serde_json::json;
+/// let content = ExtensibleContent::new("org.example.weather")
+///     .with_text("It's sunny, 24°C")
+///     .with_block("org.example.weather.temperature", &json!({ "celsius": 24 }))
+///     .unwrap();
+///
+/// let temperature: serde_json::Value =
+///     content.block("org.example.weather.temperature").unwrap();
+/// assert_eq!(temperature["celsius"], 24);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ExtensibleContent {
+    event_type: String,
+    blocks: BTreeMap<String, JsonValue>,
+}
+
+impl ExtensibleContent {
+    /// Create a content without any blocks.
+    pub fn new(event_type: impl Into<String>) -> Self {
+        Self { event_type: event_type.into(), blocks: BTreeMap::new() }
+    }
+
+    /// The event type of the content.
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// Add a text block to the content.
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.blocks.insert(TEXT_BLOCK.to_owned(), text.into().into());
+        self
+    }
+
+    /// Add a HTML block to the content.
+    pub fn with_html(mut self, html: impl Into<String>) -> Self {
+        self.blocks.insert(HTML_BLOCK.to_owned(), html.into().into());
+        self
+    }
+
+    /// Add a block with the given key to the content, replacing a block with
+    /// the same key.
+    pub fn with_block(
+        mut self,
+        key: impl Into<String>,
+        block: &impl Serialize,
+    ) -> serde_json::Result<Self> {
+        self.blocks.insert(key.into(), serde_json::to_value(block)?);
+        Ok(self)
+    }
+
+    /// The plain text representation of the content, if it has a text block.
+    pub fn text(&self) -> Option<&str> {
+        self.blocks.get(TEXT_BLOCK).and_then(|t| t.as_str())
+    }
+
+    /// The HTML representation of the content, if it has a HTML block.
+    pub fn html(&self) -> Option<&str> {
+        self.blocks.get(HTML_BLOCK).and_then(|t| t.as_str())
+    }
+
+    /// Get the block with the given key.
+    ///
+    /// Returns `None` if there is no such block or if it can't be
+    /// deserialized into the given type.
+    pub fn block<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        serde_json::from_value(self.blocks.get(key)?.clone()).ok()
+    }
+
+    /// The keys of all the blocks of the content.
+    pub fn block_keys(&self) -> impl Iterator<Item = &str> {
+        self.blocks.keys().map(|k| k.as_str())
+    }
+}
+
+impl From<CustomEventContent> for ExtensibleContent {
+    fn from(content: CustomEventContent) -> Self {
+        Self { event_type: content.event_type, blocks: content.data }
+    }
+}
+
+impl From<ExtensibleContent> for CustomEventContent {
+    fn from(content: ExtensibleContent) -> Self {
+        CustomEventContent { event_type: content.event_type, data: content.blocks }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::event_id;
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{
+        AssetType, ExtensibleEventContent, LocationContent, PollAnswer, PollResponseContent,
+        PollStartContent,
+    };
+
+    #[test]
+    fn poll_contents() {
+        let poll = PollStartContent::new(
+            "What should we order for the party?",
+            vec![PollAnswer::new("pizza", "Pizza 🍕"), PollAnswer::new("poutine", "Poutine 🍟")],
+        );
+        let custom = poll.to_custom().unwrap();
+
+        assert_eq!(custom.event_type, "org.matrix.msc3381.poll.start");
+        assert_eq!(
+            serde_json::to_value(&custom).unwrap(),
+            json!({
+                "org.matrix.msc3381.poll.start": {
+                    "question": {
+                        "org.matrix.msc1767.text": "What should we order for the party?"
+                    },
+                    "kind": "org.matrix.msc3381.poll.disclosed",
+                    "max_selections": 1,
+                    "answers": [
+                        { "id": "pizza", "org.matrix.msc1767.text": "Pizza 🍕" },
+                        { "id": "poutine", "org.matrix.msc1767.text": "Poutine 🍟" }
+                    ]
+                },
+                "org.matrix.msc1767.text":
+                    "What should we order for the party?\n1. Pizza 🍕\n2. Poutine 🍟"
+            })
+        );
+        assert_eq!(PollStartContent::from_custom(&custom).unwrap().answers().len(), 2);
+        assert!(PollResponseContent::from_custom(&custom).is_none());
+
+        let event = to_raw_value(&json!({
+            "type": "org.matrix.msc3381.poll.response",
+            "event_id": "$response:localhost",
+            "sender": "@alice:localhost",
+            "origin_server_ts": 1,
+            "content": {
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$poll:localhost" },
+                "org.matrix.msc3381.poll.response": { "answers": ["poutine"] }
+            }
+        }))
+        .unwrap();
+        let response = PollResponseContent::from_raw_event(&event).unwrap();
+
+        assert_eq!(response.poll_id(), &event_id!("$poll:localhost"));
+        assert_eq!(response.answers(), ["poutine"]);
+    }
+
+    #[test]
+    fn location_content() {
+        let location = |content| {
+            to_raw_value(&json!({
+                "type": "m.room.message",
+                "event_id": "$location:localhost",
+                "sender": "@alice:localhost",
+                "origin_server_ts": 1,
+                "content": content,
+            }))
+            .unwrap()
+        };
+
+        let event = location(json!({
+            "body": "Alice was at geo:51.5008,0.1247;u=35",
+            "msgtype": "m.location",
+            "geo_uri": "geo:51.5008,0.1247;u=35",
+            "org.matrix.msc1767.text": "Alice was at geo:51.5008,0.1247;u=35",
+            "org.matrix.msc3488.location": {
+                "uri": "geo:51.5008,0.1247;u=35",
+                "description": "Alice's whereabouts"
+            },
+            "org.matrix.msc3488.asset": { "type": "m.pin" },
+            "org.matrix.msc3488.ts": 1636829458432u64
+        }));
+        let content = LocationContent::from_raw_event(&event).unwrap();
+
+        assert_eq!(content.geo_uri(), "geo:51.5008,0.1247;u=35");
+        assert_eq!(content.location_description(), Some("Alice's whereabouts"));
+        assert_eq!(content.asset_type(), &AssetType::Pin);
+        assert!(content.ts().is_some());
+
+        // Location messages of clients that don't support MSC3488.
+        let event = location(json!({
+            "body": "Bob's home",
+            "msgtype": "m.location",
+            "geo_uri": "geo:48.8584,2.2945"
+        }));
+        let content = LocationContent::from_raw_event(&event).unwrap();
+
+        assert_eq!(content.geo_uri(), "geo:48.8584,2.2945");
+        assert_eq!(content.asset_type(), &AssetType::Self_);
+
+        let event = location(json!({ "body": "Hello", "msgtype": "m.text" }));
+        assert!(LocationContent::from_raw_event(&event).is_none());
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Polls as described in [MSC3381].
+//!
+//! [MSC3381]: https://github.com/matrix-org/matrix-doc/pull/3381
+
+use ruma::{EventId, UInt};
+use serde::{Deserialize, Serialize};
+
+use super::{ExtensibleEventContent, TextContent};
+
+/// The event type of the event that starts a poll.
+pub const POLL_START: &str = "org.matrix.msc3381.poll.start";
+
+/// The event type of the event that answers a poll.
+pub const POLL_RESPONSE: &str = "org.matrix.msc3381.poll.response";
+
+/// The event type of the event that closes a poll.
+pub const POLL_END: &str = "org.matrix.msc3381.poll.end";
+
+/// When the results of a poll are shown.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum PollKind {
+    /// The results are shown while the poll is running.
+    Disclosed,
+    /// The results are only shown once the poll is closed.
+    Undisclosed,
+    /// Any other kind of poll.
+    Custom(String),
+}
+
+impl Default for PollKind {
+    fn default() -> Self {
+        Self::Disclosed
+    }
+}
+
+impl From<String> for PollKind {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "org.matrix.msc3381.poll.disclosed" => Self::Disclosed,
+            "org.matrix.msc3381.poll.undisclosed" => Self::Undisclosed,
+            _ => Self::Custom(s),
+        }
+    }
+}
+
+impl From<PollKind> for String {
+    fn from(kind: PollKind) -> Self {
+        match kind {
+            PollKind::Disclosed => "org.matrix.msc3381.poll.disclosed".to_owned(),
+            PollKind::Undisclosed => "org.matrix.msc3381.poll.undisclosed".to_owned(),
+            PollKind::Custom(s) => s,
+        }
+    }
+}
+
+/// A possible answer of a poll.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PollAnswer {
+    /// The id of the answer, it's unique within the poll.
+    pub id: String,
+    /// The text of the answer.
+    #[serde(flatten)]
+    pub text: TextContent,
+}
+
+impl PollAnswer {
+    /// Create a new answer with the given id and plain text.
+    pub fn new(id: impl Into<String>, text: impl Into<String>) -> Self {
+        Self { id: id.into(), text: TextContent::plain(text) }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PollStartBlock {
+    question: TextContent,
+    #[serde(default)]
+    kind: PollKind,
+    #[serde(default = "default_max_selections")]
+    max_selections: UInt,
+    answers: Vec<PollAnswer>,
+}
+
+fn default_max_selections() -> UInt {
+    UInt::from(1u32)
+}
+
+/// The content of an event that starts a poll.
+///
+/// # Example
+///
+/// ```
+/// # use matrix_sdk::extensible::{PollAnswer, PollKind, PollStartContent};
+/// let content = PollStartContent::new(
+///     "What should we order for the party?",
+///     vec![PollAnswer::new("pizza", "Pizza 🍕"), PollAnswer::new("poutine", "Poutine 🍟")],
+/// )
+/// .kind(PollKind::Undisclosed);
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PollStartContent {
+    #[serde(rename = "org.matrix.msc3381.poll.start")]
+    poll: PollStartBlock,
+    #[serde(rename = "org.matrix.msc1767.text")]
+    text: String,
+}
+
+impl PollStartContent {
+    /// Create a new disclosed poll where a single answer can be selected.
+    ///
+    /// The text shown by clients that don't support polls lists the question
+    /// and the answers.
+    pub fn new(question: impl Into<String>, answers: Vec<PollAnswer>) -> Self {
+        let question = question.into();
+        let mut text = question.clone();
+
+        for (i, answer) in answers.iter().enumerate() {
+            text.push_str(&format!("\n{}. {}", i + 1, answer.text.text));
+        }
+
+        Self {
+            poll: PollStartBlock {
+                question: TextContent::plain(question),
+                kind: PollKind::default(),
+                max_selections: default_max_selections(),
+                answers,
+            },
+            text,
+        }
+    }
+
+    /// Set the kind of the poll.
+    pub fn kind(mut self, kind: PollKind) -> Self {
+        self.poll.kind = kind;
+        self
+    }
+
+    /// Set how many answers can be selected at most.
+    pub fn max_selections(mut self, max_selections: UInt) -> Self {
+        self.poll.max_selections = max_selections;
+        self
+    }
+
+    /// The question of the poll.
+    pub fn question(&self) -> &str {
+        &self.poll.question.text
+    }
+
+    /// The kind of the poll.
+    pub fn poll_kind(&self) -> &PollKind {
+        &self.poll.kind
+    }
+
+    /// How many answers can be selected at most.
+    pub fn selection_limit(&self) -> UInt {
+        self.poll.max_selections
+    }
+
+    /// The possible answers of the poll.
+    pub fn answers(&self) -> &[PollAnswer] {
+        &self.poll.answers
+    }
+
+    /// The text shown by clients that don't support polls.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl ExtensibleEventContent for PollStartContent {
+    const EVENT_TYPE: &'static str = POLL_START;
+}
+
+/// The reference to the event that started a poll.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Reference {
+    rel_type: String,
+    event_id: EventId,
+}
+
+impl Reference {
+    fn new(event_id: EventId) -> Self {
+        Self { rel_type: "m.reference".to_owned(), event_id }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PollResponseBlock {
+    answers: Vec<String>,
+}
+
+/// The content of an event that answers a poll.
+///
+/// Sending another response replaces the previous response of the user, a
+/// response without answers withdraws it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PollResponseContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Reference,
+    #[serde(rename = "org.matrix.msc3381.poll.response")]
+    response: PollResponseBlock,
+}
+
+impl PollResponseContent {
+    /// Create a new response to a poll.
+    ///
+    /// # Arguments
+    ///
+    /// * `poll_id` - The id of the event that started the poll.
+    ///
+    /// * `answers` - The ids of the selected answers.
+    pub fn new(poll_id: EventId, answers: Vec<String>) -> Self {
+        Self { relates_to: Reference::new(poll_id), response: PollResponseBlock { answers } }
+    }
+
+    /// The id of the event that started the poll.
+    pub fn poll_id(&self) -> &EventId {
+        &self.relates_to.event_id
+    }
+
+    /// The ids of the selected answers.
+    pub fn answers(&self) -> &[String] {
+        &self.response.answers
+    }
+}
+
+impl ExtensibleEventContent for PollResponseContent {
+    const EVENT_TYPE: &'static str = POLL_RESPONSE;
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct PollEndBlock {}
+
+/// The content of an event that closes a poll.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PollEndContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Reference,
+    #[serde(rename = "org.matrix.msc3381.poll.end", default)]
+    end: PollEndBlock,
+    #[serde(rename = "org.matrix.msc1767.text")]
+    text: String,
+}
+
+impl PollEndContent {
+    /// Create a new event that closes a poll.
+    ///
+    /// # Arguments
+    ///
+    /// * `poll_id` - The id of the event that started the poll.
+    ///
+    /// * `text` - The text shown by clients that don't support polls, e.g. the
+    /// results of the poll.
+    pub fn new(poll_id: EventId, text: impl Into<String>) -> Self {
+        Self { relates_to: Reference::new(poll_id), end: PollEndBlock {}, text: text.into() }
+    }
+
+    /// The id of the event that started the poll.
+    pub fn poll_id(&self) -> &EventId {
+        &self.relates_to.event_id
+    }
+
+    /// The text shown by clients that don't support polls.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl ExtensibleEventContent for PollEndContent {
+    const EVENT_TYPE: &'static str = POLL_END;
+}
@@ -0,0 +1,383 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities for the HTML formatted body of messages.
+//!
+//! Messages with a formatted body can be composed using mention pills, see
+//! [user_pill()] and [room_pill()], or from markdown with the `markdown`
+//! feature, see [RoomMessage::markdown()](crate::prelude::RoomMessage).
+//!
+//! The formatted body of received messages can contain arbitrary HTML, it
+//! should be passed through [sanitize()] before it's displayed.
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use ruma::{RoomIdOrAliasId, UserId};
+
+/// The characters that are percent encoded in matrix.to links, the sigils of
+/// the identifiers and the server name separator are kept readable.
+const MATRIX_TO_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'@')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b':')
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_');
+
+/// The elements that may appear in a formatted body, as recommended by the
+/// spec.
+const ALLOWED_ELEMENTS: &[&str] = &[
+    "font",
+    "del",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "p",
+    "a",
+    "ul",
+    "ol",
+    "sup",
+    "sub",
+    "li",
+    "b",
+    "i",
+    "u",
+    "strong",
+    "em",
+    "strike",
+    "code",
+    "hr",
+    "br",
+    "div",
+    "table",
+    "thead",
+    "tbody",
+    "tr",
+    "th",
+    "td",
+    "caption",
+    "pre",
+    "span",
+    "img",
+    "details",
+    "summary",
+];
+
+/// Elements that don't have any content and are never closed.
+const VOID_ELEMENTS: &[&str] = &["br", "hr", "img"];
+
+/// Elements that are removed together with their content. Reply fallbacks are
+/// removed since the replied to event should be shown instead.
+const DROPPED_ELEMENTS: &[&str] = &["script", "style", "mx-reply"];
+
+/// The URL schemes links may use.
+const ALLOWED_SCHEMES: &[&str] = &["http://", "https://", "ftp://", "mailto:", "magnet:"];
+
+/// Get the matrix.to link of the given user or room.
+pub fn matrix_to_url(id: &str) -> String {
+    format!("https://matrix.to/#/{}", utf8_percent_encode(id, MATRIX_TO_ENCODE_SET))
+}
+
+/// Create a mention pill of the given user for the formatted body of a
+/// message.
+///
+/// Clients render the pill with the current name and avatar of the user, the
+/// display name is shown by clients that don't support pills. The plain body
+/// of the message should contain the display name of the user.
+///
+/// # Arguments
+///
+/// * `user_id` - The user that is mentioned.
+///
+/// * `display_name` - The display name of the user, the user id is used if
+/// it's `None`.
+pub fn user_pill(user_id: &UserId, display_name: Option<&str>) -> String {
+    format!(
+        "<a href=\"{}\">{}</a>",
+        matrix_to_url(user_id.as_str()),
+        escape(display_name.unwrap_or_else(|| user_id.as_str()))
+    )
+}
+
+/// Create a pill of the given room for the formatted body of a message.
+///
+/// The alias of a room should be preferred over its id, ids aren't readable
+/// in clients that don't support pills.
+pub fn room_pill(room: &RoomIdOrAliasId) -> String {
+    format!("<a href=\"{}\">{}</a>", matrix_to_url(room.as_str()), escape(room.as_str()))
+}
+
+/// Escape the given text so it can be put into a HTML document.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Sanitize the formatted body of a received message so it can be displayed.
+///
+/// Only the elements and attributes the spec recommends are kept, the
+/// content of other elements is kept without the element itself. Scripts,
+/// styles and reply fallbacks are removed completely. Links may only use
+/// web, mail and magnet URLs and images may only be loaded from the media
+/// repository. Elements that aren't closed are closed at the end.
+pub fn sanitize(html: &str) -> String {
+    let mut sanitized = String::with_capacity(html.len());
+    // The elements that were opened and not closed yet.
+    let mut open: Vec<String> = Vec::new();
+    // The dropped element we're in and how often it's nested in itself.
+    let mut dropping: Option<(String, usize)> = None;
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        if dropping.is_none() {
+            sanitized.push_str(&rest[..start]);
+        }
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+
+        let is_tag = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/');
+        let end = if is_tag { tag_end(rest) } else { None };
+
+        let end = match end {
+            Some(end) => end,
+            None => {
+                // Not a tag, e.g. `1 < 2`.
+                if dropping.is_none() {
+                    sanitized.push_str("&lt;");
+                }
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let tag = Tag::parse(&rest[1..end]);
+        rest = &rest[end + 1..];
+
+        if let Some((name, depth)) = &mut dropping {
+            if tag.name == *name {
+                if tag.closing {
+                    *depth -= 1;
+                } else if !tag.self_closing {
+                    *depth += 1;
+                }
+            }
+
+            if *depth == 0 {
+                dropping.take();
+            }
+
+            continue;
+        }
+
+        if DROPPED_ELEMENTS.contains(&tag.name.as_str()) {
+            if !tag.closing && !tag.self_closing {
+                dropping = Some((tag.name, 1));
+            }
+        } else if !ALLOWED_ELEMENTS.contains(&tag.name.as_str()) {
+            continue;
+        } else if tag.closing {
+            if let Some(position) = open.iter().rposition(|n| *n == tag.name) {
+                for name in open.drain(position..).rev() {
+                    sanitized.push_str(&format!("</{}>", name));
+                }
+            }
+        } else {
+            sanitized.push('<');
+            sanitized.push_str(&tag.name);
+
+            for (name, value) in tag.allowed_attributes() {
+                sanitized.push_str(&format!(" {}=\"{}\"", name, value.replace('"', "&quot;")));
+            }
+
+            sanitized.push('>');
+
+            if !VOID_ELEMENTS.contains(&tag.name.as_str()) {
+                open.push(tag.name);
+            }
+        }
+    }
+
+    if dropping.is_none() {
+        sanitized.push_str(rest);
+    }
+
+    for name in open.iter().rev() {
+        sanitized.push_str(&format!("</{}>", name));
+    }
+
+    sanitized
+}
+
+/// Find the `>` that ends the tag at the start of the given HTML, skipping
+/// quoted attribute values.
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in html.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[derive(Debug)]
+struct Tag<'a> {
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    attributes: Vec<(String, &'a str)>,
+}
+
+impl<'a> Tag<'a> {
+    /// Parse the content of a tag, without the angle brackets.
+    fn parse(tag: &'a str) -> Self {
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let self_closing = tag.ends_with('/');
+
+        let name_end = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        let mut rest = &tag[name_end..];
+        let mut attributes = Vec::new();
+
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+
+            if rest.is_empty() {
+                break;
+            }
+
+            let name_end = rest
+                .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+                .unwrap_or(rest.len());
+            let name = rest[..name_end].to_ascii_lowercase();
+            rest = rest[name_end..].trim_start();
+
+            let value = match rest.strip_prefix('=') {
+                Some(value) => {
+                    let value = value.trim_start();
+
+                    match value.chars().next() {
+                        Some(q @ '"') | Some(q @ '\'') => {
+                            let end = value[1..].find(q).map_or(value.len(), |e| e + 1);
+                            rest = value.get(end + 1..).unwrap_or("");
+                            &value[1..end]
+                        }
+                        _ => {
+                            let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                            rest = &value[end..];
+                            &value[..end]
+                        }
+                    }
+                }
+                None => "",
+            };
+
+            attributes.push((name, value));
+        }
+
+        Self { name, closing, self_closing, attributes }
+    }
+
+    /// The attributes of the tag that are allowed for its element.
+    fn allowed_attributes(&self) -> impl Iterator<Item = &(String, &'a str)> + '_ {
+        self.attributes.iter().filter(move |(name, value)| {
+            match (self.name.as_str(), name.as_str()) {
+                ("font", "data-mx-bg-color") | ("font", "data-mx-color") | ("font", "color") => {
+                    true
+                }
+                ("span", "data-mx-bg-color")
+                | ("span", "data-mx-color")
+                | ("span", "data-mx-spoiler") => true,
+                ("a", "name") | ("a", "target") => true,
+                ("a", "href") => {
+                    let value = value.trim().to_ascii_lowercase();
+                    ALLOWED_SCHEMES.iter().any(|s| value.starts_with(s))
+                }
+                ("img", "width") | ("img", "height") | ("img", "alt") | ("img", "title") => true,
+                ("img", "src") => value.trim().starts_with("mxc://"),
+                ("ol", "start") => true,
+                ("code", "class") => value.starts_with("language-"),
+                _ => false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{room_alias_id, user_id, RoomIdOrAliasId};
+
+    use super::{room_pill, sanitize, user_pill};
+
+    #[test]
+    fn pills() {
+        assert_eq!(
+            user_pill(&user_id!("@alice:example.org"), Some("Alice <3")),
+            "<a href=\"https://matrix.to/#/@alice:example.org\">Alice &lt;3</a>"
+        );
+
+        let alias: RoomIdOrAliasId = room_alias_id!("#rust:example.org").into();
+        assert_eq!(
+            room_pill(&alias),
+            "<a href=\"https://matrix.to/#/%23rust:example.org\">#rust:example.org</a>"
+        );
+    }
+
+    #[test]
+    fn sanitization() {
+        assert_eq!(
+            sanitize("<p onclick=\"evil()\">Hi <script>alert('<b>')</script><b>there</b></p>"),
+            "<p>Hi <b>there</b></p>"
+        );
+        assert_eq!(
+            sanitize("<a href=\"javascript:alert(1)\">x</a> <a href='https://matrix.org'>y</a>"),
+            "<a>x</a> <a href=\"https://matrix.org\">y</a>"
+        );
+        assert_eq!(
+            sanitize("<img src=\"https://example.org/a.png\"><IMG SRC=mxc://example.org/a />"),
+            "<img><img src=\"mxc://example.org/a\">"
+        );
+        assert_eq!(sanitize("<mx-reply><blockquote>quote</blockquote></mx-reply>answer"), "answer");
+        assert_eq!(sanitize("<div><marquee>1 < 2</marquee><!-- -->"), "<div>1 &lt; 2</div>");
+        assert_eq!(sanitize("<ul><li>one<li>two</ul>"), "<ul><li>one<li>two</li></li></ul>");
+    }
+}
@@ -12,21 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(all(not(target_arch = "wasm32")))]
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::{convert::TryFrom, fmt::Debug, sync::Arc};
 
-#[cfg(all(not(target_arch = "wasm32")))]
-use backoff::{future::retry, Error as RetryError, ExponentialBackoff};
 #[cfg(all(not(target_arch = "wasm32")))]
 use http::StatusCode;
 use http::{HeaderValue, Response as HttpResponse};
+#[cfg(all(not(target_arch = "wasm32")))]
+use matrix_sdk_common::retry::retry;
 use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
 use reqwest::{Client, Response};
 use ruma::api::{
     client::r0::media::create_content, error::FromHttpResponseError, AuthScheme, IncomingResponse,
     OutgoingRequest, OutgoingRequestAppserviceExt, SendAccessToken,
 };
+#[cfg(all(not(target_arch = "wasm32")))]
+use ruma::api::{client::Error as RumaClientApiError, error::ServerError, EndpointError};
+#[cfg(feature = "encryption")]
+use ruma::DeviceKeyAlgorithm;
+#[cfg(feature = "encryption")]
+use serde::Deserialize;
 use tracing::trace;
 use url::Url;
 
@@ -210,6 +214,19 @@ impl HttpClient {
         Ok(create_content::Response::try_from_http_response(response)?)
     }
 
+    /// Send the request, returning the response without parsing it.
+    pub async fn send_raw<Request: OutgoingRequest + Debug>(
+        &self,
+        request: Request,
+        config: Option<RequestConfig>,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let response = self.send_request(request, self.session.clone(), config).await?;
+
+        trace!("Got response: {:?}", response);
+
+        Ok(response)
+    }
+
     pub async fn send<Request>(
         &self,
         request: Request,
@@ -241,11 +258,37 @@ pub(crate) fn client_with_config(config: &ClientConfig) -> Result<Client, HttpEr
             http_client
         };
 
-        let http_client = match &config.proxy {
-            Some(p) => http_client.proxy(p.clone()),
+        let http_client = match proxy_with_config(config)? {
+            Some(p) => http_client.proxy(p),
             None => http_client,
         };
 
+        let http_client = match config.tls_backend {
+            Some(backend) => backend.configure(http_client),
+            None => http_client,
+        };
+
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        let http_client = {
+            let mut http_client = http_client;
+
+            for certificate in &config.root_certificates {
+                http_client =
+                    http_client.add_root_certificate(reqwest::Certificate::from_der(certificate)?);
+            }
+
+            http_client
+        };
+
+        // The preconfigured TLS replaces the backend and the root certificates
+        // set above.
+        #[cfg(feature = "rustls-tls")]
+        let http_client = if config.pinned_certificates.is_empty() {
+            http_client
+        } else {
+            http_client.use_preconfigured_tls(crate::tls::pinning_tls_config(config))
+        };
+
         let mut headers = reqwest::header::HeaderMap::new();
 
         let user_agent = match &config.user_agent {
@@ -266,6 +309,87 @@ pub(crate) fn client_with_config(config: &ClientConfig) -> Result<Client, HttpEr
     Ok(http_client.build()?)
 }
 
+/// Get the key algorithms of the unused fallback keys from the body of a sync
+/// response, `None` if the server doesn't report them.
+///
+/// The sync response of ruma doesn't contain the
+/// `device_unused_fallback_key_types` field of [MSC2732] yet.
+///
+/// [MSC2732]: https://github.com/matrix-org/matrix-doc/pull/2732
+#[cfg(feature = "encryption")]
+pub(crate) fn unused_fallback_keys(body: &[u8]) -> Option<Vec<DeviceKeyAlgorithm>> {
+    #[derive(Deserialize)]
+    struct UnusedFallbackKeys {
+        #[serde(alias = "org.matrix.msc2732.device_unused_fallback_key_types")]
+        device_unused_fallback_key_types: Option<Vec<DeviceKeyAlgorithm>>,
+    }
+
+    serde_json::from_slice::<UnusedFallbackKeys>(body).ok()?.device_unused_fallback_key_types
+}
+
+/// Is the given URL the URL of an onion service.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn is_onion(url: &Url) -> bool {
+    url.host_str().map_or(false, |h| h.ends_with(".onion"))
+}
+
+/// Get the URL of the proxy of the given configuration that is used to reach
+/// a host, `None` if no proxy is configured.
+///
+/// SOCKS proxies take the credentials from the URL, they are embedded into it.
+/// Onion services can't be resolved locally, a `socks5://` proxy is used as a
+/// `socks5h://` proxy, which resolves the host name itself, to reach them.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn proxy_url(config: &ClientConfig, onion: bool) -> Option<Url> {
+    let mut url = config.proxy.clone()?;
+
+    if url.scheme().starts_with("socks") {
+        if let Some((username, password)) = &config.proxy_credentials {
+            let _ = url.set_username(username);
+            let _ = url.set_password(Some(password));
+        }
+
+        if onion && url.scheme() == "socks5" {
+            url.set_scheme("socks5h").expect("Both schemes aren't special");
+        }
+    }
+
+    Some(url)
+}
+
+/// Build the proxy of the given configuration, including its credentials.
+#[cfg(not(target_arch = "wasm32"))]
+fn proxy_with_config(config: &ClientConfig) -> Result<Option<reqwest::Proxy>, reqwest::Error> {
+    let url = match proxy_url(config, false) {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    // The homeserver can change after the client was built, e.g. after the
+    // discovery of the homeserver, so the proxy is picked for every request.
+    if url.scheme() == "socks5" {
+        // Check that the proxy is supported, errors of a custom proxy are
+        // ignored.
+        reqwest::Proxy::all(url.as_str())?;
+        let onion_url = proxy_url(config, true).expect("The proxy is configured");
+
+        return Ok(Some(reqwest::Proxy::custom(move |target| {
+            Some(if is_onion(target) { onion_url.clone() } else { url.clone() })
+        })));
+    }
+
+    if url.scheme() == "socks5h" {
+        return reqwest::Proxy::all(url.as_str()).map(Some);
+    }
+
+    let proxy = reqwest::Proxy::all(url.as_str())?;
+
+    Ok(Some(match &config.proxy_credentials {
+        Some((username, password)) => proxy.basic_auth(username, password),
+        None => proxy,
+    }))
+}
+
 async fn response_to_http_response(
     mut response: Response,
 ) -> Result<http::Response<Bytes>, reqwest::Error> {
@@ -303,54 +427,51 @@ async fn send_request(
     request: http::Request<Bytes>,
     config: RequestConfig,
 ) -> Result<http::Response<Bytes>, HttpError> {
-    let mut backoff = ExponentialBackoff::default();
     let mut request = reqwest::Request::try_from(request)?;
-    let retry_limit = config.retry_limit;
-    let retry_count = AtomicU64::new(1);
+    let policy = config.effective_retry_policy();
 
     *request.timeout_mut() = Some(config.timeout);
 
-    backoff.max_elapsed_time = config.retry_timeout;
-
     let request = &request;
-    let retry_count = &retry_count;
 
     let request = || async move {
-        let stop = if let Some(retry_limit) = retry_limit {
-            retry_count.fetch_add(1, Ordering::Relaxed) >= retry_limit
-        } else {
-            false
-        };
-
-        // Turn errors into permanent errors when the retry limit is reached
-        let error_type = if stop { RetryError::Permanent } else { RetryError::Transient };
-
         let request = request.try_clone().ok_or(HttpError::UnableToCloneRequest)?;
 
-        let response =
-            client.execute(request).await.map_err(|e| error_type(HttpError::Reqwest(e)))?;
+        let response = client.execute(request).await.map_err(connection_error)?;
 
         let status_code = response.status();
-        // TODO TOO_MANY_REQUESTS will have a retry timeout which we should
-        // use.
-        if !stop
-            && (status_code.is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS)
-        {
-            return Err(error_type(HttpError::Server(status_code)));
+        let response = response_to_http_response(response).await?;
+
+        // Keep the Matrix error the homeserver responded with, if the last
+        // attempt fails it's returned as is, including the `retry_after_ms`
+        // of a rate limited request.
+        if status_code.is_server_error() || status_code == StatusCode::TOO_MANY_REQUESTS {
+            return Err(match RumaClientApiError::try_from_http_response(response) {
+                Ok(e) => HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))),
+                Err(_) => HttpError::Server(status_code),
+            });
         }
 
-        let response = response_to_http_response(response)
-            .await
-            .map_err(|e| RetryError::Permanent(HttpError::Reqwest(e)))?;
-
         Ok(response)
     };
 
-    let response = retry(backoff, request).await?;
+    let response = retry(&policy, request).await?;
 
     Ok(response)
 }
 
+/// Convert the error of a failed request, pointing out if the certificate
+/// pinning failed.
+#[cfg(not(target_arch = "wasm32"))]
+fn connection_error(error: reqwest::Error) -> HttpError {
+    #[cfg(feature = "rustls-tls")]
+    if let Some(e) = crate::tls::pinning_failure(&error) {
+        return e;
+    }
+
+    error.into()
+}
+
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl HttpSend for Client {
@@ -362,3 +483,47 @@ impl HttpSend for Client {
         send_request(self, request, config).await
     }
 }
+
+/// Lets tests answer the requests of a client with a scripted server, see
+/// [`ScriptedServer`](matrix_sdk_test::ScriptedServer).
+#[cfg(test)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl HttpSend for matrix_sdk_test::ScriptedServer {
+    async fn send_request(
+        &self,
+        request: http::Request<Bytes>,
+        _: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        Ok(self.handle(&request).map(Bytes::from))
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod test {
+    use ruma::DeviceKeyAlgorithm;
+    use serde_json::json;
+
+    use super::unused_fallback_keys;
+
+    #[test]
+    fn unused_fallback_key_types() {
+        let body = json!({
+            "next_batch": "s1",
+            "device_unused_fallback_key_types": ["signed_curve25519"],
+        });
+        assert_eq!(
+            unused_fallback_keys(&serde_json::to_vec(&body).unwrap()),
+            Some(vec![DeviceKeyAlgorithm::SignedCurve25519])
+        );
+
+        let body = json!({
+            "next_batch": "s1",
+            "org.matrix.msc2732.device_unused_fallback_key_types": [],
+        });
+        assert_eq!(unused_fallback_keys(&serde_json::to_vec(&body).unwrap()), Some(vec![]));
+
+        let body = json!({ "next_batch": "s1" });
+        assert_eq!(unused_fallback_keys(&serde_json::to_vec(&body).unwrap()), None);
+    }
+}
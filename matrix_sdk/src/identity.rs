@@ -0,0 +1,375 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integration with identity servers, which map third party ids like email
+//! addresses and phone numbers to Matrix user ids.
+//!
+//! An identity server is set using [Client::set_identity_server()], which
+//! registers the user with the identity server. Most identity servers require
+//! the user to accept their terms of service, see [IdentityServer::terms()],
+//! before they answer lookups.
+//!
+//! Lookups use the hashed lookup of the v2 identity service API, the
+//! addresses never leave the client in plain text unless the caller explicitly
+//! allows it using [IdentityServer::lookup_allowing_plaintext()].
+//!
+//! Users that aren't on Matrix yet can be invited to a room by their email
+//! address using
+//! [Joined::invite_by_email()](crate::room::Joined::invite_by_email).
+
+use std::{collections::BTreeMap, fmt};
+
+use bytes::Bytes;
+use http::{header::AUTHORIZATION, Method};
+use ruma::{
+    api::{
+        client::{r0::account::request_openid_token, Error as ClientError},
+        error::{FromHttpResponseError, ServerError},
+        EndpointError,
+    },
+    thirdparty::Medium,
+    UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{Client, Error, HttpError, Result};
+
+/// The credentials the client uses to talk to an identity server.
+#[derive(Clone)]
+pub(crate) struct Credentials {
+    pub url: Url,
+    pub access_token: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("url", &self.url)
+            .field("access_token", &"<redacted>")
+            .finish()
+    }
+}
+
+/// A policy of an identity server that the user needs to accept.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Policy {
+    /// The version of the policy.
+    pub version: String,
+    /// The translations of the policy, keyed by their language code.
+    #[serde(flatten)]
+    pub translations: BTreeMap<String, PolicyTranslation>,
+}
+
+/// A translation of a [Policy].
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyTranslation {
+    /// The name of the policy in this language.
+    pub name: String,
+    /// The URL of the policy in this language, this is the URL that needs to
+    /// be passed to [IdentityServer::accept_terms()].
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+struct TermsResponse {
+    policies: BTreeMap<String, Policy>,
+}
+
+#[derive(Deserialize)]
+struct RegisterResponse {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct HashDetailsResponse {
+    lookup_pepper: String,
+    algorithms: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    mappings: BTreeMap<String, UserId>,
+}
+
+#[derive(Deserialize)]
+struct EmptyResponse {}
+
+/// An identity server the client is registered with.
+#[derive(Clone, Debug)]
+pub struct IdentityServer {
+    client: Client,
+    credentials: Credentials,
+}
+
+impl IdentityServer {
+    pub(crate) fn new(client: Client, credentials: Credentials) -> Self {
+        Self { client, credentials }
+    }
+
+    /// Register with the identity server at the given URL using an OpenID
+    /// token of our homeserver.
+    pub(crate) async fn register(client: &Client, url: Url) -> Result<Credentials> {
+        let user_id = client.user_id().await.ok_or(Error::AuthenticationRequired)?;
+        let request = request_openid_token::Request::new(&user_id);
+        let openid = client.send(request, None).await?;
+
+        #[derive(Serialize)]
+        struct RegisterRequest<'a> {
+            access_token: &'a str,
+            token_type: &'a str,
+            matrix_server_name: &'a str,
+            expires_in: u64,
+        }
+
+        let body = RegisterRequest {
+            access_token: &openid.access_token,
+            token_type: openid.token_type.as_ref(),
+            matrix_server_name: openid.matrix_server_name.as_str(),
+            expires_in: openid.expires_in.as_secs(),
+        };
+
+        let response: RegisterResponse =
+            send(client, &url, None, Method::POST, "account/register", Some(&body)).await?;
+
+        Ok(Credentials { url, access_token: response.token })
+    }
+
+    /// The URL of the identity server.
+    pub fn url(&self) -> &Url {
+        &self.credentials.url
+    }
+
+    /// The access token the client uses to talk to the identity server.
+    ///
+    /// The token can be stored and passed to
+    /// [Client::restore_identity_server()] to avoid registering again after a
+    /// restart.
+    pub fn access_token(&self) -> &str {
+        &self.credentials.access_token
+    }
+
+    /// The server name of the identity server as it's used in third party
+    /// invites, i.e. the host and the port of its URL.
+    pub fn server_name(&self) -> String {
+        let url = self.url();
+        let host = url.host_str().unwrap_or_default();
+
+        match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        }
+    }
+
+    /// Get the policies of the identity server, keyed by their id.
+    pub async fn terms(&self) -> Result<BTreeMap<String, Policy>> {
+        let response: TermsResponse = self.send(Method::GET, "terms", None::<&()>).await?;
+        Ok(response.policies)
+    }
+
+    /// Accept the policies with the given URLs.
+    ///
+    /// # Arguments
+    ///
+    /// * `urls` - The URLs of the accepted policies, one translation per
+    /// policy is enough.
+    pub async fn accept_terms(&self, urls: &[&str]) -> Result<()> {
+        #[derive(Serialize)]
+        struct AcceptTermsRequest<'a> {
+            user_accepts: &'a [&'a str],
+        }
+
+        let body = AcceptTermsRequest { user_accepts: urls };
+        let _: EmptyResponse = self.send(Method::POST, "terms", Some(&body)).await?;
+
+        Ok(())
+    }
+
+    /// Look up the Matrix users that the given third party ids are bound to.
+    ///
+    /// Returns the user id for every given third party id in the same order,
+    /// `None` if the third party id isn't bound to a user.
+    ///
+    /// The addresses are hashed before they are sent to the identity server,
+    /// an [`Error::HashedLookupUnsupported`] is returned if the identity
+    /// server doesn't support that.
+    ///
+    /// # Arguments
+    ///
+    /// * `threepids` - The medium and the address of the third party ids.
+    pub async fn lookup(&self, threepids: &[(Medium, &str)]) -> Result<Vec<Option<UserId>>> {
+        self.lookup_helper(threepids, false).await
+    }
+
+    /// Like [`lookup()`](#method.lookup), but if the identity server doesn't
+    /// support hashed lookups, the addresses are sent to it in plain text.
+    ///
+    /// # Arguments
+    ///
+    /// * `threepids` - The medium and the address of the third party ids.
+    pub async fn lookup_allowing_plaintext(
+        &self,
+        threepids: &[(Medium, &str)],
+    ) -> Result<Vec<Option<UserId>>> {
+        self.lookup_helper(threepids, true).await
+    }
+
+    async fn lookup_helper(
+        &self,
+        threepids: &[(Medium, &str)],
+        allow_plaintext: bool,
+    ) -> Result<Vec<Option<UserId>>> {
+        let details: HashDetailsResponse =
+            self.send(Method::GET, "hash_details", None::<&()>).await?;
+
+        let hashed = details.algorithms.iter().any(|a| a == "sha256");
+
+        if !hashed && !allow_plaintext {
+            return Err(Error::HashedLookupUnsupported);
+        }
+
+        let addresses: Vec<String> = threepids
+            .iter()
+            .map(|(medium, address)| {
+                lookup_address(medium, address, hashed.then(|| details.lookup_pepper.as_str()))
+            })
+            .collect();
+
+        #[derive(Serialize)]
+        struct LookupRequest<'a> {
+            addresses: &'a [String],
+            algorithm: &'a str,
+            pepper: &'a str,
+        }
+
+        let body = LookupRequest {
+            addresses: &addresses,
+            algorithm: if hashed { "sha256" } else { "none" },
+            pepper: &details.lookup_pepper,
+        };
+        let mut response: LookupResponse = self.send(Method::POST, "lookup", Some(&body)).await?;
+
+        Ok(addresses.iter().map(|a| response.mappings.remove(a)).collect())
+    }
+
+    /// Look up the Matrix user that the given email address is bound to.
+    pub async fn lookup_email(&self, address: &str) -> Result<Option<UserId>> {
+        Ok(self.lookup(&[(Medium::Email, address)]).await?.pop().flatten())
+    }
+
+    async fn send<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<R> {
+        send(
+            &self.client,
+            &self.credentials.url,
+            Some(&self.credentials.access_token),
+            method,
+            path,
+            body,
+        )
+        .await
+    }
+}
+
+/// Get the address that is sent to the identity server for a lookup, hashed
+/// with the given pepper if there is one.
+fn lookup_address(medium: &Medium, address: &str, pepper: Option<&str>) -> String {
+    // Email addresses are case insensitive, the identity server stores them
+    // in lower case.
+    let address = match medium {
+        Medium::Email => address.to_lowercase(),
+        _ => address.to_owned(),
+    };
+
+    match pepper {
+        Some(pepper) => {
+            let hash = Sha256::digest(format!("{} {} {}", address, medium, pepper).as_bytes());
+            base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+        }
+        None => format!("{} {}", address, medium),
+    }
+}
+
+/// Send a request to the v2 API of the identity server at the given URL.
+async fn send<B: Serialize, R: DeserializeOwned>(
+    client: &Client,
+    url: &Url,
+    access_token: Option<&str>,
+    method: Method,
+    path: &str,
+    body: Option<&B>,
+) -> Result<R> {
+    let uri = format!("{}/_matrix/identity/v2/{}", url.as_str().trim_end_matches('/'), path);
+    let mut request = http::Request::builder().method(method).uri(uri);
+
+    if let Some(access_token) = access_token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", access_token));
+    }
+
+    let body = match body {
+        Some(body) => Bytes::from(serde_json::to_vec(body)?),
+        None => Bytes::new(),
+    };
+    let request = request.body(body).map_err(|e| HttpError::IntoHttp(e.into()))?;
+
+    let config = client.http_client.request_config;
+    let response = client.http_client.inner.send_request(request, config).await?;
+
+    if response.status().is_success() {
+        Ok(serde_json::from_slice(response.body())?)
+    } else {
+        let error = match ClientError::try_from_http_response(response) {
+            Ok(e) => ServerError::Known(e),
+            Err(e) => ServerError::Unknown(e),
+        };
+
+        Err(HttpError::ClientApi(FromHttpResponseError::Http(error)).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::thirdparty::Medium;
+    use url::Url;
+
+    use super::{lookup_address, Credentials};
+
+    #[test]
+    fn hashed_lookup_address() {
+        // The example from the identity service API spec.
+        assert_eq!(
+            lookup_address(&Medium::Email, "alice@example.com", Some("matrixrocks")),
+            "4kenr7N9drpCJ4AfalmlGQVsOn3o2RHjkADUpXJWZUc"
+        );
+        assert_eq!(
+            lookup_address(&Medium::Email, "Alice@Example.com", None),
+            "alice@example.com email"
+        );
+    }
+
+    #[test]
+    fn credentials_debug_hides_the_access_token() {
+        let credentials = Credentials {
+            url: Url::parse("https://identity.example.com").unwrap(),
+            access_token: "secret_token".to_owned(),
+        };
+
+        assert!(!format!("{:?}", credentials).contains("secret_token"));
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `/keys/upload` endpoint with support for fallback keys as described in
+//! [MSC2732], Ruma doesn't support them yet.
+//!
+//! [MSC2732]: https://github.com/matrix-org/matrix-doc/pull/2732
+
+use bytes::BufMut;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use matrix_sdk_base::crypto::KeysUploadRequest;
+use ruma::{
+    api::{
+        client::{r0::keys::upload_keys, Error as ClientError},
+        error::IntoHttpError,
+        AuthScheme, Metadata, OutgoingRequest, SendAccessToken,
+    },
+    serde::json_to_buf,
+};
+
+#[derive(Debug)]
+pub(crate) struct Request<'a> {
+    pub keys: &'a KeysUploadRequest,
+}
+
+impl OutgoingRequest for Request<'_> {
+    type EndpointError = ClientError;
+    type IncomingResponse = upload_keys::Response;
+
+    const METADATA: Metadata = Metadata {
+        description: "Publishes end-to-end encryption keys for the device.",
+        method: http::Method::POST,
+        name: "upload_keys",
+        path: "/_matrix/client/r0/keys/upload",
+        rate_limited: false,
+        authentication: AuthScheme::AccessToken,
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let uri = format!(
+            "{}/_matrix/client/r0/keys/upload",
+            base_url.strip_suffix('/').unwrap_or(base_url),
+        );
+
+        let access_token =
+            access_token.get_required_for_endpoint().ok_or(IntoHttpError::NeedsAuthentication)?;
+
+        Ok(http::Request::builder()
+            .method(Self::METADATA.method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .body(json_to_buf(self.keys)?)?)
+    }
+}
@@ -43,7 +43,12 @@
 //! keys. If this is disabled and `encryption` support is enabled the keys will
 //! by default be stored only in memory and thus lost after the client is
 //! destroyed.
-//! * `markdown`: Support for sending markdown formatted messages.
+//! * `markdown`: Support for composing messages from markdown, see
+//! [`RoomMessage::markdown()`](prelude::RoomMessage::markdown).
+//! * `metrics`: Enables tracing spans for the stages of the sync processing
+//! and collects metrics about every processed sync response.
+//! * `passphrase_strength`: Enables the estimation of the strength of the
+//! store passphrase, weak passphrases can be refused.
 //! * `socks`: Enables SOCKS support in reqwest, the default HTTP client.
 //! * `sso_login`: Enables SSO login with a local http server.
 //! * `require_auth_for_profile_requests`: Whether to send the access token in
@@ -53,6 +58,9 @@
 //! default.
 //! * `appservice`: Enables low-level appservice functionality. For an
 //!   high-level API there's the `matrix-sdk-appservice` crate
+//! * `async-std`: Adds an executor that runs the tasks of the SDK on async-std,
+//! see the [`executor`] module. The default HTTP client still needs a tokio
+//! runtime, a custom [`HttpSend`] implementation can be used to avoid it.
 
 #![deny(
     missing_debug_implementations,
@@ -76,13 +84,28 @@ compile_error!("only one of 'native-tls' or 'rustls-tls' features can be enabled
 compile_error!("'sso_login' cannot be enabled on 'wasm32' arch");
 
 pub use bytes::{Bytes, BytesMut};
+#[cfg(feature = "passphrase_strength")]
+#[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+pub use matrix_sdk_base::crypto::estimate_passphrase_strength;
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
-pub use matrix_sdk_base::crypto::{EncryptionInfo, LocalTrust};
+pub use matrix_sdk_base::crypto::{
+    CrossSigningStatus, DeviceListChange, EncryptionHealth, EncryptionInfo, LocalTrust,
+    MaintenanceSettings, MaintenanceSummary, PassphraseStrength, RejectedDevice, RoomKeyCounts,
+    RoomKeyExportFilter,
+};
+#[cfg(feature = "sled_state_store")]
+#[cfg_attr(feature = "docs", doc(cfg(sled_state_store)))]
+pub use matrix_sdk_base::IntegrityReport;
 pub use matrix_sdk_base::{
-    media, Error as BaseError, Room as BaseRoom, RoomInfo, RoomMember as BaseRoomMember, RoomType,
-    Session, StateChanges, StoreError,
+    media, Aggregations, Error as BaseError, InvitedRoomInfo, Inviter, PowerLevelsChange,
+    PowerLevelsDiff, RelationType, Room as BaseRoom, RoomInfo, RoomInfoChanges, RoomListDiff,
+    RoomListEntry, RoomListFilter, RoomListOrder, RoomListService, RoomListUpdate,
+    RoomMember as BaseRoomMember, RoomType, Session, StateChanges, StoreError,
 };
+#[cfg(feature = "metrics")]
+#[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+pub use matrix_sdk_base::{MetricsExporter, SyncMetrics};
 pub use matrix_sdk_common::*;
 pub use reqwest;
 #[cfg(feature = "appservice")]
@@ -103,27 +126,47 @@ pub use ruma::{
     thirdparty, uint, Int, MilliSecondsSinceUnixEpoch, Outgoing, SecondsSinceUnixEpoch, UInt,
 };
 
+pub mod call;
 mod client;
 mod error;
 mod event_handler;
+pub mod extensible;
+pub mod html;
 mod http_client;
+pub mod identity;
+mod limiter;
+mod manager;
+pub mod moderation;
+pub mod prelude;
 /// High-level room API
 pub mod room;
 /// High-level room API
 mod room_member;
+pub mod task;
+mod tls;
+pub mod validation;
 
 #[cfg(feature = "encryption")]
 mod device;
 #[cfg(feature = "encryption")]
+mod keys_upload;
+#[cfg(feature = "encryption")]
 pub mod verification;
+pub mod widget;
 
-pub use client::{Client, ClientConfig, LoopCtrl, RequestConfig, SyncSettings};
+pub use client::{
+    Client, ClientConfig, LoopCtrl, RequestConfig, ServerFeature, SyncSettings, SyncState,
+    ToDevicePassthrough,
+};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use device::Device;
-pub use error::{Error, HttpError, Result};
+pub use error::{Error, ErrorCategory, HttpError, Result};
 pub use event_handler::{CustomEvent, EventHandler};
 pub use http_client::HttpSend;
+pub use limiter::RequestCategory;
+pub use manager::ClientManager;
 pub use room_member::RoomMember;
+pub use tls::TlsBackend;
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,137 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// The categories of requests that tend to be sent in bursts, the number of
+/// concurrent requests of each category is limited.
+///
+/// The limits can be changed using
+/// [ClientConfig::request_limit()](crate::ClientConfig::request_limit).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestCategory {
+    /// Downloads of media content and thumbnails, 8 concurrent requests by
+    /// default.
+    Media,
+    /// Fetches of the member list of a room, 4 concurrent requests by default.
+    Members,
+    /// Queries of the device keys of users, 2 concurrent requests by default.
+    KeysQuery,
+}
+
+impl RequestCategory {
+    const ALL: [RequestCategory; 3] = [Self::Media, Self::Members, Self::KeysQuery];
+
+    fn default_limit(self) -> usize {
+        match self {
+            Self::Media => 8,
+            Self::Members => 4,
+            Self::KeysQuery => 2,
+        }
+    }
+}
+
+/// Limits the number of concurrent requests per [RequestCategory].
+///
+/// On WASM the browser already limits the number of connections per host, the
+/// limiter doesn't limit anything there.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RequestLimiter {
+    #[cfg(not(target_arch = "wasm32"))]
+    semaphores: Arc<BTreeMap<RequestCategory, Semaphore>>,
+}
+
+impl RequestLimiter {
+    /// Create a new limiter, the given limits override the default ones and a
+    /// limit of `0` disables the limit of a category.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    pub(crate) fn new(limits: &BTreeMap<RequestCategory, usize>) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        let semaphores = RequestCategory::ALL
+            .iter()
+            .filter_map(|c| {
+                let limit = limits.get(c).copied().unwrap_or_else(|| c.default_limit());
+                (limit > 0).then(|| (*c, Semaphore::new(limit)))
+            })
+            .collect();
+
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            semaphores: Arc::new(semaphores),
+        }
+    }
+
+    /// Wait until a request of the given category may be sent, the request
+    /// should be sent while the returned permit is held.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) async fn acquire(&self, category: RequestCategory) -> Option<SemaphorePermit<'_>> {
+        match self.semaphores.get(&category) {
+            Some(semaphore) => {
+                Some(semaphore.acquire().await.expect("The request semaphores are never closed"))
+            }
+            None => None,
+        }
+    }
+
+    /// Wait until a request of the given category may be sent, the request
+    /// should be sent while the returned permit is held.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) async fn acquire(&self, _category: RequestCategory) -> Option<()> {
+        None
+    }
+
+    /// The number of requests of the given category that may still be sent
+    /// right away, `None` if the category isn't limited.
+    #[cfg(all(test, not(target_arch = "wasm32")))]
+    pub(crate) fn available(&self, category: RequestCategory) -> Option<usize> {
+        self.semaphores.get(&category).map(|s| s.available_permits())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use std::collections::BTreeMap;
+
+    use futures::FutureExt;
+
+    use super::{RequestCategory, RequestLimiter};
+
+    #[tokio::test]
+    async fn request_limits() {
+        let mut limits = BTreeMap::new();
+        limits.insert(RequestCategory::Media, 2);
+        limits.insert(RequestCategory::Members, 0);
+        let limiter = RequestLimiter::new(&limits);
+
+        assert_eq!(limiter.available(RequestCategory::Media), Some(2));
+        assert_eq!(limiter.available(RequestCategory::Members), None);
+        assert_eq!(limiter.available(RequestCategory::KeysQuery), Some(2));
+
+        let first = limiter.acquire(RequestCategory::Media).await;
+        let _second = limiter.acquire(RequestCategory::Media).await;
+        assert_eq!(limiter.available(RequestCategory::Media), Some(0));
+
+        // A third request has to wait until one of the running ones finishes.
+        assert!(limiter.acquire(RequestCategory::Media).now_or_never().is_none());
+        drop(first);
+        assert!(limiter.acquire(RequestCategory::Media).now_or_never().is_some());
+
+        assert!(limiter.acquire(RequestCategory::Members).await.is_none());
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the clients of many accounts in a single process.
+
+use std::{
+    fmt::{self, Debug},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use dashmap::DashMap;
+use futures::future::join_all;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use ruma::UserId;
+use url::Url;
+
+use crate::{
+    http_client::client_with_config, task::TaskScheduler, Client, ClientConfig, EventHandler,
+    HttpSend, RequestConfig, Result, Session, SyncSettings, ToDevicePassthrough,
+};
+
+/// Manages the clients of many accounts in a single process, e.g. for bridges
+/// or bots that run hundreds of sessions.
+///
+/// All the clients of a manager share a single HTTP client, and thus its
+/// connection pool, as well as a single [`TaskScheduler`] for their background
+/// tasks, and store their state in a subdirectory of a common store
+/// directory. The sync loops of all clients can be driven by a single task
+/// using [`sync_all()`](#method.sync_all), every client has its own event
+/// handler.
+///
+/// # Example
+///
+/// ```no_run
+/// # use futures::executor::block_on;
+/// # use matrix_sdk::{ClientConfig, ClientManager, Session, SyncSettings};
+/// # use url::Url;
+/// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+/// # let sessions: Vec<Session> = Vec::new();
+/// # block_on(async {
+/// let manager = ClientManager::new(ClientConfig::new())?.store_dir("/var/lib/my-bridge");
+///
+/// for session in sessions {
+///     manager.add_session(homeserver.clone(), session).await?;
+/// }
+///
+/// manager.sync_all(SyncSettings::default()).await;
+/// # matrix_sdk::Result::Ok(()) });
+/// ```
+#[derive(Clone)]
+pub struct ClientManager {
+    http_client: Arc<dyn HttpSend>,
+    task_scheduler: TaskScheduler,
+    request_config: RequestConfig,
+    to_device_passthrough: ToDevicePassthrough,
+    auto_join_room_upgrades: bool,
+    store_dir: Option<PathBuf>,
+    clients: Arc<DashMap<UserId, Client>>,
+    /// Locks making sure that only one client is created per account at a
+    /// time.
+    creation_locks: Arc<DashMap<UserId, Arc<StdMutex<()>>>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl Debug for ClientManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientManager")
+            .field("store_dir", &self.store_dir)
+            .field("accounts", &self.clients.len())
+            .finish()
+    }
+}
+
+impl ClientManager {
+    /// Create a new manager.
+    ///
+    /// The HTTP client that is shared by all clients is created from the given
+    /// config, its task scheduler, request config, to-device passthrough and
+    /// room upgrade settings are used for all clients. A new task scheduler is
+    /// created if the config doesn't have one. Store paths in the config are
+    /// ignored, see [`store_dir()`](#method.store_dir).
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        let http_client = match config.client.clone() {
+            Some(client) => client,
+            None => Arc::new(client_with_config(&config)?),
+        };
+
+        Ok(Self {
+            http_client,
+            task_scheduler: config.task_scheduler.unwrap_or_default(),
+            request_config: config.request_config,
+            to_device_passthrough: config.to_device_passthrough,
+            auto_join_room_upgrades: config.auto_join_room_upgrades,
+            store_dir: None,
+            clients: Arc::new(DashMap::new()),
+            creation_locks: Arc::new(DashMap::new()),
+        })
+    }
+
+    /// Set the directory the stores of the clients are saved in.
+    ///
+    /// Every account gets its own subdirectory which is created when the
+    /// client of the account is created. If no directory is set the state of
+    /// the clients is only kept in memory.
+    pub fn store_dir(mut self, path: impl AsRef<Path>) -> Self {
+        self.store_dir = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Get the path the stores of the given account are saved in, `None` if
+    /// no store directory is set.
+    pub fn store_path(&self, user_id: &UserId) -> Option<PathBuf> {
+        self.store_dir.as_ref().map(|dir| {
+            dir.join(utf8_percent_encode(user_id.as_str(), NON_ALPHANUMERIC).to_string())
+        })
+    }
+
+    /// Create the client of the given account, which still needs to log in.
+    ///
+    /// If a client for the account exists already, it's returned instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `homeserver_url` - The homeserver of the account.
+    ///
+    /// * `user_id` - The user id of the account, used to pick the store path.
+    pub fn create_client(&self, homeserver_url: Url, user_id: &UserId) -> Result<Client> {
+        self.get_or_create_client(homeserver_url, user_id).map(|(client, _)| client)
+    }
+
+    /// Get the client of the given account or create it if there is none,
+    /// returns whether the client was created as well.
+    fn get_or_create_client(
+        &self,
+        homeserver_url: Url,
+        user_id: &UserId,
+    ) -> Result<(Client, bool)> {
+        if let Some(client) = self.get(user_id) {
+            return Ok((client, false));
+        }
+
+        // The creation lock makes sure that concurrent calls for the same
+        // account don't create two clients that use the same store. The store
+        // is opened while holding only this lock, not a lock of the map of
+        // clients which would block the calls for other accounts as well.
+        let lock = self.creation_locks.entry(user_id.clone()).or_default().clone();
+        let _guard = lock.lock().unwrap();
+
+        // Another call might have created the client while we were waiting.
+        if let Some(client) = self.get(user_id) {
+            return Ok((client, false));
+        }
+
+        let result = self.new_client(homeserver_url, user_id);
+
+        if let Ok(client) = &result {
+            self.clients.insert(user_id.clone(), client.clone());
+        }
+
+        // Only forget the lock if no other call is waiting for it, the map and
+        // this call hold the only references then.
+        self.creation_locks.remove_if(user_id, |_, l| Arc::strong_count(l) == 2);
+
+        result.map(|client| (client, true))
+    }
+
+    fn new_client(&self, homeserver_url: Url, user_id: &UserId) -> Result<Client> {
+        let mut config = ClientConfig::new()
+            .client(self.http_client.clone())
+            .task_scheduler(self.task_scheduler.clone())
+            .request_config(self.request_config)
+            .to_device_passthrough(self.to_device_passthrough)
+            .auto_join_room_upgrades(self.auto_join_room_upgrades);
+
+        if let Some(path) = self.store_path(user_id) {
+            std::fs::create_dir_all(&path)?;
+            config = config.store_path(path);
+        }
+
+        Client::new_with_config(homeserver_url, config)
+    }
+
+    /// Create the client of an account that is already logged in and restore
+    /// its session.
+    ///
+    /// If a client for the account exists already, the session is restored
+    /// in it instead. The client is only removed from the manager again if
+    /// restoring the session fails and the client was created by this call.
+    ///
+    /// # Arguments
+    ///
+    /// * `homeserver_url` - The homeserver of the account.
+    ///
+    /// * `session` - The session of the account, e.g. from an earlier login.
+    pub async fn add_session(&self, homeserver_url: Url, session: Session) -> Result<Client> {
+        let user_id = session.user_id.clone();
+        let (client, created) = self.get_or_create_client(homeserver_url, &user_id)?;
+
+        if let Err(e) = client.restore_login(session).await {
+            if created {
+                self.clients.remove(&user_id);
+            }
+
+            return Err(e);
+        }
+
+        Ok(client)
+    }
+
+    /// Get the client of the given account.
+    pub fn get(&self, user_id: &UserId) -> Option<Client> {
+        self.clients.get(user_id).map(|c| c.clone())
+    }
+
+    /// Get the clients of all accounts.
+    pub fn clients(&self) -> Vec<Client> {
+        self.clients.iter().map(|c| c.value().clone()).collect()
+    }
+
+    /// Set the event handler of the given account.
+    ///
+    /// Returns `false` if the manager has no client for the account.
+    pub async fn set_event_handler(
+        &self,
+        user_id: &UserId,
+        handler: Box<dyn EventHandler>,
+    ) -> bool {
+        match self.get(user_id) {
+            Some(client) => {
+                client.set_event_handler(handler).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Shut the client of the given account down and remove it from the
+    /// manager, see [`Client::shutdown()`].
+    pub async fn remove(&self, user_id: &UserId) -> Result<Option<Client>> {
+        match self.clients.remove(user_id) {
+            Some((_, client)) => {
+                client.shutdown().await?;
+                Ok(Some(client))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Run the sync loops of all clients concurrently, resuming from their
+    /// stored sync tokens, see [`Client::start_sync()`].
+    ///
+    /// Only the clients that exist when the method is called are synced, the
+    /// method returns once all of their sync loops are stopped.
+    pub async fn sync_all(&self, sync_settings: SyncSettings<'_>) {
+        let clients = self.clients();
+
+        join_all(clients.iter().map(|c| c.start_sync(sync_settings.clone()))).await;
+    }
+
+    /// Stop the sync loops of all clients, see [`Client::stop_sync()`].
+    pub async fn stop_all(&self) -> Result<()> {
+        for result in join_all(self.clients().iter().map(|c| c.stop_sync())).await {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    /// Shut the clients of all accounts down, see [`Client::shutdown()`].
+    pub async fn shutdown(&self) -> Result<()> {
+        for result in join_all(self.clients().iter().map(|c| c.shutdown())).await {
+            result?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{channel::oneshot, StreamExt};
+    use matrix_sdk_test::test_json;
+    use mockito::{mock, Matcher};
+    use ruma::{room_id, user_id};
+    use url::Url;
+
+    use super::ClientManager;
+    use crate::{ClientConfig, Session, SyncSettings, SyncState};
+
+    #[tokio::test]
+    async fn manage_accounts() {
+        let homeserver = Url::parse(&mockito::server_url()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ClientManager::new(ClientConfig::new()).unwrap().store_dir(dir.path());
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        for (user_id, token) in &[("@alice:localhost", "alice"), ("@bob:localhost", "bob")] {
+            let session = Session {
+                access_token: token.to_string(),
+                user_id: user_id.parse().unwrap(),
+                device_id: "DEVICEID".into(),
+            };
+            manager.add_session(homeserver.clone(), session).await.unwrap();
+        }
+
+        let alice = user_id!("@alice:localhost");
+        let store_path = manager.store_path(&alice).unwrap();
+
+        assert_eq!(store_path, dir.path().join("%40alice%3Alocalhost"));
+        assert!(store_path.is_dir());
+        assert_eq!(manager.clients().len(), 2);
+
+        manager.create_client(homeserver.clone(), &alice).unwrap();
+        assert_eq!(manager.clients().len(), 2);
+        assert!(manager.creation_locks.is_empty());
+
+        // The background tasks of all clients run on the shared scheduler.
+        let mut receivers = Vec::new();
+
+        for client in manager.clients() {
+            let (sender, receiver) = oneshot::channel();
+            receivers.push(receiver);
+            client.spawn_task("test_task", async move {
+                sender.send(()).unwrap();
+                Ok(())
+            });
+        }
+
+        for receiver in receivers {
+            receiver.await.unwrap();
+        }
+
+        let mut states: Vec<_> = manager.clients().iter().map(|c| c.sync_state_updates()).collect();
+
+        let sync_manager = manager.clone();
+        let sync = tokio::spawn(async move { sync_manager.sync_all(SyncSettings::new()).await });
+
+        for states in &mut states {
+            while states.next().await != Some(SyncState::Syncing) {}
+        }
+
+        manager.stop_all().await.unwrap();
+        sync.await.unwrap();
+
+        for client in manager.clients() {
+            assert!(client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).is_some());
+        }
+
+        assert!(manager.remove(&alice).await.unwrap().is_some());
+        assert!(manager.get(&alice).is_none());
+        assert!(manager.remove(&alice).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for moderation bots.
+//!
+//! Besides the moderation methods of joined rooms, like
+//! [Joined::redact_recent_messages_from()](crate::room::Joined::redact_recent_messages_from)
+//! and [Joined::set_server_acl()](crate::room::Joined::set_server_acl), this
+//! module contains support for ban lists.
+//!
+//! A ban list is a room whose state contains policy rules as described in
+//! [MSC2313], e.g. that a user or a server should be banned. The client keeps
+//! the ban lists it's subscribed to up to date while it syncs, see
+//! [Client::subscribe_to_ban_list()](crate::Client::subscribe_to_ban_list).
+//!
+//! [MSC2313]: https://github.com/matrix-org/matrix-doc/pull/2313
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock as StdRwLock},
+};
+
+use ruma::{events::policy::rule::Recommendation, RoomId, ServerName, UserId};
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+
+/// The kind of entity a policy rule applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PolicyEntity {
+    /// The rule applies to users, its entity is a glob of user ids.
+    User,
+    /// The rule applies to rooms, its entity is a glob of room ids.
+    Room,
+    /// The rule applies to servers, its entity is a glob of server names.
+    Server,
+}
+
+impl PolicyEntity {
+    fn from_event_type(event_type: &str) -> Option<Self> {
+        // Ban lists that were created before the event types were specified
+        // use the `m.room.rule.*` and `org.matrix.mjolnir.rule.*` types.
+        match event_type {
+            "m.policy.rule.user" | "m.room.rule.user" | "org.matrix.mjolnir.rule.user" => {
+                Some(Self::User)
+            }
+            "m.policy.rule.room" | "m.room.rule.room" | "org.matrix.mjolnir.rule.room" => {
+                Some(Self::Room)
+            }
+            "m.policy.rule.server" | "m.room.rule.server" | "org.matrix.mjolnir.rule.server" => {
+                Some(Self::Server)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A policy rule of a ban list.
+#[derive(Clone, Debug)]
+pub struct PolicyRule {
+    /// The kind of entity the rule applies to.
+    pub kind: PolicyEntity,
+    /// The entities the rule applies to, `*` matches zero or more characters
+    /// and `?` matches exactly one character.
+    pub entity: String,
+    /// The action that should be taken against the entities.
+    pub recommendation: Recommendation,
+    /// The reason for the rule.
+    pub reason: String,
+}
+
+impl PolicyRule {
+    /// Does the rule apply to the given entity.
+    pub fn matches(&self, entity: &str) -> bool {
+        glob_matches(&self.entity, entity)
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct PolicyRuleContent {
+    entity: Option<String>,
+    recommendation: Option<Recommendation>,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct PolicyRuleEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: Option<String>,
+    #[serde(default)]
+    content: PolicyRuleContent,
+}
+
+/// A ban list the client is subscribed to.
+///
+/// The rules of the list are updated while the client syncs, clones of a ban
+/// list share the same rules.
+#[derive(Clone, Debug)]
+pub struct BanList {
+    room_id: RoomId,
+    rules: Arc<StdRwLock<BTreeMap<(PolicyEntity, String), PolicyRule>>>,
+}
+
+impl BanList {
+    pub(crate) fn new(room_id: RoomId) -> Self {
+        Self { room_id, rules: Default::default() }
+    }
+
+    /// The id of the room that contains the ban list.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// The current rules of the ban list.
+    pub fn rules(&self) -> Vec<PolicyRule> {
+        self.rules.read().unwrap().values().cloned().collect()
+    }
+
+    /// Get the rule that bans the given user, either directly or because the
+    /// user is on a banned server.
+    pub fn user_ban(&self, user_id: &UserId) -> Option<PolicyRule> {
+        self.ban(PolicyEntity::User, user_id.as_str())
+            .or_else(|| self.server_ban(user_id.server_name()))
+    }
+
+    /// Get the rule that bans the given room.
+    pub fn room_ban(&self, room_id: &RoomId) -> Option<PolicyRule> {
+        self.ban(PolicyEntity::Room, room_id.as_str())
+    }
+
+    /// Get the rule that bans the given server.
+    pub fn server_ban(&self, server_name: &ServerName) -> Option<PolicyRule> {
+        self.ban(PolicyEntity::Server, server_name.as_str())
+    }
+
+    /// Is the given user banned, either directly or because the user is on a
+    /// banned server.
+    pub fn is_user_banned(&self, user_id: &UserId) -> bool {
+        self.user_ban(user_id).is_some()
+    }
+
+    fn ban(&self, kind: PolicyEntity, entity: &str) -> Option<PolicyRule> {
+        self.rules
+            .read()
+            .unwrap()
+            .values()
+            .find(|r| {
+                r.kind == kind && r.recommendation == Recommendation::Ban && r.matches(entity)
+            })
+            .cloned()
+    }
+
+    /// Update the rules with the given state event of the ban list room.
+    ///
+    /// Events that aren't policy rules are ignored, a policy rule event
+    /// without an entity removes the rule.
+    pub(crate) fn handle_event(&self, event: &RawJsonValue) {
+        let event = match serde_json::from_str::<PolicyRuleEvent>(event.get()) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+
+        let kind = match PolicyEntity::from_event_type(&event.event_type) {
+            Some(k) => k,
+            None => return,
+        };
+        let state_key = match event.state_key {
+            Some(s) => s,
+            None => return,
+        };
+
+        let mut rules = self.rules.write().unwrap();
+        let content = event.content;
+
+        match (content.entity, content.recommendation) {
+            (Some(entity), Some(recommendation)) => {
+                let rule = PolicyRule { kind, entity, recommendation, reason: content.reason };
+                rules.insert((kind, state_key), rule);
+            }
+            _ => {
+                rules.remove(&(kind, state_key));
+            }
+        }
+    }
+}
+
+/// Match a value against a glob where `*` matches zero or more characters and
+/// `?` matches exactly one character.
+fn glob_matches(glob: &str, value: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut g, mut v) = (0, 0);
+    // The position after the last `*` in the glob and the position in the
+    // value it was matched up to, to backtrack to on a mismatch.
+    let mut backtrack = None;
+
+    while v < value.len() {
+        match glob.get(g) {
+            Some('*') => {
+                g += 1;
+                backtrack = Some((g, v));
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                g += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star_g, star_v)) => {
+                    g = star_g;
+                    v = star_v + 1;
+                    backtrack = Some((star_g, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{room_id, user_id};
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{glob_matches, BanList};
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("@*:evil.org", "@spammer:evil.org"));
+        assert!(glob_matches("*.evil.org", "matrix.evil.org"));
+        assert!(!glob_matches("*.evil.org", "evil.org"));
+        assert!(glob_matches("@spam?:example.org", "@spam1:example.org"));
+        assert!(!glob_matches("@spam?:example.org", "@spam:example.org"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn ban_list_rules() {
+        let ban_list = BanList::new(room_id!("!banlist:example.org"));
+
+        let rule = |event_type: &str, state_key: &str, content| {
+            to_raw_value(&json!({
+                "type": event_type,
+                "state_key": state_key,
+                "sender": "@moderator:example.org",
+                "content": content,
+            }))
+            .unwrap()
+        };
+
+        ban_list.handle_event(&rule(
+            "m.policy.rule.server",
+            "rule1",
+            json!({ "entity": "*.evil.org", "recommendation": "m.ban", "reason": "spam" }),
+        ));
+        ban_list.handle_event(&rule(
+            "org.matrix.mjolnir.rule.user",
+            "rule2",
+            json!({ "entity": "@troll:example.org", "recommendation": "m.ban", "reason": "" }),
+        ));
+
+        assert_eq!(ban_list.rules().len(), 2);
+        assert!(ban_list.is_user_banned(&user_id!("@spammer:matrix.evil.org")));
+        assert!(ban_list.is_user_banned(&user_id!("@troll:example.org")));
+        assert!(!ban_list.is_user_banned(&user_id!("@alice:example.org")));
+
+        // Rules are removed by sending the event again without content.
+        ban_list.handle_event(&rule("m.policy.rule.server", "rule1", json!({})));
+        assert!(!ban_list.is_user_banned(&user_id!("@spammer:matrix.evil.org")));
+    }
+}
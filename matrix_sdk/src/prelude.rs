@@ -0,0 +1,379 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A curated set of the most commonly used types of the SDK.
+//!
+//! The prelude re-exports the client types together with the ruma types that
+//! most applications need, so they can be imported with a single line:
+//!
+//! ```
+//! use matrix_sdk::prelude::*;
+//! ```
+//!
+//! The prelude consists of two parts:
+//!
+//! * The facade types and traits that are defined in this module, i.e.
+//!   [`ParseId`], [`RoomMessage`], [`Reaction`] and [`RoomTopic`]. They are
+//!   owned by the SDK and keep their API across ruma upgrades, so creating
+//!   identifiers and the most common event contents doesn't depend on the
+//!   constructors and the layout of the ruma types. They can be converted into
+//!   the ruma types that the client and room methods accept, and back.
+//!
+//! * The ruma types that the API of the SDK itself uses, e.g. the identifiers
+//!   and the event types that event handlers receive. They follow the ruma
+//!   version of the SDK, but if a ruma type gets renamed or moved the prelude
+//!   keeps exporting it under the old name.
+
+use std::convert::TryFrom;
+
+use ruma::events::{
+    reaction::{ReactionEventContent, Relation},
+    room::{
+        message::{
+            EmoteMessageEventContent, MessageType, NoticeMessageEventContent,
+            TextMessageEventContent,
+        },
+        topic::TopicEventContent,
+    },
+    AnyStateEventContent,
+};
+pub use ruma::{
+    events::{
+        room::{member::MemberEventContent, message::MessageEventContent},
+        AnyMessageEventContent, AnyRoomEvent, AnySyncRoomEvent, AnyToDeviceEvent, EventType,
+        StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
+    },
+    identifiers::{
+        DeviceId, DeviceIdBox, EventId, MxcUri, RoomAliasId, RoomId, RoomIdOrAliasId, ServerName,
+        ServerNameBox, UserId,
+    },
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch, UInt,
+};
+
+#[cfg(feature = "encryption")]
+#[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+pub use crate::Device;
+use crate::Result;
+pub use crate::{
+    async_trait,
+    room::{Common, Invited, Joined, Left, Room},
+    Client, ClientConfig, Error, EventHandler, LoopCtrl, RequestConfig, RoomMember, Session,
+    SyncSettings,
+};
+
+/// Parse a Matrix identifier from a string.
+///
+/// ruma changes how its identifiers are constructed from time to time, this
+/// trait keeps a single way to parse them.
+///
+/// # Examples
+///
+/// ```
+/// # use matrix_sdk::prelude::*;
+/// let user_id = UserId::parse_id("@alice:example.org").unwrap();
+/// assert_eq!(user_id.localpart(), "alice");
+///
+/// assert!(RoomId::parse_id("alice").is_err());
+/// ```
+pub trait ParseId: Sized {
+    /// Parse the given string as an identifier of this kind.
+    ///
+    /// Returns an [`Error::Identifier`] if the string isn't a valid
+    /// identifier of this kind.
+    fn parse_id(id: impl AsRef<str>) -> Result<Self>;
+}
+
+macro_rules! parse_id_impls {
+    ($($id:ty),*) => {
+        $(
+            impl ParseId for $id {
+                fn parse_id(id: impl AsRef<str>) -> Result<Self> {
+                    Ok(<$id>::try_from(id.as_ref())?)
+                }
+            }
+        )*
+    };
+}
+
+parse_id_impls!(UserId, RoomId, EventId, RoomAliasId, RoomIdOrAliasId, ServerNameBox);
+
+/// A simple `m.room.message` event content.
+///
+/// This is a stable facade over the ruma message content, it can be converted
+/// into the content types that the room methods accept and created from
+/// received messages.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use futures::executor::block_on;
+/// # use matrix_sdk::prelude::*;
+/// # block_on(async {
+/// # let room: Joined = todo!();
+/// room.send(RoomMessage::text("Hello world"), None).await.unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct RoomMessage(MessageEventContent);
+
+impl RoomMessage {
+    /// Create a plain text message.
+    pub fn text(body: impl Into<String>) -> Self {
+        Self(MessageEventContent::text_plain(body))
+    }
+
+    /// Create a text message with a HTML formatted body.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The plain text version of the message.
+    ///
+    /// * `html_body` - The HTML version of the message.
+    pub fn html(body: impl Into<String>, html_body: impl Into<String>) -> Self {
+        Self(MessageEventContent::text_html(body, html_body))
+    }
+
+    /// Create a text message from markdown.
+    ///
+    /// The markdown is rendered to the HTML formatted body of the message, the
+    /// message is a plain text message if the markdown doesn't contain any
+    /// formatting. Mentions can be added as [pills](crate::html::user_pill)
+    /// since markdown allows inline HTML.
+    #[cfg(feature = "markdown")]
+    #[cfg_attr(feature = "docs", doc(cfg(markdown)))]
+    pub fn markdown(body: impl AsRef<str> + Into<String>) -> Self {
+        Self(MessageEventContent::new(MessageType::Text(TextMessageEventContent::markdown(body))))
+    }
+
+    /// Create a plain text notice, notices are usually sent by bots.
+    pub fn notice(body: impl Into<String>) -> Self {
+        Self(MessageEventContent::notice_plain(body))
+    }
+
+    /// Create a plain text emote, i.e. a `/me` message.
+    pub fn emote(body: impl Into<String>) -> Self {
+        Self(MessageEventContent::new(MessageType::Emote(EmoteMessageEventContent::plain(body))))
+    }
+
+    /// Get the plain text body of the message if it's a text, notice or emote
+    /// message.
+    pub fn text_body(&self) -> Option<&str> {
+        match &self.0.msgtype {
+            MessageType::Text(TextMessageEventContent { body, .. })
+            | MessageType::Notice(NoticeMessageEventContent { body, .. })
+            | MessageType::Emote(EmoteMessageEventContent { body, .. }) => Some(body),
+            _ => None,
+        }
+    }
+
+    /// Get the underlying ruma message content.
+    pub fn into_inner(self) -> MessageEventContent {
+        self.0
+    }
+}
+
+impl From<MessageEventContent> for RoomMessage {
+    fn from(content: MessageEventContent) -> Self {
+        Self(content)
+    }
+}
+
+impl From<&SyncMessageEvent<MessageEventContent>> for RoomMessage {
+    fn from(event: &SyncMessageEvent<MessageEventContent>) -> Self {
+        Self(event.content.clone())
+    }
+}
+
+impl From<RoomMessage> for MessageEventContent {
+    fn from(message: RoomMessage) -> Self {
+        message.0
+    }
+}
+
+impl From<RoomMessage> for AnyMessageEventContent {
+    fn from(message: RoomMessage) -> Self {
+        AnyMessageEventContent::RoomMessage(message.0)
+    }
+}
+
+/// An `m.reaction` event content, i.e. an annotation of another event.
+///
+/// This is a stable facade over the ruma reaction content.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use futures::executor::block_on;
+/// # use matrix_sdk::prelude::*;
+/// # block_on(async {
+/// # let room: Joined = todo!();
+/// let event_id = EventId::parse_id("$h29iv0s8:example.com").unwrap();
+/// room.send(Reaction::new(&event_id, "👍"), None).await.unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct Reaction(ReactionEventContent);
+
+impl Reaction {
+    /// Create a reaction to the given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that is reacted to.
+    ///
+    /// * `key` - The key of the reaction, usually an emoji.
+    pub fn new(event_id: &EventId, key: impl Into<String>) -> Self {
+        Self(ReactionEventContent::new(Relation::new(event_id.clone(), key.into())))
+    }
+
+    /// Get the id of the event that is reacted to.
+    pub fn event_id(&self) -> &EventId {
+        &self.0.relation.event_id
+    }
+
+    /// Get the key of the reaction.
+    pub fn key(&self) -> &str {
+        &self.0.relation.emoji
+    }
+
+    /// Get the underlying ruma reaction content.
+    pub fn into_inner(self) -> ReactionEventContent {
+        self.0
+    }
+}
+
+impl From<ReactionEventContent> for Reaction {
+    fn from(content: ReactionEventContent) -> Self {
+        Self(content)
+    }
+}
+
+impl From<Reaction> for ReactionEventContent {
+    fn from(reaction: Reaction) -> Self {
+        reaction.0
+    }
+}
+
+impl From<Reaction> for AnyMessageEventContent {
+    fn from(reaction: Reaction) -> Self {
+        AnyMessageEventContent::Reaction(reaction.0)
+    }
+}
+
+/// An `m.room.topic` state event content.
+///
+/// This is a stable facade over the ruma topic content, it can be sent with
+/// [`Joined::send_state_event()`] using an empty state key.
+#[derive(Clone, Debug)]
+pub struct RoomTopic(TopicEventContent);
+
+impl RoomTopic {
+    /// Create a topic content with the given topic.
+    pub fn new(topic: impl Into<String>) -> Self {
+        Self(TopicEventContent::new(topic.into()))
+    }
+
+    /// Get the topic.
+    pub fn topic(&self) -> &str {
+        &self.0.topic
+    }
+
+    /// Get the underlying ruma topic content.
+    pub fn into_inner(self) -> TopicEventContent {
+        self.0
+    }
+}
+
+impl From<TopicEventContent> for RoomTopic {
+    fn from(content: TopicEventContent) -> Self {
+        Self(content)
+    }
+}
+
+impl From<RoomTopic> for TopicEventContent {
+    fn from(topic: RoomTopic) -> Self {
+        topic.0
+    }
+}
+
+impl From<RoomTopic> for AnyStateEventContent {
+    fn from(topic: RoomTopic) -> Self {
+        AnyStateEventContent::RoomTopic(topic.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::events::{room::message::MessageType, AnyMessageEventContent, AnyStateEventContent};
+    use serde_json::json;
+
+    use super::{EventId, ParseId, Reaction, RoomMessage, RoomTopic, ServerNameBox, UserId};
+    use crate::Error;
+
+    #[test]
+    fn room_message() {
+        let message = RoomMessage::emote("waves");
+        assert_eq!(message.text_body(), Some("waves"));
+
+        match AnyMessageEventContent::from(message) {
+            AnyMessageEventContent::RoomMessage(content) => {
+                assert!(matches!(content.msgtype, MessageType::Emote(_)))
+            }
+            _ => panic!("Invalid message content"),
+        }
+
+        assert_eq!(RoomMessage::html("hello", "<b>hello</b>").text_body(), Some("hello"));
+    }
+
+    #[test]
+    fn parse_id() {
+        let user_id = UserId::parse_id("@alice:example.org").unwrap();
+        assert_eq!(user_id.localpart(), "alice");
+        assert_eq!(
+            ServerNameBox::parse_id(String::from("example.org")).unwrap().as_str(),
+            "example.org"
+        );
+
+        assert!(matches!(UserId::parse_id("alice"), Err(Error::Identifier(_))));
+        assert!(matches!(EventId::parse_id("!room:example.org"), Err(Error::Identifier(_))));
+    }
+
+    #[test]
+    fn reaction_and_topic() {
+        let event_id = EventId::parse_id("$h29iv0s8:example.com").unwrap();
+        let reaction = Reaction::new(&event_id, "👍");
+        assert_eq!(reaction.event_id(), &event_id);
+        assert_eq!(reaction.key(), "👍");
+
+        let content = AnyMessageEventContent::from(reaction);
+        assert_eq!(
+            serde_json::to_value(&content).unwrap(),
+            json!({
+                "m.relates_to": {
+                    "rel_type": "m.annotation",
+                    "event_id": "$h29iv0s8:example.com",
+                    "key": "👍",
+                }
+            })
+        );
+
+        let topic = RoomTopic::new("Cats");
+        assert_eq!(topic.topic(), "Cats");
+        assert!(matches!(
+            AnyStateEventContent::from(topic),
+            AnyStateEventContent::RoomTopic(c) if c.topic == "Cats"
+        ));
+    }
+}
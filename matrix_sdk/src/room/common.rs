@@ -1,18 +1,31 @@
-use std::{ops::Deref, sync::Arc};
+use std::{io::Write, ops::Deref, path::Path, sync::Arc};
 
-use matrix_sdk_base::deserialized_responses::MembersResponse;
+use matrix_sdk_base::{
+    deserialized_responses::{EventContext, MembersResponse},
+    Aggregations, RelationType,
+};
 use matrix_sdk_common::locks::Mutex;
 use ruma::{
     api::client::r0::{
+        context::get_context,
         membership::{get_member_events, join_room_by_id, leave_room},
         message::get_message_events,
+        room::aliases,
     },
-    UserId,
+    assign,
+    events::EventType,
+    push::Action,
+    uint, EventId, RoomAliasId, UserId,
 };
 
+use super::{
+    export::{ExportFormat, ExportSummary, Exporter},
+    relations::{self, Relations},
+};
 use crate::{
     media::{MediaFormat, MediaRequest, MediaType},
-    BaseRoom, Client, Result, RoomMember,
+    room::{Room, RoomNotificationMode},
+    BaseRoom, Client, RequestCategory, Result, RoomMember,
 };
 
 /// A struct containing methods that are common for Joined, Invited and Left
@@ -107,6 +120,10 @@ impl Common {
     /// returns a `get_message_events::Response` that contains a chunk of
     /// room and state events (`AnyRoomEvent` and `AnyStateEvent`).
     ///
+    /// The events are added to the index of known events of the room, use
+    /// [`new_messages()`](#method.new_messages) to only get the events that
+    /// weren't received before.
+    ///
     /// # Arguments
     ///
     /// * `request` - The easiest way to create this request is using the
@@ -139,7 +156,307 @@ impl Common {
         request: impl Into<get_message_events::Request<'_>>,
     ) -> Result<get_message_events::Response> {
         let request = request.into();
-        self.client.send(request, None).await
+        let response = self.client.send(request, None).await?;
+
+        self.client.base_client.receive_messages(self.inner.room_id(), &response.chunk).await?;
+
+        Ok(response)
+    }
+
+    /// Like [`messages()`](#method.messages), but events that were already
+    /// received, either in a sync response or in a previous `/messages`
+    /// response, are removed from the chunk of the response.
+    ///
+    /// Returns the response together with the number of events that were
+    /// removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The easiest way to create this request is using the
+    /// `get_message_events::Request` itself.
+    pub async fn new_messages(
+        &self,
+        request: impl Into<get_message_events::Request<'_>>,
+    ) -> Result<(get_message_events::Response, usize)> {
+        let request = request.into();
+        let mut response = self.client.send(request, None).await?;
+
+        let new_events =
+            self.client.base_client.receive_messages(self.inner.room_id(), &response.chunk).await?;
+        let total = response.chunk.len();
+
+        response.chunk = response
+            .chunk
+            .into_iter()
+            .zip(new_events)
+            .filter_map(|(event, is_new)| is_new.then(|| event))
+            .collect();
+
+        let skipped = total - response.chunk.len();
+
+        Ok((response, skipped))
+    }
+
+    /// Get an event together with the events that happened right before and
+    /// after it, e.g. to show the surrounding messages of a permalink.
+    ///
+    /// Encrypted events are decrypted if the room key for them is known. Like
+    /// with [`messages()`](#method.messages), the events are added to the
+    /// index of known events of the room. Use `messages()` with the returned
+    /// `start` and `end` tokens to paginate further.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that should be fetched.
+    ///
+    /// * `limit` - The maximal number of surrounding events that should be
+    /// returned, the server splits them between the events before and after
+    /// the requested event.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::identifiers::{event_id, room_id};
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!roomid:example.com");
+    /// # let room = client.get_joined_room(&room_id).unwrap();
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// let event_id = event_id!("$xxxxxx:example.org");
+    /// let context = room.event_with_context(&event_id, 10).await.unwrap();
+    ///
+    /// for event in context.events_before.iter().rev() {
+    ///     println!("{:?}", event.event);
+    /// }
+    /// # });
+    /// ```
+    pub async fn event_with_context(&self, event_id: &EventId, limit: u32) -> Result<EventContext> {
+        let request = assign!(get_context::Request::new(self.inner.room_id(), event_id), {
+            limit: limit.into(),
+        });
+        let response = self.client.send(request, None).await?;
+
+        Ok(self.client.base_client.receive_context(self.inner.room_id(), response).await?)
+    }
+
+    /// Export the whole history of the room, e.g. for compliance or to keep
+    /// a personal archive.
+    ///
+    /// The history is paginated from the latest event that was received by
+    /// a sync back to the creation of the room and written in chronological
+    /// order. Encrypted events are decrypted if the room key for them is
+    /// known, events that can't be decrypted are exported in their encrypted
+    /// form.
+    ///
+    /// Only one page of events is kept in memory, the pages are fetched a
+    /// second time in chronological order once the start of the history was
+    /// found. The exported events don't change the state of the client, e.g.
+    /// they aren't added to the aggregations.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where the export should be written to.
+    ///
+    /// * `format` - The format of the export.
+    ///
+    /// * `attachments` - A directory the files of media messages should be
+    /// downloaded and decrypted into, `None` if they shouldn't be downloaded.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::fs::File;
+    /// # use matrix_sdk::{Client, room::ExportFormat};
+    /// # use matrix_sdk::identifiers::room_id;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!roomid:example.com");
+    /// # let room = client.get_joined_room(&room_id).unwrap();
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// let file = File::create("history.jsonl").unwrap();
+    /// let summary = room.export_history(file, ExportFormat::JsonLines, None).await.unwrap();
+    ///
+    /// println!("Exported {} events", summary.events);
+    /// # });
+    /// ```
+    pub async fn export_history(
+        &self,
+        writer: impl Write + Send,
+        format: ExportFormat,
+        attachments: Option<&Path>,
+    ) -> Result<ExportSummary> {
+        let room_id = self.inner.room_id();
+
+        let fetch_page = |from: String| async move {
+            let request = assign!(get_message_events::Request::backward(room_id, &from), {
+                limit: uint!(100),
+            });
+            let response = self.client.send(request, None).await?;
+
+            Result::Ok((from, response))
+        };
+
+        // Walk back to the start of the history, only remembering the tokens
+        // of the pages.
+        let mut pages = Vec::new();
+
+        if let Some(mut from) = self.client.sync_token().await.or_else(|| self.last_prev_batch()) {
+            loop {
+                let (token, response) = fetch_page(from).await?;
+
+                if response.chunk.is_empty() {
+                    break;
+                }
+
+                pages.push(token.clone());
+
+                match response.end {
+                    Some(end) if end != token => from = end,
+                    _ => break,
+                }
+            }
+        }
+
+        let mut exporter = Exporter::new(self, writer, format, attachments);
+        exporter.start().await?;
+
+        for token in pages.into_iter().rev() {
+            let (_, response) = fetch_page(token).await?;
+            let events = self.client.base_client.receive_history(room_id, response.chunk).await;
+
+            for event in events.iter().rev() {
+                exporter.write_event(event).await?;
+            }
+        }
+
+        exporter.finish()
+    }
+
+    /// Get the aliases of this room that are published in the room directory of
+    /// our homeserver.
+    ///
+    /// Unlike [`canonical_alias()`](#method.canonical_alias) this includes the
+    /// aliases that aren't advertised in the room.
+    pub async fn local_aliases(&self) -> Result<Vec<RoomAliasId>> {
+        let request = aliases::Request::new(self.inner.room_id());
+        let response = self.client.send(request, None).await?;
+
+        Ok(response.aliases)
+    }
+
+    /// Get the room that this room replaced if the room was created by an
+    /// upgrade and the old room is known to the client.
+    ///
+    /// The timeline of the old room ends with the event that
+    /// [`predecessor()`](#method.predecessor) points to, fetching that event
+    /// with [`event_with_context()`](#method.event_with_context) allows to
+    /// continue paginating backwards across the upgrade.
+    pub fn predecessor_room(&self) -> Option<Room> {
+        self.inner.predecessor().and_then(|p| self.client.get_room(&p.room_id))
+    }
+
+    /// Get the room that replaced this room if the room was upgraded and the
+    /// new room is known to the client.
+    pub fn successor_room(&self) -> Option<Room> {
+        self.inner.successor().and_then(|room_id| self.client.get_room(&room_id))
+    }
+
+    /// Fetch the events that relate to the given event with the given type of
+    /// relation, e.g. the reactions or edits of a message.
+    ///
+    /// The fetched events are merged into the locally aggregated relations of
+    /// the event, this repairs the aggregations of events whose related events
+    /// weren't part of a limited sync timeline. Encrypted events are decrypted
+    /// if the room key for them is known.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event whose related events should be
+    /// fetched.
+    ///
+    /// * `rel_type` - The type of the relation.
+    ///
+    /// * `event_type` - The type of the related events, e.g. `m.reaction`.
+    ///
+    /// * `from` - The `next_batch` token of a previous call to fetch the next
+    /// page of related events, `None` to fetch the first page.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::{Client, RelationType};
+    /// # use matrix_sdk::identifiers::{event_id, room_id};
+    /// # use matrix_sdk::events::EventType;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!roomid:example.com");
+    /// # let room = client.get_joined_room(&room_id).unwrap();
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// let event_id = event_id!("$xxxxxx:example.org");
+    /// let mut from = None;
+    ///
+    /// loop {
+    ///     let relations = room
+    ///         .relations(
+    ///             &event_id,
+    ///             RelationType::Annotation,
+    ///             &EventType::Reaction,
+    ///             from.as_deref(),
+    ///         )
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     from = relations.next_batch;
+    ///
+    ///     if from.is_none() {
+    ///         break;
+    ///     }
+    /// }
+    ///
+    /// let aggregations = room.aggregations(&event_id).unwrap();
+    /// println!("The message got {} thumbs up", aggregations.annotation_count("👍"));
+    /// # });
+    /// ```
+    pub async fn relations(
+        &self,
+        event_id: &EventId,
+        rel_type: RelationType,
+        event_type: &EventType,
+        from: Option<&str>,
+    ) -> Result<Relations> {
+        let request = relations::Request {
+            room_id: self.inner.room_id(),
+            event_id,
+            rel_type,
+            event_type,
+            from,
+            limit: None,
+        };
+        let response = self.client.send(request, None).await?;
+
+        let chunk =
+            self.client.base_client.receive_relations(self.inner.room_id(), response.chunk).await;
+
+        Ok(Relations { chunk, next_batch: response.next_batch, prev_batch: response.prev_batch })
+    }
+
+    /// Get the events that relate to the given event, e.g. its reactions and
+    /// edits, as far as they are known to the client.
+    ///
+    /// Related events are aggregated as they are received in sync responses
+    /// or fetched using [`relations()`](#method.relations), the aggregations
+    /// are kept in memory only.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event whose aggregations should be
+    /// returned.
+    pub fn aggregations(&self, event_id: &EventId) -> Option<Aggregations> {
+        self.client.store().aggregations(self.inner.room_id(), event_id)
     }
 
     pub(crate) async fn request_members(&self) -> Result<Option<MembersResponse>> {
@@ -157,7 +474,10 @@ impl Common {
             let _guard = mutex.lock().await;
 
             let request = get_member_events::Request::new(self.inner.room_id());
-            let response = self.client.send(request, None).await?;
+            let response = {
+                let _permit = self.client.request_limiter.acquire(RequestCategory::Members).await;
+                self.client.send(request, None).await?
+            };
 
             let response =
                 self.client.base_client.receive_members(self.inner.room_id(), &response).await?;
@@ -317,4 +637,31 @@ impl Common {
             .map(|member| RoomMember::new(self.client.clone(), member))
             .collect())
     }
+
+    /// Get the notification mode of this room.
+    ///
+    /// The mode is derived from the push rules of the user, a muted room has an
+    /// override rule for the room while a room that only notifies about
+    /// mentions has a room rule, both without a notify action.
+    pub async fn notification_mode(&self) -> Result<RoomNotificationMode> {
+        let rules = self.client.push_rules().await?;
+        let room_id = self.inner.room_id().as_str();
+        let notifies = |actions: &[Action]| actions.iter().any(|a| matches!(a, Action::Notify));
+
+        if rules
+            .override_
+            .iter()
+            .any(|r| r.enabled && r.rule_id == room_id && !notifies(&r.actions))
+        {
+            Ok(RoomNotificationMode::Mute)
+        } else if rules
+            .room
+            .iter()
+            .any(|r| r.enabled && r.rule_id == room_id && !notifies(&r.actions))
+        {
+            Ok(RoomNotificationMode::MentionsOnly)
+        } else {
+            Ok(RoomNotificationMode::All)
+        }
+    }
 }
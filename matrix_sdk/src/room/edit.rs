@@ -0,0 +1,110 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Message edits as described in [MSC2676].
+//!
+//! [MSC2676]: https://github.com/matrix-org/matrix-doc/pull/2676
+
+use ruma::{
+    events::room::{
+        message::{MessageEventContent, MessageType, Relation},
+        relationships::Replacement,
+    },
+    EventId,
+};
+
+/// Create the content of an edit that replaces the content of the given
+/// event with the given content.
+///
+/// The body of the edit is the new body prefixed with `* `, as a fallback
+/// for clients that don't support edits.
+pub(crate) fn make_edit(
+    event_id: EventId,
+    mut new_content: MessageEventContent,
+) -> MessageEventContent {
+    // The new content replaces the whole content of the original event, but
+    // the relations of the original event are kept.
+    new_content.relates_to = None;
+
+    let mut msgtype = new_content.msgtype.clone();
+
+    match &mut msgtype {
+        MessageType::Text(c) => {
+            add_fallback(&mut c.body, c.formatted.as_mut().map(|f| &mut f.body))
+        }
+        MessageType::Notice(c) => {
+            add_fallback(&mut c.body, c.formatted.as_mut().map(|f| &mut f.body))
+        }
+        MessageType::Emote(c) => {
+            add_fallback(&mut c.body, c.formatted.as_mut().map(|f| &mut f.body))
+        }
+        _ => {}
+    }
+
+    let mut content = MessageEventContent::new(msgtype);
+    content.relates_to = Some(Relation::Replacement(Replacement::new(event_id)));
+    content.new_content = Some(Box::new(new_content));
+
+    content
+}
+
+fn add_fallback(body: &mut String, formatted: Option<&mut String>) {
+    body.insert_str(0, "* ");
+
+    if let Some(formatted) = formatted {
+        formatted.insert_str(0, "* ");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{
+        event_id,
+        events::room::message::{MessageEventContent, MessageType, Relation},
+    };
+    use serde_json::json;
+
+    use super::make_edit;
+
+    #[test]
+    fn edit_content() {
+        let content = make_edit(
+            event_id!("$original:localhost"),
+            MessageEventContent::text_html("Hello *world*", "Hello <em>world</em>"),
+        );
+
+        assert_eq!(
+            serde_json::to_value(&content).unwrap(),
+            json!({
+                "msgtype": "m.text",
+                "body": "* Hello *world*",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "* Hello <em>world</em>",
+                "m.new_content": {
+                    "msgtype": "m.text",
+                    "body": "Hello *world*",
+                    "format": "org.matrix.custom.html",
+                    "formatted_body": "Hello <em>world</em>",
+                },
+                "m.relates_to": {
+                    "rel_type": "m.replace",
+                    "event_id": "$original:localhost",
+                },
+            })
+        );
+
+        assert!(matches!(content.relates_to, Some(Relation::Replacement(_))));
+        assert!(matches!(content.msgtype, MessageType::Text(_)));
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of the decrypted history of a room, see
+//! [`Common::export_history()`].
+
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncRoomEvent};
+use ruma::{
+    events::{
+        room::message::{FormattedBody, MessageEventContent, MessageFormat, MessageType},
+        AnySyncMessageEvent, AnySyncRoomEvent, SyncMessageEvent,
+    },
+    EventId, MilliSecondsSinceUnixEpoch, UserId,
+};
+use serde::Serialize;
+use serde_json::value::RawValue as RawJsonValue;
+use tracing::warn;
+
+use crate::{
+    html,
+    media::{MediaEventContent, MediaFormat, MediaRequest},
+    room::Common,
+    Result,
+};
+
+/// The format of an exported room history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line for every event. The object contains the
+    /// decrypted event, its encryption info and the file name of its
+    /// attachment if it was downloaded.
+    JsonLines,
+    /// A HTML document showing the messages of the room, other events are
+    /// left out.
+    Html,
+}
+
+/// What was written by an export of the room history.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// The number of exported events.
+    pub events: usize,
+    /// The number of events that couldn't be decrypted, they are exported in
+    /// their encrypted form.
+    pub undecryptable_events: usize,
+    /// The number of attachments that were downloaded.
+    pub attachments: usize,
+    /// The number of attachments that couldn't be downloaded.
+    pub failed_attachments: usize,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    event: &'a RawJsonValue,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption_info: Option<&'a EncryptionInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attachment: Option<&'a str>,
+}
+
+/// Writes the events of a room in chronological order.
+pub(crate) struct Exporter<'a, W> {
+    room: &'a Common,
+    writer: W,
+    format: ExportFormat,
+    attachments: Option<&'a Path>,
+    display_names: BTreeMap<UserId, String>,
+    summary: ExportSummary,
+}
+
+impl<'a, W: Write> Exporter<'a, W> {
+    pub fn new(
+        room: &'a Common,
+        writer: W,
+        format: ExportFormat,
+        attachments: Option<&'a Path>,
+    ) -> Self {
+        Self {
+            room,
+            writer,
+            format,
+            attachments,
+            display_names: BTreeMap::new(),
+            summary: ExportSummary::default(),
+        }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        if self.format == ExportFormat::Html {
+            let name = html::escape(&self.room.display_name().await?);
+
+            write!(
+                self.writer,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                 <title>{name}</title>\n</head>\n<body>\n<h1>{name}</h1>\n",
+                name = name
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_event(&mut self, event: &SyncRoomEvent) -> Result<()> {
+        let deserialized = event.deserialize().ok();
+
+        if let Some(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(_))) = deserialized
+        {
+            self.summary.undecryptable_events += 1;
+        }
+
+        let attachment = match (&deserialized, self.attachments) {
+            (Some(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(m))), Some(dir)) => {
+                self.download_attachment(dir, m).await?
+            }
+            _ => None,
+        };
+
+        match self.format {
+            ExportFormat::JsonLines => {
+                let line = JsonLine {
+                    event: event.event.json(),
+                    encryption_info: event.encryption_info.as_ref(),
+                    attachment: attachment.as_deref(),
+                };
+
+                serde_json::to_writer(&mut self.writer, &line)?;
+                self.writer.write_all(b"\n")?;
+            }
+            ExportFormat::Html => {
+                if let Some(AnySyncRoomEvent::Message(message)) = &deserialized {
+                    self.write_html_message(message, attachment.as_deref()).await?;
+                }
+            }
+        }
+
+        self.summary.events += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<ExportSummary> {
+        if self.format == ExportFormat::Html {
+            self.writer.write_all(b"</body>\n</html>\n")?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(self.summary)
+    }
+
+    /// Download and decrypt the file of a media message into the given
+    /// directory.
+    ///
+    /// Returns the name of the file, `None` if the message has no file or if
+    /// it couldn't be downloaded.
+    async fn download_attachment(
+        &mut self,
+        dir: &Path,
+        event: &SyncMessageEvent<MessageEventContent>,
+    ) -> Result<Option<String>> {
+        let (body, media_type) = match &event.content.msgtype {
+            MessageType::Audio(c) => (&c.body, c.file()),
+            MessageType::File(c) => (&c.body, c.file()),
+            MessageType::Image(c) => (&c.body, c.file()),
+            MessageType::Video(c) => (&c.body, c.file()),
+            _ => return Ok(None),
+        };
+
+        let media_type = match media_type {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+
+        let request = MediaRequest { media_type, format: MediaFormat::File };
+
+        match self.room.client.get_media_content(&request, false).await {
+            Ok(content) => {
+                let file_name = attachment_file_name(&event.event_id, body);
+                std::fs::write(dir.join(&file_name), content)?;
+                self.summary.attachments += 1;
+
+                Ok(Some(file_name))
+            }
+            Err(e) => {
+                warn!("Couldn't download the attachment of {}: {:?}", event.event_id, e);
+                self.summary.failed_attachments += 1;
+
+                Ok(None)
+            }
+        }
+    }
+
+    async fn write_html_message(
+        &mut self,
+        message: &AnySyncMessageEvent,
+        attachment: Option<&str>,
+    ) -> Result<()> {
+        let content = match message {
+            AnySyncMessageEvent::RoomMessage(m) => match message_html(&m.content, attachment) {
+                Some(content) => content,
+                None => return Ok(()),
+            },
+            AnySyncMessageEvent::RoomEncrypted(_) => {
+                "<em>Unable to decrypt this message.</em>".to_owned()
+            }
+            _ => return Ok(()),
+        };
+
+        let sender = self.display_name(message.sender()).await?;
+
+        writeln!(
+            self.writer,
+            "<div class=\"event\" id=\"{}\"><span class=\"time\">{}</span> \
+             <span class=\"sender\">{}</span><div class=\"content\">{}</div></div>",
+            html::escape(message.event_id().as_str()),
+            format_timestamp(*message.origin_server_ts()),
+            html::escape(&sender),
+            content,
+        )?;
+
+        Ok(())
+    }
+
+    /// The display name of the given user in the room, falls back to the
+    /// user id.
+    async fn display_name(&mut self, user_id: &UserId) -> Result<String> {
+        if let Some(name) = self.display_names.get(user_id) {
+            return Ok(name.clone());
+        }
+
+        let name = match self.room.get_member_no_sync(user_id).await? {
+            Some(member) => member.name().to_owned(),
+            None => user_id.to_string(),
+        };
+
+        self.display_names.insert(user_id.clone(), name.clone());
+
+        Ok(name)
+    }
+}
+
+/// The HTML of the content of a message, `None` if the message type can't be
+/// shown.
+fn message_html(content: &MessageEventContent, attachment: Option<&str>) -> Option<String> {
+    let text_html = |body: &str, formatted: &Option<_>| match formatted {
+        Some(FormattedBody { format: MessageFormat::Html, body }) => html::sanitize(body),
+        _ => html::escape(body).replace('\n', "<br>"),
+    };
+
+    Some(match &content.msgtype {
+        MessageType::Text(c) => text_html(&c.body, &c.formatted),
+        MessageType::Notice(c) => text_html(&c.body, &c.formatted),
+        MessageType::Emote(c) => format!("* {}", text_html(&c.body, &c.formatted)),
+        MessageType::ServerNotice(c) => html::escape(&c.body),
+        MessageType::Location(c) => html::escape(&c.body),
+        MessageType::Image(c) => match attachment {
+            Some(file) => format!("<img src=\"{}\" alt=\"{}\">", file, html::escape(&c.body)),
+            None => html::escape(&c.body),
+        },
+        MessageType::Audio(c) => media_link(&c.body, attachment),
+        MessageType::File(c) => media_link(&c.body, attachment),
+        MessageType::Video(c) => media_link(&c.body, attachment),
+        _ => return None,
+    })
+}
+
+fn media_link(body: &str, attachment: Option<&str>) -> String {
+    match attachment {
+        Some(file) => format!("<a href=\"{}\">{}</a>", file, html::escape(body)),
+        None => html::escape(body),
+    }
+}
+
+/// The name of the file an attachment is stored in, only characters that
+/// are safe in file names and URLs are kept.
+fn attachment_file_name(event_id: &EventId, body: &str) -> String {
+    format!("{}-{}", event_id.localpart(), body)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Format the given timestamp as an UTC date and time, e.g.
+/// `2021-06-01 12:30:00`.
+fn format_timestamp(ts: MilliSecondsSinceUnixEpoch) -> String {
+    let seconds = u64::from(ts.get()) / 1000;
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Convert the days since the epoch into a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{event_id, uint, MilliSecondsSinceUnixEpoch};
+
+    use super::{attachment_file_name, format_timestamp};
+
+    #[test]
+    fn timestamps_and_file_names() {
+        assert_eq!(format_timestamp(MilliSecondsSinceUnixEpoch(uint!(0))), "1970-01-01 00:00:00");
+        assert_eq!(
+            format_timestamp(MilliSecondsSinceUnixEpoch(uint!(1_000_000_000_123))),
+            "2001-09-09 01:46:40"
+        );
+        assert_eq!(
+            format_timestamp(MilliSecondsSinceUnixEpoch(uint!(1_582_977_600_000))),
+            "2020-02-29 12:00:00"
+        );
+
+        assert_eq!(
+            attachment_file_name(&event_id!("$abc:example.org"), "../cat picture.png"),
+            "abc-.._cat_picture.png"
+        );
+    }
+}
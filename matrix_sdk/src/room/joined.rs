@@ -1,52 +1,87 @@
-#[cfg(feature = "encryption")]
-use std::sync::Arc;
-use std::{io::Read, ops::Deref};
+use std::{
+    collections::BTreeSet,
+    io::Read,
+    ops::Deref,
+    slice,
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+};
 
+use futures::Stream;
 #[cfg(feature = "encryption")]
-use matrix_sdk_base::crypto::AttachmentEncryptor;
-#[cfg(feature = "encryption")]
-use matrix_sdk_common::locks::Mutex;
+use matrix_sdk_base::crypto::{AttachmentEncryptor, EncryptionHealth};
+use matrix_sdk_base::deserialized_responses::SyncRoomEvent;
 use matrix_sdk_common::{
     instant::{Duration, Instant},
+    locks::Mutex,
     uuid::Uuid,
 };
 use mime::{self, Mime};
 #[cfg(feature = "encryption")]
 use ruma::events::room::EncryptedFileInit;
 use ruma::{
-    api::client::r0::{
-        membership::{
-            ban_user,
-            invite_user::{self, InvitationRecipient},
-            kick_user, Invite3pid,
+    api::client::{
+        error::ErrorKind,
+        r0::{
+            alias::{create_alias, delete_alias},
+            filter::RoomEventFilter,
+            membership::{
+                ban_user, forget_room, get_member_events,
+                invite_user::{self, InvitationRecipient},
+                joined_members, kick_user, unban_user, Invite3pid, Invite3pidInit,
+            },
+            message::{get_message_events, send_message_event},
+            push::{delete_pushrule, set_pushrule, RuleKind},
+            read_marker::set_read_marker,
+            receipt::create_receipt,
+            redact::redact_event,
+            room::upgrade_room,
+            state::{get_state_events_for_key, send_state_event},
+            typing::create_typing_event::{Request as TypingRequest, Typing},
         },
-        message::send_message_event,
-        read_marker::set_read_marker,
-        receipt::create_receipt,
-        redact::redact_event,
-        state::send_state_event,
-        typing::create_typing_event::{Request as TypingRequest, Typing},
     },
     assign,
     events::{
         room::{
+            canonical_alias::CanonicalAliasEventContent,
+            member::{MemberEventContent, MembershipState},
             message::{
                 AudioMessageEventContent, FileMessageEventContent, ImageMessageEventContent,
-                MessageEventContent, MessageType, VideoMessageEventContent,
+                MessageEventContent, MessageType, Relation, VideoMessageEventContent,
             },
+            power_levels::PowerLevelsEventContent,
+            server_acl::ServerAclEventContent,
             EncryptedFile,
         },
-        AnyMessageEventContent, AnyStateEventContent,
+        AnyMessageEventContent, AnyStateEventContent, AnySyncRoomEvent, AnySyncStateEvent,
+        EventContent, EventType, SyncMessageEvent,
     },
-    identifiers::{EventId, UserId},
+    identifiers::{EventId, RoomAliasId, RoomId, RoomVersionId, UserId},
+    push::{Action, PushCondition},
     receipt::ReceiptType,
+    serde::Raw,
+    thirdparty::Medium,
+    Int, MilliSecondsSinceUnixEpoch,
 };
+use serde::Deserialize;
+use serde_json::{json, value::to_raw_value};
 #[cfg(feature = "encryption")]
 use tracing::instrument;
+use tracing::{debug, warn};
 
-use crate::{room::Common, BaseRoom, Client, Result, RoomType};
+use crate::{
+    extensible::ExtensibleEventContent,
+    room::{
+        edit::make_edit, reply::make_reply, Common, DesiredMembership, LocalEchoUpdate,
+        MemberReconciliation, MembershipChange, QueuedEvent, RoomNotificationMode, SendQueue,
+    },
+    validation::{self, validate_encrypted_event, validate_event},
+    BaseRoom, Client, Error, RequestCategory, Result, RoomType,
+};
 
 const TYPING_NOTICE_TIMEOUT: Duration = Duration::from_secs(4);
+/// How often the membership changes are recalculated if the membership of a
+/// user changes while [`Joined::ensure_membership()`] applies them.
+const MEMBERSHIP_ATTEMPTS: u32 = 3;
 const TYPING_NOTICE_RESEND_TIMEOUT: Duration = Duration::from_secs(3);
 
 /// A room in the joined state.
@@ -67,6 +102,21 @@ impl Deref for Joined {
     }
 }
 
+/// Marks a send queue as stopped when the task that sends out its events
+/// ends without emptying the queue, i.e. if it panicked or was aborted.
+struct SendQueueTask {
+    queue: Arc<StdMutex<SendQueue>>,
+    finished: bool,
+}
+
+impl Drop for SendQueueTask {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.queue.lock().unwrap_or_else(PoisonError::into_inner).stopped();
+        }
+    }
+}
+
 impl Joined {
     /// Create a new `room::Joined` if the underlying `BaseRoom` has type
     /// `RoomType::Joined`.
@@ -89,6 +139,86 @@ impl Joined {
         self.inner.leave().await
     }
 
+    /// Leave this room and optionally clean up after it.
+    ///
+    /// If the room was a direct message room, it will be removed from the
+    /// `m.direct` account data. The outbound group session of the room, if
+    /// any, is invalidated immediately so that no future messages can be
+    /// decrypted with it.
+    ///
+    /// # Arguments
+    ///
+    /// * `purge_local_data` - Remove all the data of the room from the local
+    ///   store after leaving it.
+    ///
+    /// * `forget_on_server` - Forget the room after leaving it, the room won't
+    ///   show up in sync responses anymore.
+    ///
+    /// * `reason` - The reason for leaving the room, shown to the other room
+    ///   members.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::identifiers::room_id;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!test:localhost");
+    /// # block_on(async {
+    /// let room = client.get_joined_room(&room_id).unwrap();
+    /// room.leave_with_options(true, true, Some("Moving to a new room")).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn leave_with_options(
+        &self,
+        purge_local_data: bool,
+        forget_on_server: bool,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let room_id = self.room_id();
+
+        if let Some(reason) = reason {
+            // The leave endpoint doesn't support a reason, leave by updating
+            // our own membership instead.
+            let user_id = self.own_user_id();
+            let content = to_raw_value(&json!({
+                "membership": "leave",
+                "reason": reason,
+            }))?;
+            let request = send_state_event::Request::new_raw(
+                room_id,
+                EventType::RoomMember.as_str(),
+                user_id.as_str(),
+                Raw::from_json(content),
+            );
+
+            self.client.send(request, None).await?;
+        } else {
+            self.inner.leave().await?;
+        }
+
+        #[cfg(feature = "encryption")]
+        self.client.base_client.invalidate_group_session(room_id).await?;
+
+        if let Some(user_id) = self.direct_target() {
+            self.client.remove_direct_room(&user_id, room_id).await?;
+        }
+
+        if forget_on_server {
+            let request = forget_room::Request::new(room_id);
+            self.client.send(request, None).await?;
+        }
+
+        if purge_local_data {
+            self.client.base_client.purge_room(room_id).await?;
+        }
+
+        Ok(())
+    }
+
     /// Ban the user with `UserId` from this room.
     ///
     /// # Arguments
@@ -102,6 +232,17 @@ impl Joined {
         Ok(())
     }
 
+    /// Unban a previously banned user with `UserId` from this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user to unban with `UserId`.
+    pub async fn unban_user(&self, user_id: &UserId) -> Result<()> {
+        let request = unban_user::Request::new(self.inner.room_id(), user_id);
+        self.client.send(request, None).await?;
+        Ok(())
+    }
+
     /// Kick a user out of this room.
     ///
     /// # Arguments
@@ -116,6 +257,66 @@ impl Joined {
         Ok(())
     }
 
+    /// Set the power level of the user with `UserId` in this room.
+    ///
+    /// This will take the current `m.room.power_levels` state of the room and
+    /// send an updated version of it where only the power level of the given
+    /// user changed. Setting the power level to the default power level of the
+    /// room removes the user from the list of users with an explicit power
+    /// level.
+    ///
+    /// Returns the parsed response from the server.
+    ///
+    /// The current power levels are fetched from the homeserver if they aren't
+    /// in the store, an error is returned if they can't be loaded so the
+    /// existing power levels are never replaced by the defaults.
+    ///
+    /// The homeserver will reject the request with an `M_FORBIDDEN` error,
+    /// available through `Error::client_api_error_kind()`, if we aren't
+    /// allowed to change the power levels or to raise the user to the given
+    /// level.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The `UserId` of the user whose power level should change.
+    ///
+    /// * `level` - The new power level of the user.
+    pub async fn set_power_level(
+        &self,
+        user_id: &UserId,
+        level: Int,
+    ) -> Result<send_state_event::Response> {
+        let event = self
+            .client
+            .store()
+            .get_state_event(self.inner.room_id(), EventType::RoomPowerLevels, "")
+            .await?
+            .map(|e| e.deserialize())
+            .transpose()?;
+
+        let mut content: PowerLevelsEventContent =
+            if let Some(AnySyncStateEvent::RoomPowerLevels(e)) = event {
+                e.content
+            } else {
+                let request = get_state_events_for_key::Request::new(
+                    self.inner.room_id(),
+                    EventType::RoomPowerLevels,
+                    "",
+                );
+                let response = self.client.send(request, None).await?;
+
+                serde_json::from_str(response.content.json().get())?
+            };
+
+        if level == content.users_default {
+            content.users.remove(user_id);
+        } else {
+            content.users.insert(user_id.clone(), level);
+        }
+
+        self.send_state_event(AnyStateEventContent::RoomPowerLevels(content), "").await
+    }
+
     /// Invite the specified user by `UserId` to this room.
     ///
     /// # Arguments
@@ -130,6 +331,115 @@ impl Joined {
         Ok(())
     }
 
+    /// Bring the membership of the given user into the desired state.
+    ///
+    /// This is meant for bridges and other appservices that mirror the
+    /// membership of a remote room. The current membership of the user is
+    /// fetched from the server and the minimal list of changes is applied,
+    /// e.g. an unban followed by an invite, nothing is done if the user
+    /// already has the desired membership.
+    ///
+    /// If the membership of the user changes while the changes are applied,
+    /// the homeserver rejects the outdated change and the changes are
+    /// calculated again from the new membership.
+    ///
+    /// Returns the changes that were applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The `UserId` of the user whose membership should change.
+    ///
+    /// * `desired` - The membership the user should end up with.
+    ///
+    /// * `user_client` - A client acting as the user, e.g. a virtual user
+    ///   client of an appservice. It's used to join the room on behalf of the
+    ///   user, the method fails with an [`Error::UserClientRequired`] error if
+    ///   the user needs to join the room and no client was given.
+    ///
+    /// [`Error::UserClientRequired`]: crate::Error::UserClientRequired
+    pub async fn ensure_membership(
+        &self,
+        user_id: &UserId,
+        desired: DesiredMembership,
+        user_client: Option<&Client>,
+    ) -> Result<Vec<MembershipChange>> {
+        let mut applied = Vec::new();
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let current = self.fetch_membership(user_id).await?;
+            let changes = desired.changes_from(current.as_ref());
+
+            if changes.is_empty() {
+                return Ok(applied);
+            }
+
+            if changes.contains(&MembershipChange::Join) && user_client.is_none() {
+                return Err(Error::UserClientRequired(user_id.clone()));
+            }
+
+            match self.apply_membership_changes(user_id, &changes, user_client, &mut applied).await
+            {
+                Ok(()) => return Ok(applied),
+                // The membership of the user might have changed in the
+                // meantime, try again with the new membership.
+                Err(e)
+                    if attempt < MEMBERSHIP_ATTEMPTS
+                        && matches!(e.client_api_error_kind(), Some(ErrorKind::Forbidden)) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn fetch_membership(&self, user_id: &UserId) -> Result<Option<MembershipState>> {
+        let request = get_state_events_for_key::Request::new(
+            self.inner.room_id(),
+            EventType::RoomMember,
+            user_id.as_str(),
+        );
+
+        match self.client.send(request, None).await {
+            Ok(response) => {
+                let content: MemberEventContent =
+                    serde_json::from_str(response.content.json().get())?;
+                Ok(Some(content.membership))
+            }
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn apply_membership_changes(
+        &self,
+        user_id: &UserId,
+        changes: &[MembershipChange],
+        user_client: Option<&Client>,
+        applied: &mut Vec<MembershipChange>,
+    ) -> Result<()> {
+        for change in changes {
+            match change {
+                MembershipChange::Unban => self.unban_user(user_id).await?,
+                MembershipChange::Invite => self.invite_user_by_id(user_id).await?,
+                MembershipChange::Kick => self.kick_user(user_id, None).await?,
+                MembershipChange::Ban => self.ban_user(user_id, None).await?,
+                MembershipChange::Join => {
+                    let client =
+                        user_client.ok_or_else(|| Error::UserClientRequired(user_id.clone()))?;
+                    client.join_room_by_id(self.inner.room_id()).await?;
+                }
+            }
+
+            applied.push(*change);
+        }
+
+        Ok(())
+    }
+
     /// Invite the specified user by third party id to this room.
     ///
     /// # Arguments
@@ -143,6 +453,93 @@ impl Joined {
         Ok(())
     }
 
+    /// Invite the user with the given email address to this room.
+    ///
+    /// The invite is sent through the identity server of the client, see
+    /// [`Client::set_identity_server()`]. If the email address isn't bound to
+    /// a Matrix user yet, the identity server emails the invite and the user
+    /// receives it once they bind the address to their account.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The email address of the user that should be invited.
+    pub async fn invite_by_email(&self, address: &str) -> Result<()> {
+        let identity_server = self.client.identity_server().ok_or(Error::IdentityServerRequired)?;
+        let id_server = identity_server.server_name();
+
+        self.invite_user_by_3pid(
+            Invite3pidInit {
+                id_server: &id_server,
+                id_access_token: identity_server.access_token(),
+                medium: Medium::Email,
+                address,
+            }
+            .into(),
+        )
+        .await
+    }
+
+    /// Set the notification mode of this room.
+    ///
+    /// This creates or removes the room specific push rules of the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - The new notification mode of the room.
+    pub async fn set_notification_mode(&self, mode: RoomNotificationMode) -> Result<()> {
+        let room_id = self.inner.room_id().as_str();
+
+        match mode {
+            RoomNotificationMode::All => {
+                self.remove_push_rule(RuleKind::Override).await?;
+                self.remove_push_rule(RuleKind::Room).await?;
+            }
+            RoomNotificationMode::MentionsOnly => {
+                self.remove_push_rule(RuleKind::Override).await?;
+
+                let actions = [Action::DontNotify];
+                let request =
+                    set_pushrule::Request::new("global", RuleKind::Room, room_id, &actions);
+                self.client.send(request, None).await?;
+            }
+            RoomNotificationMode::Mute => {
+                let actions = [Action::DontNotify];
+                let conditions = [PushCondition::EventMatch {
+                    key: "room_id".to_owned(),
+                    pattern: room_id.to_owned(),
+                }];
+                let request = assign!(
+                    set_pushrule::Request::new("global", RuleKind::Override, room_id, &actions),
+                    { conditions: &conditions }
+                );
+                self.client.send(request, None).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mute this room.
+    ///
+    /// This is a shorthand for setting the notification mode of the room to
+    /// `RoomNotificationMode::Mute`, see
+    /// [`set_notification_mode()`](#method.set_notification_mode).
+    pub async fn mute(&self) -> Result<()> {
+        self.set_notification_mode(RoomNotificationMode::Mute).await
+    }
+
+    /// Remove the push rule of the given kind for this room, a missing rule
+    /// isn't considered to be an error.
+    async fn remove_push_rule(&self, kind: RuleKind) -> Result<()> {
+        let request = delete_pushrule::Request::new("global", kind, self.inner.room_id().as_str());
+
+        match self.client.send(request, None).await {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Activate typing notice for this room.
     ///
     /// The typing notice remains active for 4s. It can be deactivate at any
@@ -328,6 +725,67 @@ impl Joined {
         Ok(())
     }
 
+    /// Share the history of this room with a user that was invited to it.
+    ///
+    /// The room keys of messages that were sent while the history visibility
+    /// of the room was `shared` or `world_readable` are forwarded to the
+    /// devices of the user, this allows them to read messages that were sent
+    /// before they joined the room.
+    ///
+    /// **Note**: Clients using this SDK only accept keys they didn't request
+    /// from verified devices of their own user or of the user that invited
+    /// them, so the history should be shared by the user that sent the
+    /// invite.
+    ///
+    /// The devices of the user need to be known, so this should be called
+    /// once the invite of the user was received in a sync.
+    ///
+    /// Does nothing if the room isn't encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that was invited to the room.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn share_history_with(&self, user_id: &UserId) -> Result<()> {
+        if !self.is_encrypted() {
+            return Ok(());
+        }
+
+        self.client.claim_one_time_keys(std::iter::once(user_id)).await?;
+
+        let requests =
+            self.client.base_client.share_room_history(self.inner.room_id(), user_id).await?;
+
+        for request in requests {
+            let response = self.client.send_to_device(&request).await?;
+
+            self.client.base_client.mark_request_as_sent(&request.txn_id, &response).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check if the room key of this room can be shared with all the devices
+    /// of its joined and invited members.
+    ///
+    /// This is meant for debugging, e.g. to show the state of the encryption
+    /// of the room in a debug panel. Returns `None` if the client isn't logged
+    /// in.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn encryption_health(&self) -> Result<Option<EncryptionHealth>> {
+        let olm = match self.client.base_client.olm_machine().await {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+
+        let joined = self.client.store().get_joined_user_ids(self.inner.room_id()).await?;
+        let invited = self.client.store().get_invited_user_ids(self.inner.room_id()).await?;
+
+        Ok(Some(olm.encryption_health(self.inner.room_id(), joined.iter().chain(&invited)).await?))
+    }
+
     /// Send a room message to this room.
     ///
     /// Returns the parsed response from the server.
@@ -335,6 +793,12 @@ impl Joined {
     /// If the encryption feature is enabled this method will transparently
     /// encrypt the room message if this room is encrypted.
     ///
+    /// The transaction id of the message is persisted together with the id of
+    /// the sent event. If a message with the same transaction id was already
+    /// sent, e.g. before the application crashed, it isn't sent again and the
+    /// id of the existing event is returned, see
+    /// [`event_id_for_transaction()`](#method.event_id_for_transaction).
+    ///
     /// # Arguments
     ///
     /// * `content` - The content of the message event.
@@ -377,8 +841,30 @@ impl Joined {
         content: impl Into<AnyMessageEventContent>,
         txn_id: Option<Uuid>,
     ) -> Result<send_message_event::Response> {
-        #[cfg(not(feature = "encryption"))]
-        let content: AnyMessageEventContent = content.into();
+        if let Some(txn_id) = txn_id {
+            if let Some(event_id) = self.event_id_for_transaction(&txn_id.to_string()).await? {
+                debug!("The transaction {} was already sent as the event {}", txn_id, event_id);
+                return Ok(send_message_event::Response::new(event_id));
+            }
+        }
+
+        let mut content: AnyMessageEventContent = content.into();
+        let encrypted = cfg!(feature = "encryption") && self.is_encrypted();
+
+        if self.client.truncate_formatted_bodies {
+            if let AnyMessageEventContent::RoomMessage(content) = &mut content {
+                validation::truncate_formatted_bodies(content, encrypted)?;
+            }
+        }
+
+        // The content of encrypted messages is checked before it's encrypted,
+        // a message that is too large would otherwise use up a message index
+        // of the group session.
+        if encrypted {
+            validate_encrypted_event(content.event_type(), &content)?;
+        } else {
+            validate_event(content.event_type(), None, &content)?;
+        }
 
         #[cfg(feature = "encryption")]
         let content = if self.is_encrypted() {
@@ -392,16 +878,348 @@ impl Joined {
                 self.client.base_client.encrypt(self.inner.room_id(), content).await?,
             )
         } else {
-            content.into()
+            content
         };
 
         let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
         let request = send_message_event::Request::new(self.inner.room_id(), &txn_id, &content);
 
         let response = self.client.send(request, None).await?;
+        self.client
+            .base_client
+            .receive_sent_event(self.inner.room_id(), &txn_id, &response.event_id)
+            .await?;
+
         Ok(response)
     }
 
+    /// Send a reply to the given message to this room.
+    ///
+    /// The relation to the original message is added to the content. Text,
+    /// notice and emote messages also get a quote of the original message
+    /// prepended to their body and formatted body, as a fallback for clients
+    /// that don't support replies. If the original message is a reply itself
+    /// its fallback isn't quoted.
+    ///
+    /// # Arguments
+    ///
+    /// * `original` - The message that is replied to.
+    ///
+    /// * `content` - The content of the reply.
+    ///
+    /// * `txn_id` - A unique `Uuid` that can be attached to a `MessageEvent`
+    /// held in its unsigned field as `transaction_id`. If not given one is
+    /// created for the message.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{
+    /// #     async_trait,
+    /// #     events::{room::message::MessageEventContent, SyncMessageEvent},
+    /// #     room::Room,
+    /// #     EventHandler,
+    /// # };
+    /// struct ReplyBot;
+    ///
+    /// #[async_trait]
+    /// impl EventHandler for ReplyBot {
+    ///     async fn on_room_message(&self, room: Room, event: &SyncMessageEvent<MessageEventContent>) {
+    ///         if let Room::Joined(room) = room {
+    ///             let content = MessageEventContent::text_plain("Thanks!");
+    ///             room.send_reply(event, content, None).await.unwrap();
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn send_reply(
+        &self,
+        original: &SyncMessageEvent<MessageEventContent>,
+        content: MessageEventContent,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        let content = make_reply(self.inner.room_id(), original, content);
+        self.send(AnyMessageEventContent::RoomMessage(content), txn_id).await
+    }
+
+    /// Edit the given message.
+    ///
+    /// The edit replaces the content of the message, its body is the new body
+    /// prefixed with `* ` as a fallback for clients that don't support edits.
+    /// The edit is added to the aggregations of the message right away, see
+    /// [`aggregations()`](#method.aggregations).
+    ///
+    /// # Arguments
+    ///
+    /// * `original` - The message that should be edited, it needs to be sent
+    /// by us. Edits of an edit replace the message the edit replaced.
+    ///
+    /// * `new_content` - The new content of the message.
+    ///
+    /// * `txn_id` - A unique `Uuid` that can be attached to a `MessageEvent`
+    /// held in its unsigned field as `transaction_id`. If not given one is
+    /// created for the message.
+    pub async fn edit_message(
+        &self,
+        original: &SyncMessageEvent<MessageEventContent>,
+        new_content: MessageEventContent,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        let user_id = self.client.user_id().await.ok_or(Error::AuthenticationRequired)?;
+
+        if original.sender != user_id {
+            return Err(Error::EditNotAllowed(original.event_id.clone()));
+        }
+
+        let event_id = match &original.content.relates_to {
+            Some(Relation::Replacement(replacement)) => replacement.event_id.clone(),
+            _ => original.event_id.clone(),
+        };
+
+        let content = make_edit(event_id, new_content);
+        let response =
+            self.send(AnyMessageEventContent::RoomMessage(content.clone()), txn_id).await?;
+
+        let event = to_raw_value(&json!({
+            "type": "m.room.message",
+            "event_id": response.event_id,
+            "sender": user_id,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "content": content,
+        }))?;
+        let event: SyncRoomEvent = Raw::<AnySyncRoomEvent>::from_json(event).into();
+        self.client.store().aggregate_event(self.inner.room_id(), &event);
+
+        Ok(response)
+    }
+
+    /// Add a message event to the send queue of this room.
+    ///
+    /// The events of the queue are sent in the background, one after the
+    /// other, using [`send()`](#method.send). Until an event was sent it can
+    /// be removed from the queue with
+    /// [`abort_queued()`](#method.abort_queued) or get its content replaced
+    /// with [`replace_queued()`](#method.replace_queued). Use
+    /// [`local_echo_updates()`](#method.local_echo_updates) to follow the
+    /// changes of the queue.
+    ///
+    /// Returns the transaction id the event will be sent with.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event.
+    pub fn queue(&self, content: impl Into<AnyMessageEventContent>) -> Uuid {
+        let event =
+            QueuedEvent { txn_id: Uuid::new_v4(), content: content.into(), depends_on: None };
+        let txn_id = event.txn_id;
+
+        let queue = self.send_queue();
+        let start = queue.lock().unwrap().push(event);
+        self.start_send_queue(queue, start);
+
+        txn_id
+    }
+
+    /// Add an edit of a message that was queued with
+    /// [`queue()`](#method.queue) to the send queue of this room.
+    ///
+    /// If the message wasn't sent yet the edit waits for it in the queue and
+    /// is removed together with it if the message is aborted or fails to be
+    /// sent.
+    ///
+    /// Returns the transaction id the edit will be sent with.
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The transaction id of the message that should be edited.
+    ///
+    /// * `new_content` - The new content of the message.
+    pub async fn queue_edit(
+        &self,
+        txn_id: &Uuid,
+        new_content: MessageEventContent,
+    ) -> Result<Uuid> {
+        let queue = self.send_queue();
+
+        {
+            let mut queue_guard = queue.lock().unwrap();
+
+            if queue_guard.contains(txn_id) {
+                let event = QueuedEvent {
+                    txn_id: Uuid::new_v4(),
+                    content: AnyMessageEventContent::RoomMessage(new_content),
+                    depends_on: Some(*txn_id),
+                };
+                let edit_txn_id = event.txn_id;
+
+                let start = queue_guard.push(event);
+                drop(queue_guard);
+                self.start_send_queue(queue, start);
+
+                return Ok(edit_txn_id);
+            }
+        }
+
+        // The message was already sent.
+        let event_id = self
+            .event_id_for_transaction(&txn_id.to_string())
+            .await?
+            .ok_or(Error::UnknownTransaction(*txn_id))?;
+
+        Ok(self.queue(AnyMessageEventContent::RoomMessage(make_edit(event_id, new_content))))
+    }
+
+    /// Remove an event that wasn't sent yet from the send queue of this room.
+    ///
+    /// Events that depend on the event, e.g. queued edits of it, are removed
+    /// as well.
+    ///
+    /// Returns false if the event isn't queued anymore or if it's being sent
+    /// right now, a sent event needs to be redacted instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The transaction id of the event.
+    pub fn abort_queued(&self, txn_id: &Uuid) -> bool {
+        self.send_queue().lock().unwrap().abort(txn_id)
+    }
+
+    /// Replace the content of an event that wasn't sent yet.
+    ///
+    /// Returns false if the event isn't queued anymore or if it's being sent
+    /// right now, a sent event needs to be edited instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The transaction id of the event.
+    ///
+    /// * `content` - The new content of the event.
+    pub fn replace_queued(
+        &self,
+        txn_id: &Uuid,
+        content: impl Into<AnyMessageEventContent>,
+    ) -> bool {
+        self.send_queue().lock().unwrap().replace(txn_id, content.into())
+    }
+
+    /// Get the events that are waiting in the send queue of this room, in the
+    /// order they will be sent.
+    pub fn queued_events(&self) -> Vec<QueuedEvent> {
+        self.send_queue().lock().unwrap().events()
+    }
+
+    /// Get a stream of the changes of the send queue of this room.
+    pub fn local_echo_updates(&self) -> impl Stream<Item = LocalEchoUpdate> {
+        self.send_queue().lock().unwrap().subscribe()
+    }
+
+    fn send_queue(&self) -> Arc<StdMutex<SendQueue>> {
+        self.client.send_queues.entry(self.room_id().clone()).or_default().clone()
+    }
+
+    fn start_send_queue(&self, queue: Arc<StdMutex<SendQueue>>, start: bool) {
+        if start {
+            let mut room = self.clone();
+            let name = format!("send_queue_{}", self.room_id());
+            let mut guard = SendQueueTask { queue: queue.clone(), finished: false };
+
+            self.client.spawn_task_with_client(&name, move |client| {
+                room.inner.client = client;
+
+                async move {
+                    let result = room.process_send_queue(queue).await;
+                    guard.finished = true;
+
+                    result
+                }
+            });
+        }
+    }
+
+    /// Send out the events of the given queue until it's empty.
+    async fn process_send_queue(&self, queue: Arc<StdMutex<SendQueue>>) -> Result<()> {
+        loop {
+            let event = match queue.lock().unwrap().next() {
+                Some(e) => e,
+                None => return Ok(()),
+            };
+            let txn_id = event.txn_id;
+
+            match self.send_queued_event(event).await {
+                Ok(event_id) => queue.lock().unwrap().sent(txn_id, event_id),
+                Err(e) => {
+                    warn!(
+                        "Failed to send the queued event {} to {}: {}",
+                        txn_id,
+                        self.room_id(),
+                        e
+                    );
+                    queue.lock().unwrap().failed(txn_id, e.to_string());
+                }
+            }
+        }
+    }
+
+    async fn send_queued_event(&self, event: QueuedEvent) -> Result<EventId> {
+        let content = match (event.depends_on, event.content) {
+            // Events that depend on an event only get sent once the event
+            // they depend on was sent.
+            (Some(original), AnyMessageEventContent::RoomMessage(c)) => {
+                let event_id = self
+                    .event_id_for_transaction(&original.to_string())
+                    .await?
+                    .ok_or(Error::UnknownTransaction(original))?;
+
+                AnyMessageEventContent::RoomMessage(make_edit(event_id, c))
+            }
+            (_, content) => content,
+        };
+
+        Ok(self.send(content, Some(event.txn_id)).await?.event_id)
+    }
+
+    /// Send an extensible event, like a location message or a poll, to this
+    /// room.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the event, see the
+    /// [extensible](crate::extensible) module for the supported contents.
+    ///
+    /// * `txn_id` - A unique `Uuid` that can be attached to a `MessageEvent`
+    /// held in its unsigned field as `transaction_id`. If not given one is
+    /// created for the message.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, extensible::{PollAnswer, PollStartContent}};
+    /// # use matrix_sdk::identifiers::room_id;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!test:localhost");
+    /// let poll = PollStartContent::new(
+    ///     "Tabs or spaces?",
+    ///     vec![PollAnswer::new("tabs", "Tabs"), PollAnswer::new("spaces", "Spaces")],
+    /// );
+    ///
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     room.send_extensible(&poll, None).await.unwrap();
+    /// }
+    /// # });
+    /// ```
+    pub async fn send_extensible(
+        &self,
+        content: &impl ExtensibleEventContent,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        let content = AnyMessageEventContent::Custom(content.to_custom()?);
+        self.send(content, txn_id).await
+    }
+
     /// Send an attachment to this room.
     ///
     /// This will upload the given data that the reader produces using the
@@ -563,11 +1381,114 @@ impl Joined {
         state_key: &str,
     ) -> Result<send_state_event::Response> {
         let content = content.into();
+        validate_event(content.event_type(), Some(state_key), &content)?;
+
         let request = send_state_event::Request::new(self.inner.room_id(), state_key, &content);
 
         self.client.send(request, None).await
     }
 
+    /// Publish the given alias for this room in the room directory of the
+    /// alias' homeserver.
+    ///
+    /// The alias isn't advertised in the room, use
+    /// [`set_canonical_alias()`](#method.set_canonical_alias) to make it the
+    /// main alias of the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should point to this room, it needs to be on
+    /// our own homeserver.
+    pub async fn add_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        let request = create_alias::Request::new(alias, self.inner.room_id());
+        self.client.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Remove the given alias of this room from the room directory.
+    ///
+    /// If the alias is advertised in the `m.room.canonical_alias` event of the
+    /// room, it's removed from the event as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should be removed.
+    pub async fn remove_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        let request = delete_alias::Request::new(alias);
+        self.client.send(request, None).await?;
+
+        self.update_canonical_alias(|content| {
+            if content.alias.as_ref() == Some(alias) {
+                content.alias = None;
+            }
+            content.alt_aliases.retain(|a| a != alias);
+        })
+        .await
+    }
+
+    /// Set the canonical alias of this room, the main alias that clients
+    /// show for the room.
+    ///
+    /// The alternative aliases of the room are kept, the new canonical alias
+    /// is removed from them.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The new canonical alias, it needs to point to this room.
+    /// `None` removes the canonical alias.
+    pub async fn set_canonical_alias(&self, alias: Option<&RoomAliasId>) -> Result<()> {
+        self.update_canonical_alias(|content| {
+            if let Some(alias) = alias {
+                content.alt_aliases.retain(|a| a != alias);
+            }
+            content.alias = alias.cloned();
+        })
+        .await
+    }
+
+    /// Fetch the current `m.room.canonical_alias` event of the room, modify it
+    /// with the given closure and send it back if it changed.
+    ///
+    /// The event is fetched from the server instead of the store and the
+    /// updates of a room are serialized, so concurrent updates don't overwrite
+    /// each other's changes.
+    async fn update_canonical_alias(
+        &self,
+        update: impl FnOnce(&mut CanonicalAliasEventContent),
+    ) -> Result<()> {
+        let lock = self
+            .client
+            .canonical_alias_locks
+            .entry(self.inner.room_id().clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        let _guard = lock.lock().await;
+
+        let request = get_state_events_for_key::Request::new(
+            self.inner.room_id(),
+            EventType::RoomCanonicalAlias,
+            "",
+        );
+
+        let old_content = match self.client.send(request, None).await {
+            Ok(response) => serde_json::from_str(response.content.json().get())?,
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) => {
+                CanonicalAliasEventContent::new()
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut content = old_content.clone();
+        update(&mut content);
+
+        if content.alias != old_content.alias || content.alt_aliases != old_content.alt_aliases {
+            self.send_state_event(content, "").await?;
+        }
+
+        Ok(())
+    }
+
     /// Strips all information out of an event of the room.
     ///
     /// Returns the [`redact_event::Response`] from the server.
@@ -614,4 +1535,208 @@ impl Joined {
 
         self.client.send(request, None).await
     }
+
+    /// Redact the messages a user sent to this room since the given point in
+    /// time, e.g. to clean up after a spammer.
+    ///
+    /// Only message events are redacted, the state events of the user, like
+    /// their membership, are kept. Returns the ids of the redacted events.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user whose messages should be redacted.
+    ///
+    /// * `since` - The point in time from which on the messages should be
+    /// redacted.
+    ///
+    /// * `reason` - The reason for the redactions.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::time::{Duration, SystemTime};
+    /// # use matrix_sdk::{identifiers::{room_id, user_id}, MilliSecondsSinceUnixEpoch};
+    /// # futures::executor::block_on(async {
+    /// # let homeserver = url::Url::parse("http://localhost:8080").unwrap();
+    /// # let client = matrix_sdk::Client::new(homeserver).unwrap();
+    /// # let room = client.get_joined_room(&room_id!("!test:localhost")).unwrap();
+    /// let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+    /// let since = MilliSecondsSinceUnixEpoch::from_system_time(an_hour_ago).unwrap();
+    ///
+    /// let spammer = user_id!("@spammer:example.org");
+    /// room.redact_recent_messages_from(&spammer, since, Some("Spam")).await?;
+    /// # matrix_sdk::Result::Ok(()) });
+    /// ```
+    pub async fn redact_recent_messages_from(
+        &self,
+        user_id: &UserId,
+        since: MilliSecondsSinceUnixEpoch,
+        reason: Option<&str>,
+    ) -> Result<Vec<EventId>> {
+        #[derive(Deserialize)]
+        struct Unsigned {
+            redacted_because: Option<serde::de::IgnoredAny>,
+        }
+
+        #[derive(Deserialize)]
+        struct Event {
+            event_id: EventId,
+            #[serde(rename = "type")]
+            event_type: String,
+            sender: UserId,
+            origin_server_ts: MilliSecondsSinceUnixEpoch,
+            state_key: Option<String>,
+            unsigned: Option<Unsigned>,
+        }
+
+        // The sync token points to the end of the timeline, paginating
+        // backwards from it includes the events of the latest sync.
+        let mut from = match self.client.sync_token().await.or_else(|| self.last_prev_batch()) {
+            Some(token) => token,
+            None => return Ok(Vec::new()),
+        };
+
+        let senders = slice::from_ref(user_id);
+        let mut redacted = Vec::new();
+
+        loop {
+            let filter = assign!(RoomEventFilter::empty(), { senders: Some(senders) });
+            let mut request = get_message_events::Request::backward(self.inner.room_id(), &from);
+            request.filter = Some(filter);
+            let response = self.client.send(request, None).await?;
+
+            let mut reached_since = false;
+
+            for event in &response.chunk {
+                let event: Event = match serde_json::from_str(event.json().get()) {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+
+                if event.origin_server_ts < since {
+                    reached_since = true;
+                    break;
+                }
+
+                let already_redacted =
+                    event.unsigned.map_or(false, |u| u.redacted_because.is_some());
+
+                if &event.sender == user_id
+                    && event.state_key.is_none()
+                    && event.event_type != "m.room.redaction"
+                    && !already_redacted
+                {
+                    self.redact(&event.event_id, reason, None).await?;
+                    redacted.push(event.event_id);
+                }
+            }
+
+            match response.end {
+                Some(end) if !reached_since && !response.chunk.is_empty() => from = end,
+                _ => break,
+            }
+        }
+
+        Ok(redacted)
+    }
+
+    /// Set the server access control list of this room, which decides which
+    /// servers may participate in the room.
+    ///
+    /// Servers whose names are IP address literals are denied. Make sure our
+    /// own server is allowed, otherwise we lock ourselves out of the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `allow` - The names of the servers that are allowed, `*` matches zero
+    /// or more characters and `?` matches exactly one character.
+    ///
+    /// * `deny` - The names of the servers that are denied, even if they are
+    /// allowed by `allow`.
+    pub async fn set_server_acl(
+        &self,
+        allow: Vec<String>,
+        deny: Vec<String>,
+    ) -> Result<send_state_event::Response> {
+        let content = ServerAclEventContent::new(false, allow, deny);
+        self.send_state_event(AnyStateEventContent::RoomServerAcl(content), "").await
+    }
+
+    /// Upgrade this room to a new room version.
+    ///
+    /// The homeserver creates a new room with the given version, copies the
+    /// important state over and tombstones this room. Returns the id of the
+    /// new room.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_version` - The version of the new room. If it's `None` the
+    /// default version of the homeserver is used. Fails with
+    /// [`Error::UnsupportedRoomVersion`] if the homeserver doesn't support
+    /// the version, see [`Client::capabilities()`].
+    pub async fn upgrade(&self, new_version: Option<&RoomVersionId>) -> Result<RoomId> {
+        let capabilities = self.client.capabilities().await?;
+        let room_versions = capabilities.room_versions;
+
+        let new_version = new_version.unwrap_or(&room_versions.default);
+
+        if !room_versions.available.contains_key(new_version) {
+            return Err(Error::UnsupportedRoomVersion(new_version.clone()));
+        }
+
+        let request = upgrade_room::Request::new(self.inner.room_id(), new_version);
+        let response = self.client.send(request, None).await?;
+
+        Ok(response.replacement_room)
+    }
+
+    /// Compare the joined members of this room in the store with the ones of
+    /// the homeserver and repair the store if they differ.
+    ///
+    /// The store can drift from the homeserver over time, e.g. if a sync
+    /// response was lost or the state of the room was reset. If any
+    /// discrepancy is found, the full member list is fetched from the
+    /// homeserver and replaces the stored one, including the member counts.
+    ///
+    /// Returns the discrepancies that were found, this is mostly useful for
+    /// long running bots that want to log or monitor them.
+    pub async fn reconcile_members(&self) -> Result<MemberReconciliation> {
+        let room_id = self.inner.room_id();
+
+        let request = joined_members::Request::new(room_id);
+        let response = {
+            let _permit = self.client.request_limiter.acquire(RequestCategory::Members).await;
+            self.client.send(request, None).await?
+        };
+        let joined: BTreeSet<UserId> = response.joined.into_iter().map(|(u, _)| u).collect();
+        let stored: BTreeSet<UserId> = self.inner.joined_user_ids().await?.into_iter().collect();
+
+        let reconciliation = MemberReconciliation {
+            missing: joined.difference(&stored).cloned().collect(),
+            stale: stored.difference(&joined).cloned().collect(),
+            stored_count: self.inner.joined_members_count(),
+            count: joined.len() as u64,
+        };
+
+        if !reconciliation.is_consistent() {
+            warn!(
+                "The joined members of {} drifted from the homeserver, {} missing, {} stale, \
+                 {} counted instead of {}, repairing the store",
+                room_id,
+                reconciliation.missing.len(),
+                reconciliation.stale.len(),
+                reconciliation.stored_count,
+                reconciliation.count,
+            );
+
+            let request = get_member_events::Request::new(room_id);
+            let response = {
+                let _permit = self.client.request_limiter.acquire(RequestCategory::Members).await;
+                self.client.send(request, None).await?
+            };
+            self.client.base_client.reconcile_members(room_id, &response).await?;
+        }
+
+        Ok(reconciliation)
+    }
 }
@@ -0,0 +1,144 @@
+use std::ops::Deref;
+
+use crate::{room::Common, BaseRoom, Client, Result, RoomType};
+
+/// A room in the knocked state.
+///
+/// This struct contains all methods specific to a `Room` with type
+/// `RoomType::Knocked`. Operations may fail once the underlying `Room` changes
+/// `RoomType`.
+#[derive(Debug, Clone)]
+pub struct Knocked {
+    pub(crate) inner: Common,
+}
+
+impl Knocked {
+    /// Create a new `room::Knocked` if the underlying `Room` has type
+    /// `RoomType::Knocked`.
+    ///
+    /// # Arguments
+    /// * `client` - The client used to make requests.
+    ///
+    /// * `room` - The underlying room.
+    pub fn new(client: Client, room: BaseRoom) -> Option<Self> {
+        if room.room_type() == RoomType::Knocked {
+            Some(Self { inner: Common::new(client, room) })
+        } else {
+            None
+        }
+    }
+
+    /// Withdraw the knock, a member of the room won't be able to invite us
+    /// anymore in response to it.
+    pub async fn cancel_knock(&self) -> Result<()> {
+        self.inner.leave().await
+    }
+}
+
+impl Deref for Knocked {
+    type Target = Common;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// The `/knock` endpoint, Ruma doesn't support it yet.
+pub(crate) mod knock {
+    use bytes::BufMut;
+    use http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use ruma::{
+        api::{
+            client::Error as ClientError,
+            error::{FromHttpResponseError, IntoHttpError, ServerError},
+            AuthScheme, EndpointError, IncomingResponse, Metadata, OutgoingRequest,
+            SendAccessToken,
+        },
+        serde::{json_to_buf, urlencoded},
+        RoomId, RoomIdOrAliasId, ServerName,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug)]
+    pub(crate) struct Request<'a> {
+        pub room_id_or_alias: &'a RoomIdOrAliasId,
+        pub reason: Option<&'a str>,
+        pub server_names: &'a [Box<ServerName>],
+    }
+
+    #[derive(Serialize)]
+    struct RequestBody<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    }
+
+    impl OutgoingRequest for Request<'_> {
+        type EndpointError = ClientError;
+        type IncomingResponse = Response;
+
+        const METADATA: Metadata = Metadata {
+            description: "Knock on a room, requesting permission to join.",
+            method: http::Method::POST,
+            name: "knock_room",
+            path: "/_matrix/client/r0/knock/:room_id_or_alias",
+            rate_limited: true,
+            authentication: AuthScheme::AccessToken,
+        };
+
+        fn try_into_http_request<T: Default + BufMut>(
+            self,
+            base_url: &str,
+            access_token: SendAccessToken<'_>,
+        ) -> Result<http::Request<T>, IntoHttpError> {
+            let mut uri = format!(
+                "{}/_matrix/client/r0/knock/{}",
+                base_url.strip_suffix('/').unwrap_or(base_url),
+                utf8_percent_encode(self.room_id_or_alias.as_str(), NON_ALPHANUMERIC),
+            );
+
+            if !self.server_names.is_empty() {
+                let query: Vec<_> =
+                    self.server_names.iter().map(|s| ("server_name", s.as_str())).collect();
+
+                uri.push('?');
+                uri.push_str(&urlencoded::to_string(query)?);
+            }
+
+            let access_token = access_token
+                .get_required_for_endpoint()
+                .ok_or(IntoHttpError::NeedsAuthentication)?;
+
+            Ok(http::Request::builder()
+                .method(Self::METADATA.method)
+                .uri(uri)
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, format!("Bearer {}", access_token))
+                .body(json_to_buf(&RequestBody { reason: self.reason })?)?)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct Response {
+        pub room_id: RoomId,
+    }
+
+    impl IncomingResponse for Response {
+        type EndpointError = ClientError;
+
+        fn try_from_http_response<T: AsRef<[u8]>>(
+            response: http::Response<T>,
+        ) -> Result<Self, FromHttpResponseError<ClientError>> {
+            if response.status().as_u16() < 400 {
+                Ok(serde_json::from_slice(response.body().as_ref())?)
+            } else {
+                Err(FromHttpResponseError::Http(
+                    match ClientError::try_from_http_response(response) {
+                        Ok(e) => ServerError::Known(e),
+                        Err(e) => ServerError::Unknown(e),
+                    },
+                ))
+            }
+        }
+    }
+}
@@ -1,13 +1,138 @@
 use std::ops::Deref;
 
+use ruma::{events::room::member::MembershipState, UserId};
+
 use crate::RoomType;
 
 mod common;
+mod edit;
+mod export;
 mod invited;
 mod joined;
+mod knocked;
 mod left;
+mod preview;
+mod relations;
+mod reply;
+mod send_queue;
+
+pub use self::{
+    common::Common,
+    export::{ExportFormat, ExportSummary},
+    invited::Invited,
+    joined::Joined,
+    knocked::Knocked,
+    left::Left,
+    preview::RoomPreview,
+    relations::Relations,
+    send_queue::{LocalEchoUpdate, QueuedEvent},
+};
+pub(crate) use self::{knocked::knock, preview::latest_messages, send_queue::SendQueue};
+
+/// The notification mode of a room, controlled through the push rules of the
+/// user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomNotificationMode {
+    /// Get notified about all the messages of the room.
+    All,
+    /// Only get notified about messages that mention us or contain one of our
+    /// keywords.
+    MentionsOnly,
+    /// Don't get notified about any messages of the room.
+    Mute,
+}
+
+/// The membership a user should end up with, see
+/// [`Joined::ensure_membership()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DesiredMembership {
+    /// The user should be a member of the room.
+    Join,
+    /// The user should be invited to the room, a user that already joined the
+    /// room stays in it.
+    Invite,
+    /// The user should neither be a member of the room nor be invited to it.
+    Leave,
+    /// The user should be banned from the room.
+    Ban,
+}
+
+/// A single membership change that is needed to reach a
+/// [`DesiredMembership`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipChange {
+    /// Lift the ban of the user.
+    Unban,
+    /// Invite the user to the room.
+    Invite,
+    /// Join the room as the user.
+    Join,
+    /// Kick the user out of the room, this also revokes invites and rejects
+    /// knocks.
+    Kick,
+    /// Ban the user from the room.
+    Ban,
+}
+
+impl DesiredMembership {
+    /// Get the minimal list of changes that bring a user from the given
+    /// membership to the desired one.
+    ///
+    /// The list is empty if the user already has the desired membership.
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - The current membership of the user, `None` if the user
+    ///   never was in the room.
+    pub fn changes_from(self, current: Option<&MembershipState>) -> Vec<MembershipChange> {
+        use MembershipChange::*;
+
+        let current = current.unwrap_or(&MembershipState::Leave);
+
+        match (self, current) {
+            (Self::Join, MembershipState::Join) => vec![],
+            (Self::Join, MembershipState::Invite) => vec![Join],
+            (Self::Join, MembershipState::Ban) => vec![Unban, Invite, Join],
+            (Self::Join, _) => vec![Invite, Join],
+
+            (Self::Invite, MembershipState::Join) | (Self::Invite, MembershipState::Invite) => {
+                vec![]
+            }
+            (Self::Invite, MembershipState::Ban) => vec![Unban, Invite],
+            (Self::Invite, _) => vec![Invite],
+
+            (Self::Leave, MembershipState::Leave) => vec![],
+            (Self::Leave, MembershipState::Ban) => vec![Unban],
+            (Self::Leave, _) => vec![Kick],
+
+            (Self::Ban, MembershipState::Ban) => vec![],
+            (Self::Ban, _) => vec![Ban],
+        }
+    }
+}
+
+/// The result of comparing the joined members in the store with the ones of
+/// the homeserver, see [`Joined::reconcile_members()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberReconciliation {
+    /// The users that are joined according to the homeserver but not
+    /// according to the store.
+    pub missing: Vec<UserId>,
+    /// The users that are joined according to the store but not according to
+    /// the homeserver.
+    pub stale: Vec<UserId>,
+    /// The number of joined members the room summary in the store reported.
+    pub stored_count: u64,
+    /// The number of joined members according to the homeserver.
+    pub count: u64,
+}
 
-pub use self::{common::Common, invited::Invited, joined::Joined, left::Left};
+impl MemberReconciliation {
+    /// Did the store agree with the homeserver.
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.stored_count == self.count
+    }
+}
 
 /// An enum that abstracts over the different states a room can be in.
 #[derive(Debug, Clone)]
@@ -18,6 +143,8 @@ pub enum Room {
     Left(Left),
     /// The room in the `invited` state.
     Invited(Invited),
+    /// The room in the `knocked` state.
+    Knocked(Knocked),
 }
 
 impl Deref for Room {
@@ -28,6 +155,7 @@ impl Deref for Room {
             Self::Joined(room) => &*room,
             Self::Left(room) => &*room,
             Self::Invited(room) => &*room,
+            Self::Knocked(room) => &*room,
         }
     }
 }
@@ -38,6 +166,7 @@ impl From<Common> for Room {
             RoomType::Joined => Self::Joined(Joined { inner: room }),
             RoomType::Left => Self::Left(Left { inner: room }),
             RoomType::Invited => Self::Invited(Invited { inner: room }),
+            RoomType::Knocked => Self::Knocked(Knocked { inner: room }),
         }
     }
 }
@@ -49,6 +178,7 @@ impl From<Joined> for Room {
             RoomType::Joined => Self::Joined(Joined { inner: room }),
             RoomType::Left => Self::Left(Left { inner: room }),
             RoomType::Invited => Self::Invited(Invited { inner: room }),
+            RoomType::Knocked => Self::Knocked(Knocked { inner: room }),
         }
     }
 }
@@ -60,6 +190,7 @@ impl From<Left> for Room {
             RoomType::Joined => Self::Joined(Joined { inner: room }),
             RoomType::Left => Self::Left(Left { inner: room }),
             RoomType::Invited => Self::Invited(Invited { inner: room }),
+            RoomType::Knocked => Self::Knocked(Knocked { inner: room }),
         }
     }
 }
@@ -71,6 +202,19 @@ impl From<Invited> for Room {
             RoomType::Joined => Self::Joined(Joined { inner: room }),
             RoomType::Left => Self::Left(Left { inner: room }),
             RoomType::Invited => Self::Invited(Invited { inner: room }),
+            RoomType::Knocked => Self::Knocked(Knocked { inner: room }),
+        }
+    }
+}
+
+impl From<Knocked> for Room {
+    fn from(room: Knocked) -> Self {
+        let room = (*room).clone();
+        match room.room_type() {
+            RoomType::Joined => Self::Joined(Joined { inner: room }),
+            RoomType::Left => Self::Left(Left { inner: room }),
+            RoomType::Invited => Self::Invited(Invited { inner: room }),
+            RoomType::Knocked => Self::Knocked(Knocked { inner: room }),
         }
     }
 }
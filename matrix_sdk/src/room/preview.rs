@@ -0,0 +1,226 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::{
+    events::{
+        room::{
+            create::RoomType as CreateRoomType, history_visibility::HistoryVisibility,
+            join_rules::JoinRule, member::MembershipState,
+        },
+        AnyMessageEvent, AnyRoomEvent, AnyStateEvent, AnyStateEventContent,
+    },
+    serde::Raw,
+    MxcUri, RoomAliasId, RoomId,
+};
+use tracing::warn;
+
+/// A read-only preview of a room we didn't join.
+///
+/// A preview can be fetched using
+/// [`Client::preview_room()`](crate::Client::preview_room), this only works
+/// for rooms that are world readable.
+#[derive(Clone, Debug)]
+pub struct RoomPreview {
+    /// The id of the room.
+    pub room_id: RoomId,
+    /// The `m.room.name` of the room.
+    pub name: Option<String>,
+    /// The canonical alias of the room.
+    pub canonical_alias: Option<RoomAliasId>,
+    /// The topic of the room.
+    pub topic: Option<String>,
+    /// The avatar URL of the room.
+    pub avatar_url: Option<MxcUri>,
+    /// The type of the room, e.g. `m.space`, taken from the `m.room.create`
+    /// event.
+    pub room_type: Option<CreateRoomType>,
+    /// Is the room encrypted.
+    pub is_encrypted: bool,
+    /// The join rule of the room.
+    pub join_rule: JoinRule,
+    /// The history visibility of the room.
+    pub history_visibility: HistoryVisibility,
+    /// The number of members that joined the room.
+    pub joined_members_count: u64,
+    /// The most recent messages of the room, oldest first.
+    ///
+    /// The messages are only fetched for world readable rooms. Messages of
+    /// encrypted rooms can't be decrypted since we aren't a member of the
+    /// room.
+    pub messages: Vec<AnyMessageEvent>,
+}
+
+impl RoomPreview {
+    /// Create a preview from the state of the room, the join rule and history
+    /// visibility default to the ones the spec defines for rooms without the
+    /// corresponding state events.
+    pub(crate) fn new(room_id: RoomId, state: &[Raw<AnyStateEvent>]) -> Self {
+        let mut preview = Self {
+            room_id,
+            name: None,
+            canonical_alias: None,
+            topic: None,
+            avatar_url: None,
+            room_type: None,
+            is_encrypted: false,
+            join_rule: JoinRule::Invite,
+            history_visibility: HistoryVisibility::Shared,
+            joined_members_count: 0,
+            messages: Vec::new(),
+        };
+
+        for event in state {
+            match event.deserialize() {
+                Ok(event) => preview.handle_state_event(event.content()),
+                Err(e) => {
+                    warn!("Couldn't deserialize state event of room {}: {:?}", preview.room_id, e)
+                }
+            }
+        }
+
+        preview
+    }
+
+    /// Set the most recent messages of the room.
+    pub(crate) fn set_messages(&mut self, messages: &[Raw<AnyRoomEvent>]) {
+        // The messages are fetched using backwards pagination, they arrive
+        // newest first.
+        self.messages = messages
+            .iter()
+            .rev()
+            .filter_map(|e| match e.deserialize() {
+                Ok(AnyRoomEvent::Message(e)) => Some(e),
+                _ => None,
+            })
+            .collect();
+    }
+
+    fn handle_state_event(&mut self, content: AnyStateEventContent) {
+        match content {
+            AnyStateEventContent::RoomName(n) => self.name = n.name().map(|n| n.to_string()),
+            AnyStateEventContent::RoomCanonicalAlias(a) => self.canonical_alias = a.alias,
+            AnyStateEventContent::RoomTopic(t) => self.topic = Some(t.topic),
+            AnyStateEventContent::RoomAvatar(a) => self.avatar_url = a.url,
+            AnyStateEventContent::RoomCreate(c) => self.room_type = c.room_type,
+            AnyStateEventContent::RoomEncryption(_) => self.is_encrypted = true,
+            AnyStateEventContent::RoomJoinRules(j) => self.join_rule = j.join_rule,
+            AnyStateEventContent::RoomHistoryVisibility(h) => {
+                self.history_visibility = h.history_visibility
+            }
+            AnyStateEventContent::RoomMember(m) if m.membership == MembershipState::Join => {
+                self.joined_members_count += 1
+            }
+            _ => {}
+        }
+    }
+
+    /// Get a name that can be shown for the room.
+    ///
+    /// This is the name of the room, its canonical alias or, if the room has
+    /// neither, its room id.
+    pub fn display_name(&self) -> String {
+        self.name
+            .clone()
+            .or_else(|| self.canonical_alias.as_ref().map(|a| a.to_string()))
+            .unwrap_or_else(|| self.room_id.to_string())
+    }
+}
+
+/// The `/messages` endpoint without a `from` token, the messages are returned
+/// starting from the most recent one. Ruma requires the token to be set.
+pub(crate) mod latest_messages {
+    use bytes::BufMut;
+    use http::header::AUTHORIZATION;
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use ruma::{
+        api::{
+            client::Error as ClientError,
+            error::{FromHttpResponseError, IntoHttpError, ServerError},
+            AuthScheme, EndpointError, IncomingResponse, Metadata, OutgoingRequest,
+            SendAccessToken,
+        },
+        events::AnyRoomEvent,
+        serde::Raw,
+        RoomId, UInt,
+    };
+    use serde::Deserialize;
+
+    #[derive(Debug)]
+    pub(crate) struct Request<'a> {
+        pub room_id: &'a RoomId,
+        pub limit: UInt,
+    }
+
+    impl OutgoingRequest for Request<'_> {
+        type EndpointError = ClientError;
+        type IncomingResponse = Response;
+
+        const METADATA: Metadata = Metadata {
+            description: "Get the most recent message events of a room.",
+            method: http::Method::GET,
+            name: "get_message_events",
+            path: "/_matrix/client/r0/rooms/:room_id/messages",
+            rate_limited: false,
+            authentication: AuthScheme::AccessToken,
+        };
+
+        fn try_into_http_request<T: Default + BufMut>(
+            self,
+            base_url: &str,
+            access_token: SendAccessToken<'_>,
+        ) -> Result<http::Request<T>, IntoHttpError> {
+            let uri = format!(
+                "{}/_matrix/client/r0/rooms/{}/messages?dir=b&limit={}",
+                base_url.strip_suffix('/').unwrap_or(base_url),
+                utf8_percent_encode(self.room_id.as_str(), NON_ALPHANUMERIC),
+                self.limit,
+            );
+
+            let access_token = access_token
+                .get_required_for_endpoint()
+                .ok_or(IntoHttpError::NeedsAuthentication)?;
+
+            Ok(http::Request::builder()
+                .method(Self::METADATA.method)
+                .uri(uri)
+                .header(AUTHORIZATION, format!("Bearer {}", access_token))
+                .body(T::default())?)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct Response {
+        #[serde(default)]
+        pub chunk: Vec<Raw<AnyRoomEvent>>,
+    }
+
+    impl IncomingResponse for Response {
+        type EndpointError = ClientError;
+
+        fn try_from_http_response<T: AsRef<[u8]>>(
+            response: http::Response<T>,
+        ) -> Result<Self, FromHttpResponseError<ClientError>> {
+            if response.status().as_u16() < 400 {
+                Ok(serde_json::from_slice(response.body().as_ref())?)
+            } else {
+                Err(FromHttpResponseError::Http(
+                    match ClientError::try_from_http_response(response) {
+                        Ok(e) => ServerError::Known(e),
+                        Err(e) => ServerError::Unknown(e),
+                    },
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `/relations` endpoint of [MSC2675], Ruma doesn't support it yet.
+//!
+//! [MSC2675]: https://github.com/matrix-org/matrix-doc/pull/2675
+
+use bytes::BufMut;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use matrix_sdk_base::{deserialized_responses::SyncRoomEvent, RelationType};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use ruma::{
+    api::{
+        client::Error as ClientError,
+        error::{FromHttpResponseError, IntoHttpError, ServerError},
+        AuthScheme, EndpointError, IncomingResponse, Metadata, OutgoingRequest, SendAccessToken,
+    },
+    events::{AnyRoomEvent, EventType},
+    serde::{urlencoded, Raw},
+    EventId, RoomId, UInt,
+};
+use serde::Deserialize;
+
+/// A page of the events that relate to an event.
+#[derive(Clone, Debug)]
+pub struct Relations {
+    /// The related events, encrypted events are decrypted if the room key for
+    /// them is known.
+    pub chunk: Vec<SyncRoomEvent>,
+    /// A token to fetch the next page of related events, `None` if there are
+    /// no more related events.
+    pub next_batch: Option<String>,
+    /// A token to fetch the previous page of related events.
+    pub prev_batch: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Request<'a> {
+    pub room_id: &'a RoomId,
+    pub event_id: &'a EventId,
+    pub rel_type: RelationType,
+    pub event_type: &'a EventType,
+    pub from: Option<&'a str>,
+    pub limit: Option<UInt>,
+}
+
+impl OutgoingRequest for Request<'_> {
+    type EndpointError = ClientError;
+    type IncomingResponse = Response;
+
+    const METADATA: Metadata = Metadata {
+        description: "Get the child events for a given parent event.",
+        method: http::Method::GET,
+        name: "get_relating_events",
+        path: "/_matrix/client/unstable/rooms/:room_id/relations/:event_id/:rel_type/:event_type",
+        rate_limited: false,
+        authentication: AuthScheme::AccessToken,
+    };
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let encode = |segment: &str| utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string();
+
+        let mut uri = format!(
+            "{}/_matrix/client/unstable/rooms/{}/relations/{}/{}/{}",
+            base_url.strip_suffix('/').unwrap_or(base_url),
+            encode(self.room_id.as_str()),
+            encode(self.event_id.as_str()),
+            encode(self.rel_type.as_str()),
+            encode(self.event_type.as_str()),
+        );
+
+        let mut query = Vec::new();
+
+        if let Some(from) = self.from {
+            query.push(("from", from.to_owned()));
+        }
+
+        if let Some(limit) = self.limit {
+            query.push(("limit", limit.to_string()));
+        }
+
+        if !query.is_empty() {
+            uri.push('?');
+            uri.push_str(&urlencoded::to_string(query)?);
+        }
+
+        let access_token =
+            access_token.get_required_for_endpoint().ok_or(IntoHttpError::NeedsAuthentication)?;
+
+        Ok(http::Request::builder()
+            .method(Self::METADATA.method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .body(T::default())?)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub chunk: Vec<Raw<AnyRoomEvent>>,
+    pub next_batch: Option<String>,
+    pub prev_batch: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseBody {
+    #[serde(default)]
+    chunk: Vec<Raw<AnyRoomEvent>>,
+    next_batch: Option<String>,
+    prev_batch: Option<String>,
+}
+
+impl IncomingResponse for Response {
+    type EndpointError = ClientError;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<ClientError>> {
+        if response.status().as_u16() < 400 {
+            let body: ResponseBody = serde_json::from_slice(response.body().as_ref())?;
+
+            Ok(Self { chunk: body.chunk, next_batch: body.next_batch, prev_batch: body.prev_batch })
+        } else {
+            Err(FromHttpResponseError::Http(match ClientError::try_from_http_response(response) {
+                Ok(e) => ServerError::Known(e),
+                Err(e) => ServerError::Unknown(e),
+            }))
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rich replies, including the fallback for clients that don't support them.
+//!
+//! See the [spec] for the format of the fallback.
+//!
+//! [spec]: https://matrix.org/docs/spec/client_server/r0.6.1#rich-replies
+
+use ruma::{
+    events::{
+        room::message::{
+            EmoteMessageEventContent, FormattedBody, InReplyTo, MessageEventContent, MessageFormat,
+            MessageType, NoticeMessageEventContent, Relation, TextMessageEventContent,
+        },
+        SyncMessageEvent,
+    },
+    RoomId,
+};
+
+use crate::html;
+
+/// Turn the given content into a reply to the given event.
+///
+/// Text, notice and emote messages get the quote of the original event
+/// prepended to their body, other message types only get the relation.
+pub(crate) fn make_reply(
+    room_id: &RoomId,
+    original: &SyncMessageEvent<MessageEventContent>,
+    mut content: MessageEventContent,
+) -> MessageEventContent {
+    match &mut content.msgtype {
+        MessageType::Text(TextMessageEventContent { body, formatted, .. })
+        | MessageType::Notice(NoticeMessageEventContent { body, formatted, .. })
+        | MessageType::Emote(EmoteMessageEventContent { body, formatted, .. }) => {
+            let html_reply = match formatted.take() {
+                Some(f) if f.format == MessageFormat::Html => f.body,
+                _ => plain_to_html(body),
+            };
+
+            *formatted = Some(FormattedBody::html(format!(
+                "{}{}",
+                html_quote(room_id, original),
+                html_reply
+            )));
+            *body = format!("{}\n\n{}", plain_quote(original), body);
+        }
+        _ => {}
+    }
+
+    content.relates_to =
+        Some(Relation::Reply { in_reply_to: InReplyTo::new(original.event_id.clone()) });
+
+    content
+}
+
+/// The part of the original event that is quoted, with the fallback of the
+/// original event removed if it's a reply itself.
+///
+/// Returns the plain body and the formatted body if there is one, `None` if
+/// the original event isn't a text-like message.
+fn quoted_bodies(
+    original: &SyncMessageEvent<MessageEventContent>,
+) -> Option<(String, Option<String>)> {
+    let (body, formatted) = match &original.content.msgtype {
+        MessageType::Text(c) => (&c.body, &c.formatted),
+        MessageType::Notice(c) => (&c.body, &c.formatted),
+        MessageType::Emote(c) => (&c.body, &c.formatted),
+        MessageType::ServerNotice(c) => return Some((c.body.clone(), None)),
+        MessageType::Location(c) => return Some((c.body.clone(), None)),
+        _ => return None,
+    };
+
+    let is_reply = matches!(original.content.relates_to, Some(Relation::Reply { .. }));
+    let formatted = formatted.as_ref().filter(|f| f.format == MessageFormat::Html);
+
+    if is_reply {
+        Some((
+            strip_plain_reply_fallback(body),
+            formatted.map(|f| strip_html_reply_fallback(&f.body).to_owned()),
+        ))
+    } else {
+        Some((body.clone(), formatted.map(|f| f.body.clone())))
+    }
+}
+
+/// What is quoted for message types that aren't text-like, e.g. images.
+fn media_description(original: &SyncMessageEvent<MessageEventContent>) -> &'static str {
+    match original.content.msgtype {
+        MessageType::Audio(_) => "sent an audio file.",
+        MessageType::File(_) => "sent a file.",
+        MessageType::Image(_) => "sent an image.",
+        MessageType::Video(_) => "sent a video.",
+        _ => "sent a message.",
+    }
+}
+
+fn plain_quote(original: &SyncMessageEvent<MessageEventContent>) -> String {
+    let body = quoted_bodies(original)
+        .map(|(body, _)| body)
+        .unwrap_or_else(|| media_description(original).to_owned());
+
+    let emote = if let MessageType::Emote(_) = original.content.msgtype { "* " } else { "" };
+    let mut lines = body.lines();
+
+    let mut quote =
+        format!("> {}<{}> {}", emote, original.sender, lines.next().unwrap_or_default());
+
+    for line in lines {
+        quote.push_str("\n> ");
+        quote.push_str(line);
+    }
+
+    quote
+}
+
+fn html_quote(room_id: &RoomId, original: &SyncMessageEvent<MessageEventContent>) -> String {
+    let html = match quoted_bodies(original) {
+        Some((_, Some(formatted))) => formatted,
+        Some((body, None)) => plain_to_html(&body),
+        None => media_description(original).to_owned(),
+    };
+
+    let emote = if let MessageType::Emote(_) = original.content.msgtype { "* " } else { "" };
+
+    format!(
+        "<mx-reply><blockquote>\
+            <a href=\"https://matrix.to/#/{room_id}/{event_id}\">In reply to</a> \
+            {emote}<a href=\"https://matrix.to/#/{sender}\">{sender}</a>\
+            <br>{html}\
+        </blockquote></mx-reply>",
+        room_id = room_id,
+        event_id = original.event_id,
+        emote = emote,
+        sender = original.sender,
+        html = html,
+    )
+}
+
+/// Remove the reply fallback from the plain body of a reply.
+fn strip_plain_reply_fallback(body: &str) -> String {
+    let mut lines = body.lines().skip_while(|l| l.starts_with('>')).peekable();
+
+    // The fallback is separated from the reply by an empty line.
+    if lines.peek() == Some(&"") {
+        lines.next();
+    }
+
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+/// Remove the reply fallback from the formatted body of a reply.
+fn strip_html_reply_fallback(html: &str) -> &str {
+    const END: &str = "</mx-reply>";
+
+    match html.find(END) {
+        Some(end) if html.trim_start().starts_with("<mx-reply>") => &html[end + END.len()..],
+        _ => html,
+    }
+}
+
+fn plain_to_html(body: &str) -> String {
+    html::escape(body).replace('\n', "<br>")
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{
+        events::{
+            room::message::{MessageEventContent, MessageType, Relation},
+            SyncMessageEvent,
+        },
+        room_id,
+    };
+    use serde_json::json;
+
+    use super::make_reply;
+
+    #[test]
+    fn reply_to_reply_strips_previous_fallback() {
+        let original: SyncMessageEvent<MessageEventContent> = serde_json::from_value(json!({
+            "content": {
+                "body": "> <@bob:localhost> Who's there?\n\nIt's me\nAlice",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<mx-reply><blockquote>Who's there?</blockquote></mx-reply>\
+                                   It's me<br>Alice",
+                "msgtype": "m.text",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$first:localhost" } }
+            },
+            "event_id": "$second:localhost",
+            "origin_server_ts": 1,
+            "sender": "@alice:localhost",
+            "type": "m.room.message"
+        }))
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        let reply = make_reply(&room_id, &original, MessageEventContent::text_plain("Hi <3"));
+
+        match reply.relates_to {
+            Some(Relation::Reply { in_reply_to }) => {
+                assert_eq!(in_reply_to.event_id.as_str(), "$second:localhost")
+            }
+            _ => panic!("The content isn't a reply"),
+        }
+
+        let content = match reply.msgtype {
+            MessageType::Text(c) => c,
+            _ => panic!("The reply isn't a text message"),
+        };
+
+        assert_eq!(content.body, "> <@alice:localhost> It's me\n> Alice\n\nHi <3");
+        assert_eq!(
+            content.formatted.unwrap().body,
+            "<mx-reply><blockquote>\
+             <a href=\"https://matrix.to/#/!room:localhost/$second:localhost\">In reply to</a> \
+             <a href=\"https://matrix.to/#/@alice:localhost\">@alice:localhost</a>\
+             <br>It's me<br>Alice\
+             </blockquote></mx-reply>\
+             Hi &lt;3"
+        );
+    }
+}
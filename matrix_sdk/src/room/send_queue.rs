@@ -0,0 +1,397 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The queue of events that are waiting to be sent to a room.
+//!
+//! Events are sent one after the other in the order they were queued. Events
+//! that weren't sent yet can be removed from the queue or get their content
+//! replaced, every change of the queue is reported as a [`LocalEchoUpdate`]
+//! so the local echoes of the events can be kept up to date.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use matrix_sdk_common::uuid::Uuid;
+use ruma::{events::AnyMessageEventContent, EventId};
+
+/// An event that is waiting in the send queue of a room.
+#[derive(Clone, Debug)]
+pub struct QueuedEvent {
+    /// The transaction id the event will be sent with, it identifies the local
+    /// echo of the event.
+    pub txn_id: Uuid,
+    /// The content of the event.
+    ///
+    /// For edits of events that are queued themselves this is the new content
+    /// of the edited event, the relation to the edited event is added once
+    /// the edited event was sent.
+    pub content: AnyMessageEventContent,
+    /// The transaction id of the queued event this event depends on, e.g. the
+    /// message an edit replaces.
+    ///
+    /// The event is removed from the queue together with the event it depends
+    /// on.
+    pub depends_on: Option<Uuid>,
+}
+
+/// A change of the send queue of a room, used to keep the local echoes of
+/// the queued events up to date.
+#[derive(Clone, Debug)]
+pub enum LocalEchoUpdate {
+    /// An event was added to the queue.
+    Queued(QueuedEvent),
+    /// The content of an event that wasn't sent yet was replaced.
+    Replaced(QueuedEvent),
+    /// An event was removed from the queue before it was sent, either because
+    /// it was aborted or because the event it depends on was removed.
+    Aborted(Uuid),
+    /// An event was sent.
+    Sent {
+        /// The transaction id of the event.
+        txn_id: Uuid,
+        /// The id the homeserver gave the event.
+        event_id: EventId,
+    },
+    /// Sending an event failed, the event was removed from the queue.
+    SendingFailed {
+        /// The transaction id of the event.
+        txn_id: Uuid,
+        /// A description of the error.
+        error: String,
+    },
+}
+
+/// The send queue of a single room.
+#[derive(Debug, Default)]
+pub(crate) struct SendQueue {
+    /// The events that weren't sent yet, the first one might be in flight.
+    events: VecDeque<QueuedEvent>,
+    /// The transaction id of the event that is being sent right now, it can't
+    /// be changed anymore.
+    sending: Option<Uuid>,
+    /// Is a task sending out the events of the queue.
+    running: bool,
+    /// The senders of the streams that the local echo updates get sent to.
+    senders: Vec<UnboundedSender<LocalEchoUpdate>>,
+}
+
+impl SendQueue {
+    /// Get a stream of the changes of this queue.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<LocalEchoUpdate> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.senders.push(sender);
+
+        receiver
+    }
+
+    fn notify(&mut self, update: LocalEchoUpdate) {
+        // Drop the senders of streams that are gone.
+        self.senders.retain(|s| s.unbounded_send(update.clone()).is_ok());
+    }
+
+    /// The events that weren't sent yet, in the order they will be sent.
+    pub fn events(&self) -> Vec<QueuedEvent> {
+        self.events.iter().cloned().collect()
+    }
+
+    /// Are there no events waiting in the queue or being sent.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Is a task sending out the events of the queue.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Is the event with the given transaction id waiting in the queue or
+    /// being sent.
+    pub fn contains(&self, txn_id: &Uuid) -> bool {
+        self.events.iter().any(|e| &e.txn_id == txn_id)
+    }
+
+    /// Add the given event to the end of the queue.
+    ///
+    /// Returns true if a task needs to be started that sends out the events,
+    /// the task should call [`next()`](#method.next) until it returns `None`.
+    pub fn push(&mut self, event: QueuedEvent) -> bool {
+        self.events.push_back(event.clone());
+        self.notify(LocalEchoUpdate::Queued(event));
+
+        !std::mem::replace(&mut self.running, true)
+    }
+
+    /// Remove the event with the given transaction id, and the events that
+    /// depend on it, from the queue.
+    ///
+    /// Returns false if the event isn't queued or if it's being sent right
+    /// now.
+    pub fn abort(&mut self, txn_id: &Uuid) -> bool {
+        if self.sending.as_ref() == Some(txn_id) || !self.contains(txn_id) {
+            return false;
+        }
+
+        for txn_id in self.remove_with_dependents(txn_id) {
+            self.notify(LocalEchoUpdate::Aborted(txn_id));
+        }
+
+        true
+    }
+
+    /// Replace the content of the event with the given transaction id.
+    ///
+    /// Returns false if the event isn't queued or if it's being sent right
+    /// now.
+    pub fn replace(&mut self, txn_id: &Uuid, content: AnyMessageEventContent) -> bool {
+        if self.sending.as_ref() == Some(txn_id) {
+            return false;
+        }
+
+        let event = match self.events.iter_mut().find(|e| &e.txn_id == txn_id) {
+            Some(e) => {
+                e.content = content;
+                e.clone()
+            }
+            None => return false,
+        };
+
+        self.notify(LocalEchoUpdate::Replaced(event));
+
+        true
+    }
+
+    /// Get the next event that should be sent and mark it as being sent.
+    ///
+    /// Returns `None` and marks the queue as stopped if it's empty.
+    pub fn next(&mut self) -> Option<QueuedEvent> {
+        let event = self.events.front().cloned();
+
+        self.sending = event.as_ref().map(|e| e.txn_id);
+        self.running = event.is_some();
+
+        event
+    }
+
+    /// Remove the event that was being sent from the queue after it was sent.
+    pub fn sent(&mut self, txn_id: Uuid, event_id: EventId) {
+        self.sending = None;
+        self.events.retain(|e| e.txn_id != txn_id);
+        self.notify(LocalEchoUpdate::Sent { txn_id, event_id });
+    }
+
+    /// Remove the event that was being sent, and the events that depend on
+    /// it, from the queue after sending it failed.
+    pub fn failed(&mut self, txn_id: Uuid, error: String) {
+        self.sending = None;
+
+        for removed in self.remove_with_dependents(&txn_id) {
+            if removed == txn_id {
+                self.notify(LocalEchoUpdate::SendingFailed { txn_id, error: error.clone() });
+            } else {
+                self.notify(LocalEchoUpdate::Aborted(removed));
+            }
+        }
+    }
+
+    /// Mark the queue as stopped after the task that sends out its events
+    /// ended without emptying the queue, e.g. because it was aborted.
+    ///
+    /// The event that was being sent is reported as failed, it might have
+    /// reached the homeserver nevertheless. The remaining events stay queued
+    /// and are sent once the queue is started again.
+    pub fn stopped(&mut self) {
+        if let Some(txn_id) = self.sending {
+            self.failed(txn_id, "sending the event was interrupted".to_owned());
+        }
+
+        self.running = false;
+    }
+
+    /// Remove all events from a queue that isn't running.
+    pub fn abort_all(&mut self) {
+        for event in std::mem::take(&mut self.events) {
+            self.notify(LocalEchoUpdate::Aborted(event.txn_id));
+        }
+    }
+
+    /// Remove the given event and all the events that depend on it, directly
+    /// or indirectly, returns the transaction ids of the removed events in
+    /// the order they were queued.
+    fn remove_with_dependents(&mut self, txn_id: &Uuid) -> Vec<Uuid> {
+        let mut removed = BTreeSet::new();
+        removed.insert(*txn_id);
+
+        // Events can only depend on events that were queued before them.
+        for event in &self.events {
+            if event.depends_on.map_or(false, |d| removed.contains(&d)) {
+                removed.insert(event.txn_id);
+            }
+        }
+
+        let removed_in_order =
+            self.events.iter().map(|e| e.txn_id).filter(|t| removed.contains(t)).collect();
+        self.events.retain(|e| !removed.contains(&e.txn_id));
+
+        removed_in_order
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::uuid::Uuid;
+    use ruma::{
+        event_id,
+        events::{
+            room::message::{MessageEventContent, MessageType},
+            AnyMessageEventContent,
+        },
+    };
+
+    use super::{LocalEchoUpdate, QueuedEvent, SendQueue};
+
+    fn event(body: &str, depends_on: Option<Uuid>) -> QueuedEvent {
+        QueuedEvent {
+            txn_id: Uuid::new_v4(),
+            content: AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(body)),
+            depends_on,
+        }
+    }
+
+    fn txn_ids(queue: &SendQueue) -> Vec<Uuid> {
+        queue.events().into_iter().map(|e| e.txn_id).collect()
+    }
+
+    #[test]
+    fn abort_removes_dependents() {
+        let mut queue = SendQueue::default();
+        let mut updates = queue.subscribe();
+
+        let original = event("Hello", None);
+        let edit = event("Hello world", Some(original.txn_id));
+        let edit_of_edit = event("Hello there", Some(edit.txn_id));
+        let other = event("Bye", None);
+
+        assert!(queue.push(original.clone()));
+        assert!(!queue.push(edit.clone()));
+        assert!(!queue.push(edit_of_edit.clone()));
+        assert!(!queue.push(other.clone()));
+
+        for _ in 0..4 {
+            assert!(matches!(updates.try_next(), Ok(Some(LocalEchoUpdate::Queued(_)))));
+        }
+
+        assert!(queue.abort(&edit.txn_id));
+        assert_eq!(txn_ids(&queue), vec![original.txn_id, other.txn_id]);
+
+        for txn_id in &[edit.txn_id, edit_of_edit.txn_id] {
+            match updates.try_next() {
+                Ok(Some(LocalEchoUpdate::Aborted(t))) => assert_eq!(&t, txn_id),
+                u => panic!("Unexpected local echo update {:?}", u),
+            }
+        }
+
+        assert!(!queue.abort(&edit.txn_id));
+    }
+
+    #[test]
+    fn events_in_flight_cant_be_changed() {
+        let mut queue = SendQueue::default();
+        let first = event("Hello", None);
+        let second = event("Bye", None);
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hi"));
+
+        queue.push(first.clone());
+        queue.push(second.clone());
+
+        assert_eq!(queue.next().unwrap().txn_id, first.txn_id);
+        assert!(!queue.abort(&first.txn_id));
+        assert!(!queue.replace(&first.txn_id, content.clone()));
+
+        assert!(queue.replace(&second.txn_id, content));
+        match &queue.events()[1].content {
+            AnyMessageEventContent::RoomMessage(c) => {
+                assert!(matches!(&c.msgtype, MessageType::Text(t) if t.body == "Hi"))
+            }
+            c => panic!("Unexpected content {:?}", c),
+        }
+
+        queue.sent(first.txn_id, event_id!("$h29iv0s8:example.com"));
+        assert_eq!(txn_ids(&queue), vec![second.txn_id]);
+
+        assert_eq!(queue.next().unwrap().txn_id, second.txn_id);
+        queue.sent(second.txn_id, event_id!("$h29iv0s9:example.com"));
+
+        // The queue stops once it's empty and needs to be started again.
+        assert!(queue.next().is_none());
+        assert!(queue.push(event("Again", None)));
+    }
+
+    #[test]
+    fn failures_abort_dependents() {
+        let mut queue = SendQueue::default();
+        let original = event("Hello", None);
+        let edit = event("Hello world", Some(original.txn_id));
+
+        queue.push(original.clone());
+        queue.push(edit.clone());
+        let mut updates = queue.subscribe();
+
+        queue.next();
+        queue.failed(original.txn_id, "Boom".to_owned());
+
+        assert!(queue.events().is_empty());
+        assert!(matches!(
+            updates.try_next(),
+            Ok(Some(LocalEchoUpdate::SendingFailed { txn_id, .. })) if txn_id == original.txn_id
+        ));
+        assert!(matches!(
+            updates.try_next(),
+            Ok(Some(LocalEchoUpdate::Aborted(txn_id))) if txn_id == edit.txn_id
+        ));
+    }
+
+    #[test]
+    fn stopped_task() {
+        let mut queue = SendQueue::default();
+        let first = event("Hello", None);
+        let second = event("Bye", None);
+
+        queue.push(first.clone());
+        queue.push(second.clone());
+        let mut updates = queue.subscribe();
+
+        // The task is aborted while it sends the first event.
+        queue.next();
+        queue.stopped();
+
+        assert!(!queue.is_running());
+        assert_eq!(txn_ids(&queue), vec![second.txn_id]);
+        assert!(matches!(
+            updates.try_next(),
+            Ok(Some(LocalEchoUpdate::SendingFailed { txn_id, .. })) if txn_id == first.txn_id
+        ));
+
+        // The remaining event is sent once the queue is started again.
+        assert!(queue.push(event("Again", None)));
+
+        queue.stopped();
+        queue.abort_all();
+        assert!(queue.is_empty());
+        assert!(matches!(updates.try_next(), Ok(Some(LocalEchoUpdate::Queued(_)))));
+        assert!(matches!(
+            updates.try_next(),
+            Ok(Some(LocalEchoUpdate::Aborted(txn_id))) if txn_id == second.txn_id
+        ));
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Background tasks of the client.
+//!
+//! Tasks that the client runs in the background, e.g. the local server of the
+//! SSO login, are spawned using
+//! [Client::spawn_task()](crate::Client::spawn_task) and can be inspected using
+//! [Client::tasks()](crate::Client::tasks). Every task runs inside a `sdk_task`
+//! tracing span that carries its name.
+//!
+//! The tasks are aborted when the client is shut down or once the last clone
+//! of the client is dropped. Tasks that need the client should be spawned
+//! using [Client::spawn_task_with_client()](crate::Client::spawn_task_with_client),
+//! the client they get doesn't count as a clone that keeps the tasks running.
+//!
+//! Every task is spawned on the executor by default, clients can share a
+//! [`TaskScheduler`] instead that spawns the tasks of all of them from a single
+//! task of the executor.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, abortable, AbortHandle},
+    StreamExt,
+};
+use matrix_sdk_common::{
+    executor::{spawn, BoxedFuture},
+    instant::Instant,
+};
+use tracing::{debug_span, warn, Instrument};
+
+use crate::Result;
+
+/// The number of tasks that are no longer running that are kept around so
+/// their outcome can be inspected.
+const MAX_FINISHED_TASKS: usize = 32;
+
+/// Super trait for the futures that can be spawned as a task, this trait will
+/// differ if it's used on WASM. WASM targets will not require the future to be
+/// `Send`, while other targets will.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait TaskFuture: Future<Output = Result<()>> + Send + 'static {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Future<Output = Result<()>> + Send + 'static> TaskFuture for T {}
+
+/// Super trait for the futures that can be spawned as a task, this trait will
+/// differ if it's used on WASM. WASM targets will not require the future to be
+/// `Send`, while other targets will.
+#[cfg(target_arch = "wasm32")]
+pub trait TaskFuture: Future<Output = Result<()>> + 'static {}
+#[cfg(target_arch = "wasm32")]
+impl<T: Future<Output = Result<()>> + 'static> TaskFuture for T {}
+
+/// The status of a background task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskStatus {
+    /// The task is still running.
+    Running,
+    /// The task returned successfully.
+    Finished,
+    /// The task returned an error.
+    Failed,
+    /// The task was aborted before it returned.
+    Aborted,
+}
+
+/// Information about a background task of the client.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    /// The name of the task.
+    pub name: String,
+    /// The status of the task.
+    pub status: TaskStatus,
+    /// The error the task returned, if it failed.
+    pub last_error: Option<String>,
+    /// When the task was spawned.
+    pub started_at: Instant,
+}
+
+/// Spawns the background tasks of many clients from a single task of the
+/// executor.
+///
+/// The tasks end up on the runtime the scheduler was started on, even if the
+/// clients spawn them from elsewhere. Every task still runs on its own, so a
+/// task that panics doesn't take the tasks of the other clients down with it.
+///
+/// The scheduler is shared between clients using
+/// [`ClientConfig::task_scheduler()`](crate::ClientConfig::task_scheduler),
+/// the [`ClientManager`](crate::ClientManager) shares one between all of its
+/// clients. The tasks are still aborted together with the client that spawned
+/// them, the scheduler itself stops once all of its clones are dropped.
+#[derive(Clone, Debug)]
+pub struct TaskScheduler {
+    sender: UnboundedSender<BoxedFuture<()>>,
+    receiver: Arc<StdMutex<Option<UnboundedReceiver<BoxedFuture<()>>>>>,
+}
+
+impl TaskScheduler {
+    /// Create a new scheduler.
+    ///
+    /// The task that spawns the scheduled tasks is spawned once the first
+    /// task is scheduled, so the scheduler can be created outside of an async
+    /// runtime.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        Self { sender, receiver: Arc::new(StdMutex::new(Some(receiver))) }
+    }
+
+    fn schedule(&self, task: BoxedFuture<()>) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            spawn(receiver.for_each(|task| {
+                spawn(task);
+                future::ready(())
+            }));
+        }
+
+        if let Err(e) = self.sender.unbounded_send(task) {
+            // The task of the scheduler is gone, e.g. because the runtime it
+            // was spawned on was shut down, spawn the task on its own instead.
+            spawn(e.into_inner());
+        }
+    }
+}
+
+impl Default for TaskScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct TaskEntry {
+    info: Arc<StdMutex<TaskInfo>>,
+    abort_handle: AbortHandle,
+}
+
+/// The tasks a client spawned.
+#[derive(Debug, Default)]
+pub(crate) struct TaskRegistry {
+    next_id: AtomicU64,
+    tasks: StdMutex<BTreeMap<u64, TaskEntry>>,
+    scheduler: Option<TaskScheduler>,
+}
+
+impl TaskRegistry {
+    /// Create a registry that runs its tasks on the given scheduler, or
+    /// spawns them on the executor if there is none.
+    pub(crate) fn new(scheduler: Option<TaskScheduler>) -> Self {
+        Self { scheduler, ..Default::default() }
+    }
+
+    /// Spawn the given future as a named background task.
+    pub(crate) fn spawn(self: &Arc<Self>, name: &str, future: impl TaskFuture) -> TaskHandle {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (future, abort_handle) = abortable(future);
+
+        let info = Arc::new(StdMutex::new(TaskInfo {
+            name: name.to_owned(),
+            status: TaskStatus::Running,
+            last_error: None,
+            started_at: Instant::now(),
+        }));
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert(id, TaskEntry { info: info.clone(), abort_handle: abort_handle.clone() });
+
+        let registry = Arc::downgrade(self);
+        let task_info = info.clone();
+
+        let task = async move {
+            let (status, error) = match future.await {
+                Ok(Ok(())) => (TaskStatus::Finished, None),
+                Ok(Err(e)) => (TaskStatus::Failed, Some(e.to_string())),
+                Err(_) => (TaskStatus::Aborted, None),
+            };
+
+            {
+                let mut info = task_info.lock().unwrap();
+
+                if let Some(e) = &error {
+                    warn!("The background task {} failed: {}", info.name, e);
+                }
+
+                info.status = status;
+                info.last_error = error;
+            }
+
+            if let Some(registry) = registry.upgrade() {
+                registry.prune_finished();
+            }
+        }
+        .instrument(debug_span!("sdk_task", name = name));
+
+        match &self.scheduler {
+            Some(scheduler) => scheduler.schedule(Box::pin(task)),
+            None => {
+                spawn(task);
+            }
+        }
+
+        TaskHandle { info, abort_handle }
+    }
+
+    /// Get information about the running tasks and the tasks that recently
+    /// stopped, ordered by the time they were spawned.
+    pub(crate) fn tasks(&self) -> Vec<TaskInfo> {
+        self.tasks.lock().unwrap().values().map(|t| t.info.lock().unwrap().clone()).collect()
+    }
+
+    /// Abort all the running tasks.
+    pub(crate) fn abort_all(&self) {
+        for task in self.tasks.lock().unwrap().values() {
+            task.abort_handle.abort();
+        }
+    }
+
+    /// Forget the oldest tasks that stopped if there are too many of them.
+    fn prune_finished(&self) {
+        let mut tasks = self.tasks.lock().unwrap();
+
+        let finished: Vec<u64> = tasks
+            .iter()
+            .filter(|(_, t)| t.info.lock().unwrap().status != TaskStatus::Running)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_TASKS)) {
+            tasks.remove(id);
+        }
+    }
+}
+
+/// Aborts the running tasks of a registry once it's dropped.
+///
+/// The guard is shared by the clones of the client that are handed out to
+/// users, but not by the clones the tasks themselves hold, otherwise a task
+/// that holds the client would never be aborted.
+#[derive(Debug)]
+pub(crate) struct TaskGuard {
+    registry: Arc<TaskRegistry>,
+}
+
+impl TaskGuard {
+    pub(crate) fn new(registry: Arc<TaskRegistry>) -> Self {
+        Self { registry }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.registry.abort_all();
+    }
+}
+
+/// A handle to a background task of the client.
+///
+/// Dropping the handle doesn't abort the task.
+#[derive(Clone, Debug)]
+pub struct TaskHandle {
+    info: Arc<StdMutex<TaskInfo>>,
+    abort_handle: AbortHandle,
+}
+
+impl TaskHandle {
+    /// Get information about the task, e.g. its current status.
+    pub fn info(&self) -> TaskInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    /// The name of the task.
+    pub fn name(&self) -> String {
+        self.info.lock().unwrap().name.clone()
+    }
+
+    /// The current status of the task.
+    pub fn status(&self) -> TaskStatus {
+        self.info.lock().unwrap().status
+    }
+
+    /// The error the task returned, if it failed.
+    pub fn last_error(&self) -> Option<String> {
+        self.info.lock().unwrap().last_error.clone()
+    }
+
+    /// Abort the task, nothing happens if it already stopped.
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client-side validation of events before they are sent.
+//!
+//! Homeservers reject events that exceed the limits of the spec with a fairly
+//! opaque error, e.g. a `413 Payload Too Large`. The events the client sends
+//! are checked against those limits beforehand, violations are reported as an
+//! [`Error::Validation`](crate::Error::Validation) that names the offending
+//! part of the event.
+
+use ruma::events::room::message::{FormattedBody, MessageEventContent, MessageType};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use thiserror::Error;
+
+use crate::Result;
+
+/// The maximal size of an event in bytes, including the fields the homeserver
+/// adds to it.
+pub const MAX_EVENT_SIZE: usize = 65_536;
+
+/// The maximal size of the serialized content of an event in bytes.
+///
+/// The content needs to stay below [`MAX_EVENT_SIZE`] by a margin, the
+/// homeserver adds the sender, the ids of the previous events, hashes and
+/// signatures to the event.
+pub const MAX_CONTENT_SIZE: usize = MAX_EVENT_SIZE - 4_096;
+
+/// The maximal size of the serialized content of a message in bytes that is
+/// sent to an encrypted room, before it's encrypted.
+///
+/// The content is encrypted together with the event type and the room ID, the
+/// ciphertext is base64 encoded which makes it grow by a third, and the
+/// encrypted content adds the keys and IDs of the session.
+pub const MAX_ENCRYPTED_CONTENT_SIZE: usize = (MAX_CONTENT_SIZE - 1_024) * 3 / 4 - 1_024;
+
+/// The maximal length of a state key in bytes.
+pub const MAX_STATE_KEY_LENGTH: usize = 255;
+
+/// The maximal length of an event type in bytes.
+pub const MAX_EVENT_TYPE_LENGTH: usize = 255;
+
+/// The text that is appended to a formatted body that was truncated.
+const TRUNCATION_MARKER: &str = "…";
+
+/// The ways an event can exceed the limits of the spec.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The serialized content of the event is too large.
+    #[error("the content of the event is {size} bytes large, the limit is {limit} bytes")]
+    ContentTooLarge {
+        /// The size of the serialized content in bytes.
+        size: usize,
+        /// The maximal size of the content in bytes.
+        limit: usize,
+        /// The top-level field of the content that takes up the most space,
+        /// if the content is a JSON object.
+        field: Option<String>,
+    },
+
+    /// The state key of the event is too long.
+    #[error("the state key of the event is {length} bytes long, the limit is {limit} bytes")]
+    StateKeyTooLong {
+        /// The length of the state key in bytes.
+        length: usize,
+        /// The maximal length of the state key in bytes.
+        limit: usize,
+    },
+
+    /// The type of the event is too long.
+    #[error("the event type is {length} bytes long, the limit is {limit} bytes")]
+    EventTypeTooLong {
+        /// The length of the event type in bytes.
+        length: usize,
+        /// The maximal length of the event type in bytes.
+        limit: usize,
+    },
+}
+
+/// Check that an event with the given type, state key and content doesn't
+/// exceed the limits of the spec.
+///
+/// # Arguments
+///
+/// * `event_type` - The type of the event.
+///
+/// * `state_key` - The state key of the event, `None` for message events.
+///
+/// * `content` - The content of the event.
+pub fn validate_event(
+    event_type: &str,
+    state_key: Option<&str>,
+    content: &impl Serialize,
+) -> Result<()> {
+    validate_event_with_limit(event_type, state_key, content, MAX_CONTENT_SIZE)
+}
+
+/// Check that a message with the given type and content doesn't exceed the
+/// limits of the spec once it's encrypted.
+///
+/// The content is checked against [`MAX_ENCRYPTED_CONTENT_SIZE`] before it's
+/// encrypted, so a message that is too large doesn't use up a message index
+/// of the group session.
+///
+/// # Arguments
+///
+/// * `event_type` - The type of the message.
+///
+/// * `content` - The content of the message, before it's encrypted.
+pub fn validate_encrypted_event(event_type: &str, content: &impl Serialize) -> Result<()> {
+    validate_event_with_limit(event_type, None, content, MAX_ENCRYPTED_CONTENT_SIZE)
+}
+
+fn validate_event_with_limit(
+    event_type: &str,
+    state_key: Option<&str>,
+    content: &impl Serialize,
+    limit: usize,
+) -> Result<()> {
+    if event_type.len() > MAX_EVENT_TYPE_LENGTH {
+        return Err(ValidationError::EventTypeTooLong {
+            length: event_type.len(),
+            limit: MAX_EVENT_TYPE_LENGTH,
+        }
+        .into());
+    }
+
+    if let Some(state_key) = state_key.filter(|k| k.len() > MAX_STATE_KEY_LENGTH) {
+        return Err(ValidationError::StateKeyTooLong {
+            length: state_key.len(),
+            limit: MAX_STATE_KEY_LENGTH,
+        }
+        .into());
+    }
+
+    let content = serde_json::to_value(content)?;
+    let size = serde_json::to_vec(&content)?.len();
+
+    if size > limit {
+        let field = content.as_object().and_then(|object| {
+            object
+                .iter()
+                .max_by_key(|(_, value)| serialized_size(value))
+                .map(|(key, _)| key.to_owned())
+        });
+
+        return Err(ValidationError::ContentTooLarge { size, limit, field }.into());
+    }
+
+    Ok(())
+}
+
+fn serialized_size(value: &JsonValue) -> usize {
+    serde_json::to_vec(value).map_or(0, |v| v.len())
+}
+
+fn formatted_body(msgtype: &mut MessageType) -> Option<&mut Option<FormattedBody>> {
+    match msgtype {
+        MessageType::Text(c) => Some(&mut c.formatted),
+        MessageType::Notice(c) => Some(&mut c.formatted),
+        MessageType::Emote(c) => Some(&mut c.formatted),
+        _ => None,
+    }
+}
+
+/// Shorten the formatted bodies of the message until its content fits into
+/// [`MAX_CONTENT_SIZE`], or into [`MAX_ENCRYPTED_CONTENT_SIZE`] if the message
+/// is going to be encrypted.
+///
+/// The plain text bodies are left alone, formatted bodies that would need to be
+/// cut down completely are removed. The content may still be too large
+/// afterwards if the plain text body alone exceeds the limit.
+pub(crate) fn truncate_formatted_bodies(
+    content: &mut MessageEventContent,
+    encrypted: bool,
+) -> Result<()> {
+    let limit = if encrypted { MAX_ENCRYPTED_CONTENT_SIZE } else { MAX_CONTENT_SIZE };
+
+    loop {
+        let size = serde_json::to_vec(&*content)?.len();
+
+        if size <= limit {
+            return Ok(());
+        }
+
+        let excess = size - limit;
+
+        let mut bodies = Vec::new();
+        bodies.extend(formatted_body(&mut content.msgtype));
+        if let Some(new_content) = &mut content.new_content {
+            bodies.extend(formatted_body(&mut new_content.msgtype));
+        }
+
+        let formatted = match bodies.into_iter().filter(|f| f.is_some()).max_by_key(|f| match f {
+            Some(f) => f.body.len(),
+            None => 0,
+        }) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        let body = &mut formatted.as_mut().expect("Only formatted bodies were kept").body;
+
+        if body.len() <= excess + TRUNCATION_MARKER.len() {
+            *formatted = None;
+        } else {
+            let len = body.len() - excess - TRUNCATION_MARKER.len();
+            truncate_html(body, len);
+        }
+    }
+}
+
+/// Truncate the HTML to at most `len` bytes without cutting a tag or an
+/// entity in half.
+///
+/// Elements that are cut off aren't closed, HTML parsers close them
+/// implicitly.
+fn truncate_html(html: &mut String, len: usize) {
+    let mut end = len;
+    while !html.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    for (start, stop) in [('<', '>'), ('&', ';')].iter() {
+        let kept = &html[..end];
+
+        if let Some(pos) = kept.rfind(*start) {
+            if !kept[pos..].contains(*stop) {
+                end = pos;
+            }
+        }
+    }
+
+    html.truncate(end);
+    html.push_str(TRUNCATION_MARKER);
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::events::room::message::MessageEventContent;
+    use serde_json::json;
+
+    use super::{
+        truncate_formatted_bodies, validate_encrypted_event, validate_event, ValidationError,
+        MAX_CONTENT_SIZE, MAX_ENCRYPTED_CONTENT_SIZE, MAX_STATE_KEY_LENGTH,
+    };
+    use crate::Error;
+
+    #[test]
+    fn event_validation() {
+        let content = json!({ "body": "Hello", "msgtype": "m.text" });
+        validate_event("m.room.message", None, &content).unwrap();
+
+        let content = json!({ "body": "a".repeat(MAX_CONTENT_SIZE), "msgtype": "m.text" });
+        assert!(matches!(
+            validate_event("m.room.message", None, &content),
+            Err(Error::Validation(ValidationError::ContentTooLarge { field: Some(f), .. }))
+                if f == "body"
+        ));
+
+        let state_key = "a".repeat(MAX_STATE_KEY_LENGTH + 1);
+        assert!(matches!(
+            validate_event("m.room.member", Some(&state_key), &json!({})),
+            Err(Error::Validation(ValidationError::StateKeyTooLong { .. }))
+        ));
+
+        let event_type = "a".repeat(256);
+        assert!(matches!(
+            validate_event(&event_type, None, &json!({})),
+            Err(Error::Validation(ValidationError::EventTypeTooLong { .. }))
+        ));
+
+        // Content that fits into an unencrypted event can still be too large
+        // once it's encrypted.
+        let content =
+            json!({ "body": "a".repeat(MAX_ENCRYPTED_CONTENT_SIZE), "msgtype": "m.text" });
+        validate_event("m.room.message", None, &content).unwrap();
+        assert!(matches!(
+            validate_encrypted_event("m.room.message", &content),
+            Err(Error::Validation(ValidationError::ContentTooLarge { limit, field: Some(f), .. }))
+                if f == "body" && limit == MAX_ENCRYPTED_CONTENT_SIZE
+        ));
+    }
+
+    #[test]
+    fn formatted_body_truncation() {
+        let html = "<b>Hello</b> &amp; welcome ".repeat(MAX_CONTENT_SIZE / 10);
+        let mut content = MessageEventContent::text_html("Hello & welcome", html);
+
+        truncate_formatted_bodies(&mut content, false).unwrap();
+        validate_event("m.room.message", None, &content).unwrap();
+
+        let json = serde_json::to_value(&content).unwrap();
+        let formatted = json["formatted_body"].as_str().unwrap();
+        let kept = formatted.strip_suffix('…').unwrap();
+        assert!(kept.rfind('<') < kept.rfind('>'));
+        assert!(kept.rfind('&') < kept.rfind(';'));
+        assert_eq!(json["body"], "Hello & welcome");
+
+        let mut content = MessageEventContent::text_html("a".repeat(MAX_CONTENT_SIZE), "<b>a</b>");
+        truncate_formatted_bodies(&mut content, false).unwrap();
+        assert!(serde_json::to_value(&content).unwrap().get("formatted_body").is_none());
+    }
+
+    #[test]
+    fn encrypted_formatted_body_truncation() {
+        let html = "<b>Hello</b> ".repeat(MAX_CONTENT_SIZE / 14);
+        let mut content = MessageEventContent::text_html("Hello", html.clone());
+
+        // The content fits into an unencrypted event.
+        truncate_formatted_bodies(&mut content, false).unwrap();
+        assert_eq!(serde_json::to_value(&content).unwrap()["formatted_body"], html);
+
+        // The encrypted content would grow past the limit.
+        truncate_formatted_bodies(&mut content, true).unwrap();
+        let size = serde_json::to_vec(&content).unwrap().len();
+        assert!(size <= MAX_ENCRYPTED_CONTENT_SIZE);
+
+        let formatted = serde_json::to_value(&content).unwrap()["formatted_body"].clone();
+        assert!(formatted.as_str().unwrap().ends_with('…'));
+
+        // The base64 encoded ciphertext of the content and the fields of the
+        // encrypted content still fit into the limit.
+        let ciphertext = (size + 1_024 + 2) / 3 * 4;
+        assert!(ciphertext + 1_024 <= MAX_CONTENT_SIZE);
+    }
+}
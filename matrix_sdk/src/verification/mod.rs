@@ -33,6 +33,7 @@ mod qrcode;
 mod requests;
 mod sas;
 
+pub use matrix_sdk_base::crypto::matrix_qrcode::TerminalStyle;
 pub use qrcode::QrVerification;
 pub use requests::VerificationRequest;
 pub use sas::SasVerification;
@@ -81,6 +82,14 @@ impl Verification {
         }
     }
 
+    /// Get the unique id that identifies this verification flow.
+    pub fn flow_id(&self) -> &str {
+        match self {
+            Verification::SasV1(v) => v.inner.flow_id().as_str(),
+            Verification::QrV1(v) => v.inner.flow_id().as_str(),
+        }
+    }
+
     /// Get our own user id.
     pub fn own_user_id(&self) -> &ruma::UserId {
         match self {
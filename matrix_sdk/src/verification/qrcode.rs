@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use matrix_sdk_base::crypto::{
-    matrix_qrcode::{qrcode::QrCode, EncodingError},
+    matrix_qrcode::{qrcode::QrCode, EncodingError, TerminalStyle},
     QrVerification as BaseQrVerification,
 };
 use ruma::UserId;
@@ -67,6 +67,15 @@ impl QrVerification {
         self.inner.to_bytes()
     }
 
+    /// Render the QR code that is representing this verification flow as text
+    /// that can be printed in a terminal, e.g. by CLI clients.
+    pub fn to_terminal_string(
+        &self,
+        style: TerminalStyle,
+    ) -> std::result::Result<String, EncodingError> {
+        self.inner.to_terminal_string(style)
+    }
+
     /// Confirm that the other side has scanned our QR code.
     pub async fn confirm(&self) -> Result<()> {
         if let Some(request) = self.inner.confirm_scanning() {
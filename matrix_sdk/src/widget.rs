@@ -0,0 +1,722 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The client side of the widget API.
+//!
+//! Widgets are web applications that are embedded into a room, e.g. using a
+//! webview, and talk to the client by passing JSON messages back and forth as
+//! described in [MSC2762]. A [WidgetDriver] implements the client side of this
+//! API for a single widget in a joined room, the messages are exchanged over a
+//! [WidgetTransport] that the client implements:
+//!
+//! * Messages the widget posted are passed to
+//! [WidgetDriver::handle_message()].
+//! * The capabilities the widget wants are fetched with
+//! [WidgetDriver::request_capabilities()], the ones the user approved are
+//! granted with [WidgetDriver::approve_capabilities()].
+//! * Room events are passed to [WidgetDriver::handle_room_event()], they are
+//! forwarded to the widget if it's allowed to receive them.
+//!
+//! The widget can send events on behalf of the user if it's allowed to send
+//! them, and it can get an OpenID token to verify the identity of the user if
+//! [WidgetDriver::set_openid_allowed()] allows it.
+//!
+//! [MSC2762]: https://github.com/matrix-org/matrix-doc/pull/2762
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex, RwLock as StdRwLock,
+    },
+};
+
+use futures::channel::oneshot;
+use matrix_sdk_common::{async_trait, executor, instant::Duration, uuid::Uuid, AsyncTraitDeps};
+use ruma::{
+    events::{AnyMessageEventContent, AnyStateEventContent, AnySyncRoomEvent, EventContent},
+    serde::Raw,
+    RoomId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value, Value};
+
+use crate::{room::Joined, Error, Result};
+
+/// The versions of the widget API the driver supports.
+pub const SUPPORTED_API_VERSIONS: &[&str] = &["0.0.1", "0.0.2", "org.matrix.msc2762"];
+
+/// How long the driver waits for the widget to answer a request by default.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SEND_EVENT_PREFIX: &str = "org.matrix.msc2762.send.event:";
+const RECEIVE_EVENT_PREFIX: &str = "org.matrix.msc2762.receive.event:";
+const SEND_STATE_EVENT_PREFIX: &str = "org.matrix.msc2762.send.state_event:";
+const RECEIVE_STATE_EVENT_PREFIX: &str = "org.matrix.msc2762.receive.state_event:";
+
+/// A capability a widget can request.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WidgetCapability {
+    /// Send message events of the given type.
+    SendEvent(String),
+    /// Receive message events of the given type.
+    ReceiveEvent(String),
+    /// Send state events of the given type, only with the given state key if
+    /// there is one.
+    SendStateEvent {
+        /// The type of the state events.
+        event_type: String,
+        /// The state key the capability is restricted to.
+        state_key: Option<String>,
+    },
+    /// Receive state events of the given type, only with the given state key
+    /// if there is one.
+    ReceiveStateEvent {
+        /// The type of the state events.
+        event_type: String,
+        /// The state key the capability is restricted to.
+        state_key: Option<String>,
+    },
+    /// Any other capability, e.g. `m.always_on_screen`.
+    Other(String),
+}
+
+impl WidgetCapability {
+    /// Does the capability allow sending the given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `state_key` - The state key of the event, if it's a state event.
+    pub fn allows_sending(&self, event_type: &str, state_key: Option<&str>) -> bool {
+        match (self, state_key) {
+            (Self::SendEvent(t), None) => t == event_type,
+            (Self::SendStateEvent { event_type: t, state_key: k }, Some(key)) => {
+                t == event_type && k.as_deref().map_or(true, |k| k == key)
+            }
+            _ => false,
+        }
+    }
+
+    /// Does the capability allow receiving the given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `state_key` - The state key of the event, if it's a state event.
+    pub fn allows_receiving(&self, event_type: &str, state_key: Option<&str>) -> bool {
+        match (self, state_key) {
+            (Self::ReceiveEvent(t), None) => t == event_type,
+            (Self::ReceiveStateEvent { event_type: t, state_key: k }, Some(key)) => {
+                t == event_type && k.as_deref().map_or(true, |k| k == key)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<&str> for WidgetCapability {
+    fn from(capability: &str) -> Self {
+        let state_event = |rest: &str| match rest.split_once('#') {
+            Some((t, k)) => (t.to_owned(), Some(k.to_owned())),
+            None => (rest.to_owned(), None),
+        };
+
+        if let Some(t) = capability.strip_prefix(SEND_EVENT_PREFIX) {
+            Self::SendEvent(t.to_owned())
+        } else if let Some(t) = capability.strip_prefix(RECEIVE_EVENT_PREFIX) {
+            Self::ReceiveEvent(t.to_owned())
+        } else if let Some(rest) = capability.strip_prefix(SEND_STATE_EVENT_PREFIX) {
+            let (event_type, state_key) = state_event(rest);
+            Self::SendStateEvent { event_type, state_key }
+        } else if let Some(rest) = capability.strip_prefix(RECEIVE_STATE_EVENT_PREFIX) {
+            let (event_type, state_key) = state_event(rest);
+            Self::ReceiveStateEvent { event_type, state_key }
+        } else {
+            Self::Other(capability.to_owned())
+        }
+    }
+}
+
+impl fmt::Display for WidgetCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state_event =
+            |f: &mut fmt::Formatter<'_>, prefix: &str, t: &str, k: &Option<String>| match k {
+                Some(k) => write!(f, "{}{}#{}", prefix, t, k),
+                None => write!(f, "{}{}", prefix, t),
+            };
+
+        match self {
+            Self::SendEvent(t) => write!(f, "{}{}", SEND_EVENT_PREFIX, t),
+            Self::ReceiveEvent(t) => write!(f, "{}{}", RECEIVE_EVENT_PREFIX, t),
+            Self::SendStateEvent { event_type, state_key } => {
+                state_event(f, SEND_STATE_EVENT_PREFIX, event_type, state_key)
+            }
+            Self::ReceiveStateEvent { event_type, state_key } => {
+                state_event(f, RECEIVE_STATE_EVENT_PREFIX, event_type, state_key)
+            }
+            Self::Other(c) => f.write_str(c),
+        }
+    }
+}
+
+/// The channel that the messages of the widget API are exchanged over, e.g.
+/// the `postMessage` API of a webview.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait WidgetTransport: AsyncTraitDeps {
+    /// Send the given JSON message to the widget.
+    async fn send(&self, message: String) -> Result<()>;
+}
+
+/// The direction of a widget API message.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+enum WidgetApi {
+    /// A request of the widget, answered by the client.
+    #[serde(rename = "fromWidget")]
+    FromWidget,
+    /// A request of the client, answered by the widget.
+    #[serde(rename = "toWidget")]
+    ToWidget,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WidgetMessage {
+    api: WidgetApi,
+    widget_id: String,
+    request_id: String,
+    action: String,
+    #[serde(default)]
+    data: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct SendEventRequest {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: Option<String>,
+    #[serde(default)]
+    content: Value,
+    room_id: Option<RoomId>,
+}
+
+#[derive(Deserialize)]
+struct CapabilitiesResponse {
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+/// The client side of the widget API for a single widget in a joined room.
+///
+/// Clones of the driver share the same state.
+#[derive(Clone, Debug)]
+pub struct WidgetDriver {
+    room: Joined,
+    widget_id: Arc<str>,
+    transport: Arc<dyn WidgetTransport>,
+    requested_capabilities: Arc<StdRwLock<Vec<WidgetCapability>>>,
+    capabilities: Arc<StdRwLock<BTreeSet<WidgetCapability>>>,
+    openid_allowed: Arc<AtomicBool>,
+    pending_requests: Arc<StdMutex<BTreeMap<String, oneshot::Sender<Value>>>>,
+    request_timeout: Duration,
+}
+
+impl WidgetDriver {
+    /// Create a new driver for a widget in the given room.
+    ///
+    /// The widget doesn't have any capabilities until they are approved.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room the widget is embedded in.
+    ///
+    /// * `widget_id` - The ID of the widget, i.e. the state key of its widget
+    /// state event.
+    ///
+    /// * `transport` - The channel to exchange messages with the widget.
+    pub fn new(room: Joined, widget_id: &str, transport: Arc<dyn WidgetTransport>) -> Self {
+        Self {
+            room,
+            widget_id: widget_id.into(),
+            transport,
+            requested_capabilities: Default::default(),
+            capabilities: Default::default(),
+            openid_allowed: Arc::new(AtomicBool::new(false)),
+            pending_requests: Default::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Set how long the driver waits for the widget to answer a request,
+    /// defaults to 10 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// The ID of the widget.
+    pub fn widget_id(&self) -> &str {
+        &self.widget_id
+    }
+
+    /// The room the widget is embedded in.
+    pub fn room(&self) -> &Joined {
+        &self.room
+    }
+
+    /// The capabilities that were approved for the widget.
+    pub fn capabilities(&self) -> Vec<WidgetCapability> {
+        self.capabilities.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Set whether the widget is allowed to get an OpenID token that
+    /// verifies the identity of the user, it isn't allowed by default.
+    pub fn set_openid_allowed(&self, allowed: bool) {
+        self.openid_allowed.store(allowed, Ordering::SeqCst);
+    }
+
+    /// Ask the widget which capabilities it wants.
+    ///
+    /// This waits until the widget answers, the capabilities should then be
+    /// presented to the user and the approved ones passed to
+    /// [approve_capabilities()](#method.approve_capabilities).
+    pub async fn request_capabilities(&self) -> Result<Vec<WidgetCapability>> {
+        let response = self.request("capabilities", json!({})).await?;
+        let response: CapabilitiesResponse = serde_json::from_value(response)?;
+
+        let requested: Vec<WidgetCapability> =
+            response.capabilities.iter().map(|c| c.as_str().into()).collect();
+        *self.requested_capabilities.write().unwrap() = requested.clone();
+
+        Ok(requested)
+    }
+
+    /// Grant the given capabilities to the widget and notify it about them.
+    ///
+    /// The approved capabilities replace the previously approved ones.
+    pub async fn approve_capabilities(
+        &self,
+        approved: impl IntoIterator<Item = WidgetCapability>,
+    ) -> Result<()> {
+        let approved: BTreeSet<WidgetCapability> = approved.into_iter().collect();
+
+        let requested: Vec<String> =
+            self.requested_capabilities.read().unwrap().iter().map(|c| c.to_string()).collect();
+        let data = json!({
+            "requested": requested,
+            "approved": approved.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+        });
+
+        *self.capabilities.write().unwrap() = approved;
+
+        self.post(
+            WidgetApi::ToWidget,
+            &Uuid::new_v4().to_string(),
+            "notify_capabilities",
+            data,
+            None,
+        )
+        .await
+    }
+
+    /// Forward the given event of the room to the widget, if the widget is
+    /// allowed to receive it.
+    ///
+    /// Returns `true` if the event was forwarded.
+    pub async fn handle_room_event(&self, event: &Raw<AnySyncRoomEvent>) -> Result<bool> {
+        let mut data: Value = serde_json::from_str(event.json().get())?;
+
+        let event_type = data.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+        let state_key = data.get("state_key").and_then(|k| k.as_str());
+
+        let allowed = self
+            .capabilities
+            .read()
+            .unwrap()
+            .iter()
+            .any(|c| c.allows_receiving(event_type, state_key));
+
+        if !allowed {
+            return Ok(false);
+        }
+
+        if let Some(object) = data.as_object_mut() {
+            object.insert("room_id".to_owned(), json!(self.room.room_id()));
+        }
+
+        self.post(WidgetApi::ToWidget, &Uuid::new_v4().to_string(), "send_event", data, None)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Handle a message the widget posted.
+    ///
+    /// Requests of the widget are answered over the transport, messages that
+    /// belong to a different widget are ignored.
+    pub async fn handle_message(&self, message: &str) -> Result<()> {
+        let message: WidgetMessage = serde_json::from_str(message)?;
+
+        if message.widget_id != *self.widget_id {
+            return Ok(());
+        }
+
+        match message.api {
+            WidgetApi::ToWidget => {
+                if let Some(response) = message.response {
+                    let sender = self.pending_requests.lock().unwrap().remove(&message.request_id);
+
+                    if let Some(sender) = sender {
+                        let _ = sender.send(response);
+                    }
+                }
+
+                Ok(())
+            }
+            WidgetApi::FromWidget => {
+                if message.response.is_some() {
+                    return Ok(());
+                }
+
+                let response =
+                    match self.handle_request(&message.action, message.data.clone()).await {
+                        Ok(r) => r,
+                        Err(e) => json!({ "error": { "message": e } }),
+                    };
+
+                self.post(
+                    WidgetApi::FromWidget,
+                    &message.request_id,
+                    &message.action,
+                    message.data,
+                    Some(response),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Answer a request of the widget, an error is sent back to the widget
+    /// as its message.
+    async fn handle_request(
+        &self,
+        action: &str,
+        data: Value,
+    ) -> std::result::Result<Value, String> {
+        match action {
+            "supported_api_versions" => Ok(json!({ "supported_versions": SUPPORTED_API_VERSIONS })),
+            "content_loaded" => Ok(json!({})),
+            "send_event" => {
+                let request: SendEventRequest =
+                    serde_json::from_value(data).map_err(|e| e.to_string())?;
+                self.send_event(request).await
+            }
+            "get_openid" => self.get_openid().await,
+            _ => Err(format!("Unknown or unsupported action {}", action)),
+        }
+    }
+
+    async fn send_event(&self, request: SendEventRequest) -> std::result::Result<Value, String> {
+        if request.room_id.as_ref().map_or(false, |r| r != self.room.room_id()) {
+            return Err("The widget can only send events to its own room".to_owned());
+        }
+
+        let allowed = self
+            .capabilities
+            .read()
+            .unwrap()
+            .iter()
+            .any(|c| c.allows_sending(&request.event_type, request.state_key.as_deref()));
+
+        if !allowed {
+            return Err(format!("The widget isn't allowed to send {} events", request.event_type));
+        }
+
+        let content = to_raw_value(&request.content).map_err(|e| e.to_string())?;
+
+        let event_id = if let Some(state_key) = &request.state_key {
+            let content = AnyStateEventContent::from_parts(&request.event_type, &content)
+                .map_err(|e| e.to_string())?;
+            self.room
+                .send_state_event(content, state_key)
+                .await
+                .map_err(|e| e.to_string())?
+                .event_id
+        } else {
+            let content = AnyMessageEventContent::from_parts(&request.event_type, &content)
+                .map_err(|e| e.to_string())?;
+            self.room.send(content, None).await.map_err(|e| e.to_string())?.event_id
+        };
+
+        Ok(json!({ "room_id": self.room.room_id(), "event_id": event_id }))
+    }
+
+    async fn get_openid(&self) -> std::result::Result<Value, String> {
+        if !self.openid_allowed.load(Ordering::SeqCst) {
+            return Ok(json!({ "state": "blocked" }));
+        }
+
+        let token = self.room.client.get_openid_token().await.map_err(|e| e.to_string())?;
+
+        Ok(json!({
+            "state": "allowed",
+            "access_token": token.access_token,
+            "token_type": token.token_type,
+            "matrix_server_name": token.matrix_server_name,
+            "expires_in": token.expires_in.as_secs(),
+        }))
+    }
+
+    /// Send a request to the widget and wait for its response.
+    async fn request(&self, action: &str, data: Value) -> Result<Value> {
+        let request_id = Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending_requests.lock().unwrap().insert(request_id.clone(), sender);
+
+        if let Err(e) = self.post(WidgetApi::ToWidget, &request_id, action, data, None).await {
+            self.pending_requests.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
+
+        let response = match executor::timeout(self.request_timeout, receiver).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => {
+                return Err(Error::InvalidWidgetResponse(format!(
+                    "the {} request was dropped",
+                    action
+                )))
+            }
+            Err(_) => {
+                self.pending_requests.lock().unwrap().remove(&request_id);
+
+                return Err(Error::InvalidWidgetResponse(format!(
+                    "the {} request timed out",
+                    action
+                )));
+            }
+        };
+
+        match response.get("error").and_then(|e| e.get("message")).and_then(|m| m.as_str()) {
+            Some(message) => Err(Error::InvalidWidgetResponse(message.to_owned())),
+            None => Ok(response),
+        }
+    }
+
+    async fn post(
+        &self,
+        api: WidgetApi,
+        request_id: &str,
+        action: &str,
+        data: Value,
+        response: Option<Value>,
+    ) -> Result<()> {
+        let message = WidgetMessage {
+            api,
+            widget_id: self.widget_id.to_string(),
+            request_id: request_id.to_owned(),
+            action: action.to_owned(),
+            data,
+            response,
+        };
+
+        self.transport.send(serde_json::to_string(&message)?).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Mutex as StdMutex, time::Duration};
+
+    use matrix_sdk_common::async_trait;
+    use matrix_sdk_test::test_json;
+    use mockito::{mock, Matcher};
+    use ruma::{events::AnySyncRoomEvent, room_id, serde::Raw, user_id};
+    use serde_json::{json, Value};
+
+    use super::{Arc, WidgetCapability, WidgetDriver, WidgetTransport};
+    use crate::{room::Joined, Client, Session, SyncSettings};
+
+    #[derive(Debug, Default)]
+    struct Transport(StdMutex<Vec<Value>>);
+
+    impl Transport {
+        fn take(&self) -> Vec<Value> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    #[async_trait]
+    impl WidgetTransport for Transport {
+        async fn send(&self, message: String) -> crate::Result<()> {
+            self.0.lock().unwrap().push(serde_json::from_str(&message).unwrap());
+            Ok(())
+        }
+    }
+
+    async fn joined_room() -> Joined {
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+        };
+        let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap()
+    }
+
+    #[test]
+    fn capabilities() {
+        let capabilities = [
+            "org.matrix.msc2762.send.event:m.room.message",
+            "org.matrix.msc2762.receive.event:m.reaction",
+            "org.matrix.msc2762.send.state_event:m.room.topic",
+            "org.matrix.msc2762.receive.state_event:m.room.member#@alice:example.org",
+            "m.always_on_screen",
+        ];
+
+        for capability in capabilities.iter() {
+            assert_eq!(WidgetCapability::from(*capability).to_string(), *capability);
+        }
+
+        let send = WidgetCapability::from(capabilities[0]);
+        assert!(send.allows_sending("m.room.message", None));
+        assert!(!send.allows_sending("m.room.message", Some("")));
+        assert!(!send.allows_receiving("m.room.message", None));
+
+        let topic = WidgetCapability::from(capabilities[2]);
+        assert!(topic.allows_sending("m.room.topic", Some("")));
+        assert!(!topic.allows_sending("m.room.topic", None));
+
+        let member = WidgetCapability::from(capabilities[3]);
+        assert!(member.allows_receiving("m.room.member", Some("@alice:example.org")));
+        assert!(!member.allows_receiving("m.room.member", Some("@bob:example.org")));
+    }
+
+    #[tokio::test]
+    async fn widget_driver() {
+        let transport = Arc::new(Transport::default());
+        let driver = WidgetDriver::new(joined_room().await, "widget", transport.clone());
+
+        // The widget answers the capabilities request while the driver waits
+        // for it.
+        let (requested, handled) = futures::join!(driver.request_capabilities(), async {
+            let mut request = transport.take().pop().unwrap();
+            assert_eq!(request["action"], "capabilities");
+
+            request["response"] = json!({
+                "capabilities": [
+                    "org.matrix.msc2762.send.event:m.room.message",
+                    "org.matrix.msc2762.receive.event:m.room.message",
+                ]
+            });
+            driver.handle_message(&request.to_string()).await
+        });
+        handled.unwrap();
+
+        let requested = requested.unwrap();
+        assert_eq!(
+            requested,
+            vec![
+                WidgetCapability::SendEvent("m.room.message".to_owned()),
+                WidgetCapability::ReceiveEvent("m.room.message".to_owned()),
+            ]
+        );
+
+        driver.approve_capabilities(requested).await.unwrap();
+        assert_eq!(transport.take()[0]["action"], "notify_capabilities");
+
+        let request = |action: &str, data: Value| {
+            json!({
+                "api": "fromWidget",
+                "widgetId": "widget",
+                "requestId": "1",
+                "action": action,
+                "data": data,
+            })
+            .to_string()
+        };
+
+        let send = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::EVENT_ID.to_string())
+            .expect(1)
+            .create();
+
+        let message = json!({
+            "type": "m.room.message",
+            "content": { "msgtype": "m.text", "body": "Hello from the widget" },
+        });
+        driver.handle_message(&request("send_event", message)).await.unwrap();
+        send.assert();
+        assert_eq!(transport.take()[0]["response"]["event_id"], "$h29iv0s8:example.com");
+
+        let topic = json!({ "type": "m.room.topic", "state_key": "", "content": { "topic": "" } });
+        driver.handle_message(&request("send_event", topic)).await.unwrap();
+        assert!(transport.take()[0]["response"]["error"]["message"].is_string());
+
+        driver.handle_message(&request("get_openid", json!({}))).await.unwrap();
+        assert_eq!(transport.take()[0]["response"]["state"], "blocked");
+
+        let event = |event_type: &str| -> Raw<AnySyncRoomEvent> {
+            serde_json::from_value(json!({
+                "type": event_type,
+                "event_id": "$event:localhost",
+                "sender": "@example:localhost",
+                "origin_server_ts": 0,
+                "content": {},
+            }))
+            .unwrap()
+        };
+
+        assert!(driver.handle_room_event(&event("m.room.message")).await.unwrap());
+        assert_eq!(transport.take()[0]["data"]["room_id"], "!SVkFJHzfwvuaIEawgC:localhost");
+        assert!(!driver.handle_room_event(&event("m.reaction")).await.unwrap());
+        assert!(transport.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_time_out() {
+        let transport = Arc::new(Transport::default());
+        let driver = WidgetDriver::new(joined_room().await, "widget", transport.clone())
+            .request_timeout(Duration::from_millis(10));
+
+        // The widget never answers.
+        let error = driver.request_capabilities().await.unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+        assert!(driver.pending_requests.lock().unwrap().is_empty());
+
+        // A late answer is ignored.
+        let mut request = transport.take().pop().unwrap();
+        request["response"] = json!({ "capabilities": [] });
+        driver.handle_message(&request.to_string()).await.unwrap();
+    }
+}
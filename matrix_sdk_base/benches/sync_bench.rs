@@ -0,0 +1,139 @@
+use criterion::*;
+use matrix_sdk_base::{BaseClient, BaseClientConfig, Session};
+use matrix_sdk_test::response_from_file;
+use ruma::{
+    api::{client::r0::sync::sync_events::Response as SyncResponse, IncomingResponse},
+    user_id, UserId,
+};
+use serde_json::{json, Value};
+use tokio::runtime::{Builder, Runtime};
+
+const ROOM_COUNT: usize = 500;
+const MESSAGES_PER_ROOM: usize = 10;
+
+fn alice_id() -> UserId {
+    user_id!("@alice:example.org")
+}
+
+fn state_event(
+    room: usize,
+    event: usize,
+    event_type: &str,
+    state_key: &str,
+    content: Value,
+) -> Value {
+    json!({
+        "content": content,
+        "event_id": format!("$state{}_{}:example.org", room, event),
+        "origin_server_ts": 1_600_000_000_000u64 + event as u64,
+        "sender": alice_id(),
+        "state_key": state_key,
+        "type": event_type,
+        "unsigned": { "age": 1234 }
+    })
+}
+
+/// Create a sync response that looks like the initial sync of an account that
+/// is part of a lot of rooms.
+fn initial_sync_response() -> SyncResponse {
+    let rooms: serde_json::Map<String, Value> = (0..ROOM_COUNT)
+        .map(|i| {
+            let state = vec![
+                state_event(i, 0, "m.room.create", "", json!({ "creator": alice_id() })),
+                state_event(
+                    i,
+                    1,
+                    "m.room.member",
+                    alice_id().as_str(),
+                    json!({ "membership": "join", "displayname": "Alice" }),
+                ),
+                state_event(
+                    i,
+                    2,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { alice_id(): 100 } }),
+                ),
+                state_event(i, 3, "m.room.name", "", json!({ "name": format!("Room {}", i) })),
+            ];
+
+            let timeline: Vec<Value> = (0..MESSAGES_PER_ROOM)
+                .map(|j| {
+                    json!({
+                        "content": { "body": format!("Message {}", j), "msgtype": "m.text" },
+                        "event_id": format!("$message{}_{}:example.org", i, j),
+                        "origin_server_ts": 1_600_000_001_000u64 + j as u64,
+                        "sender": alice_id(),
+                        "type": "m.room.message",
+                        "unsigned": { "age": 1234 }
+                    })
+                })
+                .collect();
+
+            let room = json!({
+                "summary": { "m.joined_member_count": 1, "m.invited_member_count": 0 },
+                "state": { "events": state },
+                "timeline": { "events": timeline, "limited": false, "prev_batch": "t392" },
+                "ephemeral": { "events": [] },
+                "account_data": { "events": [] },
+                "unread_notifications": { "highlight_count": 0, "notification_count": 0 }
+            });
+
+            (format!("!room{}:example.org", i), room)
+        })
+        .collect();
+
+    let response = json!({
+        "next_batch": "s526_47314_0_7_1_1_1_11444_1",
+        "rooms": { "join": rooms, "invite": {}, "leave": {} },
+        "account_data": { "events": [] },
+        "presence": { "events": [] },
+        "to_device": { "events": [] },
+        "device_lists": { "changed": [], "left": [] },
+        "device_one_time_keys_count": {}
+    });
+
+    SyncResponse::try_from_http_response(response_from_file(&response))
+        .expect("Can't parse the sync response")
+}
+
+fn logged_in_client(runtime: &Runtime, room_concurrency: usize) -> BaseClient {
+    let config = BaseClientConfig::new().sync_room_concurrency(room_concurrency);
+    let client = BaseClient::new_with_config(config).unwrap();
+    let session = Session {
+        access_token: "1234".to_owned(),
+        user_id: alice_id(),
+        device_id: "JLAFKJWSCS".into(),
+    };
+
+    runtime.block_on(client.restore_login(session)).unwrap();
+
+    client
+}
+
+pub fn initial_sync(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+
+    let mut group = c.benchmark_group("Initial sync");
+    group.throughput(Throughput::Elements(ROOM_COUNT as u64));
+    group.sample_size(10);
+
+    for &room_concurrency in &[1, 8] {
+        let name = format!("{} rooms, {} rooms at a time", ROOM_COUNT, room_concurrency);
+
+        group.bench_function(BenchmarkId::new("receive_sync_response", name), |b| {
+            b.iter_batched(
+                || (logged_in_client(&runtime, room_concurrency), initial_sync_response()),
+                |(client, response)| {
+                    runtime.block_on(client.receive_sync_response(response, None)).unwrap();
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish()
+}
+
+criterion_group!(benches, initial_sync);
+criterion_main!(benches);
@@ -13,8 +13,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "encryption")]
+use std::time::Duration;
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     convert::TryFrom,
     fmt,
     path::{Path, PathBuf},
@@ -22,28 +24,30 @@ use std::{
     sync::Arc,
 };
 
+use futures::stream::{self, StreamExt};
+#[cfg(feature = "encryption")]
+use matrix_sdk_common::uuid::Uuid;
 use matrix_sdk_common::{
     deserialized_responses::{
-        AmbiguityChanges, JoinedRoom, LeftRoom, MemberEvent, MembersResponse, Rooms,
-        StrippedMemberEvent, SyncResponse, SyncRoomEvent, Timeline,
+        AmbiguityChanges, EventContext, JoinedRoom, LeftRoom, MemberEvent, MembersResponse, Rooms,
+        StrippedMemberEvent, SyncResponse, SyncRoomEvent, Timeline, ToDevice,
     },
+    executor::spawn,
     instant::Instant,
-    locks::RwLock,
+    locks::{Mutex, RwLock},
 };
 #[cfg(feature = "encryption")]
-use matrix_sdk_common::{locks::Mutex, uuid::Uuid};
-#[cfg(feature = "encryption")]
 use matrix_sdk_crypto::{
-    store::{CryptoStore, CryptoStoreError},
-    Device, EncryptionSettings, IncomingResponse, MegolmError, OlmError, OlmMachine,
-    OutgoingRequest, ToDeviceRequest, UserDevices,
+    store::{CryptoStore, CryptoStoreError, SyncCheckpoint},
+    Device, EncryptionSettings, IncomingResponse, MaintenanceSettings, MaintenanceSummary,
+    MegolmError, OlmError, OlmMachine, OutgoingRequest, ToDeviceRequest, UserDevices,
 };
 #[cfg(feature = "encryption")]
 use ruma::{
     api::client::r0::keys::claim_keys::Request as KeysClaimRequest,
     events::{
         room::{encrypted::EncryptedEventContent, history_visibility::HistoryVisibility},
-        AnyMessageEventContent, AnySyncMessageEvent,
+        AnyMessageEventContent, AnySyncMessageEvent, SyncMessageEvent,
     },
     DeviceId,
 };
@@ -51,17 +55,23 @@ use ruma::{
     api::client::r0::{self as api, push::get_notifications::Notification},
     events::{
         room::member::{MemberEventContent, MembershipState},
-        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyRoomEvent, AnyStrippedStateEvent,
         AnySyncEphemeralRoomEvent, AnySyncRoomEvent, AnySyncStateEvent, EventContent, EventType,
         StateEvent,
     },
     push::{Action, PushConditionRoomCtx, Ruleset},
     serde::Raw,
-    MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
 };
-use tracing::{info, warn};
+#[cfg(feature = "metrics")]
+use tracing::{debug, instrument};
+use tracing::{info, warn, Instrument};
 use zeroize::Zeroizing;
 
+#[cfg(feature = "passphrase_strength")]
+use crate::error::Error;
+#[cfg(feature = "metrics")]
+use crate::metrics::{DecryptionMetrics, MetricsExporter, SyncMetrics};
 use crate::{
     error::Result,
     rooms::{Room, RoomInfo, RoomType},
@@ -71,6 +81,20 @@ use crate::{
 
 pub type Token = String;
 
+/// Create a span for a stage of the sync processing.
+///
+/// The span is only recorded if the `metrics` feature is enabled, otherwise
+/// a disabled span is returned.
+macro_rules! sync_span {
+    ($name:literal $(, $($fields:tt)*)?) => {{
+        #[cfg(feature = "metrics")]
+        let span = tracing::info_span!($name $(, $($fields)*)?);
+        #[cfg(not(feature = "metrics"))]
+        let span = tracing::Span::none();
+        span
+    }};
+}
+
 /// A deserialization wrapper for extracting the prev_content field when
 /// found in an `unsigned` field.
 ///
@@ -89,6 +113,44 @@ pub struct AdditionalUnsignedData {
     pub prev_content: Option<Raw<MemberEventContent>>,
 }
 
+/// The fields every room event has.
+///
+/// Deserializing only those is a lot cheaper than deserializing the whole
+/// event, which lets us skip the content of events we don't need to look at.
+#[derive(serde::Deserialize)]
+struct EventHeader {
+    event_id: EventId,
+    sender: UserId,
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: Option<String>,
+    #[serde(default)]
+    unsigned: UnsignedHeader,
+}
+
+/// The `unsigned` fields of a room event that the state machine looks at.
+#[derive(Default, serde::Deserialize)]
+struct UnsignedHeader {
+    /// The transaction id of the event, only present for events that were
+    /// sent by this device.
+    transaction_id: Option<String>,
+}
+
+impl EventHeader {
+    fn is_state(&self) -> bool {
+        self.state_key.is_some()
+    }
+
+    /// Does the state machine need to look at the content of the event, this
+    /// is the case for state events and for encrypted events, which need to
+    /// be decrypted.
+    fn needs_deserialization(&self) -> bool {
+        self.is_state()
+            || (cfg!(feature = "encryption")
+                && self.event_type == EventType::RoomEncrypted.as_str())
+    }
+}
+
 /// Transform state event by hoisting `prev_content` field from `unsigned` to
 /// the top level.
 ///
@@ -152,6 +214,38 @@ fn hoist_room_event_prev_content(
     Ok(ev)
 }
 
+/// Copy the relation of an encrypted event over to the decrypted event.
+///
+/// The `m.relates_to` field of encrypted events is sent in the clear, it's not
+/// part of the decrypted content. Copying it makes sure that the relations of
+/// encrypted events can be aggregated.
+#[cfg(feature = "encryption")]
+fn copy_relation(
+    encrypted: &SyncMessageEvent<EncryptedEventContent>,
+    mut decrypted: SyncRoomEvent,
+) -> SyncRoomEvent {
+    let relates_to = match &encrypted.content.relates_to {
+        Some(r) => r,
+        None => return decrypted,
+    };
+
+    if let Ok(mut json) = serde_json::from_str::<serde_json::Value>(decrypted.event.json().get()) {
+        if let Some(content) = json.get_mut("content").and_then(|c| c.as_object_mut()) {
+            if !content.contains_key("m.relates_to") {
+                if let Ok(relates_to) = serde_json::to_value(relates_to) {
+                    content.insert("m.relates_to".to_owned(), relates_to);
+                }
+
+                if let Ok(json) = serde_json::value::to_raw_value(&json) {
+                    decrypted.event = Raw::from_json(json);
+                }
+            }
+        }
+    }
+
+    decrypted
+}
+
 /// A no IO Client implementation.
 ///
 /// This Client is a state machine that receives responses and events and
@@ -169,8 +263,40 @@ pub struct BaseClient {
     olm: Arc<Mutex<Option<OlmMachine>>>,
     #[cfg(feature = "encryption")]
     cryptostore: Arc<Mutex<Option<Box<dyn CryptoStore>>>>,
+    /// The rooms that were recently active, ordered from the most recently
+    /// used to the least recently used one.
+    active_rooms: Arc<Mutex<VecDeque<RoomId>>>,
+    max_active_rooms: Option<usize>,
+    #[cfg(feature = "encryption")]
+    one_time_key_target: Option<u64>,
+    #[cfg(feature = "encryption")]
+    fallback_key_lifetime: Option<Duration>,
+    #[cfg(feature = "encryption")]
+    crypto_maintenance: Option<MaintenanceSettings>,
+    #[cfg(feature = "passphrase_strength")]
+    min_passphrase_score: Option<u8>,
     store_path: Arc<Option<PathBuf>>,
     store_passphrase: Arc<Option<Zeroizing<String>>>,
+    /// The number of joined rooms of a sync response that are processed
+    /// concurrently.
+    room_concurrency: usize,
+    #[cfg(feature = "metrics")]
+    metrics_exporter: Option<Arc<dyn MetricsExporter>>,
+    #[cfg(feature = "metrics")]
+    decryption_metrics: Arc<DecryptionMetrics>,
+}
+
+/// The number of joined rooms that are processed concurrently by default.
+const DEFAULT_ROOM_CONCURRENCY: usize = 8;
+
+/// The result of processing a single joined room of a sync response.
+struct JoinedRoomUpdate {
+    room_id: RoomId,
+    room: JoinedRoom,
+    changes: StateChanges,
+    ambiguity_cache: AmbiguityCache,
+    /// Did the room receive any timeline events.
+    active: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -198,8 +324,23 @@ impl fmt::Debug for BaseClient {
 pub struct BaseClientConfig {
     #[cfg(feature = "encryption")]
     crypto_store: Option<Box<dyn CryptoStore>>,
+    max_active_rooms: Option<usize>,
+    #[cfg(feature = "encryption")]
+    one_time_key_target: Option<u64>,
+    #[cfg(feature = "encryption")]
+    fallback_key_lifetime: Option<Duration>,
+    #[cfg(feature = "encryption")]
+    crypto_maintenance: Option<MaintenanceSettings>,
+    #[cfg(feature = "passphrase_strength")]
+    min_passphrase_score: Option<u8>,
     store_path: Option<PathBuf>,
     passphrase: Option<Zeroizing<String>>,
+    state_cache_size: Option<usize>,
+    room_concurrency: Option<usize>,
+    #[cfg(feature = "sled_state_store")]
+    full_integrity_check: bool,
+    #[cfg(feature = "metrics")]
+    metrics_exporter: Option<Arc<dyn MetricsExporter>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -252,6 +393,163 @@ impl BaseClientConfig {
         self.passphrase = Some(Zeroizing::new(passphrase));
         self
     }
+
+    /// Require the passphrase of the stores to have a minimum strength.
+    ///
+    /// The strength of the passphrase is estimated using
+    /// [`estimate_passphrase_strength()`](crate::crypto::estimate_passphrase_strength)
+    /// when the client is created, creating the client fails with
+    /// [`Error::WeakPassphrase`](crate::Error::WeakPassphrase) if the
+    /// passphrase is weaker. The same minimum applies to the passphrases of
+    /// key exports.
+    ///
+    /// # Arguments
+    ///
+    /// * `score` - The minimum score of the passphrase, from `0` to `4`.
+    #[cfg(feature = "passphrase_strength")]
+    #[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+    pub fn min_passphrase_score(mut self, score: u8) -> Self {
+        self.min_passphrase_score = Some(score);
+        self
+    }
+
+    /// Set the number of entries the in-memory cache of the state store should
+    /// hold.
+    ///
+    /// Room members, their profiles and room state events like the power
+    /// levels are cached after they have been loaded from the state store,
+    /// the cache is kept up to date with the changes every sync brings. Each
+    /// of those item types gets a cache of the given size, a size of 0
+    /// disables the cache.
+    ///
+    /// Defaults to 500 entries.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The maximal number of entries per cached item type.
+    pub fn state_cache_size(mut self, size: usize) -> Self {
+        self.state_cache_size = Some(size);
+        self
+    }
+
+    /// Check every entry of the state store when it's opened and remove the
+    /// ones that can't be deserialized anymore.
+    ///
+    /// This reads the whole store, so it should only be enabled if the store
+    /// is suspected to be corrupted, e.g. after a crash. The trees that sled
+    /// reports as corrupted are only reported, never cleared. The lost state
+    /// can be inspected using [`Store::integrity_report()`] and is fetched
+    /// again by the next sync. Disabled by default.
+    #[cfg(feature = "sled_state_store")]
+    pub fn full_integrity_check(mut self, check: bool) -> Self {
+        self.full_integrity_check = check;
+        self
+    }
+
+    /// Set the number of joined rooms of a sync response that should be
+    /// processed concurrently.
+    ///
+    /// Rooms are independent of each other, processing many of them at once
+    /// speeds up the initial sync of accounts that are part of a lot of rooms.
+    ///
+    /// Defaults to 8 rooms.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_concurrency` - The maximal number of rooms that are processed at
+    /// the same time, a value of 1 processes the rooms one after the other.
+    pub fn sync_room_concurrency(mut self, room_concurrency: usize) -> Self {
+        self.room_concurrency = Some(room_concurrency.max(1));
+        self
+    }
+
+    /// Set a hook that receives metrics about every sync response the client
+    /// processes.
+    ///
+    /// The metrics are logged at the debug level if no exporter is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `exporter` - The exporter that should receive the metrics.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+    pub fn metrics_exporter(mut self, exporter: Arc<dyn MetricsExporter>) -> Self {
+        self.metrics_exporter = Some(exporter);
+        self
+    }
+
+    /// Set the maximal number of rooms that should keep their state cached in
+    /// memory.
+    ///
+    /// Rooms that didn't receive or send any messages for a while will be
+    /// unloaded once more than the given number of rooms are active, their
+    /// state will be lazily reloaded from the store once they become active
+    /// again. This keeps the memory footprint of long running clients that
+    /// are part of many rooms small.
+    ///
+    /// By default no rooms will be unloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_active_rooms` - The number of rooms that should stay loaded.
+    pub fn max_active_rooms(mut self, max_active_rooms: usize) -> Self {
+        self.max_active_rooms = Some(max_active_rooms);
+        self
+    }
+
+    /// Set the number of one-time keys that should be kept uploaded to the
+    /// server.
+    ///
+    /// Other devices need one of our one-time keys to start an Olm session
+    /// with us, a lower target reduces the size of key uploads at the cost of
+    /// running out of keys sooner while the device is offline. The default is
+    /// also the maximum, half the number of keys an Olm account can hold.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The number of one-time keys that should be on the server.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub fn one_time_key_target(mut self, target: u64) -> Self {
+        self.one_time_key_target = Some(target);
+        self
+    }
+
+    /// Set how long a fallback key is used before it's replaced with a new
+    /// one.
+    ///
+    /// Other devices use the fallback key to start an Olm session with us once
+    /// our one-time keys ran out. It can be used multiple times, replacing it
+    /// regularly limits how long a compromise of the key matters. The default
+    /// is a week. Servers that report which fallback keys are unused get a
+    /// new fallback key once the current one was used instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `lifetime` - How long a fallback key is used.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub fn fallback_key_lifetime(mut self, lifetime: Duration) -> Self {
+        self.fallback_key_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Enable the periodic maintenance of the crypto store, which prunes old
+    /// Olm message hashes and Olm sessions that weren't used for a long time.
+    ///
+    /// The maintenance runs at most once per configured interval, whenever
+    /// [`BaseClient::run_crypto_maintenance_if_due()`] is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The interval and the retention periods of the
+    /// maintenance.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub fn crypto_maintenance(mut self, settings: MaintenanceSettings) -> Self {
+        self.crypto_maintenance = Some(settings);
+        self
+    }
 }
 
 impl BaseClient {
@@ -267,6 +565,15 @@ impl BaseClient {
     /// * `config` - An optional session if the user already has one from a
     /// previous login call.
     pub fn new_with_config(config: BaseClientConfig) -> Result<Self> {
+        #[cfg(feature = "passphrase_strength")]
+        if let (Some(score), Some(passphrase)) = (config.min_passphrase_score, &config.passphrase) {
+            let strength = matrix_sdk_crypto::estimate_passphrase_strength(passphrase, &[]);
+
+            if !strength.is_at_least(score) {
+                return Err(Error::WeakPassphrase(strength));
+            }
+        }
+
         #[cfg(feature = "sled_state_store")]
         let stores = if let Some(path) = &config.store_path {
             if config.passphrase.is_some() {
@@ -274,7 +581,11 @@ impl BaseClient {
             } else {
                 info!("Opening store in path {}", path.display());
             }
-            Store::open_default(path, config.passphrase.as_deref().map(|p| p.as_str()))?
+            Store::open_sled(
+                path,
+                config.passphrase.as_deref().map(|p| p.as_str()),
+                config.full_integrity_check,
+            )?
         } else {
             Store::open_temporary()?
         };
@@ -306,6 +617,11 @@ impl BaseClient {
         #[cfg(not(feature = "sled_state_store"))]
         let store = stores;
 
+        let store = match config.state_cache_size {
+            Some(size) => store.with_cache_size(size),
+            None => store,
+        };
+
         Ok(BaseClient {
             session: store.session.clone(),
             sync_token: store.sync_token.clone(),
@@ -314,8 +630,23 @@ impl BaseClient {
             olm: Mutex::new(None).into(),
             #[cfg(feature = "encryption")]
             cryptostore: Mutex::new(crypto_store).into(),
+            active_rooms: Mutex::new(VecDeque::new()).into(),
+            max_active_rooms: config.max_active_rooms,
+            #[cfg(feature = "encryption")]
+            one_time_key_target: config.one_time_key_target,
+            #[cfg(feature = "encryption")]
+            fallback_key_lifetime: config.fallback_key_lifetime,
+            #[cfg(feature = "encryption")]
+            crypto_maintenance: config.crypto_maintenance,
+            #[cfg(feature = "passphrase_strength")]
+            min_passphrase_score: config.min_passphrase_score,
             store_path: config.store_path.into(),
             store_passphrase: config.passphrase.into(),
+            room_concurrency: config.room_concurrency.unwrap_or(DEFAULT_ROOM_CONCURRENCY),
+            #[cfg(feature = "metrics")]
+            metrics_exporter: config.metrics_exporter,
+            #[cfg(feature = "metrics")]
+            decryption_metrics: Default::default(),
         })
     }
 
@@ -330,6 +661,28 @@ impl BaseClient {
         &self.store
     }
 
+    /// The minimum score passphrases need to have, if one was configured.
+    #[cfg(feature = "passphrase_strength")]
+    #[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+    pub fn min_passphrase_score(&self) -> Option<u8> {
+        self.min_passphrase_score
+    }
+
+    /// Write all the pending changes of the state and crypto stores to disk.
+    ///
+    /// Returns once all the changes that were made up until now are durably
+    /// stored.
+    pub async fn flush(&self) -> Result<()> {
+        self.store.flush().await?;
+
+        #[cfg(feature = "encryption")]
+        if let Some(o) = &*self.olm.lock().await {
+            o.flush().await?;
+        }
+
+        Ok(())
+    }
+
     /// Is the client logged in.
     pub async fn logged_in(&self) -> bool {
         // TODO turn this into a atomic bool so this method doesn't need to be
@@ -402,6 +755,14 @@ impl BaseClient {
             } else {
                 *olm = Some(OlmMachine::new(&session.user_id, &session.device_id));
             }
+
+            if let Some(machine) = olm.as_ref() {
+                machine.set_one_time_key_target(self.one_time_key_target);
+                machine.set_fallback_key_lifetime(self.fallback_key_lifetime);
+                machine.set_maintenance_settings(self.crypto_maintenance);
+
+                self.check_sync_checkpoint(machine).await?;
+            }
         }
 
         *self.session.write().await = Some(session);
@@ -409,6 +770,49 @@ impl BaseClient {
         Ok(())
     }
 
+    /// Compare the sync token of the state store with the checkpoint of the
+    /// crypto store, which is committed first.
+    ///
+    /// The sync token of the state store is always kept. If the state changes
+    /// of the last sync response weren't committed, the token is the one the
+    /// response was requested with and the response is fetched again, the
+    /// to-device journal of the crypto store makes sure that its to-device
+    /// events aren't processed twice.
+    ///
+    /// Any other mismatch is only logged, syncing from an older token can't
+    /// bring back to-device events since the homeserver deletes them once a
+    /// later token was used. A state store without a token, e.g. an in-memory
+    /// one, does an initial sync.
+    #[cfg(feature = "encryption")]
+    async fn check_sync_checkpoint(&self, machine: &OlmMachine) -> Result<()> {
+        let checkpoint = match machine.sync_checkpoint().await.map_err(OlmError::from)? {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        match self.sync_token().await {
+            None => {}
+            Some(token) if token == checkpoint.next_batch => {}
+            Some(token) if Some(&token) == checkpoint.since.as_ref() => {
+                info!(
+                    next_batch = checkpoint.next_batch.as_str(),
+                    "The state changes of the last sync response weren't committed, it will be \
+                     fetched again"
+                );
+            }
+            Some(token) => {
+                warn!(
+                    sync_token = token.as_str(),
+                    checkpoint = ?checkpoint,
+                    "The sync token of the state store doesn't match the checkpoint of the crypto \
+                     store"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get the current, if any, sync token of the client.
     /// This will be None if the client didn't sync at least once.
     pub async fn sync_token(&self) -> Option<String> {
@@ -421,6 +825,7 @@ impl BaseClient {
         room: &Room,
         ruma_timeline: api::sync::sync_events::Timeline,
         push_rules: &Ruleset,
+        ignored_users: &[UserId],
         room_info: &mut RoomInfo,
         changes: &mut StateChanges,
         ambiguity_cache: &mut AmbiguityCache,
@@ -435,10 +840,45 @@ impl BaseClient {
             #[allow(unused_mut)]
             let mut event: SyncRoomEvent = event.into();
 
-            match hoist_room_event_prev_content(&event.event) {
-                Ok(e) => {
-                    #[allow(clippy::single_match)]
-                    match &e {
+            let header = match event.event.deserialize_as::<EventHeader>() {
+                Ok(h) => h,
+                Err(e) => {
+                    warn!("Error deserializing event {:?}", e);
+                    timeline.events.push(event);
+                    continue;
+                }
+            };
+
+            // The event might have been received already, either in a previous
+            // sync response or while paginating.
+            if self.store.is_event_known(room_id, &header.event_id).await?
+                || !changes.add_seen_event(room_id, header.event_id.clone())
+            {
+                continue;
+            }
+
+            // Remember which transaction our own events belong to, the
+            // response of the send request might have been lost.
+            if let Some(txn_id) = header.unsigned.transaction_id.clone() {
+                if header.sender == *user_id {
+                    changes.add_transaction(room_id, txn_id, header.event_id.clone());
+                }
+            }
+
+            // State events of ignored users still need to be processed to keep
+            // the room state consistent, everything else gets removed from the
+            // timeline.
+            if !header.is_state() && ignored_users.contains(&header.sender) {
+                continue;
+            }
+
+            // Only the events that change our state need to be fully
+            // deserialized, everything else stays raw until someone asks for
+            // the typed event.
+            if header.needs_deserialization() {
+                #[allow(clippy::single_match)]
+                match hoist_room_event_prev_content(&event.event) {
+                    Ok(e) => match &e {
                         AnySyncRoomEvent::State(s) => match s {
                             AnySyncStateEvent::RoomMember(member) => {
                                 if let Ok(member) = MemberEvent::try_from(member.clone()) {
@@ -474,6 +914,11 @@ impl BaseClient {
                             }
                             _ => {
                                 room_info.handle_state_event(&s.content());
+
+                                if let AnySyncStateEvent::RoomJoinRules(_) = s {
+                                    room_info.handle_join_rules_allow(event.event.json());
+                                }
+
                                 let raw_event: Raw<AnySyncStateEvent> =
                                     Raw::from_json(event.event.clone().into_json());
                                 changes.add_state_event(room_id, s.clone(), raw_event);
@@ -485,10 +930,25 @@ impl BaseClient {
                             encrypted,
                         )) => {
                             if let Some(olm) = self.olm_machine().await {
-                                if let Ok(decrypted) =
-                                    olm.decrypt_room_event(encrypted, room_id).await
-                                {
-                                    event = decrypted;
+                                #[cfg(feature = "metrics")]
+                                let start = Instant::now();
+
+                                let decrypted = olm.decrypt_room_event(encrypted, room_id).await;
+
+                                #[cfg(feature = "metrics")]
+                                self.decryption_metrics.record(start.elapsed(), decrypted.is_ok());
+
+                                match decrypted {
+                                    Ok(decrypted) => {
+                                        event = copy_relation(encrypted, decrypted);
+                                    }
+                                    Err(e @ MegolmError::ReplayedEvent { .. }) => {
+                                        warn!(
+                                            "Received a replayed event {} in room {}: {}",
+                                            encrypted.event_id, room_id, e
+                                        );
+                                    }
+                                    Err(_) => (),
                                 }
                             }
                         }
@@ -497,47 +957,54 @@ impl BaseClient {
                         // requests that are needed to be called to heal this
                         // redacted state.
                         _ => (),
+                    },
+                    Err(e) => {
+                        warn!("Error deserializing event {:?}", e);
+                        timeline.events.push(event);
+                        continue;
                     }
+                }
+            }
 
-                    if let Some(context) = &mut push_context {
-                        self.update_push_room_context(context, user_id, room_info, changes).await;
-                    } else {
-                        push_context = self.get_push_room_context(room, room_info, changes).await?;
-                    }
+            if let Some(context) = &mut push_context {
+                self.update_push_room_context(context, user_id, room_info, changes).await;
+            } else {
+                push_context = self.get_push_room_context(room, room_info, changes).await?;
+            }
 
-                    if let Some(context) = &push_context {
-                        let actions = push_rules.get_actions(&event.event, context).to_vec();
-
-                        if actions.iter().any(|a| matches!(a, Action::Notify)) {
-                            changes.add_notification(
-                                room_id,
-                                Notification::new(
-                                    actions,
-                                    event.event.clone(),
-                                    false,
-                                    room_id.clone(),
-                                    MilliSecondsSinceUnixEpoch::now(),
-                                ),
-                            );
-                        }
-                        // TODO if there is an
-                        // Action::SetTweak(Tweak::Highlight) we need to store
-                        // its value with the event so a client can show if the
-                        // event is highlighted
-                        // in the UI.
-                        // Requires the possibility to associate custom data
-                        // with events and to
-                        // store them.
-                    }
-                }
-                Err(e) => {
-                    warn!("Error deserializing event {:?}", e);
+            if let Some(context) = &push_context {
+                let actions = push_rules.get_actions(&event.event, context).to_vec();
+
+                if actions.iter().any(|a| matches!(a, Action::Notify)) {
+                    changes.add_notification(
+                        room_id,
+                        Notification::new(
+                            actions,
+                            event.event.clone(),
+                            false,
+                            room_id.clone(),
+                            MilliSecondsSinceUnixEpoch::now(),
+                        ),
+                    );
                 }
+                // TODO if there is an
+                // Action::SetTweak(Tweak::Highlight) we need to store
+                // its value with the event so a client can show if the
+                // event is highlighted
+                // in the UI.
+                // Requires the possibility to associate custom data
+                // with events and to
+                // store them.
             }
 
+            self.store.aggregate_event(room_id, &event);
             timeline.events.push(event);
         }
 
+        if let Some(event) = timeline.events.last() {
+            room_info.latest_event = Some(event.clone());
+        }
+
         Ok(timeline)
     }
 
@@ -568,6 +1035,11 @@ impl BaseClient {
                             }
                         } else {
                             room_info.handle_state_event(&e.content());
+
+                            if let AnyStrippedStateEvent::RoomJoinRules(_) = e {
+                                room_info.handle_join_rules_allow(raw_event.json());
+                            }
+
                             state_events
                                 .entry(e.content().event_type().to_owned())
                                 .or_insert_with(BTreeMap::new)
@@ -614,6 +1086,10 @@ impl BaseClient {
 
             room_info.handle_state_event(&event.content());
 
+            if let AnySyncStateEvent::RoomJoinRules(_) = event {
+                room_info.handle_join_rules_allow(raw_event.json());
+            }
+
             if let AnySyncStateEvent::RoomMember(member) = event {
                 match MemberEvent::try_from(member) {
                     Ok(m) => {
@@ -708,10 +1184,18 @@ impl BaseClient {
     /// # Arguments
     ///
     /// * `response` - The response that we received after a successful sync.
+    ///
+    /// * `since` - The sync token the sync request was sent with, `None` if it
+    /// was an initial sync.
+    #[cfg_attr(feature = "metrics", instrument(skip(self, response)))]
     pub async fn receive_sync_response(
         &self,
         response: api::sync::sync_events::Response,
+        since: Option<&str>,
     ) -> Result<SyncResponse> {
+        #[cfg(feature = "metrics")]
+        let mut metrics = SyncMetrics::from_response(&response);
+
         #[cfg(test)]
         let api::sync::sync_events::Response {
             next_batch,
@@ -745,8 +1229,18 @@ impl BaseClient {
 
         let now = Instant::now();
 
+        // The sync response is committed in two phases. The crypto changes are
+        // committed first, together with a checkpoint of the sync tokens,
+        // followed by the state changes that include the new sync token. A
+        // crash between the two phases makes us fetch the response again,
+        // while the token can never be persisted before the to-device events,
+        // which would lose room keys for good.
+
         #[cfg(feature = "encryption")]
         let to_device = {
+            #[cfg(feature = "metrics")]
+            let start = Instant::now();
+
             let olm = self.olm.lock().await;
 
             if let Some(o) = &*olm {
@@ -754,100 +1248,85 @@ impl BaseClient {
                 // decrypts to-device events, but leaves room events alone.
                 // This makes sure that we have the decryption keys for the room
                 // events at hand.
-                o.receive_sync_changes(to_device, &device_lists, &device_one_time_keys_count)
-                    .await?
-            } else {
+                let checkpoint = SyncCheckpoint {
+                    since: since.map(ToOwned::to_owned),
+                    next_batch: next_batch.clone(),
+                };
+
+                let to_device = o
+                    .receive_sync_changes(
+                        to_device,
+                        &device_lists,
+                        &device_one_time_keys_count,
+                        checkpoint,
+                    )
+                    .instrument(sync_span!("receive_sync_changes"))
+                    .await?;
+
+                #[cfg(feature = "metrics")]
+                {
+                    metrics.crypto_time = start.elapsed();
+                }
+
                 to_device
+            } else {
+                ToDevice::from(to_device)
             }
         };
 
+        #[cfg(not(feature = "encryption"))]
+        let to_device = ToDevice::from(to_device);
+
         let mut changes = StateChanges::new(next_batch.clone());
         let mut ambiguity_cache = AmbiguityCache::new(self.store.clone());
 
         self.handle_account_data(&account_data.events, &mut changes).await;
 
         let push_rules = self.get_push_rules(&changes).await?;
+        let ignored_users = self.get_ignored_users(&changes).await?;
 
         let mut new_rooms = Rooms::default();
-
-        for (room_id, new_info) in rooms.join {
-            let room = self.store.get_or_create_room(&room_id, RoomType::Joined).await;
-            let mut room_info = room.clone_info();
-            room_info.mark_as_joined();
-
-            room_info.update_summary(&new_info.summary);
-            room_info.set_prev_batch(new_info.timeline.prev_batch.as_deref());
-
-            let mut user_ids = self
-                .handle_state(
-                    &mut changes,
-                    &mut ambiguity_cache,
-                    &new_info.state.events,
-                    &mut room_info,
-                )
-                .await?;
-
-            if let Some(event) =
-                new_info.ephemeral.events.iter().find_map(|e| match e.deserialize() {
-                    Ok(AnySyncEphemeralRoomEvent::Receipt(event)) => Some(event.content),
-                    _ => None,
-                })
-            {
-                changes.add_receipts(&room_id, event);
-            }
-
-            if new_info.timeline.limited {
-                room_info.mark_members_missing();
-            }
-
-            let timeline = self
-                .handle_timeline(
-                    &room,
-                    new_info.timeline,
-                    &push_rules,
-                    &mut room_info,
-                    &mut changes,
-                    &mut ambiguity_cache,
-                    &mut user_ids,
+        let mut active_rooms = Vec::new();
+
+        let push_rules = Arc::new(push_rules);
+        let ignored_users = Arc::new(ignored_users);
+
+        #[cfg(feature = "metrics")]
+        let rooms_start = Instant::now();
+
+        // Joined rooms are independent of each other, process them
+        // concurrently and merge their changes in the order of the response.
+        // The tasks are cancelled if the processing of the response is
+        // dropped.
+        let mut joined_rooms = stream::iter(rooms.join)
+            .map(|(room_id, new_info)| {
+                let span = sync_span!("handle_joined_room", room_id = %room_id);
+
+                spawn(
+                    self.clone()
+                        .handle_joined_room(
+                            room_id,
+                            new_info,
+                            push_rules.clone(),
+                            ignored_users.clone(),
+                        )
+                        .instrument(span),
                 )
-                .await?;
-
-            self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
-                .await;
+                .abort_on_drop()
+            })
+            .buffered(self.room_concurrency);
 
-            #[cfg(feature = "encryption")]
-            if room_info.is_encrypted() {
-                if let Some(o) = self.olm_machine().await {
-                    if !room.is_encrypted() {
-                        // The room turned on encryption in this sync, we need
-                        // to also get all the existing users and mark them for
-                        // tracking.
-                        let joined = self.store.get_joined_user_ids(&room_id).await?;
-                        let invited = self.store.get_invited_user_ids(&room_id).await?;
-
-                        let user_ids: Vec<&UserId> = joined.iter().chain(&invited).collect();
-                        o.update_tracked_users(user_ids).await
-                    }
+        while let Some(update) = joined_rooms.next().await {
+            let update = update??;
 
-                    o.update_tracked_users(&user_ids).await
-                }
+            if update.active {
+                active_rooms.push(update.room_id.clone());
             }
 
-            let notification_count = new_info.unread_notifications.into();
-            room_info.update_notification_count(notification_count);
-
-            new_rooms.join.insert(
-                room_id,
-                JoinedRoom::new(
-                    timeline,
-                    new_info.state,
-                    new_info.account_data,
-                    new_info.ephemeral,
-                    notification_count,
-                ),
-            );
-
-            changes.add_room(room_info);
+            changes.extend(update.changes);
+            ambiguity_cache.cache.extend(update.ambiguity_cache.cache);
+            ambiguity_cache.changes.extend(update.ambiguity_cache.changes);
+            new_rooms.join.insert(update.room_id, update.room);
         }
 
         for (room_id, new_info) in rooms.leave {
@@ -869,6 +1348,7 @@ impl BaseClient {
                     &room,
                     new_info.timeline,
                     &push_rules,
+                    &ignored_users,
                     &mut room_info,
                     &mut changes,
                     &mut ambiguity_cache,
@@ -899,6 +1379,18 @@ impl BaseClient {
             let (members, state_events) =
                 self.handle_invited_state(&new_info.invite_state.events, &mut room_info);
 
+            #[cfg(feature = "encryption")]
+            if let Some(o) = self.olm_machine().await {
+                // Accept the room keys the inviter shares from the history of
+                // the room.
+                if let Some(invite) = members
+                    .get(room.own_user_id())
+                    .filter(|m| m.content.membership == MembershipState::Invite)
+                {
+                    o.receive_room_invite(&room_id, &invite.sender).await?;
+                }
+            }
+
             changes.stripped_members.insert(room_id.clone(), members);
             changes.stripped_state.insert(room_id.clone(), state_events);
             changes.add_stripped_room(room_info);
@@ -906,6 +1398,11 @@ impl BaseClient {
             new_rooms.invite.insert(room_id, new_info);
         }
 
+        #[cfg(feature = "metrics")]
+        {
+            metrics.rooms_time = rooms_start.elapsed();
+        }
+
         changes.presence = presence
             .events
             .iter()
@@ -917,13 +1414,30 @@ impl BaseClient {
 
         changes.ambiguity_maps = ambiguity_cache.cache;
 
-        self.store.save_changes(&changes).await?;
+        self.mark_rooms_as_active(&active_rooms).await;
+
+        #[cfg(feature = "metrics")]
+        let store_start = Instant::now();
+
+        self.store.save_changes(&changes).instrument(sync_span!("save_changes")).await?;
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics.store_write_time = store_start.elapsed();
+        }
+
         *self.sync_token.write().await = Some(next_batch.clone());
         self.apply_changes(&changes).await;
 
         info!("Processed a sync response in {:?}", now.elapsed());
 
-        let response = SyncResponse {
+        #[cfg(feature = "metrics")]
+        {
+            metrics.total_time = now.elapsed();
+            self.export_sync_metrics(metrics);
+        }
+
+        let response = SyncResponse {
             next_batch,
             rooms: new_rooms,
             presence,
@@ -941,12 +1455,352 @@ impl BaseClient {
         Ok(response)
     }
 
+    /// Process the state, timeline and account data of a single joined room of
+    /// a sync response.
+    ///
+    /// The changes are collected separately for every room so multiple rooms
+    /// can be processed concurrently.
+    async fn handle_joined_room(
+        self,
+        room_id: RoomId,
+        new_info: api::sync::sync_events::JoinedRoom,
+        push_rules: Arc<Ruleset>,
+        ignored_users: Arc<Vec<UserId>>,
+    ) -> Result<JoinedRoomUpdate> {
+        let active = !new_info.timeline.events.is_empty();
+
+        let mut changes = StateChanges::default();
+        let mut ambiguity_cache = AmbiguityCache::new(self.store.clone());
+
+        let room = self.store.get_or_create_room(&room_id, RoomType::Joined).await;
+        let mut room_info = room.clone_info();
+        room_info.mark_as_joined();
+
+        room_info.update_summary(&new_info.summary);
+        room_info.set_prev_batch(new_info.timeline.prev_batch.as_deref());
+
+        let mut user_ids = self
+            .handle_state(
+                &mut changes,
+                &mut ambiguity_cache,
+                &new_info.state.events,
+                &mut room_info,
+            )
+            .await?;
+
+        for event in new_info.ephemeral.events.iter().filter_map(|e| e.deserialize().ok()) {
+            match event {
+                AnySyncEphemeralRoomEvent::Receipt(event) => {
+                    changes.add_receipts(&room_id, event.content)
+                }
+                AnySyncEphemeralRoomEvent::Typing(event) => {
+                    room.set_typing_users(event.content.user_ids)
+                }
+                _ => (),
+            }
+        }
+
+        if new_info.timeline.limited {
+            room_info.mark_members_missing();
+        }
+
+        let timeline = self
+            .handle_timeline(
+                &room,
+                new_info.timeline,
+                &push_rules,
+                &ignored_users,
+                &mut room_info,
+                &mut changes,
+                &mut ambiguity_cache,
+                &mut user_ids,
+            )
+            .await?;
+
+        self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes).await;
+
+        #[cfg(feature = "encryption")]
+        if room_info.is_encrypted() {
+            if let Some(o) = self.olm_machine().await {
+                if !room.is_encrypted() {
+                    // The room turned on encryption in this sync, we need
+                    // to also get all the existing users and mark them for
+                    // tracking.
+                    let joined = self.store.get_joined_user_ids(&room_id).await?;
+                    let invited = self.store.get_invited_user_ids(&room_id).await?;
+
+                    let user_ids: Vec<&UserId> = joined.iter().chain(&invited).collect();
+                    o.update_tracked_users(user_ids).await
+                }
+
+                o.update_tracked_users(&user_ids).await
+            }
+        }
+
+        let notification_count = new_info.unread_notifications.into();
+        room_info.update_notification_count(notification_count);
+
+        changes.add_room(room_info);
+
+        Ok(JoinedRoomUpdate {
+            room: JoinedRoom::new(
+                timeline,
+                new_info.state,
+                new_info.account_data,
+                new_info.ephemeral,
+                notification_count,
+            ),
+            room_id,
+            changes,
+            ambiguity_cache,
+            active,
+        })
+    }
+
+    #[cfg(feature = "metrics")]
+    fn export_sync_metrics(&self, mut metrics: SyncMetrics) {
+        self.decryption_metrics.take_into(&mut metrics);
+
+        if let Some(exporter) = &self.metrics_exporter {
+            exporter.export_sync_metrics(&metrics);
+        } else {
+            debug!(?metrics, "Sync metrics");
+        }
+    }
+
     async fn apply_changes(&self, changes: &StateChanges) {
         for (room_id, room_info) in &changes.room_infos {
             if let Some(room) = self.store.get_room(room_id) {
-                room.update_summary(room_info.clone())
+                let tags_changed = changes
+                    .room_account_data
+                    .get(room_id)
+                    .map_or(false, |e| e.contains_key(EventType::Tag.as_str()));
+
+                self.store.update_room_info(&room, room_info.clone(), tags_changed)
+            }
+        }
+    }
+
+    /// Mark the given room as knocked after we successfully knocked on it.
+    ///
+    /// The room stays knocked until it shows up in a sync response, e.g.
+    /// because a member of the room invited us or our knock was rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room we knocked on.
+    pub async fn room_knocked(&self, room_id: &RoomId) -> Result<()> {
+        let room = self.store.get_or_create_room(room_id, RoomType::Knocked).await;
+        let mut room_info = room.clone_info();
+        room_info.mark_as_knocked();
+
+        let mut changes = StateChanges::default();
+        changes.add_room(room_info);
+
+        self.store.save_changes(&changes).await?;
+        self.apply_changes(&changes).await;
+
+        Ok(())
+    }
+
+    /// Receive the events of a `/messages` response.
+    ///
+    /// The events that weren't received before are added to the index of
+    /// known events of the room, so they can be told apart if they show up in
+    /// a sync response or in a later `/messages` response.
+    ///
+    /// Returns for every event whether it was received for the first time.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id this response belongs to.
+    ///
+    /// * `events` - The events of the `/messages` response.
+    pub async fn receive_messages(
+        &self,
+        room_id: &RoomId,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<Vec<bool>> {
+        let mut changes = StateChanges::default();
+        let mut new_events = Vec::with_capacity(events.len());
+
+        for event in events {
+            if let Ok(e) = event.deserialize() {
+                if self.store.is_event_known(room_id, e.event_id()).await?
+                    || !changes.add_seen_event(room_id, e.event_id().clone())
+                {
+                    new_events.push(false);
+                    continue;
+                }
+            }
+
+            let sync_event = Raw::<AnySyncRoomEvent>::from_json(event.clone().into_json()).into();
+            self.store.aggregate_event(room_id, &sync_event);
+
+            new_events.push(true);
+        }
+
+        self.store.save_changes(&changes).await?;
+
+        Ok(new_events)
+    }
+
+    /// Receive the response of a `/context` request.
+    ///
+    /// Encrypted events are decrypted if the room key for them is known. Like
+    /// the events of a `/messages` response, the events that weren't received
+    /// before are added to the index of known events of the room and to the
+    /// aggregations, so they aren't received twice once they show up in a sync
+    /// response or a `/messages` response.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id this response belongs to.
+    ///
+    /// * `response` - The response of the `/context` request.
+    pub async fn receive_context(
+        &self,
+        room_id: &RoomId,
+        response: api::context::get_context::Response,
+    ) -> Result<EventContext> {
+        let event = match response.event {
+            Some(e) => Some(self.decrypt_room_event(room_id, e).await),
+            None => None,
+        };
+
+        let context = EventContext {
+            event,
+            events_before: self.receive_history(room_id, response.events_before).await,
+            events_after: self.receive_history(room_id, response.events_after).await,
+            state: response.state,
+            start: response.start,
+            end: response.end,
+        };
+
+        let mut changes = StateChanges::default();
+        let events =
+            context.event.iter().chain(&context.events_before).chain(&context.events_after);
+
+        for event in events {
+            if let Ok(header) = event.event.deserialize_as::<EventHeader>() {
+                if self.store.is_event_known(room_id, &header.event_id).await?
+                    || !changes.add_seen_event(room_id, header.event_id)
+                {
+                    continue;
+                }
+            }
+
+            self.store.aggregate_event(room_id, event);
+        }
+
+        self.store.save_changes(&changes).await?;
+
+        Ok(context)
+    }
+
+    /// Receive the related events of a `/relations` response.
+    ///
+    /// Encrypted events are decrypted if the room key for them is known. The
+    /// events are added to the aggregations of the events they relate to, but
+    /// they aren't added to the index of known events of the room, they are
+    /// still new once they show up in a sync response or a `/messages`
+    /// response.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id this response belongs to.
+    ///
+    /// * `events` - The events of the `/relations` response.
+    pub async fn receive_relations(
+        &self,
+        room_id: &RoomId,
+        events: Vec<Raw<AnyRoomEvent>>,
+    ) -> Vec<SyncRoomEvent> {
+        let mut related_events = Vec::with_capacity(events.len());
+
+        for event in events {
+            let event = self.decrypt_room_event(room_id, event).await;
+            self.store.aggregate_event(room_id, &event);
+
+            related_events.push(event);
+        }
+
+        related_events
+    }
+
+    /// Receive events of the room history that were fetched with a
+    /// `/messages` request, e.g. to export the history.
+    ///
+    /// Encrypted events are decrypted if the room key for them is known.
+    /// Unlike [`receive_messages()`](#method.receive_messages) the events
+    /// don't change the state of the client, they aren't marked as seen and
+    /// aren't aggregated.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id the events belong to.
+    ///
+    /// * `events` - The events of the `/messages` response.
+    pub async fn receive_history(
+        &self,
+        room_id: &RoomId,
+        events: Vec<Raw<AnyRoomEvent>>,
+    ) -> Vec<SyncRoomEvent> {
+        let mut history = Vec::with_capacity(events.len());
+
+        for event in events {
+            history.push(self.decrypt_room_event(room_id, event).await);
+        }
+
+        history
+    }
+
+    /// Remember the id of an event we sent, so that a send with the same
+    /// transaction id can be recognized, e.g. if it's retried after a crash.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `txn_id` - The transaction id the event was sent with.
+    ///
+    /// * `event_id` - The id the homeserver assigned to the event.
+    pub async fn receive_sent_event(
+        &self,
+        room_id: &RoomId,
+        txn_id: &str,
+        event_id: &EventId,
+    ) -> Result<()> {
+        let mut changes = StateChanges::default();
+        changes.add_transaction(room_id, txn_id.to_owned(), event_id.clone());
+
+        Ok(self.store.save_changes(&changes).await?)
+    }
+
+    /// Decrypt a room event that was fetched outside of a sync response, if
+    /// the room key for it is known.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    async fn decrypt_room_event(
+        &self,
+        room_id: &RoomId,
+        event: Raw<AnyRoomEvent>,
+    ) -> SyncRoomEvent {
+        // A room event is a sync room event with an additional room id field.
+        #[allow(unused_mut)]
+        let mut event: SyncRoomEvent = Raw::<AnySyncRoomEvent>::from_json(event.into_json()).into();
+
+        #[cfg(feature = "encryption")]
+        if let Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(encrypted))) =
+            event.deserialize()
+        {
+            if let Some(olm) = self.olm_machine().await {
+                if let Ok(decrypted) = olm.decrypt_room_event(&encrypted, room_id).await {
+                    event = copy_relation(&encrypted, decrypted);
+                }
             }
         }
+
+        event
     }
 
     /// Receive a get member events response and convert it to a deserialized
@@ -962,6 +1816,39 @@ impl BaseClient {
         &self,
         room_id: &RoomId,
         response: &api::membership::get_member_events::Response,
+    ) -> Result<MembersResponse> {
+        self.receive_member_events(room_id, response, false).await
+    }
+
+    /// Receive a get member events response containing the full member list
+    /// of a room and repair the stored members with it.
+    ///
+    /// Unlike [`receive_members`], member events that differ from the stored
+    /// ones replace them and the member counts of the room are recalculated
+    /// from the response. This is used to fix a member list that drifted from
+    /// the one of the homeserver.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id this response belongs to.
+    ///
+    /// * `response` - The raw response that was received from the server, it
+    /// needs to contain the members at the current state of the room.
+    ///
+    /// [`receive_members`]: #method.receive_members
+    pub async fn reconcile_members(
+        &self,
+        room_id: &RoomId,
+        response: &api::membership::get_member_events::Response,
+    ) -> Result<MembersResponse> {
+        self.receive_member_events(room_id, response, true).await
+    }
+
+    async fn receive_member_events(
+        &self,
+        room_id: &RoomId,
+        response: &api::membership::get_member_events::Response,
+        overwrite: bool,
     ) -> Result<MembersResponse> {
         let members: Vec<MemberEvent> = response
             .chunk
@@ -974,13 +1861,29 @@ impl BaseClient {
             let mut room_info = room.clone_info();
             room_info.mark_members_synced();
 
+            if overwrite {
+                let count = |membership| {
+                    members.iter().filter(|m| m.content.membership == membership).count() as u64
+                };
+
+                room_info.set_member_counts(
+                    count(MembershipState::Join),
+                    count(MembershipState::Invite),
+                );
+            }
+
             let mut changes = StateChanges::default();
 
             #[cfg(feature = "encryption")]
             let mut user_ids = BTreeSet::new();
 
             for member in &members {
-                if self.store.get_member_event(room_id, &member.state_key).await?.is_none() {
+                let changed = match self.store.get_member_event(room_id, &member.state_key).await? {
+                    Some(stored) => overwrite && stored.event_id != member.event_id,
+                    None => true,
+                };
+
+                if changed {
                     #[cfg(feature = "encryption")]
                     match member.content.membership {
                         MembershipState::Join | MembershipState::Invite => {
@@ -1152,12 +2055,40 @@ impl BaseClient {
                 let settings = settings.ok_or(MegolmError::EncryptionNotEnabled)?;
                 let settings = EncryptionSettings::new(settings, history_visibility);
 
+                self.mark_rooms_as_active(std::iter::once(room_id)).await;
+
                 Ok(o.share_group_session(room_id, members, settings).await?)
             }
             None => panic!("Olm machine wasn't started"),
         }
     }
 
+    /// Get to-device requests that share the history of a room with a user
+    /// that was invited to the room.
+    ///
+    /// Only room keys that were created while the history visibility of the
+    /// room was `shared` or `world_readable` will be shared.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room whose history should be shared.
+    ///
+    /// * `user_id` - The user that was invited to the room.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn share_room_history(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Vec<Arc<ToDeviceRequest>>> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.share_room_history(room_id, user_id).await?),
+            None => Ok(Vec::new()),
+        }
+    }
+
     /// Get the room with the given room id.
     ///
     /// # Arguments
@@ -1305,6 +2236,80 @@ impl BaseClient {
         }
     }
 
+    /// Mark the given rooms as the most recently active ones.
+    ///
+    /// If more than the configured maximum of rooms are active, the least
+    /// recently active rooms will be unloaded.
+    async fn mark_rooms_as_active<'a>(&self, room_ids: impl IntoIterator<Item = &'a RoomId>) {
+        let max_active_rooms = if let Some(m) = self.max_active_rooms { m } else { return };
+
+        let inactive_rooms = {
+            let mut active_rooms = self.active_rooms.lock().await;
+
+            for room_id in room_ids {
+                active_rooms.retain(|r| r != room_id);
+                active_rooms.push_front(room_id.clone());
+            }
+
+            if active_rooms.len() <= max_active_rooms {
+                return;
+            }
+
+            active_rooms.split_off(max_active_rooms)
+        };
+
+        for room_id in inactive_rooms {
+            self.unload_room_state(&room_id).await;
+        }
+    }
+
+    /// Drop the cached members, profiles and state events of the given room,
+    /// as well as its cached cryptographic state.
+    async fn unload_room_state(&self, room_id: &RoomId) -> bool {
+        let unloaded = self.store.unload_room(room_id).await;
+
+        #[cfg(feature = "encryption")]
+        let unloaded = match self.olm_machine().await {
+            Some(o) => o.unload_room(room_id) || unloaded,
+            None => unloaded,
+        };
+
+        unloaded
+    }
+
+    /// Unload the state of the given room that is cached in memory.
+    ///
+    /// This drops the cached members, profiles and state events of the room
+    /// as well as its cached cryptographic state, the info of the room is
+    /// kept. The state will be lazily reloaded from the store once it's
+    /// needed again. This is done automatically for inactive rooms if a maximal
+    /// number of active rooms is configured with
+    /// `BaseClientConfig::max_active_rooms()`.
+    ///
+    /// Returns true if some state was unloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that should be unloaded.
+    pub async fn unload_room(&self, room_id: &RoomId) -> bool {
+        self.active_rooms.lock().await.retain(|r| r != room_id);
+        self.unload_room_state(room_id).await
+    }
+
+    /// Run the maintenance of the crypto store if it's enabled and didn't
+    /// run within the configured interval, see
+    /// [`BaseClientConfig::crypto_maintenance()`].
+    ///
+    /// Returns what the maintenance removed, `None` if it didn't run.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn run_crypto_maintenance_if_due(&self) -> Result<Option<MaintenanceSummary>> {
+        match self.olm_machine().await {
+            Some(o) => Ok(o.run_maintenance_if_due().await?),
+            None => Ok(None),
+        }
+    }
+
     /// Get the olm machine.
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
@@ -1339,6 +2344,50 @@ impl BaseClient {
         }
     }
 
+    /// Get the list of users that we ignore.
+    ///
+    /// The list is taken from the `m.ignored_user_list` global account data
+    /// event, messages of ignored users are removed from the timeline in sync
+    /// responses.
+    pub async fn ignored_users(&self) -> Result<Vec<UserId>> {
+        if let Some(AnyGlobalAccountDataEvent::IgnoredUserList(event)) = self
+            .store
+            .get_account_data_event(EventType::IgnoredUserList)
+            .await?
+            .and_then(|e| e.deserialize().ok())
+        {
+            Ok(event.content.ignored_users)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Remove all the locally stored data of the given room.
+    ///
+    /// This only removes the room from the local store, the room will be
+    /// recreated if it appears in a sync response again.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that should be removed.
+    pub async fn purge_room(&self, room_id: &RoomId) -> Result<()> {
+        Ok(self.store.remove_room(room_id).await?)
+    }
+
+    /// Get the list of ignored users from `changes` if it has been updated,
+    /// otherwise get it from the store.
+    async fn get_ignored_users(&self, changes: &StateChanges) -> Result<Vec<UserId>> {
+        if let Some(AnyGlobalAccountDataEvent::IgnoredUserList(event)) = changes
+            .account_data
+            .get(EventType::IgnoredUserList.as_str())
+            .and_then(|e| e.deserialize().ok())
+        {
+            Ok(event.content.ignored_users)
+        } else {
+            self.ignored_users().await
+        }
+    }
+
     /// Get the push context for the given room.
     ///
     /// Tries to get the data from `changes` or the up to date `room_info`.
@@ -1432,5 +2481,121 @@ impl BaseClient {
     }
 }
 
-#[cfg(test)]
-mod test {}
+#[cfg(all(test, feature = "encryption"))]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_crypto::store::{Changes, CryptoStore, MemoryStore, SyncCheckpoint};
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        events::{
+            room::member::{MemberEventContent, MembershipState},
+            Unsigned,
+        },
+        room_id, user_id, EventId, MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::{BaseClient, BaseClientConfig, Session, StateChanges};
+    use crate::deserialized_responses::MemberEvent;
+
+    async fn client_with_stores(
+        sync_token: Option<&str>,
+        checkpoint: SyncCheckpoint,
+    ) -> BaseClient {
+        let crypto_store = MemoryStore::new();
+        let changes = Changes { sync_checkpoint: Some(checkpoint), ..Default::default() };
+        crypto_store.save_changes(changes).await.unwrap();
+
+        let config = BaseClientConfig::new().crypto_store(Box::new(crypto_store));
+        let client = BaseClient::new_with_config(config).unwrap();
+
+        if let Some(sync_token) = sync_token {
+            client.store().save_changes(&StateChanges::new(sync_token.to_owned())).await.unwrap();
+        }
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+        };
+        client.restore_login(session).await.unwrap();
+
+        client
+    }
+
+    #[async_test]
+    async fn uncommitted_state_is_fetched_again() {
+        let checkpoint =
+            SyncCheckpoint { since: Some("s1".to_owned()), next_batch: "s2".to_owned() };
+        let client = client_with_stores(Some("s1"), checkpoint).await;
+
+        assert_eq!(client.sync_token().await.as_deref(), Some("s1"));
+    }
+
+    #[async_test]
+    async fn diverged_stores_keep_the_state_token() {
+        let checkpoint =
+            SyncCheckpoint { since: Some("s1".to_owned()), next_batch: "s2".to_owned() };
+        let client = client_with_stores(Some("s5"), checkpoint).await;
+
+        assert_eq!(client.sync_token().await.as_deref(), Some("s5"));
+
+        let checkpoint = SyncCheckpoint { since: None, next_batch: "s2".to_owned() };
+        let client = client_with_stores(Some("s5"), checkpoint).await;
+
+        assert_eq!(client.sync_token().await.as_deref(), Some("s5"));
+    }
+
+    #[async_test]
+    async fn empty_state_store_does_an_initial_sync() {
+        // A persistent crypto store together with an in-memory state store.
+        let checkpoint =
+            SyncCheckpoint { since: Some("s1".to_owned()), next_batch: "s2".to_owned() };
+        let client = client_with_stores(None, checkpoint).await;
+
+        assert_eq!(client.sync_token().await, None);
+    }
+
+    #[async_test]
+    async fn inactive_rooms_are_unloaded() {
+        let client =
+            BaseClient::new_with_config(BaseClientConfig::new().max_active_rooms(1)).unwrap();
+        let first_room = room_id!("!first:localhost");
+        let second_room = room_id!("!second:localhost");
+        let user_id = user_id!("@example:localhost");
+
+        let member = MemberEvent {
+            event_id: EventId::try_from("$h29iv0s8:example.com").unwrap(),
+            content: MemberEventContent::new(MembershipState::Join),
+            sender: user_id.clone(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            state_key: user_id.clone(),
+            prev_content: None,
+            unsigned: Unsigned::default(),
+        };
+
+        let mut changes = StateChanges::default();
+        for room_id in &[&first_room, &second_room] {
+            changes
+                .members
+                .entry((*room_id).clone())
+                .or_default()
+                .insert(user_id.clone(), member.clone());
+        }
+        client.store().save_changes(&changes).await.unwrap();
+
+        for room_id in &[&first_room, &second_room] {
+            assert!(client.store().get_member_event(room_id, &user_id).await.unwrap().is_some());
+        }
+
+        client.mark_rooms_as_active(std::iter::once(&first_room)).await;
+        client.mark_rooms_as_active(std::iter::once(&second_room)).await;
+
+        // The first room was unloaded, its members are still in the store.
+        assert!(!client.store.unload_room(&first_room).await);
+        assert!(client.store().get_member_event(&first_room, &user_id).await.unwrap().is_some());
+
+        assert!(client.unload_room(&second_room).await);
+        assert!(!client.unload_room(&second_room).await);
+    }
+}
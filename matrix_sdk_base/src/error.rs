@@ -17,6 +17,9 @@
 
 use std::io::Error as IoError;
 
+use matrix_sdk_common::executor::JoinError;
+#[cfg(feature = "passphrase_strength")]
+use matrix_sdk_crypto::PassphraseStrength;
 #[cfg(feature = "encryption")]
 use matrix_sdk_crypto::{CryptoStoreError, MegolmError, OlmError};
 use serde_json::Error as JsonError;
@@ -46,6 +49,10 @@ pub enum Error {
     #[error(transparent)]
     IoError(#[from] IoError),
 
+    /// A task that processed a part of a sync response panicked.
+    #[error("processing the sync response failed: {0}")]
+    SyncTask(#[from] JoinError),
+
     /// An error occurred in the crypto store.
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
@@ -63,4 +70,11 @@ pub enum Error {
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
     #[error(transparent)]
     MegolmError(#[from] MegolmError),
+
+    /// The passphrase of the stores is weaker than the minimum strength that
+    /// was configured.
+    #[cfg(feature = "passphrase_strength")]
+    #[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+    #[error("the store passphrase is too weak, it has a score of {}", .0.score)]
+    WeakPassphrase(PassphraseStrength),
 }
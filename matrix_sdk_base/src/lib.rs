@@ -25,6 +25,10 @@
 //! by default be stored only in memory and thus lost after the client is
 //! destroyed.
 //! * `markdown`: Support for sending markdown formatted messages.
+//! * `metrics`: Enables tracing spans for the stages of the sync processing
+//! and collects metrics about every processed sync response.
+//! * `passphrase_strength`: Enables the estimation of the strength of the
+//! store passphrase, weak passphrases can be refused.
 #![deny(
     missing_debug_implementations,
     missing_docs,
@@ -46,6 +50,8 @@ pub use crate::{
 mod client;
 mod error;
 pub mod media;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod rooms;
 mod session;
 mod store;
@@ -54,5 +60,14 @@ pub use client::{BaseClient, BaseClientConfig};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use matrix_sdk_crypto as crypto;
-pub use rooms::{Room, RoomInfo, RoomMember, RoomType};
+#[cfg(feature = "metrics")]
+#[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+pub use metrics::{MetricsExporter, SyncMetrics};
+pub use rooms::{
+    Aggregations, InvitedRoomInfo, Inviter, PowerLevelsChange, PowerLevelsDiff, RelationType, Room,
+    RoomInfo, RoomInfoChanges, RoomListDiff, RoomListEntry, RoomListFilter, RoomListOrder,
+    RoomListService, RoomListUpdate, RoomMember, RoomType,
+};
+#[cfg(feature = "sled_state_store")]
+pub use store::IntegrityReport;
 pub use store::{StateChanges, StateStore, Store, StoreError};
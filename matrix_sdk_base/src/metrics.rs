@@ -0,0 +1,191 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics about the time the client spends processing sync responses.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use ruma::api::client::r0::sync::sync_events::Response as SyncResponse;
+
+/// Metrics about the processing of a single sync response.
+#[derive(Clone, Debug, Default)]
+pub struct SyncMetrics {
+    /// The number of joined rooms in the response.
+    pub joined_rooms: usize,
+    /// The number of left rooms in the response.
+    pub left_rooms: usize,
+    /// The number of invited rooms in the response.
+    pub invited_rooms: usize,
+    /// The number of timeline events in the response, summed up over all the
+    /// joined and left rooms.
+    pub timeline_events: usize,
+    /// The number of state events in the response, summed up over all the
+    /// rooms.
+    pub state_events: usize,
+    /// The number of to-device events in the response.
+    pub to_device_events: usize,
+    /// The number of presence events in the response.
+    pub presence_events: usize,
+    /// The number of room events that were successfully decrypted.
+    pub decrypted_events: usize,
+    /// The number of room events that failed to decrypt.
+    pub undecryptable_events: usize,
+    /// The time the crypto machine spent on the to-device events, the device
+    /// list changes and the one-time key counts.
+    pub crypto_time: Duration,
+    /// The time spent decrypting room events, summed up over all the rooms.
+    ///
+    /// Joined rooms are processed concurrently, this might be longer than
+    /// `rooms_time`.
+    pub decryption_time: Duration,
+    /// The time spent processing the joined, left and invited rooms.
+    pub rooms_time: Duration,
+    /// The time spent writing the changes to the state store.
+    pub store_write_time: Duration,
+    /// The total time the processing of the response took.
+    pub total_time: Duration,
+}
+
+impl SyncMetrics {
+    /// Count the rooms and events of the given sync response.
+    pub(crate) fn from_response(response: &SyncResponse) -> Self {
+        let rooms = &response.rooms;
+
+        Self {
+            joined_rooms: rooms.join.len(),
+            left_rooms: rooms.leave.len(),
+            invited_rooms: rooms.invite.len(),
+            timeline_events: rooms.join.values().map(|r| r.timeline.events.len()).sum::<usize>()
+                + rooms.leave.values().map(|r| r.timeline.events.len()).sum::<usize>(),
+            state_events: rooms.join.values().map(|r| r.state.events.len()).sum::<usize>()
+                + rooms.leave.values().map(|r| r.state.events.len()).sum::<usize>()
+                + rooms.invite.values().map(|r| r.invite_state.events.len()).sum::<usize>(),
+            to_device_events: response.to_device.events.len(),
+            presence_events: response.presence.events.len(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A hook that receives the metrics of every sync response the client
+/// processes, e.g. to forward them to a monitoring system.
+///
+/// # Examples
+///
+/// ```
+/// # use matrix_sdk_base::{BaseClientConfig, MetricsExporter, SyncMetrics};
+/// # use std::sync::Arc;
+/// #[derive(Debug)]
+/// struct LogExporter;
+///
+/// impl MetricsExporter for LogExporter {
+///     fn export_sync_metrics(&self, metrics: &SyncMetrics) {
+///         println!("Processed a sync response in {:?}", metrics.total_time);
+///     }
+/// }
+///
+/// let config = BaseClientConfig::new().metrics_exporter(Arc::new(LogExporter));
+/// ```
+pub trait MetricsExporter: fmt::Debug + Send + Sync {
+    /// Export the metrics of a sync response that was just processed.
+    fn export_sync_metrics(&self, metrics: &SyncMetrics);
+}
+
+/// Collects the decryption metrics of the rooms of a sync response, the rooms
+/// are processed concurrently so the counters need to be shared.
+#[derive(Debug, Default)]
+pub(crate) struct DecryptionMetrics {
+    decrypted: AtomicUsize,
+    failed: AtomicUsize,
+    nanos: AtomicU64,
+}
+
+impl DecryptionMetrics {
+    #[cfg(feature = "encryption")]
+    pub fn record(&self, elapsed: Duration, success: bool) {
+        use std::convert::TryFrom;
+
+        if success {
+            self.decrypted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Move the collected metrics into the given sync metrics and reset the
+    /// counters for the next sync response.
+    pub fn take_into(&self, metrics: &mut SyncMetrics) {
+        metrics.decrypted_events = self.decrypted.swap(0, Ordering::Relaxed);
+        metrics.undecryptable_events = self.failed.swap(0, Ordering::Relaxed);
+        metrics.decryption_time = Duration::from_nanos(self.nanos.swap(0, Ordering::Relaxed));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use matrix_sdk_test::{async_test, response_from_file, test_json};
+    use ruma::{
+        api::{client::r0::sync::sync_events::Response as SyncResponse, IncomingResponse},
+        user_id,
+    };
+
+    use super::{MetricsExporter, SyncMetrics};
+    use crate::{BaseClient, BaseClientConfig, Session};
+
+    #[derive(Debug, Default)]
+    struct Exporter(Mutex<Vec<SyncMetrics>>);
+
+    impl MetricsExporter for Exporter {
+        fn export_sync_metrics(&self, metrics: &SyncMetrics) {
+            self.0.lock().unwrap().push(metrics.clone());
+        }
+    }
+
+    #[async_test]
+    async fn sync_metrics_are_exported() {
+        let exporter = Arc::new(Exporter::default());
+        let config = BaseClientConfig::new().metrics_exporter(exporter.clone());
+        let client = BaseClient::new_with_config(config).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+        };
+        client.restore_login(session).await.unwrap();
+
+        let response =
+            SyncResponse::try_from_http_response(response_from_file(&test_json::SYNC)).unwrap();
+        let expected = SyncMetrics::from_response(&response);
+        client.receive_sync_response(response, None).await.unwrap();
+
+        let exported = exporter.0.lock().unwrap();
+        assert_eq!(exported.len(), 1);
+
+        let metrics = &exported[0];
+        assert_eq!(metrics.joined_rooms, 1);
+        assert_eq!(metrics.timeline_events, expected.timeline_events);
+        assert_eq!(metrics.state_events, expected.state_events);
+        assert!(metrics.total_time >= metrics.store_write_time);
+    }
+}
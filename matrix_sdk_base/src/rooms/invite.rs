@@ -0,0 +1,59 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::{events::room::create::RoomType as CreateRoomType, MxcUri, RoomAliasId, RoomId, UserId};
+
+/// The details of an invite to a room.
+///
+/// The details are gathered from the stripped state the homeserver sends
+/// alongside the invite, they contain everything that is needed to render an
+/// invite before the room is joined.
+#[derive(Clone, Debug)]
+pub struct InvitedRoomInfo {
+    /// The id of the room the invite is for.
+    pub room_id: RoomId,
+    /// The `m.room.name` of the room.
+    pub name: Option<String>,
+    /// The canonical alias of the room.
+    pub canonical_alias: Option<RoomAliasId>,
+    /// The avatar URL of the room.
+    pub avatar_url: Option<MxcUri>,
+    /// The topic of the room.
+    pub topic: Option<String>,
+    /// Is the room encrypted.
+    pub is_encrypted: bool,
+    /// The type of the room, e.g. `m.space`, taken from the `m.room.create`
+    /// event.
+    pub room_type: Option<CreateRoomType>,
+    /// Is the invite for a direct message.
+    pub is_direct: bool,
+    /// The user that sent the invite.
+    ///
+    /// This is `None` if the stripped state doesn't contain the invite of our
+    /// own user.
+    pub inviter: Option<Inviter>,
+}
+
+/// The user that sent an invite to a room.
+#[derive(Clone, Debug)]
+pub struct Inviter {
+    /// The user id of the inviter.
+    pub user_id: UserId,
+    /// The display name the inviter uses in the room, if the stripped state
+    /// contains the member event of the inviter.
+    pub display_name: Option<String>,
+    /// The avatar URL the inviter uses in the room, if the stripped state
+    /// contains the member event of the inviter.
+    pub avatar_url: Option<MxcUri>,
+}
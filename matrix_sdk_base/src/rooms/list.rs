@@ -0,0 +1,154 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use ruma::RoomId;
+
+use super::{Room, RoomInfo};
+
+/// The fields of a `RoomInfo` that are relevant to present a room in a room
+/// list and that changed between two versions of the info.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoomInfoChanges {
+    /// The membership of our own user changed, i.e. the room type.
+    pub room_type: bool,
+    /// The name, the canonical alias or the summary changed, the display name
+    /// of the room might need to be recalculated.
+    pub name: bool,
+    /// The topic of the room changed.
+    pub topic: bool,
+    /// The avatar of the room changed.
+    pub avatar: bool,
+    /// The unread notification counts of the room changed.
+    pub notification_counts: bool,
+    /// A new event was received in the timeline of the room.
+    pub latest_event: bool,
+    /// The tags of the room changed.
+    pub tags: bool,
+}
+
+impl RoomInfoChanges {
+    /// Calculate which fields changed between the old and the new room info.
+    ///
+    /// Tags aren't part of the room info, the `tags` field will always be
+    /// false.
+    pub fn new(old: &RoomInfo, new: &RoomInfo) -> Self {
+        let latest_event = match (&old.latest_event, &new.latest_event) {
+            (Some(old), Some(new)) => old.event.json().get() != new.event.json().get(),
+            (None, None) => false,
+            _ => true,
+        };
+
+        Self {
+            room_type: old.room_type != new.room_type,
+            name: old.base_info.name != new.base_info.name
+                || old.base_info.canonical_alias != new.base_info.canonical_alias
+                || old.summary != new.summary,
+            topic: old.base_info.topic != new.base_info.topic,
+            avatar: old.base_info.avatar_url != new.base_info.avatar_url,
+            notification_counts: old.notification_counts.highlight_count
+                != new.notification_counts.highlight_count
+                || old.notification_counts.notification_count
+                    != new.notification_counts.notification_count,
+            latest_event,
+            tags: false,
+        }
+    }
+
+    /// Did none of the fields change.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A change of the list of rooms the client knows about.
+#[derive(Clone, Debug)]
+pub enum RoomListDiff {
+    /// A room was added to the room list.
+    Added(Room),
+    /// The info of a room in the room list changed.
+    Updated(Room, RoomInfoChanges),
+    /// The room with the given id was removed from the room list, e.g. because
+    /// it was forgotten.
+    Removed(RoomId),
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    senders: Vec<UnboundedSender<RoomListDiff>>,
+    known_rooms: BTreeSet<RoomId>,
+}
+
+impl Subscribers {
+    fn send(&mut self, diff: RoomListDiff) {
+        // Drop the senders of streams that are gone.
+        self.senders.retain(|s| s.unbounded_send(diff.clone()).is_ok());
+    }
+}
+
+/// Keeps track of the subscribers of room list changes and sends out the diffs
+/// once changes to rooms get applied.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RoomListNotifier {
+    inner: Arc<StdMutex<Subscribers>>,
+}
+
+impl RoomListNotifier {
+    pub fn subscribe(&self) -> UnboundedReceiver<RoomListDiff> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.lock().unwrap().senders.push(sender);
+
+        receiver
+    }
+
+    /// Remember a room that was restored from the store, restored rooms are
+    /// part of the initial room list and not announced as added.
+    pub fn room_restored(&self, room_id: &RoomId) {
+        self.inner.lock().unwrap().known_rooms.insert(room_id.clone());
+    }
+
+    /// Notify the subscribers that the info of the given room was replaced,
+    /// `old` is the info the room had before.
+    pub fn room_updated(&self, room: &Room, old: &RoomInfo, tags_changed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let diff = if inner.known_rooms.insert(room.room_id().clone()) {
+            RoomListDiff::Added(room.clone())
+        } else {
+            let mut changes = RoomInfoChanges::new(old, &room.clone_info());
+            changes.tags = tags_changed;
+
+            if changes.is_empty() {
+                return;
+            }
+
+            RoomListDiff::Updated(room.clone(), changes)
+        };
+
+        inner.send(diff);
+    }
+
+    pub fn room_removed(&self, room_id: &RoomId) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.known_rooms.remove(room_id) {
+            inner.send(RoomListDiff::Removed(room_id.clone()));
+        }
+    }
+}
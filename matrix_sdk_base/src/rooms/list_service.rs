@@ -0,0 +1,382 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp::Ordering, fmt, sync::Arc};
+
+use futures::{
+    channel::mpsc::UnboundedReceiver,
+    stream::{self, Stream, StreamExt},
+};
+use ruma::{
+    events::{room::create::RoomType as CreateRoomType, tag::TagName},
+    MilliSecondsSinceUnixEpoch, RoomId,
+};
+
+use super::{Room, RoomListDiff, RoomType};
+use crate::store::{Result as StoreResult, Store};
+
+/// The order in which a [`RoomListService`] sorts its rooms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomListOrder {
+    /// Rooms with the most recent latest event come first, rooms without a
+    /// known latest event come last.
+    Recency,
+    /// Rooms are sorted by their display name.
+    Alphabetical,
+    /// Rooms with unread notifications come first, both groups are sorted by
+    /// recency.
+    UnreadFirst,
+}
+
+/// A predicate that decides which rooms are part of a [`RoomListService`].
+#[derive(Clone)]
+pub enum RoomListFilter {
+    /// All the joined, invited and knocked rooms.
+    All,
+    /// Only spaces.
+    Spaces,
+    /// Only rooms that are tagged as favourite.
+    Favourites,
+    /// Only rooms that are tagged as low priority.
+    LowPriority,
+    /// Only direct message rooms.
+    People,
+    /// Only rooms that are neither direct message rooms nor spaces.
+    Rooms,
+    /// Only rooms we knocked on and are waiting to get invited to.
+    Knocked,
+    /// A custom predicate.
+    Custom(Arc<dyn Fn(&RoomListEntry) -> bool + Send + Sync>),
+}
+
+impl fmt::Debug for RoomListFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => write!(f, "All"),
+            Self::Spaces => write!(f, "Spaces"),
+            Self::Favourites => write!(f, "Favourites"),
+            Self::LowPriority => write!(f, "LowPriority"),
+            Self::People => write!(f, "People"),
+            Self::Rooms => write!(f, "Rooms"),
+            Self::Knocked => write!(f, "Knocked"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl RoomListFilter {
+    fn matches(&self, entry: &RoomListEntry) -> bool {
+        match self {
+            Self::All => true,
+            Self::Spaces => entry.is_space,
+            Self::Favourites => entry.is_favourite,
+            Self::LowPriority => entry.is_low_priority,
+            Self::People => entry.room.is_direct(),
+            Self::Rooms => !entry.room.is_direct() && !entry.is_space,
+            Self::Knocked => entry.room.room_type() == RoomType::Knocked,
+            Self::Custom(predicate) => predicate(entry),
+        }
+    }
+}
+
+/// A room in the list of a [`RoomListService`], together with the values the
+/// list is sorted and filtered by.
+///
+/// The values are a snapshot of the room at the time the entry was created,
+/// the entry is replaced once any of them change, which keeps the list sorted
+/// even if the room itself already changed.
+#[derive(Clone, Debug)]
+pub struct RoomListEntry {
+    /// The room this entry is for.
+    pub room: Room,
+    /// The display name of the room.
+    pub display_name: String,
+    /// The timestamp of the latest event of the room, if it's known.
+    pub latest_event_ts: Option<MilliSecondsSinceUnixEpoch>,
+    /// Is the room a space.
+    pub is_space: bool,
+    /// Is the room tagged as favourite.
+    pub is_favourite: bool,
+    /// Is the room tagged as low priority.
+    pub is_low_priority: bool,
+    /// Does the room have unread notifications.
+    pub has_unread: bool,
+}
+
+impl RoomListEntry {
+    async fn new(room: Room) -> StoreResult<Self> {
+        let display_name = room.display_name().await?;
+        let latest_event_ts =
+            room.latest_event().and_then(|e| e.event.get_field("origin_server_ts").ok().flatten());
+        let is_space = room
+            .create_content()
+            .map_or(false, |c| matches!(c.room_type, Some(CreateRoomType::Space)));
+        let tags = room.tags().await?.unwrap_or_default();
+        let counts = room.unread_notification_counts();
+
+        Ok(Self {
+            display_name,
+            latest_event_ts,
+            is_space,
+            is_favourite: tags.contains_key(&TagName::Favorite),
+            is_low_priority: tags.contains_key(&TagName::LowPriority),
+            has_unread: counts.notification_count > 0 || counts.highlight_count > 0,
+            room,
+        })
+    }
+
+    fn compare(&self, other: &Self, order: RoomListOrder) -> Ordering {
+        let by_recency = |a: &Self, b: &Self| match (a.latest_event_ts, b.latest_event_ts) {
+            (Some(a), Some(b)) => b.cmp(&a),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+
+        let ordering = match order {
+            RoomListOrder::Recency => by_recency(self, other),
+            RoomListOrder::Alphabetical => {
+                self.display_name.to_lowercase().cmp(&other.display_name.to_lowercase())
+            }
+            RoomListOrder::UnreadFirst => {
+                other.has_unread.cmp(&self.has_unread).then_with(|| by_recency(self, other))
+            }
+        };
+
+        // Fall back to the room id so the order is stable.
+        ordering.then_with(|| self.room.room_id().cmp(other.room.room_id()))
+    }
+}
+
+/// An incremental update of the list of a [`RoomListService`].
+///
+/// Indices refer to the list as it is after all the previous updates were
+/// applied.
+#[derive(Clone, Debug)]
+pub enum RoomListUpdate {
+    /// A room was inserted at the given index.
+    Insert {
+        /// The index the room was inserted at.
+        index: usize,
+        /// The entry of the inserted room.
+        entry: RoomListEntry,
+    },
+    /// The room at the given index changed but kept its position.
+    Update {
+        /// The index of the room.
+        index: usize,
+        /// The updated entry of the room.
+        entry: RoomListEntry,
+    },
+    /// The room at the given index was removed.
+    Remove {
+        /// The index of the removed room.
+        index: usize,
+    },
+    /// The whole list changed, e.g. because the order or the filter changed.
+    Reset(Vec<RoomListEntry>),
+}
+
+/// A sorted and filtered list of the rooms the client knows about that is kept
+/// up to date as the state of the rooms changes.
+///
+/// Left rooms are never part of the list.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use futures::executor::block_on;
+/// # use matrix_sdk_base::{BaseClient, RoomListFilter, RoomListOrder, RoomListService};
+/// # let client = BaseClient::new().unwrap();
+/// # block_on(async {
+/// let mut service = RoomListService::new(
+///     client.store().clone(),
+///     RoomListOrder::Recency,
+///     RoomListFilter::Favourites,
+/// )
+/// .await
+/// .unwrap();
+///
+/// for entry in service.entries() {
+///     println!("{}", entry.display_name);
+/// }
+///
+/// while let Some(updates) = service.next_updates().await {
+///     println!("The room list changed {:?}", updates.unwrap());
+/// }
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct RoomListService {
+    store: Store,
+    order: RoomListOrder,
+    filter: RoomListFilter,
+    entries: Vec<RoomListEntry>,
+    diffs: UnboundedReceiver<RoomListDiff>,
+}
+
+impl RoomListService {
+    /// Create a new room list service for the rooms of the given store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store of the client whose rooms should be listed.
+    ///
+    /// * `order` - The order the rooms should be sorted in.
+    ///
+    /// * `filter` - The filter that decides which rooms are part of the list.
+    pub async fn new(
+        store: Store,
+        order: RoomListOrder,
+        filter: RoomListFilter,
+    ) -> StoreResult<Self> {
+        // Subscribe before the rooms are loaded so no change gets lost.
+        let diffs = store.room_list.subscribe();
+        let mut service = Self { store, order, filter, entries: Vec::new(), diffs };
+        service.reload().await?;
+
+        Ok(service)
+    }
+
+    /// The current list of rooms.
+    pub fn entries(&self) -> &[RoomListEntry] {
+        &self.entries
+    }
+
+    /// The order the rooms are sorted in.
+    pub fn order(&self) -> RoomListOrder {
+        self.order
+    }
+
+    /// Change the order the rooms are sorted in.
+    ///
+    /// Returns a [`RoomListUpdate::Reset`] containing the newly sorted list.
+    pub fn set_order(&mut self, order: RoomListOrder) -> RoomListUpdate {
+        self.order = order;
+        self.entries.sort_by(|a, b| a.compare(b, order));
+
+        RoomListUpdate::Reset(self.entries.clone())
+    }
+
+    /// Change the filter that decides which rooms are part of the list.
+    ///
+    /// Returns a [`RoomListUpdate::Reset`] containing the new list.
+    pub async fn set_filter(&mut self, filter: RoomListFilter) -> StoreResult<RoomListUpdate> {
+        self.filter = filter;
+        self.reload().await?;
+
+        Ok(RoomListUpdate::Reset(self.entries.clone()))
+    }
+
+    /// Wait for the next change of the room list.
+    ///
+    /// Changes of rooms that don't affect the list, e.g. of rooms that don't
+    /// match the filter, are skipped. Returns `None` once the store is gone.
+    pub async fn next_updates(&mut self) -> Option<StoreResult<Vec<RoomListUpdate>>> {
+        loop {
+            let diff = self.diffs.next().await?;
+
+            match self.handle_diff(diff).await {
+                Ok(updates) if updates.is_empty() => continue,
+                ret => return Some(ret),
+            }
+        }
+    }
+
+    /// Turn the service into a stream of room list updates.
+    pub fn into_stream(self) -> impl Stream<Item = StoreResult<Vec<RoomListUpdate>>> {
+        stream::unfold(self, |mut service| async move {
+            let updates = service.next_updates().await?;
+            Some((updates, service))
+        })
+    }
+
+    async fn reload(&mut self) -> StoreResult<()> {
+        let mut entries = Vec::new();
+
+        for room in self.store.get_rooms() {
+            if room.room_type() == RoomType::Left {
+                continue;
+            }
+
+            let entry = RoomListEntry::new(room).await?;
+
+            if self.filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+
+        let order = self.order;
+        entries.sort_by(|a, b| a.compare(b, order));
+        self.entries = entries;
+
+        Ok(())
+    }
+
+    fn position(&self, room_id: &RoomId) -> Option<usize> {
+        self.entries.iter().position(|e| e.room.room_id() == room_id)
+    }
+
+    async fn handle_diff(&mut self, diff: RoomListDiff) -> StoreResult<Vec<RoomListUpdate>> {
+        let room = match diff {
+            RoomListDiff::Added(room) | RoomListDiff::Updated(room, _) => room,
+            RoomListDiff::Removed(room_id) => {
+                return Ok(self
+                    .position(&room_id)
+                    .map(|index| {
+                        self.entries.remove(index);
+                        vec![RoomListUpdate::Remove { index }]
+                    })
+                    .unwrap_or_default());
+            }
+        };
+
+        let old_index = self.position(room.room_id());
+
+        let entry = if room.room_type() != RoomType::Left {
+            Some(RoomListEntry::new(room).await?).filter(|e| self.filter.matches(e))
+        } else {
+            None
+        };
+
+        let mut updates = Vec::new();
+
+        if let Some(index) = old_index {
+            self.entries.remove(index);
+        }
+
+        if let Some(entry) = entry {
+            let order = self.order;
+            let index =
+                self.entries.binary_search_by(|e| e.compare(&entry, order)).unwrap_or_else(|i| i);
+
+            self.entries.insert(index, entry.clone());
+
+            match old_index {
+                Some(old_index) if old_index == index => {
+                    updates.push(RoomListUpdate::Update { index, entry })
+                }
+                Some(old_index) => {
+                    updates.push(RoomListUpdate::Remove { index: old_index });
+                    updates.push(RoomListUpdate::Insert { index, entry });
+                }
+                None => updates.push(RoomListUpdate::Insert { index, entry }),
+            }
+        } else if let Some(index) = old_index {
+            updates.push(RoomListUpdate::Remove { index });
+        }
+
+        Ok(updates)
+    }
+}
@@ -1,22 +1,37 @@
+mod invite;
+mod list;
+mod list_service;
 mod members;
 mod normal;
+mod power_levels;
+mod relations;
 
 use std::cmp::max;
 
+pub use invite::{InvitedRoomInfo, Inviter};
+pub(crate) use list::RoomListNotifier;
+pub use list::{RoomInfoChanges, RoomListDiff};
+pub use list_service::{
+    RoomListEntry, RoomListFilter, RoomListOrder, RoomListService, RoomListUpdate,
+};
 pub use members::RoomMember;
 pub use normal::{Room, RoomInfo, RoomType};
+pub use power_levels::{PowerLevelsChange, PowerLevelsDiff};
+pub use relations::{Aggregations, RelationType};
+pub(crate) use relations::{RelationEvent, RoomAggregations};
 use ruma::{
     events::{
         room::{
             create::CreateEventContent, encryption::EncryptionEventContent,
             guest_access::GuestAccess, history_visibility::HistoryVisibility, join_rules::JoinRule,
-            tombstone::TombstoneEventContent,
+            power_levels::PowerLevelsEventContent, tombstone::TombstoneEventContent,
         },
-        AnyStateEventContent,
+        AnyStateEventContent, EventType,
     },
-    MxcUri, RoomAliasId, UserId,
+    Int, MxcUri, RoomAliasId, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
 
 /// A base room info struct that is the backbone of normal as well as stripped
 /// rooms. Holds all the state events that are important to present a room to
@@ -27,6 +42,10 @@ pub struct BaseRoomInfo {
     pub avatar_url: Option<MxcUri>,
     /// The canonical alias of this room.
     pub canonical_alias: Option<RoomAliasId>,
+    /// The alternative aliases of this room that are advertised in its
+    /// `m.room.canonical_alias` event.
+    #[serde(default)]
+    pub alt_aliases: Vec<RoomAliasId>,
     /// The `m.room.create` event content of this room.
     pub create: Option<CreateEventContent>,
     /// The user id this room is sharing the direct message with, if the room is
@@ -40,10 +59,17 @@ pub struct BaseRoomInfo {
     pub history_visibility: HistoryVisibility,
     /// The join rule policy of this room.
     pub join_rule: JoinRule,
+    /// The rooms, usually spaces, whose members are allowed to join this room
+    /// if the join rule of the room is `restricted`.
+    #[serde(default)]
+    pub join_rule_allowed_rooms: Vec<RoomId>,
     /// The maximal power level that can be found in this room.
     pub max_power_level: i64,
     /// The `m.room.name` of this room.
     pub name: Option<String>,
+    /// The `m.room.power_levels` event content of this room.
+    #[serde(default)]
+    pub power_levels: Option<PowerLevelsEventContent>,
     /// The `m.room.tombstone` event content of this room.
     pub tombstone: Option<TombstoneEventContent>,
     /// The topic of this room.
@@ -69,6 +95,92 @@ impl BaseRoomInfo {
         )
     }
 
+    /// Get the power level of the given user.
+    ///
+    /// If the room doesn't have a `m.room.power_levels` event the creator of
+    /// the room has a power level of 100 while every other user has a power
+    /// level of 0.
+    pub fn user_power_level(&self, user_id: &UserId) -> i64 {
+        match &self.power_levels {
+            Some(p) => (*p.users.get(user_id).unwrap_or(&p.users_default)).into(),
+            None => {
+                if self.create.as_ref().map(|c| &c.creator == user_id).unwrap_or(false) {
+                    100
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// Get the power level that is required to send a message event of the
+    /// given type.
+    pub fn message_power_level(&self, event_type: &EventType) -> i64 {
+        match &self.power_levels {
+            Some(p) => (*p.events.get(event_type).unwrap_or(&p.events_default)).into(),
+            None => 0,
+        }
+    }
+
+    /// Get the power level that is required to send a state event of the given
+    /// type.
+    pub fn state_power_level(&self, event_type: &EventType) -> i64 {
+        match &self.power_levels {
+            Some(p) => (*p.events.get(event_type).unwrap_or(&p.state_default)).into(),
+            None => 0,
+        }
+    }
+
+    /// Get the power level that is required to perform the action that the
+    /// given closure selects out of the power levels, e.g. to kick users.
+    ///
+    /// Falls back to the given default if the room doesn't have a
+    /// `m.room.power_levels` event, the spec defaults are 0 for invites and 50
+    /// for every other action.
+    pub(crate) fn action_power_level(
+        &self,
+        action: impl Fn(&PowerLevelsEventContent) -> Int,
+        default: i64,
+    ) -> i64 {
+        self.power_levels.as_ref().map(|p| action(p).into()).unwrap_or(default)
+    }
+
+    /// Update the rooms that grant access to this room from the given raw
+    /// `m.room.join_rules` event.
+    ///
+    /// Ruma doesn't know about the `allow` field of restricted join rules yet,
+    /// so it has to be parsed from the raw event.
+    pub(crate) fn handle_join_rules_allow(&mut self, event: &RawJsonValue) {
+        #[derive(Deserialize)]
+        struct AllowRule {
+            #[serde(rename = "type")]
+            rule_type: String,
+            room_id: Option<RoomId>,
+        }
+
+        #[derive(Deserialize)]
+        struct JoinRulesContent {
+            #[serde(default)]
+            allow: Vec<AllowRule>,
+        }
+
+        #[derive(Deserialize)]
+        struct JoinRulesEvent {
+            content: JoinRulesContent,
+        }
+
+        self.join_rule_allowed_rooms = serde_json::from_str::<JoinRulesEvent>(event.get())
+            .map(|e| {
+                e.content
+                    .allow
+                    .into_iter()
+                    .filter(|r| r.rule_type == "m.room_membership")
+                    .filter_map(|r| r.room_id)
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+
     /// Handle a state event for this room and update our info accordingly.
     ///
     /// Returns true if the event modified the info, false otherwise.
@@ -108,6 +220,7 @@ impl BaseRoomInfo {
             }
             AnyStateEventContent::RoomCanonicalAlias(a) => {
                 self.canonical_alias = a.alias.clone();
+                self.alt_aliases = a.alt_aliases.clone();
                 true
             }
             AnyStateEventContent::RoomTopic(t) => {
@@ -122,6 +235,7 @@ impl BaseRoomInfo {
                 let max_power_level =
                     p.users.values().fold(self.max_power_level, |acc, p| max(acc, (*p).into()));
                 self.max_power_level = max_power_level;
+                self.power_levels = Some(p.clone());
                 true
             }
             _ => false,
@@ -134,14 +248,17 @@ impl Default for BaseRoomInfo {
         Self {
             avatar_url: None,
             canonical_alias: None,
+            alt_aliases: Vec::new(),
             create: None,
             dm_target: None,
             encryption: None,
             guest_access: GuestAccess::CanJoin,
             history_visibility: HistoryVisibility::WorldReadable,
             join_rule: JoinRule::Public,
+            join_rule_allowed_rooms: Vec::new(),
             max_power_level: 100,
             name: None,
+            power_levels: None,
             tombstone: None,
             topic: None,
         }
@@ -224,4 +341,68 @@ mod tests {
         actual = calculate_room_name(1, 0, vec!["a", "b", "c"]);
         assert_eq!("Empty room (was a, b, c)", actual);
     }
+
+    #[test]
+    fn test_default_power_levels() {
+        let creator = ruma::user_id!("@creator:localhost");
+        let user = ruma::user_id!("@user:localhost");
+
+        let mut info = BaseRoomInfo::new();
+        info.handle_state_event(&AnyStateEventContent::RoomCreate(CreateEventContent::new(
+            creator.clone(),
+        )));
+
+        assert_eq!(info.user_power_level(&creator), 100);
+        assert_eq!(info.user_power_level(&user), 0);
+        assert_eq!(info.message_power_level(&EventType::RoomMessage), 0);
+        assert_eq!(info.state_power_level(&EventType::RoomName), 0);
+        assert_eq!(info.action_power_level(|p| p.kick, 50), 50);
+        assert_eq!(info.action_power_level(|p| p.invite, 0), 0);
+    }
+
+    #[test]
+    fn test_power_levels() {
+        let moderator = ruma::user_id!("@moderator:localhost");
+        let user = ruma::user_id!("@user:localhost");
+
+        let mut power_levels = PowerLevelsEventContent::new();
+        power_levels.users.insert(moderator.clone(), ruma::int!(50));
+        power_levels.events.insert(EventType::RoomName, ruma::int!(100));
+        power_levels.events_default = ruma::int!(10);
+
+        let mut info = BaseRoomInfo::new();
+        info.handle_state_event(&AnyStateEventContent::RoomPowerLevels(power_levels));
+
+        assert_eq!(info.user_power_level(&moderator), 50);
+        assert_eq!(info.user_power_level(&user), 0);
+        assert_eq!(info.message_power_level(&EventType::RoomMessage), 10);
+        assert_eq!(info.state_power_level(&EventType::RoomName), 100);
+        assert_eq!(info.state_power_level(&EventType::RoomTopic), 50);
+        assert_eq!(info.action_power_level(|p| p.redact, 50), 50);
+        assert_eq!(info.action_power_level(|p| p.invite, 0), 50);
+    }
+
+    #[test]
+    fn test_restricted_join_rules() {
+        let event = serde_json::json!({
+            "content": {
+                "join_rule": "restricted",
+                "allow": [
+                    { "type": "m.room_membership", "room_id": "!space:localhost" },
+                    { "type": "org.example.unknown" }
+                ]
+            },
+            "event_id": "$join_rules:localhost",
+            "origin_server_ts": 152037280,
+            "sender": "@example:localhost",
+            "state_key": "",
+            "type": "m.room.join_rules"
+        });
+        let event = serde_json::value::to_raw_value(&event).unwrap();
+
+        let mut info = BaseRoomInfo::new();
+        info.handle_join_rules_allow(&event);
+
+        assert_eq!(info.join_rule_allowed_rooms, vec![ruma::room_id!("!space:localhost")]);
+    }
 }
@@ -26,22 +26,28 @@ use ruma::{
     events::{
         receipt::Receipt,
         room::{
-            create::CreateEventContent, encryption::EncryptionEventContent,
-            guest_access::GuestAccess, history_visibility::HistoryVisibility, join_rules::JoinRule,
+            create::{CreateEventContent, PreviousRoom},
+            encryption::EncryptionEventContent,
+            guest_access::GuestAccess,
+            history_visibility::HistoryVisibility,
+            join_rules::JoinRule,
+            member::MembershipState,
+            power_levels::PowerLevelsEventContent,
             tombstone::TombstoneEventContent,
         },
         tag::Tags,
         AnyRoomAccountDataEvent, AnyStateEventContent, AnySyncStateEvent, EventType,
     },
     receipt::ReceiptType,
-    EventId, MxcUri, RoomAliasId, RoomId, UserId,
+    EventId, Int, MxcUri, RoomAliasId, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
 use tracing::info;
 
-use super::{BaseRoomInfo, RoomMember};
+use super::{BaseRoomInfo, InvitedRoomInfo, Inviter, RoomMember};
 use crate::{
-    deserialized_responses::UnreadNotificationsCount,
+    deserialized_responses::{SyncRoomEvent, UnreadNotificationsCount},
     store::{Result as StoreResult, StateStore},
 };
 
@@ -52,12 +58,13 @@ pub struct Room {
     room_id: Arc<RoomId>,
     own_user_id: Arc<UserId>,
     inner: Arc<SyncRwLock<RoomInfo>>,
+    typing_users: Arc<SyncRwLock<Vec<UserId>>>,
     store: Arc<dyn StateStore>,
 }
 
 /// The room summary containing member counts and members that should be used to
 /// calculate the room display name.
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct RoomSummary {
     /// The heroes of the room, members that should be used for the room display
     /// name.
@@ -78,6 +85,9 @@ pub enum RoomType {
     Left,
     /// The room is in a invited state.
     Invited,
+    /// We knocked on the room and are waiting for a member of the room to
+    /// invite us.
+    Knocked,
 }
 
 impl Room {
@@ -97,6 +107,7 @@ impl Room {
             members_synced: false,
             last_prev_batch: None,
             base_info: BaseRoomInfo::new(),
+            latest_event: None,
         };
 
         Self::restore(own_user_id, store, room_info)
@@ -112,6 +123,7 @@ impl Room {
             room_id: room_info.room_id.clone(),
             store,
             inner: Arc::new(SyncRwLock::new(room_info)),
+            typing_users: Default::default(),
         }
     }
 
@@ -161,6 +173,12 @@ impl Room {
         self.inner.read().unwrap().base_info.canonical_alias.clone()
     }
 
+    /// Get the alternative aliases of this room, as advertised in its
+    /// `m.room.canonical_alias` event.
+    pub fn alt_aliases(&self) -> Vec<RoomAliasId> {
+        self.inner.read().unwrap().base_info.alt_aliases.clone()
+    }
+
     /// Get the `m.room.create` content of this room.
     ///
     /// This usually isn't optional but some servers might not send an
@@ -216,6 +234,32 @@ impl Room {
         self.inner.read().unwrap().base_info.join_rule.clone()
     }
 
+    /// Is the join rule of this room `restricted`.
+    ///
+    /// Members of the rooms returned by
+    /// [`join_rule_allowed_rooms()`](#method.join_rule_allowed_rooms) can join
+    /// a restricted room without being invited.
+    pub fn is_restricted(&self) -> bool {
+        self.join_rule().as_ref() == "restricted"
+    }
+
+    /// Can users knock on this room to ask for an invite.
+    pub fn is_knockable(&self) -> bool {
+        matches!(self.join_rule(), JoinRule::Knock)
+    }
+
+    /// Get the rooms, usually spaces, whose members are allowed to join this
+    /// room if its join rule is `restricted`.
+    pub fn join_rule_allowed_rooms(&self) -> Vec<RoomId> {
+        let info = self.inner.read().unwrap();
+
+        if info.base_info.join_rule.as_ref() == "restricted" {
+            info.base_info.join_rule_allowed_rooms.clone()
+        } else {
+            Vec::new()
+        }
+    }
+
     /// Get the maximum power level that this room contains.
     ///
     /// This is useful if one wishes to normalize the power levels, e.g. from
@@ -224,6 +268,82 @@ impl Room {
         self.inner.read().unwrap().base_info.max_power_level
     }
 
+    /// Get the `m.room.power_levels` content of this room if there is one.
+    pub fn power_levels(&self) -> Option<PowerLevelsEventContent> {
+        self.inner.read().unwrap().base_info.power_levels.clone()
+    }
+
+    /// Get the power level of the given user in this room.
+    ///
+    /// This takes the default power levels into account if the room doesn't
+    /// have a `m.room.power_levels` event.
+    pub fn user_power_level(&self, user_id: &UserId) -> i64 {
+        self.inner.read().unwrap().base_info.user_power_level(user_id)
+    }
+
+    /// Can the given user send message events of the given type to this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The id of the user that wants to send the event.
+    ///
+    /// * `event_type` - The type of the message event, e.g.
+    /// `EventType::RoomMessage`.
+    pub fn can_user_send_message(&self, user_id: &UserId, event_type: EventType) -> bool {
+        let info = self.inner.read().unwrap();
+        info.base_info.user_power_level(user_id) >= info.base_info.message_power_level(&event_type)
+    }
+
+    /// Can the given user send state events of the given type to this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The id of the user that wants to send the event.
+    ///
+    /// * `event_type` - The type of the state event, e.g.
+    /// `EventType::RoomName`.
+    pub fn can_user_send_state(&self, user_id: &UserId, event_type: EventType) -> bool {
+        let info = self.inner.read().unwrap();
+        info.base_info.user_power_level(user_id) >= info.base_info.state_power_level(&event_type)
+    }
+
+    /// Can the given user invite other users to this room.
+    pub fn can_user_invite(&self, user_id: &UserId) -> bool {
+        self.has_action_power_level(user_id, |p| p.invite, 0)
+    }
+
+    /// Can the given user kick other users out of this room.
+    ///
+    /// Note that a user can only kick users that have a lower power level.
+    pub fn can_user_kick(&self, user_id: &UserId) -> bool {
+        self.has_action_power_level(user_id, |p| p.kick, 50)
+    }
+
+    /// Can the given user ban other users from this room.
+    ///
+    /// Note that a user can only ban users that have a lower power level.
+    pub fn can_user_ban(&self, user_id: &UserId) -> bool {
+        self.has_action_power_level(user_id, |p| p.ban, 50)
+    }
+
+    /// Can the given user redact events that were sent by other users.
+    ///
+    /// Users are always allowed to redact their own events.
+    pub fn can_user_redact(&self, user_id: &UserId) -> bool {
+        self.has_action_power_level(user_id, |p| p.redact, 50)
+    }
+
+    fn has_action_power_level(
+        &self,
+        user_id: &UserId,
+        action: impl Fn(&PowerLevelsEventContent) -> Int,
+        default: i64,
+    ) -> bool {
+        let info = self.inner.read().unwrap();
+        info.base_info.user_power_level(user_id)
+            >= info.base_info.action_power_level(action, default)
+    }
+
     /// Get the `m.room.name` of this room.
     pub fn name(&self) -> Option<String> {
         self.inner.read().unwrap().base_info.name.clone()
@@ -239,6 +359,24 @@ impl Room {
         self.inner.read().unwrap().base_info.tombstone.clone()
     }
 
+    /// Get the id of the room that replaced this room if the room was
+    /// upgraded.
+    pub fn successor(&self) -> Option<RoomId> {
+        self.inner.read().unwrap().base_info.tombstone.as_ref().map(|t| t.replacement_room.clone())
+    }
+
+    /// Get the room that this room replaced if the room was created by an
+    /// upgrade, together with the id of the last event of the old room.
+    pub fn predecessor(&self) -> Option<PreviousRoom> {
+        self.inner.read().unwrap().base_info.create.as_ref().and_then(|c| c.predecessor.clone())
+    }
+
+    /// Get the latest event of the room timeline that was received in a sync
+    /// since the client was started.
+    pub fn latest_event(&self) -> Option<SyncRoomEvent> {
+        self.inner.read().unwrap().latest_event.clone()
+    }
+
     /// Get the topic of the room.
     pub fn topic(&self) -> Option<String> {
         self.inner.read().unwrap().base_info.topic.clone()
@@ -254,6 +392,21 @@ impl Room {
         self.calculate_name().await
     }
 
+    /// The number of members that are considered to be joined to this room,
+    /// as reported by the room summary of the homeserver.
+    ///
+    /// This can differ from the number of joined members in the store if
+    /// members are lazy loaded.
+    pub fn joined_members_count(&self) -> u64 {
+        self.inner.read().unwrap().summary.joined_member_count
+    }
+
+    /// The number of members that are considered to be invited to this room,
+    /// as reported by the room summary of the homeserver.
+    pub fn invited_members_count(&self) -> u64 {
+        self.inner.read().unwrap().summary.invited_member_count
+    }
+
     /// Get the list of users ids that are considered to be joined members of
     /// this room.
     pub async fn joined_user_ids(&self) -> StoreResult<Vec<UserId>> {
@@ -372,6 +525,18 @@ impl Room {
         *inner = summary;
     }
 
+    /// Get the list of users that are currently typing in this room.
+    ///
+    /// The list is replaced every time a `m.typing` event is received for this
+    /// room and is not persisted in the store.
+    pub fn typing_users(&self) -> Vec<UserId> {
+        self.typing_users.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_typing_users(&self, user_ids: Vec<UserId>) {
+        *self.typing_users.write().unwrap() = user_ids;
+    }
+
     /// Get the `RoomMember` with the given `user_id`.
     ///
     /// Returns `None` if the member was never part of this room, otherwise
@@ -433,6 +598,55 @@ impl Room {
         }))
     }
 
+    /// Get the details of the invite to this room, e.g. the name and topic of
+    /// the room and the profile of the user that sent the invite.
+    ///
+    /// The details are gathered from the stripped state the homeserver sent
+    /// alongside the invite.
+    ///
+    /// Returns `None` if the room isn't in the invited state.
+    pub async fn invite_details(&self) -> StoreResult<Option<InvitedRoomInfo>> {
+        if self.room_type() != RoomType::Invited {
+            return Ok(None);
+        }
+
+        let own_member = self
+            .store
+            .get_stripped_member_event(self.room_id(), self.own_user_id())
+            .await?
+            .filter(|m| m.content.membership == MembershipState::Invite);
+
+        let inviter = if let Some(member) = &own_member {
+            let profile = self
+                .store
+                .get_stripped_member_event(self.room_id(), &member.sender)
+                .await?
+                .map(|m| m.content);
+
+            Some(Inviter {
+                user_id: member.sender.clone(),
+                display_name: profile.as_ref().and_then(|p| p.displayname.clone()),
+                avatar_url: profile.and_then(|p| p.avatar_url),
+            })
+        } else {
+            None
+        };
+
+        let info = self.inner.read().unwrap();
+
+        Ok(Some(InvitedRoomInfo {
+            room_id: self.room_id().clone(),
+            name: info.base_info.name.clone(),
+            canonical_alias: info.base_info.canonical_alias.clone(),
+            avatar_url: info.base_info.avatar_url.clone(),
+            topic: info.base_info.topic.clone(),
+            is_encrypted: info.is_encrypted(),
+            room_type: info.base_info.create.as_ref().and_then(|c| c.room_type.clone()),
+            is_direct: own_member.and_then(|m| m.content.is_direct).unwrap_or(false),
+            inviter,
+        }))
+    }
+
     /// Get the `Tags` for this room.
     pub async fn tags(&self) -> StoreResult<Option<Tags>> {
         if let Some(AnyRoomAccountDataEvent::Tag(event)) = self
@@ -447,6 +661,23 @@ impl Room {
         }
     }
 
+    /// Check if the event with the given id was already received in this room,
+    /// either in a sync response or while paginating the room history.
+    pub async fn is_event_known(&self, event_id: &EventId) -> StoreResult<bool> {
+        self.store.is_event_known(self.room_id(), event_id).await
+    }
+
+    /// Get the id of the event we sent to this room with the given
+    /// transaction id.
+    ///
+    /// The id is known once the homeserver responded to the send request or
+    /// once the event came down a sync response. This allows a send that is
+    /// repeated with the same transaction id, e.g. after a crash, to be
+    /// correlated with the event that was already sent.
+    pub async fn event_id_for_transaction(&self, txn_id: &str) -> StoreResult<Option<EventId>> {
+        self.store.get_event_id_for_transaction(self.room_id(), txn_id).await
+    }
+
     /// Get the read receipt as a `EventId` and `Receipt` tuple for the given
     /// `user_id` in this room.
     pub async fn user_read_receipt(
@@ -486,6 +717,12 @@ pub struct RoomInfo {
     /// Base room info which holds some basic event contents important for the
     /// room state.
     pub base_info: BaseRoomInfo,
+    /// The latest event of the room timeline that was received in a sync.
+    ///
+    /// The event isn't persisted, it's only known if the room received an
+    /// event since the client was started.
+    #[serde(skip)]
+    pub latest_event: Option<SyncRoomEvent>,
 }
 
 impl RoomInfo {
@@ -501,6 +738,10 @@ impl RoomInfo {
         self.room_type = RoomType::Invited;
     }
 
+    pub(crate) fn mark_as_knocked(&mut self) {
+        self.room_type = RoomType::Knocked;
+    }
+
     pub(crate) fn mark_members_synced(&mut self) {
         self.members_synced = true;
     }
@@ -526,6 +767,10 @@ impl RoomInfo {
         self.base_info.handle_state_event(event)
     }
 
+    pub(crate) fn handle_join_rules_allow(&mut self, event: &RawJsonValue) {
+        self.base_info.handle_join_rules_allow(event)
+    }
+
     pub(crate) fn update_notification_count(
         &mut self,
         notification_counts: UnreadNotificationsCount,
@@ -556,6 +801,11 @@ impl RoomInfo {
         changed
     }
 
+    pub(crate) fn set_member_counts(&mut self, joined: u64, invited: u64) {
+        self.summary.joined_member_count = joined;
+        self.summary.invited_member_count = invited;
+    }
+
     /// The number of active members (invited + joined) in the room.
     ///
     /// The return value is saturated at `u64::MAX`.
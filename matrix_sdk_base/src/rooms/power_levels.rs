@@ -0,0 +1,190 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{
+    events::{room::power_levels::PowerLevelsEventContent, EventType},
+    Int, UserId,
+};
+
+/// The difference between two versions of the `m.room.power_levels` event
+/// content of a room.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowerLevelsDiff {
+    /// The users whose power level changed, mapped to their old and new power
+    /// level.
+    ///
+    /// Users that aren't explicitly listed in the power levels are considered
+    /// to have the `users_default` power level.
+    pub users: BTreeMap<UserId, (Int, Int)>,
+    /// The event types whose required power level changed, mapped to their old
+    /// and new power level.
+    ///
+    /// `None` means that the event type wasn't explicitly listed and the
+    /// default for message or state events applies.
+    pub events: BTreeMap<EventType, (Option<Int>, Option<Int>)>,
+}
+
+impl PowerLevelsDiff {
+    /// Calculate the difference between the old and the new power levels.
+    pub fn new(old: &PowerLevelsEventContent, new: &PowerLevelsEventContent) -> Self {
+        let user_ids: BTreeSet<&UserId> = old.users.keys().chain(new.users.keys()).collect();
+        let users = user_ids
+            .into_iter()
+            .filter_map(|user_id| {
+                let old_level = *old.users.get(user_id).unwrap_or(&old.users_default);
+                let new_level = *new.users.get(user_id).unwrap_or(&new.users_default);
+
+                (old_level != new_level).then(|| (user_id.clone(), (old_level, new_level)))
+            })
+            .collect();
+
+        let event_types: BTreeSet<&EventType> =
+            old.events.keys().chain(new.events.keys()).collect();
+        let events = event_types
+            .into_iter()
+            .filter_map(|event_type| {
+                let old_level = old.events.get(event_type).copied();
+                let new_level = new.events.get(event_type).copied();
+
+                (old_level != new_level).then(|| (event_type.clone(), (old_level, new_level)))
+            })
+            .collect();
+
+        Self { users, events }
+    }
+
+    /// Are there any changes in the user or event power levels.
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.events.is_empty()
+    }
+}
+
+/// A change of the power levels of a room that affects our own user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerLevelsChange {
+    /// The difference between the old and the new power levels.
+    pub diff: PowerLevelsDiff,
+    /// Our power level before the change.
+    pub old_power_level: Int,
+    /// Our power level after the change.
+    pub new_power_level: Int,
+    /// Could we send `m.room.message` events before the change.
+    pub could_send_messages: bool,
+    /// Can we send `m.room.message` events after the change.
+    pub can_send_messages: bool,
+}
+
+impl PowerLevelsChange {
+    /// Calculate how a power levels change affects the given user.
+    ///
+    /// Returns `None` if neither the power level of the user nor their ability
+    /// to send messages changed.
+    pub fn new(
+        user_id: &UserId,
+        old: &PowerLevelsEventContent,
+        new: &PowerLevelsEventContent,
+    ) -> Option<Self> {
+        let old_power_level = *old.users.get(user_id).unwrap_or(&old.users_default);
+        let new_power_level = *new.users.get(user_id).unwrap_or(&new.users_default);
+
+        let can_send = |levels: &PowerLevelsEventContent, user_level: Int| {
+            user_level
+                >= *levels.events.get(&EventType::RoomMessage).unwrap_or(&levels.events_default)
+        };
+
+        let could_send_messages = can_send(old, old_power_level);
+        let can_send_messages = can_send(new, new_power_level);
+
+        if old_power_level == new_power_level && could_send_messages == can_send_messages {
+            None
+        } else {
+            Some(Self {
+                diff: PowerLevelsDiff::new(old, new),
+                old_power_level,
+                new_power_level,
+                could_send_messages,
+                can_send_messages,
+            })
+        }
+    }
+
+    /// Did our power level increase.
+    pub fn is_promotion(&self) -> bool {
+        self.new_power_level > self.old_power_level
+    }
+
+    /// Did our power level decrease.
+    pub fn is_demotion(&self) -> bool {
+        self.new_power_level < self.old_power_level
+    }
+
+    /// Did we lose the ability to send messages with this change.
+    pub fn lost_send_permission(&self) -> bool {
+        self.could_send_messages && !self.can_send_messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{events::room::power_levels::PowerLevelsEventContent, int, user_id};
+
+    use super::{PowerLevelsChange, PowerLevelsDiff};
+
+    #[test]
+    fn power_levels_diff() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let mut old = PowerLevelsEventContent::new();
+        old.users.insert(alice.clone(), int!(100));
+        old.users.insert(bob.clone(), int!(50));
+
+        let mut new = old.clone();
+        assert!(PowerLevelsDiff::new(&old, &new).is_empty());
+
+        new.users.remove(&bob);
+        let diff = PowerLevelsDiff::new(&old, &new);
+        assert_eq!(diff.users.get(&bob), Some(&(int!(50), int!(0))));
+        assert!(!diff.users.contains_key(&alice));
+        assert!(diff.events.is_empty());
+    }
+
+    #[test]
+    fn own_power_levels_change() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let mut old = PowerLevelsEventContent::new();
+        old.users.insert(alice.clone(), int!(100));
+
+        let mut new = old.clone();
+        new.users.insert(bob.clone(), int!(50));
+
+        assert!(PowerLevelsChange::new(&alice, &old, &new).is_none());
+
+        let change = PowerLevelsChange::new(&bob, &old, &new).unwrap();
+        assert!(change.is_promotion());
+        assert!(!change.lost_send_permission());
+
+        let mut muted = new.clone();
+        muted.users.remove(&bob);
+        muted.events_default = int!(10);
+
+        let change = PowerLevelsChange::new(&bob, &new, &muted).unwrap();
+        assert!(change.is_demotion());
+        assert!(change.lost_send_permission());
+    }
+}
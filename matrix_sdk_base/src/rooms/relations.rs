@@ -0,0 +1,431 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+};
+
+use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+use ruma::{EventId, MilliSecondsSinceUnixEpoch, UserId};
+use serde::Deserialize;
+
+/// The maximum number of events per room whose aggregations are kept, the
+/// aggregations of the oldest events are dropped first.
+const MAX_AGGREGATED_EVENTS: usize = 1000;
+/// The maximum number of events per room whose senders are remembered to
+/// validate replacements.
+const MAX_KNOWN_SENDERS: usize = 1000;
+/// The maximum number of replacements of an event that are kept until the
+/// sender of the event is known.
+const MAX_UNVERIFIED_REPLACEMENTS: usize = 16;
+
+/// The type of a relation between two events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RelationType {
+    /// An annotation of the related event, e.g. a reaction.
+    Annotation,
+    /// A replacement of the related event, e.g. an edit.
+    Replacement,
+    /// A reference to the related event.
+    Reference,
+    /// A reply in the thread the related event started.
+    Thread,
+}
+
+impl RelationType {
+    /// The string representation of the relation type, as used in the
+    /// `rel_type` field of events.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Annotation => "m.annotation",
+            Self::Replacement => "m.replace",
+            Self::Reference => "m.reference",
+            Self::Thread => "m.thread",
+        }
+    }
+
+    fn from_rel_type(rel_type: &str) -> Option<Self> {
+        match rel_type {
+            "m.annotation" => Some(Self::Annotation),
+            "m.replace" => Some(Self::Replacement),
+            "m.reference" => Some(Self::Reference),
+            // Threads are still unstable, servers might use the prefixed type.
+            "m.thread" | "io.element.thread" => Some(Self::Thread),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RelationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct RelatesTo {
+    rel_type: Option<String>,
+    event_id: Option<EventId>,
+    key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct RelationContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Option<RelatesTo>,
+}
+
+/// The parts of a room event that are needed to aggregate it.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct RelationEvent {
+    event_id: EventId,
+    sender: UserId,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    #[serde(default)]
+    content: RelationContent,
+    redacts: Option<EventId>,
+}
+
+impl RelationEvent {
+    /// The id of the related event and the type of the relation, if the event
+    /// relates to another event.
+    pub fn relation(&self) -> Option<(&EventId, RelationType)> {
+        let relates_to = self.content.relates_to.as_ref()?;
+        let rel_type = RelationType::from_rel_type(relates_to.rel_type.as_deref()?)?;
+
+        Some((relates_to.event_id.as_ref()?, rel_type))
+    }
+
+    /// The id of the event this event redacts, if it's a redaction.
+    pub fn redacts(&self) -> Option<&EventId> {
+        self.redacts.as_ref()
+    }
+}
+
+/// The events that relate to an event, aggregated from the events the client
+/// received so far.
+///
+/// Replacements are only aggregated if they were sent by the sender of the
+/// event they replace.
+#[derive(Clone, Debug, Default)]
+pub struct Aggregations {
+    /// The annotations of the event, e.g. reactions, grouped by their key.
+    ///
+    /// Maps the id of every annotation to its sender.
+    pub annotations: BTreeMap<String, BTreeMap<EventId, UserId>>,
+    /// The ids of the events that replace the event, e.g. edits.
+    pub replacements: BTreeSet<EventId>,
+    /// The most recent event that replaces the event.
+    pub latest_replacement: Option<SyncRoomEvent>,
+    latest_replacement_ts: Option<MilliSecondsSinceUnixEpoch>,
+    /// The ids of the events that reference the event.
+    pub references: BTreeSet<EventId>,
+    /// The ids of the replies in the thread the event started.
+    pub thread_replies: BTreeSet<EventId>,
+    /// The sender of the event, once it's known.
+    sender: Option<UserId>,
+    /// Replacements that were received before the sender of the event was
+    /// known.
+    unverified_replacements: Vec<(RelationEvent, SyncRoomEvent)>,
+}
+
+impl Aggregations {
+    /// Set the sender of the event, replacements that were sent by someone
+    /// else are dropped.
+    fn set_sender(&mut self, sender: &UserId) {
+        if self.sender.is_some() {
+            return;
+        }
+
+        self.sender = Some(sender.clone());
+
+        for (relation, event) in std::mem::take(&mut self.unverified_replacements) {
+            self.add(&relation, RelationType::Replacement, &event);
+        }
+    }
+
+    /// Add a related event to the aggregations.
+    ///
+    /// Adding an event that is already part of the aggregations is a no-op.
+    /// Replacements are only accepted from the sender of the event.
+    fn add(&mut self, relation: &RelationEvent, rel_type: RelationType, event: &SyncRoomEvent) {
+        let event_id = relation.event_id.clone();
+
+        match rel_type {
+            RelationType::Annotation => {
+                if let Some(key) = relation.content.relates_to.as_ref().and_then(|r| r.key.clone())
+                {
+                    self.annotations
+                        .entry(key)
+                        .or_default()
+                        .insert(event_id, relation.sender.clone());
+                }
+            }
+            RelationType::Replacement => {
+                match &self.sender {
+                    Some(sender) if sender != &relation.sender => return,
+                    Some(_) => {}
+                    None => {
+                        if self.unverified_replacements.len() < MAX_UNVERIFIED_REPLACEMENTS
+                            && self
+                                .unverified_replacements
+                                .iter()
+                                .all(|(r, _)| r.event_id != event_id)
+                        {
+                            self.unverified_replacements.push((relation.clone(), event.clone()));
+                        }
+
+                        return;
+                    }
+                }
+
+                if self.latest_replacement_ts.map_or(true, |ts| ts <= relation.origin_server_ts) {
+                    self.latest_replacement = Some(event.clone());
+                    self.latest_replacement_ts = Some(relation.origin_server_ts);
+                }
+
+                self.replacements.insert(event_id);
+            }
+            RelationType::Reference => {
+                self.references.insert(event_id);
+            }
+            RelationType::Thread => {
+                self.thread_replies.insert(event_id);
+            }
+        }
+    }
+
+    /// Remove a related event that was redacted.
+    fn remove(&mut self, event_id: &EventId) {
+        self.unverified_replacements.retain(|(r, _)| &r.event_id != event_id);
+
+        for annotations in self.annotations.values_mut() {
+            annotations.remove(event_id);
+        }
+        self.annotations.retain(|_, a| !a.is_empty());
+
+        if self.replacements.remove(event_id) {
+            let latest_id = self
+                .latest_replacement
+                .as_ref()
+                .and_then(|e| e.event.get_field::<EventId>("event_id").ok().flatten());
+
+            // The latest replacement was redacted, fetching the replacements
+            // again restores the one before it.
+            if latest_id.as_ref() == Some(event_id) {
+                self.latest_replacement = None;
+                self.latest_replacement_ts = None;
+            }
+        }
+
+        self.references.remove(event_id);
+        self.thread_replies.remove(event_id);
+    }
+
+    /// Are there no related events.
+    pub fn is_empty(&self) -> bool {
+        self.annotations.is_empty()
+            && self.replacements.is_empty()
+            && self.references.is_empty()
+            && self.thread_replies.is_empty()
+            && self.unverified_replacements.is_empty()
+    }
+
+    /// The number of annotations with the given key, e.g. the number of
+    /// reactions with a given emoji.
+    pub fn annotation_count(&self, key: &str) -> usize {
+        self.annotations.get(key).map_or(0, |a| a.len())
+    }
+}
+
+/// The aggregations of the events of a room.
+///
+/// The number of aggregated events and of remembered senders is capped, the
+/// oldest entries are dropped first.
+#[derive(Debug, Default)]
+pub(crate) struct RoomAggregations {
+    aggregations: BTreeMap<EventId, Aggregations>,
+    /// The ids of the events in `aggregations`, the oldest first.
+    aggregation_order: VecDeque<EventId>,
+    /// The senders of the events that were seen recently.
+    senders: BTreeMap<EventId, UserId>,
+    /// The ids of the events in `senders`, the oldest first.
+    sender_order: VecDeque<EventId>,
+}
+
+impl RoomAggregations {
+    /// Get the aggregations of the given event.
+    pub fn get(&self, event_id: &EventId) -> Option<&Aggregations> {
+        self.aggregations.get(event_id)
+    }
+
+    /// Add the given event to the aggregations of the event it relates to, or
+    /// remove the event it redacts from the aggregations.
+    pub fn add(&mut self, relation: &RelationEvent, event: &SyncRoomEvent) {
+        self.remember_sender(relation);
+
+        if let Some((related_event_id, rel_type)) = relation.relation() {
+            let sender = self.senders.get(related_event_id).cloned();
+            let aggregations = self.get_or_insert(related_event_id);
+
+            if let Some(sender) = sender {
+                aggregations.set_sender(&sender);
+            }
+
+            aggregations.add(relation, rel_type, event);
+        } else if let Some(redacted) = relation.redacts() {
+            self.aggregations.remove(redacted);
+
+            for a in self.aggregations.values_mut() {
+                a.remove(redacted);
+            }
+
+            self.aggregations.retain(|_, a| !a.is_empty());
+            let aggregations = &self.aggregations;
+            self.aggregation_order.retain(|e| aggregations.contains_key(e));
+        }
+    }
+
+    fn remember_sender(&mut self, relation: &RelationEvent) {
+        if let Some(aggregations) = self.aggregations.get_mut(&relation.event_id) {
+            aggregations.set_sender(&relation.sender);
+        }
+
+        if self.senders.insert(relation.event_id.clone(), relation.sender.clone()).is_none() {
+            self.sender_order.push_back(relation.event_id.clone());
+
+            if self.sender_order.len() > MAX_KNOWN_SENDERS {
+                if let Some(oldest) = self.sender_order.pop_front() {
+                    self.senders.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    fn get_or_insert(&mut self, event_id: &EventId) -> &mut Aggregations {
+        if !self.aggregations.contains_key(event_id) {
+            if self.aggregation_order.len() >= MAX_AGGREGATED_EVENTS {
+                if let Some(oldest) = self.aggregation_order.pop_front() {
+                    self.aggregations.remove(&oldest);
+                }
+            }
+
+            self.aggregation_order.push_back(event_id.clone());
+        }
+
+        self.aggregations.entry(event_id.clone()).or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+    use ruma::{event_id, events::AnySyncRoomEvent, serde::Raw, EventId};
+    use serde_json::json;
+
+    use super::{RelationEvent, RoomAggregations, MAX_AGGREGATED_EVENTS};
+
+    fn event(event_id: &str, sender: &str, relates_to: Option<(&str, &str)>) -> SyncRoomEvent {
+        let mut content = json!({ "body": "hello", "msgtype": "m.text" });
+
+        if let Some((rel_type, related)) = relates_to {
+            content["m.relates_to"] = json!({ "rel_type": rel_type, "event_id": related });
+        }
+
+        let event = json!({
+            "content": content,
+            "event_id": event_id,
+            "origin_server_ts": 152037280,
+            "sender": sender,
+            "type": "m.room.message"
+        });
+
+        Raw::<AnySyncRoomEvent>::from_json(serde_json::value::to_raw_value(&event).unwrap()).into()
+    }
+
+    fn add(aggregations: &mut RoomAggregations, event: SyncRoomEvent) {
+        let relation = event.event.deserialize_as::<RelationEvent>().unwrap();
+        aggregations.add(&relation, &event);
+    }
+
+    #[test]
+    fn replacements_need_the_same_sender() {
+        let mut aggregations = RoomAggregations::default();
+        let original = event_id!("$original:localhost");
+
+        add(&mut aggregations, event("$original:localhost", "@alice:localhost", None));
+        add(
+            &mut aggregations,
+            event(
+                "$forged:localhost",
+                "@mallory:localhost",
+                Some(("m.replace", original.as_str())),
+            ),
+        );
+
+        assert!(aggregations.get(&original).map_or(true, |a| a.latest_replacement.is_none()));
+
+        add(
+            &mut aggregations,
+            event("$edit:localhost", "@alice:localhost", Some(("m.replace", original.as_str()))),
+        );
+
+        let a = aggregations.get(&original).unwrap();
+        assert_eq!(a.replacements.len(), 1);
+        assert!(a.replacements.contains(&event_id!("$edit:localhost")));
+
+        // Replacements that arrive before the original are only accepted once
+        // the sender of the original is known.
+        let later = event_id!("$later:localhost");
+
+        add(
+            &mut aggregations,
+            event("$forged2:localhost", "@mallory:localhost", Some(("m.replace", later.as_str()))),
+        );
+        add(
+            &mut aggregations,
+            event("$edit2:localhost", "@alice:localhost", Some(("m.replace", later.as_str()))),
+        );
+        assert!(aggregations.get(&later).unwrap().latest_replacement.is_none());
+
+        add(&mut aggregations, event("$later:localhost", "@alice:localhost", None));
+
+        let a = aggregations.get(&later).unwrap();
+        assert_eq!(a.replacements.len(), 1);
+        assert!(a.replacements.contains(&event_id!("$edit2:localhost")));
+        assert!(a.latest_replacement.is_some());
+    }
+
+    #[test]
+    fn aggregations_are_capped() {
+        let mut aggregations = RoomAggregations::default();
+
+        for i in 0..=MAX_AGGREGATED_EVENTS {
+            let related = format!("$event{}:localhost", i);
+            let event_id = format!("$reference{}:localhost", i);
+
+            add(
+                &mut aggregations,
+                event(&event_id, "@alice:localhost", Some(("m.reference", related.as_str()))),
+            );
+        }
+
+        assert_eq!(aggregations.aggregations.len(), MAX_AGGREGATED_EVENTS);
+        assert!(aggregations.get(&EventId::try_from("$event0:localhost").unwrap()).is_none());
+        assert!(aggregations.get(&EventId::try_from("$event1:localhost").unwrap()).is_some());
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-memory LRU cache in front of a `StateStore`.
+//!
+//! Room members, their profiles and state events like the power levels are
+//! looked up over and over again, e.g. every time a display name gets
+//! calculated. The cache keeps the most recently used ones around so they
+//! don't need to be loaded and deserialized from the store every time.
+//!
+//! Room infos don't need to be cached here, the `Store` keeps all of them in
+//! memory as part of the `Room` objects.
+
+use std::{collections::BTreeSet, fmt, sync::Arc};
+
+use lru::LruCache;
+use matrix_sdk_common::{async_trait, locks::Mutex};
+use ruma::{
+    events::{
+        presence::PresenceEvent, receipt::Receipt, room::member::MemberEventContent,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncStateEvent, EventType,
+    },
+    receipt::ReceiptType,
+    serde::Raw,
+    EventId, MxcUri, RoomId, UserId,
+};
+
+use super::{Result, RoomInfo, StateChanges, StateStore};
+use crate::{
+    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    media::MediaRequest,
+};
+
+/// The number of entries each of the caches holds by default.
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 500;
+
+type MemberKey = (RoomId, UserId);
+type StateKey = (RoomId, String, String);
+
+struct Caches {
+    members: LruCache<MemberKey, Option<MemberEvent>>,
+    profiles: LruCache<MemberKey, Option<MemberEventContent>>,
+    state: LruCache<StateKey, Option<Raw<AnySyncStateEvent>>>,
+    /// Counter that is increased every time the store gets modified.
+    ///
+    /// Values that were loaded from the store while a modification was in
+    /// progress might be stale, they are only cached if the generation didn't
+    /// change in the meantime.
+    generation: u64,
+}
+
+impl Caches {
+    fn new(size: usize) -> Self {
+        Self {
+            members: LruCache::new(size),
+            profiles: LruCache::new(size),
+            state: LruCache::new(size),
+            generation: 0,
+        }
+    }
+
+    fn invalidate(&mut self, changes: &StateChanges) {
+        let member_keys = changes
+            .members
+            .iter()
+            .flat_map(|(r, m)| m.keys().map(move |u| (r, u)))
+            .chain(changes.profiles.iter().flat_map(|(r, p)| p.keys().map(move |u| (r, u))))
+            .chain(
+                changes.stripped_members.iter().flat_map(|(r, m)| m.keys().map(move |u| (r, u))),
+            );
+
+        for (room_id, user_id) in member_keys {
+            let key = (room_id.clone(), user_id.clone());
+            self.members.pop(&key);
+            self.profiles.pop(&key);
+        }
+
+        for (room_id, event_type, state_key) in changes
+            .state
+            .iter()
+            .flat_map(|(r, s)| s.iter().flat_map(move |(t, e)| e.keys().map(move |k| (r, t, k))))
+            .chain(changes.stripped_state.iter().flat_map(|(r, s)| {
+                s.iter().flat_map(move |(t, e)| e.keys().map(move |k| (r, t, k)))
+            }))
+        {
+            self.state.pop(&(room_id.clone(), event_type.clone(), state_key.clone()));
+        }
+
+        self.generation += 1;
+    }
+
+    /// Remove the cached entries of the given room, returns true if anything
+    /// was removed.
+    fn remove_room(&mut self, room_id: &RoomId) -> bool {
+        fn keys<K: Clone + std::hash::Hash + Eq, V>(
+            cache: &LruCache<K, V>,
+            filter: impl Fn(&K) -> bool,
+        ) -> Vec<K> {
+            cache.iter().map(|(k, _)| k).filter(|k| filter(k)).cloned().collect()
+        }
+
+        let members = keys(&self.members, |k| &k.0 == room_id);
+        let profiles = keys(&self.profiles, |k| &k.0 == room_id);
+        let state = keys(&self.state, |k| &k.0 == room_id);
+        let removed = !members.is_empty() || !profiles.is_empty() || !state.is_empty();
+
+        for key in members {
+            self.members.pop(&key);
+        }
+
+        for key in profiles {
+            self.profiles.pop(&key);
+        }
+
+        for key in state {
+            self.state.pop(&key);
+        }
+
+        self.generation += 1;
+
+        removed
+    }
+
+    fn clear(&mut self) {
+        self.members.clear();
+        self.profiles.clear();
+        self.state.clear();
+        self.generation += 1;
+    }
+}
+
+/// A `StateStore` that caches the most recently used members, profiles and
+/// state events of the wrapped store.
+pub(crate) struct CachedStore {
+    inner: Arc<dyn StateStore>,
+    caches: Mutex<Caches>,
+}
+
+impl fmt::Debug for CachedStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedStore").finish()
+    }
+}
+
+impl CachedStore {
+    /// Wrap the given store, each of the caches will hold up to `size`
+    /// entries, a size of 0 disables caching.
+    pub fn new(inner: Arc<dyn StateStore>, size: usize) -> Self {
+        Self { inner, caches: Mutex::new(Caches::new(size)) }
+    }
+
+    /// Get the store this store is caching values for.
+    pub fn inner(&self) -> &Arc<dyn StateStore> {
+        &self.inner
+    }
+
+    /// Drop the cached entries of the given room without touching the
+    /// wrapped store, returns true if anything was cached for the room.
+    pub async fn unload_room(&self, room_id: &RoomId) -> bool {
+        self.caches.lock().await.remove_room(room_id)
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl StateStore for CachedStore {
+    async fn save_filter(&self, filter_name: &str, filter_id: &str) -> Result<()> {
+        self.inner.save_filter(filter_name, filter_id).await
+    }
+
+    async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        let ret = self.inner.save_changes(changes).await;
+        // Invalidate even if saving failed, we don't know which of the changes
+        // made it into the store.
+        self.caches.lock().await.invalidate(changes);
+        ret
+    }
+
+    async fn get_filter(&self, filter_name: &str) -> Result<Option<String>> {
+        self.inner.get_filter(filter_name).await
+    }
+
+    async fn get_sync_token(&self) -> Result<Option<String>> {
+        self.inner.get_sync_token().await
+    }
+
+    async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<Raw<PresenceEvent>>> {
+        self.inner.get_presence_event(user_id).await
+    }
+
+    async fn get_state_event(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+        state_key: &str,
+    ) -> Result<Option<Raw<AnySyncStateEvent>>> {
+        let key = (room_id.clone(), event_type.to_string(), state_key.to_owned());
+
+        let generation = {
+            let mut caches = self.caches.lock().await;
+
+            if let Some(event) = caches.state.get(&key) {
+                return Ok(event.clone());
+            }
+
+            caches.generation
+        };
+
+        let event = self.inner.get_state_event(room_id, event_type, state_key).await?;
+
+        let mut caches = self.caches.lock().await;
+
+        if caches.generation == generation {
+            caches.state.put(key, event.clone());
+        }
+
+        Ok(event)
+    }
+
+    async fn get_profile(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<MemberEventContent>> {
+        let key = (room_id.clone(), user_id.clone());
+
+        let generation = {
+            let mut caches = self.caches.lock().await;
+
+            if let Some(profile) = caches.profiles.get(&key) {
+                return Ok(profile.clone());
+            }
+
+            caches.generation
+        };
+
+        let profile = self.inner.get_profile(room_id, user_id).await?;
+
+        let mut caches = self.caches.lock().await;
+
+        if caches.generation == generation {
+            caches.profiles.put(key, profile.clone());
+        }
+
+        Ok(profile)
+    }
+
+    async fn get_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<MemberEvent>> {
+        let key = (room_id.clone(), state_key.clone());
+
+        let generation = {
+            let mut caches = self.caches.lock().await;
+
+            if let Some(event) = caches.members.get(&key) {
+                return Ok(event.clone());
+            }
+
+            caches.generation
+        };
+
+        let event = self.inner.get_member_event(room_id, state_key).await?;
+
+        let mut caches = self.caches.lock().await;
+
+        if caches.generation == generation {
+            caches.members.put(key, event.clone());
+        }
+
+        Ok(event)
+    }
+
+    async fn get_stripped_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<StrippedMemberEvent>> {
+        self.inner.get_stripped_member_event(room_id, state_key).await
+    }
+
+    async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.inner.get_user_ids(room_id).await
+    }
+
+    async fn get_invited_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.inner.get_invited_user_ids(room_id).await
+    }
+
+    async fn get_joined_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.inner.get_joined_user_ids(room_id).await
+    }
+
+    async fn get_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        self.inner.get_room_infos().await
+    }
+
+    async fn get_stripped_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        self.inner.get_stripped_room_infos().await
+    }
+
+    async fn get_users_with_display_name(
+        &self,
+        room_id: &RoomId,
+        display_name: &str,
+    ) -> Result<BTreeSet<UserId>> {
+        self.inner.get_users_with_display_name(room_id, display_name).await
+    }
+
+    async fn get_account_data_event(
+        &self,
+        event_type: EventType,
+    ) -> Result<Option<Raw<AnyGlobalAccountDataEvent>>> {
+        self.inner.get_account_data_event(event_type).await
+    }
+
+    async fn get_room_account_data_event(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Option<Raw<AnyRoomAccountDataEvent>>> {
+        self.inner.get_room_account_data_event(room_id, event_type).await
+    }
+
+    async fn get_user_room_receipt_event(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        user_id: &UserId,
+    ) -> Result<Option<(EventId, Receipt)>> {
+        self.inner.get_user_room_receipt_event(room_id, receipt_type, user_id).await
+    }
+
+    async fn get_event_room_receipt_events(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        event_id: &EventId,
+    ) -> Result<Vec<(UserId, Receipt)>> {
+        self.inner.get_event_room_receipt_events(room_id, receipt_type, event_id).await
+    }
+
+    async fn is_event_known(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        self.inner.is_event_known(room_id, event_id).await
+    }
+
+    async fn get_event_id_for_transaction(
+        &self,
+        room_id: &RoomId,
+        txn_id: &str,
+    ) -> Result<Option<EventId>> {
+        self.inner.get_event_id_for_transaction(room_id, txn_id).await
+    }
+
+    async fn add_media_content(&self, request: &MediaRequest, content: Vec<u8>) -> Result<()> {
+        self.inner.add_media_content(request, content).await
+    }
+
+    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        self.inner.get_media_content(request).await
+    }
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        self.inner.remove_media_content(request).await
+    }
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        self.inner.remove_media_content_for_uri(uri).await
+    }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let ret = self.inner.remove_room(room_id).await;
+        self.caches.lock().await.remove_room(room_id);
+        ret
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn export_all(&self) -> Result<StateChanges> {
+        self.inner.export_all().await
+    }
+
+    async fn import_all(&self, state: &StateChanges) -> Result<()> {
+        let ret = self.inner.import_all(state).await;
+        self.caches.lock().await.clear();
+        ret
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "sled_state_store"))]
+mod test {
+    use std::{convert::TryFrom, sync::Arc};
+
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        events::{
+            room::member::{MemberEventContent, MembershipState},
+            Unsigned,
+        },
+        identifiers::{room_id, user_id, EventId, UserId},
+        MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::{CachedStore, StateChanges, StateStore};
+    use crate::{deserialized_responses::MemberEvent, store::memory_store::MemoryStore};
+
+    fn user_id() -> UserId {
+        user_id!("@example:localhost")
+    }
+
+    fn membership_event(membership: MembershipState) -> MemberEvent {
+        MemberEvent {
+            event_id: EventId::try_from("$h29iv0s8:example.com").unwrap(),
+            content: MemberEventContent::new(membership),
+            sender: user_id(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            state_key: user_id(),
+            prev_content: None,
+            unsigned: Unsigned::default(),
+        }
+    }
+
+    #[async_test]
+    async fn cache_invalidation() {
+        let store = CachedStore::new(Arc::new(MemoryStore::new()), 10);
+        let room_id = room_id!("!test:localhost");
+
+        assert!(store.get_member_event(&room_id, &user_id()).await.unwrap().is_none());
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id(), membership_event(MembershipState::Join));
+        store.save_changes(&changes).await.unwrap();
+
+        let member = store.get_member_event(&room_id, &user_id()).await.unwrap().unwrap();
+        assert_eq!(member.content.membership, MembershipState::Join);
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id(), membership_event(MembershipState::Leave));
+        store.save_changes(&changes).await.unwrap();
+
+        let member = store.get_member_event(&room_id, &user_id()).await.unwrap().unwrap();
+        assert_eq!(member.content.membership, MembershipState::Leave);
+
+        assert!(store.unload_room(&room_id).await);
+        assert!(!store.unload_room(&room_id).await);
+
+        let member = store.get_member_event(&room_id, &user_id()).await.unwrap().unwrap();
+        assert_eq!(member.content.membership, MembershipState::Leave);
+
+        store.remove_room(&room_id).await.unwrap();
+        assert!(store.get_member_event(&room_id, &user_id()).await.unwrap().is_none());
+    }
+}
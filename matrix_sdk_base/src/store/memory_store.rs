@@ -13,7 +13,8 @@
 // limitations under the License.
 
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    hash::Hash,
     sync::{Arc, RwLock},
 };
 
@@ -23,7 +24,7 @@ use matrix_sdk_common::{async_trait, instant::Instant, locks::Mutex};
 use ruma::{
     events::{
         presence::PresenceEvent,
-        receipt::Receipt,
+        receipt::{Receipt, ReceiptEventContent},
         room::member::{MemberEventContent, MembershipState},
         AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
         AnySyncStateEvent, EventType,
@@ -34,12 +35,67 @@ use ruma::{
 };
 use tracing::info;
 
-use super::{Result, RoomInfo, StateChanges, StateStore};
+use super::{Result, RoomInfo, StateChanges, StateStore, SEEN_EVENTS_LIMIT, TRANSACTIONS_LIMIT};
 use crate::{
     deserialized_responses::{MemberEvent, StrippedMemberEvent},
     media::{MediaRequest, UniqueKey},
 };
 
+/// The most recently seen events of a room.
+#[derive(Debug, Default)]
+struct SeenEvents {
+    /// The events ordered by the time they were last seen.
+    order: BTreeMap<u64, EventId>,
+    /// The position of every event in `order`.
+    event_ids: HashMap<EventId, u64>,
+    next: u64,
+}
+
+impl SeenEvents {
+    /// Remember the event, forgetting the oldest one if there are too many.
+    ///
+    /// Seeing an event again makes it the most recent one.
+    fn insert(&mut self, event_id: EventId) {
+        if let Some(previous) = self.event_ids.insert(event_id.clone(), self.next) {
+            self.order.remove(&previous);
+        }
+
+        self.order.insert(self.next, event_id);
+        self.next += 1;
+
+        while self.order.len() > SEEN_EVENTS_LIMIT {
+            if let Some(oldest) = self.order.keys().next().copied() {
+                if let Some(event_id) = self.order.remove(&oldest) {
+                    self.event_ids.remove(&event_id);
+                }
+            }
+        }
+    }
+}
+
+/// The most recent transactions of events we sent to a room.
+#[derive(Debug, Default)]
+struct SentTransactions {
+    order: VecDeque<String>,
+    event_ids: HashMap<String, EventId>,
+}
+
+impl SentTransactions {
+    /// Remember the transaction, forgetting the oldest one if there are too
+    /// many.
+    fn insert(&mut self, txn_id: String, event_id: EventId) {
+        if self.event_ids.insert(txn_id.clone(), event_id).is_none() {
+            self.order.push_back(txn_id);
+        }
+
+        while self.order.len() > TRANSACTIONS_LIMIT {
+            if let Some(txn_id) = self.order.pop_front() {
+                self.event_ids.remove(&txn_id);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
     sync_token: Arc<RwLock<Option<String>>>,
@@ -65,6 +121,8 @@ pub struct MemoryStore {
     #[allow(clippy::type_complexity)]
     room_event_receipts:
         Arc<DashMap<RoomId, DashMap<String, DashMap<EventId, DashMap<UserId, Receipt>>>>>,
+    seen_events: Arc<DashMap<RoomId, SeenEvents>>,
+    transactions: Arc<DashMap<RoomId, SentTransactions>>,
     media: Arc<Mutex<LruCache<String, Vec<u8>>>>,
 }
 
@@ -89,6 +147,8 @@ impl MemoryStore {
             presence: DashMap::new().into(),
             room_user_receipts: DashMap::new().into(),
             room_event_receipts: DashMap::new().into(),
+            seen_events: DashMap::new().into(),
+            transactions: DashMap::new().into(),
             media: Arc::new(Mutex::new(LruCache::new(100))),
         }
     }
@@ -271,6 +331,22 @@ impl MemoryStore {
             }
         }
 
+        for (room, event_ids) in &changes.seen_events {
+            let mut seen_events = self.seen_events.entry(room.clone()).or_default();
+
+            for event_id in event_ids {
+                seen_events.insert(event_id.clone());
+            }
+        }
+
+        for (room, transactions) in &changes.transactions {
+            let mut room_transactions = self.transactions.entry(room.clone()).or_default();
+
+            for (txn_id, event_id) in transactions {
+                room_transactions.insert(txn_id.clone(), event_id.clone());
+            }
+        }
+
         info!("Saved changes in {:?}", now.elapsed());
 
         Ok(())
@@ -311,6 +387,15 @@ impl MemoryStore {
         Ok(self.members.get(room_id).and_then(|m| m.get(state_key).map(|m| m.clone())))
     }
 
+    fn get_stripped_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Option<StrippedMemberEvent> {
+        #[allow(clippy::map_clone)]
+        self.stripped_members.get(room_id).and_then(|m| m.get(state_key).map(|m| m.clone()))
+    }
+
     fn get_user_ids(&self, room_id: &RoomId) -> Vec<UserId> {
         #[allow(clippy::map_clone)]
         self.members
@@ -392,6 +477,18 @@ impl MemoryStore {
             .unwrap_or_else(Vec::new))
     }
 
+    async fn is_event_known(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        Ok(self
+            .seen_events
+            .get(room_id)
+            .map(|e| e.event_ids.contains_key(event_id))
+            .unwrap_or(false))
+    }
+
+    fn get_event_id_for_transaction(&self, room_id: &RoomId, txn_id: &str) -> Option<EventId> {
+        self.transactions.get(room_id).and_then(|t| t.event_ids.get(txn_id).cloned())
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.media.lock().await.put(request.unique_key(), data);
 
@@ -424,6 +521,100 @@ impl MemoryStore {
 
         Ok(())
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        self.members.remove(room_id);
+        self.profiles.remove(room_id);
+        self.display_names.remove(room_id);
+        self.joined_user_ids.remove(room_id);
+        self.invited_user_ids.remove(room_id);
+        self.room_info.remove(room_id);
+        self.room_state.remove(room_id);
+        self.room_account_data.remove(room_id);
+        self.stripped_room_info.remove(room_id);
+        self.stripped_room_state.remove(room_id);
+        self.stripped_members.remove(room_id);
+        self.room_user_receipts.remove(room_id);
+        self.room_event_receipts.remove(room_id);
+        self.seen_events.remove(room_id);
+        self.transactions.remove(room_id);
+
+        Ok(())
+    }
+
+    async fn export_all(&self) -> Result<StateChanges> {
+        fn to_map<K, V>(map: &DashMap<K, V>) -> BTreeMap<K, V>
+        where
+            K: Clone + Ord + Hash,
+            V: Clone,
+        {
+            map.iter().map(|e| (e.key().clone(), e.value().clone())).collect()
+        }
+
+        fn to_nested_map<K, L, V>(map: &DashMap<K, DashMap<L, V>>) -> BTreeMap<K, BTreeMap<L, V>>
+        where
+            K: Clone + Ord + Hash,
+            L: Clone + Ord + Hash,
+            V: Clone,
+        {
+            map.iter().map(|e| (e.key().clone(), to_map(e.value()))).collect()
+        }
+
+        let mut receipts: BTreeMap<RoomId, ReceiptEventContent> = BTreeMap::new();
+
+        for room in self.room_user_receipts.iter() {
+            for receipt_type in room.value().iter() {
+                for user in receipt_type.value().iter() {
+                    let (event_id, receipt) = user.value().clone();
+
+                    receipts
+                        .entry(room.key().clone())
+                        .or_insert_with(|| ReceiptEventContent(BTreeMap::new()))
+                        .0
+                        .entry(event_id)
+                        .or_default()
+                        .entry(ReceiptType::from(receipt_type.key().as_str()))
+                        .or_default()
+                        .insert(user.key().clone(), receipt);
+                }
+            }
+        }
+
+        Ok(StateChanges {
+            sync_token: self.get_sync_token().await?,
+            account_data: to_map(&self.account_data),
+            presence: to_map(&self.presence),
+            members: to_nested_map(&self.members),
+            profiles: to_nested_map(&self.profiles),
+            state: self
+                .room_state
+                .iter()
+                .map(|e| (e.key().clone(), to_nested_map(e.value())))
+                .collect(),
+            room_account_data: to_nested_map(&self.room_account_data),
+            room_infos: to_map(&self.room_info),
+            receipts,
+            stripped_state: self
+                .stripped_room_state
+                .iter()
+                .map(|e| (e.key().clone(), to_nested_map(e.value())))
+                .collect(),
+            stripped_members: to_nested_map(&self.stripped_members),
+            invited_room_info: to_map(&self.stripped_room_info),
+            ambiguity_maps: to_nested_map(&self.display_names),
+            seen_events: self
+                .seen_events
+                .iter()
+                .map(|e| (e.key().clone(), e.value().order.values().cloned().collect()))
+                .collect(),
+            transactions: self
+                .transactions
+                .iter()
+                .map(|e| (e.key().clone(), e.value().event_ids.clone().into_iter().collect()))
+                .collect(),
+            ..Default::default()
+        })
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -474,6 +665,14 @@ impl StateStore for MemoryStore {
         self.get_member_event(room_id, state_key).await
     }
 
+    async fn get_stripped_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<StrippedMemberEvent>> {
+        Ok(self.get_stripped_member_event(room_id, state_key))
+    }
+
     async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
         Ok(self.get_user_ids(room_id))
     }
@@ -540,6 +739,18 @@ impl StateStore for MemoryStore {
         self.get_event_room_receipt_events(room_id, receipt_type, event_id).await
     }
 
+    async fn is_event_known(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        self.is_event_known(room_id, event_id).await
+    }
+
+    async fn get_event_id_for_transaction(
+        &self,
+        room_id: &RoomId,
+        txn_id: &str,
+    ) -> Result<Option<EventId>> {
+        Ok(self.get_event_id_for_transaction(room_id, txn_id))
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.add_media_content(request, data).await
     }
@@ -555,27 +766,59 @@ impl StateStore for MemoryStore {
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
         self.remove_media_content_for_uri(uri).await
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        self.remove_room(room_id).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn export_all(&self) -> Result<StateChanges> {
+        self.export_all().await
+    }
 }
 
 #[cfg(test)]
 #[cfg(not(feature = "sled_state_store"))]
 mod test {
+    use std::convert::TryFrom;
+
     use matrix_sdk_test::async_test;
     use ruma::{
         api::client::r0::media::get_content_thumbnail::Method,
-        identifiers::{event_id, mxc_uri, room_id, user_id, UserId},
+        identifiers::{event_id, mxc_uri, room_id, user_id, EventId, UserId},
         receipt::ReceiptType,
         uint,
     };
     use serde_json::json;
 
-    use super::{MemoryStore, StateChanges};
+    use super::{MemoryStore, SeenEvents, StateChanges, SEEN_EVENTS_LIMIT};
     use crate::media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType};
 
     fn user_id() -> UserId {
         user_id!("@example:localhost")
     }
 
+    #[test]
+    fn seen_events_limit() {
+        let mut seen_events = SeenEvents::default();
+
+        for i in 0..SEEN_EVENTS_LIMIT {
+            seen_events.insert(EventId::try_from(format!("$event{}:localhost", i)).unwrap());
+        }
+
+        // Seeing an event again makes it the most recent one.
+        seen_events.insert(event_id!("$event0:localhost"));
+        seen_events.insert(event_id!("$newest:localhost"));
+
+        assert_eq!(seen_events.order.len(), SEEN_EVENTS_LIMIT);
+        assert!(seen_events.event_ids.contains_key(&event_id!("$event0:localhost")));
+        assert!(!seen_events.event_ids.contains_key(&event_id!("$event1:localhost")));
+        assert!(seen_events.event_ids.contains_key(&event_id!("$newest:localhost")));
+    }
+
     #[async_test]
     async fn test_receipts_saving() {
         let store = MemoryStore::new();
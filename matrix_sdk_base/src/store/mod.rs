@@ -21,6 +21,7 @@ use std::{
 };
 
 use dashmap::DashMap;
+use futures::Stream;
 use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
 use ruma::{
     api::client::r0::push::get_notifications::Notification,
@@ -35,24 +36,32 @@ use ruma::{
     serde::Raw,
     EventId, MxcUri, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
 #[cfg(feature = "sled_state_store")]
 use sled::Db;
 
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    deserialized_responses::{MemberEvent, StrippedMemberEvent, SyncRoomEvent},
     media::MediaRequest,
-    rooms::{RoomInfo, RoomType},
+    rooms::{
+        Aggregations, RelationEvent, RoomAggregations, RoomInfo, RoomListDiff, RoomListNotifier,
+        RoomType,
+    },
     Room, Session,
 };
 
 pub(crate) mod ambiguity_map;
+mod cache;
 mod memory_store;
 #[cfg(feature = "sled_state_store")]
 mod sled_store;
 
+use self::cache::{CachedStore, DEFAULT_CACHE_SIZE};
 #[cfg(not(feature = "sled_state_store"))]
 use self::memory_store::MemoryStore;
 #[cfg(feature = "sled_state_store")]
+pub use self::sled_store::IntegrityReport;
+#[cfg(feature = "sled_state_store")]
 use self::sled_store::SledStore;
 
 /// State store specific error type.
@@ -84,6 +93,14 @@ pub enum StoreError {
 /// A `StateStore` specific result type.
 pub type Result<T, E = StoreError> = std::result::Result<T, E>;
 
+/// The number of most recently seen events the stores remember per room to
+/// de-duplicate timeline events.
+pub(crate) const SEEN_EVENTS_LIMIT: usize = 2_000;
+
+/// The number of most recent transactions of sent events the stores remember
+/// per room.
+pub(crate) const TRANSACTIONS_LIMIT: usize = 1_000;
+
 /// An abstract state store trait that can be used to implement different stores
 /// for the SDK.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -159,6 +176,20 @@ pub trait StateStore: AsyncTraitDeps {
         state_key: &UserId,
     ) -> Result<Option<MemberEvent>>;
 
+    /// Get the `StrippedMemberEvent` for the given state key in the given
+    /// invited room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the invited room the member event belongs to.
+    ///
+    /// * `state_key` - The user id that the member event defines the state for.
+    async fn get_stripped_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<StrippedMemberEvent>>;
+
     /// Get all the user ids of members for a given room.
     async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>>;
 
@@ -250,6 +281,36 @@ pub trait StateStore: AsyncTraitDeps {
         event_id: &EventId,
     ) -> Result<Vec<(UserId, Receipt)>>;
 
+    /// Check if an event was already received in the given room, either in a
+    /// sync response or while paginating the room history.
+    ///
+    /// Only the most recently received events of a room are remembered, this
+    /// is enough to skip the events that overlap when a sync is restarted from
+    /// an older sync token or when the history is paginated.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event belongs to.
+    ///
+    /// * `event_id` - The id of the event.
+    async fn is_event_known(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool>;
+
+    /// Get the id of the event that was sent with the given transaction id.
+    ///
+    /// Only the most recent transactions of a room are remembered, older ones
+    /// are forgotten once a room has more than a thousand of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `txn_id` - The transaction id the event was sent with.
+    async fn get_event_id_for_transaction(
+        &self,
+        room_id: &RoomId,
+        txn_id: &str,
+    ) -> Result<Option<EventId>>;
+
     /// Add a media file's content in the media store.
     ///
     /// # Arguments
@@ -280,6 +341,41 @@ pub trait StateStore: AsyncTraitDeps {
     ///
     /// * `uri` - The `MxcUri` of the media files.
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()>;
+
+    /// Removes all the data of the given room from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The `RoomId` of the room that should be removed.
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()>;
+
+    /// Write all the pending changes of the store to disk.
+    ///
+    /// Returns once all the changes that were made up until now are durably
+    /// stored.
+    async fn flush(&self) -> Result<()>;
+
+    /// Export the whole state of the store, e.g. to move it into a store of a
+    /// different type.
+    ///
+    /// The returned changes can be serialized and imported into another store
+    /// using [`import_all()`]. Saved filters and the media cache aren't part of
+    /// the export.
+    ///
+    /// [`import_all()`]: #method.import_all
+    async fn export_all(&self) -> Result<StateChanges>;
+
+    /// Import the state that was exported from another store using
+    /// [`export_all()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The exported state that should be added to this store.
+    ///
+    /// [`export_all()`]: #method.export_all
+    async fn import_all(&self, state: &StateChanges) -> Result<()> {
+        self.save_changes(state).await
+    }
 }
 
 /// A state store wrapper for the SDK.
@@ -288,11 +384,15 @@ pub trait StateStore: AsyncTraitDeps {
 /// `StateStore` implementation.
 #[derive(Debug, Clone)]
 pub struct Store {
-    inner: Arc<dyn StateStore>,
+    inner: Arc<CachedStore>,
     pub(crate) session: Arc<RwLock<Option<Session>>>,
     pub(crate) sync_token: Arc<RwLock<Option<String>>>,
     rooms: Arc<DashMap<RoomId, Room>>,
     stripped_rooms: Arc<DashMap<RoomId, Room>>,
+    pub(crate) room_list: RoomListNotifier,
+    aggregations: Arc<DashMap<RoomId, RoomAggregations>>,
+    #[cfg(feature = "sled_state_store")]
+    integrity_report: Arc<IntegrityReport>,
 }
 
 impl Store {
@@ -301,22 +401,38 @@ impl Store {
         let sync_token = Arc::new(RwLock::new(None));
 
         Self {
-            inner: inner.into(),
+            inner: CachedStore::new(inner.into(), DEFAULT_CACHE_SIZE).into(),
             session,
             sync_token,
             rooms: DashMap::new().into(),
             stripped_rooms: DashMap::new().into(),
+            room_list: RoomListNotifier::default(),
+            aggregations: DashMap::new().into(),
+            #[cfg(feature = "sled_state_store")]
+            integrity_report: IntegrityReport::default().into(),
         }
     }
 
+    /// Replace the cache in front of the state store with one that holds up
+    /// to `size` entries per cached item type.
+    ///
+    /// This needs to be called before any room is created or restored, rooms
+    /// hold on to the cache they were created with.
+    pub(crate) fn with_cache_size(mut self, size: usize) -> Self {
+        self.inner = CachedStore::new(self.inner.inner().clone(), size).into();
+        self
+    }
+
     pub(crate) async fn restore_session(&self, session: Session) -> Result<()> {
         for info in self.inner.get_room_infos().await? {
             let room = Room::restore(&session.user_id, self.inner.clone(), info);
+            self.room_list.room_restored(room.room_id());
             self.rooms.insert(room.room_id().to_owned(), room);
         }
 
         for info in self.inner.get_stripped_room_infos().await? {
             let room = Room::restore(&session.user_id, self.inner.clone(), info);
+            self.room_list.room_restored(room.room_id());
             self.stripped_rooms.insert(room.room_id().to_owned(), room);
         }
 
@@ -345,13 +461,44 @@ impl Store {
     /// store.
     #[cfg(feature = "sled_state_store")]
     pub fn open_default(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<(Self, Db)> {
+        Self::open_sled(path, passphrase, false)
+    }
+
+    /// Open the default Sled store, checking every entry of it if
+    /// `full_integrity_check` is set.
+    #[cfg(feature = "sled_state_store")]
+    pub(crate) fn open_sled(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        full_integrity_check: bool,
+    ) -> Result<(Self, Db)> {
         let inner = if let Some(passphrase) = passphrase {
             SledStore::open_with_passphrase(path, passphrase)?
         } else {
             SledStore::open_with_path(path)?
         };
 
-        Ok((Self::new(Box::new(inner.clone())), inner.inner))
+        let integrity_report = if full_integrity_check {
+            inner.check_integrity()?
+        } else {
+            inner.integrity_report().clone()
+        };
+
+        let store = Self {
+            integrity_report: integrity_report.into(),
+            ..Self::new(Box::new(inner.clone()))
+        };
+
+        Ok((store, inner.inner))
+    }
+
+    /// Get the state that was lost because the state store was corrupted,
+    /// found by the integrity check that ran when the store was opened.
+    ///
+    /// The lost state is fetched again by the next sync.
+    #[cfg(feature = "sled_state_store")]
+    pub fn integrity_report(&self) -> &IntegrityReport {
+        &self.integrity_report
     }
 
     #[cfg(feature = "sled_state_store")]
@@ -372,12 +519,61 @@ impl Store {
             .get(room_id)
             .and_then(|r| match r.room_type() {
                 RoomType::Joined => Some(r.clone()),
-                RoomType::Left => Some(r.clone()),
+                RoomType::Left | RoomType::Knocked => Some(r.clone()),
                 RoomType::Invited => self.get_stripped_room(room_id),
             })
             .or_else(|| self.get_stripped_room(room_id))
     }
 
+    /// Subscribe to changes of the room list.
+    ///
+    /// The returned stream yields a [`RoomListDiff`] every time a room gets
+    /// added or removed, or when the info of a room that is relevant to
+    /// present it in a room list changes, e.g. its name or its unread
+    /// notification counts.
+    ///
+    /// Rooms that are already known when the subscription is made aren't
+    /// announced, the initial room list can be fetched using
+    /// [`get_rooms()`](#method.get_rooms).
+    pub fn room_list_updates(&self) -> impl Stream<Item = RoomListDiff> {
+        self.room_list.subscribe()
+    }
+
+    /// Replace the info of the given room with the new info and notify the
+    /// room list subscribers about the change.
+    pub(crate) fn update_room_info(&self, room: &Room, info: RoomInfo, tags_changed: bool) {
+        let old = room.clone_info();
+        room.update_summary(info);
+        self.room_list.room_updated(room, &old, tags_changed);
+    }
+
+    /// Get the events that relate to the given event, e.g. its reactions and
+    /// edits, as far as they are known to the client.
+    ///
+    /// The aggregations are built from the events received in sync responses
+    /// and from fetched events, they are kept in memory only and only for a
+    /// limited number of events per room.
+    pub fn aggregations(&self, room_id: &RoomId, event_id: &EventId) -> Option<Aggregations> {
+        self.aggregations.get(room_id).and_then(|r| r.get(event_id).cloned())
+    }
+
+    /// Add the given event to the aggregations of the event it relates to, or
+    /// remove the event it redacts from the aggregations.
+    ///
+    /// Events from sync responses and from fetched events are aggregated
+    /// automatically, this is only needed to aggregate events we sent before
+    /// they come down the sync.
+    pub fn aggregate_event(&self, room_id: &RoomId, event: &SyncRoomEvent) {
+        let relation = match event.event.deserialize_as::<RelationEvent>() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+
+        // Every event is passed on, the senders of the events are needed to
+        // validate replacements.
+        self.aggregations.entry(room_id.clone()).or_default().add(&relation, event);
+    }
+
     fn get_stripped_room(&self, room_id: &RoomId) -> Option<Room> {
         self.stripped_rooms.get(room_id).map(|r| r.clone())
     }
@@ -392,6 +588,27 @@ impl Store {
             .clone()
     }
 
+    /// Remove the room with the given room id and all of its data from the
+    /// store.
+    pub(crate) async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        self.inner.remove_room(room_id).await?;
+
+        self.rooms.remove(room_id);
+        self.stripped_rooms.remove(room_id);
+        self.room_list.room_removed(room_id);
+        self.aggregations.remove(room_id);
+
+        Ok(())
+    }
+
+    /// Drop the cached members, profiles and state events of the given room,
+    /// they will be reloaded from the store once they are accessed again.
+    ///
+    /// Returns true if anything was cached for the room.
+    pub(crate) async fn unload_room(&self, room_id: &RoomId) -> bool {
+        self.inner.unload_room(room_id).await
+    }
+
     pub(crate) async fn get_or_create_room(&self, room_id: &RoomId, room_type: RoomType) -> Room {
         let session = self.session.read().await;
         let user_id = &session.as_ref().expect("Creating room while not being logged in").user_id;
@@ -412,7 +629,7 @@ impl Deref for Store {
 }
 
 /// Store state changes and pass them to the StateStore.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct StateChanges {
     /// The sync token that relates to this update.
     pub sync_token: Option<String>,
@@ -452,7 +669,18 @@ pub struct StateChanges {
     /// share that display name in the given room.
     pub ambiguity_maps: BTreeMap<RoomId, BTreeMap<String, BTreeSet<UserId>>>,
     /// A map of `RoomId` to a vector of `Notification`s
+    ///
+    /// Notifications aren't persisted by the stores, they are skipped when the
+    /// changes are serialized.
+    #[serde(skip)]
     pub notifications: BTreeMap<RoomId, Vec<Notification>>,
+    /// A map of `RoomId` to the ids of timeline events that were received for
+    /// the first time.
+    pub seen_events: BTreeMap<RoomId, BTreeSet<EventId>>,
+    /// A map of `RoomId` to the transaction ids of events we sent and the ids
+    /// of the events.
+    #[serde(default)]
+    pub transactions: BTreeMap<RoomId, BTreeMap<String, EventId>>,
 }
 
 impl StateChanges {
@@ -461,6 +689,73 @@ impl StateChanges {
         Self { sync_token: Some(sync_token), ..Default::default() }
     }
 
+    /// Merge the given changes into this `StateChanges` struct.
+    ///
+    /// Values of the given changes replace the existing ones, the maps of
+    /// events are merged room by room.
+    pub fn extend(&mut self, other: StateChanges) {
+        if other.sync_token.is_some() {
+            self.sync_token = other.sync_token;
+        }
+
+        if other.session.is_some() {
+            self.session = other.session;
+        }
+
+        self.account_data.extend(other.account_data);
+        self.presence.extend(other.presence);
+
+        for (room_id, members) in other.members {
+            self.members.entry(room_id).or_default().extend(members);
+        }
+
+        for (room_id, profiles) in other.profiles {
+            self.profiles.entry(room_id).or_default().extend(profiles);
+        }
+
+        for (room_id, event_types) in other.state {
+            let room = self.state.entry(room_id).or_default();
+
+            for (event_type, events) in event_types {
+                room.entry(event_type).or_default().extend(events);
+            }
+        }
+
+        for (room_id, events) in other.room_account_data {
+            self.room_account_data.entry(room_id).or_default().extend(events);
+        }
+
+        self.room_infos.extend(other.room_infos);
+        self.receipts.extend(other.receipts);
+
+        for (room_id, event_types) in other.stripped_state {
+            let room = self.stripped_state.entry(room_id).or_default();
+
+            for (event_type, events) in event_types {
+                room.entry(event_type).or_default().extend(events);
+            }
+        }
+
+        for (room_id, members) in other.stripped_members {
+            self.stripped_members.entry(room_id).or_default().extend(members);
+        }
+
+        self.invited_room_info.extend(other.invited_room_info);
+        self.ambiguity_maps.extend(other.ambiguity_maps);
+
+        for (room_id, notifications) in other.notifications {
+            self.notifications.entry(room_id).or_default().extend(notifications);
+        }
+
+        for (room_id, event_ids) in other.seen_events {
+            self.seen_events.entry(room_id).or_default().extend(event_ids);
+        }
+
+        for (room_id, transactions) in other.transactions {
+            self.transactions.entry(room_id).or_default().extend(transactions);
+        }
+    }
+
     /// Update the `StateChanges` struct with the given `PresenceEvent`.
     pub fn add_presence_event(&mut self, event: PresenceEvent, raw_event: Raw<PresenceEvent>) {
         self.presence.insert(event.sender, raw_event);
@@ -532,6 +827,20 @@ impl StateChanges {
         self.notifications.entry(room_id.to_owned()).or_insert_with(Vec::new).push(notification);
     }
 
+    /// Update the `StateChanges` struct with the id of a timeline event that
+    /// was received for the first time in the given room.
+    ///
+    /// Returns false if the event was already added to the changes.
+    pub fn add_seen_event(&mut self, room_id: &RoomId, event_id: EventId) -> bool {
+        self.seen_events.entry(room_id.to_owned()).or_insert_with(BTreeSet::new).insert(event_id)
+    }
+
+    /// Update the `StateChanges` struct with the id of an event we sent to the
+    /// given room and the transaction id it was sent with.
+    pub fn add_transaction(&mut self, room_id: &RoomId, txn_id: String, event_id: EventId) {
+        self.transactions.entry(room_id.to_owned()).or_default().insert(txn_id, event_id);
+    }
+
     /// Update the `StateChanges` struct with the given room with a new
     /// `Receipts`.
     pub fn add_receipts(&mut self, room_id: &RoomId, event: ReceiptEventContent) {
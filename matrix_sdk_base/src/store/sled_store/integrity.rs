@@ -0,0 +1,246 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The integrity check of the sled based state store.
+//!
+//! The first entries of every tree are read when the store is opened, to find
+//! trees that sled reports as corrupted. The full check reads every entry of
+//! the store and removes the ones that can't be deserialized anymore, it
+//! needs to be requested explicitly.
+//!
+//! Corrupted trees are only reported, never cleared, the crypto store might
+//! share the database, and with it the `session` tree, with the state store.
+//!
+//! All of the state can be fetched from the homeserver again, so if anything
+//! was lost the sync token is removed and the next sync fetches the full state
+//! again.
+
+use std::collections::BTreeSet;
+
+use ruma::{
+    events::{
+        presence::PresenceEvent, room::member::MemberEventContent, AnyGlobalAccountDataEvent,
+        AnyRoomAccountDataEvent, AnyStrippedStateEvent, AnySyncStateEvent,
+    },
+    receipt::Receipt,
+    serde::Raw,
+    EventId, UserId,
+};
+use serde::de::DeserializeOwned;
+use sled::{IVec, Tree};
+use tracing::warn;
+
+use super::{decode_key_value, EncodeKey, Result, SledStore, ENCODE_SEPARATOR};
+use crate::{
+    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    RoomInfo,
+};
+
+/// The state that was lost because it was corrupted, found by the integrity
+/// check of the state store.
+///
+/// The lost state is fetched again by the next sync, the ids of the lost
+/// entries are taken from the keys of the store, they might be garbled if the
+/// keys themselves were corrupted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The rooms whose info was lost.
+    pub rooms: Vec<String>,
+    /// The lost members, as `(room_id, user_id)` pairs.
+    pub members: Vec<(String, String)>,
+    /// The other lost entries, e.g. state events or receipts, as `(tree,
+    /// key)` pairs, the key is split up into its parts.
+    pub other_entries: Vec<(String, Vec<String>)>,
+    /// The trees that sled reported as corrupted. They are left as they are,
+    /// the entries that come after the corruption couldn't be checked.
+    pub corrupted_trees: Vec<String>,
+}
+
+impl IntegrityReport {
+    /// Was nothing lost.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// The number of entries of every tree that the check which runs when the
+/// store is opened reads.
+const SAMPLE_SIZE: usize = 100;
+
+struct Checker {
+    /// Should every entry be checked, or only a sample of every tree.
+    full: bool,
+    /// The names of the trees that sled reported as corrupted.
+    corrupted_trees: Vec<String>,
+}
+
+impl Checker {
+    /// Walk the given tree and, for a full check, check every entry of the
+    /// tree with the given check, remove the ones that fail and return their
+    /// keys.
+    ///
+    /// If the tree itself is corrupted it's recorded and left alone, only the
+    /// entries that failed the check until then are removed.
+    fn check_tree(
+        &mut self,
+        tree: &Tree,
+        name: &str,
+        check: impl Fn(&IVec) -> bool,
+    ) -> Result<Vec<IVec>> {
+        let mut lost = Vec::new();
+        let limit = if self.full { usize::MAX } else { SAMPLE_SIZE };
+
+        for entry in tree.iter().take(limit) {
+            match entry {
+                Ok((key, value)) => {
+                    if self.full && !check(&value) {
+                        lost.push(key);
+                    }
+                }
+                Err(sled::Error::Corruption { .. }) => {
+                    warn!("The {} tree of the state store is corrupted", name);
+                    self.corrupted_trees.push(name.to_owned());
+
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for key in &lost {
+            tree.remove(key)?;
+        }
+
+        if !lost.is_empty() {
+            warn!(
+                "Removed {} corrupted entries from the {} tree of the state store",
+                lost.len(),
+                name
+            );
+        }
+
+        Ok(lost)
+    }
+}
+
+fn deserializes<T: DeserializeOwned>(store: &SledStore) -> impl Fn(&IVec) -> bool + '_ {
+    move |value| store.deserialize_event::<T>(value).is_ok()
+}
+
+fn any(_: &IVec) -> bool {
+    true
+}
+
+fn key_part(key: &IVec, position: usize) -> String {
+    decode_key_value(key, position).unwrap_or_default()
+}
+
+fn key_parts(key: &IVec) -> Vec<String> {
+    key.split(|b| *b == ENCODE_SEPARATOR)
+        .filter(|p| !p.is_empty())
+        .map(|p| String::from_utf8_lossy(p).into_owned())
+        .collect()
+}
+
+/// Check the trees of the given store, and all of their entries if `full` is
+/// set, and remove the corrupted entries.
+///
+/// The media tree is only checked by the full check, its entries are too big
+/// to be read every time the store is opened.
+pub(super) fn check_integrity(store: &SledStore, full: bool) -> Result<IntegrityReport> {
+    let mut checker = Checker { full, corrupted_trees: Vec::new() };
+
+    let mut rooms =
+        checker.check_tree(&store.room_info, "room_infos", deserializes::<RoomInfo>(store))?;
+    rooms.extend(checker.check_tree(
+        &store.stripped_room_info,
+        "stripped_room_info",
+        deserializes::<RoomInfo>(store),
+    )?);
+
+    let mut members =
+        checker.check_tree(&store.members, "members", deserializes::<MemberEvent>(store))?;
+    members.extend(checker.check_tree(
+        &store.stripped_members,
+        "stripped_members",
+        deserializes::<StrippedMemberEvent>(store),
+    )?);
+
+    let mut other = Vec::new();
+    let mut check_other = |tree: &Tree, name: &str, check: &dyn Fn(&IVec) -> bool| -> Result<()> {
+        let lost = checker.check_tree(tree, name, check)?;
+        other.extend(lost.iter().map(|k| (name.to_owned(), key_parts(k))));
+
+        Ok(())
+    };
+
+    check_other(&store.session, "session", &any)?;
+    check_other(
+        &store.account_data,
+        "account_data",
+        &deserializes::<Raw<AnyGlobalAccountDataEvent>>(store),
+    )?;
+    check_other(&store.profiles, "profiles", &deserializes::<MemberEventContent>(store))?;
+    check_other(&store.display_names, "display_names", &deserializes::<BTreeSet<UserId>>(store))?;
+    check_other(&store.joined_user_ids, "joined_user_ids", &any)?;
+    check_other(&store.invited_user_ids, "invited_user_ids", &any)?;
+    check_other(&store.room_state, "room_state", &deserializes::<Raw<AnySyncStateEvent>>(store))?;
+    check_other(
+        &store.room_account_data,
+        "room_account_data",
+        &deserializes::<Raw<AnyRoomAccountDataEvent>>(store),
+    )?;
+    check_other(
+        &store.stripped_room_state,
+        "stripped_room_state",
+        &deserializes::<Raw<AnyStrippedStateEvent>>(store),
+    )?;
+    check_other(&store.presence, "presence", &deserializes::<Raw<PresenceEvent>>(store))?;
+    check_other(
+        &store.room_user_receipts,
+        "room_user_receipts",
+        &deserializes::<(EventId, Receipt)>(store),
+    )?;
+    check_other(
+        &store.room_event_receipts,
+        "room_event_receipts",
+        &deserializes::<Receipt>(store),
+    )?;
+    check_other(&store.seen_events, "seen_events", &any)?;
+    check_other(&store.seen_event_order, "seen_event_order", &any)?;
+    check_other(&store.seen_event_counts, "seen_event_counts", &any)?;
+    check_other(&store.transactions, "transactions", &any)?;
+
+    if full {
+        check_other(&store.media, "media", &any)?;
+    }
+
+    let report = IntegrityReport {
+        rooms: rooms.iter().map(|k| key_part(k, 0)).collect(),
+        members: members.iter().map(|k| (key_part(k, 0), key_part(k, 1))).collect(),
+        other_entries: other,
+        corrupted_trees: checker.corrupted_trees,
+    };
+
+    if !report.is_empty() {
+        warn!(
+            "State was lost because the state store was corrupted, fetching the full state again"
+        );
+
+        store.session.remove("sync_token".encode())?;
+        store.inner.flush()?;
+    }
+
+    Ok(report)
+}
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod integrity;
 mod store_key;
 
 use std::{
@@ -30,7 +31,7 @@ use matrix_sdk_common::async_trait;
 use ruma::{
     events::{
         presence::PresenceEvent,
-        receipt::Receipt,
+        receipt::{Receipt, ReceiptEventContent},
         room::member::{MemberEventContent, MembershipState},
         AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncStateEvent, EventType,
     },
@@ -45,10 +46,16 @@ use sled::{
 };
 use tracing::info;
 
-use self::store_key::{EncryptedEvent, StoreKey};
-use super::{Result, RoomInfo, StateChanges, StateStore, StoreError};
+pub use self::integrity::IntegrityReport;
+use self::{
+    integrity::check_integrity,
+    store_key::{EncryptedEvent, StoreKey},
+};
+use super::{
+    Result, RoomInfo, StateChanges, StateStore, StoreError, SEEN_EVENTS_LIMIT, TRANSACTIONS_LIMIT,
+};
 use crate::{
-    deserialized_responses::MemberEvent,
+    deserialized_responses::{MemberEvent, StrippedMemberEvent},
     media::{MediaRequest, UniqueKey},
 };
 
@@ -167,11 +174,18 @@ pub fn decode_key_value(key: &[u8], position: usize) -> Option<String> {
     values.get(position).map(|s| String::from_utf8_lossy(s).to_string())
 }
 
+/// Split a stored transaction into its order and the event id.
+fn decode_transaction(value: &[u8]) -> (u64, &[u8]) {
+    let (order, event_id) = value.split_at(8);
+    (u64::from_be_bytes(order.try_into().expect("The order is 8 bytes long")), event_id)
+}
+
 #[derive(Clone)]
 pub struct SledStore {
     path: Option<PathBuf>,
     pub(crate) inner: Db,
     store_key: Arc<Option<StoreKey>>,
+    integrity_report: Arc<IntegrityReport>,
     session: Tree,
     account_data: Tree,
     members: Tree,
@@ -188,6 +202,10 @@ pub struct SledStore {
     presence: Tree,
     room_user_receipts: Tree,
     room_event_receipts: Tree,
+    seen_events: Tree,
+    seen_event_order: Tree,
+    seen_event_counts: Tree,
+    transactions: Tree,
     media: Tree,
 }
 
@@ -223,13 +241,18 @@ impl SledStore {
 
         let room_user_receipts = db.open_tree("room_user_receipts")?;
         let room_event_receipts = db.open_tree("room_event_receipts")?;
+        let seen_events = db.open_tree("seen_events")?;
+        let seen_event_order = db.open_tree("seen_event_order")?;
+        let seen_event_counts = db.open_tree("seen_event_counts")?;
+        let transactions = db.open_tree("transactions")?;
 
         let media = db.open_tree("media")?;
 
-        Ok(Self {
+        let mut store = Self {
             path,
             inner: db,
             store_key: store_key.into(),
+            integrity_report: IntegrityReport::default().into(),
             session,
             account_data,
             members,
@@ -246,8 +269,39 @@ impl SledStore {
             stripped_room_state,
             room_user_receipts,
             room_event_receipts,
+            seen_events,
+            seen_event_order,
+            seen_event_counts,
+            transactions,
             media,
-        })
+        };
+
+        store.integrity_report = check_integrity(&store, false)?.into();
+
+        Ok(store)
+    }
+
+    /// Get the state that was lost because it was corrupted, found by the
+    /// integrity check that runs when the store is opened.
+    ///
+    /// The check that runs when the store is opened only reads the first
+    /// entries of every tree to find the trees that sled reports as
+    /// corrupted, see [`check_integrity()`](#method.check_integrity) for a
+    /// full check.
+    pub fn integrity_report(&self) -> &IntegrityReport {
+        &self.integrity_report
+    }
+
+    /// Check every entry of the store and remove the ones that can't be
+    /// deserialized anymore.
+    ///
+    /// This reads the whole store, so it should only be run if the store is
+    /// suspected to be corrupted, e.g. after a crash. If anything was lost the
+    /// sync token is removed, so the next sync fetches the full state again.
+    ///
+    /// Returns the state that was lost.
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        check_integrity(self, true)
     }
 
     pub fn open() -> Result<Self> {
@@ -556,6 +610,29 @@ impl SledStore {
 
         ret?;
 
+        for (room, event_ids) in &changes.seen_events {
+            self.save_seen_events(room, event_ids)?;
+        }
+
+        let mut transactions = sled::Batch::default();
+
+        // Like the seen events, the transactions are stored with an increasing
+        // id in front of the event id so the oldest ones can be forgotten.
+        for (room, room_transactions) in &changes.transactions {
+            for (txn_id, event_id) in room_transactions {
+                let mut value = self.inner.generate_id()?.to_be_bytes().to_vec();
+                value.extend_from_slice(event_id.as_str().as_bytes());
+
+                transactions.insert((room.as_str(), txn_id.as_str()).encode(), value);
+            }
+        }
+
+        self.transactions.apply_batch(transactions)?;
+
+        for room in changes.transactions.keys() {
+            self.prune_transactions(room)?;
+        }
+
         self.inner.flush_async().await?;
 
         info!("Saved changes in {:?}", now.elapsed());
@@ -604,6 +681,18 @@ impl SledStore {
             .transpose()?)
     }
 
+    pub async fn get_stripped_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<StrippedMemberEvent>> {
+        Ok(self
+            .stripped_members
+            .get((room_id.as_str(), state_key.as_str()).encode())?
+            .map(|v| self.deserialize_event(&v))
+            .transpose()?)
+    }
+
     pub async fn get_user_ids_stream(
         &self,
         room_id: &RoomId,
@@ -730,6 +819,22 @@ impl SledStore {
             .collect()
     }
 
+    async fn is_event_known(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        Ok(self.seen_events.contains_key((room_id.as_str(), event_id.as_str()).encode())?)
+    }
+
+    pub async fn get_event_id_for_transaction(
+        &self,
+        room_id: &RoomId,
+        txn_id: &str,
+    ) -> Result<Option<EventId>> {
+        Ok(self
+            .transactions
+            .get((room_id.as_str(), txn_id).encode())?
+            .map(|e| EventId::try_from(String::from_utf8_lossy(decode_transaction(&e).1).as_ref()))
+            .transpose()?)
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.media.insert(
             (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
@@ -769,6 +874,273 @@ impl SledStore {
 
         Ok(self.media.apply_batch(batch)?)
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let trees = [
+            &self.members,
+            &self.profiles,
+            &self.display_names,
+            &self.joined_user_ids,
+            &self.invited_user_ids,
+            &self.room_info,
+            &self.room_state,
+            &self.room_account_data,
+            &self.stripped_room_info,
+            &self.stripped_room_state,
+            &self.stripped_members,
+            &self.room_user_receipts,
+            &self.room_event_receipts,
+            &self.seen_events,
+            &self.seen_event_order,
+            &self.seen_event_counts,
+            &self.transactions,
+        ];
+
+        for tree in trees.iter() {
+            let mut batch = sled::Batch::default();
+
+            for key in tree.scan_prefix(room_id.encode()).keys() {
+                batch.remove(key?);
+            }
+
+            tree.apply_batch(batch)?;
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Remember the seen events of the room, forgetting the oldest ones if the
+    /// room has more than [`SEEN_EVENTS_LIMIT`] of them.
+    ///
+    /// Every event gets an increasing id when it's seen, seeing an event again
+    /// makes it the most recent one. The `seen_event_order` tree indexes the
+    /// events of a room by that id and `seen_event_counts` holds the number of
+    /// events per room, so the oldest events are found without scanning the
+    /// whole room.
+    fn save_seen_events(&self, room_id: &RoomId, event_ids: &BTreeSet<EventId>) -> Result<()> {
+        let room_key = room_id.encode();
+        let mut count = self
+            .seen_event_counts
+            .get(&room_key)?
+            .and_then(|c| <[u8; 8]>::try_from(&c[..]).ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+
+        let mut events = sled::Batch::default();
+        let mut order = sled::Batch::default();
+
+        for event_id in event_ids {
+            let key = (room_id.as_str(), event_id.as_str()).encode();
+            let id = self.inner.generate_id()?.to_be_bytes();
+
+            if let Some(previous) = self.seen_events.get(&key)? {
+                order.remove([room_key.as_slice(), &previous].concat());
+            } else {
+                count += 1;
+            }
+
+            events.insert(key, id.to_vec());
+            order.insert([room_key.as_slice(), &id].concat(), event_id.as_str().as_bytes());
+        }
+
+        self.seen_events.apply_batch(events)?;
+        self.seen_event_order.apply_batch(order)?;
+
+        let excess = count.saturating_sub(SEEN_EVENTS_LIMIT as u64);
+
+        if excess > 0 {
+            let mut events = sled::Batch::default();
+            let mut order = sled::Batch::default();
+
+            for entry in self.seen_event_order.scan_prefix(&room_key).take(excess as usize) {
+                let (key, event_id) = entry?;
+                events.remove(
+                    (room_id.as_str(), String::from_utf8_lossy(&event_id).as_ref()).encode(),
+                );
+                order.remove(key);
+            }
+
+            self.seen_events.apply_batch(events)?;
+            self.seen_event_order.apply_batch(order)?;
+            count -= excess;
+        }
+
+        self.seen_event_counts.insert(room_key, count.to_be_bytes().to_vec())?;
+
+        Ok(())
+    }
+
+    /// Forget the oldest transactions of the room if it has more than
+    /// [`TRANSACTIONS_LIMIT`] of them.
+    fn prune_transactions(&self, room_id: &RoomId) -> Result<()> {
+        let mut transactions = Vec::new();
+
+        for entry in self.transactions.scan_prefix(room_id.encode()) {
+            let (key, value) = entry?;
+            transactions.push((decode_transaction(&value).0, key));
+        }
+
+        if transactions.len() <= TRANSACTIONS_LIMIT {
+            return Ok(());
+        }
+
+        transactions.sort_unstable_by_key(|(order, _)| *order);
+
+        let excess = transactions.len() - TRANSACTIONS_LIMIT;
+        let mut batch = sled::Batch::default();
+
+        for (_, key) in transactions.into_iter().take(excess) {
+            batch.remove(key);
+        }
+
+        Ok(self.transactions.apply_batch(batch)?)
+    }
+
+    pub async fn export_all(&self) -> Result<StateChanges> {
+        fn decode(key: &[u8], position: usize) -> String {
+            decode_key_value(key, position).unwrap_or_default()
+        }
+
+        let mut changes =
+            StateChanges { sync_token: self.get_sync_token().await?, ..Default::default() };
+
+        for entry in self.account_data.iter() {
+            let (key, value) = entry?;
+            changes.account_data.insert(decode(&key, 0), self.deserialize_event(&value)?);
+        }
+
+        for entry in self.presence.iter() {
+            let (key, value) = entry?;
+            changes
+                .presence
+                .insert(UserId::try_from(decode(&key, 0))?, self.deserialize_event(&value)?);
+        }
+
+        for entry in self.members.iter() {
+            let (key, value) = entry?;
+            let event: MemberEvent = self.deserialize_event(&value)?;
+
+            changes
+                .members
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .insert(event.state_key.clone(), event);
+        }
+
+        for entry in self.profiles.iter() {
+            let (key, value) = entry?;
+
+            changes
+                .profiles
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .insert(UserId::try_from(decode(&key, 1))?, self.deserialize_event(&value)?);
+        }
+
+        for entry in self.display_names.iter() {
+            let (key, value) = entry?;
+
+            changes
+                .ambiguity_maps
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .insert(decode(&key, 1), self.deserialize_event(&value)?);
+        }
+
+        for entry in self.room_info.iter() {
+            let info: RoomInfo = self.deserialize_event(&entry?.1)?;
+            changes.room_infos.insert(info.room_id.as_ref().clone(), info);
+        }
+
+        for entry in self.room_state.iter() {
+            let (key, value) = entry?;
+
+            changes
+                .state
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .entry(decode(&key, 1))
+                .or_default()
+                .insert(decode(&key, 2), self.deserialize_event(&value)?);
+        }
+
+        for entry in self.room_account_data.iter() {
+            let (key, value) = entry?;
+
+            changes
+                .room_account_data
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .insert(decode(&key, 1), self.deserialize_event(&value)?);
+        }
+
+        for entry in self.stripped_room_info.iter() {
+            let info: RoomInfo = self.deserialize_event(&entry?.1)?;
+            changes.invited_room_info.insert(info.room_id.as_ref().clone(), info);
+        }
+
+        for entry in self.stripped_room_state.iter() {
+            let (key, value) = entry?;
+
+            changes
+                .stripped_state
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .entry(decode(&key, 1))
+                .or_default()
+                .insert(decode(&key, 2), self.deserialize_event(&value)?);
+        }
+
+        for entry in self.stripped_members.iter() {
+            let (key, value) = entry?;
+            let event: StrippedMemberEvent = self.deserialize_event(&value)?;
+
+            changes
+                .stripped_members
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .insert(event.state_key.clone(), event);
+        }
+
+        for entry in self.room_user_receipts.iter() {
+            let (key, value) = entry?;
+            let (event_id, receipt): (EventId, Receipt) = self.deserialize_event(&value)?;
+
+            changes
+                .receipts
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_insert_with(|| ReceiptEventContent(Default::default()))
+                .0
+                .entry(event_id)
+                .or_default()
+                .entry(ReceiptType::from(decode(&key, 1)))
+                .or_default()
+                .insert(UserId::try_from(decode(&key, 2))?, receipt);
+        }
+
+        for entry in self.seen_events.iter() {
+            let (key, _) = entry?;
+
+            changes
+                .seen_events
+                .entry(RoomId::try_from(decode(&key, 0))?)
+                .or_default()
+                .insert(EventId::try_from(decode(&key, 1))?);
+        }
+
+        for entry in self.transactions.iter() {
+            let (key, value) = entry?;
+
+            changes.transactions.entry(RoomId::try_from(decode(&key, 0))?).or_default().insert(
+                decode(&key, 1),
+                EventId::try_from(String::from_utf8_lossy(decode_transaction(&value).1).as_ref())?,
+            );
+        }
+
+        Ok(changes)
+    }
 }
 
 #[async_trait]
@@ -818,6 +1190,14 @@ impl StateStore for SledStore {
         self.get_member_event(room_id, state_key).await
     }
 
+    async fn get_stripped_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<StrippedMemberEvent>> {
+        self.get_stripped_member_event(room_id, state_key).await
+    }
+
     async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
         self.get_user_ids_stream(room_id).await.try_collect().await
     }
@@ -879,6 +1259,18 @@ impl StateStore for SledStore {
         self.get_event_room_receipt_events(room_id, receipt_type, event_id).await
     }
 
+    async fn is_event_known(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        self.is_event_known(room_id, event_id).await
+    }
+
+    async fn get_event_id_for_transaction(
+        &self,
+        room_id: &RoomId,
+        txn_id: &str,
+    ) -> Result<Option<EventId>> {
+        self.get_event_id_for_transaction(room_id, txn_id).await
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.add_media_content(request, data).await
     }
@@ -894,6 +1286,20 @@ impl StateStore for SledStore {
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
         self.remove_media_content_for_uri(uri).await
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        self.remove_room(room_id).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn export_all(&self) -> Result<StateChanges> {
+        self.export_all().await
+    }
 }
 
 #[cfg(test)]
@@ -917,7 +1323,7 @@ mod test {
     };
     use serde_json::json;
 
-    use super::{SledStore, StateChanges};
+    use super::{EncodeKey, SledStore, StateChanges, SEEN_EVENTS_LIMIT, TRANSACTIONS_LIMIT};
     use crate::{
         deserialized_responses::MemberEvent,
         media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
@@ -956,6 +1362,86 @@ mod test {
         }
     }
 
+    #[async_test]
+    async fn test_seen_events_limit() {
+        let store = SledStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+        let old_event = event_id!("$old:localhost");
+
+        let mut changes = StateChanges::default();
+        changes.add_seen_event(&room_id, old_event.clone());
+        store.save_changes(&changes).await.unwrap();
+        assert!(store.is_event_known(&room_id, &old_event).await.unwrap());
+
+        let mut changes = StateChanges::default();
+        for i in 0..SEEN_EVENTS_LIMIT {
+            let event_id = EventId::try_from(format!("$new{}:localhost", i)).unwrap();
+            changes.add_seen_event(&room_id, event_id);
+        }
+        store.save_changes(&changes).await.unwrap();
+
+        // The oldest event is forgotten once the room has too many events.
+        assert!(!store.is_event_known(&room_id, &old_event).await.unwrap());
+        assert!(store.is_event_known(&room_id, &event_id!("$new0:localhost")).await.unwrap());
+        assert_eq!(
+            store.export_all().await.unwrap().seen_events[&room_id].len(),
+            SEEN_EVENTS_LIMIT
+        );
+
+        // Seeing an event again makes it the most recent one.
+        let mut changes = StateChanges::default();
+        changes.add_seen_event(&room_id, event_id!("$new0:localhost"));
+        store.save_changes(&changes).await.unwrap();
+
+        let mut changes = StateChanges::default();
+        changes.add_seen_event(&room_id, event_id!("$newest:localhost"));
+        store.save_changes(&changes).await.unwrap();
+
+        assert!(store.is_event_known(&room_id, &event_id!("$new0:localhost")).await.unwrap());
+        assert!(!store.is_event_known(&room_id, &event_id!("$new1:localhost")).await.unwrap());
+        assert_eq!(
+            store.export_all().await.unwrap().seen_events[&room_id].len(),
+            SEEN_EVENTS_LIMIT
+        );
+    }
+
+    #[async_test]
+    async fn test_transactions_limit() {
+        let store = SledStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+        let other_room_id = room_id!("!other:localhost");
+
+        let mut changes = StateChanges::default();
+        changes.add_transaction(&room_id, "old".to_owned(), event_id!("$old:localhost"));
+        changes.add_transaction(&other_room_id, "other".to_owned(), event_id!("$other:localhost"));
+        store.save_changes(&changes).await.unwrap();
+
+        assert_eq!(
+            store.get_event_id_for_transaction(&room_id, "old").await.unwrap(),
+            Some(event_id!("$old:localhost"))
+        );
+
+        let mut changes = StateChanges::default();
+        for i in 0..TRANSACTIONS_LIMIT {
+            let event_id = EventId::try_from(format!("$new{}:localhost", i)).unwrap();
+            changes.add_transaction(&room_id, format!("new{}", i), event_id);
+        }
+        store.save_changes(&changes).await.unwrap();
+
+        // The oldest transactions are forgotten once the room has too many.
+        assert!(store.get_event_id_for_transaction(&room_id, "old").await.unwrap().is_none());
+        assert!(store.get_event_id_for_transaction(&room_id, "new0").await.unwrap().is_some());
+        assert!(store
+            .get_event_id_for_transaction(&other_room_id, "other")
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            store.export_all().await.unwrap().transactions[&room_id].len(),
+            TRANSACTIONS_LIMIT
+        );
+    }
+
     #[async_test]
     async fn test_member_saving() {
         let store = SledStore::open().unwrap();
@@ -977,6 +1463,102 @@ mod test {
         assert!(!members.is_empty())
     }
 
+    #[async_test]
+    async fn test_integrity_check() {
+        let store = SledStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let mut changes = StateChanges::new("t392-516_47314_0_7_1_1_1_11444_1".to_owned());
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), membership_event());
+        store.save_changes(&changes).await.unwrap();
+
+        assert!(store.integrity_report().is_empty());
+        assert!(store.check_integrity().unwrap().is_empty());
+
+        store
+            .members
+            .insert((room_id.as_str(), "@bob:localhost").encode(), b"{ garbage".to_vec())
+            .unwrap();
+        store.room_info.insert(room_id.encode(), b"[]".to_vec()).unwrap();
+        store
+            .room_state
+            .insert(("!other:localhost", "m.room.name", "").encode(), b"{".to_vec())
+            .unwrap();
+
+        let report = store.check_integrity().unwrap();
+        assert_eq!(report.rooms, vec![room_id.to_string()]);
+        assert_eq!(report.members, vec![(room_id.to_string(), "@bob:localhost".to_owned())]);
+        assert_eq!(
+            report.other_entries,
+            vec![(
+                "room_state".to_owned(),
+                vec!["!other:localhost".to_owned(), "m.room.name".to_owned()]
+            )]
+        );
+
+        // The lost state is fetched again by the next sync.
+        assert!(store.get_sync_token().await.unwrap().is_none());
+        assert!(store.get_member_event(&room_id, &user_id).await.unwrap().is_some());
+        assert!(store.check_integrity().unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn test_export_and_import_all() {
+        let store = SledStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+        let event_id = event_id!("$1435641916114394fHBLK:matrix.org");
+
+        let receipt_event = serde_json::from_value(json!({
+            event_id.clone(): {
+                "m.read": {
+                    user_id(): {
+                        "ts": 1436451550453u64
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        let raw_event = power_level_event();
+        let event = raw_event.deserialize().unwrap();
+
+        let mut changes = StateChanges::new("s526_47314_0_7_1_1_1_11444_1".to_owned());
+        changes.add_state_event(&room_id, event, raw_event);
+        changes.add_receipts(&room_id, receipt_event);
+        changes.members.entry(room_id.clone()).or_default().insert(user_id(), membership_event());
+        store.save_changes(&changes).await.unwrap();
+
+        let exported = store.export_all().await.unwrap();
+        let exported: StateChanges =
+            serde_json::from_str(&serde_json::to_string(&exported).unwrap()).unwrap();
+
+        let other_store = SledStore::open().unwrap();
+        other_store.import_all(&exported).await.unwrap();
+
+        assert_eq!(other_store.get_sync_token().await.unwrap(), changes.sync_token);
+        assert!(other_store
+            .get_state_event(&room_id, EventType::RoomPowerLevels, "")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(other_store.get_member_event(&room_id, &user_id()).await.unwrap().is_some());
+        assert!(!StateStore::get_joined_user_ids(&other_store, &room_id).await.unwrap().is_empty());
+        assert_eq!(
+            other_store
+                .get_user_room_receipt_event(&room_id, ReceiptType::Read, &user_id())
+                .await
+                .unwrap()
+                .unwrap()
+                .0,
+            event_id
+        );
+    }
+
     #[async_test]
     async fn test_power_level_saving() {
         let store = SledStore::open().unwrap();
@@ -5,12 +5,13 @@ use ruma::{
         push::get_notifications::Notification,
         sync::sync_events::{
             DeviceLists, Ephemeral, GlobalAccountData, InvitedRoom, Presence, RoomAccountData,
-            State, ToDevice, UnreadNotificationsCount as RumaUnreadNotificationsCount,
+            State, ToDevice as RumaToDevice,
+            UnreadNotificationsCount as RumaUnreadNotificationsCount,
         },
     },
     events::{
-        room::member::MemberEventContent, AnySyncRoomEvent, StateEvent, StrippedStateEvent,
-        SyncStateEvent, Unsigned,
+        room::member::MemberEventContent, AnyStateEvent, AnySyncRoomEvent, AnyToDeviceEvent,
+        StateEvent, StrippedStateEvent, SyncStateEvent, Unsigned,
     },
     identifiers::{DeviceKeyAlgorithm, EventId, RoomId, UserId},
     serde::Raw,
@@ -40,7 +41,7 @@ pub struct AmbiguityChanges {
 }
 
 /// The verification state of the device that sent an event to us.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum VerificationState {
     /// The device is trusted.
     Trusted,
@@ -48,6 +49,10 @@ pub enum VerificationState {
     Untrusted,
     /// The device is not known to us.
     UnknownDevice,
+    /// The device is known to us but the keys of the session that encrypted
+    /// the event don't belong to it, the event was either sent by a
+    /// different device or the sender was forged.
+    MismatchedSender,
 }
 
 /// The algorithm specific information of a decrypted event.
@@ -65,6 +70,14 @@ pub enum AlgorithmInfo {
         /// Chain of curve25519 keys through which this session was forwarded,
         /// via m.forwarded_room_key events.
         forwarding_curve25519_key_chain: Vec<String>,
+        /// The message index of the event in the megolm session.
+        #[serde(default)]
+        message_index: u32,
+        /// The first message index of the megolm session that we can decrypt,
+        /// messages with a lower index can't be decrypted using the session we
+        /// have.
+        #[serde(default)]
+        first_known_index: u32,
     },
 }
 
@@ -85,6 +98,35 @@ pub struct EncryptionInfo {
     pub verification_state: VerificationState,
 }
 
+impl EncryptionInfo {
+    /// The curve25519 key of the device that created the session that
+    /// encrypted the event.
+    pub fn sender_curve25519_key(&self) -> &str {
+        match &self.algorithm_info {
+            AlgorithmInfo::MegolmV1AesSha2 { curve25519_key, .. } => curve25519_key,
+        }
+    }
+
+    /// Are there earlier messages in the session that encrypted the event
+    /// that we can't decrypt.
+    ///
+    /// This is the case if we only hold a later ratchet state of the session,
+    /// e.g. because we received it from another device of ours after some
+    /// messages were already sent. The session can be requested again using
+    /// `OlmMachine::request_room_key()`, in the hope that another device has
+    /// the session from the first message index.
+    pub fn is_missing_earlier_messages(&self) -> bool {
+        match &self.algorithm_info {
+            AlgorithmInfo::MegolmV1AesSha2 { first_known_index, .. } => *first_known_index > 0,
+        }
+    }
+
+    /// Was the event sent by a device that we trust.
+    pub fn is_verified(&self) -> bool {
+        self.verification_state == VerificationState::Trusted
+    }
+}
+
 /// A customized version of a room event coming from a sync that holds optional
 /// encryption info.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -96,12 +138,98 @@ pub struct SyncRoomEvent {
     pub encryption_info: Option<EncryptionInfo>,
 }
 
+impl SyncRoomEvent {
+    /// Deserialize the event into its typed form.
+    ///
+    /// Events are kept in their raw form while a sync response gets processed,
+    /// only the events that are needed to update the room state get
+    /// deserialized. Use this to get the typed version of any other event.
+    pub fn deserialize(&self) -> serde_json::Result<AnySyncRoomEvent> {
+        self.event.deserialize()
+    }
+}
+
 impl From<Raw<AnySyncRoomEvent>> for SyncRoomEvent {
     fn from(inner: Raw<AnySyncRoomEvent>) -> Self {
         Self { encryption_info: None, event: inner }
     }
 }
 
+/// An event together with the events that happened right before and after it,
+/// as returned by the `/context` endpoint.
+///
+/// Encrypted events are decrypted if the room key for them is known.
+#[derive(Clone, Debug, Default)]
+pub struct EventContext {
+    /// The requested event.
+    pub event: Option<SyncRoomEvent>,
+    /// The events that happened right before the requested event, in
+    /// reverse-chronological order.
+    pub events_before: Vec<SyncRoomEvent>,
+    /// The events that happened right after the requested event, in
+    /// chronological order.
+    pub events_after: Vec<SyncRoomEvent>,
+    /// The state of the room at the last returned event.
+    pub state: Vec<Raw<AnyStateEvent>>,
+    /// A token that can be used to paginate backwards from the earliest
+    /// returned event.
+    pub start: Option<String>,
+    /// A token that can be used to paginate forwards from the latest returned
+    /// event.
+    pub end: Option<String>,
+}
+
+/// Struct containing information on how a to-device event was decrypted.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OlmEncryptionInfo {
+    /// The user ID of the event sender, note this is untrusted data unless the
+    /// `verification_state` is as well trusted.
+    pub sender: UserId,
+    /// The device ID of the device that sent us the event, `None` if we don't
+    /// know a device of the sender that owns the curve25519 key of the event.
+    pub sender_device: Option<DeviceIdBox>,
+    /// The curve25519 key of the device that sent us the event.
+    pub sender_curve25519_key: String,
+    /// The ed25519 key that the sender claims to own, this is only trusted if
+    /// the `verification_state` is trusted.
+    pub sender_claimed_ed25519_key: String,
+    /// The verification state of the device that sent us the event, note this
+    /// is the state of the device at the time of decryption. It may change in
+    /// the future if a device gets verified or deleted.
+    pub verification_state: VerificationState,
+}
+
+/// A customized version of a to-device event coming from a sync that holds
+/// optional encryption info.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ToDeviceEvent {
+    /// The actual event, if the event was encrypted this is the decrypted
+    /// version of it.
+    pub event: Raw<AnyToDeviceEvent>,
+    /// The encryption info about the event. Will be `None` if the event was not
+    /// encrypted.
+    pub encryption_info: Option<OlmEncryptionInfo>,
+}
+
+impl From<Raw<AnyToDeviceEvent>> for ToDeviceEvent {
+    fn from(inner: Raw<AnyToDeviceEvent>) -> Self {
+        Self { encryption_info: None, event: inner }
+    }
+}
+
+/// Messages sent directly between devices.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ToDevice {
+    /// A list of to-device events.
+    pub events: Vec<ToDeviceEvent>,
+}
+
+impl From<RumaToDevice> for ToDevice {
+    fn from(to_device: RumaToDevice) -> Self {
+        Self { events: to_device.events.into_iter().map(ToDeviceEvent::from).collect() }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct SyncResponse {
     /// The batch token to supply in the `since` param of the next `/sync`
@@ -1,40 +1,394 @@
 //! Abstraction over an executor so we can spawn tasks under WASM the same way
 //! we do usually.
-#[cfg(target_arch = "wasm32")]
+//!
+//! The SDK doesn't spawn tasks or wait for timers on a specific async runtime,
+//! it goes through the [`Executor`] that is set using [`set_executor()`]. By
+//! default tasks are spawned on the ambient tokio runtime, or using
+//! `spawn_local()` on WASM. Applications that don't run a tokio runtime can
+//! plug in their own runtime, the `async-std` feature provides an executor for
+//! async-std:
+//!
+//! ```ignore
+//! use matrix_sdk_common::executor::{set_executor, AsyncStdExecutor};
+//!
+//! set_executor(AsyncStdExecutor).expect("No task was spawned yet");
+//! ```
+//!
+//! Only the tasks and timers of the SDK go through the executor. The default
+//! HTTP client of the `matrix-sdk` crate is based on reqwest, which still
+//! requires a tokio runtime, applications without one need to provide their
+//! own `HttpSend` implementation as well.
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+};
 use std::{
+    error::Error,
+    fmt,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
+#[cfg(not(target_arch = "wasm32"))]
+use futures::channel::oneshot;
+use futures::{
+    future::{self, Either, RemoteHandle},
+    pin_mut, Future, FutureExt,
+};
+use futures_timer::Delay;
+use once_cell::sync::OnceCell;
+
+/// A type-erased future that can be handed to an [`Executor`], this type will
+/// differ if it's used on WASM. WASM targets will not require the future to
+/// be `Send`, while other targets will.
+#[cfg(not(target_arch = "wasm32"))]
+pub type BoxedFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// A type-erased future that can be handed to an [`Executor`], this type will
+/// differ if it's used on WASM. WASM targets will not require the future to
+/// be `Send`, while other targets will.
 #[cfg(target_arch = "wasm32")]
-use futures::{future::RemoteHandle, Future, FutureExt};
+pub type BoxedFuture<T> = Pin<Box<dyn Future<Output = T> + 'static>>;
+
+/// The async runtime the SDK spawns its tasks on.
+pub trait Executor: fmt::Debug + Send + Sync {
+    /// Run the given future in the background until it completes.
+    fn spawn(&self, future: BoxedFuture<()>);
+
+    /// Run the given blocking closure on a thread where blocking is
+    /// acceptable.
+    ///
+    /// The default implementation starts a new thread for every closure.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send + 'static>) {
+        std::thread::spawn(task);
+    }
+
+    /// Create a future that completes once the given duration has passed.
+    ///
+    /// The default implementation uses a timer that doesn't depend on a
+    /// runtime.
+    fn sleep(&self, duration: Duration) -> BoxedFuture<()> {
+        Box::pin(Delay::new(duration))
+    }
+}
+
+/// An [`Executor`] that spawns tasks on the tokio runtime of the current
+/// thread, this is the default executor on non-WASM targets.
+///
+/// Spawning a task panics if the current thread doesn't belong to a tokio
+/// runtime.
 #[cfg(not(target_arch = "wasm32"))]
-pub use tokio::spawn;
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioExecutor;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Executor for TokioExecutor {
+    fn spawn(&self, future: BoxedFuture<()>) {
+        tokio::spawn(future);
+    }
+
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send + 'static>) {
+        tokio::task::spawn_blocking(task);
+    }
+}
+
+/// An [`Executor`] that spawns tasks on the global async-std runtime.
+#[cfg(all(feature = "async-std", not(target_arch = "wasm32")))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdExecutor;
+
+#[cfg(all(feature = "async-std", not(target_arch = "wasm32")))]
+impl Executor for AsyncStdExecutor {
+    fn spawn(&self, future: BoxedFuture<()>) {
+        async_std_runtime::task::spawn(future);
+    }
+
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send + 'static>) {
+        async_std_runtime::task::spawn_blocking(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxedFuture<()> {
+        Box::pin(async_std_runtime::task::sleep(duration))
+    }
+}
+
+/// An [`Executor`] that spawns tasks on the event loop of the browser, this is
+/// the default executor on WASM.
 #[cfg(target_arch = "wasm32")]
-use wasm_bindgen_futures::spawn_local;
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WasmExecutor;
 
 #[cfg(target_arch = "wasm32")]
+impl Executor for WasmExecutor {
+    fn spawn(&self, future: BoxedFuture<()>) {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+}
+
+static EXECUTOR: OnceCell<Box<dyn Executor>> = OnceCell::new();
+
+/// Set the executor the SDK spawns its tasks on.
+///
+/// This needs to be called before the SDK spawns its first task, e.g. before
+/// the first sync, the executor can't be replaced afterwards. The executor is
+/// given back if another one is already in use.
+pub fn set_executor<E: Executor + 'static>(executor: E) -> Result<(), E> {
+    let mut executor = Some(executor);
+    EXECUTOR.get_or_init(|| Box::new(executor.take().expect("The executor is only taken once")));
+
+    match executor {
+        Some(executor) => Err(executor),
+        None => Ok(()),
+    }
+}
+
+fn executor() -> &'static dyn Executor {
+    #[cfg(not(target_arch = "wasm32"))]
+    let executor = EXECUTOR.get_or_init(|| Box::new(TokioExecutor));
+    #[cfg(target_arch = "wasm32")]
+    let executor = EXECUTOR.get_or_init(|| Box::new(WasmExecutor));
+
+    executor.as_ref()
+}
+
+/// Spawn the given future on the current [`Executor`].
+///
+/// The task keeps running if the returned handle is dropped.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn spawn<F, T>(future: F) -> JoinHandle<T>
 where
-    F: Future<Output = T> + 'static,
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
 {
-    let fut = future.unit_error();
-    let (fut, handle) = fut.remote_handle();
-    spawn_local(fut);
+    let (future, handle) =
+        AssertUnwindSafe(future).catch_unwind().map(|r| r.map_err(JoinError::new)).remote_handle();
+    executor().spawn(Box::pin(future));
 
-    JoinHandle { handle }
+    JoinHandle { handle: Some(handle) }
 }
 
+/// Spawn the given future on the current [`Executor`].
+///
+/// The task keeps running if the returned handle is dropped.
 #[cfg(target_arch = "wasm32")]
+pub fn spawn<F, T>(future: F) -> JoinHandle<T>
+where
+    F: Future<Output = T> + 'static,
+    T: 'static,
+{
+    let (future, handle) = future.map(Ok).remote_handle();
+    executor().spawn(Box::pin(future));
+
+    JoinHandle { handle: Some(handle) }
+}
+
+/// Run the given blocking closure using the current [`Executor`].
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn spawn_blocking<F, T>(task: F) -> Result<T, JoinError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+
+    executor().spawn_blocking(Box::new(move || {
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(task)).map_err(JoinError::new));
+    }));
+
+    receiver.await.unwrap_or(Err(JoinError { panic: None }))
+}
+
+/// Wait until the given duration has passed, using the timer of the current
+/// [`Executor`].
+pub async fn sleep(duration: Duration) {
+    executor().sleep(duration).await
+}
+
+/// Wait for the given future to complete, but at most for the given duration.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let sleep = sleep(duration);
+    pin_mut!(future, sleep);
+
+    match future::select(future, sleep).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed(())),
+    }
+}
+
+/// A handle to a spawned task that can be awaited to get the output of the
+/// task.
+#[derive(Debug)]
 pub struct JoinHandle<T> {
-    handle: RemoteHandle<Result<T, ()>>,
+    handle: Option<RemoteHandle<Result<T, JoinError>>>,
 }
 
-#[cfg(target_arch = "wasm32")]
 impl<T: 'static> Future for JoinHandle<T> {
-    type Output = Result<T, ()>;
+    type Output = Result<T, JoinError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        Pin::new(&mut self.handle).poll(cx)
+        self.handle.as_mut().expect("The handle is only taken when dropped").poll_unpin(cx)
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Turn this handle into one that cancels the task once it's dropped,
+    /// e.g. to tie the task to the future that awaits it.
+    pub fn abort_on_drop(mut self) -> AbortOnDropHandle<T> {
+        AbortOnDropHandle {
+            handle: self.handle.take().expect("The handle is only taken when dropped"),
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // A dropped remote handle cancels the task, detach it instead.
+        if let Some(handle) = self.handle.take() {
+            handle.forget();
+        }
+    }
+}
+
+/// A handle to a spawned task that cancels the task once it's dropped, see
+/// [`JoinHandle::abort_on_drop()`].
+#[derive(Debug)]
+pub struct AbortOnDropHandle<T> {
+    handle: RemoteHandle<Result<T, JoinError>>,
+}
+
+impl<T: 'static> Future for AbortOnDropHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.handle.poll_unpin(cx)
+    }
+}
+
+/// The error of a task that panicked.
+pub struct JoinError {
+    #[cfg(not(target_arch = "wasm32"))]
+    panic: Option<Box<dyn Any + Send + 'static>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl JoinError {
+    fn new(panic: Box<dyn Any + Send + 'static>) -> Self {
+        Self { panic: Some(panic) }
+    }
+
+    /// Continue unwinding with the panic of the task.
+    ///
+    /// Panics with a generic message if the task was dropped by the executor
+    /// before it completed.
+    pub fn resume_unwind(self) -> ! {
+        match self.panic {
+            Some(panic) => panic::resume_unwind(panic),
+            None => panic!("The task was dropped by the executor"),
+        }
+    }
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinError").finish()
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.panic.is_none() {
+            return f.write_str("the task was dropped before it completed");
+        }
+
+        f.write_str("the task panicked")
+    }
+}
+
+impl Error for JoinError {}
+
+/// The error of a [`timeout()`] that elapsed before the future completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the deadline has elapsed")
+    }
+}
+
+impl Error for Elapsed {}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use std::time::Duration;
+
+    use futures::{executor::block_on, future};
+    use tokio::runtime::Builder;
+
+    use super::{spawn, spawn_blocking, timeout, Executor, TokioExecutor};
+
+    #[test]
+    fn timeout_elapses() {
+        block_on(async {
+            assert_eq!(timeout(Duration::from_secs(10), future::ready(1)).await, Ok(1));
+            assert!(timeout(Duration::from_millis(10), future::pending::<()>()).await.is_err());
+        })
+    }
+
+    #[test]
+    fn blocking_tasks() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+
+        runtime.block_on(async {
+            assert_eq!(spawn_blocking(|| 1 + 1).await.unwrap(), 2);
+
+            let result = spawn_blocking(|| -> u32 { panic!("Boom") }).await;
+            assert_eq!(result.unwrap_err().to_string(), "the task panicked");
+        })
+    }
+
+    #[test]
+    fn abort_on_drop() {
+        use futures::channel::oneshot;
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+
+        runtime.block_on(async {
+            let (sender, receiver) = oneshot::channel::<()>();
+            let handle = spawn(async move {
+                let _sender = sender;
+                future::pending::<()>().await;
+            });
+
+            // Dropping the handle cancels the task, which drops the sender.
+            drop(handle.abort_on_drop());
+            assert!(receiver.await.is_err());
+        })
+    }
+
+    #[test]
+    fn default_sleep_without_runtime() {
+        block_on(TokioExecutor.sleep(Duration::from_millis(1)));
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn async_std_executor() {
+        use futures::channel::oneshot;
+
+        use super::AsyncStdExecutor;
+
+        let (sender, receiver) = oneshot::channel();
+
+        AsyncStdExecutor.spawn(Box::pin(async move {
+            AsyncStdExecutor.sleep(Duration::from_millis(1)).await;
+            sender.send(1).unwrap();
+        }));
+
+        assert_eq!(block_on(receiver), Ok(1));
     }
 }
@@ -0,0 +1,240 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying of fallible async operations using exponential backoff with
+//! jitter.
+//!
+//! A [`RetryPolicy`] decides if a failed operation should be retried and how
+//! long to wait before the next attempt, the [`retry()`] function drives an
+//! operation using such a policy.
+
+use std::{fmt, future::Future, time::Duration};
+
+use instant::Instant;
+use uuid::Uuid;
+
+use crate::executor::sleep;
+
+/// The delay before the first retry if none is given.
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// The upper limit for the delay between two attempts if none is given.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// The default amount of jitter that is applied to the delays.
+const DEFAULT_JITTER: f64 = 0.5;
+
+/// Errors that know if the operation that produced them is worth retrying.
+///
+/// This is used as the default classifier of a [`RetryPolicy`].
+pub trait Retryable {
+    /// Is the failure transient, e.g. a network or server error, so that the
+    /// operation may succeed if it's retried.
+    fn is_retryable(&self) -> bool;
+}
+
+/// A policy describing if and when a failed operation should be retried.
+///
+/// The delay between attempts doubles with every attempt, starting at the
+/// base delay and capped at the max delay. A random jitter is applied to the
+/// delays, so that many clients don't retry at the same time after an outage.
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Duration;
+/// # use matrix_sdk_common::retry::RetryPolicy;
+/// #[derive(Debug)]
+/// enum Error {
+///     Timeout,
+///     Forbidden,
+/// }
+///
+/// let policy = RetryPolicy::new(|e: &Error| matches!(e, Error::Timeout))
+///     .max_attempts(5)
+///     .base_delay(Duration::from_millis(100))
+///     .jitter(0.2);
+/// ```
+pub struct RetryPolicy<E> {
+    max_attempts: Option<u32>,
+    max_elapsed_time: Option<Duration>,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    classifier: fn(&E) -> bool,
+}
+
+impl<E> Clone for RetryPolicy<E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E> Copy for RetryPolicy<E> {}
+
+#[cfg(not(tarpaulin_include))]
+impl<E> fmt::Debug for RetryPolicy<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("max_elapsed_time", &self.max_elapsed_time)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+impl<E: Retryable> Default for RetryPolicy<E> {
+    fn default() -> Self {
+        Self::new(E::is_retryable)
+    }
+}
+
+impl<E> RetryPolicy<E> {
+    /// Create a new policy that retries errors for which the given classifier
+    /// returns true.
+    ///
+    /// By default failed operations are retried indefinitely.
+    pub fn new(classifier: fn(&E) -> bool) -> Self {
+        Self {
+            max_attempts: None,
+            max_elapsed_time: None,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: DEFAULT_JITTER,
+            classifier,
+        }
+    }
+
+    /// A policy that never retries.
+    pub fn never() -> Self {
+        Self::new(|_| false).max_attempts(1)
+    }
+
+    /// Set the maximal number of attempts, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Set the time after which no new attempt will be started.
+    pub fn max_elapsed_time(mut self, max_elapsed_time: Duration) -> Self {
+        self.max_elapsed_time = Some(max_elapsed_time);
+        self
+    }
+
+    /// Set the delay before the first retry.
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Set the upper limit for the delay between two attempts, before the
+    /// jitter is applied.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set the amount of jitter, as a fraction of the delay.
+    ///
+    /// A jitter of `0.5` means that the delay is randomly picked between 50%
+    /// and 150% of the calculated delay. The value is clamped between `0.0`,
+    /// no jitter, and `1.0`.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    /// Set the classifier that decides which errors should be retried.
+    pub fn classifier(mut self, classifier: fn(&E) -> bool) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    /// Should an operation be retried after it failed with the given error.
+    pub fn is_retryable(&self, error: &E) -> bool {
+        (self.classifier)(error)
+    }
+
+    /// The delay before the given retry, the first retry has the number `1`.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let delay = self.base_delay.checked_mul(1u32 << exponent).unwrap_or(self.max_delay);
+        let delay = delay.min(self.max_delay);
+
+        // Uuids are our only portable source of randomness, the lower 53 bits
+        // of a v4 uuid are random and fit into the mantissa of a f64.
+        let random = (Uuid::new_v4().as_u128() as u64 & ((1 << 53) - 1)) as f64;
+        let random = random / (1u64 << 53) as f64;
+
+        delay.mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+    }
+
+    /// Get the delay before the next attempt of an operation, or `None` if the
+    /// operation should not be retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - The number of the attempt that failed, the first attempt
+    /// has the number `1`.
+    ///
+    /// * `elapsed` - The time that passed since the first attempt started.
+    ///
+    /// * `error` - The error of the failed attempt.
+    pub fn next_delay(&self, attempt: u32, elapsed: Duration, error: &E) -> Option<Duration> {
+        if !self.is_retryable(error) || self.max_attempts.map_or(false, |max| attempt >= max) {
+            return None;
+        }
+
+        let delay = self.delay(attempt);
+
+        match self.max_elapsed_time {
+            Some(max) if elapsed + delay > max => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// Run the given operation until it succeeds or the policy decides to stop
+/// retrying it.
+///
+/// Returns the result of the last attempt.
+///
+/// # Arguments
+///
+/// * `policy` - The policy that decides if and when the operation is retried.
+///
+/// * `operation` - A closure creating the future of a single attempt.
+pub async fn retry<T, E, F, Fut>(policy: &RetryPolicy<E>, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match operation().await {
+            Ok(result) => return Ok(result),
+            Err(error) => match policy.next_delay(attempt, start.elapsed(), &error) {
+                Some(delay) => sleep(delay).await,
+                None => return Err(error),
+            },
+        }
+    }
+}
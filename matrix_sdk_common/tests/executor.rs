@@ -0,0 +1,58 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+// The executor is global, so the custom executor is tested in its own binary.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use futures::executor::block_on;
+use matrix_sdk_common::executor::{
+    set_executor, sleep, spawn, spawn_blocking, timeout, BoxedFuture, Executor,
+};
+
+static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+static SLEEPS: AtomicUsize = AtomicUsize::new(0);
+
+/// An executor that runs every task on its own thread, without any runtime.
+#[derive(Debug)]
+struct ThreadExecutor;
+
+impl Executor for ThreadExecutor {
+    fn spawn(&self, future: BoxedFuture<()>) {
+        SPAWNED.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || block_on(future));
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxedFuture<()> {
+        SLEEPS.fetch_add(1, Ordering::SeqCst);
+        Box::pin(futures_timer::Delay::new(duration))
+    }
+}
+
+#[test]
+fn custom_executor() {
+    set_executor(ThreadExecutor).unwrap();
+    assert!(set_executor(ThreadExecutor).is_err());
+
+    block_on(async {
+        let task = spawn(async {
+            sleep(Duration::from_millis(1)).await;
+            1 + 1
+        });
+
+        assert_eq!(task.await.unwrap(), 2);
+        assert_eq!(SPAWNED.load(Ordering::SeqCst), 1);
+        assert_eq!(SLEEPS.load(Ordering::SeqCst), 1);
+
+        assert_eq!(spawn_blocking(|| 2 + 2).await.unwrap(), 4);
+
+        let result = spawn(async { panic!("Boom") }).await;
+        assert_eq!(result.unwrap_err().to_string(), "the task panicked");
+
+        assert!(timeout(Duration::from_millis(1), futures::future::pending::<()>()).await.is_err());
+        assert_eq!(SLEEPS.load(Ordering::SeqCst), 2);
+    });
+}
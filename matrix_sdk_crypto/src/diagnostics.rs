@@ -0,0 +1,70 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reports about the state of the end to end encryption, meant to be shown in
+//! a debug panel of a client.
+
+use ruma::{DeviceIdBox, RoomId, UserId};
+
+/// The number of room keys in the store, see
+/// [`OlmMachine::room_key_counts()`](crate::OlmMachine::room_key_counts).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoomKeyCounts {
+    /// The number of inbound group sessions.
+    pub total: usize,
+    /// The number of inbound group sessions that were marked as backed up.
+    pub backed_up: usize,
+}
+
+/// Which private cross signing keys this device has, see
+/// [`OlmMachine::cross_signing_status()`](crate::OlmMachine::cross_signing_status).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CrossSigningStatus {
+    /// Do we have the master key.
+    pub has_master_key: bool,
+    /// Do we have the self signing key, needed to sign our own devices.
+    pub has_self_signing_key: bool,
+    /// Do we have the user signing key, needed to sign other users.
+    pub has_user_signing_key: bool,
+}
+
+impl CrossSigningStatus {
+    /// Do we have all the private cross signing keys.
+    pub fn is_complete(&self) -> bool {
+        self.has_master_key && self.has_self_signing_key && self.has_user_signing_key
+    }
+}
+
+/// The state of the encryption of a room, see
+/// [`OlmMachine::encryption_health()`](crate::OlmMachine::encryption_health).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptionHealth {
+    /// The id of the room.
+    pub room_id: RoomId,
+    /// Is there an outbound group session for the room that is shared and can
+    /// still be used to encrypt messages.
+    pub has_usable_outbound_session: bool,
+    /// The devices of the members that we don't have an Olm session with, the
+    /// room key can't be shared with them until one is established.
+    pub devices_without_sessions: Vec<(UserId, DeviceIdBox)>,
+    /// The members whose devices weren't queried yet or might be outdated.
+    pub users_with_unqueried_devices: Vec<UserId>,
+}
+
+impl EncryptionHealth {
+    /// Can all known devices of the members receive the room key.
+    pub fn is_healthy(&self) -> bool {
+        self.devices_without_sessions.is_empty() && self.users_with_unqueried_devices.is_empty()
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sources of randomness and time of the crate.
+//!
+//! All randomness and all timestamps the crate itself generates go through
+//! this module. Tests can switch the current thread into a deterministic mode
+//! using [`deterministic()`], in which randomness comes from a seeded
+//! generator and the time is frozen until it's advanced explicitly. This
+//! makes failures of randomized tests reproducible.
+//!
+//! Only the randomness this crate generates itself is covered: the salts,
+//! nonces, IVs and secrets of the file encryption, key exports, recovery keys
+//! and pickle keys, the fallback keys, and the transaction and request ids.
+//! The olm-rs bindings draw their own randomness from the operating system,
+//! so the identity keys of an account, the key material of one-time keys, the
+//! ratchet keys of Olm sessions, Megolm session keys and the ephemeral keys of
+//! SAS verifications differ between runs even in deterministic mode. Tests
+//! must not rely on those being reproducible; the ids of one-time keys are
+//! stable nonetheless since libolm counts them up.
+
+#[cfg(test)]
+use std::{cell::RefCell, time::Duration};
+
+#[cfg(test)]
+use matrix_sdk_common::uuid;
+use matrix_sdk_common::{instant::Instant, uuid::Uuid};
+use ruma::MilliSecondsSinceUnixEpoch;
+
+#[cfg(test)]
+thread_local! {
+    static DETERMINISTIC: RefCell<Option<Deterministic>> = RefCell::new(None);
+}
+
+/// Fill the given buffer with random bytes.
+///
+/// # Panics
+///
+/// Panics if the operating system can't provide randomness.
+pub(crate) fn fill_random(dest: &mut [u8]) {
+    #[cfg(test)]
+    {
+        if with_deterministic(|d| d.fill(dest)).is_some() {
+            return;
+        }
+    }
+
+    getrandom::getrandom(dest).expect("Can't generate randomness");
+}
+
+/// Generate a new random UUID, e.g. for a transaction or request id.
+pub(crate) fn random_uuid() -> Uuid {
+    #[cfg(test)]
+    {
+        let mut bytes = [0u8; 16];
+
+        if with_deterministic(|d| d.fill(&mut bytes)).is_some() {
+            return uuid::Builder::from_bytes(bytes)
+                .set_variant(uuid::Variant::RFC4122)
+                .set_version(uuid::Version::Random)
+                .build();
+        }
+    }
+
+    Uuid::new_v4()
+}
+
+/// The current instant, used to measure the age of sessions and requests.
+pub(crate) fn now() -> Instant {
+    #[cfg(test)]
+    {
+        if let Some(now) = with_deterministic(|d| d.start + d.elapsed) {
+            return now;
+        }
+    }
+
+    Instant::now()
+}
+
+/// The current wall clock time, used for the timestamps of events.
+pub(crate) fn timestamp() -> MilliSecondsSinceUnixEpoch {
+    #[cfg(test)]
+    {
+        if let Some(ts) = with_deterministic(|d| d.timestamp()) {
+            return ts;
+        }
+    }
+
+    MilliSecondsSinceUnixEpoch::now()
+}
+
+#[cfg(test)]
+fn with_deterministic<T>(f: impl FnOnce(&mut Deterministic) -> T) -> Option<T> {
+    DETERMINISTIC.with(|d| d.borrow_mut().as_mut().map(f))
+}
+
+/// The wall clock time deterministic mode starts at, 2021-01-01 00:00:00 UTC.
+#[cfg(test)]
+const DETERMINISTIC_EPOCH_MILLIS: u64 = 1_609_459_200_000;
+
+#[cfg(test)]
+struct Deterministic {
+    state: u64,
+    start: Instant,
+    elapsed: Duration,
+}
+
+#[cfg(test)]
+impl Deterministic {
+    /// The next output of a SplitMix64 generator, good enough to replace the
+    /// randomness in tests but not for anything else.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn timestamp(&self) -> MilliSecondsSinceUnixEpoch {
+        let millis = DETERMINISTIC_EPOCH_MILLIS + self.elapsed.as_millis() as u64;
+        MilliSecondsSinceUnixEpoch(ruma::UInt::new(millis).expect("Timestamp out of range"))
+    }
+}
+
+/// Switches the current thread back to real randomness and time when it's
+/// dropped.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct DeterministicGuard(());
+
+#[cfg(test)]
+impl DeterministicGuard {
+    /// Advance the frozen time by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        with_deterministic(|d| d.elapsed += duration);
+    }
+}
+
+#[cfg(test)]
+impl Drop for DeterministicGuard {
+    fn drop(&mut self) {
+        DETERMINISTIC.with(|d| d.borrow_mut().take());
+    }
+}
+
+/// Switch the current thread into deterministic mode, with randomness seeded
+/// by the given seed and a frozen time.
+///
+/// The randomness of libolm isn't seeded, see the [module](self) docs. Only
+/// the current thread is affected, work that is moved to other threads,
+/// e.g. by [`parallel_map()`](crate::utilities::parallel_map), still uses
+/// real randomness and time.
+#[cfg(test)]
+pub(crate) fn deterministic(seed: u64) -> DeterministicGuard {
+    DETERMINISTIC.with(|d| {
+        *d.borrow_mut() = Some(Deterministic {
+            state: seed,
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+        })
+    });
+
+    DeterministicGuard(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{deterministic, fill_random, now, random_uuid, timestamp};
+
+    #[test]
+    fn deterministic_mode() {
+        let sample = || {
+            let mut bytes = [0u8; 20];
+            fill_random(&mut bytes);
+            (bytes, random_uuid())
+        };
+
+        let first = {
+            let _guard = deterministic(42);
+            sample()
+        };
+        let second = {
+            let _guard = deterministic(42);
+            sample()
+        };
+
+        assert_eq!(first, second);
+        assert_ne!(first, sample());
+        assert_eq!(first.1.get_version_num(), 4);
+
+        let guard = deterministic(1);
+        let (instant, ts) = (now(), timestamp());
+
+        assert_eq!(now(), instant);
+        assert_eq!(timestamp(), ts);
+
+        guard.advance(Duration::from_secs(5));
+
+        assert_eq!(now() - instant, Duration::from_secs(5));
+        assert_eq!(u64::from(timestamp().get()) - u64::from(ts.get()), 5000);
+    }
+}
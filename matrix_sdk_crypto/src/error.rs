@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use olm_rs::errors::{OlmGroupSessionError, OlmSessionError};
-use ruma::{identifiers::Error as IdentifierError, DeviceId, UserId};
+use ruma::{identifiers::Error as IdentifierError, DeviceId, EventId, UserId};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
@@ -46,6 +46,10 @@ pub enum OlmError {
     #[error("failed to read or write to the crypto store {0}")]
     Store(#[from] CryptoStoreError),
 
+    /// A signature of a JSON object couldn't be verified.
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+
     /// The session with a device has become corrupted.
     #[error(
         "decryption failed likely because an Olm session from {0} with sender key {1} was wedged"
@@ -90,6 +94,40 @@ pub enum MegolmError {
     #[error("The room where a group session should be shared is not encrypted")]
     EncryptionNotEnabled,
 
+    /// The event was encrypted at a message index before the first one the
+    /// group session we have can decrypt, we only hold a later ratchet state
+    /// of the session.
+    ///
+    /// The full session is requested from our other devices.
+    #[error(
+        "decryption failed because the group session {session_id} is only known from the \
+            message index {first_known_index}"
+    )]
+    UnknownMessageIndex {
+        /// The id of the group session that encrypted the event.
+        session_id: String,
+        /// The first message index the group session can decrypt.
+        first_known_index: u32,
+    },
+
+    /// A Megolm message index was already used to decrypt a different event,
+    /// the event is likely a replay attack.
+    ///
+    /// Decrypting the same event again, e.g. because the server delivered it
+    /// twice, isn't treated as a replay.
+    #[error(
+        "decryption failed because the message index {message_index} of the group session \
+            {session_id} was already used by the event {original_event_id}"
+    )]
+    ReplayedEvent {
+        /// The id of the group session that encrypted the event.
+        session_id: String,
+        /// The message index that was used by both events.
+        message_index: u32,
+        /// The id of the event that used the message index first.
+        original_event_id: EventId,
+    },
+
     /// The storage layer returned an error.
     #[error(transparent)]
     Store(#[from] CryptoStoreError),
@@ -135,29 +173,45 @@ pub enum SessionUnpicklingError {
     SessionTimestampError,
 }
 
+/// Error representing a failure while signing or verifying the signature of a
+/// JSON object.
 #[derive(Error, Debug)]
 pub enum SignatureError {
+    /// The signature used an unsupported algorithm.
     #[error("the signature used a unsupported algorithm")]
     UnsupportedAlgorithm,
 
+    /// The key ID of the signing key is invalid.
     #[error("the key id of the signing key is invalid")]
     InvalidKeyId(#[from] IdentifierError),
 
+    /// The signing key is unknown or missing.
     #[error("the signing key is missing from the object that signed the message")]
     MissingSigningKey,
 
+    /// The user ID of the signing key differs from the user ID of the signed
+    /// key.
     #[error("the user id of the signing differs from the subkey user id")]
     UserIdMissmatch,
 
+    /// The JSON value isn't an object.
     #[error("the provided JSON value isn't an object")]
     NotAnObject,
 
+    /// The JSON value can't be converted to canonical JSON, e.g. because it
+    /// contains floating point numbers.
+    #[error("the provided JSON value can't be converted to canonical JSON")]
+    NotCanonicalJson,
+
+    /// The JSON object doesn't contain a valid signature of the signing key.
     #[error("the provided JSON object doesn't contain a signatures field")]
     NoSignatureFound,
 
+    /// The signature didn't match the signing key.
     #[error("the signature didn't match the provided key")]
     VerificationError,
 
+    /// The signed object couldn't be serialized.
     #[error(transparent)]
     JsonError(#[from] SerdeError),
 }
@@ -22,14 +22,17 @@ use aes_ctr::{
     Aes256Ctr,
 };
 use base64::DecodeError;
-use getrandom::getrandom;
 use ruma::events::room::{EncryptedFile, JsonWebKey, JsonWebKeyInit};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use thiserror::Error;
 use zeroize::Zeroizing;
 
-use crate::utilities::{decode, decode_url_safe, encode, encode_url_safe};
+use crate::{
+    environment::fill_random,
+    utilities::{decode, decode_url_safe, encode, encode_url_safe},
+    SecretVec,
+};
 
 const IV_SIZE: usize = 16;
 const KEY_SIZE: usize = 32;
@@ -124,11 +127,12 @@ impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
         }
 
         let hash = decode(info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?)?;
-        let key = Zeroizing::from(decode_url_safe(info.web_key.k)?);
+        let key = SecretVec::new(decode_url_safe(info.web_key.k)?);
         let iv = decode(info.iv)?;
 
         let sha = Sha256::default();
-        let aes = Aes256Ctr::new_var(&key, &iv).map_err(|_| DecryptorError::KeyNonceLength)?;
+        let aes =
+            Aes256Ctr::new_var(key.expose(), &iv).map_err(|_| DecryptorError::KeyNonceLength)?;
 
         Ok(AttachmentDecryptor { inner_reader: input, expected_hash: hash, sha, aes })
     }
@@ -193,24 +197,25 @@ impl<'a, R: Read + 'a> AttachmentEncryptor<'a, R> {
     /// let key = encryptor.finish();
     /// ```
     pub fn new(reader: &'a mut R) -> Self {
-        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        let mut key = SecretVec::zeroed(KEY_SIZE);
         let mut iv = Zeroizing::new([0u8; IV_SIZE]);
 
-        getrandom(&mut *key).expect("Can't generate randomness");
+        fill_random(key.expose_mut());
         // Only populate the first 8 bits with randomness, the rest is 0
         // initialized.
-        getrandom(&mut iv[0..8]).expect("Can't generate randomness");
+        fill_random(&mut iv[0..8]);
 
         let web_key = JsonWebKey::from(JsonWebKeyInit {
             kty: "oct".to_owned(),
             key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
             alg: "A256CTR".to_owned(),
-            k: encode_url_safe(&*key),
+            k: encode_url_safe(key.expose()),
             ext: true,
         });
         let encoded_iv = encode(&*iv);
 
-        let aes = Aes256Ctr::new_var(&*key, &*iv).expect("Cannot create AES encryption object.");
+        let aes =
+            Aes256Ctr::new_var(key.expose(), &*iv).expect("Cannot create AES encryption object.");
 
         AttachmentEncryptor {
             finished: false,
@@ -19,16 +19,19 @@ use aes_ctr::{
     Aes256Ctr,
 };
 use byteorder::{BigEndian, ReadBytesExt};
-use getrandom::getrandom;
 use hmac::{Hmac, Mac, NewMac};
 use pbkdf2::pbkdf2;
 use serde_json::Error as SerdeError;
 use sha2::{Sha256, Sha512};
 use thiserror::Error;
 
+#[cfg(feature = "passphrase_strength")]
+use crate::estimate_passphrase_strength;
 use crate::{
+    environment::fill_random,
     olm::ExportedRoomKey,
     utilities::{decode, encode, DecodeError},
+    PassphraseStrength, SecretVec,
 };
 
 const SALT_SIZE: usize = 16;
@@ -36,6 +39,27 @@ const IV_SIZE: usize = 16;
 const MAC_SIZE: usize = 32;
 const KEY_SIZE: usize = 32;
 const VERSION: u8 = 1;
+const VERSION_2: u8 = 2;
+
+const KDF_RAW_KEY: u8 = 0;
+const KDF_PBKDF2: u8 = 1;
+#[cfg(feature = "argon2")]
+const KDF_ARGON2ID: u8 = 2;
+
+/// The default maximum of PBKDF2 rounds, well above the 500000 rounds other
+/// clients use.
+const DEFAULT_MAX_ROUNDS: u32 = 2_000_000;
+/// The default maximum size of an armored key export, 256 MiB.
+const DEFAULT_MAX_SIZE: usize = 256 * 1024 * 1024;
+/// The default maximum of memory Argon2id may use, 1 GiB in KiB.
+#[cfg(feature = "argon2")]
+const DEFAULT_MAX_MEMORY_COST: u32 = 1024 * 1024;
+/// The default maximum of Argon2id iterations.
+#[cfg(feature = "argon2")]
+const DEFAULT_MAX_ITERATIONS: u32 = 64;
+/// The default maximum degree of parallelism of Argon2id.
+#[cfg(feature = "argon2")]
+const DEFAULT_MAX_PARALLELISM: u32 = 16;
 
 const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
 const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
@@ -64,10 +88,172 @@ pub enum KeyExportError {
     /// The key export doesn't all the required fields.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// The key export uses a key derivation function that isn't supported.
+    #[error("The key export uses an unsupported key derivation function.")]
+    UnsupportedKdf,
+    /// The key export was encrypted with a raw key but a passphrase was
+    /// given, or the other way around.
+    #[error("The key export was encrypted with a different kind of key.")]
+    KeyMismatch,
+    /// The parameters of the key derivation function are invalid.
+    #[error("The parameters of the key derivation function are invalid.")]
+    InvalidKdfParameters,
+    /// The key export is larger or uses a more expensive key derivation than
+    /// the [`KeyExportLimits`] allow.
+    #[error("The key export exceeds the size or key derivation limits.")]
+    LimitExceeded,
+    /// The passphrase is weaker than the required minimum strength.
+    #[error("The passphrase is too weak, it has a score of {}.", .0.score)]
+    WeakPassphrase(PassphraseStrength),
+}
+
+/// Limits that protect against key exports that would take too long or use
+/// too much memory to decrypt.
+///
+/// Key exports usually come from an untrusted source, e.g. a file the user
+/// was sent, the limits are checked before any key derivation takes place.
+///
+/// The limits that exist depend on the enabled features, start from the
+/// [`Default`] limits and use the setters to change them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KeyExportLimits {
+    /// The maximum number of PBKDF2 rounds, defaults to `2000000`.
+    pub max_rounds: u32,
+    /// The maximum size in bytes of the armored key export, defaults to 256
+    /// MiB.
+    pub max_size: usize,
+    /// The maximum amount of memory in KiB Argon2id may use, defaults to 1
+    /// GiB.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    pub max_memory_cost: u32,
+    /// The maximum number of Argon2id iterations, defaults to `64`.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    pub max_iterations: u32,
+    /// The maximum degree of parallelism of Argon2id, defaults to `16`.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    pub max_parallelism: u32,
+}
+
+impl Default for KeyExportLimits {
+    fn default() -> Self {
+        Self {
+            max_rounds: DEFAULT_MAX_ROUNDS,
+            max_size: DEFAULT_MAX_SIZE,
+            #[cfg(feature = "argon2")]
+            max_memory_cost: DEFAULT_MAX_MEMORY_COST,
+            #[cfg(feature = "argon2")]
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            #[cfg(feature = "argon2")]
+            max_parallelism: DEFAULT_MAX_PARALLELISM,
+        }
+    }
+}
+
+impl KeyExportLimits {
+    /// Set the maximum number of PBKDF2 rounds.
+    pub fn max_rounds(mut self, rounds: u32) -> Self {
+        self.max_rounds = rounds;
+        self
+    }
+
+    /// Set the maximum size in bytes of the armored key export.
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Set the maximum amount of memory in KiB Argon2id may use.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    pub fn max_memory_cost(mut self, memory_cost: u32) -> Self {
+        self.max_memory_cost = memory_cost;
+        self
+    }
+
+    /// Set the maximum number of Argon2id iterations.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    pub fn max_iterations(mut self, iterations: u32) -> Self {
+        self.max_iterations = iterations;
+        self
+    }
+
+    /// Set the maximum degree of parallelism of Argon2id.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    pub fn max_parallelism(mut self, parallelism: u32) -> Self {
+        self.max_parallelism = parallelism;
+        self
+    }
+
+    fn check_kdf(&self, kdf: Option<KeyDerivation>) -> Result<(), KeyExportError> {
+        match kdf {
+            None => Ok(()),
+            Some(KeyDerivation::Pbkdf2 { rounds }) => {
+                if rounds > self.max_rounds {
+                    Err(KeyExportError::LimitExceeded)
+                } else {
+                    Ok(())
+                }
+            }
+            #[cfg(feature = "argon2")]
+            Some(KeyDerivation::Argon2id { memory_cost, iterations, parallelism }) => {
+                if memory_cost > self.max_memory_cost
+                    || iterations > self.max_iterations
+                    || parallelism > self.max_parallelism
+                {
+                    Err(KeyExportError::LimitExceeded)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// The key derivation function that turns a passphrase into the key that
+/// encrypts a key export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyDerivation {
+    /// PBKDF2 using HMAC-SHA512, understood by all Matrix clients.
+    Pbkdf2 {
+        /// The number of rounds of the key derivation, should be at least
+        /// `10000`, while values in the `100000` ranges should be preferred.
+        rounds: u32,
+    },
+    /// Argon2id, key exports using it can only be imported by clients that
+    /// support the second version of the key export format.
+    #[cfg(feature = "argon2")]
+    #[cfg_attr(feature = "docs", doc(cfg(argon2)))]
+    Argon2id {
+        /// The amount of memory in KiB that should be used.
+        memory_cost: u32,
+        /// The number of iterations.
+        iterations: u32,
+        /// The degree of parallelism.
+        parallelism: u32,
+    },
+}
+
+/// The secret a key export is encrypted with.
+#[derive(Clone, Copy)]
+enum Secret<'a> {
+    Passphrase(&'a str),
+    Key(&'a [u8; KEY_SIZE]),
 }
 
 /// Try to decrypt a reader into a list of exported room keys.
 ///
+/// Both versions of the key export format are supported, the key derivation
+/// function is detected from the header of the export.
+///
+/// The default [`KeyExportLimits`] are enforced, use
+/// [`decrypt_key_export_with_limits()`] to change them.
+///
 /// # Arguments
 ///
 /// * `passphrase` - The passphrase that was used to encrypt the exported keys.
@@ -87,21 +273,67 @@ pub enum KeyExportError {
 /// # });
 /// ```
 pub fn decrypt_key_export(
-    mut input: impl Read,
+    input: impl Read,
     passphrase: &str,
 ) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
+    decrypt_key_export_with_limits(input, passphrase, KeyExportLimits::default())
+}
+
+/// Try to decrypt a reader into a list of exported room keys, enforcing the
+/// given limits.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase that was used to encrypt the exported keys.
+///
+/// * `limits` - The maximum size and key derivation costs of the export,
+/// exports that exceed them are rejected with
+/// [`KeyExportError::LimitExceeded`].
+pub fn decrypt_key_export_with_limits(
+    input: impl Read,
+    passphrase: &str,
+    limits: KeyExportLimits,
+) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
+    let payload = read_payload(input, &limits)?;
+    let plaintext = decrypt_payload(&payload, Secret::Passphrase(passphrase), &limits)?;
+
+    Ok(serde_json::from_slice(plaintext.expose())?)
+}
+
+/// Try to decrypt a reader into a list of exported room keys using a raw 32
+/// byte key.
+///
+/// This is the counterpart of [`encrypt_key_export_with_key()`].
+///
+/// # Arguments
+///
+/// * `key` - The key that was used to encrypt the exported keys.
+pub fn decrypt_key_export_with_key(
+    input: impl Read,
+    key: &[u8; KEY_SIZE],
+) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
+    let limits = KeyExportLimits::default();
+    let payload = read_payload(input, &limits)?;
+    let plaintext = decrypt_payload(&payload, Secret::Key(key), &limits)?;
+
+    Ok(serde_json::from_slice(plaintext.expose())?)
+}
+
+fn read_payload(input: impl Read, limits: &KeyExportLimits) -> Result<String, KeyExportError> {
     let mut x: String = String::new();
 
-    input.read_to_string(&mut x)?;
+    // Read one byte more than allowed to notice if the input is too large.
+    input.take(limits.max_size as u64 + 1).read_to_string(&mut x)?;
+
+    if x.len() > limits.max_size {
+        return Err(KeyExportError::LimitExceeded);
+    }
 
     if !(x.trim_start().starts_with(HEADER) && x.trim_end().ends_with(FOOTER)) {
         return Err(KeyExportError::InvalidHeaders);
     }
 
-    let payload: String =
-        x.lines().filter(|l| !(l.starts_with(HEADER) || l.starts_with(FOOTER))).collect();
-
-    Ok(serde_json::from_str(&decrypt_helper(&payload, passphrase)?)?)
+    Ok(x.lines().filter(|l| !(l.starts_with(HEADER) || l.starts_with(FOOTER))).collect())
 }
 
 /// Encrypt the list of exported room keys using the given passphrase.
@@ -142,24 +374,133 @@ pub fn encrypt_key_export(
     passphrase: &str,
     rounds: u32,
 ) -> Result<String, SerdeError> {
-    let mut plaintext = serde_json::to_string(keys)?.into_bytes();
-    let ciphertext = encrypt_helper(&mut plaintext, passphrase, rounds);
+    let mut plaintext = SecretVec::from(serde_json::to_string(keys)?);
+    let ciphertext = encrypt_helper(plaintext.expose_mut(), passphrase, rounds);
+    Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
+}
+
+/// Encrypt the list of exported room keys using the given passphrase and key
+/// derivation function.
+///
+/// Exports using [`KeyDerivation::Pbkdf2`] are written in the first version of
+/// the key export format, exports using other key derivation functions are
+/// written in the second version. [`decrypt_key_export()`] detects the version
+/// and the key derivation function of an export.
+///
+/// # Arguments
+///
+/// * `keys` - A list of sessions that should be encrypted.
+///
+/// * `passphrase` - The passphrase that will be used to encrypt the exported
+/// room keys.
+///
+/// * `kdf` - The key derivation function that turns the passphrase into the
+/// encryption key.
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the exported keys securely.
+pub fn encrypt_key_export_with_kdf(
+    keys: &[ExportedRoomKey],
+    passphrase: &str,
+    kdf: KeyDerivation,
+) -> Result<String, KeyExportError> {
+    let mut plaintext = SecretVec::from(serde_json::to_string(keys)?);
+
+    let ciphertext = match kdf {
+        KeyDerivation::Pbkdf2 { rounds } => {
+            encrypt_helper(plaintext.expose_mut(), passphrase, rounds)
+        }
+        #[cfg(feature = "argon2")]
+        kdf => {
+            encrypt_helper_v2(plaintext.expose_mut(), Secret::Passphrase(passphrase), Some(kdf))?
+        }
+    };
+
+    Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
+}
+
+/// Encrypt the list of exported room keys using the given passphrase and key
+/// derivation function, refusing passphrases that are too weak.
+///
+/// This works like [`encrypt_key_export_with_kdf()`], but the strength of the
+/// passphrase is estimated first, see [`estimate_passphrase_strength()`].
+///
+/// # Arguments
+///
+/// * `keys` - A list of sessions that should be encrypted.
+///
+/// * `passphrase` - The passphrase that will be used to encrypt the exported
+/// room keys.
+///
+/// * `kdf` - The key derivation function that turns the passphrase into the
+/// encryption key.
+///
+/// * `min_score` - The minimum score the passphrase needs to have, from `0`
+/// to [`PassphraseStrength::MAX_SCORE`]. Weaker passphrases are rejected with
+/// [`KeyExportError::WeakPassphrase`].
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the exported keys securely.
+#[cfg(feature = "passphrase_strength")]
+#[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+pub fn encrypt_key_export_with_min_strength(
+    keys: &[ExportedRoomKey],
+    passphrase: &str,
+    kdf: KeyDerivation,
+    min_score: u8,
+) -> Result<String, KeyExportError> {
+    let strength = estimate_passphrase_strength(passphrase, &[]);
+
+    if !strength.is_at_least(min_score) {
+        return Err(KeyExportError::WeakPassphrase(strength));
+    }
+
+    encrypt_key_export_with_kdf(keys, passphrase, kdf)
+}
+
+/// Encrypt the list of exported room keys using a raw 32 byte key.
+///
+/// No key derivation takes place, the key needs to be as strong as a randomly
+/// generated one. The export is written in the second version of the key
+/// export format and can be decrypted using [`decrypt_key_export_with_key()`].
+///
+/// # Arguments
+///
+/// * `keys` - A list of sessions that should be encrypted.
+///
+/// * `key` - The key that will be used to encrypt the exported room keys.
+///
+/// # Panics
+///
+/// This method will panic if it can't get enough randomness from the OS to
+/// encrypt the exported keys securely.
+pub fn encrypt_key_export_with_key(
+    keys: &[ExportedRoomKey],
+    key: &[u8; KEY_SIZE],
+) -> Result<String, KeyExportError> {
+    let mut plaintext = SecretVec::from(serde_json::to_string(keys)?);
+    let ciphertext = encrypt_helper_v2(plaintext.expose_mut(), Secret::Key(key), None)?;
+
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
 fn encrypt_helper(mut plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
     let mut salt = [0u8; SALT_SIZE];
     let mut iv = [0u8; IV_SIZE];
-    let mut derived_keys = [0u8; KEY_SIZE * 2];
+    let mut derived_keys = SecretVec::zeroed(KEY_SIZE * 2);
 
-    getrandom(&mut salt).expect("Can't generate randomness");
-    getrandom(&mut iv).expect("Can't generate randomness");
+    fill_random(&mut salt);
+    fill_random(&mut iv);
 
     let mut iv = u128::from_be_bytes(iv);
     iv &= !(1 << 63);
 
-    pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), &salt, rounds, &mut derived_keys);
-    let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+    pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), &salt, rounds, derived_keys.expose_mut());
+    let (key, hmac_key) = derived_keys.expose().split_at(KEY_SIZE);
 
     let mut aes = Aes256Ctr::new_var(key, &iv.to_be_bytes()).expect("Can't create AES object");
 
@@ -182,46 +523,209 @@ fn encrypt_helper(mut plaintext: &mut [u8], passphrase: &str, rounds: u32) -> St
     encode(payload)
 }
 
-fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExportError> {
-    let decoded = decode(ciphertext)?;
-
-    let mut decoded = Cursor::new(decoded);
-
+fn encrypt_helper_v2(
+    plaintext: &mut [u8],
+    secret: Secret<'_>,
+    kdf: Option<KeyDerivation>,
+) -> Result<String, KeyExportError> {
     let mut salt = [0u8; SALT_SIZE];
     let mut iv = [0u8; IV_SIZE];
-    let mut mac = [0u8; MAC_SIZE];
-    let mut derived_keys = [0u8; KEY_SIZE * 2];
 
-    let version = decoded.read_u8()?;
-    decoded.read_exact(&mut salt)?;
-    decoded.read_exact(&mut iv)?;
+    fill_random(&mut salt);
+    fill_random(&mut iv);
+
+    let mut iv = u128::from_be_bytes(iv);
+    iv &= !(1 << 63);
+    let iv = iv.to_be_bytes();
+
+    let mut payload: Vec<u8> = vec![VERSION_2];
+
+    match kdf {
+        None => payload.push(KDF_RAW_KEY),
+        Some(KeyDerivation::Pbkdf2 { rounds }) => {
+            payload.push(KDF_PBKDF2);
+            payload.extend(&rounds.to_be_bytes());
+        }
+        #[cfg(feature = "argon2")]
+        Some(KeyDerivation::Argon2id { memory_cost, iterations, parallelism }) => {
+            payload.push(KDF_ARGON2ID);
+            payload.extend(&memory_cost.to_be_bytes());
+            payload.extend(&iterations.to_be_bytes());
+            payload.extend(&parallelism.to_be_bytes());
+        }
+    }
+
+    let derived_keys = derive_keys(secret, kdf, &salt)?;
+    let (key, hmac_key) = derived_keys.expose().split_at(KEY_SIZE);
+
+    let mut aes = Aes256Ctr::new_var(key, &iv).expect("Can't create AES object");
+    aes.apply_keystream(plaintext);
 
-    let rounds = decoded.read_u32::<BigEndian>()?;
-    let ciphertext_start = decoded.position() as usize;
+    payload.extend(&salt);
+    payload.extend(&iv);
+    payload.extend_from_slice(plaintext);
 
-    decoded.seek(SeekFrom::End(-32))?;
-    let ciphertext_end = decoded.position() as usize;
+    let mut hmac = Hmac::<Sha256>::new_varkey(hmac_key).expect("Can't create HMAC object");
+    hmac.update(&payload);
+    payload.extend(hmac.finalize().into_bytes());
 
-    decoded.read_exact(&mut mac)?;
+    Ok(encode(payload))
+}
 
-    let mut decoded = decoded.into_inner();
+/// Derive the AES and HMAC keys of a key export from the given secret.
+///
+/// Raw keys are expanded using HMAC-SHA512 with the salt as the message,
+/// passphrases are stretched using the given key derivation function.
+fn derive_keys(
+    secret: Secret<'_>,
+    kdf: Option<KeyDerivation>,
+    salt: &[u8],
+) -> Result<SecretVec, KeyExportError> {
+    let mut derived_keys = SecretVec::zeroed(KEY_SIZE * 2);
+
+    match (secret, kdf) {
+        (Secret::Key(key), None) => {
+            let mut hmac = Hmac::<Sha512>::new_varkey(key).expect("Can't create HMAC object");
+            hmac.update(salt);
+            derived_keys.expose_mut().copy_from_slice(&hmac.finalize().into_bytes());
+        }
+        (Secret::Passphrase(passphrase), Some(KeyDerivation::Pbkdf2 { rounds })) => {
+            pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, rounds, derived_keys.expose_mut());
+        }
+        #[cfg(feature = "argon2")]
+        (
+            Secret::Passphrase(passphrase),
+            Some(KeyDerivation::Argon2id { memory_cost, iterations, parallelism }),
+        ) => {
+            let argon2 = argon2::Argon2::new(
+                None,
+                iterations,
+                memory_cost,
+                parallelism,
+                argon2::Version::V0x13,
+            )
+            .map_err(|_| KeyExportError::InvalidKdfParameters)?;
+
+            argon2
+                .hash_password_into(
+                    argon2::Algorithm::Argon2id,
+                    passphrase.as_bytes(),
+                    salt,
+                    &[],
+                    derived_keys.expose_mut(),
+                )
+                .map_err(|_| KeyExportError::InvalidKdfParameters)?;
+        }
+        _ => return Err(KeyExportError::KeyMismatch),
+    }
 
-    if version != VERSION {
-        return Err(KeyExportError::UnsupportedVersion);
+    Ok(derived_keys)
+}
+
+/// The binary payload of an encrypted key export, split into its parts.
+///
+/// Parsing doesn't need the secret of the export, the MAC is only checked once
+/// the keys are derived.
+struct ExportPayload {
+    kdf: Option<KeyDerivation>,
+    salt: [u8; SALT_SIZE],
+    iv: [u8; IV_SIZE],
+    mac: [u8; MAC_SIZE],
+    ciphertext_start: usize,
+    ciphertext_end: usize,
+}
+
+impl ExportPayload {
+    /// Parse the decoded payload of a key export of either version.
+    ///
+    /// The input is attacker controlled, every length is checked before the
+    /// payload gets sliced.
+    fn parse(decoded: &[u8]) -> Result<Self, KeyExportError> {
+        let mut decoded = Cursor::new(decoded);
+
+        let mut salt = [0u8; SALT_SIZE];
+        let mut iv = [0u8; IV_SIZE];
+        let mut mac = [0u8; MAC_SIZE];
+
+        let kdf = match decoded.read_u8()? {
+            VERSION => {
+                decoded.read_exact(&mut salt)?;
+                decoded.read_exact(&mut iv)?;
+
+                Some(KeyDerivation::Pbkdf2 { rounds: decoded.read_u32::<BigEndian>()? })
+            }
+            VERSION_2 => {
+                let kdf = match decoded.read_u8()? {
+                    KDF_RAW_KEY => None,
+                    KDF_PBKDF2 => {
+                        Some(KeyDerivation::Pbkdf2 { rounds: decoded.read_u32::<BigEndian>()? })
+                    }
+                    #[cfg(feature = "argon2")]
+                    KDF_ARGON2ID => Some(KeyDerivation::Argon2id {
+                        memory_cost: decoded.read_u32::<BigEndian>()?,
+                        iterations: decoded.read_u32::<BigEndian>()?,
+                        parallelism: decoded.read_u32::<BigEndian>()?,
+                    }),
+                    _ => return Err(KeyExportError::UnsupportedKdf),
+                };
+
+                decoded.read_exact(&mut salt)?;
+                decoded.read_exact(&mut iv)?;
+
+                kdf
+            }
+            _ => return Err(KeyExportError::UnsupportedVersion),
+        };
+
+        let ciphertext_start = decoded.position() as usize;
+
+        decoded.seek(SeekFrom::End(-(MAC_SIZE as i64)))?;
+        let ciphertext_end = decoded.position() as usize;
+
+        if ciphertext_end < ciphertext_start {
+            return Err(KeyExportError::InvalidMac);
+        }
+
+        decoded.read_exact(&mut mac)?;
+
+        Ok(Self { kdf, salt, iv, mac, ciphertext_start, ciphertext_end })
     }
+}
 
-    pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), &salt, rounds, &mut derived_keys);
-    let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+/// Parse an encrypted key export without decrypting it, returning the key
+/// derivation function it uses or `None` if it was encrypted with a raw key.
+///
+/// Only meant for the fuzzing harnesses, the key derivation of a real import
+/// is too slow to be fuzzed.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub fn parse_key_export(input: impl Read) -> Result<Option<KeyDerivation>, KeyExportError> {
+    let payload = read_payload(input, &KeyExportLimits::default())?;
+    Ok(ExportPayload::parse(&decode(payload)?)?.kdf)
+}
+
+fn decrypt_payload(
+    ciphertext: &str,
+    secret: Secret<'_>,
+    limits: &KeyExportLimits,
+) -> Result<SecretVec, KeyExportError> {
+    let mut decoded = SecretVec::new(decode(ciphertext)?);
+    let payload = ExportPayload::parse(decoded.expose())?;
+
+    limits.check_kdf(payload.kdf)?;
+
+    let derived_keys = derive_keys(secret, payload.kdf, &payload.salt)?;
+    let (key, hmac_key) = derived_keys.expose().split_at(KEY_SIZE);
+    let ciphertext = payload.ciphertext_start..payload.ciphertext_end;
 
     let mut hmac = Hmac::<Sha256>::new_varkey(hmac_key).expect("Can't create an HMAC object");
-    hmac.update(&decoded[0..ciphertext_end]);
-    hmac.verify(&mac).map_err(|_| KeyExportError::InvalidMac)?;
+    hmac.update(&decoded.expose()[0..payload.ciphertext_end]);
+    hmac.verify(&payload.mac).map_err(|_| KeyExportError::InvalidMac)?;
 
-    let mut ciphertext = &mut decoded[ciphertext_start..ciphertext_end];
-    let mut aes = Aes256Ctr::new_var(key, &iv).expect("Can't create an AES object");
-    aes.apply_keystream(&mut ciphertext);
+    let mut aes = Aes256Ctr::new_var(key, &payload.iv).expect("Can't create an AES object");
+    aes.apply_keystream(&mut decoded.expose_mut()[ciphertext.clone()]);
 
-    Ok(String::from_utf8(ciphertext.to_owned())?)
+    Ok(SecretVec::new(decoded.expose()[ciphertext].to_vec()))
 }
 
 #[cfg(test)]
@@ -233,8 +737,13 @@ mod test {
     use proptest::prelude::*;
     use ruma::room_id;
 
-    use super::{decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export};
-    use crate::machine::test::get_prepared_machine;
+    use super::{
+        decode, decrypt_key_export, decrypt_key_export_with_key, decrypt_key_export_with_limits,
+        decrypt_payload, encrypt_helper, encrypt_key_export, encrypt_key_export_with_key,
+        ExportPayload, KeyDerivation, KeyExportError, KeyExportLimits, Secret, IV_SIZE, MAC_SIZE,
+        SALT_SIZE, VERSION,
+    };
+    use crate::{environment::deterministic, machine::test::get_prepared_machine};
 
     const PASSPHRASE: &str = "1234";
 
@@ -267,13 +776,26 @@ mod test {
 
     proptest! {
         #[test]
-        fn proptest_encrypt_cycle(plaintext in prop::string::string_regex(".*").unwrap()) {
+        fn proptest_encrypt_cycle(
+            plaintext in prop::string::string_regex(".*").unwrap(),
+            seed in any::<u64>(),
+        ) {
+            // The salt and IV are derived from the seed, so a failing case can
+            // be reproduced.
+            let guard = deterministic(seed);
             let mut plaintext_bytes = plaintext.clone().into_bytes();
 
             let ciphertext = encrypt_helper(&mut plaintext_bytes, "test", 1);
-            let decrypted = decrypt_helper(&ciphertext, "test").unwrap();
+            let limits = KeyExportLimits::default();
+            let decrypted = decrypt_payload(&ciphertext, Secret::Passphrase("test"), &limits).unwrap();
+
+            prop_assert!(plaintext.as_bytes() == decrypted.expose());
 
-            prop_assert!(plaintext == decrypted);
+            drop(guard);
+            let _guard = deterministic(seed);
+            let mut plaintext_bytes = plaintext.clone().into_bytes();
+
+            prop_assert_eq!(ciphertext, encrypt_helper(&mut plaintext_bytes, "test", 1));
         }
     }
 
@@ -283,9 +805,14 @@ mod test {
         let mut bytes = data.to_owned().into_bytes();
 
         let encrypted = encrypt_helper(&mut bytes, PASSPHRASE, 10);
-        let decrypted = decrypt_helper(&encrypted, PASSPHRASE).unwrap();
-
-        assert_eq!(data, decrypted);
+        let decrypted = decrypt_payload(
+            &encrypted,
+            Secret::Passphrase(PASSPHRASE),
+            &KeyExportLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!(data.as_bytes(), decrypted.expose());
     }
 
     #[async_test]
@@ -305,6 +832,145 @@ mod test {
         assert_eq!(machine.import_keys(decrypted, |_, _| {}).await.unwrap(), (0, 1));
     }
 
+    #[async_test]
+    async fn test_session_encrypt_with_key() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+        let key = [7u8; 32];
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+
+        let encrypted = encrypt_key_export_with_key(&export, &key).unwrap();
+        let decrypted = decrypt_key_export_with_key(Cursor::new(&encrypted), &key).unwrap();
+        assert_eq!(export, decrypted);
+
+        assert!(matches!(
+            decrypt_key_export(Cursor::new(&encrypted), PASSPHRASE),
+            Err(KeyExportError::KeyMismatch)
+        ));
+        assert!(matches!(
+            decrypt_key_export_with_key(Cursor::new(&encrypted), &[8u8; 32]),
+            Err(KeyExportError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn truncated_payload() {
+        // A version 1 payload whose MAC would overlap its header.
+        let mut payload = vec![VERSION];
+        payload.extend(&[0u8; SALT_SIZE + IV_SIZE]);
+        payload.extend(&1u32.to_be_bytes());
+        payload.extend(&[0u8; MAC_SIZE / 2]);
+
+        assert!(matches!(ExportPayload::parse(&payload), Err(KeyExportError::InvalidMac)));
+        assert!(matches!(ExportPayload::parse(&payload[..20]), Err(KeyExportError::Io(_))));
+        assert!(matches!(ExportPayload::parse(&[]), Err(KeyExportError::Io(_))));
+
+        payload.extend(&[0u8; MAC_SIZE]);
+        let parsed = ExportPayload::parse(&payload).unwrap();
+
+        assert_eq!(parsed.kdf, Some(KeyDerivation::Pbkdf2 { rounds: 1 }));
+        assert_eq!(parsed.ciphertext_end - parsed.ciphertext_start, MAC_SIZE / 2);
+    }
+
+    #[async_test]
+    async fn decryption_limits() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+        let encrypted = encrypt_key_export(&export, PASSPHRASE, 1000).unwrap();
+
+        let limits = KeyExportLimits::default().max_rounds(999);
+        assert!(matches!(
+            decrypt_key_export_with_limits(Cursor::new(&encrypted), PASSPHRASE, limits),
+            Err(KeyExportError::LimitExceeded)
+        ));
+
+        let limits = KeyExportLimits::default().max_size(encrypted.len() - 1);
+        assert!(matches!(
+            decrypt_key_export_with_limits(Cursor::new(&encrypted), PASSPHRASE, limits),
+            Err(KeyExportError::LimitExceeded)
+        ));
+
+        let limits = KeyExportLimits::default().max_rounds(1000).max_size(encrypted.len());
+        let decrypted =
+            decrypt_key_export_with_limits(Cursor::new(&encrypted), PASSPHRASE, limits).unwrap();
+        assert_eq!(export, decrypted);
+    }
+
+    #[cfg(feature = "argon2")]
+    #[async_test]
+    async fn argon2_decryption_limits() {
+        use super::encrypt_key_export_with_kdf;
+
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+        let kdf = KeyDerivation::Argon2id { memory_cost: 64, iterations: 2, parallelism: 2 };
+        let encrypted = encrypt_key_export_with_kdf(&export, PASSPHRASE, kdf).unwrap();
+
+        let exceeded = [
+            KeyExportLimits::default().max_memory_cost(63),
+            KeyExportLimits::default().max_iterations(1),
+            KeyExportLimits::default().max_parallelism(1),
+        ];
+
+        for limits in exceeded.iter() {
+            assert!(matches!(
+                decrypt_key_export_with_limits(Cursor::new(&encrypted), PASSPHRASE, *limits),
+                Err(KeyExportError::LimitExceeded)
+            ));
+        }
+
+        // Absurd parameters are rejected before the key derivation runs.
+        let mut payload =
+            decode(encrypted.lines().filter(|l| !l.starts_with("-----")).collect::<String>())
+                .unwrap();
+        payload[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+        let tampered = format!(
+            "-----BEGIN MEGOLM SESSION DATA-----\n{}\n-----END MEGOLM SESSION DATA-----\n",
+            super::encode(payload)
+        );
+
+        assert!(matches!(
+            decrypt_key_export(Cursor::new(tampered), PASSPHRASE),
+            Err(KeyExportError::LimitExceeded)
+        ));
+
+        let limits =
+            KeyExportLimits::default().max_memory_cost(64).max_iterations(2).max_parallelism(2);
+        let decrypted =
+            decrypt_key_export_with_limits(Cursor::new(&encrypted), PASSPHRASE, limits).unwrap();
+        assert_eq!(export, decrypted);
+    }
+
+    #[cfg(feature = "passphrase_strength")]
+    #[async_test]
+    async fn weak_export_passphrase() {
+        use super::encrypt_key_export_with_min_strength;
+
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+        let kdf = KeyDerivation::Pbkdf2 { rounds: 10 };
+
+        assert!(matches!(
+            encrypt_key_export_with_min_strength(&export, PASSPHRASE, kdf, 3),
+            Err(KeyExportError::WeakPassphrase(s)) if s.score < 3
+        ));
+
+        let passphrase = "correct horse battery staple rotates slowly";
+        let encrypted = encrypt_key_export_with_min_strength(&export, passphrase, kdf, 3).unwrap();
+        assert_eq!(export, decrypt_key_export(Cursor::new(encrypted), passphrase).unwrap());
+    }
+
     #[test]
     fn test_real_decrypt() {
         let reader = Cursor::new(TEST_EXPORT);
@@ -2,4 +2,12 @@ mod attachments;
 mod key_export;
 
 pub use attachments::{AttachmentDecryptor, AttachmentEncryptor, DecryptorError, EncryptionInfo};
-pub use key_export::{decrypt_key_export, encrypt_key_export, KeyExportError};
+#[cfg(feature = "passphrase_strength")]
+pub use key_export::encrypt_key_export_with_min_strength;
+#[cfg(feature = "fuzzing")]
+pub use key_export::parse_key_export;
+pub use key_export::{
+    decrypt_key_export, decrypt_key_export_with_key, decrypt_key_export_with_limits,
+    encrypt_key_export, encrypt_key_export_with_kdf, encrypt_key_export_with_key, KeyDerivation,
+    KeyExportError, KeyExportLimits,
+};
@@ -43,7 +43,9 @@ use super::{atomic_bool_deserializer, atomic_bool_serializer};
 use crate::{
     error::{EventError, OlmError, OlmResult, SignatureError},
     identities::{OwnUserIdentity, UserIdentities},
-    olm::{InboundGroupSession, PrivateCrossSigningIdentity, Session, Utility},
+    olm::{
+        InboundGroupSession, PrivateCrossSigningIdentity, Session, Utility, SHARED_HISTORY_FIELD,
+    },
     store::{Changes, CryptoStore, DeviceChanges, Result as StoreResult},
     verification::VerificationMachine,
     OutgoingVerificationRequest, Sas, ToDeviceRequest,
@@ -213,7 +215,14 @@ impl Device {
             );
         };
 
-        let content = serde_json::to_value(content)?;
+        let mut content = serde_json::to_value(content)?;
+
+        if session.shared_history() {
+            if let Some(c) = content.as_object_mut() {
+                c.insert(SHARED_HISTORY_FIELD.to_owned(), true.into());
+            }
+        }
+
         self.encrypt(EventType::ForwardedRoomKey, content).await
     }
 }
@@ -288,6 +297,45 @@ impl From<i64> for LocalTrust {
     }
 }
 
+/// A device that was put into quarantine because its device keys, as received
+/// in a `/keys/query` response, failed the signature check.
+///
+/// Rejected devices are never used to encrypt messages or to share room keys
+/// with, they are kept around to help debugging broken clients or to detect
+/// potential attacks. A device is taken out of quarantine once a valid version
+/// of its device keys is received.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RejectedDevice {
+    device_keys: DeviceKeys,
+    reason: String,
+}
+
+impl RejectedDevice {
+    pub(crate) fn new(device_keys: DeviceKeys, error: &SignatureError) -> Self {
+        Self { device_keys, reason: error.to_string() }
+    }
+
+    /// The user id of the device owner.
+    pub fn user_id(&self) -> &UserId {
+        &self.device_keys.user_id
+    }
+
+    /// The unique id of the device.
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_keys.device_id
+    }
+
+    /// The device keys that failed the signature check.
+    pub fn device_keys(&self) -> &DeviceKeys {
+        &self.device_keys
+    }
+
+    /// The reason why the device keys were rejected.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
 impl ReadOnlyDevice {
     /// Create a new Device.
     pub fn new(
@@ -474,7 +522,7 @@ impl ReadOnlyDevice {
         Ok(())
     }
 
-    fn is_signed_by_device(&self, json: &mut Value) -> Result<(), SignatureError> {
+    pub(crate) fn is_signed_by_device(&self, json: &mut Value) -> Result<(), SignatureError> {
         let signing_key =
             self.get_key(DeviceKeyAlgorithm::Ed25519).ok_or(SignatureError::MissingSigningKey)?;
 
@@ -516,11 +564,21 @@ impl ReadOnlyDevice {
         self.is_signed_by_device(&mut device_keys)
     }
 
+    /// Verify the signature of a claimed one-time key.
+    ///
+    /// The key might be a fallback key, whose signature covers the `fallback`
+    /// field as well. The field isn't part of the claimed key, so a key whose
+    /// signature doesn't match is checked again as a fallback key.
     pub(crate) fn verify_one_time_key(
         &self,
         one_time_key: &SignedKey,
     ) -> Result<(), SignatureError> {
-        self.is_signed_by_device(&mut json!(&one_time_key))
+        self.is_signed_by_device(&mut json!(&one_time_key)).or_else(|e| {
+            let mut fallback_key = json!(&one_time_key);
+            fallback_key["fallback"] = true.into();
+
+            self.is_signed_by_device(&mut fallback_key).map_err(|_| e)
+        })
     }
 
     /// Mark the device as deleted.
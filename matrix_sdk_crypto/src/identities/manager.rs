@@ -13,13 +13,19 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
     convert::TryFrom,
     sync::Arc,
 };
 
+use dashmap::DashMap;
 use futures::future::join_all;
-use matrix_sdk_common::executor::spawn;
+use matrix_sdk_common::{
+    executor::spawn,
+    instant::{Duration, Instant},
+    locks::Mutex,
+    uuid::Uuid,
+};
 use ruma::{
     api::client::r0::keys::get_keys::Response as KeysQueryResponse,
     encryption::DeviceKeys,
@@ -28,10 +34,11 @@ use ruma::{
 use tracing::{trace, warn};
 
 use crate::{
+    environment::{now, random_uuid},
     error::OlmResult,
     identities::{
-        MasterPubkey, OwnUserIdentity, ReadOnlyDevice, SelfSigningPubkey, UserIdentities,
-        UserIdentity, UserSigningPubkey,
+        MasterPubkey, OwnUserIdentity, ReadOnlyDevice, RejectedDevice, SelfSigningPubkey,
+        UserIdentities, UserIdentity, UserSigningPubkey,
     },
     requests::KeysQueryRequest,
     store::{Changes, DeviceChanges, IdentityChanges, Result as StoreResult, Store},
@@ -40,7 +47,61 @@ use crate::{
 enum DeviceChange {
     New(ReadOnlyDevice),
     Updated(ReadOnlyDevice),
-    None,
+    Rejected(RejectedDevice),
+}
+
+/// A change of the device list or the identity of a tracked user that was
+/// discovered by a key query.
+///
+/// Changes are only reported for users whose devices were already known, the
+/// first key query of a user doesn't produce any changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceListChange {
+    /// The user added a new device.
+    DeviceAdded {
+        /// The user the device belongs to.
+        user_id: UserId,
+        /// The id of the new device.
+        device_id: DeviceIdBox,
+    },
+    /// The user deleted one of their devices.
+    DeviceDeleted {
+        /// The user the device belonged to.
+        user_id: UserId,
+        /// The id of the deleted device.
+        device_id: DeviceIdBox,
+    },
+    /// The master key of the user changed.
+    IdentityChanged {
+        /// The user whose identity changed.
+        user_id: UserId,
+    },
+    /// The master key of a user whose identity we verified changed.
+    ///
+    /// The user might have reset their cross signing keys, but this might
+    /// also be a man-in-the-middle attack, users should be warned about it.
+    /// The new identity isn't verified anymore.
+    VerifiedIdentityChanged {
+        /// The user whose identity changed.
+        user_id: UserId,
+    },
+}
+
+impl DeviceListChange {
+    /// The user whose devices or identity changed.
+    pub fn user_id(&self) -> &UserId {
+        match self {
+            Self::DeviceAdded { user_id, .. }
+            | Self::DeviceDeleted { user_id, .. }
+            | Self::IdentityChanged { user_id }
+            | Self::VerifiedIdentityChanged { user_id } => user_id,
+        }
+    }
+
+    /// Should the user be warned about the change.
+    pub fn is_security_alert(&self) -> bool {
+        matches!(self, Self::VerifiedIdentityChanged { .. })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -48,13 +109,40 @@ pub(crate) struct IdentityManager {
     user_id: Arc<UserId>,
     device_id: Arc<DeviceId>,
     store: Store,
+    /// The key queries that were handed out but didn't receive a response
+    /// yet, the users they contain aren't queried again in the meantime.
+    keys_queries_in_flight: Arc<DashMap<Uuid, (Vec<UserId>, Instant)>>,
+    /// How often the users were marked as changed, the generation is
+    /// increased every time.
+    user_generations: Arc<DashMap<UserId, u64>>,
+    /// The generation of the users at the time they were last handed out for
+    /// a key query. A key query response only marks a user as up to date if
+    /// the user didn't change in the meantime.
+    queried_generations: Arc<DashMap<UserId, u64>>,
+    /// The device list changes that weren't taken out yet, at most
+    /// `MAX_DEVICE_LIST_CHANGES` of them are kept.
+    device_list_changes: Arc<Mutex<Vec<DeviceListChange>>>,
 }
 
 impl IdentityManager {
     const MAX_KEY_QUERY_USERS: usize = 250;
+    /// How long we wait for the response of a key query before its users are
+    /// queried again, the request probably failed if it takes this long.
+    const KEY_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+    /// How many device list changes are kept if nobody takes them out, the
+    /// oldest changes that aren't security alerts are dropped first.
+    const MAX_DEVICE_LIST_CHANGES: usize = 1000;
 
     pub fn new(user_id: Arc<UserId>, device_id: Arc<DeviceId>, store: Store) -> Self {
-        IdentityManager { user_id, device_id, store }
+        IdentityManager {
+            user_id,
+            device_id,
+            store,
+            keys_queries_in_flight: Default::default(),
+            user_generations: Default::default(),
+            queried_generations: Default::default(),
+            device_list_changes: Default::default(),
+        }
     }
 
     fn user_id(&self) -> &UserId {
@@ -74,27 +162,134 @@ impl IdentityManager {
         &self,
         response: &KeysQueryResponse,
     ) -> OlmResult<(DeviceChanges, IdentityChanges)> {
+        let mut known_users = BTreeSet::new();
+
+        for user_id in response.device_keys.keys() {
+            if !self.store.get_readonly_devices(user_id).await?.is_empty() {
+                known_users.insert(user_id.clone());
+            }
+        }
+
+        let mut old_identities = BTreeMap::new();
+
+        for user_id in response.master_keys.keys() {
+            if let Some(identity) = self.store.get_user_identity(user_id).await? {
+                let verified = self.is_identity_verified(&identity).await?;
+                old_identities.insert(user_id.clone(), (identity.master_key().clone(), verified));
+            }
+        }
+
         let changed_devices =
             self.handle_devices_from_key_query(response.device_keys.clone()).await?;
         let changed_identities = self.handle_cross_singing_keys(response).await?;
 
+        let generations: BTreeMap<UserId, u64> =
+            response.device_keys.keys().map(|u| (u.clone(), self.generation(u))).collect();
+
+        // Users that changed after the key query was sent out need to be
+        // queried again.
         let changes = Changes {
             identities: changed_identities.clone(),
             devices: changed_devices.clone(),
+            tracked_users: generations
+                .iter()
+                .map(|(u, g)| (u.clone(), self.queried_generation(u) != *g))
+                .collect(),
             ..Default::default()
         };
 
-        // TODO turn this into a single transaction.
         self.store.save_changes(changes).await?;
-        let updated_users: Vec<&UserId> = response.device_keys.keys().collect();
 
-        for user_id in updated_users {
-            self.store.update_tracked_user(user_id, false).await?;
+        // A user might have been marked as changed while we were saving, don't
+        // let our changes overwrite that.
+        let changed_while_saving: BTreeMap<UserId, bool> = generations
+            .into_iter()
+            .filter(|(u, g)| self.generation(u) != *g)
+            .map(|(u, _)| (u, true))
+            .collect();
+
+        if !changed_while_saving.is_empty() {
+            let changes = Changes { tracked_users: changed_while_saving, ..Default::default() };
+            self.store.save_changes(changes).await?;
         }
 
+        let added =
+            changed_devices.new.iter().filter(|d| known_users.contains(d.user_id())).map(|d| {
+                DeviceListChange::DeviceAdded {
+                    user_id: d.user_id().clone(),
+                    device_id: d.device_id().into(),
+                }
+            });
+        let deleted = changed_devices.deleted.iter().map(|d| DeviceListChange::DeviceDeleted {
+            user_id: d.user_id().clone(),
+            device_id: d.device_id().into(),
+        });
+        let identities = changed_identities.changed.iter().filter_map(|i| {
+            let (old_master_key, verified) = old_identities.get(i.user_id())?;
+            let user_id = i.user_id().clone();
+
+            if old_master_key == i.master_key() {
+                None
+            } else if *verified {
+                Some(DeviceListChange::VerifiedIdentityChanged { user_id })
+            } else {
+                Some(DeviceListChange::IdentityChanged { user_id })
+            }
+        });
+
+        self.add_device_list_changes(added.chain(deleted).chain(identities)).await;
+
         Ok((changed_devices, changed_identities))
     }
 
+    async fn add_device_list_changes(
+        &self,
+        new_changes: impl IntoIterator<Item = DeviceListChange>,
+    ) {
+        let mut changes = self.device_list_changes.lock().await;
+        changes.extend(new_changes);
+
+        if changes.len() > Self::MAX_DEVICE_LIST_CHANGES {
+            let mut excess = changes.len() - Self::MAX_DEVICE_LIST_CHANGES;
+            warn!("Dropping {} device list changes that were never taken out", excess);
+
+            changes.retain(|c| {
+                if excess > 0 && !c.is_security_alert() {
+                    excess -= 1;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+
+    /// Take out the device list changes that were discovered since the last
+    /// call.
+    pub async fn take_device_list_changes(&self) -> Vec<DeviceListChange> {
+        self.device_list_changes.lock().await.drain(..).collect()
+    }
+
+    /// Did we verify the given identity, either because it's our own verified
+    /// identity or because our verified identity signed it.
+    async fn is_identity_verified(&self, identity: &UserIdentities) -> StoreResult<bool> {
+        Ok(match identity {
+            UserIdentities::Own(i) => i.is_verified(),
+            UserIdentities::Other(i) => {
+                match self
+                    .store
+                    .get_user_identity(self.user_id())
+                    .await?
+                    .as_ref()
+                    .and_then(|o| o.own())
+                {
+                    Some(own) => own.is_verified() && own.is_identity_signed(i).is_ok(),
+                    None => false,
+                }
+            }
+        })
+    }
+
     async fn update_or_create_device(
         store: Store,
         device_keys: DeviceKeys,
@@ -105,12 +300,13 @@ impl IdentityManager {
         if let Some(mut device) = old_device {
             if let Err(e) = device.update_device(&device_keys) {
                 warn!(
-                    "Failed to update the device keys for {} {}: {:?}",
+                    "Failed to update the device keys for {} {}, putting the \
+                     device into quarantine: {:?}",
                     device.user_id(),
                     device.device_id(),
                     e
                 );
-                Ok(DeviceChange::None)
+                Ok(DeviceChange::Rejected(RejectedDevice::new(device_keys, &e)))
             } else {
                 Ok(DeviceChange::Updated(device))
             }
@@ -122,10 +318,11 @@ impl IdentityManager {
                 }
                 Err(e) => {
                     warn!(
-                        "Failed to create a new device for {} {}: {:?}",
+                        "Failed to create a new device for {} {}, putting the \
+                         device into quarantine: {:?}",
                         device_keys.user_id, device_keys.device_id, e
                     );
-                    Ok(DeviceChange::None)
+                    Ok(DeviceChange::Rejected(RejectedDevice::new(device_keys, &e)))
                 }
             }
         }
@@ -165,7 +362,7 @@ impl IdentityManager {
             match device {
                 DeviceChange::New(d) => changes.new.push(d),
                 DeviceChange::Updated(d) => changes.changed.push(d),
-                DeviceChange::None => (),
+                DeviceChange::Rejected(d) => changes.rejected.push(d),
             }
         }
 
@@ -318,47 +515,104 @@ impl IdentityManager {
         Ok(changes)
     }
 
-    /// Get a key query request if one is needed.
+    /// Get the key query requests that are needed, together with their
+    /// request ids.
     ///
-    /// Returns a key query request if the client should query E2E keys,
-    /// otherwise None.
+    /// Users that are part of a key query that is still in flight aren't
+    /// queried again, all the other users that need a key query are merged
+    /// into as few requests as possible. Key queries that didn't receive a
+    /// response for a while are considered to be failed and their users are
+    /// queried again.
     ///
     /// The response of a successful key query requests needs to be passed to
-    /// the [`OlmMachine`] with the [`receive_keys_query_response`].
+    /// the [`OlmMachine`] with the [`receive_keys_query_response`], the request
+    /// needs to be marked as finished using [`finish_key_query`].
     ///
     /// [`OlmMachine`]: struct.OlmMachine.html
     /// [`receive_keys_query_response`]: #method.receive_keys_query_response
-    pub async fn users_for_key_query(&self) -> Vec<KeysQueryRequest> {
-        let users = self.store.users_for_key_query();
+    /// [`finish_key_query`]: #method.finish_key_query
+    pub async fn users_for_key_query(&self) -> Vec<(Uuid, KeysQueryRequest)> {
+        self.keys_queries_in_flight.retain(|_, (_, sent)| sent.elapsed() < Self::KEY_QUERY_TIMEOUT);
 
-        if users.is_empty() {
-            Vec::new()
-        } else {
-            let users: Vec<UserId> = users.into_iter().collect();
-
-            users
-                .chunks(Self::MAX_KEY_QUERY_USERS)
-                .map(|u| u.iter().map(|u| (u.clone(), Vec::new())).collect())
-                .map(KeysQueryRequest::new)
-                .collect()
+        let mut users: Vec<UserId> = self
+            .store
+            .users_for_key_query()
+            .into_iter()
+            .filter(|u| !self.is_key_query_in_flight(u))
+            .collect();
+        users.sort();
+
+        for user_id in &users {
+            self.queried_generations.insert(user_id.clone(), self.generation(user_id));
         }
+
+        users
+            .chunks(Self::MAX_KEY_QUERY_USERS)
+            .map(|users| {
+                let request_id = random_uuid();
+                self.keys_queries_in_flight.insert(request_id, (users.to_vec(), now()));
+
+                let request =
+                    KeysQueryRequest::new(users.iter().map(|u| (u.clone(), Vec::new())).collect());
+
+                (request_id, request)
+            })
+            .collect()
     }
 
-    /// Mark that the given user has changed his devices.
-    ///
-    /// This will queue up the given user for a key query.
+    /// Mark the key query with the given request id as finished, its users
+    /// can be queried again.
+    pub fn finish_key_query(&self, request_id: &Uuid) {
+        self.keys_queries_in_flight.remove(request_id);
+    }
+
+    /// Get the current generation of the given user.
+    fn generation(&self, user_id: &UserId) -> u64 {
+        self.user_generations.get(user_id).map_or(0, |g| *g)
+    }
+
+    /// Get the generation the given user had when it was last queried.
+    fn queried_generation(&self, user_id: &UserId) -> u64 {
+        self.queried_generations.get(user_id).map_or(0, |g| *g)
+    }
+
+    /// Increase the generation of the given user, a key query that is in
+    /// flight won't mark the user as up to date anymore.
+    fn mark_user_as_changed(&self, user_id: &UserId) {
+        *self.user_generations.entry(user_id.clone()).or_insert(0) += 1;
+    }
+
+    /// Is a key query that contains the given user still in flight.
+    fn is_key_query_in_flight(&self, user_id: &UserId) -> bool {
+        self.keys_queries_in_flight.iter().any(|q| q.value().0.contains(user_id))
+    }
+
+    /// Are the devices of the given user up to date.
     ///
-    /// Note: The user already needs to be tracked for it to be queued up for a
-    /// key query.
+    /// This is the case if the user is tracked and no key query for the user
+    /// is pending or in flight.
+    pub fn is_user_up_to_date(&self, user_id: &UserId) -> bool {
+        self.store.is_user_tracked(user_id)
+            && !self.store.users_for_key_query().contains(user_id)
+            && !self.is_key_query_in_flight(user_id)
+    }
+
+    /// Get the tracked user changes that mark the given users as changed.
     ///
-    /// Returns true if the user was queued up for a key query, false otherwise.
-    pub async fn mark_user_as_changed(&self, user_id: &UserId) -> StoreResult<bool> {
-        if self.store.is_user_tracked(user_id) {
-            self.store.update_tracked_user(user_id, true).await?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    /// Users that aren't tracked are ignored, the changes need to be saved to
+    /// queue up the users for a key query.
+    pub fn changed_users<'a>(
+        &self,
+        users: impl IntoIterator<Item = &'a UserId>,
+    ) -> BTreeMap<UserId, bool> {
+        users
+            .into_iter()
+            .filter(|u| self.store.is_user_tracked(u))
+            .map(|u| {
+                self.mark_user_as_changed(u);
+                (u.clone(), true)
+            })
+            .collect()
     }
 
     /// Update the tracked users.
@@ -374,14 +628,23 @@ impl IdentityManager {
     /// If the user is already known to the Olm machine it will not be
     /// considered for a key query.
     pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
-        for user in users {
-            if self.store.is_user_tracked(user) {
-                continue;
-            }
+        let tracked_users: BTreeMap<UserId, bool> = users
+            .into_iter()
+            .filter(|u| !self.store.is_user_tracked(u))
+            .map(|u| {
+                self.mark_user_as_changed(u);
+                (u.clone(), true)
+            })
+            .collect();
+
+        if tracked_users.is_empty() {
+            return;
+        }
 
-            if let Err(e) = self.store.update_tracked_user(user, true).await {
-                warn!("Error storing users for tracking {}", e);
-            }
+        let changes = Changes { tracked_users, ..Default::default() };
+
+        if let Err(e) = self.store.save_changes(changes).await {
+            warn!("Error storing users for tracking {}", e);
         }
     }
 }
@@ -399,10 +662,11 @@ pub(crate) mod test {
     use serde_json::json;
 
     use crate::{
-        identities::IdentityManager,
+        error::SignatureError,
+        identities::{DeviceListChange, IdentityManager},
         machine::test::response_from_file,
         olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
-        store::{CryptoStore, MemoryStore, Store},
+        store::{Changes, CryptoStore, IdentityChanges, MemoryStore, Store},
         verification::VerificationMachine,
     };
 
@@ -619,6 +883,131 @@ pub(crate) mod test {
         assert!(identity.is_device_signed(&device).is_ok())
     }
 
+    #[async_test]
+    async fn device_list_changes() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        // The first key query of a user doesn't report any changes.
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+        assert!(manager.take_device_list_changes().await.is_empty());
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+        assert!(manager.take_device_list_changes().await.is_empty());
+
+        let mut response = other_key_query();
+        response.device_keys.get_mut(&other_user).unwrap().clear();
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        assert_eq!(
+            manager.take_device_list_changes().await,
+            vec![DeviceListChange::DeviceDeleted {
+                user_id: other_user,
+                device_id: "SKISMLNIMH".into()
+            }]
+        );
+        assert!(manager.take_device_list_changes().await.is_empty());
+    }
+
+    async fn own_identity_key_query(identity: &PrivateCrossSigningIdentity) -> KeyQueryResponse {
+        let keys = identity.as_upload_request().await;
+
+        let data = response_from_file(&json!({
+            "device_keys": {},
+            "failures": {},
+            "master_keys": { "@example:localhost": keys.master_key },
+            "self_signing_keys": { "@example:localhost": keys.self_signing_key },
+            "user_signing_keys": { "@example:localhost": keys.user_signing_key },
+        }));
+        KeyQueryResponse::try_from_http_response(data).expect("Can't parse the keys query response")
+    }
+
+    #[async_test]
+    async fn verified_identity_changes() {
+        let manager = manager();
+
+        let response = own_identity_key_query(&PrivateCrossSigningIdentity::new(user_id()).await);
+        manager.receive_keys_query_response(&response).await.unwrap();
+        assert!(manager.take_device_list_changes().await.is_empty());
+
+        let identity = manager.store.get_user_identity(&user_id()).await.unwrap().unwrap();
+        identity.own().unwrap().mark_as_verified();
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![identity], ..Default::default() },
+            ..Default::default()
+        };
+        manager.store.save_changes(changes).await.unwrap();
+
+        let response = own_identity_key_query(&PrivateCrossSigningIdentity::new(user_id()).await);
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let changes = manager.take_device_list_changes().await;
+        assert_eq!(changes, vec![DeviceListChange::VerifiedIdentityChanged { user_id: user_id() }]);
+        assert!(changes[0].is_security_alert());
+
+        // The new identity isn't verified, changing it again isn't an alert.
+        let response = own_identity_key_query(&PrivateCrossSigningIdentity::new(user_id()).await);
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        assert_eq!(
+            manager.take_device_list_changes().await,
+            vec![DeviceListChange::IdentityChanged { user_id: user_id() }]
+        );
+    }
+
+    #[async_test]
+    async fn device_list_changes_are_bounded() {
+        let manager = manager();
+        let alert = DeviceListChange::VerifiedIdentityChanged { user_id: other_user_id() };
+        let added = |i: usize| DeviceListChange::DeviceAdded {
+            user_id: other_user_id(),
+            device_id: i.to_string().into(),
+        };
+
+        manager.add_device_list_changes(vec![added(0), alert.clone()]).await;
+        manager
+            .add_device_list_changes((1..=IdentityManager::MAX_DEVICE_LIST_CHANGES).map(added))
+            .await;
+
+        let changes = manager.take_device_list_changes().await;
+        assert_eq!(changes.len(), IdentityManager::MAX_DEVICE_LIST_CHANGES);
+        assert_eq!(changes[0], alert);
+        assert_eq!(changes[1], added(2));
+    }
+
+    #[async_test]
+    async fn changes_during_key_query_are_kept() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        manager.update_tracked_users(&[other_user.clone()]).await;
+
+        let requests = manager.users_for_key_query().await;
+        assert_eq!(requests.len(), 1);
+
+        // The user changes their devices while the key query is in flight.
+        let changes = Changes {
+            tracked_users: manager.changed_users(&[other_user.clone()]),
+            ..Default::default()
+        };
+        manager.store.save_changes(changes).await.unwrap();
+
+        manager.finish_key_query(&requests[0].0);
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        // The response doesn't contain the change, the user is queried again.
+        assert!(!manager.is_user_up_to_date(&other_user));
+
+        let requests = manager.users_for_key_query().await;
+        assert_eq!(requests.len(), 1);
+
+        manager.finish_key_query(&requests[0].0);
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        assert!(manager.is_user_up_to_date(&other_user));
+        assert!(manager.users_for_key_query().await.is_empty());
+    }
+
     #[async_test]
     async fn test_manager_own_key_query_response() {
         let manager = manager();
@@ -642,4 +1031,42 @@ pub(crate) mod test {
 
         assert!(identity.is_device_signed(&device).is_ok())
     }
+
+    #[async_test]
+    async fn test_manager_rejected_devices() {
+        let manager = manager();
+        let other_user = other_user_id();
+        let device_id: DeviceIdBox = "SKISMLNIMH".into();
+
+        let mut response = other_key_query();
+        let device_keys =
+            response.device_keys.get_mut(&other_user).unwrap().get_mut(&device_id).unwrap();
+        device_keys.keys.values_mut().for_each(|k| k.push_str("tampered"));
+
+        let (changes, _) = manager.receive_keys_query_response(&response).await.unwrap();
+        assert!(changes.new.is_empty());
+        assert_eq!(changes.rejected.len(), 1);
+
+        assert!(manager
+            .store
+            .get_readonly_device(&other_user, &device_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let rejected = manager.store.get_rejected_device(&other_user, &device_id).await.unwrap();
+        let rejected = rejected.unwrap();
+        assert_eq!(rejected.user_id(), &other_user);
+        assert_eq!(rejected.reason(), SignatureError::VerificationError.to_string());
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        assert!(manager
+            .store
+            .get_readonly_device(&other_user, &device_id)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(manager.store.get_rejected_devices().await.unwrap().is_empty());
+    }
 }
@@ -49,7 +49,8 @@ use std::sync::{
     Arc,
 };
 
-pub use device::{Device, LocalTrust, ReadOnlyDevice, UserDevices};
+pub use device::{Device, LocalTrust, ReadOnlyDevice, RejectedDevice, UserDevices};
+pub use manager::DeviceListChange;
 pub(crate) use manager::IdentityManager;
 use serde::{Deserialize, Deserializer, Serializer};
 pub use user::{
@@ -0,0 +1,441 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decryption of the room keys of a server-side key backup that uses the
+//! `m.megolm_backup.v1.curve25519-aes-sha2` algorithm.
+//!
+//! This allows room keys to be restored from a copy of a backup, e.g. the
+//! body of a `GET /room_keys/keys` response that another client saved, without
+//! talking to the server.
+
+use std::collections::BTreeMap;
+
+use aes::Aes256;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use olm_rs::{errors::OlmGroupSessionError, inbound_group_session::OlmInboundGroupSession};
+use ruma::{
+    api::client::r0::backup::{KeyBackupData, RoomKeyBackup},
+    DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId,
+};
+use serde::Deserialize;
+use serde_json::Error as SerdeError;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tracing::warn;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    olm::{ExportedGroupSessionKey, ExportedRoomKey},
+    utilities::{decode, encode, DecodeError},
+    RecoveryKey, SecretVec,
+};
+
+/// The size of the truncated MAC of a backed up session.
+const MAC_SIZE: usize = 8;
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+
+/// Error type for the decryption of backed up room keys.
+#[derive(Error, Debug)]
+pub enum KeyBackupError {
+    /// The session data isn't valid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// The ephemeral key of the session data isn't a valid curve25519 key.
+    #[error("The ephemeral key of the backed up session is invalid.")]
+    InvalidEphemeralKey,
+    /// The MAC of the session data doesn't match, the session was encrypted
+    /// for a different backup key or was tampered with.
+    #[error("The MAC of the backed up session is invalid.")]
+    InvalidMac,
+    /// The ciphertext of the session data couldn't be decrypted.
+    #[error("The backed up session couldn't be decrypted.")]
+    Decryption,
+    /// The decrypted session isn't a valid backed up room key.
+    #[error(transparent)]
+    Json(#[from] SerdeError),
+    /// The decrypted session key isn't a valid Megolm session key.
+    #[error(transparent)]
+    SessionKey(#[from] OlmGroupSessionError),
+    /// The decrypted session key belongs to a different session than the one
+    /// it was backed up as.
+    #[error("The backed up session {expected} contains the key of the session {found}.")]
+    SessionIdMismatch {
+        /// The session id the session was backed up as.
+        expected: String,
+        /// The id of the session the decrypted session key belongs to.
+        found: String,
+    },
+}
+
+/// The room keys that were decrypted from a key backup, see
+/// [`decrypt_key_backup()`].
+#[derive(Clone, Debug, Default)]
+pub struct DecryptedKeyBackup {
+    /// The decrypted room keys, they can be imported using
+    /// [`OlmMachine::import_keys()`](crate::OlmMachine::import_keys).
+    pub keys: Vec<ExportedRoomKey>,
+    /// The room and session ids of the sessions that couldn't be decrypted.
+    pub failures: Vec<(RoomId, String)>,
+}
+
+/// The decrypted content of a backed up session.
+#[derive(Deserialize)]
+struct BackedUpRoomKey {
+    algorithm: EventEncryptionAlgorithm,
+    sender_key: String,
+    session_key: ExportedGroupSessionKey,
+    sender_claimed_keys: BTreeMap<DeviceKeyAlgorithm, String>,
+    #[serde(default)]
+    forwarding_curve25519_key_chain: Vec<String>,
+    #[serde(default, rename = "org.matrix.msc3061.shared_history")]
+    shared_history: bool,
+}
+
+impl RecoveryKey {
+    /// Get the public curve25519 key of the key backup this recovery key
+    /// decrypts, encoded as unpadded base64.
+    ///
+    /// This is the `public_key` of the `auth_data` of the backup, clients
+    /// should check that it matches before they try to decrypt a backup.
+    pub fn megolm_backup_public_key(&self) -> String {
+        let secret = self.backup_secret();
+        encode(PublicKey::from(&secret).as_bytes())
+    }
+
+    fn backup_secret(&self) -> StaticSecret {
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        key.copy_from_slice(self.key().expose());
+
+        StaticSecret::from(*key)
+    }
+}
+
+/// Decrypt a single backed up session.
+///
+/// # Arguments
+///
+/// * `room_id` - The id of the room the session belongs to.
+///
+/// * `session_id` - The id of the session.
+///
+/// * `data` - The backed up session, as found in the key backup.
+///
+/// * `recovery_key` - The private key of the key backup.
+pub fn decrypt_backed_up_session(
+    room_id: &RoomId,
+    session_id: &str,
+    data: &KeyBackupData,
+    recovery_key: &RecoveryKey,
+) -> Result<ExportedRoomKey, KeyBackupError> {
+    let session_data = &data.session_data;
+
+    let ephemeral = decode(&session_data.ephemeral)?;
+    let ciphertext = decode(&session_data.ciphertext)?;
+    let mac = decode(&session_data.mac)?;
+
+    let mut ephemeral_key = [0u8; KEY_SIZE];
+
+    if ephemeral.len() != KEY_SIZE {
+        return Err(KeyBackupError::InvalidEphemeralKey);
+    }
+
+    ephemeral_key.copy_from_slice(&ephemeral);
+
+    let shared_secret =
+        recovery_key.backup_secret().diffie_hellman(&PublicKey::from(ephemeral_key));
+
+    let mut derived_keys = SecretVec::zeroed(KEY_SIZE * 2 + IV_SIZE);
+    Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE][..]), shared_secret.as_bytes())
+        .expand(b"", derived_keys.expose_mut())
+        .expect("Can't expand the shared secret of a backed up session");
+
+    let (aes_key, rest) = derived_keys.expose().split_at(KEY_SIZE);
+    let (mac_key, iv) = rest.split_at(KEY_SIZE);
+
+    // The spec calculates the MAC over the ciphertext, but libolm calculates
+    // it over an empty string, backups created by libolm based clients are
+    // accepted as well.
+    let mac_matches = |message: &[u8]| {
+        let mut hmac = Hmac::<Sha256>::new_varkey(mac_key).expect("Can't create HMAC object");
+        hmac.update(message);
+        let expected = hmac.finalize().into_bytes();
+
+        mac.len() == MAC_SIZE && bool::from(expected[..MAC_SIZE].ct_eq(&mac))
+    };
+
+    if !(mac_matches(&ciphertext) || mac_matches(&[])) {
+        return Err(KeyBackupError::InvalidMac);
+    }
+
+    let cipher = Cbc::<Aes256, Pkcs7>::new_var(aes_key, iv)
+        .expect("Can't create an AES object for a backed up session");
+    let plaintext =
+        SecretVec::new(cipher.decrypt_vec(&ciphertext).map_err(|_| KeyBackupError::Decryption)?);
+
+    let key: BackedUpRoomKey = serde_json::from_slice(plaintext.expose())?;
+
+    // The session id is taken from the untrusted backup, a backup could
+    // otherwise replace a session with the key of another one.
+    let found = OlmInboundGroupSession::import(&key.session_key.0)?.session_id();
+
+    if found != session_id {
+        return Err(KeyBackupError::SessionIdMismatch { expected: session_id.to_owned(), found });
+    }
+
+    Ok(ExportedRoomKey {
+        algorithm: key.algorithm,
+        room_id: room_id.clone(),
+        sender_key: key.sender_key,
+        session_id: session_id.to_owned(),
+        session_key: key.session_key,
+        sender_claimed_keys: key.sender_claimed_keys,
+        forwarding_curve25519_key_chain: key.forwarding_curve25519_key_chain,
+        shared_history: key.shared_history,
+    })
+}
+
+/// Decrypt all the sessions of a key backup.
+///
+/// Sessions that can't be decrypted are skipped and listed in the result, the
+/// decrypted keys can be imported using
+/// [`OlmMachine::import_keys()`](crate::OlmMachine::import_keys).
+///
+/// # Arguments
+///
+/// * `rooms` - The backed up sessions, grouped by room, as found in the
+/// `rooms` field of a `GET /room_keys/keys` response.
+///
+/// * `recovery_key` - The private key of the key backup.
+///
+/// # Examples
+/// ```no_run
+/// # use std::collections::BTreeMap;
+/// # use matrix_sdk_crypto::{decrypt_key_backup, RecoveryKey};
+/// # use ruma::{api::client::r0::backup::RoomKeyBackup, RoomId};
+/// # let backup_json = "";
+/// # let recovery_key = RecoveryKey::new();
+/// let rooms: BTreeMap<RoomId, RoomKeyBackup> = serde_json::from_str(backup_json).unwrap();
+/// let backup = decrypt_key_backup(&rooms, &recovery_key);
+///
+/// println!("Decrypted {} room keys", backup.keys.len());
+/// ```
+pub fn decrypt_key_backup(
+    rooms: &BTreeMap<RoomId, RoomKeyBackup>,
+    recovery_key: &RecoveryKey,
+) -> DecryptedKeyBackup {
+    let mut backup = DecryptedKeyBackup::default();
+
+    for (room_id, room) in rooms {
+        for (session_id, data) in &room.sessions {
+            match decrypt_backed_up_session(room_id, session_id, data, recovery_key) {
+                Ok(key) => backup.keys.push(key),
+                Err(e) => {
+                    warn!(
+                        "Can't decrypt the backed up session {} of room {}: {:?}",
+                        session_id, room_id, e
+                    );
+                    backup.failures.push((room_id.clone(), session_id.clone()));
+                }
+            }
+        }
+    }
+
+    backup
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use aes::Aes256;
+    use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+    use hkdf::Hkdf;
+    use hmac::{Hmac, Mac, NewMac};
+    use olm_rs::pk::OlmPkEncryption;
+    use ruma::{
+        api::client::r0::backup::{KeyBackupData, RoomKeyBackup},
+        room_id,
+    };
+    use serde_json::json;
+    use sha2::Sha256;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{
+        decrypt_backed_up_session, decrypt_key_backup, KeyBackupError, IV_SIZE, KEY_SIZE, MAC_SIZE,
+    };
+    use crate::{
+        machine::test::get_prepared_machine,
+        olm::ExportedRoomKey,
+        utilities::{decode, encode},
+        RecoveryKey,
+    };
+
+    fn backup_plaintext(key: &ExportedRoomKey) -> String {
+        json!({
+            "algorithm": key.algorithm,
+            "sender_key": key.sender_key,
+            "session_key": key.session_key,
+            "sender_claimed_keys": key.sender_claimed_keys,
+            "forwarding_curve25519_key_chain": key.forwarding_curve25519_key_chain,
+            "org.matrix.msc3061.shared_history": key.shared_history,
+        })
+        .to_string()
+    }
+
+    fn backup_data(ephemeral: String, ciphertext: String, mac: String) -> KeyBackupData {
+        serde_json::from_value(json!({
+            "first_message_index": 0,
+            "forwarded_count": 0,
+            "is_verified": true,
+            "session_data": {
+                "ephemeral": ephemeral,
+                "ciphertext": ciphertext,
+                "mac": mac,
+            },
+        }))
+        .unwrap()
+    }
+
+    /// Back up a session like libolm does it, the MAC is calculated over an
+    /// empty string.
+    fn backup_session(key: &ExportedRoomKey, recovery_key: &RecoveryKey) -> KeyBackupData {
+        let message = OlmPkEncryption::new(recovery_key.megolm_backup_public_key())
+            .encrypt(&backup_plaintext(key));
+
+        backup_data(message.ephemeral_key, message.ciphertext, message.mac)
+    }
+
+    /// Back up a session like the spec describes it, the MAC is calculated over
+    /// the ciphertext.
+    fn spec_backup_session(key: &ExportedRoomKey, recovery_key: &RecoveryKey) -> KeyBackupData {
+        let ephemeral = StaticSecret::from([7u8; KEY_SIZE]);
+
+        let mut backup_key = [0u8; KEY_SIZE];
+        backup_key.copy_from_slice(&decode(recovery_key.megolm_backup_public_key()).unwrap());
+        let shared_secret = ephemeral.diffie_hellman(&PublicKey::from(backup_key));
+
+        let mut derived_keys = [0u8; KEY_SIZE * 2 + IV_SIZE];
+        Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE][..]), shared_secret.as_bytes())
+            .expand(b"", &mut derived_keys)
+            .unwrap();
+
+        let (aes_key, rest) = derived_keys.split_at(KEY_SIZE);
+        let (mac_key, iv) = rest.split_at(KEY_SIZE);
+
+        let ciphertext = Cbc::<Aes256, Pkcs7>::new_var(aes_key, iv)
+            .unwrap()
+            .encrypt_vec(backup_plaintext(key).as_bytes());
+
+        let mut hmac = Hmac::<Sha256>::new_varkey(mac_key).unwrap();
+        hmac.update(&ciphertext);
+        let mac = hmac.finalize().into_bytes();
+
+        backup_data(
+            encode(PublicKey::from(&ephemeral).as_bytes()),
+            encode(&ciphertext),
+            encode(&mac[..MAC_SIZE]),
+        )
+    }
+
+    #[tokio::test]
+    async fn decrypt_backup() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+        let recovery_key = RecoveryKey::new();
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|_| true).await.unwrap();
+        let key = &export[0];
+
+        let mut sessions = BTreeMap::new();
+        sessions.insert(key.session_id.clone(), backup_session(key, &recovery_key));
+
+        let mut broken = backup_session(key, &recovery_key);
+        broken.session_data.mac = encode([0u8; 8]);
+        sessions.insert("broken".to_owned(), broken.clone());
+
+        let mut rooms = BTreeMap::new();
+        rooms.insert(room_id.clone(), RoomKeyBackup::new(sessions));
+
+        let backup = decrypt_key_backup(&rooms, &recovery_key);
+        assert_eq!(backup.keys, export);
+        assert_eq!(backup.failures, vec![(room_id.clone(), "broken".to_owned())]);
+
+        assert!(matches!(
+            decrypt_backed_up_session(&room_id, "broken", &broken, &recovery_key),
+            Err(KeyBackupError::InvalidMac)
+        ));
+
+        let backup = decrypt_key_backup(&rooms, &RecoveryKey::new());
+        assert!(backup.keys.is_empty());
+        assert_eq!(backup.failures.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn decrypt_spec_backup() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+        let recovery_key = RecoveryKey::new();
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|_| true).await.unwrap();
+        let key = &export[0];
+
+        let data = spec_backup_session(key, &recovery_key);
+        let decrypted =
+            decrypt_backed_up_session(&room_id, &key.session_id, &data, &recovery_key).unwrap();
+        assert_eq!(&decrypted, key);
+
+        let mut tampered = data.clone();
+        tampered.session_data.ciphertext =
+            backup_session(key, &recovery_key).session_data.ciphertext;
+        assert!(matches!(
+            decrypt_backed_up_session(&room_id, &key.session_id, &tampered, &recovery_key),
+            Err(KeyBackupError::InvalidMac)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_mismatched_session_id() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+        let recovery_key = RecoveryKey::new();
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|_| true).await.unwrap();
+        let key = &export[0];
+
+        // A backup that stores the key of a session under the id of another
+        // one mustn't be able to overwrite the other session.
+        let data = backup_session(key, &recovery_key);
+        assert!(matches!(
+            decrypt_backed_up_session(&room_id, "other_session", &data, &recovery_key),
+            Err(KeyBackupError::SessionIdMismatch { found, .. }) if found == key.session_id
+        ));
+
+        let mut sessions = BTreeMap::new();
+        sessions.insert("other_session".to_owned(), data);
+        let mut rooms = BTreeMap::new();
+        rooms.insert(room_id.clone(), RoomKeyBackup::new(sessions));
+
+        let backup = decrypt_key_backup(&rooms, &recovery_key);
+        assert!(backup.keys.is_empty());
+        assert_eq!(backup.failures, vec![(room_id, "other_session".to_owned())]);
+    }
+}
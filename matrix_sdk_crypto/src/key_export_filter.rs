@@ -0,0 +1,89 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A filter to select the room keys of a partial export.
+
+use std::collections::BTreeSet;
+
+use ruma::{MilliSecondsSinceUnixEpoch, RoomId};
+
+use crate::olm::InboundGroupSession;
+
+/// A filter selecting which room keys should be exported, see
+/// [`OlmMachine::export_keys_with_filter()`].
+///
+/// The default filter matches all keys, every condition that is added needs
+/// to hold for a key to be exported.
+///
+/// # Examples
+/// ```
+/// # use matrix_sdk_crypto::RoomKeyExportFilter;
+/// # use ruma::room_id;
+/// let filter = RoomKeyExportFilter::new()
+///     .rooms(vec![room_id!("!test:localhost")])
+///     .exclude_backed_up();
+/// ```
+///
+/// [`OlmMachine::export_keys_with_filter()`]: crate::OlmMachine::export_keys_with_filter
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomKeyExportFilter {
+    rooms: Option<BTreeSet<RoomId>>,
+    sender_keys: Option<BTreeSet<String>>,
+    created_after: Option<MilliSecondsSinceUnixEpoch>,
+    exclude_backed_up: bool,
+}
+
+impl RoomKeyExportFilter {
+    /// Create a new filter that matches all keys.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match keys of the given rooms.
+    pub fn rooms(mut self, rooms: impl IntoIterator<Item = RoomId>) -> Self {
+        self.rooms = Some(rooms.into_iter().collect());
+        self
+    }
+
+    /// Only match keys that were created by devices with the given curve25519
+    /// keys.
+    pub fn sender_keys(mut self, sender_keys: impl IntoIterator<Item = String>) -> Self {
+        self.sender_keys = Some(sender_keys.into_iter().collect());
+        self
+    }
+
+    /// Only match keys that this device created or received after the given
+    /// time.
+    ///
+    /// Imported keys and keys that were stored before the time was recorded,
+    /// see [`InboundGroupSession::created_at()`], don't match.
+    pub fn created_after(mut self, timestamp: MilliSecondsSinceUnixEpoch) -> Self {
+        self.created_after = Some(timestamp);
+        self
+    }
+
+    /// Don't match keys that were marked as backed up.
+    pub fn exclude_backed_up(mut self) -> Self {
+        self.exclude_backed_up = true;
+        self
+    }
+
+    /// Does the given session match the filter.
+    pub fn matches(&self, session: &InboundGroupSession) -> bool {
+        self.rooms.as_ref().map_or(true, |r| r.contains(session.room_id()))
+            && self.sender_keys.as_ref().map_or(true, |k| k.contains(session.sender_key()))
+            && self.created_after.map_or(true, |t| session.created_at().map_or(false, |c| c > t))
+            && !(self.exclude_backed_up && session.backed_up())
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types to observe and cancel an import of room keys.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// A handle that can be used to cancel an ongoing room key import.
+///
+/// The import checks the handle between chunks of keys, keys that were
+/// imported before the import got cancelled stay in the store. Clones of the
+/// handle share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct KeyImportCancellation {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl KeyImportCancellation {
+    /// Create a new cancellation handle.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the import that uses this handle.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst)
+    }
+
+    /// Was the import that uses this handle cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The reason why a single room key couldn't be imported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RoomKeyImportFailure {
+    /// The key uses an encryption algorithm that isn't supported.
+    UnsupportedAlgorithm,
+    /// The session key of the exported key is malformed.
+    InvalidSessionKey,
+}
+
+/// Statistics about a finished, or cancelled, room key import.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoomKeyImportResult {
+    /// The number of keys that were imported.
+    pub imported_count: usize,
+    /// The number of keys that were skipped because the same or a better
+    /// version of the session is already in the store.
+    pub skipped_count: usize,
+    /// The number of keys that couldn't be imported, grouped by the reason of
+    /// the failure.
+    pub failures: BTreeMap<RoomKeyImportFailure, usize>,
+    /// The total number of keys that were found in the key export.
+    pub total_count: usize,
+    /// Was the import cancelled before all the keys were processed.
+    pub cancelled: bool,
+}
+
+impl RoomKeyImportResult {
+    /// The number of keys that couldn't be imported.
+    pub fn failed_count(&self) -> usize {
+        self.failures.values().sum()
+    }
+
+    pub(crate) fn add_failure(&mut self, failure: RoomKeyImportFailure) {
+        *self.failures.entry(failure).or_insert(0) += 1;
+    }
+}
+
+/// Yield back to the executor once, so other tasks get a chance to run.
+pub(crate) async fn yield_now() {
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    YieldNow(false).await
+}
@@ -39,6 +39,7 @@ use thiserror::Error;
 use tracing::{error, info, trace, warn};
 
 use crate::{
+    environment::random_uuid,
     error::{OlmError, OlmResult},
     olm::{InboundGroupSession, Session, ShareState},
     requests::{OutgoingRequest, ToDeviceRequest},
@@ -47,6 +48,10 @@ use crate::{
     Device,
 };
 
+/// The maximal number of shared history room keys per room that are kept
+/// around until we learn who invited us to the room.
+const MAX_PENDING_SHARED_HISTORY_KEYS: usize = 1000;
+
 /// An error describing why a key share request won't be honored.
 #[derive(Debug, Clone, Error, PartialEq)]
 pub enum KeyshareDecision {
@@ -134,6 +139,12 @@ pub(crate) struct KeyRequestMachine {
     >,
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    /// The users that invited us to a room, shared history room keys of the
+    /// room are accepted from their verified devices.
+    room_inviters: Arc<DashMap<RoomId, UserId>>,
+    /// Shared history room keys of other users that were received before we
+    /// knew who invited us to the room, together with their sender.
+    pending_shared_history: Arc<DashMap<RoomId, Vec<(UserId, InboundGroupSession)>>>,
 }
 
 /// A struct describing an outgoing key request.
@@ -172,7 +183,7 @@ impl OutgoingKeyRequest {
             self.request_id.to_string(),
         );
 
-        let id = Uuid::new_v4();
+        let id = random_uuid();
         wrap_key_request_content(self.request_recipient.clone(), id, &content)
     }
 }
@@ -224,6 +235,8 @@ impl KeyRequestMachine {
             incoming_key_requests: DashMap::new().into(),
             wait_queue: WaitQueue::new(),
             users_for_key_claim,
+            room_inviters: DashMap::new().into(),
+            pending_shared_history: DashMap::new().into(),
         }
     }
 
@@ -387,6 +400,20 @@ impl KeyRequestMachine {
             return Ok(None);
         };
 
+        if self
+            .store
+            .get_rejected_device(&event.sender, &event.content.requesting_device_id)
+            .await?
+            .is_some()
+        {
+            warn!(
+                "Received a key request from {} {} but the device is in quarantine, \
+                 refusing to share the key",
+                &event.sender, &event.content.requesting_device_id
+            );
+            return Ok(None);
+        }
+
         let device =
             self.store.get_device(&event.sender, &event.content.requesting_device_id).await?;
 
@@ -433,7 +460,11 @@ impl KeyRequestMachine {
                 "Received a key request from an unknown device {} {}.",
                 &event.sender, &event.content.requesting_device_id
             );
-            self.store.update_tracked_user(&event.sender, true).await?;
+            // Queue the user up for a key query, the change is saved together
+            // with the rest of the changes of the sync.
+            let mut changes = Changes::default();
+            changes.tracked_users.insert(event.sender.clone(), true);
+            self.store.queue_changes(changes).await;
 
             Ok(None)
         }
@@ -448,21 +479,12 @@ impl KeyRequestMachine {
         let (used_session, content) =
             device.encrypt_session(session.clone(), message_index).await?;
 
-        let id = Uuid::new_v4();
-        let mut messages = BTreeMap::new();
+        let mut builder = ToDeviceRequest::builder(EventType::RoomEncrypted);
+        builder.add_message(device.user_id(), device.device_id().to_owned(), &content)?;
 
-        messages.entry(device.user_id().to_owned()).or_insert_with(BTreeMap::new).insert(
-            DeviceIdOrAllDevices::DeviceId(device.device_id().into()),
-            to_raw_value(&content)?,
-        );
-
-        let request = OutgoingRequest {
-            request_id: id,
-            request: Arc::new(
-                ToDeviceRequest { event_type: EventType::RoomEncrypted, txn_id: id, messages }
-                    .into(),
-            ),
-        };
+        let request = builder.build();
+        let id = request.txn_id;
+        let request = OutgoingRequest { request_id: id, request: Arc::new(request.into()) };
 
         self.outgoing_to_device_requests.insert(id, request);
 
@@ -613,7 +635,7 @@ impl KeyRequestMachine {
 
         let request = OutgoingKeyRequest {
             request_recipient: self.user_id().to_owned(),
-            request_id: Uuid::new_v4(),
+            request_id: random_uuid(),
             info: key_info,
             sent_out: false,
         };
@@ -724,15 +746,28 @@ impl KeyRequestMachine {
     }
 
     /// Receive a forwarded room key event.
+    ///
+    /// Forwarded room keys are only accepted if we requested them, or if they
+    /// are marked as shared history and were sent by a verified device of our
+    /// own or of the user that invited us to the room, see
+    /// [`receive_room_invite()`](#method.receive_room_invite). The latter are
+    /// only accepted if we don't already have a version of the session, keys
+    /// that nobody asked for from any other device are rejected since they
+    /// could be used to inject sessions.
     pub async fn receive_forwarded_room_key(
         &self,
         sender_key: &str,
         event: &mut ToDeviceEvent<ForwardedRoomKeyToDeviceEventContent>,
+        shared_history: bool,
     ) -> Result<(Option<AnyToDeviceEvent>, Option<InboundGroupSession>), CryptoStoreError> {
         let key_info = self.get_key_info(&event.content).await?;
 
         if let Some(info) = key_info {
-            let session = InboundGroupSession::from_forwarded_key(sender_key, &mut event.content)?;
+            let session = InboundGroupSession::from_forwarded_key(
+                sender_key,
+                &mut event.content,
+                shared_history,
+            )?;
 
             let old_session = self
                 .store
@@ -771,6 +806,8 @@ impl KeyRequestMachine {
             }
 
             Ok((Some(AnyToDeviceEvent::ForwardedRoomKey(event.clone())), session))
+        } else if shared_history {
+            self.receive_shared_history_key(sender_key, event).await
         } else {
             info!(
                 "Received a forwarded room key from {}, but no key info was found.",
@@ -779,6 +816,119 @@ impl KeyRequestMachine {
             Ok((None, None))
         }
     }
+
+    /// Receive a forwarded room key that is marked as shared history and
+    /// that we didn't request.
+    async fn receive_shared_history_key(
+        &self,
+        sender_key: &str,
+        event: &mut ToDeviceEvent<ForwardedRoomKeyToDeviceEventContent>,
+    ) -> Result<(Option<AnyToDeviceEvent>, Option<InboundGroupSession>), CryptoStoreError> {
+        let session =
+            InboundGroupSession::from_forwarded_key(sender_key, &mut event.content, true)?;
+
+        if self.has_session(&session).await? {
+            return Ok((None, None));
+        }
+
+        let inviter = self.room_inviters.get(session.room_id()).map(|i| i.clone());
+
+        if event.sender != *self.user_id && inviter.is_none() {
+            // The invite might be part of the same sync response, the to-device
+            // events are handled before the rooms.
+            let mut pending =
+                self.pending_shared_history.entry(session.room_id().clone()).or_default();
+
+            if pending.len() < MAX_PENDING_SHARED_HISTORY_KEYS {
+                pending.push((event.sender.clone(), session));
+            }
+
+            Ok((None, None))
+        } else if (event.sender == *self.user_id || inviter.as_ref() == Some(&event.sender))
+            && self.is_verified_device(&event.sender, sender_key).await?
+        {
+            info!(
+                "Received a shared history room key from {} for room {} with session id {}",
+                event.sender,
+                session.room_id(),
+                session.session_id()
+            );
+
+            Ok((Some(AnyToDeviceEvent::ForwardedRoomKey(event.clone())), Some(session)))
+        } else {
+            info!(
+                "Received a shared history room key from {} for room {}, but the sender isn't a \
+                 verified device of ours or of the user that invited us",
+                event.sender,
+                session.room_id(),
+            );
+
+            Ok((None, None))
+        }
+    }
+
+    /// Remember that the given user invited us to the given room.
+    ///
+    /// Shared history room keys of the room are accepted from the verified
+    /// devices of the inviter from now on. Returns the keys that were received
+    /// from them before we learned about the invite.
+    pub async fn receive_room_invite(
+        &self,
+        room_id: &RoomId,
+        inviter: &UserId,
+    ) -> Result<Vec<InboundGroupSession>, CryptoStoreError> {
+        self.room_inviters.insert(room_id.clone(), inviter.clone());
+
+        let pending =
+            self.pending_shared_history.remove(room_id).map(|(_, p)| p).unwrap_or_default();
+        let mut sessions = Vec::new();
+
+        for (sender, session) in pending {
+            if &sender == inviter
+                && self.is_verified_device(&sender, &session.sender_key).await?
+                && !self.has_session(&session).await?
+                && sessions.iter().all(|s: &InboundGroupSession| {
+                    s.session_id() != session.session_id() || s.sender_key != session.sender_key
+                })
+            {
+                sessions.push(session);
+            }
+        }
+
+        if !sessions.is_empty() {
+            info!(
+                "Accepting {} shared history room keys from {} for room {}",
+                sessions.len(),
+                inviter,
+                room_id
+            );
+        }
+
+        Ok(sessions)
+    }
+
+    /// Do we already have a version of the given session.
+    async fn has_session(&self, session: &InboundGroupSession) -> Result<bool, CryptoStoreError> {
+        Ok(self
+            .store
+            .get_inbound_group_session(session.room_id(), &session.sender_key, session.session_id())
+            .await?
+            .is_some())
+    }
+
+    /// Does the given curve25519 key belong to a verified device of the given
+    /// user.
+    async fn is_verified_device(
+        &self,
+        user_id: &UserId,
+        sender_key: &str,
+    ) -> Result<bool, CryptoStoreError> {
+        Ok(self
+            .store
+            .get_device_from_curve_key(user_id, sender_key)
+            .await?
+            .map_or(false, |d| d.trust_state()))
+    }
 }
 
 #[cfg(test)]
@@ -1006,8 +1156,10 @@ mod test {
                 .is_none()
         );
 
-        let (_, first_session) =
-            machine.receive_forwarded_room_key(&session.sender_key, &mut event).await.unwrap();
+        let (_, first_session) = machine
+            .receive_forwarded_room_key(&session.sender_key, &mut event, false)
+            .await
+            .unwrap();
         let first_session = first_session.unwrap();
 
         assert_eq!(first_session.first_known_index(), 10);
@@ -1040,8 +1192,10 @@ mod test {
 
         let mut event = ToDeviceEvent { sender: alice_id(), content };
 
-        let (_, second_session) =
-            machine.receive_forwarded_room_key(&session.sender_key, &mut event).await.unwrap();
+        let (_, second_session) = machine
+            .receive_forwarded_room_key(&session.sender_key, &mut event, false)
+            .await
+            .unwrap();
 
         assert!(second_session.is_none());
 
@@ -1051,12 +1205,118 @@ mod test {
 
         let mut event = ToDeviceEvent { sender: alice_id(), content };
 
-        let (_, second_session) =
-            machine.receive_forwarded_room_key(&session.sender_key, &mut event).await.unwrap();
+        let (_, second_session) = machine
+            .receive_forwarded_room_key(&session.sender_key, &mut event, false)
+            .await
+            .unwrap();
 
         assert_eq!(second_session.unwrap().first_known_index(), 0);
     }
 
+    #[async_test]
+    async fn reject_unrequested_forwarded_key() {
+        let machine = get_machine().await;
+        let bob_account = bob_account();
+
+        let (_, session) =
+            bob_account.create_group_session_pair_with_defaults(&room_id()).await.unwrap();
+        let content: ForwardedRoomKeyToDeviceEventContent =
+            session.export_at_index(0).await.try_into().unwrap();
+
+        // A stranger can't inject a session, even if it's marked as shared
+        // history.
+        let mut event = ToDeviceEvent { sender: bob_id(), content: content.clone() };
+        let (_, forwarded) = machine
+            .receive_forwarded_room_key(bob_account.identity_keys().curve25519(), &mut event, true)
+            .await
+            .unwrap();
+        assert!(forwarded.is_none());
+
+        // Neither can an unverified device of our own.
+        let alice_2 = alice_2_account();
+        let sender_key = alice_2.identity_keys().curve25519().to_owned();
+        let device = ReadOnlyDevice::from_account(&alice_2).await;
+        machine.store.save_devices(&[device.clone()]).await.unwrap();
+
+        let mut event = ToDeviceEvent { sender: alice_id(), content: content.clone() };
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&sender_key, &mut event, true).await.unwrap();
+        assert!(forwarded.is_none());
+
+        device.set_trust_state(LocalTrust::Verified);
+        machine.store.save_devices(&[device]).await.unwrap();
+
+        let mut event = ToDeviceEvent { sender: alice_id(), content };
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&sender_key, &mut event, true).await.unwrap();
+        assert_eq!(forwarded.unwrap().session_id(), session.session_id());
+    }
+
+    #[async_test]
+    async fn accept_shared_history_from_inviter() {
+        let machine = get_machine().await;
+        let bob_account = bob_account();
+        let bob_key = bob_account.identity_keys().curve25519().to_owned();
+        let bob_device = ReadOnlyDevice::from_account(&bob_account).await;
+        bob_device.set_trust_state(LocalTrust::Verified);
+
+        let carol_id = user_id!("@carol:example.org");
+        let carol_account = ReadOnlyAccount::new(&carol_id, &DeviceIdBox::from("CAROLDEVICE"));
+        let carol_key = carol_account.identity_keys().curve25519().to_owned();
+        let carol_device = ReadOnlyDevice::from_account(&carol_account).await;
+        carol_device.set_trust_state(LocalTrust::Verified);
+
+        machine.store.save_devices(&[bob_device, carol_device]).await.unwrap();
+
+        let shared_key = |account: &ReadOnlyAccount| {
+            let account = account.clone();
+
+            async move {
+                let (_, session) =
+                    account.create_group_session_pair_with_defaults(&room_id()).await.unwrap();
+                let content: ForwardedRoomKeyToDeviceEventContent =
+                    session.export_at_index(0).await.try_into().unwrap();
+
+                ToDeviceEvent { sender: account.user_id().clone(), content }
+            }
+        };
+
+        // Bob shares the history of the room before we know that he invited us.
+        let mut early = shared_key(&bob_account).await;
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&bob_key, &mut early, true).await.unwrap();
+        assert!(forwarded.is_none());
+
+        // Carol didn't invite us, her keys are never accepted.
+        let mut carols = shared_key(&carol_account).await;
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&carol_key, &mut carols, true).await.unwrap();
+        assert!(forwarded.is_none());
+
+        let sessions = machine.receive_room_invite(&room_id(), &bob_id()).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), early.content.session_id);
+        assert!(sessions[0].shared_history());
+
+        // Once the invite is known keys from Bob are accepted right away.
+        let mut late = shared_key(&bob_account).await;
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&bob_key, &mut late, true).await.unwrap();
+        assert_eq!(forwarded.unwrap().session_id(), late.content.session_id);
+
+        let mut carols = shared_key(&carol_account).await;
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&carol_key, &mut carols, true).await.unwrap();
+        assert!(forwarded.is_none());
+
+        // Keys that aren't marked as shared history still need to be
+        // requested.
+        let mut unmarked = shared_key(&bob_account).await;
+        let (_, forwarded) =
+            machine.receive_forwarded_room_key(&bob_key, &mut unmarked, false).await.unwrap();
+        assert!(forwarded.is_none());
+    }
+
     #[async_test]
     async fn should_share_key_test() {
         let machine = get_machine().await;
@@ -1255,7 +1515,7 @@ mod test {
 
         if let AnyToDeviceEvent::ForwardedRoomKey(mut e) = decrypted.event.deserialize().unwrap() {
             let (_, session) = alice_machine
-                .receive_forwarded_room_key(&decrypted.sender_key, &mut e)
+                .receive_forwarded_room_key(&decrypted.sender_key, &mut e, false)
                 .await
                 .unwrap();
             alice_machine.store.save_inbound_group_sessions(&[session.unwrap()]).await.unwrap();
@@ -1404,7 +1664,7 @@ mod test {
 
         if let AnyToDeviceEvent::ForwardedRoomKey(mut e) = decrypted.event.deserialize().unwrap() {
             let (_, session) = alice_machine
-                .receive_forwarded_room_key(&decrypted.sender_key, &mut e)
+                .receive_forwarded_room_key(&decrypted.sender_key, &mut e, false)
                 .await
                 .unwrap();
             alice_machine.store.save_inbound_group_sessions(&[session.unwrap()]).await.unwrap();
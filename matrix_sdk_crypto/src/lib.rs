@@ -27,33 +27,68 @@
 )]
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 
+mod diagnostics;
+mod environment;
 mod error;
 mod file_encryption;
 mod identities;
+mod key_backup;
+mod key_export_filter;
+mod key_import;
 mod key_request;
 mod machine;
+mod maintenance;
 pub mod olm;
+mod passphrase;
+mod recovery_key;
 mod requests;
+mod secret;
 mod session_manager;
 pub mod store;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(feature = "docs", doc(cfg(testing)))]
+pub mod test_vectors;
 mod utilities;
 mod verification;
 
-pub use error::{MegolmError, OlmError};
+pub use diagnostics::{CrossSigningStatus, EncryptionHealth, RoomKeyCounts};
+pub use error::{MegolmError, OlmError, SignatureError};
+#[cfg(feature = "passphrase_strength")]
+#[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+pub use file_encryption::encrypt_key_export_with_min_strength;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub use file_encryption::parse_key_export;
 pub use file_encryption::{
-    decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, EncryptionInfo, KeyExportError,
+    decrypt_key_export, decrypt_key_export_with_key, decrypt_key_export_with_limits,
+    encrypt_key_export, encrypt_key_export_with_kdf, encrypt_key_export_with_key,
+    AttachmentDecryptor, AttachmentEncryptor, DecryptorError, EncryptionInfo, KeyDerivation,
+    KeyExportError, KeyExportLimits,
 };
 pub use identities::{
-    Device, LocalTrust, OwnUserIdentity, ReadOnlyDevice, UserDevices, UserIdentities, UserIdentity,
+    Device, DeviceListChange, LocalTrust, OwnUserIdentity, ReadOnlyDevice, RejectedDevice,
+    UserDevices, UserIdentities, UserIdentity,
 };
+pub use key_backup::{
+    decrypt_backed_up_session, decrypt_key_backup, DecryptedKeyBackup, KeyBackupError,
+};
+pub use key_export_filter::RoomKeyExportFilter;
+pub use key_import::{KeyImportCancellation, RoomKeyImportFailure, RoomKeyImportResult};
 pub use machine::OlmMachine;
+pub use maintenance::{MaintenanceSettings, MaintenanceSummary};
 pub use matrix_qrcode;
 pub use olm::EncryptionSettings;
 pub(crate) use olm::ReadOnlyAccount;
+#[cfg(feature = "passphrase_strength")]
+#[cfg_attr(feature = "docs", doc(cfg(passphrase_strength)))]
+pub use passphrase::estimate_passphrase_strength;
+pub use passphrase::PassphraseStrength;
+pub use recovery_key::{PassphraseInfo, RecoveryKey, RecoveryKeyError, PBKDF2_ALGORITHM};
 pub use requests::{
-    IncomingResponse, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
-    OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest,
+    IncomingResponse, KeysQueryRequest, KeysUploadRequest, OutgoingRequest, OutgoingRequests,
+    OutgoingVerificationRequest, RequestConversionError, RoomMessageRequest, SignedFallbackKey,
+    ToDeviceRequest, ToDeviceRequestBuilder,
 };
+pub use secret::SecretVec;
 pub use store::CryptoStoreError;
 pub use verification::{AcceptSettings, QrVerification, Sas, Verification, VerificationRequest};
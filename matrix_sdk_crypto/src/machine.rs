@@ -14,14 +14,27 @@
 
 #[cfg(feature = "sled_cryptostore")]
 use std::path::Path;
-use std::{collections::BTreeMap, mem, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryInto,
+    future::Future,
+    mem,
+    sync::{Arc, RwLock as StdRwLock},
+    time::Duration,
+};
 
 use dashmap::DashMap;
+use futures::future;
 use matrix_sdk_common::{
-    deserialized_responses::{AlgorithmInfo, EncryptionInfo, SyncRoomEvent, VerificationState},
+    deserialized_responses::{
+        AlgorithmInfo, EncryptionInfo, OlmEncryptionInfo, SyncRoomEvent, ToDevice,
+        ToDeviceEvent as SyncToDeviceEvent, VerificationState,
+    },
+    instant::Instant,
     locks::Mutex,
     uuid::Uuid,
 };
+use olm_rs::errors::OlmGroupSessionError;
 use ruma::{
     api::client::r0::{
         keys::{
@@ -30,39 +43,54 @@ use ruma::{
             upload_keys,
             upload_signatures::Request as UploadSignaturesRequest,
         },
-        sync::sync_events::{DeviceLists, ToDevice},
+        sync::sync_events::{DeviceLists, ToDevice as RumaToDevice},
     },
-    assign,
     events::{
         room::encrypted::{EncryptedEventContent, EncryptedEventScheme},
         room_key::RoomKeyToDeviceEventContent,
-        AnyMessageEventContent, AnyRoomEvent, AnyToDeviceEvent, SyncMessageEvent, ToDeviceEvent,
+        AnyMessageEventContent, AnyRoomEvent, AnySyncMessageEvent, AnySyncRoomEvent,
+        AnyToDeviceEvent, SyncMessageEvent, ToDeviceEvent,
     },
-    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId, UInt, UserId,
+    serde::{CanonicalJsonValue, Raw},
+    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, RoomId, UInt,
+    UserId,
 };
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use tracing::{debug, error, info, trace, warn};
 
+#[cfg(feature = "testing")]
+use crate::olm::EncryptionPreview;
 #[cfg(feature = "sled_cryptostore")]
 use crate::store::sled::SledStore;
 use crate::{
-    error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
-    identities::{Device, IdentityManager, UserDevices},
+    diagnostics::{CrossSigningStatus, EncryptionHealth, RoomKeyCounts},
+    environment::{now, random_uuid, timestamp},
+    error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult, SignatureError},
+    identities::{Device, DeviceListChange, IdentityManager, RejectedDevice, UserDevices},
+    key_export_filter::RoomKeyExportFilter,
+    key_import::{yield_now, KeyImportCancellation, RoomKeyImportFailure, RoomKeyImportResult},
     key_request::KeyRequestMachine,
+    maintenance::{MaintenanceSettings, MaintenanceSummary},
     olm::{
         Account, EncryptionSettings, ExportedRoomKey, GroupSessionKey, IdentityKeys,
-        InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity, ReadOnlyAccount,
-        SessionType,
+        InboundGroupSession, MegolmMessageIndex, OlmDecryptionInfo, PrivateCrossSigningIdentity,
+        ReadOnlyAccount, SessionType, SHARED_HISTORY_FIELD,
     },
-    requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
+    requests::{IncomingResponse, KeysUploadRequest, OutgoingRequest, UploadSigningKeysRequest},
     session_manager::{GroupSessionManager, SessionManager},
     store::{
-        Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, Result as StoreResult,
-        Store,
+        retention_cutoff, Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore,
+        Result as StoreResult, Store, SyncCheckpoint, ToDeviceJournalEntry,
     },
+    utilities::{encode, parallel_map},
     verification::{Verification, VerificationMachine, VerificationRequest},
     ToDeviceRequest,
 };
 
+/// The number of room keys that are imported and saved at once.
+const KEY_IMPORT_CHUNK_SIZE: usize = 1000;
+
 /// State machine implementation of the Olm/Megolm encryption protocol used for
 /// Matrix end to end encryption.
 #[derive(Clone)]
@@ -96,6 +124,11 @@ pub struct OlmMachine {
     /// of when a key query needs to be done and handling one.
     identity_manager: IdentityManager,
     cross_signing_request: Arc<Mutex<Option<UploadSignaturesRequest>>>,
+    /// The settings of the periodic maintenance of the store, `None` if it's
+    /// disabled.
+    maintenance_settings: Arc<StdRwLock<Option<MaintenanceSettings>>>,
+    /// When the maintenance of the store ran last.
+    last_maintenance: Arc<Mutex<Option<Instant>>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -184,6 +217,8 @@ impl OlmMachine {
             key_request_machine,
             identity_manager,
             cross_signing_request: Arc::new(Mutex::new(None)),
+            maintenance_settings: Arc::new(StdRwLock::new(None)),
+            last_maintenance: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -270,11 +305,161 @@ impl OlmMachine {
         &self.device_id
     }
 
+    /// Set the number of one-time keys the machine should keep uploaded to
+    /// the server.
+    ///
+    /// Every new Olm session another device creates with us uses up one of
+    /// our one-time keys, the machine uploads new keys once the count on the
+    /// server drops below the target. A lower target reduces the size of key
+    /// uploads, at the cost of running out of keys sooner while the device is
+    /// offline.
+    ///
+    /// The default is also the maximum, half the number of keys an Olm
+    /// account can hold. The account needs room for the keys that were
+    /// claimed but whose messages didn't arrive yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The number of keys that should be on the server, `None`
+    /// restores the default.
+    pub fn set_one_time_key_target(&self, target: Option<u64>) {
+        self.account.set_one_time_key_target(target)
+    }
+
+    /// Get the number of one-time keys the machine keeps uploaded to the
+    /// server.
+    pub async fn one_time_key_target(&self) -> u64 {
+        self.account.one_time_key_target().await
+    }
+
+    /// Set how long a fallback key is used before it's replaced with a new
+    /// one.
+    ///
+    /// Other devices use our fallback key to start an Olm session with us once
+    /// our one-time keys ran out, e.g. because we were offline for a long
+    /// time. Unlike one-time keys it can be used multiple times, replacing it
+    /// regularly limits how long a compromise of the key matters.
+    ///
+    /// The lifetime only applies if the server doesn't report which fallback
+    /// keys are unused, otherwise the fallback key is replaced once it was
+    /// used, see [`receive_unused_fallback_keys()`].
+    ///
+    /// [`receive_unused_fallback_keys()`]: #method.receive_unused_fallback_keys
+    ///
+    /// # Arguments
+    ///
+    /// * `lifetime` - How long a fallback key is used, `None` restores the
+    /// default of a week.
+    pub fn set_fallback_key_lifetime(&self, lifetime: Option<Duration>) {
+        self.account.set_fallback_key_lifetime(lifetime)
+    }
+
+    /// Get how long a fallback key is used before it's replaced with a new
+    /// one.
+    pub fn fallback_key_lifetime(&self) -> Duration {
+        self.account.fallback_key_lifetime()
+    }
+
+    /// Receive the key algorithms of our unused fallback keys, the
+    /// `device_unused_fallback_key_types` field of a sync response.
+    ///
+    /// A fallback key the server reports as unused is kept regardless of its
+    /// age, a used one is replaced with the next keys upload.
+    ///
+    /// # Arguments
+    ///
+    /// * `unused_fallback_keys` - The key algorithms of the unused fallback
+    /// keys, `None` if the sync response didn't contain the field.
+    pub fn receive_unused_fallback_keys(
+        &self,
+        unused_fallback_keys: Option<&[DeviceKeyAlgorithm]>,
+    ) {
+        self.account.update_unused_fallback_keys(unused_fallback_keys)
+    }
+
     /// Get the public parts of our Olm identity keys.
     pub fn identity_keys(&self) -> &IdentityKeys {
         self.account.identity_keys()
     }
 
+    /// Sign a JSON object with the ed25519 key of this device, following the
+    /// Matrix [signing rules].
+    ///
+    /// The `signatures` and `unsigned` fields of the object aren't signed,
+    /// existing signatures are kept and the signature of this device is
+    /// added under our user ID and the `ed25519:<device_id>` key ID.
+    ///
+    /// Returns the signed object.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The JSON object that should be signed.
+    ///
+    /// [signing rules]: https://spec.matrix.org/unstable/appendices/#signing-json
+    pub async fn sign_json(&self, mut value: Value) -> Result<Value, SignatureError> {
+        let object = value.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+        let unsigned = object.remove("unsigned");
+        let signatures = object.remove("signatures");
+
+        let canonical_json: CanonicalJsonValue =
+            value.clone().try_into().map_err(|_| SignatureError::NotCanonicalJson)?;
+        let signature = self.account.sign(&canonical_json.to_string()).await;
+
+        let mut signatures = match signatures {
+            Some(Value::Object(s)) => s,
+            Some(_) => return Err(SignatureError::NoSignatureFound),
+            None => Map::new(),
+        };
+
+        let key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, self.device_id());
+        let user_signatures = signatures
+            .entry(self.user_id().to_string())
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or(SignatureError::NoSignatureFound)?;
+        user_signatures.insert(key_id.to_string(), Value::String(signature));
+
+        let object = value.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+        object.insert("signatures".to_owned(), Value::Object(signatures));
+
+        if let Some(u) = unsigned {
+            object.insert("unsigned".to_owned(), u);
+        }
+
+        Ok(value)
+    }
+
+    /// Check that a JSON object was signed by the ed25519 key of the given
+    /// device.
+    ///
+    /// This is the counterpart of [`sign_json`], the device needs to be known
+    /// to the store, i.e. its keys were queried before.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user that owns the device.
+    ///
+    /// * `device_id` - The ID of the device that should have signed the
+    /// object.
+    ///
+    /// * `value` - The signed JSON object.
+    ///
+    /// [`sign_json`]: #method.sign_json
+    pub async fn verify_signature(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        value: &Value,
+    ) -> OlmResult<()> {
+        let device = self
+            .store
+            .get_readonly_device(user_id, device_id)
+            .await?
+            .ok_or(SignatureError::MissingSigningKey)?;
+
+        Ok(device.is_signed_by_device(&mut value.clone())?)
+    }
+
     /// Get the outgoing requests that need to be sent out.
     ///
     /// This returns a list of `OutGoingRequest`, those requests need to be sent
@@ -288,18 +473,13 @@ impl OlmMachine {
         if let Some(r) = self
             .keys_for_upload()
             .await
-            .map(|r| OutgoingRequest { request_id: Uuid::new_v4(), request: Arc::new(r.into()) })
+            .map(|r| OutgoingRequest { request_id: random_uuid(), request: Arc::new(r.into()) })
         {
             requests.push(r);
         }
 
-        for request in
-            self.identity_manager.users_for_key_query().await.into_iter().map(|r| OutgoingRequest {
-                request_id: Uuid::new_v4(),
-                request: Arc::new(r.into()),
-            })
-        {
-            requests.push(request);
+        for (request_id, request) in self.identity_manager.users_for_key_query().await {
+            requests.push(OutgoingRequest { request_id, request: Arc::new(request.into()) });
         }
 
         requests.append(&mut self.verification_machine.outgoing_messages());
@@ -327,6 +507,7 @@ impl OlmMachine {
                 self.receive_keys_upload_response(response).await?;
             }
             IncomingResponse::KeysQuery(response) => {
+                self.identity_manager.finish_key_query(request_id);
                 self.receive_keys_query_response(response).await?;
             }
             IncomingResponse::KeysClaim(response) => {
@@ -519,9 +700,8 @@ impl OlmMachine {
     ///
     /// [`receive_keys_upload_response`]: #method.receive_keys_upload_response
     /// [`OlmMachine`]: struct.OlmMachine.html
-    async fn keys_for_upload(&self) -> Option<upload_keys::Request> {
-        let (device_keys, one_time_keys) = self.account.keys_for_upload().await?;
-        Some(assign!(upload_keys::Request::new(), { device_keys, one_time_keys }))
+    async fn keys_for_upload(&self) -> Option<KeysUploadRequest> {
+        self.account.keys_for_upload().await
     }
 
     /// Decrypt a to-device event.
@@ -560,18 +740,20 @@ impl OlmMachine {
         sender_key: &str,
         signing_key: &str,
         event: &mut ToDeviceEvent<RoomKeyToDeviceEventContent>,
+        shared_history: bool,
     ) -> OlmResult<(Option<AnyToDeviceEvent>, Option<InboundGroupSession>)> {
         match event.content.algorithm {
             EventEncryptionAlgorithm::MegolmV1AesSha2 => {
                 let session_key = GroupSessionKey(mem::take(&mut event.content.session_key));
 
-                let session = InboundGroupSession::new(
+                let mut session = InboundGroupSession::new(
                     sender_key,
                     signing_key,
                     &event.content.room_id,
                     session_key,
                     None,
                 )?;
+                session.shared_history = shared_history;
 
                 info!(
                     "Received a new room key from {} for room {} with session id {}",
@@ -639,6 +821,37 @@ impl OlmMachine {
         self.group_session_manager.encrypt(room_id, content).await
     }
 
+    /// Preview the encryption of a room message, without sending it.
+    ///
+    /// Returns the exact encrypted content, and the group session id and
+    /// message index, that [`encrypt()`](#method.encrypt) would produce for
+    /// the given content. The outbound group session of the room isn't
+    /// modified, this is meant to debug the protocol or to create test
+    /// fixtures.
+    ///
+    /// **Warning**: The preview uses the same message index as the next real
+    /// message, the previewed ciphertext must never be sent out.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the message should be
+    /// encrypted.
+    ///
+    /// * `content` - The plaintext content of the message that should be
+    /// encrypted.
+    ///
+    /// Returns a `MissingSession` error if a group session for the given room
+    /// wasn't shared beforehand.
+    #[cfg(feature = "testing")]
+    #[cfg_attr(feature = "docs", doc(cfg(testing)))]
+    pub async fn preview_encryption(
+        &self,
+        room_id: &RoomId,
+        content: AnyMessageEventContent,
+    ) -> MegolmResult<EncryptionPreview> {
+        self.group_session_manager.preview_encryption(room_id, content).await
+    }
+
     /// Invalidate the currently active outbound group session for the given
     /// room.
     ///
@@ -648,6 +861,22 @@ impl OlmMachine {
         self.group_session_manager.invalidate_group_session(room_id).await
     }
 
+    /// Drop the cryptographic state of the given room that is cached in memory.
+    ///
+    /// This is useful for long running clients that are part of many rooms,
+    /// the state will be lazily reloaded from the store once the room becomes
+    /// active again, e.g. once we try to send a message to it.
+    ///
+    /// Stores that don't persist outbound group sessions, like the
+    /// `MemoryStore`, will create a new outbound group session once a message
+    /// needs to be sent to the room again.
+    ///
+    /// Returns true if some state was dropped, false if the room didn't have
+    /// any cached state or if the state is still in use.
+    pub fn unload_room(&self, room_id: &RoomId) -> bool {
+        self.group_session_manager.unload_room(room_id)
+    }
+
     /// Get to-device requests to share a group session with users in a room.
     ///
     /// # Arguments
@@ -665,6 +894,58 @@ impl OlmMachine {
         self.group_session_manager.share_group_session(room_id, users, encryption_settings).await
     }
 
+    /// Get to-device requests to share the history of a room with a user that
+    /// was invited to the room.
+    ///
+    /// Only the room keys of sessions that were created while the history
+    /// visibility of the room was `shared` or `world_readable` are shared, the
+    /// keys are sent out as `m.forwarded_room_key` events.
+    ///
+    /// Olm sessions need to be established with the devices of the user before
+    /// this is called, see [`get_missing_sessions()`](#method.
+    /// get_missing_sessions), devices without an Olm session won't receive the
+    /// room keys.
+    ///
+    /// **Note**: Keys that weren't requested are only accepted from verified
+    /// devices of the receiving user or of the user that invited them, see
+    /// [`receive_room_invite()`](#method.receive_room_invite). The keys should
+    /// thus be shared by the user that sent the invite.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The id of the room whose history should be shared.
+    ///
+    /// `user_id` - The user that was invited to the room.
+    pub async fn share_room_history(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
+        self.group_session_manager.share_room_history(room_id, user_id).await
+    }
+
+    /// Receive an invite of our own user to a room.
+    ///
+    /// The room keys that the inviter shares from the history of the room,
+    /// see [`share_room_history()`](#method.share_room_history), are accepted
+    /// if they were sent by a verified device of the inviter. Keys that
+    /// arrived before the invite are imported now.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The id of the room we were invited to.
+    ///
+    /// `inviter` - The user that sent the invite.
+    pub async fn receive_room_invite(&self, room_id: &RoomId, inviter: &UserId) -> OlmResult<()> {
+        let sessions = self.key_request_machine.receive_room_invite(room_id, inviter).await?;
+
+        if !sessions.is_empty() {
+            self.store.save_inbound_group_sessions(&sessions).await?;
+        }
+
+        Ok(())
+    }
+
     /// Receive and properly handle a decrypted to-device event.
     ///
     /// # Arguments
@@ -686,13 +967,15 @@ impl OlmMachine {
             }
         };
 
+        let shared_history = Self::has_shared_history(&decrypted.event);
+
         match event {
-            AnyToDeviceEvent::RoomKey(mut e) => {
-                Ok(self.add_room_key(&decrypted.sender_key, &decrypted.signing_key, &mut e).await?)
-            }
+            AnyToDeviceEvent::RoomKey(mut e) => Ok(self
+                .add_room_key(&decrypted.sender_key, &decrypted.signing_key, &mut e, shared_history)
+                .await?),
             AnyToDeviceEvent::ForwardedRoomKey(mut e) => Ok(self
                 .key_request_machine
-                .receive_forwarded_room_key(&decrypted.sender_key, &mut e)
+                .receive_forwarded_room_key(&decrypted.sender_key, &mut e, shared_history)
                 .await?),
             _ => {
                 warn!("Received an unexpected encrypted to-device event");
@@ -701,6 +984,15 @@ impl OlmMachine {
         }
     }
 
+    /// Check if a decrypted room key event marks its session as one that may
+    /// be shared with new members of the room.
+    fn has_shared_history(event: &Raw<AnyToDeviceEvent>) -> bool {
+        serde_json::from_str::<Value>(event.json().get())
+            .ok()
+            .and_then(|e| e.get("content")?.get(SHARED_HISTORY_FIELD)?.as_bool())
+            .unwrap_or(false)
+    }
+
     async fn handle_verification_event(&self, event: &AnyToDeviceEvent) {
         if let Err(e) = self.verification_machine.receive_any_event(event).await {
             error!("Error handling a verification event: {:?}", e);
@@ -736,6 +1028,22 @@ impl OlmMachine {
         self.verification_machine.get_requests(user_id)
     }
 
+    /// Get all the verification objects that are currently in flight.
+    ///
+    /// Verifications are tracked per user and flow id, so multiple flows with
+    /// the same or with different users can be active at the same time. Done
+    /// or cancelled verifications aren't included.
+    pub fn active_verifications(&self) -> Vec<Verification> {
+        self.verification_machine.get_all_verifications()
+    }
+
+    /// Get all the verification requests that are currently in flight.
+    ///
+    /// Done or cancelled verification requests aren't included.
+    pub fn active_verification_requests(&self) -> Vec<VerificationRequest> {
+        self.verification_machine.get_all_requests()
+    }
+
     async fn update_one_time_key_count(&self, key_count: &BTreeMap<DeviceKeyAlgorithm, UInt>) {
         self.account.update_uploaded_key_count(key_count).await;
     }
@@ -763,6 +1071,28 @@ impl OlmMachine {
         }
     }
 
+    /// Get the checkpoint of the last sync response whose to-device events
+    /// were committed to the store.
+    ///
+    /// See [`SyncCheckpoint`] for how it relates to the sync token of the
+    /// state store.
+    pub async fn sync_checkpoint(&self) -> StoreResult<Option<SyncCheckpoint>> {
+        self.store.load_sync_checkpoint().await
+    }
+
+    /// Get the journal of the processed to-device events, the oldest entry
+    /// comes first.
+    ///
+    /// To-device events that are found in the journal are skipped if the
+    /// homeserver sends them again. Entries that are older than the
+    /// [`message_hash_retention`](MaintenanceSettings::message_hash_retention)
+    /// of the maintenance settings, or its default if the maintenance is
+    /// disabled, are pruned whenever new entries are added. The journal is
+    /// meant to be used for debugging.
+    pub async fn to_device_journal(&self) -> StoreResult<Vec<ToDeviceJournalEntry>> {
+        self.store.get_to_device_journal().await
+    }
+
     /// Handle a to-device and one-time key counts from a sync response.
     ///
     /// This will decrypt and handle to-device events returning the decrypted
@@ -781,32 +1111,45 @@ impl OlmMachine {
     /// * `one_time_keys_count` - The current one-time keys counts that the sync
     /// response returned.
     ///
+    /// * `checkpoint` - The sync tokens of the sync response, they are saved
+    /// in the same transaction as the rest of the changes. The sync token
+    /// should only be persisted elsewhere after this method returned.
+    ///
+    /// To-device events that were already processed, e.g. because the
+    /// homeserver sent the same sync response twice, are skipped and not
+    /// returned, see [`to_device_journal()`](#method.to_device_journal).
+    ///
     /// [`decrypt_room_event`]: #method.decrypt_room_event
     pub async fn receive_sync_changes(
         &self,
-        to_device_events: ToDevice,
+        to_device_events: RumaToDevice,
         changed_devices: &DeviceLists,
         one_time_keys_counts: &BTreeMap<DeviceKeyAlgorithm, UInt>,
+        checkpoint: SyncCheckpoint,
     ) -> OlmResult<ToDevice> {
         // Remove verification objects that have expired or are done.
         self.verification_machine.garbage_collect();
 
+        let sync_token = checkpoint.next_batch.clone();
+
         // Always save the account, a new session might get created which also
         // touches the account.
-        let mut changes =
-            Changes { account: Some(self.account.inner.clone()), ..Default::default() };
+        let mut changes = Changes {
+            account: Some(self.account.inner.clone()),
+            sync_checkpoint: Some(checkpoint),
+            ..Default::default()
+        };
 
         self.update_one_time_key_count(one_time_keys_counts).await;
 
-        for user_id in &changed_devices.changed {
-            if let Err(e) = self.identity_manager.mark_user_as_changed(user_id).await {
-                error!("Error marking a tracked user as changed {:?}", e);
-            }
-        }
+        // Mark the users as changed in the same transaction that saves the rest
+        // of the sync changes.
+        changes.tracked_users.extend(self.identity_manager.changed_users(&changed_devices.changed));
 
         let mut events = Vec::new();
+        let mut processed = HashSet::new();
 
-        for mut raw_event in to_device_events.events {
+        for raw_event in to_device_events.events {
             let event = match raw_event.deserialize() {
                 Ok(e) => e,
                 Err(e) => {
@@ -816,8 +1159,31 @@ impl OlmMachine {
                 }
             };
 
+            let hash = encode(Sha256::digest(raw_event.json().get().as_bytes()));
+
+            // The event might be delivered again in the same or a later sync
+            // response, handling a room key or verification message twice
+            // isn't safe.
+            if processed.contains(&hash) || self.store.is_to_device_event_processed(&hash).await? {
+                debug!(
+                    "Skipping the already processed to-device event {} of type {} from {}",
+                    hash,
+                    event.event_type(),
+                    event.sender()
+                );
+                continue;
+            }
+
             info!("Received a to-device event {:?}", event);
 
+            let entry = ToDeviceJournalEntry {
+                hash,
+                event_type: event.event_type().to_owned(),
+                sender: event.sender().clone(),
+                sync_token: sync_token.clone(),
+                processed_at: timestamp(),
+            };
+
             match event {
                 AnyToDeviceEvent::RoomEncrypted(e) => {
                     let decrypted = match self.decrypt_to_device_event(&e).await {
@@ -859,28 +1225,47 @@ impl OlmMachine {
                         changes.inbound_group_sessions.push(group_session);
                     }
 
-                    if let Some(event) = decrypted.deserialized_event {
-                        self.handle_to_device_event(&event).await;
+                    if let Some(event) = &decrypted.deserialized_event {
+                        self.handle_to_device_event(event).await;
                     }
 
-                    raw_event = decrypted.event;
+                    let encryption_info =
+                        self.get_olm_encryption_info(&e.sender, &decrypted).await?;
+
+                    events.push(SyncToDeviceEvent {
+                        event: decrypted.event,
+                        encryption_info: Some(encryption_info),
+                    });
+                }
+                e => {
+                    self.handle_to_device_event(&e).await;
+                    events.push(raw_event.into());
                 }
-                e => self.handle_to_device_event(&e).await,
             }
 
-            events.push(raw_event);
+            processed.insert(entry.hash.clone());
+            changes.to_device_journal.push(entry);
         }
 
         let changed_sessions = self.key_request_machine.collect_incoming_key_requests().await?;
 
         changes.sessions.extend(changed_sessions);
 
+        let journaled = !changes.to_device_journal.is_empty();
         self.store.save_changes(changes).await?;
 
-        let mut to_device = ToDevice::new();
-        to_device.events = events;
+        // The journal is pruned as new entries come in, using the same
+        // retention as the hashes of Olm messages.
+        if journaled {
+            let settings = self.maintenance_settings.read().unwrap().unwrap_or_default();
+            self.store
+                .remove_to_device_journal_entries_older_than(retention_cutoff(
+                    settings.message_hash_retention,
+                ))
+                .await?;
+        }
 
-        Ok(to_device)
+        Ok(ToDevice { events })
     }
 
     /// Request a room key from our devices.
@@ -918,24 +1303,34 @@ impl OlmMachine {
     async fn get_encryption_info(
         &self,
         session: &InboundGroupSession,
+        message_index: u32,
         sender: &UserId,
         device_id: &DeviceId,
     ) -> StoreResult<EncryptionInfo> {
-        let verification_state = if let Some(device) =
-            self.get_device(sender, device_id).await?.filter(|d| {
-                d.get_key(DeviceKeyAlgorithm::Curve25519)
-                    .map(|k| k == session.sender_key())
-                    .unwrap_or(false)
-            }) {
-            if (self.user_id() == device.user_id() && self.device_id() == device.device_id())
-                || device.is_trusted()
-            {
-                VerificationState::Trusted
-            } else {
-                VerificationState::Untrusted
+        let verification_state = match self.get_device(sender, device_id).await? {
+            Some(device) => {
+                let curve_key_matches = device
+                    .get_key(DeviceKeyAlgorithm::Curve25519)
+                    .map_or(false, |k| k == session.sender_key());
+                // Sessions that were forwarded to us might not contain the
+                // signing key of the device that created them.
+                let signing_key_matches = session
+                    .signing_keys()
+                    .get(&DeviceKeyAlgorithm::Ed25519)
+                    .map_or(true, |k| device.get_key(DeviceKeyAlgorithm::Ed25519) == Some(k));
+
+                if !curve_key_matches || !signing_key_matches {
+                    VerificationState::MismatchedSender
+                } else if (self.user_id() == device.user_id()
+                    && self.device_id() == device.device_id())
+                    || device.is_trusted()
+                {
+                    VerificationState::Trusted
+                } else {
+                    VerificationState::Untrusted
+                }
             }
-        } else {
-            VerificationState::UnknownDevice
+            None => VerificationState::UnknownDevice,
         };
 
         let sender = sender.clone();
@@ -948,11 +1343,45 @@ impl OlmMachine {
                 curve25519_key: session.sender_key().to_owned(),
                 sender_claimed_keys: session.signing_keys().to_owned(),
                 forwarding_curve25519_key_chain: session.forwarding_key_chain().to_vec(),
+                message_index,
+                first_known_index: session.first_known_index(),
             },
             verification_state,
         })
     }
 
+    async fn get_olm_encryption_info(
+        &self,
+        sender: &UserId,
+        decrypted: &OlmDecryptionInfo,
+    ) -> StoreResult<OlmEncryptionInfo> {
+        let device = self.store.get_device_from_curve_key(sender, &decrypted.sender_key).await?;
+
+        let verification_state = match &device {
+            Some(device)
+                if device.get_key(DeviceKeyAlgorithm::Ed25519) == Some(&decrypted.signing_key) =>
+            {
+                if (self.user_id() == device.user_id() && self.device_id() == device.device_id())
+                    || device.is_trusted()
+                {
+                    VerificationState::Trusted
+                } else {
+                    VerificationState::Untrusted
+                }
+            }
+            Some(_) => VerificationState::MismatchedSender,
+            None => VerificationState::UnknownDevice,
+        };
+
+        Ok(OlmEncryptionInfo {
+            sender: sender.clone(),
+            sender_device: device.map(|d| d.device_id().to_owned()),
+            sender_curve25519_key: decrypted.sender_key.clone(),
+            sender_claimed_ed25519_key: decrypted.signing_key.clone(),
+            verification_state,
+        })
+    }
+
     /// Decrypt an event from a room timeline.
     ///
     /// # Arguments
@@ -965,28 +1394,141 @@ impl OlmMachine {
         event: &SyncMessageEvent<EncryptedEventContent>,
         room_id: &RoomId,
     ) -> MegolmResult<SyncRoomEvent> {
+        let session = self.find_session_for_event(room_id, event, &mut BTreeMap::new()).await?;
+
+        // TODO check if this is from a verified device.
+        let (decrypted_event, message_index) = match session.decrypt(event).await {
+            Ok(d) => d,
+            Err(e) => return Err(self.handle_decryption_error(&session, room_id, e).await),
+        };
+
+        self.finish_room_event_decryption(&session, event, room_id, decrypted_event, message_index)
+            .await
+    }
+
+    /// Decrypt a batch of events from a room timeline, e.g. a page of the room
+    /// history.
+    ///
+    /// The events are decrypted concurrently, on a background thread pool, so
+    /// the decryption of large batches doesn't block the async executor. The
+    /// results keep the order of the given events. Events that aren't
+    /// encrypted are returned as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room where the events were sent to.
+    ///
+    /// * `events` - The events that should be decrypted.
+    pub async fn decrypt_room_events(
+        &self,
+        room_id: &RoomId,
+        events: &[Raw<AnySyncRoomEvent>],
+    ) -> Vec<MegolmResult<SyncRoomEvent>> {
+        let mut results: Vec<Option<MegolmResult<SyncRoomEvent>>> =
+            events.iter().map(|_| None).collect();
+        let mut sessions: BTreeMap<(String, String), Option<InboundGroupSession>> = BTreeMap::new();
+        let mut pending = Vec::new();
+
+        for (index, raw_event) in events.iter().enumerate() {
+            let event = match raw_event.deserialize() {
+                Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(e))) => e,
+                Ok(_) => {
+                    results[index] = Some(Ok(raw_event.clone().into()));
+                    continue;
+                }
+                Err(e) => {
+                    results[index] = Some(Err(e.into()));
+                    continue;
+                }
+            };
+
+            match self.find_session_for_event(room_id, &event, &mut sessions).await {
+                Ok(session) => pending.push((index, session, event)),
+                Err(e) => results[index] = Some(Err(e)),
+            }
+        }
+
+        let decrypted = parallel_map(pending, |(index, session, event)| async move {
+            let decrypted = session.decrypt(&event).await;
+            (index, session, event, decrypted)
+        })
+        .await;
+
+        for (index, session, event, decrypted) in decrypted {
+            let result = match decrypted {
+                Ok((decrypted_event, message_index)) => {
+                    self.finish_room_event_decryption(
+                        &session,
+                        &event,
+                        room_id,
+                        decrypted_event,
+                        message_index,
+                    )
+                    .await
+                }
+                Err(e) => Err(self.handle_decryption_error(&session, room_id, e).await),
+            };
+
+            results[index] = Some(result);
+        }
+
+        results.into_iter().map(|r| r.expect("Every event of the batch has a result")).collect()
+    }
+
+    /// Get the group session that decrypts the given event, using and filling
+    /// the given cache of sessions.
+    ///
+    /// A key request is created if the session is missing.
+    async fn find_session_for_event(
+        &self,
+        room_id: &RoomId,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+        sessions: &mut BTreeMap<(String, String), Option<InboundGroupSession>>,
+    ) -> MegolmResult<InboundGroupSession> {
         let content = match &event.content.scheme {
             EncryptedEventScheme::MegolmV1AesSha2(c) => c,
             _ => return Err(EventError::UnsupportedAlgorithm.into()),
         };
 
-        let session = self
-            .store
-            .get_inbound_group_session(room_id, &content.sender_key, &content.session_id)
-            .await?;
-        // TODO check if the Olm session is wedged and re-request the key.
-        let session = if let Some(s) = session {
-            s
+        let key = (content.sender_key.clone(), content.session_id.clone());
+
+        let session = if let Some(session) = sessions.get(&key) {
+            session.clone()
         } else {
-            self.key_request_machine
-                .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
+            let session = self
+                .store
+                .get_inbound_group_session(room_id, &content.sender_key, &content.session_id)
                 .await?;
-            return Err(MegolmError::MissingSession);
+
+            // TODO check if the Olm session is wedged and re-request the key.
+            if session.is_none() {
+                self.key_request_machine
+                    .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
+                    .await?;
+            }
+
+            sessions.insert(key, session.clone());
+            session
         };
 
-        // TODO check the message index.
-        // TODO check if this is from a verified device.
-        let (decrypted_event, _) = session.decrypt(event).await?;
+        session.ok_or(MegolmError::MissingSession)
+    }
+
+    /// Check a decrypted room event and collect its encryption info.
+    async fn finish_room_event_decryption(
+        &self,
+        session: &InboundGroupSession,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+        room_id: &RoomId,
+        decrypted_event: Raw<AnySyncRoomEvent>,
+        message_index: u32,
+    ) -> MegolmResult<SyncRoomEvent> {
+        let content = match &event.content.scheme {
+            EncryptedEventScheme::MegolmV1AesSha2(c) => c,
+            _ => return Err(EventError::UnsupportedAlgorithm.into()),
+        };
+
+        self.check_message_index(session, message_index, event).await?;
 
         trace!("Successfully decrypted a Megolm event {:?}", decrypted_event);
 
@@ -998,35 +1540,258 @@ impl OlmMachine {
             }
         }
 
-        let encryption_info =
-            self.get_encryption_info(&session, &event.sender, &content.device_id).await?;
+        let encryption_info = self
+            .get_encryption_info(session, message_index, &event.sender, &content.device_id)
+            .await?;
+
+        if encryption_info.is_missing_earlier_messages() {
+            debug!(
+                "Decrypted a Megolm event using a session that starts at the message index {}, \
+                 earlier messages of the session {} can't be decrypted",
+                session.first_known_index(),
+                session.session_id(),
+            );
+
+            self.request_full_session(session, room_id).await?;
+        }
 
         Ok(SyncRoomEvent { encryption_info: Some(encryption_info), event: decrypted_event })
     }
 
-    /// Update the tracked users.
-    ///
-    /// # Arguments
-    ///
-    /// * `users` - An iterator over user ids that should be marked for
-    /// tracking.
-    ///
-    /// This will mark users that weren't seen before for a key query and
-    /// tracking.
-    ///
-    /// If the user is already known to the Olm machine it will not be
-    /// considered for a key query.
-    pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
-        self.identity_manager.update_tracked_users(users).await
+    /// Request the given group session from our other devices, starting at
+    /// the message index 0.
+    async fn request_full_session(
+        &self,
+        session: &InboundGroupSession,
+        room_id: &RoomId,
+    ) -> MegolmResult<()> {
+        Ok(self
+            .key_request_machine
+            .create_outgoing_key_request(room_id, session.sender_key(), session.session_id())
+            .await?)
     }
 
-    /// Get a specific device of a user.
-    ///
-    /// # Arguments
+    /// Turn an error that the given group session returned while decrypting
+    /// an event into the error that should be returned.
     ///
-    /// * `user_id` - The unique id of the user that the device belongs to.
+    /// If the event was encrypted before the first message index the session
+    /// knows about, the full session is requested.
+    async fn handle_decryption_error(
+        &self,
+        session: &InboundGroupSession,
+        room_id: &RoomId,
+        error: MegolmError,
+    ) -> MegolmError {
+        match error {
+            MegolmError::OlmGroupSession(OlmGroupSessionError::UnknownMessageIndex) => {
+                if let Err(e) = self.request_full_session(session, room_id).await {
+                    return e;
+                }
+
+                MegolmError::UnknownMessageIndex {
+                    session_id: session.session_id().to_owned(),
+                    first_known_index: session.first_known_index(),
+                }
+            }
+            e => e,
+        }
+    }
+
+    /// Check that the message index of a decrypted Megolm message wasn't
+    /// already used by another event.
     ///
-    /// * `device_id` - The unique id of the device.
+    /// Every message index of a group session should be used only once, if we
+    /// see the same index with a different event id the event was most likely
+    /// replayed to us.
+    async fn check_message_index(
+        &self,
+        session: &InboundGroupSession,
+        message_index: u32,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+    ) -> MegolmResult<()> {
+        let known_event = self
+            .store
+            .get_event_for_message_index(
+                session.room_id(),
+                session.sender_key(),
+                session.session_id(),
+                message_index,
+            )
+            .await?;
+
+        match known_event {
+            Some(event_id) if event_id != event.event_id => {
+                warn!(
+                    "Received a replayed Megolm event {} in room {}, the message index {} of \
+                     the session {} was already used by the event {}",
+                    event.event_id,
+                    session.room_id(),
+                    message_index,
+                    session.session_id(),
+                    event_id,
+                );
+
+                Err(MegolmError::ReplayedEvent {
+                    session_id: session.session_id().to_owned(),
+                    message_index,
+                    original_event_id: event_id,
+                })
+            }
+            Some(_) => Ok(()),
+            None => {
+                let index = MegolmMessageIndex {
+                    room_id: session.room_id().to_owned(),
+                    sender_key: session.sender_key().to_owned(),
+                    session_id: session.session_id().to_owned(),
+                    message_index,
+                    event_id: event.event_id.clone(),
+                };
+
+                let changes = Changes { message_indices: vec![index], ..Default::default() };
+                self.store.save_changes(changes).await?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Update the tracked users.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - An iterator over user ids that should be marked for
+    /// tracking.
+    ///
+    /// This will mark users that weren't seen before for a key query and
+    /// tracking.
+    ///
+    /// If the user is already known to the Olm machine it will not be
+    /// considered for a key query.
+    pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
+        self.identity_manager.update_tracked_users(users).await
+    }
+
+    /// Are the devices of the given user up to date.
+    ///
+    /// Returns false if the user isn't tracked, or if a key query for the
+    /// user is still pending or in flight, in that case the known devices of
+    /// the user might be outdated.
+    pub fn is_user_up_to_date(&self, user_id: &UserId) -> bool {
+        self.identity_manager.is_user_up_to_date(user_id)
+    }
+
+    /// Take out the changes of the devices and identities of tracked users
+    /// that were discovered by key queries since the last call.
+    ///
+    /// Changes of identities that we verified should be shown to the user,
+    /// see [`DeviceListChange::is_security_alert()`].
+    pub async fn take_device_list_changes(&self) -> Vec<DeviceListChange> {
+        self.identity_manager.take_device_list_changes().await
+    }
+
+    /// Mark the given users as having changed their devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - An iterator over user ids that changed their devices, e.g.
+    /// the `changed` list of a `/keys/changes` response.
+    ///
+    /// Users that are tracked will be queued up for a key query, users that
+    /// aren't tracked are ignored.
+    pub async fn mark_users_as_changed(
+        &self,
+        users: impl IntoIterator<Item = &UserId>,
+    ) -> StoreResult<()> {
+        let changes = Changes {
+            tracked_users: self.identity_manager.changed_users(users),
+            ..Default::default()
+        };
+
+        self.store.save_changes(changes).await
+    }
+
+    /// Save all the pending changes of the machine and write them to disk.
+    ///
+    /// This should be called before the machine is dropped, e.g. when the
+    /// application is shutting down.
+    pub async fn flush(&self) -> StoreResult<()> {
+        self.store.flush().await
+    }
+
+    /// Enable or disable the periodic maintenance of the store, see
+    /// [`run_maintenance_if_due()`](#method.run_maintenance_if_due).
+    ///
+    /// The maintenance is disabled by default.
+    pub fn set_maintenance_settings(&self, settings: Option<MaintenanceSettings>) {
+        *self.maintenance_settings.write().unwrap() = settings;
+    }
+
+    /// Prune the data the store accumulated over time.
+    ///
+    /// The hashes of Olm messages that are older than the retention period
+    /// are removed, replays of these messages aren't detected anymore, the
+    /// same goes for the entries of the to-device journal. Olm sessions that
+    /// weren't used within their retention period are removed as well, the
+    /// most recently used session with every device is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The retention periods of the pruned data.
+    pub async fn run_maintenance(
+        &self,
+        settings: &MaintenanceSettings,
+    ) -> StoreResult<MaintenanceSummary> {
+        *self.last_maintenance.lock().await = Some(now());
+
+        let oldest_hash = retention_cutoff(settings.message_hash_retention);
+
+        let removed_message_hashes =
+            self.store.remove_message_hashes_older_than(oldest_hash).await?;
+        let removed_journal_entries =
+            self.store.remove_to_device_journal_entries_older_than(oldest_hash).await?;
+
+        let unused_since = retention_cutoff(settings.session_retention);
+        let removed_sessions = self.store.remove_unused_sessions(unused_since).await?;
+
+        let summary = MaintenanceSummary {
+            removed_message_hashes,
+            removed_journal_entries,
+            removed_sessions,
+        };
+        info!("Finished the maintenance of the crypto store: {:?}", summary);
+
+        Ok(summary)
+    }
+
+    /// Run the maintenance of the store if it's enabled and if it didn't run
+    /// within the configured interval, see
+    /// [`run_maintenance()`](#method.run_maintenance).
+    ///
+    /// This is meant to be called regularly, e.g. after every sync. Returns
+    /// `None` if the maintenance didn't run.
+    pub async fn run_maintenance_if_due(&self) -> StoreResult<Option<MaintenanceSummary>> {
+        let settings = match *self.maintenance_settings.read().unwrap() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        let due =
+            self.last_maintenance.lock().await.map_or(true, |t| now() - t >= settings.interval);
+
+        if due {
+            Ok(Some(self.run_maintenance(&settings).await?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get a specific device of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
     ///
     /// Returns a `Device` if one is found and the crypto store didn't throw an
     /// error.
@@ -1081,14 +1846,33 @@ impl OlmMachine {
         self.store.get_user_devices(user_id).await
     }
 
+    /// Get all the devices that were put into quarantine.
+    ///
+    /// A device is put into quarantine if its device keys, received in a
+    /// `/keys/query` response, fail the signature check. We won't encrypt
+    /// messages for those devices nor share room keys with them, the
+    /// [`reason()`](struct.RejectedDevice.html#method.reason) of the rejection
+    /// can be used to debug broken clients or to detect a potential attack.
+    pub async fn rejected_devices(&self) -> StoreResult<Vec<RejectedDevice>> {
+        self.store.get_rejected_devices().await
+    }
+
     /// Import the given room keys into our store.
     ///
+    /// This is a convenience wrapper around
+    /// [`import_room_keys()`](#method.import_room_keys) that can't be
+    /// cancelled, the progress listener is called after every imported chunk
+    /// of keys.
+    ///
     /// # Arguments
     ///
     /// * `exported_keys` - A list of previously exported keys that should be
     /// imported into our store. If we already have a better version of a key
     /// the key will *not* be imported.
     ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of processed keys and the total number of keys.
+    ///
     /// Returns a tuple of numbers that represent the number of sessions that
     /// were imported and the total number of sessions that were found in the
     /// key export.
@@ -1112,58 +1896,166 @@ impl OlmMachine {
         exported_keys: Vec<ExportedRoomKey>,
         progress_listener: impl Fn(usize, usize),
     ) -> StoreResult<(usize, usize)> {
-        struct ShallowSessions {
-            inner: BTreeMap<Arc<RoomId>, u32>,
-        }
+        let result = self
+            .import_room_keys(exported_keys, &KeyImportCancellation::new(), |processed, total| {
+                progress_listener(processed, total);
+                future::ready(())
+            })
+            .await?;
 
-        impl ShallowSessions {
-            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
-                self.inner
-                    .get(&session.room_id)
-                    .map(|existing| existing <= &session.first_known_index())
-                    .unwrap_or(false)
+        Ok((result.imported_count, result.total_count))
+    }
+
+    /// Import the given room keys into our store in chunks.
+    ///
+    /// After every chunk the keys are saved to the store, the progress
+    /// listener is awaited and control is yielded back to the executor, so
+    /// large imports don't block other tasks. The cancellation handle is
+    /// checked between chunks, keys of chunks that were already processed stay
+    /// in the store.
+    ///
+    /// Keys that can't be imported don't abort the import, they are counted in
+    /// the [`failures`](struct.RoomKeyImportResult.html#structfield.failures)
+    /// of the returned result.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - A list of previously exported keys that should be
+    /// imported into our store. If we already have a better version of a key
+    /// the key will *not* be imported.
+    ///
+    /// * `cancellation` - A handle that can be used to cancel the import.
+    ///
+    /// * `progress_listener` - An async closure that will be called with the
+    /// number of processed keys and the total number of keys after every
+    /// chunk.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::io::Cursor;
+    /// # use matrix_sdk_crypto::{OlmMachine, KeyImportCancellation, decrypt_key_export};
+    /// # use ruma::user_id;
+    /// # use futures::executor::block_on;
+    /// # let alice = user_id!("@alice:example.org");
+    /// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+    /// # block_on(async {
+    /// # let export = Cursor::new("".to_owned());
+    /// let exported_keys = decrypt_key_export(export, "1234").unwrap();
+    /// let cancellation = KeyImportCancellation::new();
+    ///
+    /// let result = machine
+    ///     .import_room_keys(exported_keys, &cancellation, |processed, total| async move {
+    ///         println!("Imported {} out of {} keys", processed, total);
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// println!("Skipped {} keys, {} keys failed", result.skipped_count, result.failed_count());
+    /// # });
+    /// ```
+    pub async fn import_room_keys<F, Fut>(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        cancellation: &KeyImportCancellation,
+        progress_listener: F,
+    ) -> StoreResult<RoomKeyImportResult>
+    where
+        F: Fn(usize, usize) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut existing_sessions: BTreeMap<(RoomId, String, String), u32> = self
+            .store
+            .get_inbound_group_sessions()
+            .await?
+            .into_iter()
+            .map(|s| {
+                let index = s.first_known_index();
+                ((s.room_id().clone(), s.sender_key().to_owned(), s.session_id().to_owned()), index)
+            })
+            .collect();
+
+        let mut result =
+            RoomKeyImportResult { total_count: exported_keys.len(), ..Default::default() };
+        let mut processed = 0;
+        let mut keys = exported_keys.into_iter();
+
+        loop {
+            if cancellation.is_cancelled() {
+                result.cancelled = true;
+                break;
             }
-        }
 
-        let mut sessions = Vec::new();
+            let chunk: Vec<ExportedRoomKey> = keys.by_ref().take(KEY_IMPORT_CHUNK_SIZE).collect();
 
-        let existing_sessions = ShallowSessions {
-            inner: self
-                .store
-                .get_inbound_group_sessions()
-                .await?
-                .into_iter()
-                .map(|s| {
-                    let index = s.first_known_index();
-                    (s.room_id, index)
+            if chunk.is_empty() {
+                break;
+            }
+
+            processed += chunk.len();
+            let mut sessions = Vec::new();
+
+            // Importing a session is CPU heavy, do it in parallel off the
+            // executor.
+            let imported = parallel_map(chunk, |key| async move {
+                if key.algorithm != EventEncryptionAlgorithm::MegolmV1AesSha2 {
+                    return Err(RoomKeyImportFailure::UnsupportedAlgorithm);
+                }
+
+                InboundGroupSession::from_export(key).map_err(|e| {
+                    warn!("Failed to import a room key {:?}", e);
+                    RoomKeyImportFailure::InvalidSessionKey
                 })
-                .collect(),
-        };
+            })
+            .await;
 
-        let total_sessions = exported_keys.len();
+            for session in imported {
+                let session = match session {
+                    Ok(s) => s,
+                    Err(failure) => {
+                        result.add_failure(failure);
+                        continue;
+                    }
+                };
 
-        for (i, key) in exported_keys.into_iter().enumerate() {
-            let session = InboundGroupSession::from_export(key)?;
+                let session_key = (
+                    session.room_id().clone(),
+                    session.sender_key().to_owned(),
+                    session.session_id().to_owned(),
+                );
 
-            // Only import the session if we didn't have this session or if it's
-            // a better version of the same session, that is the first known
-            // index is lower.
-            if !existing_sessions.has_better_session(&session) {
-                sessions.push(session)
+                // Only import the session if we didn't have this session or if
+                // it's a better version of the same session, that is the first
+                // known index is lower.
+                if existing_sessions
+                    .get(&session_key)
+                    .map(|existing| existing <= &session.first_known_index())
+                    .unwrap_or(false)
+                {
+                    result.skipped_count += 1;
+                } else {
+                    existing_sessions.insert(session_key, session.first_known_index());
+                    sessions.push(session);
+                }
             }
 
-            progress_listener(i, total_sessions)
-        }
-
-        let num_sessions = sessions.len();
+            result.imported_count += sessions.len();
 
-        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+            let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+            self.store.save_changes(changes).await?;
 
-        self.store.save_changes(changes).await?;
+            progress_listener(processed, result.total_count).await;
+            yield_now().await;
+        }
 
-        info!("Successfully imported {} inbound group sessions", num_sessions);
+        info!(
+            imported = result.imported_count,
+            skipped = result.skipped_count,
+            failed = result.failed_count(),
+            cancelled = result.cancelled,
+            "Finished importing inbound group sessions",
+        );
 
-        Ok((num_sessions, total_sessions))
+        Ok(result)
     }
 
     /// Export the keys that match the given predicate.
@@ -1198,9 +2090,7 @@ impl OlmMachine {
         &self,
         mut predicate: impl FnMut(&InboundGroupSession) -> bool,
     ) -> StoreResult<Vec<ExportedRoomKey>> {
-        let mut exported = Vec::new();
-
-        let mut sessions: Vec<InboundGroupSession> = self
+        let sessions: Vec<InboundGroupSession> = self
             .store
             .get_inbound_group_sessions()
             .await?
@@ -1208,12 +2098,127 @@ impl OlmMachine {
             .filter(|s| predicate(s))
             .collect();
 
-        for session in sessions.drain(..) {
-            let export = session.export().await;
-            exported.push(export);
+        Ok(parallel_map(sessions, |s| async move { s.export().await }).await)
+    }
+
+    /// Export the room keys that match the given filter.
+    ///
+    /// This works like [`export_keys()`](#method.export_keys), the filter
+    /// allows clients to offer partial exports, e.g. of the keys of a couple
+    /// of rooms that weren't backed up yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The filter that selects which keys should be exported.
+    pub async fn export_keys_with_filter(
+        &self,
+        filter: &RoomKeyExportFilter,
+    ) -> StoreResult<Vec<ExportedRoomKey>> {
+        self.export_keys(|s| filter.matches(s)).await
+    }
+
+    /// Mark the given room keys as backed up.
+    ///
+    /// This should be called once the keys, e.g. the ones returned by
+    /// [`export_keys()`](#method.export_keys), were uploaded to a backup.
+    /// Keys that aren't known anymore are ignored.
+    pub async fn mark_room_keys_as_backed_up(&self, keys: &[ExportedRoomKey]) -> StoreResult<()> {
+        let mut sessions = Vec::new();
+
+        for key in keys {
+            if let Some(session) = self
+                .store
+                .get_inbound_group_session(&key.room_id, &key.sender_key, &key.session_id)
+                .await?
+            {
+                if !session.backed_up() {
+                    session.mark_as_backed_up();
+                    sessions.push(session);
+                }
+            }
+        }
+
+        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+        self.store.save_changes(changes).await
+    }
+
+    /// Get the number of room keys we have and how many of them are backed
+    /// up.
+    pub async fn room_key_counts(&self) -> StoreResult<RoomKeyCounts> {
+        self.store.inbound_group_session_counts().await
+    }
+
+    /// Get the private cross signing keys this device has.
+    pub async fn cross_signing_status(&self) -> CrossSigningStatus {
+        let identity = self.user_identity.lock().await;
+
+        CrossSigningStatus {
+            has_master_key: identity.has_master_key().await,
+            has_self_signing_key: identity.can_sign_devices().await,
+            has_user_signing_key: identity.user_signing_key.lock().await.is_some(),
+        }
+    }
+
+    /// Check if the room key of the given room can be shared with all the
+    /// devices of its members.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that should be checked.
+    ///
+    /// * `users` - The members of the room.
+    pub async fn encryption_health(
+        &self,
+        room_id: &RoomId,
+        users: impl IntoIterator<Item = &UserId>,
+    ) -> StoreResult<EncryptionHealth> {
+        let has_usable_outbound_session = self
+            .group_session_manager
+            .session_cache()
+            .get_or_load(room_id)
+            .await?
+            .map_or(false, |s| s.shared() && !s.expired() && !s.invalidated());
+
+        let mut devices_without_sessions = Vec::new();
+        let mut users_with_unqueried_devices = Vec::new();
+
+        for user_id in users {
+            if !self.is_user_up_to_date(user_id) {
+                users_with_unqueried_devices.push(user_id.clone());
+            }
+
+            for (device_id, device) in self.store.get_readonly_devices(user_id).await? {
+                let is_own_device = user_id == self.user_id() && &*device_id == self.device_id();
+
+                if is_own_device || device.deleted() || device.is_blacklisted() {
+                    continue;
+                }
+
+                let sender_key = if let Some(k) = device.get_key(DeviceKeyAlgorithm::Curve25519) {
+                    k
+                } else {
+                    continue;
+                };
+
+                let has_session = match self.store.get_sessions(sender_key).await? {
+                    Some(sessions) => !sessions.lock().await.is_empty(),
+                    None => false,
+                };
+
+                if !has_session {
+                    devices_without_sessions.push((user_id.clone(), device_id));
+                }
+            }
         }
 
-        Ok(exported)
+        devices_without_sessions.sort();
+
+        Ok(EncryptionHealth {
+            room_id: room_id.clone(),
+            has_usable_outbound_session,
+            devices_without_sessions,
+            users_with_unqueried_devices,
+        })
     }
 }
 
@@ -1224,14 +2229,22 @@ pub(crate) mod test {
     use std::{
         collections::BTreeMap,
         convert::{TryFrom, TryInto},
-        sync::Arc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
     };
 
     use http::Response;
+    use matrix_sdk_common::deserialized_responses::{AlgorithmInfo, VerificationState};
     use matrix_sdk_test::test_json;
     use ruma::{
         api::{
-            client::r0::keys::{claim_keys, get_keys, upload_keys, OneTimeKey},
+            client::r0::{
+                keys::{claim_keys, get_keys, upload_keys, OneTimeKey},
+                sync::sync_events::{DeviceLists, ToDevice},
+            },
             IncomingResponse,
         },
         events::{
@@ -1239,11 +2252,13 @@ pub(crate) mod test {
                 encrypted::EncryptedEventContent,
                 message::{MessageEventContent, MessageType},
             },
+            room_key_request::RequestedKeyInfo,
             AnyMessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
             EventType, SyncMessageEvent, ToDeviceEvent, Unsigned,
         },
         identifiers::{
-            event_id, room_id, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
+            event_id, room_id, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId,
+            EventEncryptionAlgorithm, EventId, UserId,
         },
         serde::Raw,
         uint, MilliSecondsSinceUnixEpoch,
@@ -1251,10 +2266,14 @@ pub(crate) mod test {
     use serde_json::json;
 
     use crate::{
+        environment::{deterministic, now, timestamp},
         machine::OlmMachine,
-        olm::Utility,
+        olm::{InboundGroupSession, OlmMessageHash, PicklingMode, ReadOnlyAccount, Utility},
+        store::{Changes, SyncCheckpoint},
         verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+        EncryptionSettings, KeyImportCancellation, MaintenanceSettings, MaintenanceSummary,
+        MegolmError, OlmError, OutgoingRequest, OutgoingRequests, ReadOnlyDevice, RoomKeyCounts,
+        RoomKeyExportFilter, RoomKeyImportFailure, SignatureError, ToDeviceRequest,
     };
 
     /// These keys need to be periodically uploaded to the server.
@@ -1413,45 +2432,262 @@ pub(crate) mod test {
     }
 
     #[tokio::test]
-    async fn test_device_key_signing() {
-        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+    async fn maintenance() {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
 
-        let mut device_keys = machine.account.device_keys().await;
-        let identity_keys = machine.account.identity_keys();
-        let ed25519_key = identity_keys.ed25519();
+        let clock = deterministic(3);
+        let (alice, bob) = get_machine_pair_with_session().await;
+        let sender_key = bob.identity_keys().curve25519().to_owned();
 
-        let utility = Utility::new();
-        let ret = utility.verify_json(
-            &machine.user_id,
-            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
-            ed25519_key,
-            &mut json!(&mut device_keys),
+        let sessions = alice.store.get_sessions(&sender_key).await.unwrap().unwrap();
+        let session = sessions.lock().await[0].clone();
+
+        let changes = Changes {
+            message_hashes: vec![OlmMessageHash {
+                sender_key: sender_key.clone(),
+                hash: "hash".to_owned(),
+            }],
+            ..Default::default()
+        };
+        alice.store.save_changes(changes).await.unwrap();
+
+        clock.advance(91 * DAY);
+
+        let mut fresh_session = session.clone();
+        fresh_session.session_id = "fresh_session".into();
+        fresh_session.last_used_at = Arc::new(timestamp());
+        alice.store.save_sessions(&[fresh_session]).await.unwrap();
+
+        let settings = MaintenanceSettings::default();
+        assert!(alice.run_maintenance_if_due().await.unwrap().is_none());
+
+        alice.set_maintenance_settings(Some(settings));
+        assert_eq!(
+            alice.run_maintenance_if_due().await.unwrap(),
+            Some(MaintenanceSummary {
+                removed_message_hashes: 1,
+                removed_journal_entries: 0,
+                removed_sessions: 1
+            })
+        );
+        assert!(alice.run_maintenance_if_due().await.unwrap().is_none());
+
+        let sessions = sessions.lock().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), "fresh_session");
+        drop(sessions);
+
+        // The last session with a device is kept no matter how old it is.
+        clock.advance(365 * DAY);
+        assert_eq!(
+            alice.run_maintenance_if_due().await.unwrap(),
+            Some(MaintenanceSummary::default())
         );
-        assert!(ret.is_ok());
     }
 
     #[tokio::test]
-    async fn tests_session_invalidation() {
+    async fn to_device_journal() {
         let machine = OlmMachine::new(&user_id(), &alice_device_id());
-        let room_id = room_id!("!test:example.org");
 
-        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
-        assert!(machine.group_session_manager.get_outbound_group_session(&room_id).is_some());
+        let event: Raw<AnyToDeviceEvent> = serde_json::from_value(json!({
+            "type": "m.dummy",
+            "sender": alice_id(),
+            "content": {},
+        }))
+        .unwrap();
 
-        machine.invalidate_group_session(&room_id).await.unwrap();
+        let mut to_device = ToDevice::new();
+        to_device.events = vec![event.clone(), event];
+
+        let checkpoint = SyncCheckpoint { since: None, next_batch: "s1".to_owned() };
+        let response = machine
+            .receive_sync_changes(
+                to_device.clone(),
+                &DeviceLists::new(),
+                &BTreeMap::new(),
+                checkpoint,
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.events.len(), 1);
 
-        assert!(machine
-            .group_session_manager
-            .get_outbound_group_session(&room_id)
-            .unwrap()
-            .invalidated());
+        // The homeserver sends the same sync response again.
+        let checkpoint =
+            SyncCheckpoint { since: Some("s1".to_owned()), next_batch: "s2".to_owned() };
+        let response = machine
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), checkpoint)
+            .await
+            .unwrap();
+        assert!(response.events.is_empty());
+
+        let journal = machine.to_device_journal().await.unwrap();
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal[0].event_type, "m.dummy");
+        assert_eq!(journal[0].sender, alice_id());
+        assert_eq!(journal[0].sync_token, "s1");
     }
 
     #[tokio::test]
-    async fn test_invalid_signature() {
-        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+    async fn encryption_diagnostics() {
+        let room_id = room_id!("!test:example.org");
+        let (alice, bob, _) = get_machine_pair().await;
+
+        assert!(!alice.cross_signing_status().await.is_complete());
+        alice.bootstrap_cross_signing(false).await.unwrap();
+        assert!(alice.cross_signing_status().await.is_complete());
+
+        let health = bob.encryption_health(&room_id, vec![alice.user_id()]).await.unwrap();
+        assert!(!health.is_healthy());
+        assert!(!health.has_usable_outbound_session);
+        assert_eq!(
+            health.devices_without_sessions,
+            vec![(alice.user_id().clone(), alice.device_id().into())]
+        );
+        assert_eq!(health.users_with_unqueried_devices, vec![alice.user_id().clone()]);
 
-        let mut device_keys = machine.account.device_keys().await;
+        let (alice, bob) = get_machine_pair_with_session().await;
+        let health = alice.encryption_health(&room_id, vec![bob.user_id()]).await.unwrap();
+        assert!(health.devices_without_sessions.is_empty());
+
+        alice.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        assert_eq!(
+            alice.room_key_counts().await.unwrap(),
+            RoomKeyCounts { total: 1, backed_up: 0 }
+        );
+
+        let keys = alice.export_keys(|_| true).await.unwrap();
+        alice.mark_room_keys_as_backed_up(&keys).await.unwrap();
+        assert_eq!(
+            alice.room_key_counts().await.unwrap(),
+            RoomKeyCounts { total: 1, backed_up: 1 }
+        );
+    }
+
+    #[tokio::test]
+    async fn export_keys_with_filter() {
+        let first_room = room_id!("!first:example.org");
+        let second_room = room_id!("!second:example.org");
+        let (alice, _) = get_machine_pair_with_session().await;
+        let clock = deterministic(5);
+
+        alice.create_outbound_group_session_with_defaults(&first_room).await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        let created_after = timestamp();
+        clock.advance(Duration::from_secs(60));
+        alice.create_outbound_group_session_with_defaults(&second_room).await.unwrap();
+
+        let export = |filter: RoomKeyExportFilter| {
+            let alice = alice.clone();
+            async move {
+                let keys = alice.export_keys_with_filter(&filter).await.unwrap();
+                keys.into_iter().map(|k| k.room_id).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(export(RoomKeyExportFilter::new()).await.len(), 2);
+        assert_eq!(
+            export(RoomKeyExportFilter::new().rooms(vec![first_room.clone()])).await,
+            vec![first_room.clone()]
+        );
+        assert_eq!(
+            export(RoomKeyExportFilter::new().created_after(created_after)).await,
+            vec![second_room.clone()]
+        );
+        assert!(export(RoomKeyExportFilter::new().sender_keys(vec!["unknown".to_owned()]))
+            .await
+            .is_empty());
+
+        let sender_key = alice.identity_keys().curve25519().to_owned();
+        assert_eq!(export(RoomKeyExportFilter::new().sender_keys(vec![sender_key])).await.len(), 2);
+
+        let keys = alice.export_keys(|s| s.room_id() == &first_room).await.unwrap();
+        alice.mark_room_keys_as_backed_up(&keys).await.unwrap();
+        assert_eq!(export(RoomKeyExportFilter::new().exclude_backed_up()).await, vec![second_room]);
+    }
+
+    #[tokio::test]
+    async fn one_time_key_target() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let mut response = keys_upload_response();
+
+        assert_eq!(machine.one_time_key_target().await, 50);
+
+        machine.set_one_time_key_target(Some(20));
+        assert_eq!(machine.one_time_key_target().await, 20);
+
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(15));
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        assert!(machine.should_upload_keys().await);
+
+        let request = machine.keys_for_upload().await.unwrap();
+        assert_eq!(request.one_time_keys.unwrap().len(), 5);
+
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(20));
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        assert!(!machine.should_upload_keys().await);
+
+        // Targets above what the account can safely hold are capped.
+        machine.set_one_time_key_target(Some(1000));
+        assert_eq!(machine.one_time_key_target().await, 50);
+
+        machine.set_one_time_key_target(None);
+        assert_eq!(machine.one_time_key_target().await, 50);
+    }
+
+    #[tokio::test]
+    async fn test_device_key_signing() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+
+        let mut device_keys = machine.account.device_keys().await;
+        let identity_keys = machine.account.identity_keys();
+        let ed25519_key = identity_keys.ed25519();
+
+        let utility = Utility::new();
+        let ret = utility.verify_json(
+            &machine.user_id,
+            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
+            ed25519_key,
+            &mut json!(&mut device_keys),
+        );
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tests_session_invalidation() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let room_id = room_id!("!test:example.org");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        assert!(machine.group_session_manager.get_outbound_group_session(&room_id).is_some());
+
+        machine.invalidate_group_session(&room_id).await.unwrap();
+
+        assert!(machine
+            .group_session_manager
+            .get_outbound_group_session(&room_id)
+            .unwrap()
+            .invalidated());
+    }
+
+    #[tokio::test]
+    async fn tests_room_unloading() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let room_id = room_id!("!test:example.org");
+
+        assert!(!machine.unload_room(&room_id));
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        assert!(machine.group_session_manager.get_outbound_group_session(&room_id).is_some());
+
+        assert!(machine.unload_room(&room_id));
+        assert!(machine.group_session_manager.get_outbound_group_session(&room_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_signature() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+
+        let mut device_keys = machine.account.device_keys().await;
 
         let utility = Utility::new();
         let ret = utility.verify_json(
@@ -1513,6 +2749,16 @@ pub(crate) mod test {
         );
         assert!(ret.is_ok());
 
+        let fallback_key = request.fallback_keys.as_ref().unwrap().values().next().unwrap();
+        assert!(fallback_key.fallback);
+        let ret = utility.verify_json(
+            &machine.user_id,
+            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
+            ed25519_key,
+            &mut json!(fallback_key),
+        );
+        assert!(ret.is_ok());
+
         let mut response = keys_upload_response();
         response.one_time_key_counts.insert(
             DeviceKeyAlgorithm::SignedCurve25519,
@@ -1525,6 +2771,116 @@ pub(crate) mod test {
         assert!(ret.is_none());
     }
 
+    #[tokio::test]
+    async fn fallback_key_rotation() {
+        let clock = deterministic(7);
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(50));
+
+        let request = machine.keys_for_upload().await.unwrap();
+        let fallback_keys = request.fallback_keys.unwrap();
+        assert_eq!(fallback_keys.len(), 1);
+
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        assert!(machine.keys_for_upload().await.is_none());
+
+        machine.set_fallback_key_lifetime(Some(Duration::from_secs(60 * 60)));
+        clock.advance(Duration::from_secs(59 * 60));
+        assert!(machine.keys_for_upload().await.is_none());
+
+        // Only the expired fallback key gets uploaded again, it's replaced
+        // with a new one.
+        clock.advance(Duration::from_secs(60));
+        let request = machine.keys_for_upload().await.unwrap();
+        assert!(request.device_keys.is_none());
+        assert!(request.one_time_keys.is_none());
+
+        let new_fallback_keys = request.fallback_keys.unwrap();
+        assert_eq!(new_fallback_keys.len(), 1);
+        assert_ne!(
+            new_fallback_keys.values().next().unwrap().key,
+            fallback_keys.values().next().unwrap().key
+        );
+
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        assert!(machine.keys_for_upload().await.is_none());
+
+        // The state of the fallback key survives a restart.
+        let pickle = machine.account.inner.pickle(PicklingMode::Unencrypted).await;
+        let account = ReadOnlyAccount::from_pickle(pickle, PicklingMode::Unencrypted).unwrap();
+        assert!(account.keys_for_upload().await.is_none());
+
+        clock.advance(Duration::from_secs(7 * 24 * 60 * 60));
+        assert!(account.keys_for_upload().await.unwrap().fallback_keys.is_some());
+    }
+
+    #[tokio::test]
+    async fn unused_fallback_key_types() {
+        let clock = deterministic(8);
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(50));
+
+        let fallback_keys = machine.keys_for_upload().await.unwrap().fallback_keys.unwrap();
+        machine.receive_keys_upload_response(&response).await.unwrap();
+
+        // A fallback key the server reports as unused isn't replaced once
+        // it's older than its lifetime.
+        machine.receive_unused_fallback_keys(Some(&[DeviceKeyAlgorithm::SignedCurve25519]));
+        clock.advance(Duration::from_secs(8 * 24 * 60 * 60));
+        assert!(machine.keys_for_upload().await.is_none());
+
+        // A used one is replaced right away.
+        machine.receive_unused_fallback_keys(Some(&[]));
+        let new_fallback_keys = machine.keys_for_upload().await.unwrap().fallback_keys.unwrap();
+        assert_ne!(
+            new_fallback_keys.values().next().unwrap().key,
+            fallback_keys.values().next().unwrap().key
+        );
+
+        // Until the new fallback key is uploaded, the reports are about the
+        // previous one.
+        machine.receive_unused_fallback_keys(Some(&[]));
+        let request = machine.keys_for_upload().await.unwrap();
+        assert_eq!(
+            request.fallback_keys.unwrap().values().next().unwrap().key,
+            new_fallback_keys.values().next().unwrap().key
+        );
+
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        machine.receive_unused_fallback_keys(Some(&[DeviceKeyAlgorithm::SignedCurve25519]));
+        assert!(machine.keys_for_upload().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn olm_session_from_fallback_key() {
+        let bob = OlmMachine::new(&user_id(), &alice_device_id());
+        let fallback_keys = bob.keys_for_upload().await.unwrap().fallback_keys.unwrap();
+        bob.receive_keys_upload_response(&keys_upload_response()).await.unwrap();
+
+        // Bob ran out of one-time keys, the fallback key can be claimed by
+        // multiple devices.
+        for device_id in &["ALICEDEVICE", "OTHERDEVICE"] {
+            let alice = OlmMachine::new(&alice_id(), (*device_id).into());
+            alice.store.save_devices(&[ReadOnlyDevice::from_machine(&bob).await]).await.unwrap();
+            bob.store.save_devices(&[ReadOnlyDevice::from_machine(&alice).await]).await.unwrap();
+
+            let one_time_keys = json!({
+                bob.user_id().as_str(): { bob.device_id().as_str(): &fallback_keys }
+            });
+            let response =
+                claim_keys::Response::new(serde_json::from_value(one_time_keys).unwrap());
+            alice.receive_keys_claim_response(&response).await.unwrap();
+
+            let bob_device = alice.get_device(&bob.user_id, &bob.device_id).await.unwrap().unwrap();
+            let (_, content) = bob_device.encrypt(EventType::Dummy, json!({})).await.unwrap();
+            let event = ToDeviceEvent { sender: alice.user_id().clone(), content };
+
+            bob.decrypt_to_device_event(&event).await.unwrap();
+        }
+    }
+
     #[tokio::test]
     async fn test_keys_query() {
         let (machine, _) = get_prepared_machine().await;
@@ -1542,6 +2898,37 @@ pub(crate) mod test {
         assert_eq!(device.device_id(), alice_device_id);
     }
 
+    #[tokio::test]
+    async fn keys_queries_are_not_repeated_while_in_flight() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let alice_id = user_id!("@alice:example.org");
+
+        let keys_queries = |requests: Vec<OutgoingRequest>| {
+            requests
+                .into_iter()
+                .filter(|r| matches!(r.request(), OutgoingRequests::KeysQuery(_)))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(!machine.is_user_up_to_date(&alice_id));
+        machine.update_tracked_users(&[alice_id.clone()]).await;
+
+        let requests = keys_queries(machine.outgoing_requests().await.unwrap());
+        assert_eq!(requests.len(), 1);
+        assert!(!machine.is_user_up_to_date(&alice_id));
+
+        // The query is in flight, the users aren't queried a second time.
+        assert!(keys_queries(machine.outgoing_requests().await.unwrap()).is_empty());
+
+        machine
+            .mark_request_as_sent(requests[0].request_id(), &keys_query_response())
+            .await
+            .unwrap();
+
+        assert!(machine.is_user_up_to_date(&alice_id));
+        assert!(keys_queries(machine.outgoing_requests().await.unwrap()).is_empty());
+    }
+
     #[tokio::test]
     async fn test_missing_sessions_calculation() {
         let (machine, _) = get_machine_after_query().await;
@@ -1605,6 +2992,52 @@ pub(crate) mod test {
         }
     }
 
+    #[tokio::test]
+    async fn encrypted_custom_to_device_event() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+
+        let bob_device = alice.get_device(&bob.user_id, &bob.device_id).await.unwrap().unwrap();
+        let (_, content) = bob_device
+            .encrypt(EventType::from("org.example.ping"), json!({ "ping": 1 }))
+            .await
+            .unwrap();
+
+        let event: Raw<AnyToDeviceEvent> = serde_json::from_value(json!({
+            "type": "m.room.encrypted",
+            "sender": alice.user_id(),
+            "content": content,
+        }))
+        .unwrap();
+
+        let mut to_device = ToDevice::new();
+        to_device.events = vec![event];
+
+        let checkpoint = SyncCheckpoint { since: None, next_batch: "s1".to_owned() };
+        let response = bob
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), checkpoint)
+            .await
+            .unwrap();
+        assert_eq!(response.events.len(), 1);
+
+        // The decrypted event is passed through together with the info about
+        // its sender.
+        let event = &response.events[0];
+        match event.event.deserialize().unwrap() {
+            AnyToDeviceEvent::Custom(e) => {
+                assert_eq!(e.content.event_type, "org.example.ping");
+                assert_eq!(e.content.data.get("ping"), Some(&json!(1)));
+                assert_eq!(&e.sender, alice.user_id());
+            }
+            e => panic!("Wrong event type found {:?}", e),
+        }
+
+        let encryption_info = event.encryption_info.as_ref().unwrap();
+        assert_eq!(&encryption_info.sender, alice.user_id());
+        assert_eq!(encryption_info.sender_device.as_deref(), Some(alice.device_id()));
+        assert_eq!(encryption_info.sender_curve25519_key, alice.identity_keys().curve25519());
+        assert_eq!(encryption_info.verification_state, VerificationState::Untrusted);
+    }
+
     #[tokio::test]
     async fn test_room_key_sharing() {
         let (alice, bob) = get_machine_pair_with_session().await;
@@ -1656,6 +3089,94 @@ pub(crate) mod test {
         assert!(session.unwrap().is_some());
     }
 
+    #[tokio::test]
+    async fn test_room_key_import() {
+        let alice = OlmMachine::new(&alice_id(), &alice_device_id());
+        let bob = OlmMachine::new(&user_id(), &alice_device_id());
+        let room_id = room_id!("!test:example.org");
+
+        let (_, session) =
+            alice.account.create_group_session_pair_with_defaults(&room_id).await.unwrap();
+        alice.store.save_inbound_group_sessions(&[session]).await.unwrap();
+
+        let mut exported_keys = alice.export_keys(|_| true).await.unwrap();
+
+        let mut unsupported = exported_keys[0].clone();
+        unsupported.algorithm = EventEncryptionAlgorithm::OlmV1Curve25519AesSha2;
+        let mut invalid = exported_keys[0].clone();
+        invalid.session_key.0 = "invalid".to_owned();
+
+        exported_keys.extend(vec![exported_keys[0].clone(), unsupported, invalid]);
+
+        let progress = Arc::new(AtomicUsize::new(0));
+        let listener_progress = progress.clone();
+
+        let result = bob
+            .import_room_keys(
+                exported_keys.clone(),
+                &KeyImportCancellation::new(),
+                |processed, _| {
+                    listener_progress.store(processed, Ordering::SeqCst);
+                    async {}
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(progress.load(Ordering::SeqCst), 4);
+        assert_eq!(result.total_count, 4);
+        assert_eq!(result.imported_count, 1);
+        assert_eq!(result.skipped_count, 1);
+        assert_eq!(result.failed_count(), 2);
+        assert_eq!(result.failures.get(&RoomKeyImportFailure::UnsupportedAlgorithm), Some(&1));
+        assert!(!result.cancelled);
+
+        let cancellation = KeyImportCancellation::new();
+        cancellation.cancel();
+
+        let result =
+            alice.import_room_keys(exported_keys, &cancellation, |_, _| async {}).await.unwrap();
+
+        assert!(result.cancelled);
+        assert_eq!(result.imported_count + result.skipped_count + result.failed_count(), 0);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "testing")]
+    async fn test_encryption_preview() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
+            "It is a secret to everybody",
+        ));
+
+        assert!(alice.preview_encryption(&room_id, content.clone()).await.is_err());
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let preview = alice.preview_encryption(&room_id, content.clone()).await.unwrap();
+        let second_preview = alice.preview_encryption(&room_id, content.clone()).await.unwrap();
+
+        let session = alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+
+        assert_eq!(preview.session_id, session.session_id());
+        assert_eq!(preview.message_index, 0);
+        assert_eq!(preview.json, second_preview.json);
+
+        let encrypted = alice.encrypt(&room_id, content).await.unwrap();
+
+        assert_eq!(preview.json, serde_json::to_value(&encrypted).unwrap());
+        assert_eq!(session.message_index().await, 1);
+    }
+
     #[tokio::test]
     async fn test_megolm_encryption() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -1696,14 +3217,18 @@ pub(crate) mod test {
             unsigned: Unsigned::default(),
         };
 
-        let decrypted_event =
-            bob.decrypt_room_event(&event, &room_id).await.unwrap().event.deserialize().unwrap();
+        let decrypted = bob.decrypt_room_event(&event, &room_id).await.unwrap();
+
+        let encryption_info = decrypted.encryption_info.unwrap();
+        assert_eq!(&*encryption_info.sender_device, alice.device_id());
+        assert_eq!(encryption_info.sender_curve25519_key(), alice.identity_keys().curve25519());
+        assert_eq!(encryption_info.verification_state, VerificationState::Untrusted);
 
         if let AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
             sender,
             content,
             ..
-        })) = decrypted_event
+        })) = decrypted.event.deserialize().unwrap()
         {
             assert_eq!(&sender, alice.user_id());
             if let MessageType::Text(c) = &content.msgtype {
@@ -1714,6 +3239,259 @@ pub(crate) mod test {
         } else {
             panic!("Decrypted room event has the wrong type")
         }
+
+        // A device that claims to be the device of Alice but has different
+        // keys than the session that encrypted the event.
+        let forged = OlmMachine::new(alice.user_id(), alice.device_id());
+        bob.store.save_devices(&[ReadOnlyDevice::from_machine(&forged).await]).await.unwrap();
+
+        let decrypted = bob.decrypt_room_event(&event, &room_id).await.unwrap();
+        let encryption_info = decrypted.encryption_info.unwrap();
+        assert_eq!(encryption_info.verification_state, VerificationState::MismatchedSender);
+        assert!(!encryption_info.is_verified());
+    }
+
+    #[tokio::test]
+    async fn decrypt_room_events() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session;
+        bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
+
+        let mut events: Vec<Raw<AnySyncRoomEvent>> = Vec::new();
+
+        for i in 0..5 {
+            let content = MessageEventContent::text_plain(format!("Message {}", i));
+            let event = SyncMessageEvent {
+                event_id: EventId::try_from(format!("${}:example.org", i)).unwrap(),
+                origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+                sender: alice.user_id().clone(),
+                content: alice
+                    .encrypt(&room_id, AnyMessageEventContent::RoomMessage(content))
+                    .await
+                    .unwrap(),
+                unsigned: Unsigned::default(),
+            };
+
+            events.push(serde_json::from_value(serde_json::to_value(&event).unwrap()).unwrap());
+        }
+
+        events.insert(
+            2,
+            serde_json::from_value(json!({
+                "type": "m.room.message",
+                "event_id": "$plain:example.org",
+                "sender": alice.user_id(),
+                "origin_server_ts": 0,
+                "content": { "msgtype": "m.text", "body": "Not a secret" },
+            }))
+            .unwrap(),
+        );
+
+        let results = bob.decrypt_room_events(&room_id, &events).await;
+        assert_eq!(results.len(), 6);
+
+        let bodies: Vec<String> = results
+            .into_iter()
+            .map(|r| match r.unwrap().event.deserialize().unwrap() {
+                AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(e)) => {
+                    match e.content.msgtype {
+                        MessageType::Text(c) => c.body,
+                        _ => panic!("Decrypted event has a mismatched content"),
+                    }
+                }
+                _ => panic!("Decrypted room event has the wrong type"),
+            })
+            .collect();
+
+        assert_eq!(
+            bodies,
+            vec!["Message 0", "Message 1", "Not a secret", "Message 2", "Message 3", "Message 4"]
+        );
+
+        let other_room = room_id!("!other:example.org");
+        let results = alice.decrypt_room_events(&other_room, &events[..1]).await;
+        assert!(matches!(results[0], Err(MegolmError::MissingSession)));
+    }
+
+    #[tokio::test]
+    async fn sign_and_verify_json() {
+        let (alice, bob, _) = get_machine_pair().await;
+
+        let value = json!({
+            "widget_id": "abcdef",
+            "unsigned": { "age": 5 },
+        });
+
+        let signed = alice.sign_json(value).await.unwrap();
+        assert_eq!(signed["unsigned"]["age"], 5);
+        assert!(signed["signatures"][alice.user_id().as_str()]
+            .get(format!("ed25519:{}", alice.device_id()))
+            .is_some());
+
+        bob.verify_signature(alice.user_id(), alice.device_id(), &signed).await.unwrap();
+
+        let mut tampered = signed.clone();
+        tampered["widget_id"] = json!("ghijkl");
+        assert!(matches!(
+            bob.verify_signature(alice.user_id(), alice.device_id(), &tampered).await,
+            Err(OlmError::Signature(SignatureError::VerificationError))
+        ));
+
+        let unknown_device: Box<DeviceId> = "UNKNOWN".into();
+        assert!(matches!(
+            bob.verify_signature(alice.user_id(), &unknown_device, &signed).await,
+            Err(OlmError::Signature(SignatureError::MissingSigningKey))
+        ));
+
+        assert!(matches!(
+            alice.sign_json(json!({ "ratio": 0.5 })).await,
+            Err(SignatureError::NotCanonicalJson)
+        ));
+    }
+
+    #[tokio::test]
+    async fn megolm_ratchet_gap() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session.unwrap();
+
+        let encrypt = |event_id| {
+            let alice = alice.clone();
+            let room_id = room_id.clone();
+
+            async move {
+                let content = MessageEventContent::text_plain("It is a secret to everybody");
+                SyncMessageEvent {
+                    event_id,
+                    origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+                    sender: alice.user_id().clone(),
+                    content: alice
+                        .encrypt(&room_id, AnyMessageEventContent::RoomMessage(content))
+                        .await
+                        .unwrap(),
+                    unsigned: Unsigned::default(),
+                }
+            }
+        };
+
+        let first = encrypt(event_id!("$first:example.org")).await;
+        let second = encrypt(event_id!("$second:example.org")).await;
+
+        // Bob only got the session starting at the second message.
+        let later_session =
+            InboundGroupSession::from_export(group_session.export_at_index(1).await).unwrap();
+        bob.store.save_inbound_group_sessions(&[later_session]).await.unwrap();
+
+        assert!(matches!(
+            bob.decrypt_room_event(&first, &room_id).await,
+            Err(MegolmError::UnknownMessageIndex { first_known_index: 1, .. })
+        ));
+
+        // The full session is requested from our other devices.
+        let info = RequestedKeyInfo::new(
+            EventEncryptionAlgorithm::MegolmV1AesSha2,
+            room_id.clone(),
+            group_session.sender_key().to_owned(),
+            group_session.session_id().to_owned(),
+        );
+        assert!(bob.store.get_key_request_by_info(&info).await.unwrap().is_some());
+
+        let info =
+            bob.decrypt_room_event(&second, &room_id).await.unwrap().encryption_info.unwrap();
+        assert!(info.is_missing_earlier_messages());
+        assert!(matches!(
+            info.algorithm_info,
+            AlgorithmInfo::MegolmV1AesSha2 { message_index: 1, first_known_index: 1, .. }
+        ));
+
+        bob.store.save_inbound_group_sessions(&[group_session]).await.unwrap();
+
+        let info = bob.decrypt_room_event(&first, &room_id).await.unwrap().encryption_info.unwrap();
+        assert!(!info.is_missing_earlier_messages());
+    }
+
+    #[tokio::test]
+    async fn test_megolm_replay_detection() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session;
+        bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
+
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+
+        let encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        bob.decrypt_room_event(&event, &room_id).await.unwrap();
+        // Decrypting the same event again is fine.
+        bob.decrypt_room_event(&event, &room_id).await.unwrap();
+
+        let replayed_event =
+            SyncMessageEvent { event_id: event_id!("$yyyyy:example.org"), ..event };
+
+        match bob.decrypt_room_event(&replayed_event, &room_id).await {
+            Err(MegolmError::ReplayedEvent { message_index: 0, original_event_id, .. }) => {
+                assert_eq!(original_event_id, event_id!("$xxxxx:example.org"))
+            }
+            _ => panic!("A replayed event wasn't detected"),
+        }
     }
 
     #[tokio::test]
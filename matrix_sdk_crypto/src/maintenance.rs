@@ -0,0 +1,56 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pruning of the data the crypto store accumulates over time.
+
+use std::time::Duration;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Settings for the maintenance of the crypto store, see
+/// [`OlmMachine::run_maintenance()`](crate::OlmMachine::run_maintenance).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaintenanceSettings {
+    /// How often the maintenance runs, defaults to once a day.
+    pub interval: Duration,
+    /// How long the hashes of received Olm messages and the entries of the
+    /// to-device journal are kept to detect replayed messages, defaults to 30
+    /// days.
+    ///
+    /// The journal is pruned with this retention whenever new entries are
+    /// added as well.
+    pub message_hash_retention: Duration,
+    /// How long Olm sessions are kept after they were last used, defaults to
+    /// 90 days.
+    ///
+    /// The most recently used session with every device is always kept.
+    pub session_retention: Duration,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self { interval: DAY, message_hash_retention: 30 * DAY, session_retention: 90 * DAY }
+    }
+}
+
+/// What a run of the maintenance of the crypto store removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceSummary {
+    /// The number of removed Olm message hashes.
+    pub removed_message_hashes: usize,
+    /// The number of removed entries of the to-device journal.
+    pub removed_journal_entries: usize,
+    /// The number of removed Olm sessions.
+    pub removed_sessions: usize,
+}
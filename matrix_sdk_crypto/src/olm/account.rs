@@ -18,12 +18,13 @@ use std::{
     fmt,
     ops::Deref,
     sync::{
-        atomic::{AtomicBool, AtomicI64, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
-use matrix_sdk_common::{instant::Instant, locks::Mutex};
+use matrix_sdk_common::locks::Mutex;
 use olm_rs::{
     account::{IdentityKeys, OlmAccount, OneTimeKeys},
     errors::{OlmAccountError, OlmSessionError},
@@ -46,7 +47,7 @@ use ruma::{
         UserId,
     },
     serde::{CanonicalJsonValue, Raw},
-    UInt,
+    MilliSecondsSinceUnixEpoch, UInt,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -54,13 +55,14 @@ use sha2::{Digest, Sha256};
 use tracing::{debug, trace, warn};
 
 use super::{
-    EncryptionSettings, InboundGroupSession, OutboundGroupSession, PrivateCrossSigningIdentity,
-    Session,
+    fallback_key, EncryptionSettings, InboundGroupSession, OutboundGroupSession,
+    PrivateCrossSigningIdentity, Session,
 };
 use crate::{
+    environment::{now, timestamp},
     error::{EventError, OlmResult, SessionCreationError},
     identities::ReadOnlyDevice,
-    requests::UploadSigningKeysRequest,
+    requests::{KeysUploadRequest, SignedFallbackKey, UploadSigningKeysRequest},
     store::{Changes, Store},
     utilities::encode,
     OlmError,
@@ -440,6 +442,21 @@ pub struct ReadOnlyAccount {
     /// needs to set this for us, depending on the count we will suggest the
     /// client to upload new keys.
     uploaded_signed_key_count: Arc<AtomicI64>,
+    /// The number of one-time keys we try to keep uploaded to the server, 0
+    /// if the default of half the keys the account can hold should be used.
+    one_time_key_target: Arc<AtomicU64>,
+    /// When the current fallback key was generated, in milliseconds since the
+    /// unix epoch, 0 if the account doesn't have a fallback key.
+    fallback_key_created_at: Arc<AtomicU64>,
+    /// Was the current fallback key uploaded to the server.
+    fallback_key_published: Arc<AtomicBool>,
+    /// Did the server report whether the published fallback key is unused.
+    fallback_key_usage_reported: Arc<AtomicBool>,
+    /// Did the server report that the published fallback key was used.
+    fallback_key_used: Arc<AtomicBool>,
+    /// How long a fallback key is used before it's replaced, in milliseconds,
+    /// 0 if the default should be used.
+    fallback_key_lifetime: Arc<AtomicU64>,
 }
 
 /// A typed representation of a base64 encoded string containing the account
@@ -476,6 +493,13 @@ pub struct PickledAccount {
     pub shared: bool,
     /// The number of uploaded one-time keys we have on the server.
     pub uploaded_signed_key_count: i64,
+    /// When the current fallback key was generated, `None` if the account
+    /// doesn't have a fallback key.
+    #[serde(default)]
+    pub fallback_key_created_at: Option<MilliSecondsSinceUnixEpoch>,
+    /// Was the current fallback key uploaded to the server.
+    #[serde(default)]
+    pub fallback_key_published: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -489,6 +513,9 @@ impl fmt::Debug for ReadOnlyAccount {
 }
 
 impl ReadOnlyAccount {
+    /// How long a fallback key is used by default before it's replaced.
+    const FALLBACK_KEY_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
     const ALGORITHMS: &'static [&'static EventEncryptionAlgorithm] = &[
         &EventEncryptionAlgorithm::OlmV1Curve25519AesSha2,
         &EventEncryptionAlgorithm::MegolmV1AesSha2,
@@ -497,8 +524,9 @@ impl ReadOnlyAccount {
     /// Create a fresh new account, this will generate the identity key-pair.
     #[allow(clippy::ptr_arg)]
     pub fn new(user_id: &UserId, device_id: &DeviceId) -> Self {
-        let account = OlmAccount::new();
+        let mut account = OlmAccount::new();
         let identity_keys = account.parsed_identity_keys();
+        fallback_key::generate_fallback_key(&mut account);
 
         Self {
             user_id: Arc::new(user_id.to_owned()),
@@ -507,6 +535,12 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::new(false)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(0)),
+            one_time_key_target: Arc::new(AtomicU64::new(0)),
+            fallback_key_created_at: Arc::new(AtomicU64::new(timestamp().0.into())),
+            fallback_key_published: Arc::new(AtomicBool::new(false)),
+            fallback_key_usage_reported: Arc::new(AtomicBool::new(false)),
+            fallback_key_used: Arc::new(AtomicBool::new(false)),
+            fallback_key_lifetime: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -570,66 +604,175 @@ impl ReadOnlyAccount {
         self.inner.lock().await.max_number_of_one_time_keys()
     }
 
+    /// Set the number of one-time keys that should be kept uploaded to the
+    /// server, `None` restores the default.
+    ///
+    /// The target is capped at half the number of keys the account can hold,
+    /// the account needs to keep the private parts of claimed keys around
+    /// until the messages that use them arrive.
+    pub(crate) fn set_one_time_key_target(&self, target: Option<u64>) {
+        let target = target.map_or(0, |t| t.max(1));
+        self.one_time_key_target.store(target, Ordering::Relaxed);
+    }
+
+    /// Get the number of one-time keys that should be kept uploaded to the
+    /// server.
+    pub(crate) async fn one_time_key_target(&self) -> u64 {
+        let max_on_server = self.max_one_time_keys().await as u64 / 2;
+
+        match self.one_time_key_target.load(Ordering::Relaxed) {
+            0 => max_on_server,
+            target => target.min(max_on_server),
+        }
+    }
+
+    /// Set how long a fallback key is used before it's replaced with a new
+    /// one, `None` restores the default of a week.
+    pub(crate) fn set_fallback_key_lifetime(&self, lifetime: Option<Duration>) {
+        let lifetime = lifetime.map_or(0, |l| (l.as_millis() as u64).max(1));
+        self.fallback_key_lifetime.store(lifetime, Ordering::Relaxed);
+    }
+
+    /// Get how long a fallback key is used before it's replaced.
+    pub(crate) fn fallback_key_lifetime(&self) -> Duration {
+        match self.fallback_key_lifetime.load(Ordering::Relaxed) {
+            0 => Self::FALLBACK_KEY_LIFETIME,
+            lifetime => Duration::from_millis(lifetime),
+        }
+    }
+
+    /// Remember which of our fallback keys the server reports as unused.
+    ///
+    /// # Arguments
+    ///
+    /// * `unused_fallback_keys` - The key algorithms of the unused fallback
+    /// keys of a sync response, `None` if the server didn't report them.
+    pub(crate) fn update_unused_fallback_keys(
+        &self,
+        unused_fallback_keys: Option<&[DeviceKeyAlgorithm]>,
+    ) {
+        // Until the current fallback key is uploaded the server can only
+        // report on the previous one.
+        if !self.fallback_key_published.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let used = unused_fallback_keys
+            .map_or(false, |unused| !unused.contains(&DeviceKeyAlgorithm::SignedCurve25519));
+
+        self.fallback_key_usage_reported.store(unused_fallback_keys.is_some(), Ordering::Relaxed);
+        self.fallback_key_used.store(used, Ordering::Relaxed);
+    }
+
+    /// Forget what the server reported about the usage of the fallback key,
+    /// the report was about a fallback key that isn't the current one
+    /// anymore.
+    fn reset_fallback_key_usage(&self) {
+        self.fallback_key_usage_reported.store(false, Ordering::Relaxed);
+        self.fallback_key_used.store(false, Ordering::Relaxed);
+    }
+
+    /// Does the fallback key need to be replaced with a new one.
+    ///
+    /// The fallback key is replaced once the server reports that it was used.
+    /// If the server doesn't report which fallback keys are unused, it's
+    /// replaced once it's older than the fallback key lifetime instead. An
+    /// account without a fallback key always needs a new one.
+    fn fallback_key_expired(&self) -> bool {
+        match self.fallback_key_created_at.load(Ordering::Relaxed) {
+            0 => true,
+            _ if self.fallback_key_used.load(Ordering::Relaxed) => true,
+            _ if self.fallback_key_usage_reported.load(Ordering::Relaxed) => false,
+            created_at => {
+                let now: u64 = timestamp().0.into();
+                now.saturating_sub(created_at) >= self.fallback_key_lifetime().as_millis() as u64
+            }
+        }
+    }
+
+    /// Does a fallback key need to be uploaded, either because the current
+    /// one wasn't uploaded yet or because it needs to be replaced.
+    fn should_upload_fallback_key(&self) -> bool {
+        !self.fallback_key_published.load(Ordering::Relaxed) || self.fallback_key_expired()
+    }
+
+    /// Generate a new fallback key, replacing the current one.
+    ///
+    /// The replaced fallback key can still be used to create Olm sessions
+    /// until the next fallback key is generated, other devices might have
+    /// claimed it before the new one was uploaded.
+    async fn generate_fallback_key(&self) {
+        fallback_key::generate_fallback_key(&mut *self.inner.lock().await);
+
+        self.fallback_key_created_at.store(timestamp().0.into(), Ordering::Relaxed);
+        self.fallback_key_published.store(false, Ordering::Relaxed);
+        self.reset_fallback_key_usage();
+    }
+
     /// Get a tuple of device and one-time keys that need to be uploaded.
     ///
     /// Returns an empty error if no keys need to be uploaded.
     pub(crate) async fn generate_one_time_keys(&self) -> Result<u64, ()> {
         let count = self.uploaded_key_count() as u64;
         let max_keys = self.max_one_time_keys().await;
-        let max_on_server = (max_keys as u64) / 2;
+        let target = self.one_time_key_target().await;
 
-        if count >= (max_on_server) {
+        if count >= target {
             return Err(());
         }
 
-        let key_count = max_on_server - count;
+        let key_count = target - count;
         let key_count: usize = key_count.try_into().unwrap_or(max_keys);
 
         self.generate_one_time_keys_helper(key_count).await;
         Ok(key_count as u64)
     }
 
-    /// Should account or one-time keys be uploaded to the server.
+    /// Should account, one-time or fallback keys be uploaded to the server.
     pub(crate) async fn should_upload_keys(&self) -> bool {
-        if !self.shared() {
+        if !self.shared() || self.should_upload_fallback_key() {
             return true;
         }
 
         let count = self.uploaded_key_count() as u64;
 
-        // If we have a known key count, check that we have more than
-        // max_one_time_Keys() / 2, otherwise tell the client to upload more.
-        let max_keys = self.max_one_time_keys().await as u64;
-        // If there are more keys already uploaded than max_key / 2
-        // bail out returning false, this also avoids overflow.
-        if count > (max_keys / 2) {
-            return false;
-        }
-
-        let key_count = (max_keys / 2) - count;
-        key_count > 0
+        // If we have a known key count, check that we have at least as many
+        // keys as our target on the server, otherwise tell the client to
+        // upload more.
+        count < self.one_time_key_target().await
     }
 
-    /// Get a tuple of device and one-time keys that need to be uploaded.
+    /// Get a request that uploads the device, one-time and fallback keys that
+    /// need to be uploaded.
+    ///
+    /// An expired fallback key is replaced with a new one first.
     ///
     /// Returns None if no keys need to be uploaded.
-    pub(crate) async fn keys_for_upload(
-        &self,
-    ) -> Option<(Option<DeviceKeys>, Option<BTreeMap<DeviceKeyId, OneTimeKey>>)> {
+    pub(crate) async fn keys_for_upload(&self) -> Option<KeysUploadRequest> {
         if !self.should_upload_keys().await {
             return None;
         }
 
+        if self.fallback_key_expired() {
+            self.generate_fallback_key().await;
+        }
+
         let device_keys = if !self.shared() { Some(self.device_keys().await) } else { None };
 
         let one_time_keys = self.signed_one_time_keys().await.ok();
+        let fallback_keys = self.signed_fallback_key().await;
 
-        Some((device_keys, one_time_keys))
+        Some(KeysUploadRequest { device_keys, one_time_keys, fallback_keys })
     }
 
-    /// Mark the current set of one-time keys as being published.
+    /// Mark the current set of one-time keys and the fallback key as being
+    /// published.
     pub(crate) async fn mark_keys_as_published(&self) {
         self.inner.lock().await.mark_keys_as_published();
+        self.fallback_key_published.store(true, Ordering::Relaxed);
+        // Reports of sync responses that were received before the upload may
+        // still be about the previous fallback key.
+        self.reset_fallback_key_usage();
     }
 
     /// Sign the given string using the accounts signing key.
@@ -655,6 +798,11 @@ impl ReadOnlyAccount {
             pickle,
             shared: self.shared(),
             uploaded_signed_key_count: self.uploaded_key_count(),
+            fallback_key_created_at: match self.fallback_key_created_at.load(Ordering::Relaxed) {
+                0 => None,
+                created_at => UInt::new(created_at).map(MilliSecondsSinceUnixEpoch),
+            },
+            fallback_key_published: self.fallback_key_published.load(Ordering::Relaxed),
         }
     }
 
@@ -681,6 +829,14 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::from(pickle.shared)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(pickle.uploaded_signed_key_count)),
+            one_time_key_target: Arc::new(AtomicU64::new(0)),
+            fallback_key_created_at: Arc::new(AtomicU64::new(
+                pickle.fallback_key_created_at.map_or(0, |t| t.0.into()),
+            )),
+            fallback_key_published: Arc::new(AtomicBool::new(pickle.fallback_key_published)),
+            fallback_key_usage_reported: Arc::new(AtomicBool::new(false)),
+            fallback_key_used: Arc::new(AtomicBool::new(false)),
+            fallback_key_lifetime: Arc::new(AtomicU64::new(0)),
         })
     }
 
@@ -752,6 +908,27 @@ impl ReadOnlyAccount {
         self.sign(&canonical_json.to_string()).await
     }
 
+    /// Sign the given key JSON, returns the signatures in the form they are
+    /// uploaded in.
+    async fn key_signatures(
+        &self,
+        key_json: Value,
+    ) -> BTreeMap<UserId, BTreeMap<DeviceKeyId, String>> {
+        let signature = self.sign_json(key_json).await;
+
+        let mut signature_map = BTreeMap::new();
+
+        signature_map.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, &self.device_id),
+            signature,
+        );
+
+        let mut signatures = BTreeMap::new();
+        signatures.insert((*self.user_id).clone(), signature_map);
+
+        signatures
+    }
+
     pub(crate) async fn signed_one_time_keys_helper(
         &self,
     ) -> Result<BTreeMap<DeviceKeyId, OneTimeKey>, ()> {
@@ -763,18 +940,7 @@ impl ReadOnlyAccount {
                 "key": key,
             });
 
-            let signature = self.sign_json(key_json).await;
-
-            let mut signature_map = BTreeMap::new();
-
-            signature_map.insert(
-                DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, &self.device_id),
-                signature,
-            );
-
-            let mut signatures = BTreeMap::new();
-            signatures.insert((*self.user_id).clone(), signature_map);
-
+            let signatures = self.key_signatures(key_json).await;
             let signed_key = SignedKey::new(key.to_owned(), signatures);
 
             one_time_key_map.insert(
@@ -799,6 +965,30 @@ impl ReadOnlyAccount {
         self.signed_one_time_keys_helper().await
     }
 
+    /// Sign the fallback key so it can be uploaded.
+    ///
+    /// Returns `None` if the fallback key was published already.
+    pub(crate) async fn signed_fallback_key(
+        &self,
+    ) -> Option<BTreeMap<DeviceKeyId, SignedFallbackKey>> {
+        let (key_id, key) = fallback_key::unpublished_fallback_key(&*self.inner.lock().await)?;
+
+        let key_json = json!({
+            "key": key,
+            "fallback": true,
+        });
+
+        let signatures = self.key_signatures(key_json).await;
+
+        let mut fallback_keys = BTreeMap::new();
+        fallback_keys.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::SignedCurve25519, key_id.as_str().into()),
+            SignedFallbackKey { key, fallback: true, signatures },
+        );
+
+        Some(fallback_keys)
+    }
+
     /// Create a new session with another account given a one-time key.
     ///
     /// Returns the newly created session or a `OlmSessionError` if creating a
@@ -820,7 +1010,7 @@ impl ReadOnlyAccount {
             .await
             .create_outbound_session(their_identity_key, &their_one_time_key.key)?;
 
-        let now = Instant::now();
+        let now = now();
         let session_id = session.session_id();
 
         Ok(Session {
@@ -832,6 +1022,7 @@ impl ReadOnlyAccount {
             sender_key: their_identity_key.into(),
             creation_time: Arc::new(now),
             last_use_time: Arc::new(now),
+            last_used_at: Arc::new(timestamp()),
         })
     }
 
@@ -920,7 +1111,7 @@ impl ReadOnlyAccount {
             "Session was successfully created but the account doesn't hold a matching one-time key",
         );
 
-        let now = Instant::now();
+        let now = now();
         let session_id = session.session_id();
 
         Ok(Session {
@@ -932,6 +1123,7 @@ impl ReadOnlyAccount {
             sender_key: their_identity_key.into(),
             creation_time: Arc::new(now),
             last_use_time: Arc::new(now),
+            last_used_at: Arc::new(timestamp()),
         })
     }
 
@@ -0,0 +1,182 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fallback keys as described in [MSC2732].
+//!
+//! libolm supports fallback keys but olm-rs doesn't wrap that part of the
+//! libolm API yet. The account is thus pickled, unpickled into an account that
+//! is accessed through the libolm API directly and, if it was modified, pickled
+//! and unpickled back into an olm-rs account.
+//!
+//! olm-rs doesn't zeroize the pickles it unpickles, the account is handed back
+//! to it encrypted with a random key that only lives for the hand-off.
+//!
+//! [MSC2732]: https://github.com/matrix-org/matrix-doc/pull/2732
+
+use std::ffi::CStr;
+
+use olm_rs::{
+    account::{OlmAccount, OneTimeKeys},
+    PicklingMode,
+};
+use zeroize::Zeroizing;
+
+use crate::environment::fill_random;
+
+/// The pickles libolm reads never leave memory and are zeroized, so they
+/// aren't encrypted.
+const NO_PICKLE_KEY: &[u8] = &[];
+
+/// The size of the key the account is encrypted with when it's handed back to
+/// olm-rs.
+const HAND_OFF_KEY_SIZE: usize = 32;
+
+/// An Olm account that is accessed through the libolm API directly.
+struct RawAccount {
+    /// The memory libolm keeps the account in, it needs to outlive the
+    /// pointer.
+    _buffer: Vec<u8>,
+    account: *mut olm_sys::OlmAccount,
+}
+
+impl RawAccount {
+    /// Unpickle an unencrypted pickle of an account.
+    fn unpickle(pickle: &str) -> Self {
+        let mut buffer = vec![0; unsafe { olm_sys::olm_account_size() }];
+        let account = unsafe { olm_sys::olm_account(buffer.as_mut_ptr() as *mut _) };
+        let raw = Self { _buffer: buffer, account };
+
+        // libolm decodes the pickle in place.
+        let mut pickle = Zeroizing::new(pickle.as_bytes().to_vec());
+
+        raw.check(unsafe {
+            olm_sys::olm_unpickle_account(
+                raw.account,
+                NO_PICKLE_KEY.as_ptr() as *const _,
+                0,
+                pickle.as_mut_ptr() as *mut _,
+                pickle.len(),
+            )
+        });
+
+        raw
+    }
+
+    /// Pickle the account, encrypting it with the given key.
+    fn pickle(&self, key: &[u8]) -> String {
+        let mut pickle = vec![0; unsafe { olm_sys::olm_pickle_account_length(self.account) }];
+
+        let length = self.check(unsafe {
+            olm_sys::olm_pickle_account(
+                self.account,
+                key.as_ptr() as *const _,
+                key.len(),
+                pickle.as_mut_ptr() as *mut _,
+                pickle.len(),
+            )
+        });
+        pickle.truncate(length);
+
+        String::from_utf8(pickle).expect("libolm created a pickle that isn't valid UTF-8")
+    }
+
+    /// Generate a new fallback key, libolm keeps the previous one around
+    /// until the next one is generated.
+    fn generate_fallback_key(&self) {
+        let length =
+            unsafe { olm_sys::olm_account_generate_fallback_key_random_length(self.account) };
+        let mut random = Zeroizing::new(vec![0; length]);
+        fill_random(&mut random);
+
+        self.check(unsafe {
+            olm_sys::olm_account_generate_fallback_key(
+                self.account,
+                random.as_mut_ptr() as *mut _,
+                random.len(),
+            )
+        });
+    }
+
+    /// Get the current fallback key if it wasn't marked as published yet.
+    fn unpublished_fallback_key(&self) -> OneTimeKeys {
+        let mut key =
+            vec![0; unsafe { olm_sys::olm_account_unpublished_fallback_key_length(self.account) }];
+
+        let length = self.check(unsafe {
+            olm_sys::olm_account_unpublished_fallback_key(
+                self.account,
+                key.as_mut_ptr() as *mut _,
+                key.len(),
+            )
+        });
+        key.truncate(length);
+
+        serde_json::from_slice(&key).expect("libolm returned an invalid fallback key")
+    }
+
+    /// Check the return value of a libolm function.
+    ///
+    /// # Panics
+    ///
+    /// The buffers are sized as libolm requests and the pickles are created by
+    /// libolm itself, so an error can only be caused by a bug and panics.
+    fn check(&self, result: usize) -> usize {
+        if result == unsafe { olm_sys::olm_error() } {
+            let error = unsafe { CStr::from_ptr(olm_sys::olm_account_last_error(self.account)) };
+            panic!("libolm failed to handle the fallback key: {}", error.to_string_lossy());
+        }
+
+        result
+    }
+}
+
+impl Drop for RawAccount {
+    fn drop(&mut self) {
+        unsafe { olm_sys::olm_clear_account(self.account) };
+    }
+}
+
+fn raw_account(account: &OlmAccount) -> RawAccount {
+    RawAccount::unpickle(&Zeroizing::new(account.pickle(PicklingMode::Unencrypted)))
+}
+
+/// Generate a new fallback key for the given account.
+///
+/// The previous fallback key stays usable until the next one is generated,
+/// messages that were encrypted for it while the new one wasn't published yet
+/// can still be decrypted.
+pub(super) fn generate_fallback_key(account: &mut OlmAccount) {
+    let raw = raw_account(account);
+    raw.generate_fallback_key();
+
+    // olm-rs moves the key into a buffer that is zeroized once the account is
+    // unpickled.
+    let mut key = vec![0; HAND_OFF_KEY_SIZE];
+    fill_random(&mut key);
+    let pickle = raw.pickle(&key);
+
+    *account = OlmAccount::unpickle(pickle, PicklingMode::Encrypted { key })
+        .expect("Can't unpickle an account libolm just pickled");
+}
+
+/// Get the key id and the public part of the fallback key of the given
+/// account, if the account has one that wasn't marked as published yet.
+pub(super) fn unpublished_fallback_key(account: &OlmAccount) -> Option<(String, String)> {
+    raw_account(account)
+        .unpublished_fallback_key()
+        .curve25519()
+        .iter()
+        .next()
+        .map(|(key_id, key)| (key_id.to_owned(), key.to_owned()))
+}
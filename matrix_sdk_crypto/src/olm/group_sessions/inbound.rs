@@ -12,7 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, convert::TryFrom, fmt, mem, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use matrix_sdk_common::locks::Mutex;
 pub use olm_rs::{
@@ -32,19 +40,19 @@ use ruma::{
         },
         AnySyncRoomEvent, SyncMessageEvent,
     },
-    identifiers::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId},
+    identifiers::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, EventId, RoomId},
     serde::Raw,
+    MilliSecondsSinceUnixEpoch,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use zeroize::Zeroizing;
 
 use super::{ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey};
-use crate::error::{EventError, MegolmResult};
-
-// TODO add creation times to the inbound group sessions so we can export
-// sessions that were created between some time period, this should only be set
-// for non-imported sessions.
+use crate::{
+    environment::timestamp,
+    error::{EventError, MegolmResult},
+};
 
 /// Inbound group session.
 ///
@@ -61,6 +69,9 @@ pub struct InboundGroupSession {
     pub(crate) room_id: Arc<RoomId>,
     forwarding_chains: Arc<Vec<String>>,
     imported: Arc<bool>,
+    backed_up: Arc<AtomicBool>,
+    created_at: Option<MilliSecondsSinceUnixEpoch>,
+    pub(crate) shared_history: bool,
 }
 
 impl InboundGroupSession {
@@ -94,6 +105,11 @@ impl InboundGroupSession {
         let mut keys: BTreeMap<DeviceKeyAlgorithm, String> = BTreeMap::new();
         keys.insert(DeviceKeyAlgorithm::Ed25519, signing_key.to_owned());
 
+        let shared_history = matches!(
+            history_visibility,
+            Some(HistoryVisibility::Shared) | Some(HistoryVisibility::WorldReadable)
+        );
+
         Ok(InboundGroupSession {
             inner: Arc::new(Mutex::new(session)),
             session_id: session_id.into(),
@@ -104,6 +120,9 @@ impl InboundGroupSession {
             room_id: room_id.clone().into(),
             forwarding_chains: Vec::new().into(),
             imported: false.into(),
+            backed_up: AtomicBool::new(false).into(),
+            created_at: Some(timestamp()),
+            shared_history,
         })
     }
 
@@ -130,9 +149,13 @@ impl InboundGroupSession {
     ///
     /// * `content` - A forwarded room key content that contains the session key
     /// to create the `InboundGroupSession`.
+    ///
+    /// * `shared_history` - Was the session marked as one that may be shared
+    /// with new members of the room.
     pub(crate) fn from_forwarded_key(
         sender_key: &str,
         content: &mut ForwardedRoomKeyToDeviceEventContent,
+        shared_history: bool,
     ) -> Result<Self, OlmGroupSessionError> {
         let key = Zeroizing::from(mem::take(&mut content.session_key));
 
@@ -155,6 +178,9 @@ impl InboundGroupSession {
             room_id: content.room_id.clone().into(),
             forwarding_chains: forwarding_chains.into(),
             imported: true.into(),
+            backed_up: AtomicBool::new(false).into(),
+            created_at: None,
+            shared_history,
         })
     }
 
@@ -174,7 +200,10 @@ impl InboundGroupSession {
             room_id: (&*self.room_id).clone(),
             forwarding_chains: self.forwarding_key_chain().to_vec(),
             imported: *self.imported,
+            backed_up: self.backed_up(),
+            created_at: self.created_at,
             history_visibility: self.history_visibility.as_ref().clone(),
+            shared_history: self.shared_history,
         }
     }
 
@@ -196,6 +225,44 @@ impl InboundGroupSession {
         &self.signing_keys
     }
 
+    /// Was the session created while the history visibility of the room was
+    /// `shared` or `world_readable`.
+    ///
+    /// Such sessions may be shared with users that get invited to the room
+    /// later on, so they can read the messages that were sent before they
+    /// joined.
+    pub fn shared_history(&self) -> bool {
+        self.shared_history
+    }
+
+    /// Has the session been marked as backed up, see
+    /// [`mark_room_keys_as_backed_up()`].
+    ///
+    /// [`mark_room_keys_as_backed_up()`]: crate::OlmMachine::mark_room_keys_as_backed_up
+    pub fn backed_up(&self) -> bool {
+        self.backed_up.load(Ordering::SeqCst)
+    }
+
+    /// Was the session imported, either from a key export or as a forwarded
+    /// room key, instead of being sent to us by its creator.
+    pub fn imported(&self) -> bool {
+        *self.imported
+    }
+
+    /// The time this device created or received the session.
+    ///
+    /// Returns `None` for [imported](#method.imported) sessions, since we
+    /// don't know when they were created, and for sessions that were stored
+    /// before the time was recorded.
+    pub fn created_at(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.created_at
+    }
+
+    /// Mark the session as backed up.
+    pub(crate) fn mark_as_backed_up(&self) {
+        self.backed_up.store(true, Ordering::SeqCst)
+    }
+
     /// Get the list of ed25519 keys that this session was forwarded through.
     ///
     /// Each ed25519 key represents a single device. If device A forwards the
@@ -221,6 +288,7 @@ impl InboundGroupSession {
             forwarding_curve25519_key_chain: self.forwarding_key_chain().to_vec(),
             sender_claimed_keys: (&*self.signing_keys).clone(),
             session_key,
+            shared_history: self.shared_history,
         }
     }
 
@@ -253,6 +321,9 @@ impl InboundGroupSession {
             room_id: pickle.room_id.into(),
             forwarding_chains: pickle.forwarding_chains.into(),
             imported: pickle.imported.into(),
+            backed_up: AtomicBool::new(pickle.backed_up).into(),
+            created_at: pickle.created_at,
+            shared_history: pickle.shared_history,
         })
     }
 
@@ -369,6 +440,17 @@ pub struct PickledInboundGroupSession {
     pub imported: bool,
     /// History visibility of the room when the session was created.
     pub history_visibility: Option<HistoryVisibility>,
+    /// Flag remembering if the session may be shared with users that join the
+    /// room later on.
+    #[serde(default)]
+    pub shared_history: bool,
+    /// Flag remembering if the session has been backed up.
+    #[serde(default)]
+    pub backed_up: bool,
+    /// The time the session was created or received, unknown for imported
+    /// sessions and for sessions that were pickled before it was recorded.
+    #[serde(default)]
+    pub created_at: Option<MilliSecondsSinceUnixEpoch>,
 }
 
 /// The typed representation of a base64 encoded string of the GroupSession
@@ -389,6 +471,24 @@ impl InboundGroupSessionPickle {
     }
 }
 
+/// The message index of a successfully decrypted Megolm message.
+///
+/// Can be used to check if a message has been replayed to us, a message index
+/// of a group session should only ever be used by a single event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MegolmMessageIndex {
+    /// The id of the room that the group session is used in.
+    pub room_id: RoomId,
+    /// The curve25519 key of the device that created the group session.
+    pub sender_key: String,
+    /// The unique id of the group session.
+    pub session_id: String,
+    /// The message index that was used to decrypt the event.
+    pub message_index: u32,
+    /// The id of the event that used up the message index.
+    pub event_id: EventId,
+}
+
 impl TryFrom<ExportedRoomKey> for InboundGroupSession {
     type Error = OlmGroupSessionError;
 
@@ -406,6 +506,9 @@ impl TryFrom<ExportedRoomKey> for InboundGroupSession {
             room_id: Arc::new(key.room_id),
             forwarding_chains: Arc::new(key.forwarding_curve25519_key_chain),
             imported: Arc::new(true),
+            backed_up: AtomicBool::new(false).into(),
+            created_at: None,
+            shared_history: key.shared_history,
         })
     }
 }
@@ -26,11 +26,20 @@ use zeroize::Zeroize;
 mod inbound;
 mod outbound;
 
-pub use inbound::{InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession};
+pub use inbound::{
+    InboundGroupSession, InboundGroupSessionPickle, MegolmMessageIndex, PickledInboundGroupSession,
+};
+#[cfg(feature = "testing")]
+pub use outbound::EncryptionPreview;
 pub use outbound::{
     EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareState,
 };
 
+/// The field of `m.room_key` and `m.forwarded_room_key` contents that marks a
+/// session as one that may be shared with users that join the room later, as
+/// described in MSC3061.
+pub(crate) const SHARED_HISTORY_FIELD: &str = "org.matrix.msc3061.shared_history";
+
 /// The private session key of a group session.
 /// Can be used to create a new inbound group session.
 #[derive(Clone, Debug, Serialize, Deserialize, Zeroize)]
@@ -69,6 +78,11 @@ pub struct ExportedRoomKey {
     /// Chain of Curve25519 keys through which this session was forwarded, via
     /// m.forwarded_room_key events.
     pub forwarding_curve25519_key_chain: Vec<String>,
+
+    /// Was the session created while the history visibility of the room
+    /// allowed new members to read the room history.
+    #[serde(default, rename = "org.matrix.msc3061.shared_history")]
+    pub shared_history: bool,
 }
 
 impl TryInto<ForwardedRoomKeyToDeviceEventContent> for ExportedRoomKey {
@@ -119,6 +133,7 @@ impl From<ForwardedRoomKeyToDeviceEventContent> for ExportedRoomKey {
             sender_claimed_keys,
             sender_key: forwarded_key.sender_key,
             session_key: ExportedGroupSessionKey(forwarded_key.session_key),
+            shared_history: false,
         }
     }
 }
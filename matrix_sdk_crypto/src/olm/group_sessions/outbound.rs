@@ -52,9 +52,28 @@ use tracing::{debug, error, trace};
 
 use super::{
     super::{deserialize_instant, serialize_instant},
-    GroupSessionKey,
+    GroupSessionKey, SHARED_HISTORY_FIELD,
 };
-use crate::ToDeviceRequest;
+use crate::{environment::now, ToDeviceRequest};
+
+/// A preview of an encrypted room message, as returned by
+/// [`OlmMachine::preview_encryption()`](../struct.OlmMachine.html#method.
+/// preview_encryption).
+#[cfg(feature = "testing")]
+#[cfg_attr(feature = "docs", doc(cfg(testing)))]
+#[derive(Clone, Debug)]
+pub struct EncryptionPreview {
+    /// The content of the `m.room.encrypted` event that would be sent out.
+    pub content: EncryptedEventContent,
+    /// The JSON of the encrypted content, exactly as it would be sent to the
+    /// server.
+    pub json: Value,
+    /// The id of the group session that was used to encrypt the content.
+    pub session_id: String,
+    /// The message index of the group session that was used to encrypt the
+    /// content.
+    pub message_index: u32,
+}
 
 const ROTATION_PERIOD: Duration = Duration::from_millis(604800000);
 const ROTATION_MESSAGES: u64 = 100;
@@ -160,7 +179,7 @@ impl OutboundGroupSession {
             device_id,
             account_identity_keys: identity_keys,
             session_id: session_id.into(),
-            creation_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(now()),
             message_count: Arc::new(AtomicU64::new(0)),
             shared: Arc::new(AtomicBool::new(false)),
             invalidated: Arc::new(AtomicBool::new(false)),
@@ -301,6 +320,37 @@ impl OutboundGroupSession {
         )
     }
 
+    /// Encrypt a room message using a copy of this session.
+    ///
+    /// The state of this session, e.g. the message index, isn't advanced, the
+    /// returned preview contains the ciphertext the next call to
+    /// [`encrypt()`](#method.encrypt) would produce for the same content.
+    #[cfg(feature = "testing")]
+    pub(crate) async fn preview_encryption(
+        &self,
+        content: AnyMessageEventContent,
+    ) -> Result<EncryptionPreview, serde_json::Error> {
+        let pickle = self.pickle(PicklingMode::Unencrypted).await;
+        let session = Self::from_pickle(
+            self.device_id.clone(),
+            self.account_identity_keys.clone(),
+            pickle,
+            PicklingMode::Unencrypted,
+        )
+        .expect("Can't restore a freshly pickled outbound group session");
+
+        let message_index = session.message_index().await;
+        let content = session.encrypt(content).await;
+        let json = serde_json::to_value(&content)?;
+
+        Ok(EncryptionPreview {
+            content,
+            json,
+            session_id: session.session_id().to_owned(),
+            message_index,
+        })
+    }
+
     /// Check if the session has expired and if it should be rotated.
     ///
     /// A session will expire after some time or if enough messages have been
@@ -364,12 +414,18 @@ impl OutboundGroupSession {
     /// Get the outbound group session key as a json value that can be sent as a
     /// m.room_key.
     pub async fn as_json(&self) -> Value {
+        let shared_history = matches!(
+            self.settings.history_visibility,
+            HistoryVisibility::Shared | HistoryVisibility::WorldReadable
+        );
+
         json!({
             "algorithm": EventEncryptionAlgorithm::MegolmV1AesSha2,
             "room_id": &*self.room_id,
             "session_id": &*self.session_id,
             "session_key": self.session_key().await,
             "chain_index": self.message_index().await,
+            SHARED_HISTORY_FIELD: shared_history,
         })
     }
 
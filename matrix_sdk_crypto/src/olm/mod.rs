@@ -18,6 +18,7 @@
 //! `CryptoStore`.
 
 mod account;
+mod fallback_key;
 mod group_sessions;
 mod session;
 mod signing;
@@ -25,11 +26,16 @@ mod utility;
 
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
 pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
+#[cfg(feature = "testing")]
+pub use group_sessions::EncryptionPreview;
 pub use group_sessions::{
     EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession,
+    MegolmMessageIndex, OutboundGroupSession, PickledInboundGroupSession,
+    PickledOutboundGroupSession,
+};
+pub(crate) use group_sessions::{
+    ExportedGroupSessionKey, GroupSessionKey, ShareState, SHARED_HISTORY_FIELD,
 };
-pub(crate) use group_sessions::{GroupSessionKey, ShareState};
 use matrix_sdk_common::instant::{Duration, Instant};
 pub use olm_rs::{account::IdentityKeys, PicklingMode};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -37,6 +43,8 @@ pub use session::{PickledSession, Session, SessionPickle};
 pub use signing::{PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
 pub(crate) use utility::Utility;
 
+use crate::environment::now;
+
 pub(crate) fn serialize_instant<S>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -50,7 +58,7 @@ where
     D: Deserializer<'de>,
 {
     let duration = Duration::deserialize(deserializer)?;
-    let now = Instant::now();
+    let now = now();
     let instant = now
         .checked_sub(duration)
         .ok_or_else(|| serde::de::Error::custom("Can't subtract the current instant"))?;
@@ -59,7 +67,7 @@ where
 
 #[cfg(test)]
 pub(crate) mod test {
-    use std::{collections::BTreeMap, convert::TryInto};
+    use std::{collections::BTreeMap, convert::TryInto, time::Duration};
 
     use olm_rs::session::OlmMessage;
     use ruma::{
@@ -68,7 +76,10 @@ pub(crate) mod test {
         DeviceId, UserId,
     };
 
-    use crate::olm::{InboundGroupSession, ReadOnlyAccount, Session};
+    use crate::{
+        environment::{deterministic, timestamp},
+        olm::{InboundGroupSession, PickledSession, PicklingMode, ReadOnlyAccount, Session},
+    };
 
     fn alice_id() -> UserId {
         user_id!("@alice:example.org")
@@ -184,6 +195,29 @@ pub(crate) mod test {
         assert_eq!(plaintext, decyrpted);
     }
 
+    #[tokio::test]
+    async fn session_use_time_pickling() {
+        let clock = deterministic(1);
+        let (_, mut session) = get_account_and_session().await;
+        let created_at = timestamp();
+        assert_eq!(*session.last_used_at, created_at);
+
+        clock.advance(Duration::from_secs(60));
+        session.encrypt_helper("Hello world").await;
+        assert!(*session.last_used_at > created_at);
+
+        let pickle = session.pickle(PicklingMode::Unencrypted).await;
+        assert_eq!(pickle.last_used_at, timestamp());
+
+        let mut json = serde_json::to_value(pickle).unwrap();
+        json.as_object_mut().unwrap().remove("last_used_at");
+        let pickle: PickledSession = serde_json::from_value(json).unwrap();
+
+        // Old pickles take the time of the last use from the relative one
+        // instead of being treated as unused for decades.
+        assert_eq!(pickle.last_used_at, timestamp());
+    }
+
     #[tokio::test]
     async fn group_session_creation() {
         let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
@@ -228,5 +262,7 @@ pub(crate) mod test {
         let imported = InboundGroupSession::from_export(export).unwrap();
 
         assert_eq!(inbound.session_id(), imported.session_id());
+        assert!(inbound.created_at().is_some());
+        assert!(imported.created_at().is_none());
     }
 }
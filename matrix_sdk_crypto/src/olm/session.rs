@@ -29,12 +29,14 @@ use ruma::{
         EventType,
     },
     identifiers::{DeviceId, DeviceKeyAlgorithm, UserId},
+    MilliSecondsSinceUnixEpoch, UInt,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use super::{deserialize_instant, serialize_instant, IdentityKeys};
 use crate::{
+    environment::{now, timestamp},
     error::{EventError, OlmResult, SessionUnpicklingError},
     ReadOnlyDevice,
 };
@@ -51,6 +53,7 @@ pub struct Session {
     pub(crate) sender_key: Arc<str>,
     pub(crate) creation_time: Arc<Instant>,
     pub(crate) last_use_time: Arc<Instant>,
+    pub(crate) last_used_at: Arc<MilliSecondsSinceUnixEpoch>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -74,7 +77,8 @@ impl Session {
     /// * `message` - The Olm message that should be decrypted.
     pub async fn decrypt(&mut self, message: OlmMessage) -> Result<String, OlmSessionError> {
         let plaintext = self.inner.lock().await.decrypt(message)?;
-        self.last_use_time = Arc::new(Instant::now());
+        self.last_use_time = Arc::new(now());
+        self.last_used_at = Arc::new(timestamp());
         Ok(plaintext)
     }
 
@@ -92,7 +96,8 @@ impl Session {
     /// * `plaintext` - The plaintext that should be encrypted.
     pub(crate) async fn encrypt_helper(&mut self, plaintext: &str) -> OlmMessage {
         let message = self.inner.lock().await.encrypt(plaintext);
-        self.last_use_time = Arc::new(Instant::now());
+        self.last_use_time = Arc::new(now());
+        self.last_used_at = Arc::new(timestamp());
         message
     }
 
@@ -191,6 +196,7 @@ impl Session {
             sender_key: self.sender_key.to_string(),
             creation_time: *self.creation_time,
             last_use_time: *self.last_use_time,
+            last_used_at: *self.last_used_at,
         }
     }
 
@@ -230,6 +236,7 @@ impl Session {
             sender_key: pickle.sender_key.into(),
             creation_time: Arc::new(pickle.creation_time),
             last_use_time: Arc::new(pickle.last_use_time),
+            last_used_at: Arc::new(pickle.last_used_at),
         })
     }
 }
@@ -245,17 +252,59 @@ impl PartialEq for Session {
 /// Holds all the information that needs to be stored in a database to restore
 /// a Session.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredPickledSession")]
 pub struct PickledSession {
     /// The pickle string holding the Olm Session.
     pub pickle: SessionPickle,
     /// The curve25519 key of the other user that we share this session with.
     pub sender_key: String,
     /// The relative time elapsed since the session was created.
-    #[serde(deserialize_with = "deserialize_instant", serialize_with = "serialize_instant")]
+    #[serde(serialize_with = "serialize_instant")]
     pub creation_time: Instant,
     /// The relative time elapsed since the session was last used.
-    #[serde(deserialize_with = "deserialize_instant", serialize_with = "serialize_instant")]
+    #[serde(serialize_with = "serialize_instant")]
     pub last_use_time: Instant,
+    /// The wall clock time the session was last used at.
+    ///
+    /// Pickles that were created before this was recorded take it from the
+    /// relative `last_use_time`.
+    pub last_used_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// A `PickledSession` as it's stored, pickles that were created before the
+/// wall clock time of the last use was recorded don't contain it.
+#[derive(Deserialize)]
+struct StoredPickledSession {
+    pickle: SessionPickle,
+    sender_key: String,
+    #[serde(deserialize_with = "deserialize_instant")]
+    creation_time: Instant,
+    #[serde(deserialize_with = "deserialize_instant")]
+    last_use_time: Instant,
+    last_used_at: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl From<StoredPickledSession> for PickledSession {
+    fn from(stored: StoredPickledSession) -> Self {
+        let last_used_at =
+            stored.last_used_at.unwrap_or_else(|| wall_clock_time(stored.last_use_time));
+
+        Self {
+            pickle: stored.pickle,
+            sender_key: stored.sender_key,
+            creation_time: stored.creation_time,
+            last_use_time: stored.last_use_time,
+            last_used_at,
+        }
+    }
+}
+
+/// Get the wall clock time of the given instant.
+fn wall_clock_time(instant: Instant) -> MilliSecondsSinceUnixEpoch {
+    let elapsed = now().duration_since(instant).as_millis() as u64;
+    let millis = u64::from(timestamp().0).saturating_sub(elapsed);
+
+    MilliSecondsSinceUnixEpoch(UInt::new(millis).unwrap_or(UInt::MAX))
 }
 
 /// The typed representation of a base64 encoded string of the Olm Session
@@ -18,7 +18,6 @@ use aes_gcm::{
     aead::{generic_array::GenericArray, Aead, NewAead},
     Aes256Gcm,
 };
-use getrandom::getrandom;
 use matrix_sdk_common::locks::Mutex;
 use olm_rs::pk::OlmPkSigning;
 #[cfg(test)]
@@ -32,13 +31,13 @@ use ruma::{
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Error as JsonError, Value};
 use thiserror::Error;
-use zeroize::Zeroizing;
 
 use crate::{
+    environment::fill_random,
     error::SignatureError,
     identities::{MasterPubkey, SelfSigningPubkey, UserSigningPubkey},
     utilities::{decode_url_safe as decode, encode_url_safe as encode, DecodeError},
-    UserIdentity,
+    SecretVec, UserIdentity,
 };
 
 const NONCE_SIZE: usize = 12;
@@ -62,7 +61,7 @@ pub enum SigningError {
 #[derive(Clone)]
 pub struct Signing {
     inner: Arc<Mutex<OlmPkSigning>>,
-    seed: Arc<Zeroizing<Vec<u8>>>,
+    seed: Arc<SecretVec>,
     public_key: PublicSigningKey,
 }
 
@@ -295,18 +294,15 @@ pub struct PickledSigning(String);
 impl Signing {
     pub fn new() -> Self {
         let seed = OlmPkSigning::generate_seed();
-        Self::from_seed(seed)
+        Self::from_seed(seed.into())
     }
 
-    pub fn from_seed(seed: Vec<u8>) -> Self {
-        let inner = OlmPkSigning::new(seed.clone()).expect("Unable to create pk signing object");
+    pub fn from_seed(seed: SecretVec) -> Self {
+        let inner =
+            OlmPkSigning::new(seed.expose().to_vec()).expect("Unable to create pk signing object");
         let public_key = PublicSigningKey(inner.public_key().into());
 
-        Signing {
-            inner: Arc::new(Mutex::new(inner)),
-            seed: Arc::new(Zeroizing::from(seed)),
-            public_key,
-        }
+        Signing { inner: Arc::new(Mutex::new(inner)), seed: Arc::new(seed), public_key }
     }
 
     pub fn from_pickle(pickle: PickledSigning, pickle_key: &[u8]) -> Result<Self, SigningError> {
@@ -323,7 +319,7 @@ impl Signing {
             .decrypt(nonce, ciphertext.as_slice())
             .map_err(|e| SigningError::Decryption(e.to_string()))?;
 
-        Ok(Self::from_seed(seed))
+        Ok(Self::from_seed(seed.into()))
     }
 
     pub async fn pickle(&self, pickle_key: &[u8]) -> PickledSigning {
@@ -331,11 +327,11 @@ impl Signing {
         let cipher = Aes256Gcm::new(key);
 
         let mut nonce = vec![0u8; NONCE_SIZE];
-        getrandom(&mut nonce).expect("Can't generate nonce to pickle the signing object");
+        fill_random(&mut nonce);
         let nonce = GenericArray::from_slice(nonce.as_slice());
 
         let ciphertext =
-            cipher.encrypt(nonce, self.seed.as_slice()).expect("Can't encrypt signing pickle");
+            cipher.encrypt(nonce, self.seed.expose()).expect("Can't encrypt signing pickle");
 
         let ciphertext = encode(ciphertext);
 
@@ -0,0 +1,87 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Estimation of the strength of the passphrases that protect key exports
+//! and the crypto store, using [zxcvbn].
+//!
+//! [zxcvbn]: https://github.com/dropbox/zxcvbn
+
+/// The estimated strength of a passphrase.
+///
+/// The strength is estimated by `estimate_passphrase_strength()`, which is
+/// only available with the `passphrase_strength` feature.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassphraseStrength {
+    /// The score of the passphrase, from `0` for a passphrase that is too
+    /// guessable to `4` for a very unguessable one.
+    pub score: u8,
+    /// The base 10 logarithm of the estimated number of guesses needed to
+    /// find the passphrase.
+    pub guesses_log10: f64,
+    /// An explanation of what makes the passphrase weak, if it's weak.
+    pub warning: Option<String>,
+    /// Suggestions for a stronger passphrase, meant to be shown to the user.
+    pub suggestions: Vec<String>,
+}
+
+impl PassphraseStrength {
+    /// The highest score a passphrase can have.
+    pub const MAX_SCORE: u8 = 4;
+
+    /// Does the passphrase have at least the given score.
+    pub fn is_at_least(&self, score: u8) -> bool {
+        self.score >= score
+    }
+}
+
+/// Estimate how hard the given passphrase is to guess.
+///
+/// # Arguments
+///
+/// * `passphrase` - The passphrase that should be checked.
+///
+/// * `user_inputs` - Words the user is likely to put into their passphrase,
+/// e.g. their user id or display name. Passphrases containing them are
+/// considered weaker.
+///
+/// # Examples
+/// ```
+/// # use matrix_sdk_crypto::estimate_passphrase_strength;
+/// let strength = estimate_passphrase_strength("password1", &["@alice:example.org"]);
+/// assert!(!strength.is_at_least(3));
+/// ```
+#[cfg(feature = "passphrase_strength")]
+pub fn estimate_passphrase_strength(passphrase: &str, user_inputs: &[&str]) -> PassphraseStrength {
+    match zxcvbn::zxcvbn(passphrase, user_inputs) {
+        Ok(entropy) => {
+            let feedback = entropy.feedback().as_ref();
+
+            PassphraseStrength {
+                score: entropy.score(),
+                guesses_log10: entropy.guesses_log10(),
+                warning: feedback.and_then(|f| f.warning()).map(|w| w.to_string()),
+                suggestions: feedback
+                    .map(|f| f.suggestions().iter().map(ToString::to_string).collect())
+                    .unwrap_or_default(),
+            }
+        }
+        // zxcvbn refuses to rate empty passphrases.
+        Err(_) => PassphraseStrength {
+            score: 0,
+            guesses_log10: 0.0,
+            warning: Some("The passphrase is empty.".to_owned()),
+            suggestions: Vec::new(),
+        },
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{convert::TryFrom, str::FromStr};
+
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+use subtle::{Choice, ConstantTimeEq};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{environment::fill_random, SecretVec};
+
+/// The two bytes every encoded recovery key starts with.
+const PREFIX: [u8; 2] = [0x8b, 0x01];
+const KEY_SIZE: usize = 32;
+const ENCODED_SIZE: usize = PREFIX.len() + KEY_SIZE + 1;
+/// The number of characters between the spaces of a displayed recovery key.
+const GROUP_SIZE: usize = 4;
+
+/// The key derivation algorithm of a passphrase protected recovery key.
+pub const PBKDF2_ALGORITHM: &str = "m.pbkdf2";
+
+fn default_bits() -> u32 {
+    256
+}
+
+/// Error type for the parsing and derivation of recovery keys.
+#[derive(Error, Debug)]
+pub enum RecoveryKeyError {
+    /// The recovery key isn't valid base58.
+    #[error("the recovery key isn't valid base58: {0}")]
+    Base58(#[from] bs58::decode::Error),
+    /// The decoded recovery key has an invalid length.
+    #[error("the decoded recovery key has an invalid length, expected 35 bytes, got {0}")]
+    InvalidLength(usize),
+    /// The decoded recovery key doesn't start with the expected prefix.
+    #[error("the decoded recovery key doesn't start with the expected prefix")]
+    InvalidPrefix,
+    /// The parity byte of the recovery key doesn't match.
+    #[error("the parity byte of the recovery key doesn't match")]
+    InvalidParity,
+    /// The passphrase info uses an unsupported key derivation algorithm.
+    #[error("unsupported key derivation algorithm {0}")]
+    UnsupportedAlgorithm(String),
+    /// The passphrase info requests a key size that isn't supported.
+    #[error("unsupported key size of {0} bits")]
+    UnsupportedKeySize(u32),
+}
+
+/// The passphrase info of a secret storage key, as found in the
+/// `m.secret_storage.key.*` account data events.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PassphraseInfo {
+    /// The key derivation algorithm, only `m.pbkdf2` is supported.
+    pub algorithm: String,
+    /// The salt that is used to derive the key.
+    pub salt: String,
+    /// The number of PBKDF2 iterations.
+    pub iterations: u32,
+    /// The number of bits the derived key should have.
+    #[serde(default = "default_bits")]
+    pub bits: u32,
+}
+
+impl PassphraseInfo {
+    /// Create new `m.pbkdf2` passphrase info with a random salt.
+    ///
+    /// # Arguments
+    ///
+    /// * `iterations` - The number of PBKDF2 iterations that should be used to
+    /// derive the key.
+    pub fn new(iterations: u32) -> Self {
+        let mut salt = [0u8; KEY_SIZE];
+        fill_random(&mut salt);
+
+        Self {
+            algorithm: PBKDF2_ALGORITHM.to_owned(),
+            salt: bs58::encode(salt).into_string(),
+            iterations,
+            bits: default_bits(),
+        }
+    }
+}
+
+/// A recovery key, used to unlock the server-side key backup and secret
+/// storage.
+///
+/// Recovery keys are presented to the user as base58 strings with a prefix and
+/// a parity byte. Parsing a recovery key checks both, comparing them in
+/// constant time.
+///
+/// The key material is wiped from memory when the `RecoveryKey` is dropped,
+/// the type doesn't implement `Display` so it can't be accidentally logged.
+///
+/// # Examples
+///
+/// ```
+/// # use matrix_sdk_crypto::RecoveryKey;
+/// let key = RecoveryKey::new();
+/// let encoded = key.to_base58();
+///
+/// let parsed: RecoveryKey = encoded.parse().unwrap();
+/// assert_eq!(key, parsed);
+/// ```
+#[derive(Clone, Debug)]
+pub struct RecoveryKey {
+    key: SecretVec,
+}
+
+impl RecoveryKey {
+    /// Generate a new random recovery key.
+    pub fn new() -> Self {
+        let mut key = SecretVec::zeroed(KEY_SIZE);
+        fill_random(key.expose_mut());
+
+        Self { key }
+    }
+
+    /// Parse a recovery key from its base58 encoded form.
+    ///
+    /// Whitespace in the input is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The base58 encoded recovery key.
+    pub fn from_base58(input: &str) -> Result<Self, RecoveryKeyError> {
+        let input: Zeroizing<String> =
+            Zeroizing::new(input.chars().filter(|c| !c.is_whitespace()).collect());
+
+        let mut decoded = SecretVec::zeroed(ENCODED_SIZE);
+        let length = bs58::decode(input.as_bytes()).into(decoded.expose_mut())?;
+
+        if length != ENCODED_SIZE {
+            return Err(RecoveryKeyError::InvalidLength(length));
+        }
+
+        let decoded = decoded.expose();
+        let (prefix, rest) = decoded.split_at(PREFIX.len());
+        let (key, parity) = rest.split_at(KEY_SIZE);
+
+        if !bool::from(prefix.ct_eq(&PREFIX)) {
+            return Err(RecoveryKeyError::InvalidPrefix);
+        }
+
+        let expected_parity = Self::parity(&decoded[..ENCODED_SIZE - 1]);
+
+        if !bool::from(parity[0].ct_eq(&expected_parity)) {
+            return Err(RecoveryKeyError::InvalidParity);
+        }
+
+        Ok(Self { key: SecretVec::new(key.to_vec()) })
+    }
+
+    /// Derive a recovery key from a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase the user entered.
+    ///
+    /// * `info` - The passphrase info of the secret storage key.
+    pub fn from_passphrase(
+        passphrase: &str,
+        info: &PassphraseInfo,
+    ) -> Result<Self, RecoveryKeyError> {
+        if info.algorithm != PBKDF2_ALGORITHM {
+            return Err(RecoveryKeyError::UnsupportedAlgorithm(info.algorithm.clone()));
+        }
+
+        if info.bits as usize != KEY_SIZE * 8 {
+            return Err(RecoveryKeyError::UnsupportedKeySize(info.bits));
+        }
+
+        let mut key = SecretVec::zeroed(KEY_SIZE);
+        pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            info.salt.as_bytes(),
+            info.iterations,
+            key.expose_mut(),
+        );
+
+        Ok(Self { key })
+    }
+
+    /// Encode the recovery key as a base58 string, split into groups of four
+    /// characters.
+    pub fn to_base58(&self) -> Zeroizing<String> {
+        let mut bytes = SecretVec::zeroed(ENCODED_SIZE);
+        let buffer = bytes.expose_mut();
+
+        buffer[..PREFIX.len()].copy_from_slice(&PREFIX);
+        buffer[PREFIX.len()..ENCODED_SIZE - 1].copy_from_slice(self.key.expose());
+        buffer[ENCODED_SIZE - 1] = Self::parity(&buffer[..ENCODED_SIZE - 1]);
+
+        let encoded = Zeroizing::new(bs58::encode(bytes.expose()).into_string());
+
+        let mut result = Zeroizing::new(String::with_capacity(encoded.len() * 5 / 4));
+
+        for (i, c) in encoded.chars().enumerate() {
+            if i > 0 && i % GROUP_SIZE == 0 {
+                result.push(' ');
+            }
+            result.push(c);
+        }
+
+        result
+    }
+
+    /// Get the raw key.
+    pub fn key(&self) -> &SecretVec {
+        &self.key
+    }
+
+    fn parity(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0, |parity, byte| parity ^ byte)
+    }
+}
+
+impl Default for RecoveryKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for RecoveryKey {
+    type Err = RecoveryKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_base58(s)
+    }
+}
+
+impl TryFrom<&str> for RecoveryKey {
+    type Error = RecoveryKeyError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::from_base58(value)
+    }
+}
+
+impl ConstantTimeEq for RecoveryKey {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.key.expose().ct_eq(other.key.expose())
+    }
+}
+
+impl PartialEq for RecoveryKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+impl Eq for RecoveryKey {}
+
+#[cfg(test)]
+mod test {
+    use super::{PassphraseInfo, RecoveryKey, RecoveryKeyError};
+
+    #[test]
+    fn recovery_key_encoding() {
+        let key = RecoveryKey::new();
+        let encoded = key.to_base58();
+
+        assert!(encoded.starts_with("Es"));
+        assert!(encoded.split(' ').all(|g| g.len() <= 4));
+        assert_eq!(RecoveryKey::from_base58(&encoded).unwrap(), key);
+        assert_eq!(RecoveryKey::from_base58(&encoded.replace(' ', "")).unwrap(), key);
+
+        let mut tampered: Vec<char> = encoded.chars().collect();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == 'a' { 'b' } else { 'a' };
+        let tampered: String = tampered.into_iter().collect();
+
+        assert!(matches!(
+            RecoveryKey::from_base58(&tampered),
+            Err(RecoveryKeyError::InvalidParity)
+        ));
+        assert!(matches!(RecoveryKey::from_base58("0OIl"), Err(RecoveryKeyError::Base58(_))));
+    }
+
+    #[test]
+    fn recovery_key_from_passphrase() {
+        let info = PassphraseInfo::new(10);
+
+        let key = RecoveryKey::from_passphrase("it's a secret", &info).unwrap();
+        let same = RecoveryKey::from_passphrase("it's a secret", &info).unwrap();
+        let other = RecoveryKey::from_passphrase("it's another secret", &info).unwrap();
+
+        assert_eq!(key, same);
+        assert_ne!(key, other);
+
+        let info = PassphraseInfo { algorithm: "m.scrypt".to_owned(), ..info };
+
+        assert!(matches!(
+            RecoveryKey::from_passphrase("it's a secret", &info),
+            Err(RecoveryKeyError::UnsupportedAlgorithm(_))
+        ));
+    }
+}
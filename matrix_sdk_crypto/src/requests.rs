@@ -12,29 +12,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc, time::Duration};
 
-use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::uuid::{self, Uuid};
 use ruma::{
     api::client::r0::{
         keys::{
             claim_keys::Response as KeysClaimResponse,
-            get_keys::Response as KeysQueryResponse,
-            upload_keys::{Request as KeysUploadRequest, Response as KeysUploadResponse},
+            get_keys::{
+                IncomingRequest as IncomingKeysQueryRequest, Request as RumaKeysQueryRequest,
+                Response as KeysQueryResponse,
+            },
+            upload_keys::Response as KeysUploadResponse,
             upload_signatures::{
                 Request as SignatureUploadRequest, Response as SignatureUploadResponse,
             },
             upload_signing_keys::Response as SigningKeysUploadResponse,
-            CrossSigningKey,
+            CrossSigningKey, OneTimeKey,
+        },
+        message::send_message_event::{
+            IncomingRequest as IncomingRoomMessageRequest, Request as RumaRoomMessageRequest,
+            Response as RoomMessageResponse,
+        },
+        to_device::{
+            send_event_to_device::{
+                IncomingRequest as IncomingToDeviceRequest, Request as RumaToDeviceRequest,
+                Response as ToDeviceResponse,
+            },
+            DeviceIdOrAllDevices,
         },
-        message::send_message_event::Response as RoomMessageResponse,
-        to_device::{send_event_to_device::Response as ToDeviceResponse, DeviceIdOrAllDevices},
     },
+    assign,
+    encryption::DeviceKeys,
     events::{AnyMessageEventContent, AnyToDeviceEventContent, EventContent, EventType},
-    DeviceIdBox, RoomId, UserId,
+    DeviceIdBox, DeviceKeyId, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::value::RawValue as RawJsonValue;
+use serde_json::{value::RawValue as RawJsonValue, Error as SerdeError};
+use thiserror::Error;
+
+use crate::environment::random_uuid;
 
 /// Customized version of
 /// `ruma_client_api::r0::to_device::send_event_to_device::Request`,
@@ -74,17 +91,47 @@ impl ToDeviceRequest {
         recipient_device: impl Into<DeviceIdOrAllDevices>,
         content: AnyToDeviceEventContent,
     ) -> Self {
-        let mut messages = BTreeMap::new();
-        let mut user_messages = BTreeMap::new();
+        let mut recipients = BTreeMap::new();
+        recipients.insert(recipient.clone(), vec![recipient_device.into()]);
 
-        user_messages.insert(
-            recipient_device.into(),
-            serde_json::value::to_raw_value(&content).expect("Can't serialize to-device content"),
-        );
-        messages.insert(recipient.clone(), user_messages);
+        Self::new_for_recipients(recipients, content)
+    }
+
+    /// Create a new owned to-device request that sends the same content to
+    /// multiple devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipients` - The devices that should receive this to-device event,
+    /// grouped by their user.
+    ///
+    /// * `content` - The content of the to-device event.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the content can't be serialized.
+    pub fn new_for_recipients(
+        recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+        content: AnyToDeviceEventContent,
+    ) -> Self {
         let event_type = EventType::from(content.event_type());
+        let content =
+            serde_json::value::to_raw_value(&content).expect("Can't serialize to-device content");
+
+        let messages = recipients
+            .into_iter()
+            .map(|(user_id, devices)| {
+                (user_id, devices.into_iter().map(|d| (d, content.clone())).collect())
+            })
+            .collect();
 
-        ToDeviceRequest { txn_id: Uuid::new_v4(), event_type, messages }
+        ToDeviceRequest { txn_id: random_uuid(), event_type, messages }
+    }
+
+    /// Create a builder for a to-device request with the given event type,
+    /// which allows to send a different content to every device.
+    pub fn builder(event_type: EventType) -> ToDeviceRequestBuilder {
+        ToDeviceRequestBuilder { event_type, messages: BTreeMap::new() }
     }
 
     /// Gets the transaction ID as a string.
@@ -99,6 +146,134 @@ impl ToDeviceRequest {
     pub fn message_count(&self) -> usize {
         self.messages.values().map(|d| d.len()).sum()
     }
+
+    /// Convert the request into the ruma request type.
+    ///
+    /// The ruma request borrows its transaction id, so the string form of the
+    /// transaction id needs to be passed in, see
+    /// [`txn_id_string()`](#method.txn_id_string).
+    pub fn as_ruma_request<'a>(&'a self, txn_id: &'a str) -> RumaToDeviceRequest<'a> {
+        RumaToDeviceRequest::new(self.event_type.clone(), txn_id, self.messages.clone())
+    }
+}
+
+/// Builder for a [`ToDeviceRequest`] that sends a different content to every
+/// device, e.g. an Olm encrypted room key per device.
+///
+/// Created with [`ToDeviceRequest::builder()`].
+#[derive(Debug)]
+pub struct ToDeviceRequestBuilder {
+    event_type: EventType,
+    messages: BTreeMap<UserId, BTreeMap<DeviceIdOrAllDevices, Box<RawJsonValue>>>,
+}
+
+impl ToDeviceRequestBuilder {
+    /// Add a message for the given device, replacing an earlier message for
+    /// the same device.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - The ID of the user that should receive the message.
+    ///
+    /// * `recipient_device` - The device that should receive the message, or
+    /// all devices.
+    ///
+    /// * `content` - The content of the message, it needs to match the event
+    /// type of the request.
+    pub fn add_message(
+        &mut self,
+        recipient: &UserId,
+        recipient_device: impl Into<DeviceIdOrAllDevices>,
+        content: &impl Serialize,
+    ) -> Result<(), SerdeError> {
+        let content = serde_json::value::to_raw_value(content)?;
+
+        self.messages
+            .entry(recipient.clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(recipient_device.into(), content);
+
+        Ok(())
+    }
+
+    /// Have no messages been added yet.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Build the request, with a new random transaction id.
+    pub fn build(self) -> ToDeviceRequest {
+        ToDeviceRequest {
+            event_type: self.event_type,
+            txn_id: random_uuid(),
+            messages: self.messages,
+        }
+    }
+}
+
+impl TryFrom<IncomingToDeviceRequest> for ToDeviceRequest {
+    type Error = RequestConversionError;
+
+    fn try_from(request: IncomingToDeviceRequest) -> Result<Self, Self::Error> {
+        Ok(Self {
+            event_type: request.event_type,
+            txn_id: Uuid::parse_str(&request.txn_id)?,
+            messages: request.messages,
+        })
+    }
+}
+
+/// Error type for the conversion of ruma requests into the request types of
+/// this crate.
+#[derive(Debug, Error)]
+pub enum RequestConversionError {
+    /// The transaction id of the request isn't a UUID, only requests that
+    /// were created by this crate can be converted.
+    #[error("the transaction id of the request isn't a valid UUID: {0}")]
+    TransactionId(#[from] uuid::Error),
+    /// The content of the request doesn't match its event type.
+    #[error("the content of the request can't be deserialized: {0}")]
+    Content(#[from] SerdeError),
+}
+
+/// Customized version of `ruma_client_api::r0::keys::upload_keys::Request`
+/// that can upload a fallback key as well, as described in [MSC2732].
+///
+/// The request is serialized into the body of the `/keys/upload` request.
+///
+/// [MSC2732]: https://github.com/matrix-org/matrix-doc/pull/2732
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeysUploadRequest {
+    /// The identity keys of the device, `None` if they were uploaded
+    /// already.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_keys: Option<DeviceKeys>,
+
+    /// The one-time keys that should be uploaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_time_keys: Option<BTreeMap<DeviceKeyId, OneTimeKey>>,
+
+    /// The fallback key that should be uploaded, it replaces the fallback key
+    /// that is currently uploaded.
+    #[serde(rename = "org.matrix.msc2732.fallback_keys", skip_serializing_if = "Option::is_none")]
+    pub fallback_keys: Option<BTreeMap<DeviceKeyId, SignedFallbackKey>>,
+}
+
+/// A signed fallback key.
+///
+/// Fallback keys are used to create Olm sessions once the one-time keys of a
+/// device ran out, unlike one-time keys they can be used multiple times.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedFallbackKey {
+    /// The unpadded base64 encoded curve25519 key.
+    pub key: String,
+
+    /// Marks the key as a fallback key, this is always `true` and part of the
+    /// signed content.
+    pub fallback: bool,
+
+    /// The signatures of the key, made with the ed25519 key of the device.
+    pub signatures: BTreeMap<UserId, BTreeMap<DeviceKeyId, String>>,
 }
 
 /// Request that will publish a cross signing identity.
@@ -145,10 +320,26 @@ impl KeysQueryRequest {
     }
 }
 
+impl<'a> From<&'a KeysQueryRequest> for RumaKeysQueryRequest<'a> {
+    fn from(request: &'a KeysQueryRequest) -> Self {
+        assign!(RumaKeysQueryRequest::new(), {
+            timeout: request.timeout,
+            device_keys: request.device_keys.clone(),
+            token: request.token.as_deref(),
+        })
+    }
+}
+
+impl From<IncomingKeysQueryRequest> for KeysQueryRequest {
+    fn from(request: IncomingKeysQueryRequest) -> Self {
+        Self { timeout: request.timeout, device_keys: request.device_keys, token: request.token }
+    }
+}
+
 /// Enum over the different outgoing requests we can have.
 #[derive(Debug)]
 pub enum OutgoingRequests {
-    /// The keys upload request, uploading device and one-time keys.
+    /// The keys upload request, uploading device, one-time and fallback keys.
     KeysUpload(KeysUploadRequest),
     /// The keys query request, fetching the device and cross singing keys of
     /// other users.
@@ -213,7 +404,7 @@ impl From<OutgoingVerificationRequest> for OutgoingRequest {
 
 impl From<SignatureUploadRequest> for OutgoingRequest {
     fn from(r: SignatureUploadRequest) -> Self {
-        Self { request_id: Uuid::new_v4(), request: Arc::new(r.into()) }
+        Self { request_id: random_uuid(), request: Arc::new(r.into()) }
     }
 }
 
@@ -316,6 +507,28 @@ pub struct RoomMessageRequest {
     pub content: AnyMessageEventContent,
 }
 
+impl RoomMessageRequest {
+    /// Convert the request into the ruma request type.
+    ///
+    /// The ruma request borrows its transaction id, so the string form of the
+    /// transaction id needs to be passed in.
+    pub fn as_ruma_request<'a>(&'a self, txn_id: &'a str) -> RumaRoomMessageRequest<'a> {
+        RumaRoomMessageRequest::new(&self.room_id, txn_id, &self.content)
+    }
+}
+
+impl TryFrom<IncomingRoomMessageRequest> for RoomMessageRequest {
+    type Error = RequestConversionError;
+
+    fn try_from(request: IncomingRoomMessageRequest) -> Result<Self, Self::Error> {
+        Ok(Self {
+            room_id: request.room_id,
+            txn_id: Uuid::parse_str(&request.txn_id)?,
+            content: AnyMessageEventContent::from_parts(&request.event_type, request.body.json())?,
+        })
+    }
+}
+
 /// An enum over the different outgoing verification based requests.
 #[derive(Clone, Debug)]
 pub enum OutgoingVerificationRequest {
@@ -355,3 +568,140 @@ impl From<OutgoingVerificationRequest> for OutgoingRequests {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, convert::TryFrom, time::Duration};
+
+    use matrix_sdk_common::uuid::Uuid;
+    use ruma::{
+        api::{
+            client::r0::{
+                keys::get_keys,
+                message::send_message_event,
+                to_device::{send_event_to_device, DeviceIdOrAllDevices},
+            },
+            IncomingRequest, OutgoingRequest, SendAccessToken,
+        },
+        events::{
+            dummy::DummyToDeviceEventContent, room::message::MessageEventContent,
+            AnyMessageEventContent, AnyToDeviceEventContent, EventType,
+        },
+        room_id, user_id,
+    };
+    use serde_json::json;
+
+    use super::{KeysQueryRequest, RequestConversionError, RoomMessageRequest, ToDeviceRequest};
+
+    fn to_http(request: impl OutgoingRequest) -> http::Request<Vec<u8>> {
+        request
+            .try_into_http_request("https://example.org", SendAccessToken::IfRequired("token"))
+            .unwrap()
+    }
+
+    #[test]
+    fn ruma_request_conversions() {
+        let mut request = KeysQueryRequest::new(
+            vec![(user_id!("@alice:example.org"), vec!["DEVICEID".into()])].into_iter().collect(),
+        );
+        request.timeout = Some(Duration::from_secs(10));
+        request.token = Some("s72594_4483_1934".to_owned());
+
+        let http = to_http(get_keys::Request::from(&request));
+        let converted =
+            KeysQueryRequest::from(get_keys::IncomingRequest::try_from_http_request(http).unwrap());
+        assert_eq!(converted.timeout, request.timeout);
+        assert_eq!(converted.device_keys, request.device_keys);
+        assert_eq!(converted.token, request.token);
+
+        let request = ToDeviceRequest::new(
+            &user_id!("@alice:example.org"),
+            DeviceIdOrAllDevices::AllDevices,
+            AnyToDeviceEventContent::Dummy(DummyToDeviceEventContent),
+        );
+        let txn_id = request.txn_id_string();
+
+        let http = to_http(request.as_ruma_request(&txn_id));
+        let incoming = send_event_to_device::IncomingRequest::try_from_http_request(http).unwrap();
+        let converted = ToDeviceRequest::try_from(incoming).unwrap();
+        assert_eq!(converted.txn_id, request.txn_id);
+        assert_eq!(converted.event_type, EventType::Dummy);
+        assert_eq!(converted.message_count(), 1);
+
+        let http = to_http(request.as_ruma_request("not-a-uuid"));
+        let incoming = send_event_to_device::IncomingRequest::try_from_http_request(http).unwrap();
+        assert!(matches!(
+            ToDeviceRequest::try_from(incoming),
+            Err(RequestConversionError::TransactionId(_))
+        ));
+
+        let request = RoomMessageRequest {
+            room_id: room_id!("!test:example.org"),
+            txn_id: Uuid::new_v4(),
+            content: AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello")),
+        };
+        let txn_id = request.txn_id.to_string();
+
+        let http = to_http(request.as_ruma_request(&txn_id));
+        let incoming = send_message_event::IncomingRequest::try_from_http_request(http).unwrap();
+        let converted = RoomMessageRequest::try_from(incoming).unwrap();
+        assert_eq!(converted.room_id, request.room_id);
+        assert_eq!(converted.txn_id, request.txn_id);
+        assert_eq!(
+            serde_json::to_value(&converted.content).unwrap(),
+            serde_json::to_value(&request.content).unwrap()
+        );
+    }
+
+    #[test]
+    fn to_device_requests_for_multiple_devices() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let mut recipients = BTreeMap::new();
+        recipients.insert(
+            alice.clone(),
+            vec![
+                DeviceIdOrAllDevices::DeviceId("ALICE1".into()),
+                DeviceIdOrAllDevices::DeviceId("ALICE2".into()),
+            ],
+        );
+        recipients.insert(bob.clone(), vec![DeviceIdOrAllDevices::AllDevices]);
+
+        let request = ToDeviceRequest::new_for_recipients(
+            recipients,
+            AnyToDeviceEventContent::Dummy(DummyToDeviceEventContent),
+        );
+        assert_eq!(request.event_type, EventType::Dummy);
+        assert_eq!(request.message_count(), 3);
+
+        let mut builder = ToDeviceRequest::builder(EventType::RoomEncrypted);
+        assert!(builder.is_empty());
+
+        builder
+            .add_message(
+                &alice,
+                DeviceIdOrAllDevices::DeviceId("ALICE1".into()),
+                &json!({ "n": 1 }),
+            )
+            .unwrap();
+        builder
+            .add_message(
+                &alice,
+                DeviceIdOrAllDevices::DeviceId("ALICE2".into()),
+                &json!({ "n": 2 }),
+            )
+            .unwrap();
+        builder.add_message(&bob, DeviceIdOrAllDevices::AllDevices, &json!({ "n": 3 })).unwrap();
+
+        let request = builder.build();
+        assert_eq!(request.event_type, EventType::RoomEncrypted);
+        assert_eq!(request.message_count(), 3);
+
+        let content = &request.messages[&alice][&DeviceIdOrAllDevices::DeviceId("ALICE2".into())];
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(content.get()).unwrap(),
+            json!({ "n": 2 })
+        );
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use zeroize::Zeroize;
+
+/// A buffer holding secret key material.
+///
+/// The buffer is wiped from memory when it gets dropped and the contents are
+/// never printed out using the `Debug` implementation. Secrets should only be
+/// copied out of the buffer, using the [`SecretVec::expose()`] method, if a
+/// library requires an unprotected version of them.
+#[derive(Clone, Default, PartialEq, Eq, Zeroize)]
+#[zeroize(drop)]
+pub struct SecretVec(Vec<u8>);
+
+impl SecretVec {
+    /// Create a new secret buffer, taking ownership of the given bytes.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self(secret)
+    }
+
+    /// Create a new secret buffer of the given length, filled with zeroes.
+    pub fn zeroed(len: usize) -> Self {
+        Self(vec![0u8; len])
+    }
+
+    /// Get the secret bytes.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Get a mutable reference to the secret bytes, used to fill the buffer
+    /// in place.
+    pub(crate) fn expose_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    /// The length of the secret in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Is the secret empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<u8>> for SecretVec {
+    fn from(secret: Vec<u8>) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl From<String> for SecretVec {
+    fn from(secret: String) -> Self {
+        Self::new(secret.into_bytes())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SecretVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecretVec").field(&format_args!("[redacted; {}]", self.0.len())).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zeroize::Zeroize;
+
+    use super::SecretVec;
+
+    #[test]
+    fn secret_vec() {
+        let mut secret = SecretVec::from(vec![1u8; 32]);
+
+        assert_eq!(format!("{:?}", secret), "SecretVec([redacted; 32])");
+        assert_eq!(secret.expose(), &[1u8; 32][..]);
+
+        secret.zeroize();
+        assert!(secret.is_empty());
+    }
+}
@@ -32,11 +32,14 @@ use serde_json::Value;
 use tracing::{debug, info, trace};
 
 use crate::{
+    environment::random_uuid,
     error::{EventError, MegolmResult, OlmResult},
     olm::{Account, InboundGroupSession, OutboundGroupSession, Session, ShareState},
     store::{Changes, Result as StoreResult, Store},
     Device, EncryptionSettings, OlmError, ToDeviceRequest,
 };
+#[cfg(feature = "testing")]
+use crate::{error::MegolmError, olm::EncryptionPreview};
 
 #[derive(Clone, Debug)]
 pub(crate) struct GroupSessionCache {
@@ -84,6 +87,21 @@ impl GroupSessionCache {
         }
     }
 
+    /// Remove the session of the given room from the cache.
+    ///
+    /// The session will be loaded from the store again the next time it's
+    /// needed. Sessions that are currently being shared stay in the cache since
+    /// the outstanding to-device requests need to update the cached copy.
+    ///
+    /// Returns true if a session was removed from the cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room this session is used for.
+    pub fn unload(&self, room_id: &RoomId) -> bool {
+        self.sessions.remove_if(room_id, |_, s| s.pending_request_ids().is_empty()).is_some()
+    }
+
     /// Get an outbound group session for a room, if one exists.
     ///
     /// # Arguments
@@ -125,6 +143,12 @@ impl GroupSessionManager {
         Self { account, store: store.clone(), sessions: GroupSessionCache::new(store) }
     }
 
+    /// Drop the cached outbound group session of the given room, it will be
+    /// reloaded from the store once it's needed again.
+    pub fn unload_room(&self, room_id: &RoomId) -> bool {
+        self.sessions.unload(room_id)
+    }
+
     pub async fn invalidate_group_session(&self, room_id: &RoomId) -> StoreResult<bool> {
         if let Some(s) = self.sessions.get(room_id) {
             s.invalidate_session();
@@ -161,7 +185,7 @@ impl GroupSessionManager {
         room_id: &RoomId,
         content: AnyMessageEventContent,
     ) -> MegolmResult<EncryptedEventContent> {
-        let session = if let Some(s) = self.sessions.get(room_id) {
+        let session = if let Some(s) = self.sessions.get_or_load(room_id).await? {
             s
         } else {
             panic!("Session wasn't created nor shared");
@@ -180,6 +204,20 @@ impl GroupSessionManager {
         Ok(content)
     }
 
+    /// Preview the encryption of a room message without advancing the state of
+    /// the outbound group session of the room.
+    #[cfg(feature = "testing")]
+    pub async fn preview_encryption(
+        &self,
+        room_id: &RoomId,
+        content: AnyMessageEventContent,
+    ) -> MegolmResult<EncryptionPreview> {
+        let session =
+            self.sessions.get_or_load(room_id).await?.ok_or(MegolmError::MissingSession)?;
+
+        Ok(session.preview_encryption(content).await?)
+    }
+
     /// Create a new outbound group session.
     ///
     /// This also creates a matching inbound group session and saves that one in
@@ -269,7 +307,7 @@ impl GroupSessionManager {
             }
         }
 
-        let id = Uuid::new_v4();
+        let id = random_uuid();
 
         let request =
             ToDeviceRequest { event_type: EventType::RoomEncrypted, txn_id: id, messages };
@@ -324,10 +362,26 @@ impl GroupSessionManager {
         // This is calculated in the following code and stored in this variable.
         let mut should_rotate = user_left || visibility_changed;
 
+        // Devices that are in quarantine because their device keys failed the
+        // signature check must never receive the session.
+        let rejected_devices: HashSet<(UserId, DeviceIdBox)> = self
+            .store
+            .get_rejected_devices()
+            .await?
+            .iter()
+            .map(|d| (d.user_id().to_owned(), d.device_id().to_owned()))
+            .collect();
+
         for user_id in users {
             let user_devices = self.store.get_user_devices(user_id).await?;
-            let non_blacklisted_devices: Vec<Device> =
-                user_devices.devices().filter(|d| !d.is_blacklisted()).collect();
+            let non_blacklisted_devices: Vec<Device> = user_devices
+                .devices()
+                .filter(|d| {
+                    !d.is_blacklisted()
+                        && !rejected_devices
+                            .contains(&(d.user_id().to_owned(), d.device_id().to_owned()))
+                })
+                .collect();
 
             // If we haven't already concluded that the session should be
             // rotated for other reasons, we also need to check whether any
@@ -534,6 +588,94 @@ impl GroupSessionManager {
 
         Ok(requests)
     }
+
+    /// Get to-device requests that forward the room keys of a room to the
+    /// devices of a newly invited user.
+    ///
+    /// Only sessions that were marked as shared history, i.e. sessions that
+    /// were created while the history of the room was visible to new members,
+    /// are forwarded.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The room id of the room whose history should be shared.
+    ///
+    /// `user_id` - The user that was invited to the room.
+    pub async fn share_room_history(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
+        let sessions: Vec<InboundGroupSession> = self
+            .store
+            .get_inbound_group_sessions()
+            .await?
+            .into_iter()
+            .filter(|s| s.room_id() == room_id && s.shared_history())
+            .collect();
+
+        if sessions.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let rejected_devices: HashSet<DeviceIdBox> = self
+            .store
+            .get_rejected_devices()
+            .await?
+            .iter()
+            .filter(|d| d.user_id() == user_id)
+            .map(|d| d.device_id().to_owned())
+            .collect();
+
+        let devices: Vec<Device> = self
+            .store
+            .get_user_devices(user_id)
+            .await?
+            .devices()
+            .filter(|d| !d.is_blacklisted() && !rejected_devices.contains(d.device_id()))
+            .collect();
+
+        let mut changes = Changes::default();
+        let mut requests = Vec::new();
+
+        for session in &sessions {
+            for chunk in devices.chunks(Self::MAX_TO_DEVICE_MESSAGES) {
+                let mut builder = ToDeviceRequest::builder(EventType::RoomEncrypted);
+
+                for device in chunk {
+                    match device.encrypt_session(session.clone(), None).await {
+                        Ok((used_session, content)) => {
+                            builder.add_message(
+                                device.user_id(),
+                                device.device_id().to_owned(),
+                                &content,
+                            )?;
+                            changes.sessions.push(used_session);
+                        }
+                        Err(OlmError::MissingSession)
+                        | Err(OlmError::EventError(EventError::MissingSenderKey)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                if !builder.is_empty() {
+                    requests.push(Arc::new(builder.build()));
+                }
+            }
+        }
+
+        info!(
+            room_id = room_id.as_str(),
+            user_id = user_id.as_str(),
+            session_count = sessions.len(),
+            request_count = requests.len(),
+            "Sharing the room history with an invited user"
+        );
+
+        self.store.save_changes(changes).await?;
+
+        Ok(requests)
+    }
 }
 
 #[cfg(test)]
@@ -545,6 +687,7 @@ mod test {
             client::r0::keys::{claim_keys, get_keys},
             IncomingResponse,
         },
+        events::room::history_visibility::HistoryVisibility,
         room_id, user_id, DeviceIdBox, UserId,
     };
     use serde_json::Value;
@@ -608,4 +751,44 @@ mod test {
         // that all 148 valid sessions get an room key.
         assert_eq!(event_count, 148);
     }
+
+    #[tokio::test]
+    async fn test_sharing_room_history() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users: Vec<_> = keys_claim.one_time_keys.keys().collect();
+        let invitee = users[0].clone();
+
+        let settings = EncryptionSettings {
+            history_visibility: HistoryVisibility::Joined,
+            ..Default::default()
+        };
+
+        machine.share_group_session(&room_id, users.clone().into_iter(), settings).await.unwrap();
+
+        // Sessions that were created while only joined members could read the
+        // history must not be shared with new members.
+        assert!(machine.share_room_history(&room_id, &invitee).await.unwrap().is_empty());
+
+        machine.invalidate_group_session(&room_id).await.unwrap();
+
+        let settings = EncryptionSettings {
+            history_visibility: HistoryVisibility::Shared,
+            ..Default::default()
+        };
+
+        machine.share_group_session(&room_id, users.into_iter(), settings).await.unwrap();
+
+        let keys = machine.export_keys(|s| s.shared_history()).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].shared_history);
+
+        let requests = machine.share_room_history(&room_id, &invitee).await.unwrap();
+
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].messages.contains_key(&invitee));
+        assert_eq!(requests[0].messages.len(), 1);
+    }
 }
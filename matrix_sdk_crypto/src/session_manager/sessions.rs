@@ -17,18 +17,18 @@ use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use dashmap::{DashMap, DashSet};
 use matrix_sdk_common::uuid::Uuid;
 use ruma::{
-    api::client::r0::{
-        keys::claim_keys::{Request as KeysClaimRequest, Response as KeysClaimResponse},
-        to_device::DeviceIdOrAllDevices,
+    api::client::r0::keys::claim_keys::{
+        Request as KeysClaimRequest, Response as KeysClaimResponse,
     },
     assign,
     events::EventType,
     DeviceId, DeviceIdBox, DeviceKeyAlgorithm, UserId,
 };
-use serde_json::{json, value::to_raw_value};
+use serde_json::json;
 use tracing::{error, info, warn};
 
 use crate::{
+    environment::random_uuid,
     error::OlmResult,
     key_request::KeyRequestMachine,
     olm::Account,
@@ -119,25 +119,13 @@ impl SessionManager {
         if self.wedged_devices.get(user_id).map(|d| d.remove(device_id)).flatten().is_some() {
             if let Some(device) = self.store.get_device(user_id, device_id).await? {
                 let (_, content) = device.encrypt(EventType::Dummy, json!({})).await?;
-                let id = Uuid::new_v4();
-                let mut messages = BTreeMap::new();
-
-                messages.entry(device.user_id().to_owned()).or_insert_with(BTreeMap::new).insert(
-                    DeviceIdOrAllDevices::DeviceId(device.device_id().into()),
-                    to_raw_value(&content)?,
-                );
-
-                let request = OutgoingRequest {
-                    request_id: id,
-                    request: Arc::new(
-                        ToDeviceRequest {
-                            event_type: EventType::RoomEncrypted,
-                            txn_id: id,
-                            messages,
-                        }
-                        .into(),
-                    ),
-                };
+
+                let mut builder = ToDeviceRequest::builder(EventType::RoomEncrypted);
+                builder.add_message(device.user_id(), device.device_id().to_owned(), &content)?;
+
+                let request = builder.build();
+                let id = request.txn_id;
+                let request = OutgoingRequest { request_id: id, request: Arc::new(request.into()) };
 
                 self.outgoing_to_device_requests.insert(id, request);
             }
@@ -225,7 +213,7 @@ impl SessionManager {
             Ok(None)
         } else {
             Ok(Some((
-                Uuid::new_v4(),
+                random_uuid(),
                 assign!(KeysClaimRequest::new(missing), {
                     timeout: Some(Self::KEY_CLAIM_TIMEOUT),
                 }),
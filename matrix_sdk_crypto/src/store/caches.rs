@@ -70,6 +70,19 @@ impl SessionStore {
     pub fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
         self.entries.insert(sender_key.to_owned(), Arc::new(Mutex::new(sessions)));
     }
+
+    /// Get the sender keys of all the sessions in the store.
+    pub fn sender_keys(&self) -> Vec<String> {
+        self.entries.iter().map(|e| e.key().clone()).collect()
+    }
+
+    /// Remove the sessions with the given ids that belong to the given sender
+    /// key.
+    pub async fn remove(&self, sender_key: &str, session_ids: &[String]) {
+        if let Some(sessions) = self.get(sender_key) {
+            sessions.lock().await.retain(|s| !session_ids.iter().any(|id| id == s.session_id()));
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
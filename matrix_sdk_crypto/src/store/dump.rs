@@ -0,0 +1,200 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dumps of the whole content of a crypto store.
+//!
+//! A dump contains everything that is needed to continue using an existing
+//! session with a store of a different type, e.g. moving from the sled store
+//! to a SQL based one, without losing the Olm account, the room keys or the
+//! verification state of devices and identities.
+
+use std::collections::BTreeMap;
+
+use ruma::{encryption::DeviceKeyAlgorithm, UserId};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Changes, CryptoStore, CryptoStoreError, DeviceChanges, EncryptedPickleKey, IdentityChanges,
+    PickleKey, Result,
+};
+use crate::{
+    identities::{ReadOnlyDevice, RejectedDevice, UserIdentities},
+    key_request::OutgoingKeyRequest,
+    olm::{
+        InboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+        PickledInboundGroupSession, PickledSession, PrivateCrossSigningIdentity, ReadOnlyAccount,
+        Session,
+    },
+};
+
+/// The serializable content of a crypto store, created by
+/// [`CryptoStore::export_all()`](trait.CryptoStore.html#method.export_all).
+///
+/// All the private keys of the dump are pickled using a random pickle key, the
+/// pickle key itself is encrypted with the passphrase that was given when the
+/// dump was created.
+///
+/// **Note**: Outbound group sessions aren't part of the dump, new ones will be
+/// created and shared when a message is sent. The same is true for the
+/// replay protection data of Olm and Megolm messages.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CryptoStoreDump {
+    /// The pickle key of the dump, encrypted with the passphrase of the dump.
+    pub pickle_key: EncryptedPickleKey,
+    /// The Olm account that owns the store.
+    pub account: PickledAccount,
+    /// Our private cross signing identity, if we have one.
+    pub private_identity: Option<PickledCrossSigningIdentity>,
+    /// The Olm sessions we have with the devices of tracked users.
+    pub sessions: Vec<PickledSession>,
+    /// All the inbound group sessions of the store.
+    pub inbound_group_sessions: Vec<PickledInboundGroupSession>,
+    /// The devices of the tracked users, including their verification state.
+    pub devices: Vec<ReadOnlyDevice>,
+    /// The devices that were rejected because of invalid device keys.
+    pub rejected_devices: Vec<RejectedDevice>,
+    /// The cross signing identities of the tracked users.
+    pub identities: Vec<UserIdentities>,
+    /// The tracked users, mapped to a flag that tells if the user needs to
+    /// have its devices queried.
+    pub tracked_users: BTreeMap<UserId, bool>,
+    /// The room key requests that weren't sent out yet.
+    pub key_requests: Vec<OutgoingKeyRequest>,
+}
+
+pub(crate) async fn export<S: CryptoStore + ?Sized>(
+    store: &S,
+    passphrase: &str,
+) -> Result<CryptoStoreDump> {
+    let account = store.load_account().await?.ok_or(CryptoStoreError::AccountUnset)?;
+
+    let pickle_key = PickleKey::new();
+
+    let private_identity = match store.load_identity().await? {
+        Some(i) => Some(i.pickle(pickle_key.key().expose()).await?),
+        None => None,
+    };
+
+    let users_for_key_query = store.users_for_key_query();
+    let tracked_users: BTreeMap<UserId, bool> = store
+        .tracked_users()
+        .into_iter()
+        .map(|u| {
+            let dirty = users_for_key_query.contains(&u);
+            (u, dirty)
+        })
+        .collect();
+
+    let mut sessions = Vec::new();
+    let mut devices = Vec::new();
+    let mut identities = Vec::new();
+
+    for user_id in tracked_users.keys() {
+        if let Some(identity) = store.get_user_identity(user_id).await? {
+            identities.push(identity);
+        }
+
+        for (_, device) in store.get_user_devices(user_id).await? {
+            if let Some(sender_key) = device.get_key(DeviceKeyAlgorithm::Curve25519) {
+                if let Some(s) = store.get_sessions(sender_key).await? {
+                    for session in s.lock().await.iter() {
+                        sessions.push(session.pickle(pickle_key.pickle_mode()).await);
+                    }
+                }
+            }
+
+            devices.push(device);
+        }
+    }
+
+    let mut inbound_group_sessions = Vec::new();
+
+    for session in store.get_inbound_group_sessions().await? {
+        inbound_group_sessions.push(session.pickle(pickle_key.pickle_mode()).await);
+    }
+
+    Ok(CryptoStoreDump {
+        pickle_key: pickle_key.encrypt(passphrase),
+        account: account.pickle(pickle_key.pickle_mode()).await,
+        private_identity,
+        sessions,
+        inbound_group_sessions,
+        devices,
+        rejected_devices: store.get_rejected_devices().await?,
+        identities,
+        tracked_users,
+        key_requests: store.get_unsent_key_requests().await?,
+    })
+}
+
+pub(crate) async fn import<S: CryptoStore + ?Sized>(
+    store: &S,
+    dump: CryptoStoreDump,
+    passphrase: &str,
+) -> Result<()> {
+    let pickle_key = PickleKey::from_encrypted(passphrase, dump.pickle_key)
+        .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+    let account = ReadOnlyAccount::from_pickle(dump.account, pickle_key.pickle_mode())?;
+
+    let private_identity = match dump.private_identity {
+        Some(p) => Some(
+            PrivateCrossSigningIdentity::from_pickle(p, pickle_key.key().expose())
+                .await
+                .map_err(|_| CryptoStoreError::UnpicklingError)?,
+        ),
+        None => None,
+    };
+
+    let sessions: Vec<Session> = dump
+        .sessions
+        .into_iter()
+        .map(|p| {
+            Session::from_pickle(
+                account.user_id.clone(),
+                account.device_id.clone(),
+                account.identity_keys.clone(),
+                p,
+                pickle_key.pickle_mode(),
+            )
+        })
+        .collect::<std::result::Result<_, _>>()?;
+
+    let inbound_group_sessions: Vec<InboundGroupSession> = dump
+        .inbound_group_sessions
+        .into_iter()
+        .map(|p| InboundGroupSession::from_pickle(p, pickle_key.pickle_mode()))
+        .collect::<std::result::Result<_, _>>()?;
+
+    // The account needs to be saved first, stores might need it to save the
+    // sessions.
+    store.save_account(account).await?;
+
+    let changes = Changes {
+        private_identity,
+        sessions,
+        inbound_group_sessions,
+        identities: IdentityChanges { new: dump.identities, ..Default::default() },
+        devices: DeviceChanges {
+            new: dump.devices,
+            rejected: dump.rejected_devices,
+            ..Default::default()
+        },
+        key_requests: dump.key_requests,
+        tracked_users: dump.tracked_users,
+        ..Default::default()
+    };
+
+    store.save_changes(changes).await
+}
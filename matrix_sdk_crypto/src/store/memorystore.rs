@@ -14,19 +14,25 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    sync::{Arc, RwLock as StdRwLock},
 };
 
 use dashmap::{DashMap, DashSet};
 use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid};
-use ruma::{events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, RoomId, UserId};
+use ruma::{
+    events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, EventId,
+    MilliSecondsSinceUnixEpoch, RoomId, UserId,
+};
 
 use super::{
     caches::{DeviceStore, GroupSessionStore, SessionStore},
-    Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, Session,
+    unused_sessions, Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, Session,
+    SyncCheckpoint, ToDeviceJournalEntry,
 };
 use crate::{
-    identities::{ReadOnlyDevice, UserIdentities},
+    diagnostics::RoomKeyCounts,
+    environment::timestamp,
+    identities::{ReadOnlyDevice, RejectedDevice, UserIdentities},
     key_request::OutgoingKeyRequest,
     olm::{OutboundGroupSession, PrivateCrossSigningIdentity},
 };
@@ -42,11 +48,15 @@ pub struct MemoryStore {
     inbound_group_sessions: GroupSessionStore,
     tracked_users: Arc<DashSet<UserId>>,
     users_for_key_query: Arc<DashSet<UserId>>,
-    olm_hashes: Arc<DashMap<String, DashSet<String>>>,
+    olm_hashes: Arc<DashMap<String, DashMap<String, MilliSecondsSinceUnixEpoch>>>,
+    message_indices: Arc<DashMap<(RoomId, String, String, u32), EventId>>,
     devices: DeviceStore,
     identities: Arc<DashMap<UserId, UserIdentities>>,
+    rejected_devices: Arc<DashMap<(UserId, DeviceIdBox), RejectedDevice>>,
     outgoing_key_requests: Arc<DashMap<Uuid, OutgoingKeyRequest>>,
     key_requests_by_info: Arc<DashMap<String, Uuid>>,
+    sync_checkpoint: Arc<StdRwLock<Option<SyncCheckpoint>>>,
+    to_device_journal: Arc<DashMap<String, ToDeviceJournalEntry>>,
 }
 
 impl Default for MemoryStore {
@@ -57,10 +67,14 @@ impl Default for MemoryStore {
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
             olm_hashes: Arc::new(DashMap::new()),
+            message_indices: Arc::new(DashMap::new()),
             devices: DeviceStore::new(),
             identities: Arc::new(DashMap::new()),
+            rejected_devices: Arc::new(DashMap::new()),
             outgoing_key_requests: Arc::new(DashMap::new()),
             key_requests_by_info: Arc::new(DashMap::new()),
+            sync_checkpoint: Arc::new(StdRwLock::new(None)),
+            to_device_journal: Arc::new(DashMap::new()),
         }
     }
 }
@@ -111,10 +125,25 @@ impl CryptoStore for MemoryStore {
         Ok(None)
     }
 
+    async fn load_sync_checkpoint(&self) -> Result<Option<SyncCheckpoint>> {
+        Ok(self.sync_checkpoint.read().unwrap().clone())
+    }
+
     async fn save_changes(&self, mut changes: Changes) -> Result<()> {
         self.save_sessions(changes.sessions).await;
         self.save_inbound_group_sessions(changes.inbound_group_sessions).await;
 
+        for device in changes.devices.rejected {
+            self.rejected_devices
+                .insert((device.user_id().to_owned(), device.device_id().into()), device);
+        }
+
+        for device in changes.devices.new.iter().chain(&changes.devices.changed) {
+            let key: (UserId, DeviceIdBox) =
+                (device.user_id().to_owned(), device.device_id().into());
+            self.rejected_devices.remove(&key);
+        }
+
         self.save_devices(changes.devices.new).await;
         self.save_devices(changes.devices.changed).await;
         self.delete_devices(changes.devices.deleted).await;
@@ -126,8 +155,15 @@ impl CryptoStore for MemoryStore {
         for hash in changes.message_hashes {
             self.olm_hashes
                 .entry(hash.sender_key.to_owned())
-                .or_insert_with(DashSet::new)
-                .insert(hash.hash.clone());
+                .or_insert_with(DashMap::new)
+                .insert(hash.hash.clone(), timestamp());
+        }
+
+        for index in changes.message_indices {
+            self.message_indices.insert(
+                (index.room_id, index.sender_key, index.session_id, index.message_index),
+                index.event_id,
+            );
         }
 
         for key_request in changes.key_requests {
@@ -138,6 +174,24 @@ impl CryptoStore for MemoryStore {
             self.key_requests_by_info.insert(info_string, id);
         }
 
+        for (user, dirty) in changes.tracked_users {
+            if dirty {
+                self.users_for_key_query.insert(user.clone());
+            } else {
+                self.users_for_key_query.remove(&user);
+            }
+
+            self.tracked_users.insert(user);
+        }
+
+        if let Some(checkpoint) = changes.sync_checkpoint {
+            *self.sync_checkpoint.write().unwrap() = Some(checkpoint);
+        }
+
+        for entry in changes.to_device_journal {
+            self.to_device_journal.insert(entry.hash.clone(), entry);
+        }
+
         Ok(())
     }
 
@@ -158,6 +212,15 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let sessions = self.inbound_group_sessions.get_all();
+
+        Ok(RoomKeyCounts {
+            total: sessions.len(),
+            backed_up: sessions.iter().filter(|s| s.backed_up()).count(),
+        })
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         _: &RoomId,
@@ -178,6 +241,11 @@ impl CryptoStore for MemoryStore {
         self.users_for_key_query.iter().map(|u| u.clone()).collect()
     }
 
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users.iter().map(|u| u.clone()).collect()
+    }
+
     async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
         // TODO to prevent a race between the sync and a key query in flight we
         // need to have an additional state to mention that the user changed.
@@ -215,6 +283,19 @@ impl CryptoStore for MemoryStore {
         Ok(self.devices.user_devices(user_id))
     }
 
+    async fn get_rejected_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<RejectedDevice>> {
+        let key: (UserId, DeviceIdBox) = (user_id.to_owned(), device_id.into());
+        Ok(self.rejected_devices.get(&key).map(|d| d.value().clone()))
+    }
+
+    async fn get_rejected_devices(&self) -> Result<Vec<RejectedDevice>> {
+        Ok(self.rejected_devices.iter().map(|d| d.value().clone()).collect())
+    }
+
     async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentities>> {
         #[allow(clippy::map_clone)]
         Ok(self.identities.get(user_id).map(|i| i.clone()))
@@ -223,9 +304,32 @@ impl CryptoStore for MemoryStore {
     async fn is_message_known(&self, message_hash: &crate::olm::OlmMessageHash) -> Result<bool> {
         Ok(self
             .olm_hashes
-            .entry(message_hash.sender_key.to_owned())
-            .or_insert_with(DashSet::new)
-            .contains(&message_hash.hash))
+            .get(&message_hash.sender_key)
+            .map_or(false, |hashes| hashes.contains_key(&message_hash.hash)))
+    }
+
+    async fn is_to_device_event_processed(&self, hash: &str) -> Result<bool> {
+        Ok(self.to_device_journal.contains_key(hash))
+    }
+
+    async fn get_to_device_journal(&self) -> Result<Vec<ToDeviceJournalEntry>> {
+        let mut journal: Vec<_> =
+            self.to_device_journal.iter().map(|e| e.value().clone()).collect();
+        journal.sort_by_key(|e| e.processed_at);
+
+        Ok(journal)
+    }
+
+    async fn get_event_for_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<EventId>> {
+        let key = (room_id.to_owned(), sender_key.to_owned(), session_id.to_owned(), message_index);
+
+        Ok(self.message_indices.get(&key).map(|e| e.clone()))
     }
 
     async fn get_outgoing_key_request(
@@ -264,16 +368,75 @@ impl CryptoStore for MemoryStore {
 
         Ok(())
     }
+
+    async fn remove_message_hashes_older_than(
+        &self,
+        time: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize> {
+        let mut removed = 0;
+
+        for hashes in self.olm_hashes.iter() {
+            let count = hashes.len();
+            hashes.retain(|_, t| t.get() >= time.get());
+            removed += count - hashes.len();
+        }
+
+        Ok(removed)
+    }
+
+    async fn remove_to_device_journal_entries_older_than(
+        &self,
+        time: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize> {
+        let count = self.to_device_journal.len();
+        self.to_device_journal.retain(|_, e| e.processed_at.get() >= time.get());
+
+        Ok(count - self.to_device_journal.len())
+    }
+
+    async fn remove_unused_sessions(
+        &self,
+        unused_since: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize> {
+        let mut sessions = Vec::new();
+
+        for sender_key in self.sessions.sender_keys() {
+            if let Some(s) = self.sessions.get(&sender_key) {
+                sessions.extend(
+                    s.lock()
+                        .await
+                        .iter()
+                        .map(|s| (sender_key.clone(), s.session_id().to_owned(), *s.last_used_at)),
+                );
+            }
+        }
+
+        let mut removed = 0;
+
+        for (sender_key, session_ids) in unused_sessions(sessions, unused_since) {
+            self.sessions.remove(&sender_key, &session_ids).await;
+            removed += session_ids.len();
+        }
+
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use ruma::room_id;
+    use futures::StreamExt;
+    use ruma::{event_id, room_id};
 
     use crate::{
         identities::device::test::get_device,
-        olm::{test::get_account_and_session, InboundGroupSession, OlmMessageHash},
-        store::{memorystore::MemoryStore, Changes, CryptoStore},
+        olm::{
+            test::get_account_and_session, InboundGroupSession, MegolmMessageIndex, OlmMessageHash,
+        },
+        store::{memorystore::MemoryStore, Changes, CryptoStore, SnapshotEntry},
     };
 
     #[tokio::test]
@@ -357,6 +520,29 @@ mod test {
         assert!(store.is_user_tracked(device.user_id()));
     }
 
+    #[tokio::test]
+    async fn test_snapshot_export() {
+        let device = get_device();
+        let store = MemoryStore::new();
+
+        store.save_devices(vec![device.clone()]).await;
+        assert!(store.export_snapshot().next().await.is_none());
+
+        store.update_tracked_user(device.user_id(), false).await.unwrap();
+
+        let entries: Vec<_> = store.export_snapshot().collect().await;
+        assert_eq!(entries.len(), 1);
+
+        match entries[0].as_ref().unwrap() {
+            SnapshotEntry::Device(d) => {
+                assert_eq!(&d.user_id, device.user_id());
+                assert_eq!(&*d.device_id, device.device_id());
+                assert_eq!(&d.keys, device.keys());
+            }
+            e => panic!("Unexpected snapshot entry {:?}", e),
+        }
+    }
+
     #[tokio::test]
     async fn test_message_hash() {
         let store = MemoryStore::new();
@@ -371,4 +557,35 @@ mod test {
         store.save_changes(changes).await.unwrap();
         assert!(store.is_message_known(&hash).await.unwrap());
     }
+
+    #[tokio::test]
+    async fn test_message_index() {
+        let store = MemoryStore::new();
+        let room_id = room_id!("!test:localhost");
+
+        let index = MegolmMessageIndex {
+            room_id: room_id.clone(),
+            sender_key: "test_sender".to_owned(),
+            session_id: "test_session".to_owned(),
+            message_index: 0,
+            event_id: event_id!("$test:localhost"),
+        };
+
+        let mut changes = Changes::default();
+        changes.message_indices.push(index.clone());
+
+        assert!(store
+            .get_event_for_message_index(&room_id, "test_sender", "test_session", 0)
+            .await
+            .unwrap()
+            .is_none());
+        store.save_changes(changes).await.unwrap();
+        assert_eq!(
+            store
+                .get_event_for_message_index(&room_id, "test_sender", "test_session", 0)
+                .await
+                .unwrap(),
+            Some(index.event_id)
+        );
+    }
 }
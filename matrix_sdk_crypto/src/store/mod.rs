@@ -38,46 +38,101 @@
 //! [`CryptoStore`]: trait.Cryptostore.html
 
 pub mod caches;
+mod dump;
 mod memorystore;
 mod pickle_key;
 #[cfg(feature = "sled_cryptostore")]
 pub(crate) mod sled;
+mod snapshot;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
     io::Error as IoError,
     ops::Deref,
     sync::Arc,
 };
 
-use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid, AsyncTraitDeps};
+pub use dump::CryptoStoreDump;
+use matrix_sdk_common::{async_trait, instant::Duration, locks::Mutex, uuid::Uuid, AsyncTraitDeps};
 pub use memorystore::MemoryStore;
 use olm_rs::errors::{OlmAccountError, OlmGroupSessionError, OlmSessionError};
 pub use pickle_key::{EncryptedPickleKey, PickleKey};
 use ruma::{
     events::room_key_request::RequestedKeyInfo,
     identifiers::{
-        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, Error as IdentifierValidationError, RoomId,
-        UserId,
+        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, Error as IdentifierValidationError, EventId,
+        RoomId, UserId,
     },
+    MilliSecondsSinceUnixEpoch, UInt,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
+pub use snapshot::{
+    DeviceSnapshot, IdentitySnapshot, InboundGroupSessionSnapshot, SessionSnapshot, SnapshotEntry,
+    SnapshotStream,
+};
 use thiserror::Error;
 
 #[cfg(feature = "sled_cryptostore")]
-pub use self::sled::SledStore;
+pub use self::sled::{IntegrityReport, MigrationProgress, SledStore};
 use crate::{
+    diagnostics::RoomKeyCounts,
+    environment::timestamp,
     error::SessionUnpicklingError,
-    identities::{Device, ReadOnlyDevice, UserDevices, UserIdentities},
+    identities::{Device, ReadOnlyDevice, RejectedDevice, UserDevices, UserIdentities},
     key_request::OutgoingKeyRequest,
     olm::{
-        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        InboundGroupSession, MegolmMessageIndex, OlmMessageHash, OutboundGroupSession,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
     },
     verification::VerificationMachine,
 };
 
+/// The time before which data that is kept for the given retention period
+/// should be removed.
+pub(crate) fn retention_cutoff(retention: Duration) -> MilliSecondsSinceUnixEpoch {
+    let now = u64::from(timestamp().get());
+    let retention = retention.as_millis() as u64;
+
+    MilliSecondsSinceUnixEpoch(UInt::new(now.saturating_sub(retention)).unwrap_or_default())
+}
+
+/// Find the sessions that weren't used since the given time, the most recently
+/// used session of every sender key isn't included.
+///
+/// Takes the sessions as `(sender_key, session_id, last_used_at)` tuples and
+/// returns the ids of the unused sessions grouped by their sender key.
+pub(crate) fn unused_sessions(
+    sessions: Vec<(String, String, MilliSecondsSinceUnixEpoch)>,
+    unused_since: MilliSecondsSinceUnixEpoch,
+) -> BTreeMap<String, Vec<String>> {
+    let mut by_sender: BTreeMap<String, Vec<(String, MilliSecondsSinceUnixEpoch)>> =
+        BTreeMap::new();
+
+    for (sender_key, session_id, last_used_at) in sessions {
+        by_sender.entry(sender_key).or_default().push((session_id, last_used_at));
+    }
+
+    by_sender
+        .into_iter()
+        .filter_map(|(sender_key, sessions)| {
+            let newest = sessions.iter().map(|(_, t)| *t).max()?;
+            let unused: Vec<String> = sessions
+                .into_iter()
+                .filter(|(_, t)| *t < unused_since && *t != newest)
+                .map(|(id, _)| id)
+                .collect();
+
+            if unused.is_empty() {
+                None
+            } else {
+                Some((sender_key, unused))
+            }
+        })
+        .collect()
+}
+
 /// A `CryptoStore` specific result type.
 pub type Result<T, E = CryptoStoreError> = std::result::Result<T, E>;
 
@@ -93,6 +148,9 @@ pub(crate) struct Store {
     identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
     inner: Arc<dyn CryptoStore>,
     verification_machine: VerificationMachine,
+    /// Changes that are queued up to be saved together with the next call to
+    /// `save_changes()`.
+    queued_changes: Arc<Mutex<Changes>>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -102,11 +160,111 @@ pub struct Changes {
     pub private_identity: Option<PrivateCrossSigningIdentity>,
     pub sessions: Vec<Session>,
     pub message_hashes: Vec<OlmMessageHash>,
+    pub message_indices: Vec<MegolmMessageIndex>,
     pub inbound_group_sessions: Vec<InboundGroupSession>,
     pub outbound_group_sessions: Vec<OutboundGroupSession>,
     pub identities: IdentityChanges,
     pub key_requests: Vec<OutgoingKeyRequest>,
     pub devices: DeviceChanges,
+    /// Users that should be tracked, mapped to a flag that tells if the
+    /// devices of the user are outdated and need to be queried.
+    pub tracked_users: BTreeMap<UserId, bool>,
+    /// The sync response the changes were received in.
+    pub sync_checkpoint: Option<SyncCheckpoint>,
+    /// The to-device events that were processed as part of the changes.
+    pub to_device_journal: Vec<ToDeviceJournalEntry>,
+}
+
+/// The sync response whose to-device events were committed to the crypto
+/// store.
+///
+/// The crypto changes of a sync response are committed before the state
+/// changes and the sync token. Comparing the checkpoint with the sync token of
+/// the state store tells if the state commit of the last sync response was
+/// lost, or if the stores diverged.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SyncCheckpoint {
+    /// The sync token the sync request was sent with, `None` for an initial
+    /// sync.
+    pub since: Option<String>,
+    /// The sync token of the sync response.
+    pub next_batch: String,
+}
+
+/// A to-device event that was processed by the [`OlmMachine`].
+///
+/// The journal of the processed events makes sure that every to-device event
+/// is handled exactly once, even if the homeserver sends the same sync
+/// response again, e.g. because it reused a sync token. A re-delivered room
+/// key or verification message would otherwise be processed twice.
+///
+/// [`OlmMachine`]: crate::OlmMachine
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ToDeviceJournalEntry {
+    /// The unpadded base64 encoded SHA-256 hash of the event as it was
+    /// received from the homeserver.
+    pub hash: String,
+    /// The type of the event, `m.room.encrypted` for encrypted events.
+    pub event_type: String,
+    /// The user that sent the event.
+    pub sender: UserId,
+    /// The sync token of the sync response that contained the event.
+    pub sync_token: String,
+    /// The time the event was processed.
+    pub processed_at: MilliSecondsSinceUnixEpoch,
+}
+
+impl Changes {
+    /// Merge the given `Changes` into this instance of `Changes`.
+    ///
+    /// The account and private identity of the given changes replace the ones
+    /// of this instance, if they are set.
+    pub fn extend(&mut self, other: Changes) {
+        if other.account.is_some() {
+            self.account = other.account;
+        }
+
+        if other.private_identity.is_some() {
+            self.private_identity = other.private_identity;
+        }
+
+        self.sessions.extend(other.sessions);
+        self.message_hashes.extend(other.message_hashes);
+        self.message_indices.extend(other.message_indices);
+        self.inbound_group_sessions.extend(other.inbound_group_sessions);
+        self.outbound_group_sessions.extend(other.outbound_group_sessions);
+        self.identities.extend(other.identities);
+        self.key_requests.extend(other.key_requests);
+        self.devices.extend(other.devices);
+        self.tracked_users.extend(other.tracked_users);
+
+        if other.sync_checkpoint.is_some() {
+            self.sync_checkpoint = other.sync_checkpoint;
+        }
+
+        self.to_device_journal.extend(other.to_device_journal);
+    }
+
+    /// Are there no changes that need to be saved.
+    pub fn is_empty(&self) -> bool {
+        self.account.is_none()
+            && self.private_identity.is_none()
+            && self.sessions.is_empty()
+            && self.message_hashes.is_empty()
+            && self.message_indices.is_empty()
+            && self.inbound_group_sessions.is_empty()
+            && self.outbound_group_sessions.is_empty()
+            && self.identities.new.is_empty()
+            && self.identities.changed.is_empty()
+            && self.key_requests.is_empty()
+            && self.devices.new.is_empty()
+            && self.devices.changed.is_empty()
+            && self.devices.deleted.is_empty()
+            && self.devices.rejected.is_empty()
+            && self.tracked_users.is_empty()
+            && self.sync_checkpoint.is_none()
+            && self.to_device_journal.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -116,12 +274,22 @@ pub struct IdentityChanges {
     pub changed: Vec<UserIdentities>,
 }
 
+impl IdentityChanges {
+    /// Merge the given `IdentityChanges` into this instance of
+    /// `IdentityChanges`.
+    pub fn extend(&mut self, other: IdentityChanges) {
+        self.new.extend(other.new);
+        self.changed.extend(other.changed);
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 #[allow(missing_docs)]
 pub struct DeviceChanges {
     pub new: Vec<ReadOnlyDevice>,
     pub changed: Vec<ReadOnlyDevice>,
     pub deleted: Vec<ReadOnlyDevice>,
+    pub rejected: Vec<RejectedDevice>,
 }
 
 impl DeviceChanges {
@@ -130,6 +298,7 @@ impl DeviceChanges {
         self.new.extend(other.new);
         self.changed.extend(other.changed);
         self.deleted.extend(other.deleted);
+        self.rejected.extend(other.rejected);
     }
 }
 
@@ -140,7 +309,49 @@ impl Store {
         store: Arc<dyn CryptoStore>,
         verification_machine: VerificationMachine,
     ) -> Self {
-        Self { user_id, identity, inner: store, verification_machine }
+        Self {
+            user_id,
+            identity,
+            inner: store,
+            verification_machine,
+            queued_changes: Arc::new(Mutex::new(Changes::default())),
+        }
+    }
+
+    /// Queue up changes to be saved together with the next call to
+    /// `save_changes()`.
+    ///
+    /// This coalesces many small writes into a single transaction, the queued
+    /// changes won't be visible to reads until they are saved.
+    pub async fn queue_changes(&self, changes: Changes) {
+        self.queued_changes.lock().await.extend(changes);
+    }
+
+    /// Save the given changes, together with all the queued up changes, in a
+    /// single transaction.
+    ///
+    /// The queued up changes are only dropped once they have been saved, if
+    /// saving fails they stay queued for the next call.
+    pub async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let mut queued_changes = self.queued_changes.lock().await;
+
+        let mut merged = queued_changes.clone();
+        merged.extend(changes);
+
+        if merged.is_empty() {
+            Ok(())
+        } else {
+            self.inner.save_changes(merged).await?;
+            *queued_changes = Changes::default();
+
+            Ok(())
+        }
+    }
+
+    /// Save all the queued up changes and write them to disk.
+    pub async fn flush(&self) -> Result<()> {
+        self.save_changes(Changes::default()).await?;
+        self.inner.flush().await
     }
 
     pub async fn get_readonly_device(
@@ -283,6 +494,11 @@ pub enum CryptoStoreError {
     /// The store failed to (de)serialize a data type.
     #[error(transparent)]
     Serialization(#[from] SerdeError),
+
+    /// The store was written by a newer version of the library, its schema
+    /// version isn't supported.
+    #[error("the store has the schema version {0}, only versions up to {1} are supported")]
+    UnsupportedVersion(u8, u8),
 }
 
 /// Trait abstracting a store that the `OlmMachine` uses to store cryptographic
@@ -303,6 +519,10 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Try to load a private cross signing identity, if one is stored.
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>>;
 
+    /// Load the checkpoint of the last sync response whose changes were
+    /// saved.
+    async fn load_sync_checkpoint(&self) -> Result<Option<SyncCheckpoint>>;
+
     /// Save the set of changes to the store.
     ///
     /// # Arguments
@@ -335,6 +555,10 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>>;
 
+    /// Count the inbound group sessions we have stored and the ones of them
+    /// that were marked as backed up.
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts>;
+
     /// Get the outbound group sessions we have stored that is used for the
     /// given room.
     async fn get_outbound_group_sessions(
@@ -352,6 +576,9 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// the tracked users.
     fn users_for_key_query(&self) -> HashSet<UserId>;
 
+    /// Set of all the users that are tracked.
+    fn tracked_users(&self) -> HashSet<UserId>;
+
     /// Add an user for tracking.
     ///
     /// Returns true if the user wasn't already tracked, false otherwise.
@@ -386,6 +613,23 @@ pub trait CryptoStore: AsyncTraitDeps {
         user_id: &UserId,
     ) -> Result<HashMap<DeviceIdBox, ReadOnlyDevice>>;
 
+    /// Get the quarantined device for the given user with the given device id.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    async fn get_rejected_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<RejectedDevice>>;
+
+    /// Get all the devices that were put into quarantine because their device
+    /// keys failed the signature check.
+    async fn get_rejected_devices(&self) -> Result<Vec<RejectedDevice>>;
+
     /// Get the user identity that is attached to the given user id.
     ///
     /// # Arguments
@@ -396,6 +640,39 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Check if a hash for an Olm message stored in the database.
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool>;
 
+    /// Check if the to-device event with the given hash was already processed.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - The hash of the event, see [`ToDeviceJournalEntry::hash`].
+    async fn is_to_device_event_processed(&self, hash: &str) -> Result<bool>;
+
+    /// Get the journal of the processed to-device events, the oldest entry
+    /// comes first.
+    async fn get_to_device_journal(&self) -> Result<Vec<ToDeviceJournalEntry>>;
+
+    /// Get the id of the event that was decrypted using the given message
+    /// index of a group session.
+    ///
+    /// Returns `None` if no event was decrypted using the message index yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the room that the session belongs to.
+    ///
+    /// * `sender_key` - The sender key that sent us the session.
+    ///
+    /// * `session_id` - The unique id of the session.
+    ///
+    /// * `message_index` - The message index of the decrypted event.
+    async fn get_event_for_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<EventId>>;
+
     /// Get an outgoing key request that we created that matches the given
     /// request id.
     ///
@@ -430,4 +707,86 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `request_id` - The unique request id that identifies this outgoing key
     /// request.
     async fn delete_outgoing_key_request(&self, request_id: Uuid) -> Result<()>;
+
+    /// Remove the hashes of the Olm messages that were received before the
+    /// given time.
+    ///
+    /// Returns the number of removed hashes.
+    async fn remove_message_hashes_older_than(
+        &self,
+        time: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize>;
+
+    /// Remove the entries of the to-device journal that were processed before
+    /// the given time.
+    ///
+    /// Returns the number of removed entries.
+    async fn remove_to_device_journal_entries_older_than(
+        &self,
+        time: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize>;
+
+    /// Remove the Olm sessions that weren't used since the given time.
+    ///
+    /// The most recently used session of every sender key is kept, even if
+    /// it wasn't used since the given time.
+    ///
+    /// Returns the number of removed sessions.
+    async fn remove_unused_sessions(
+        &self,
+        unused_since: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize>;
+
+    /// Write all the pending changes of the store to disk.
+    ///
+    /// Returns once all the changes that were made up until now are durably
+    /// stored.
+    async fn flush(&self) -> Result<()>;
+
+    /// Export a read-only snapshot of the non-secret metadata of the store.
+    ///
+    /// The returned stream yields the identities, devices and Olm sessions of
+    /// all tracked users as well as all inbound group sessions, the entries
+    /// are loaded lazily as the stream is polled.
+    ///
+    /// **Note**: The snapshot is meant for external audit or automation tools
+    /// and never contains any key material, only public keys and session ids
+    /// are exported. Use the key export of the `OlmMachine` to back up room
+    /// keys.
+    fn export_snapshot(&self) -> SnapshotStream<'_> {
+        snapshot::export(self)
+    }
+
+    /// Export the whole content of the store, e.g. to move it into a store of
+    /// a different type.
+    ///
+    /// The private keys in the dump are protected by the given passphrase, the
+    /// same passphrase is needed to import the dump using [`import_all()`].
+    /// The store needs to contain an account for the export to succeed.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should be used to encrypt the
+    /// private keys of the dump.
+    ///
+    /// [`import_all()`]: #method.import_all
+    async fn export_all(&self, passphrase: &str) -> Result<CryptoStoreDump> {
+        dump::export(self, passphrase).await
+    }
+
+    /// Import a dump that was created using [`export_all()`] into this store.
+    ///
+    /// The account and the cross signing identity of the dump replace the
+    /// ones of this store, the rest of the dump is added to the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `dump` - The dump that should be imported.
+    ///
+    /// * `passphrase` - The passphrase that was used to create the dump.
+    ///
+    /// [`export_all()`]: #method.export_all
+    async fn import_all(&self, dump: CryptoStoreDump, passphrase: &str) -> Result<()> {
+        dump::import(self, dump, passphrase).await
+    }
 }
@@ -18,13 +18,14 @@ use aes_gcm::{
     aead::{generic_array::GenericArray, Aead, NewAead},
     Aes256Gcm, Error as DecryptionError,
 };
-use getrandom::getrandom;
 use hmac::Hmac;
 use olm_rs::PicklingMode;
 use pbkdf2::pbkdf2;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use zeroize::{Zeroize, Zeroizing};
+use zeroize::Zeroize;
+
+use crate::{environment::fill_random, SecretVec};
 
 const KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 12;
@@ -76,13 +77,13 @@ pub struct EncryptedPickleKey {
 /// AES256-GCM so the key sizes match.
 #[derive(Debug, Zeroize, PartialEq)]
 pub struct PickleKey {
-    aes256_key: Vec<u8>,
+    aes256_key: SecretVec,
 }
 
 impl Default for PickleKey {
     fn default() -> Self {
-        let mut key = vec![0u8; KEY_SIZE];
-        getrandom(&mut key).expect("Can't generate new pickle key");
+        let mut key = SecretVec::zeroed(KEY_SIZE);
+        fill_random(key.expose_mut());
 
         Self { aes256_key: key }
     }
@@ -94,7 +95,7 @@ impl TryFrom<Vec<u8>> for PickleKey {
         if value.len() != KEY_SIZE {
             Err(())
         } else {
-            Ok(Self { aes256_key: value })
+            Ok(Self { aes256_key: value.into() })
         }
     }
 }
@@ -105,19 +106,22 @@ impl PickleKey {
         Default::default()
     }
 
-    fn expand_key(passphrase: &str, salt: &[u8], rounds: u32) -> Zeroizing<Vec<u8>> {
-        let mut key = Zeroizing::from(vec![0u8; KEY_SIZE]);
-        pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut *key);
+    fn expand_key(passphrase: &str, salt: &[u8], rounds: u32) -> SecretVec {
+        let mut key = SecretVec::zeroed(KEY_SIZE);
+        pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, key.expose_mut());
         key
     }
 
     /// Get a `PicklingMode` version of this pickle key.
+    ///
+    /// Note that the `PicklingMode` holds an unprotected copy of the key, it
+    /// should be dropped as soon as the pickling operation is done.
     pub fn pickle_mode(&self) -> PicklingMode {
-        PicklingMode::Encrypted { key: self.aes256_key.clone() }
+        PicklingMode::Encrypted { key: self.aes256_key.expose().to_vec() }
     }
 
     /// Get the raw AES256 key.
-    pub fn key(&self) -> &[u8] {
+    pub fn key(&self) -> &SecretVec {
         &self.aes256_key
     }
 
@@ -129,17 +133,17 @@ impl PickleKey {
     /// pickle key.
     pub fn encrypt(&self, passphrase: &str) -> EncryptedPickleKey {
         let mut salt = vec![0u8; KDF_SALT_SIZE];
-        getrandom(&mut salt).expect("Can't generate new random pickle key");
+        fill_random(&mut salt);
 
         let key = PickleKey::expand_key(passphrase, &salt, KDF_ROUNDS);
-        let key = GenericArray::from_slice(key.as_ref());
+        let key = GenericArray::from_slice(key.expose());
         let cipher = Aes256Gcm::new(key);
 
         let mut nonce = vec![0u8; NONCE_SIZE];
-        getrandom(&mut nonce).expect("Can't generate new random nonce for the pickle key");
+        fill_random(&mut nonce);
 
         let ciphertext = cipher
-            .encrypt(GenericArray::from_slice(nonce.as_ref()), self.aes256_key.as_slice())
+            .encrypt(GenericArray::from_slice(nonce.as_ref()), self.aes256_key.expose())
             .expect("Can't encrypt pickle key");
 
         EncryptedPickleKey {
@@ -165,13 +169,13 @@ impl PickleKey {
             KdfInfo::Pbkdf2 { rounds } => Self::expand_key(passphrase, &encrypted.kdf_salt, rounds),
         };
 
-        let key = GenericArray::from_slice(key.as_ref());
+        let key = GenericArray::from_slice(key.expose());
 
         let decrypted = match encrypted.ciphertext_info {
             CipherTextInfo::Aes256Gcm { nonce, ciphertext } => {
                 let cipher = Aes256Gcm::new(key);
                 let nonce = GenericArray::from_slice(&nonce);
-                cipher.decrypt(nonce, ciphertext.as_ref())?
+                SecretVec::new(cipher.decrypt(nonce, ciphertext.as_ref())?)
             }
         };
 
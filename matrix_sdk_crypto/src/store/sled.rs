@@ -22,33 +22,82 @@ use std::{
 use dashmap::DashSet;
 use matrix_sdk_common::{async_trait, locks::Mutex, uuid};
 use olm_rs::{account::IdentityKeys, PicklingMode};
-use ruma::{events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, RoomId, UserId};
+use ruma::{
+    events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, EventId,
+    MilliSecondsSinceUnixEpoch, RoomId, UserId,
+};
 pub use sled::Error;
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
-    Config, Db, Transactional, Tree,
+    Config, Db, IVec, Transactional, Tree,
 };
 use uuid::Uuid;
 
+use self::{integrity::check_integrity, migrations::migrate};
+pub use self::{integrity::IntegrityReport, migrations::MigrationProgress};
 use super::{
-    caches::SessionStore, Changes, CryptoStore, CryptoStoreError, InboundGroupSession, PickleKey,
-    ReadOnlyAccount, Result, Session,
+    caches::SessionStore, unused_sessions, Changes, CryptoStore, CryptoStoreError,
+    InboundGroupSession, PickleKey, ReadOnlyAccount, Result, Session, SyncCheckpoint,
+    ToDeviceJournalEntry,
 };
 use crate::{
-    identities::{ReadOnlyDevice, UserIdentities},
+    diagnostics::RoomKeyCounts,
+    environment::timestamp,
+    identities::{ReadOnlyDevice, RejectedDevice, UserIdentities},
     key_request::OutgoingKeyRequest,
-    olm::{OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{
+        OutboundGroupSession, PickledInboundGroupSession, PickledSession,
+        PrivateCrossSigningIdentity,
+    },
+    utilities::parallel_map,
 };
 
+mod integrity;
+mod migrations;
+
+/// Format the time of a to-device journal entry for the time index, the time
+/// is zero padded so the keys sort chronologically.
+fn format_journal_time(time: MilliSecondsSinceUnixEpoch) -> String {
+    format!("{:020}", u64::from(time.get()))
+}
+
 /// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
 /// panic once we try to pickle a Signing object.
 const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
 
+/// The key prefix of the to-device journal entries in the account tree.
+const TO_DEVICE_JOURNAL: &str = "to_device_journal";
+/// The key prefix of the index that orders the to-device journal entries by
+/// the time they were processed, the values are the hashes of the entries.
+const TO_DEVICE_JOURNAL_BY_TIME: &str = "to_device_journal_by_time";
+
 trait EncodeKey {
     const SEPARATOR: u8 = 0xff;
     fn encode(&self) -> Vec<u8>;
 }
 
+/// Split a key that was created by [`EncodeKey`] into its parts.
+fn decode_key(key: &[u8]) -> Vec<String> {
+    key.split(|b| *b == <&str as EncodeKey>::SEPARATOR)
+        .filter(|p| !p.is_empty())
+        .map(|p| String::from_utf8_lossy(p).into_owned())
+        .collect()
+}
+
+/// The length of an unpadded base64 encoded Curve25519 key.
+const CURVE25519_KEY_LENGTH: usize = 43;
+
+/// Is the given key of the session tree the key of an Olm session.
+///
+/// If the state store and the crypto store share a database, the session tree
+/// holds the entries of the state store as well, e.g. the sync token.
+fn is_session_key(key: &[u8]) -> bool {
+    match decode_key(key).as_slice() {
+        [sender_key, _] => sender_key.len() == CURVE25519_KEY_LENGTH,
+        _ => false,
+    }
+}
+
 impl EncodeKey for Uuid {
     fn encode(&self) -> Vec<u8> {
         self.as_u128().to_be_bytes().to_vec()
@@ -109,6 +158,22 @@ impl EncodeKey for (&str, &str, &str) {
     }
 }
 
+impl EncodeKey for (&str, &str, &str, &str) {
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.0.as_bytes(),
+            &[Self::SEPARATOR],
+            self.1.as_bytes(),
+            &[Self::SEPARATOR],
+            self.2.as_bytes(),
+            &[Self::SEPARATOR],
+            self.3.as_bytes(),
+            &[Self::SEPARATOR],
+        ]
+        .concat()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AccountInfo {
     user_id: Arc<UserId>,
@@ -123,6 +188,7 @@ pub struct SledStore {
     path: Option<PathBuf>,
     inner: Db,
     pickle_key: Arc<PickleKey>,
+    integrity_report: Arc<IntegrityReport>,
 
     session_cache: SessionStore,
     tracked_users_cache: Arc<DashSet<UserId>>,
@@ -132,6 +198,7 @@ pub struct SledStore {
     private_identity: Tree,
 
     olm_hashes: Tree,
+    megolm_message_indices: Tree,
     sessions: Tree,
     inbound_group_sessions: Tree,
     outbound_group_sessions: Tree,
@@ -142,6 +209,7 @@ pub struct SledStore {
 
     devices: Tree,
     identities: Tree,
+    rejected_devices: Tree,
 
     tracked_users: Tree,
     users_for_key_query: Tree,
@@ -173,20 +241,76 @@ impl SledStore {
         let path = path.as_ref().join("matrix-sdk-crypto");
         let db = Config::new().temporary(false).path(&path).open()?;
 
-        SledStore::open_helper(db, Some(path), passphrase)
+        SledStore::open_helper(db, Some(path), passphrase, &mut |_| {})
+    }
+
+    /// Open the sled based cryptostore at the given path using the given
+    /// passphrase to encrypt private data.
+    ///
+    /// If the store was written by an older version of the library its schema
+    /// is migrated to the current version, the given callback is called after
+    /// every completed migration step.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk_crypto::store::SledStore;
+    /// let store = SledStore::open_with_migration_progress("/tmp/store", None, |p| {
+    ///     println!("Migrated to version {} of {}", p.current_version, p.to_version)
+    /// })
+    /// .unwrap();
+    /// ```
+    pub fn open_with_migration_progress(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        mut progress: impl FnMut(MigrationProgress),
+    ) -> Result<Self> {
+        let path = path.as_ref().join("matrix-sdk-crypto");
+        let db = Config::new().temporary(false).path(&path).open()?;
+
+        SledStore::open_helper(db, Some(path), passphrase, &mut progress)
     }
 
     /// Create a sled based cryptostore using the given sled database.
     /// The given passphrase will be used to encrypt private data.
     pub fn open_with_database(db: Db, passphrase: Option<&str>) -> Result<Self> {
-        SledStore::open_helper(db, None, passphrase)
+        SledStore::open_helper(db, None, passphrase, &mut |_| {})
+    }
+
+    /// Get the data that was lost because it was corrupted, found by the
+    /// integrity check that runs when the store is opened.
+    ///
+    /// The check that runs when the store is opened only reads the first
+    /// entries of every tree to find the trees that sled reports as
+    /// corrupted, see [`check_integrity()`](#method.check_integrity) for a
+    /// full check.
+    pub fn integrity_report(&self) -> &IntegrityReport {
+        &self.integrity_report
+    }
+
+    /// Check every entry of the store and remove the ones that can't be
+    /// deserialized anymore.
+    ///
+    /// Corrupted entries are removed from the store instead of making the
+    /// loading of the data they belong to fail. This reads the whole store, so
+    /// it should only be run if the store is suspected to be corrupted, e.g.
+    /// after a crash.
+    ///
+    /// Returns the data that was lost.
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        check_integrity(self, true)
     }
 
     fn get_account_info(&self) -> Option<AccountInfo> {
         self.account_info.read().unwrap().clone()
     }
 
-    fn open_helper(db: Db, path: Option<PathBuf>, passphrase: Option<&str>) -> Result<Self> {
+    fn open_helper(
+        db: Db,
+        path: Option<PathBuf>,
+        passphrase: Option<&str>,
+        progress: &mut dyn FnMut(MigrationProgress),
+    ) -> Result<Self> {
         let account = db.open_tree("account")?;
         let private_identity = db.open_tree("private_identity")?;
 
@@ -197,9 +321,11 @@ impl SledStore {
         let tracked_users = db.open_tree("tracked_users")?;
         let users_for_key_query = db.open_tree("users_for_key_query")?;
         let olm_hashes = db.open_tree("olm_hashes")?;
+        let megolm_message_indices = db.open_tree("megolm_message_indices")?;
 
         let devices = db.open_tree("devices")?;
         let identities = db.open_tree("identities")?;
+        let rejected_devices = db.open_tree("rejected_devices")?;
 
         let outgoing_key_requests = db.open_tree("outgoing_key_requests")?;
         let unsent_key_requests = db.open_tree("unsent_key_requests")?;
@@ -214,11 +340,12 @@ impl SledStore {
                 .expect("Can't create default pickle key")
         };
 
-        Ok(Self {
+        let mut store = Self {
             account_info: RwLock::new(None).into(),
             path,
             inner: db,
             pickle_key: pickle_key.into(),
+            integrity_report: IntegrityReport::default().into(),
             account,
             private_identity,
             sessions,
@@ -234,8 +361,15 @@ impl SledStore {
             tracked_users,
             users_for_key_query,
             olm_hashes,
+            megolm_message_indices,
             identities,
-        })
+            rejected_devices,
+        };
+
+        migrate(&store, progress)?;
+        store.integrity_report = check_integrity(&store, false)?.into();
+
+        Ok(store)
     }
 
     fn get_or_create_pickle_key(passphrase: &str, database: &Db) -> Result<PickleKey> {
@@ -259,7 +393,7 @@ impl SledStore {
     }
 
     fn get_pickle_key(&self) -> &[u8] {
-        self.pickle_key.key()
+        self.pickle_key.key().expose()
     }
 
     async fn load_tracked_users(&self) -> Result<()> {
@@ -327,17 +461,26 @@ impl SledStore {
             session_changes.insert(key, pickle);
         }
 
-        let mut inbound_session_changes = HashMap::new();
-
-        for session in changes.inbound_group_sessions {
-            let room_id = session.room_id();
-            let sender_key = session.sender_key();
-            let session_id = session.session_id();
-            let key = (room_id.as_str(), sender_key, session_id).encode();
-            let pickle = session.pickle(self.get_pickle_mode()).await;
-
-            inbound_session_changes.insert(key, pickle);
-        }
+        // Imports can save thousands of inbound group sessions at once, pickle
+        // them in parallel.
+        let pickle_key = self.pickle_key.clone();
+        let inbound_session_changes: HashMap<Vec<u8>, PickledInboundGroupSession> =
+            parallel_map(changes.inbound_group_sessions, move |session| {
+                let pickle_key = pickle_key.clone();
+
+                async move {
+                    let room_id = session.room_id();
+                    let sender_key = session.sender_key();
+                    let session_id = session.session_id();
+                    let key = (room_id.as_str(), sender_key, session_id).encode();
+                    let pickle = session.pickle(pickle_key.pickle_mode()).await;
+
+                    (key, pickle)
+                }
+            })
+            .await
+            .into_iter()
+            .collect();
 
         let mut outbound_session_changes = HashMap::new();
 
@@ -350,20 +493,28 @@ impl SledStore {
 
         let identity_changes = changes.identities;
         let olm_hashes = changes.message_hashes;
+        let hash_time = timestamp_bytes(timestamp());
+        let message_indices = changes.message_indices;
         let key_requests = changes.key_requests;
+        let tracked_users = changes.tracked_users;
+        let sync_checkpoint = changes.sync_checkpoint;
+        let to_device_journal = changes.to_device_journal;
 
         let ret: Result<(), TransactionError<serde_json::Error>> = (
             &self.account,
             &self.private_identity,
             &self.devices,
             &self.identities,
+            &self.rejected_devices,
             &self.sessions,
             &self.inbound_group_sessions,
             &self.outbound_group_sessions,
             &self.olm_hashes,
+            &self.megolm_message_indices,
             &self.outgoing_key_requests,
             &self.unsent_key_requests,
             &self.key_requests_by_info,
+            &self.tracked_users,
         )
             .transaction(
                 |(
@@ -371,13 +522,16 @@ impl SledStore {
                     private_identity,
                     devices,
                     identities,
+                    rejected_devices,
                     sessions,
                     inbound_sessions,
                     outbound_sessions,
                     hashes,
+                    indices,
                     outgoing_key_requests,
                     unsent_key_requests,
                     key_requests_by_info,
+                    tracked_users_tree,
                 )| {
                     if let Some(a) = &account_pickle {
                         account.insert(
@@ -386,6 +540,32 @@ impl SledStore {
                         )?;
                     }
 
+                    if let Some(c) = &sync_checkpoint {
+                        account.insert(
+                            "sync_checkpoint".encode(),
+                            serde_json::to_vec(c).map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
+                    // The journal lives in the account tree, a transaction
+                    // can't span more than 14 trees.
+                    for entry in &to_device_journal {
+                        account.insert(
+                            (TO_DEVICE_JOURNAL, entry.hash.as_str()).encode(),
+                            serde_json::to_vec(entry)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                        account.insert(
+                            (
+                                TO_DEVICE_JOURNAL_BY_TIME,
+                                format_journal_time(entry.processed_at).as_str(),
+                                entry.hash.as_str(),
+                            )
+                                .encode(),
+                            entry.hash.as_bytes(),
+                        )?;
+                    }
+
                     if let Some(i) = &private_identity_pickle {
                         private_identity.insert(
                             "identity".encode(),
@@ -393,10 +573,18 @@ impl SledStore {
                         )?;
                     }
 
+                    for device in &device_changes.rejected {
+                        let key = (device.user_id().as_str(), device.device_id().as_str()).encode();
+                        let device = serde_json::to_vec(&device)
+                            .map_err(ConflictableTransactionError::Abort)?;
+                        rejected_devices.insert(key, device)?;
+                    }
+
                     for device in device_changes.new.iter().chain(&device_changes.changed) {
                         let key = (device.user_id().as_str(), device.device_id().as_str()).encode();
                         let device = serde_json::to_vec(&device)
                             .map_err(ConflictableTransactionError::Abort)?;
+                        rejected_devices.remove(key.clone())?;
                         devices.insert(key, device)?;
                     }
 
@@ -441,10 +629,23 @@ impl SledStore {
                         hashes.insert(
                             serde_json::to_vec(&hash)
                                 .map_err(ConflictableTransactionError::Abort)?,
-                            &[0],
+                            &hash_time,
                         )?;
                     }
 
+                    for index in &message_indices {
+                        let message_index = index.message_index.to_string();
+                        let key = (
+                            index.room_id.as_str(),
+                            index.sender_key.as_str(),
+                            index.session_id.as_str(),
+                            message_index.as_str(),
+                        )
+                            .encode();
+
+                        indices.insert(key, index.event_id.as_str())?;
+                    }
+
                     for key_request in &key_requests {
                         key_requests_by_info.insert(
                             (&key_request.info).encode(),
@@ -470,16 +671,54 @@ impl SledStore {
                         }
                     }
 
+                    for (user, dirty) in &tracked_users {
+                        tracked_users_tree.insert(user.as_str(), &[*dirty as u8])?;
+                    }
+
                     Ok(())
                 },
             );
 
         ret?;
+
         self.inner.flush_async().await?;
 
+        for (user, dirty) in tracked_users {
+            if dirty {
+                self.users_for_key_query_cache.insert(user.clone());
+            } else {
+                self.users_for_key_query_cache.remove(&user);
+            }
+
+            self.tracked_users_cache.insert(user);
+        }
+
         Ok(())
     }
 
+    /// Remove the entries of the to-device journal that were processed before
+    /// the given time, using the index that orders them by time.
+    ///
+    /// Returns the number of removed entries.
+    fn prune_to_device_journal(&self, time: MilliSecondsSinceUnixEpoch) -> Result<usize> {
+        let start = TO_DEVICE_JOURNAL_BY_TIME.encode();
+        let end = (TO_DEVICE_JOURNAL_BY_TIME, format_journal_time(time).as_str()).encode();
+        let mut removed = 0;
+
+        for entry in self.account.range(start..end) {
+            let (key, hash) = entry?;
+            let hash = String::from_utf8_lossy(&hash);
+
+            self.account.remove(key)?;
+
+            if self.account.remove((TO_DEVICE_JOURNAL, hash.as_ref()).encode())?.is_some() {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
     async fn get_outgoing_key_request_helper(
         &self,
         id: &[u8],
@@ -495,6 +734,15 @@ impl SledStore {
 
         Ok(request)
     }
+
+    fn to_device_journal_entries(
+        &self,
+    ) -> impl Iterator<Item = Result<(IVec, ToDeviceJournalEntry)>> {
+        self.account.scan_prefix(TO_DEVICE_JOURNAL.encode()).map(|entry| {
+            let (key, entry) = entry?;
+            Ok((key, serde_json::from_slice(&entry)?))
+        })
+    }
 }
 
 #[async_trait]
@@ -535,6 +783,14 @@ impl CryptoStore for SledStore {
         self.save_changes(changes).await
     }
 
+    async fn load_sync_checkpoint(&self) -> Result<Option<SyncCheckpoint>> {
+        Ok(self
+            .account
+            .get("sync_checkpoint".encode())?
+            .map(|c| serde_json::from_slice(&c))
+            .transpose()?)
+    }
+
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
         if let Some(i) = self.private_identity.get("identity".encode())? {
             let pickle = serde_json::from_slice(&i)?;
@@ -601,10 +857,32 @@ impl CryptoStore for SledStore {
             .map(|p| serde_json::from_slice(&p?.1).map_err(CryptoStoreError::Serialization))
             .collect();
 
-        Ok(pickles?
-            .into_iter()
-            .filter_map(|p| InboundGroupSession::from_pickle(p, self.get_pickle_mode()).ok())
-            .collect())
+        let pickle_key = self.pickle_key.clone();
+
+        Ok(parallel_map(pickles?, move |p| {
+            let session = InboundGroupSession::from_pickle(p, pickle_key.pickle_mode()).ok();
+            async move { session }
+        })
+        .await
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let mut counts = RoomKeyCounts::default();
+
+        for entry in self.inbound_group_sessions.iter() {
+            let pickle: PickledInboundGroupSession = serde_json::from_slice(&entry?.1)?;
+
+            counts.total += 1;
+
+            if pickle.backed_up {
+                counts.backed_up += 1;
+            }
+        }
+
+        Ok(counts)
     }
 
     async fn get_outbound_group_sessions(
@@ -627,6 +905,11 @@ impl CryptoStore for SledStore {
         self.users_for_key_query_cache.iter().map(|u| u.clone()).collect()
     }
 
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users_cache.iter().map(|u| u.clone()).collect()
+    }
+
     async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
         let already_added = self.tracked_users_cache.insert(user.clone());
 
@@ -669,6 +952,23 @@ impl CryptoStore for SledStore {
             .collect()
     }
 
+    async fn get_rejected_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<RejectedDevice>> {
+        let key = (user_id.as_str(), device_id.as_str()).encode();
+
+        Ok(self.rejected_devices.get(key)?.map(|d| serde_json::from_slice(&d)).transpose()?)
+    }
+
+    async fn get_rejected_devices(&self) -> Result<Vec<RejectedDevice>> {
+        self.rejected_devices
+            .iter()
+            .map(|d| serde_json::from_slice(&d?.1).map_err(CryptoStoreError::Serialization))
+            .collect()
+    }
+
     async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentities>> {
         Ok(self
             .identities
@@ -681,6 +981,37 @@ impl CryptoStore for SledStore {
         Ok(self.olm_hashes.contains_key(serde_json::to_vec(message_hash)?)?)
     }
 
+    async fn is_to_device_event_processed(&self, hash: &str) -> Result<bool> {
+        Ok(self.account.contains_key((TO_DEVICE_JOURNAL, hash).encode())?)
+    }
+
+    async fn get_to_device_journal(&self) -> Result<Vec<ToDeviceJournalEntry>> {
+        let mut journal = self
+            .to_device_journal_entries()
+            .map(|e| e.map(|(_, e)| e))
+            .collect::<Result<Vec<_>>>()?;
+        journal.sort_by_key(|e| e.processed_at);
+
+        Ok(journal)
+    }
+
+    async fn get_event_for_message_index(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<EventId>> {
+        let message_index = message_index.to_string();
+        let key = (room_id.as_str(), sender_key, session_id, message_index.as_str()).encode();
+
+        Ok(self
+            .megolm_message_indices
+            .get(key)?
+            .map(|e| EventId::try_from(String::from_utf8_lossy(&e).to_string()))
+            .transpose()?)
+    }
+
     async fn get_outgoing_key_request(
         &self,
         request_id: Uuid,
@@ -747,6 +1078,87 @@ impl CryptoStore for SledStore {
 
         Ok(())
     }
+
+    async fn remove_message_hashes_older_than(
+        &self,
+        time: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize> {
+        let time = timestamp_bytes(time);
+        let now = timestamp_bytes(timestamp());
+        let mut removed = 0;
+
+        for entry in self.olm_hashes.iter() {
+            let (hash, received) = entry?;
+
+            if received.len() != now.len() {
+                // Hashes that were stored before the time of the message was
+                // recorded are kept for a whole retention period from now on.
+                self.olm_hashes.insert(hash, &now)?;
+            } else if received.as_ref() < &time[..] {
+                self.olm_hashes.remove(hash)?;
+                removed += 1;
+            }
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(removed)
+    }
+
+    async fn remove_to_device_journal_entries_older_than(
+        &self,
+        time: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize> {
+        let removed = self.prune_to_device_journal(time)?;
+        self.inner.flush_async().await?;
+
+        Ok(removed)
+    }
+
+    async fn remove_unused_sessions(
+        &self,
+        unused_since: MilliSecondsSinceUnixEpoch,
+    ) -> Result<usize> {
+        let sessions: Result<Vec<(String, String, MilliSecondsSinceUnixEpoch)>> = self
+            .sessions
+            .iter()
+            .filter(|entry| entry.as_ref().map_or(true, |(key, _)| is_session_key(key)))
+            .map(|entry| {
+                let (key, pickle) = entry?;
+                let pickle: PickledSession = serde_json::from_slice(&pickle)?;
+                let session_id = decode_key(&key).into_iter().nth(1).unwrap_or_default();
+
+                Ok((pickle.sender_key, session_id, pickle.last_used_at))
+            })
+            .collect();
+
+        let mut removed = 0;
+
+        for (sender_key, session_ids) in unused_sessions(sessions?, unused_since) {
+            for session_id in &session_ids {
+                self.sessions.remove((sender_key.as_str(), session_id.as_str()).encode())?;
+            }
+
+            self.session_cache.remove(&sender_key, &session_ids).await;
+            removed += session_ids.len();
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+}
+
+/// The big endian bytes of the given timestamp, they sort like the timestamps
+/// themselves.
+fn timestamp_bytes(time: MilliSecondsSinceUnixEpoch) -> [u8; 8] {
+    u64::from(time.get()).to_be_bytes()
 }
 
 #[cfg(test)]
@@ -759,21 +1171,27 @@ mod test {
     use ruma::{
         api::client::r0::keys::SignedKey,
         events::room_key_request::RequestedKeyInfo,
-        identifiers::{room_id, user_id, DeviceId, EventEncryptionAlgorithm, UserId},
+        identifiers::{event_id, room_id, user_id, DeviceId, EventEncryptionAlgorithm, UserId},
+        MilliSecondsSinceUnixEpoch, UInt,
     };
     use tempfile::tempdir;
 
-    use super::{CryptoStore, OutgoingKeyRequest, SledStore};
+    use super::{
+        migrations::{load_version, save_version, DATABASE_VERSION},
+        CryptoStore, CryptoStoreError, EncodeKey, OutgoingKeyRequest, SledStore,
+        TO_DEVICE_JOURNAL_BY_TIME,
+    };
     use crate::{
+        environment::timestamp,
         identities::{
             device::test::get_device,
             user::test::{get_other_identity, get_own_identity},
         },
         olm::{
-            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
-            ReadOnlyAccount, Session,
+            GroupSessionKey, InboundGroupSession, MegolmMessageIndex, OlmMessageHash,
+            PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
         },
-        store::{Changes, DeviceChanges, IdentityChanges},
+        store::{Changes, CryptoStoreDump, DeviceChanges, IdentityChanges, ToDeviceJournalEntry},
     };
 
     fn alice_id() -> UserId {
@@ -836,6 +1254,33 @@ mod test {
         let _ = SledStore::open_with_passphrase(tmpdir_path, None).expect("Can't create store");
     }
 
+    #[async_test]
+    async fn store_migration() {
+        let (_account, store, dir) = get_loaded_store().await;
+        assert_eq!(load_version(&store.inner).unwrap(), Some(DATABASE_VERSION));
+
+        // Pretend that the store was created before the schema was versioned.
+        store.inner.remove("crypto_store_version").unwrap();
+        drop(store);
+
+        let mut steps = Vec::new();
+        let store = SledStore::open_with_migration_progress(dir.path(), None, |p| steps.push(p))
+            .expect("Can't migrate the store");
+
+        assert_eq!(load_version(&store.inner).unwrap(), Some(DATABASE_VERSION));
+        assert_eq!(steps.len(), DATABASE_VERSION as usize);
+        assert!(steps.iter().all(|p| p.from_version == 0 && p.to_version == DATABASE_VERSION));
+        assert!(store.load_account().await.unwrap().is_some());
+
+        save_version(&store.inner, DATABASE_VERSION + 1).unwrap();
+        drop(store);
+
+        assert!(matches!(
+            SledStore::open_with_passphrase(dir.path(), None),
+            Err(CryptoStoreError::UnsupportedVersion(..))
+        ));
+    }
+
     #[async_test]
     async fn save_account() {
         let (store, _dir) = get_store(None).await;
@@ -907,6 +1352,23 @@ mod test {
         assert_eq!(&session, &loaded_session);
     }
 
+    #[async_test]
+    async fn unused_sessions_with_shared_tree() {
+        let (store, _dir) = get_store(None).await;
+        let (account, session) = get_account_and_session().await;
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        // The state store keeps its sync token in the same tree if both
+        // stores use the same database.
+        store.sessions.insert("sync_token".encode(), "s72594_4483_1934").unwrap();
+
+        assert_eq!(store.remove_unused_sessions(timestamp()).await.unwrap(), 0);
+        assert!(store.sessions.get("sync_token".encode()).unwrap().is_some());
+    }
+
     #[async_test]
     async fn add_and_save_session() {
         let (store, dir) = get_store(None).await;
@@ -1033,6 +1495,84 @@ mod test {
         assert!(!store.users_for_key_query().contains(device.user_id()));
     }
 
+    #[async_test]
+    async fn tracked_users_saving() {
+        let (_account, store, dir) = get_loaded_store().await;
+        let device = get_device();
+
+        let mut changes = Changes {
+            devices: DeviceChanges { changed: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        changes.tracked_users.insert(device.user_id().clone(), true);
+
+        store.save_changes(changes).await.unwrap();
+
+        assert!(store.is_user_tracked(device.user_id()));
+        assert!(store.users_for_key_query().contains(device.user_id()));
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        store.load_account().await.unwrap();
+
+        assert!(store.is_user_tracked(device.user_id()));
+        assert!(store.users_for_key_query().contains(device.user_id()));
+        assert!(store.get_device(device.user_id(), device.device_id()).await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn export_and_import_all() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let device = get_device();
+
+        let identity_keys = account.identity_keys();
+        let outbound_session = OlmOutboundGroupSession::new();
+        let session = InboundGroupSession::new(
+            identity_keys.curve25519(),
+            identity_keys.ed25519(),
+            &room_id!("!test:localhost"),
+            GroupSessionKey(outbound_session.session_key()),
+            None,
+        )
+        .expect("Can't create session");
+
+        let mut changes = Changes {
+            inbound_group_sessions: vec![session.clone()],
+            devices: DeviceChanges { new: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        changes.tracked_users.insert(device.user_id().clone(), true);
+        store.save_changes(changes).await.unwrap();
+
+        let dump = store.export_all("secret").await.unwrap();
+        let dump: CryptoStoreDump =
+            serde_json::from_str(&serde_json::to_string(&dump).unwrap()).unwrap();
+
+        let (other_store, _other_dir) = get_store(None).await;
+        other_store.import_all(dump, "secret").await.unwrap();
+
+        assert_eq!(other_store.load_account().await.unwrap().unwrap(), account);
+        assert!(other_store
+            .get_inbound_group_session(
+                session.room_id(),
+                session.sender_key(),
+                session.session_id()
+            )
+            .await
+            .unwrap()
+            .is_some());
+        assert!(other_store
+            .get_device(device.user_id(), device.device_id())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(other_store.users_for_key_query().contains(device.user_id()));
+
+        let dump = store.export_all("secret").await.unwrap();
+        assert!(other_store.import_all(dump, "wrong secret").await.is_err());
+    }
+
     #[async_test]
     async fn device_saving() {
         let (_account, store, dir) = get_loaded_store().await;
@@ -1067,6 +1607,52 @@ mod test {
         assert_eq!(user_devices.values().next().unwrap(), &device);
     }
 
+    #[async_test]
+    async fn corrupted_entries_are_removed() {
+        let (account, store, dir) = get_loaded_store().await;
+        let device = get_device();
+
+        let changes = Changes {
+            devices: DeviceChanges { changed: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+
+        store.save_changes(changes).await.unwrap();
+
+        assert!(store.integrity_report().is_empty());
+
+        let sender_key = account.identity_keys().curve25519();
+        store.sessions.insert((sender_key, "SESSIONID").encode(), b"{ garbage".to_vec()).unwrap();
+        store.devices.insert(("@bob:example.org", "BOBDEVICE").encode(), b"[]".to_vec()).unwrap();
+        // The state store keeps its sync token in the same tree if both
+        // stores use the same database.
+        store.sessions.insert("sync_token".encode(), "s72594_4483_1934").unwrap();
+        store.inner.flush_async().await.unwrap();
+
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        // Only the full check looks at the entries themselves.
+        assert!(store.integrity_report().is_empty());
+
+        let report = store.check_integrity().unwrap();
+        assert_eq!(report.sessions, vec![(sender_key.to_owned(), "SESSIONID".to_owned())]);
+        assert_eq!(report.devices, vec![("@bob:example.org".to_owned(), "BOBDEVICE".to_owned())]);
+        assert!(report.other_entries.is_empty());
+
+        store.load_account().await.unwrap();
+
+        assert!(store.get_sessions(sender_key).await.unwrap().unwrap().lock().await.is_empty());
+        assert_eq!(
+            store.get_device(device.user_id(), device.device_id()).await.unwrap(),
+            Some(device)
+        );
+
+        assert!(store.check_integrity().unwrap().is_empty());
+        assert!(store.sessions.get("sync_token".encode()).unwrap().is_some());
+    }
+
     #[async_test]
     async fn device_deleting() {
         let (_account, store, dir) = get_loaded_store().await;
@@ -1167,6 +1753,39 @@ mod test {
         assert!(loaded_user.own().unwrap().is_verified())
     }
 
+    #[async_test]
+    async fn to_device_journal_pruning() {
+        let (_, store, _dir) = get_loaded_store().await;
+
+        let entry = |hash: &str, processed_at: MilliSecondsSinceUnixEpoch| ToDeviceJournalEntry {
+            hash: hash.to_owned(),
+            event_type: "m.room.encrypted".to_owned(),
+            sender: alice_id(),
+            sync_token: "s1".to_owned(),
+            processed_at,
+        };
+
+        let now = u64::from(timestamp().get());
+        let expired = MilliSecondsSinceUnixEpoch(UInt::new(now - 60_000).unwrap());
+        let recent = MilliSecondsSinceUnixEpoch(UInt::new(now + 60_000).unwrap());
+
+        let changes = Changes {
+            to_device_journal: vec![entry("expired", expired), entry("recent", recent)],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        assert_eq!(store.get_to_device_journal().await.unwrap().len(), 2);
+
+        // The entries are pruned using the index that orders them by time.
+        assert_eq!(
+            store.remove_to_device_journal_entries_older_than(timestamp()).await.unwrap(),
+            1
+        );
+        assert!(!store.is_to_device_event_processed("expired").await.unwrap());
+        assert!(store.is_to_device_event_processed("recent").await.unwrap());
+        assert_eq!(store.account.scan_prefix(TO_DEVICE_JOURNAL_BY_TIME.encode()).count(), 1);
+    }
+
     #[async_test]
     async fn private_identity_saving() {
         let (_, store, _dir) = get_loaded_store().await;
@@ -1195,6 +1814,44 @@ mod test {
         assert!(store.is_message_known(&hash).await.unwrap());
     }
 
+    #[async_test]
+    async fn megolm_message_index_saving() {
+        let (_, store, dir) = get_loaded_store().await;
+        let room_id = room_id!("!test:localhost");
+
+        let index = MegolmMessageIndex {
+            room_id: room_id.clone(),
+            sender_key: "test_sender".to_owned(),
+            session_id: "test_session".to_owned(),
+            message_index: 1,
+            event_id: event_id!("$test:localhost"),
+        };
+
+        let mut changes = Changes::default();
+        changes.message_indices.push(index.clone());
+
+        assert!(store
+            .get_event_for_message_index(&room_id, "test_sender", "test_session", 1)
+            .await
+            .unwrap()
+            .is_none());
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        let event_id = store
+            .get_event_for_message_index(&room_id, "test_sender", "test_session", 1)
+            .await
+            .unwrap();
+        assert_eq!(event_id, Some(index.event_id));
+        assert!(store
+            .get_event_for_message_index(&room_id, "test_sender", "test_session", 2)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
     #[async_test]
     async fn key_request_saving() {
         let (account, store, _dir) = get_loaded_store().await;
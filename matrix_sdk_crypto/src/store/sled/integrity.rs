@@ -0,0 +1,224 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The integrity check of the sled based crypto store.
+//!
+//! The first entries of every tree are read when the store is opened, to find
+//! trees that sled reports as corrupted. The full check reads every entry of
+//! the store and removes the ones that can't be deserialized anymore, it needs
+//! to be requested explicitly using [`SledStore::check_integrity()`].
+//! Everything that was removed is listed in an [`IntegrityReport`].
+//!
+//! Corrupted trees are only reported, never cleared, their entries can't be
+//! fetched again and the database might be shared with the state store.
+//!
+//! The account and the private cross signing identity aren't checked, they
+//! can't be recovered and a corrupted account makes loading the account fail.
+
+use std::convert::TryFrom;
+
+use ruma::UserId;
+use serde::de::DeserializeOwned;
+use sled::{IVec, Tree};
+use tracing::warn;
+
+use super::{decode_key, is_session_key, Result, SledStore};
+use crate::{
+    identities::{ReadOnlyDevice, RejectedDevice, UserIdentities},
+    key_request::OutgoingKeyRequest,
+    olm::{PickledInboundGroupSession, PickledOutboundGroupSession, PickledSession},
+};
+
+/// The data that was lost because it was corrupted, found by the integrity
+/// check when the store was opened.
+///
+/// The ids of the lost entries are taken from the keys of the store, they
+/// might be garbled if the keys themselves were corrupted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The lost Olm sessions, as `(sender_key, session_id)` pairs.
+    pub sessions: Vec<(String, String)>,
+    /// The lost inbound group sessions, as `(room_id, sender_key, session_id)`
+    /// tuples.
+    pub inbound_group_sessions: Vec<(String, String, String)>,
+    /// The rooms whose outbound group session was lost, a new one is created
+    /// the next time a message is sent.
+    pub outbound_group_sessions: Vec<String>,
+    /// The lost devices, as `(user_id, device_id)` pairs. They are fetched
+    /// again once their user is marked for a key query.
+    pub devices: Vec<(String, String)>,
+    /// The users whose identity was lost.
+    pub identities: Vec<String>,
+    /// The other lost entries, e.g. key requests or tracked users, as `(tree,
+    /// key)` pairs, the key is split up into its parts.
+    pub other_entries: Vec<(String, Vec<String>)>,
+    /// The trees that sled reported as corrupted. They are left as they are,
+    /// the entries that come after the corruption couldn't be checked.
+    pub corrupted_trees: Vec<String>,
+}
+
+impl IntegrityReport {
+    /// Was nothing lost.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+fn key_part(parts: &[String], index: usize) -> String {
+    parts.get(index).cloned().unwrap_or_default()
+}
+
+/// The number of entries of every tree that the check which runs when the
+/// store is opened reads.
+const SAMPLE_SIZE: usize = 100;
+
+struct Checker {
+    /// Should every entry be checked, or only a sample of every tree.
+    full: bool,
+    /// The names of the trees that sled reported as corrupted.
+    corrupted_trees: Vec<String>,
+}
+
+impl Checker {
+    /// Walk the given tree and, for a full check, check every entry of the
+    /// tree with the given check, remove the ones that fail and return their
+    /// keys.
+    ///
+    /// If the tree itself is corrupted it's recorded and left alone, only the
+    /// entries that failed the check until then are removed.
+    fn check_tree(
+        &mut self,
+        tree: &Tree,
+        name: &str,
+        check: impl Fn(&IVec, &IVec) -> bool,
+    ) -> Result<Vec<IVec>> {
+        let mut lost = Vec::new();
+        let limit = if self.full { usize::MAX } else { SAMPLE_SIZE };
+
+        for entry in tree.iter().take(limit) {
+            match entry {
+                Ok((key, value)) => {
+                    if self.full && !check(&key, &value) {
+                        lost.push(key);
+                    }
+                }
+                Err(sled::Error::Corruption { .. }) => {
+                    warn!("The {} tree of the crypto store is corrupted", name);
+                    self.corrupted_trees.push(name.to_owned());
+
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for key in &lost {
+            tree.remove(key)?;
+        }
+
+        if !lost.is_empty() {
+            warn!(
+                "Removed {} corrupted entries from the {} tree of the crypto store",
+                lost.len(),
+                name
+            );
+        }
+
+        Ok(lost)
+    }
+}
+
+fn deserializes<T: DeserializeOwned>(_: &IVec, value: &IVec) -> bool {
+    serde_json::from_slice::<T>(value).is_ok()
+}
+
+/// Check the trees of the given store, and all of their entries if `full` is
+/// set, and remove the corrupted entries.
+pub(super) fn check_integrity(store: &SledStore, full: bool) -> Result<IntegrityReport> {
+    let mut checker = Checker { full, corrupted_trees: Vec::new() };
+
+    // Entries of the state store in a shared session tree are left alone.
+    let lost = checker.check_tree(&store.sessions, "session", |key, value| {
+        !is_session_key(key) || deserializes::<PickledSession>(key, value)
+    })?;
+    let sessions =
+        lost.iter().map(|k| decode_key(k)).map(|p| (key_part(&p, 0), key_part(&p, 1))).collect();
+
+    let lost = checker.check_tree(
+        &store.inbound_group_sessions,
+        "inbound_group_sessions",
+        deserializes::<PickledInboundGroupSession>,
+    )?;
+    let inbound_group_sessions = lost
+        .iter()
+        .map(|k| decode_key(k))
+        .map(|p| (key_part(&p, 0), key_part(&p, 1), key_part(&p, 2)))
+        .collect();
+
+    let lost = checker.check_tree(
+        &store.outbound_group_sessions,
+        "outbound_group_sessions",
+        deserializes::<PickledOutboundGroupSession>,
+    )?;
+    let outbound_group_sessions =
+        lost.iter().map(|k| decode_key(k)).map(|p| key_part(&p, 0)).collect();
+
+    let lost = checker.check_tree(&store.devices, "devices", deserializes::<ReadOnlyDevice>)?;
+    let devices =
+        lost.iter().map(|k| decode_key(k)).map(|p| (key_part(&p, 0), key_part(&p, 1))).collect();
+
+    let lost =
+        checker.check_tree(&store.identities, "identities", deserializes::<UserIdentities>)?;
+    let identities = lost.iter().map(|k| decode_key(k)).map(|p| key_part(&p, 0)).collect();
+
+    let mut other = Vec::new();
+    let mut check_other =
+        |tree: &Tree, name: &str, check: &dyn Fn(&IVec, &IVec) -> bool| -> Result<()> {
+            let lost = checker.check_tree(tree, name, check)?;
+            other.extend(lost.iter().map(|k| (name.to_owned(), decode_key(k))));
+
+            Ok(())
+        };
+
+    check_other(&store.rejected_devices, "rejected_devices", &deserializes::<RejectedDevice>)?;
+    check_other(
+        &store.outgoing_key_requests,
+        "outgoing_key_requests",
+        &deserializes::<OutgoingKeyRequest>,
+    )?;
+    check_other(
+        &store.unsent_key_requests,
+        "unsent_key_requests",
+        &deserializes::<OutgoingKeyRequest>,
+    )?;
+    check_other(&store.tracked_users, "tracked_users", &|key, _| {
+        UserId::try_from(String::from_utf8_lossy(key).into_owned()).is_ok()
+    })?;
+
+    let report = IntegrityReport {
+        sessions,
+        inbound_group_sessions,
+        outbound_group_sessions,
+        devices,
+        identities,
+        other_entries: other,
+        corrupted_trees: checker.corrupted_trees,
+    };
+
+    if !report.is_empty() {
+        store.inner.flush()?;
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,103 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Schema migrations of the sled based crypto store.
+//!
+//! Every store records the version of the schema it was written with. Opening
+//! a store with an older schema version runs the migrations from that version
+//! up to the current one, each migration is followed by an update of the
+//! recorded version so an interrupted upgrade continues where it stopped.
+
+use sled::Db;
+use tracing::info;
+
+use super::{CryptoStoreError, Result, SledStore};
+
+/// The key the schema version is stored under, the database might be shared
+/// with the state store so the key needs to be specific to the crypto store.
+const VERSION_KEY: &str = "crypto_store_version";
+
+/// The schema version of the store this version of the library writes.
+pub(super) const DATABASE_VERSION: u8 = 1;
+
+type Migration = fn(&SledStore) -> Result<()>;
+
+/// The list of migrations, the migration at index `i` upgrades the store from
+/// version `i` to version `i + 1`.
+const MIGRATIONS: [Migration; DATABASE_VERSION as usize] = [migrate_to_v1];
+
+/// The progress of a running migration of the crypto store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// The schema version the store had when it was opened.
+    pub from_version: u8,
+    /// The schema version the store is being migrated to.
+    pub to_version: u8,
+    /// The schema version the store has been migrated to so far.
+    pub current_version: u8,
+}
+
+pub(super) fn load_version(db: &Db) -> Result<Option<u8>> {
+    Ok(db.get(VERSION_KEY)?.map(|v| v.first().copied().unwrap_or_default()))
+}
+
+pub(super) fn save_version(db: &Db, version: u8) -> Result<()> {
+    db.insert(VERSION_KEY, &[version])?;
+    Ok(())
+}
+
+/// Stores that were created before the schema version was recorded don't have
+/// a version, their format is the same as the one of version 1.
+fn migrate_to_v1(_: &SledStore) -> Result<()> {
+    Ok(())
+}
+
+/// Bring the schema of the given store up to date.
+///
+/// The `progress` callback is called after every completed migration step.
+pub(super) fn migrate(
+    store: &SledStore,
+    progress: &mut dyn FnMut(MigrationProgress),
+) -> Result<()> {
+    let version = match load_version(&store.inner)? {
+        Some(v) => v,
+        // A store without an account is a new one, there's nothing to migrate.
+        None if store.account.is_empty() => {
+            return save_version(&store.inner, DATABASE_VERSION);
+        }
+        None => 0,
+    };
+
+    if version > DATABASE_VERSION {
+        return Err(CryptoStoreError::UnsupportedVersion(version, DATABASE_VERSION));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = from as u8 + 1;
+
+        info!("Migrating the crypto store from version {} to {}", from, to);
+
+        migration(store)?;
+        save_version(&store.inner, to)?;
+        store.inner.flush()?;
+
+        progress(MigrationProgress {
+            from_version: version,
+            to_version: DATABASE_VERSION,
+            current_version: to,
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,230 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read-only snapshots of the non-secret metadata of a crypto store.
+//!
+//! The entries of a snapshot only ever contain public information, e.g. the
+//! public keys of devices or the ids of sessions. Private keys, pickles or
+//! session keys are never part of a snapshot, which makes it safe to hand the
+//! snapshot to external audit or automation tools.
+
+use std::collections::BTreeMap;
+
+#[cfg(not(target_arch = "wasm32"))]
+use futures::stream::BoxStream;
+#[cfg(target_arch = "wasm32")]
+use futures::stream::LocalBoxStream;
+use futures::stream::{self, Stream, StreamExt};
+use ruma::{
+    encryption::DeviceKeyAlgorithm, events::room::encrypted::EventEncryptionAlgorithm, DeviceIdBox,
+    DeviceKeyId, RoomId, UserId,
+};
+
+use super::{CryptoStore, Result};
+use crate::{
+    identities::{LocalTrust, ReadOnlyDevice, UserIdentities},
+    olm::{InboundGroupSession, Session},
+};
+
+/// A stream of snapshot entries, returned by
+/// [`CryptoStore::export_snapshot()`](trait.CryptoStore.html#method.
+/// export_snapshot).
+#[cfg(not(target_arch = "wasm32"))]
+pub type SnapshotStream<'a> = BoxStream<'a, Result<SnapshotEntry>>;
+
+/// A stream of snapshot entries, returned by
+/// [`CryptoStore::export_snapshot()`](trait.CryptoStore.html#method.
+/// export_snapshot).
+#[cfg(target_arch = "wasm32")]
+pub type SnapshotStream<'a> = LocalBoxStream<'a, Result<SnapshotEntry>>;
+
+/// A single entry of a crypto store snapshot.
+#[derive(Clone, Debug)]
+pub enum SnapshotEntry {
+    /// The metadata of a device of a tracked user.
+    Device(DeviceSnapshot),
+    /// The metadata of the cross signing identity of a tracked user.
+    Identity(IdentitySnapshot),
+    /// The metadata of an Olm session with a device of a tracked user.
+    Session(SessionSnapshot),
+    /// The metadata of an inbound Megolm session.
+    InboundGroupSession(InboundGroupSessionSnapshot),
+}
+
+/// The public metadata of a device.
+#[derive(Clone, Debug)]
+pub struct DeviceSnapshot {
+    /// The user the device belongs to.
+    pub user_id: UserId,
+    /// The unique id of the device.
+    pub device_id: DeviceIdBox,
+    /// The human readable name of the device.
+    pub display_name: Option<String>,
+    /// The encryption algorithms the device supports.
+    pub algorithms: Vec<EventEncryptionAlgorithm>,
+    /// The public identity keys of the device.
+    pub keys: BTreeMap<DeviceKeyId, String>,
+    /// The local trust state of the device.
+    pub local_trust: LocalTrust,
+    /// Was the device deleted by its owner.
+    pub deleted: bool,
+}
+
+impl From<&ReadOnlyDevice> for DeviceSnapshot {
+    fn from(device: &ReadOnlyDevice) -> Self {
+        Self {
+            user_id: device.user_id().clone(),
+            device_id: device.device_id().into(),
+            display_name: device.display_name().clone(),
+            algorithms: device.algorithms().to_vec(),
+            keys: device.keys().clone(),
+            local_trust: device.local_trust_state(),
+            deleted: device.deleted(),
+        }
+    }
+}
+
+/// The public metadata of a cross signing identity.
+#[derive(Clone, Debug)]
+pub struct IdentitySnapshot {
+    /// The user the identity belongs to.
+    pub user_id: UserId,
+    /// Is this the identity of our own user.
+    pub is_own: bool,
+    /// The public master keys of the identity.
+    pub master_key: BTreeMap<String, String>,
+    /// The public self-signing keys of the identity.
+    pub self_signing_key: BTreeMap<String, String>,
+}
+
+impl From<&UserIdentities> for IdentitySnapshot {
+    fn from(identity: &UserIdentities) -> Self {
+        Self {
+            user_id: identity.user_id().clone(),
+            is_own: identity.own().is_some(),
+            master_key: identity.master_key().as_ref().keys.clone(),
+            self_signing_key: identity.self_signing_key().as_ref().keys.clone(),
+        }
+    }
+}
+
+/// The metadata of an Olm session, without the session pickle.
+#[derive(Clone, Debug)]
+pub struct SessionSnapshot {
+    /// The user owning the device the session was established with.
+    pub user_id: UserId,
+    /// The device the session was established with.
+    pub device_id: DeviceIdBox,
+    /// The curve25519 key of the other side of the session.
+    pub sender_key: String,
+    /// The unique id of the session.
+    pub session_id: String,
+}
+
+impl SessionSnapshot {
+    fn new(device: &ReadOnlyDevice, session: &Session) -> Self {
+        Self {
+            user_id: device.user_id().clone(),
+            device_id: device.device_id().into(),
+            sender_key: session.sender_key().to_owned(),
+            session_id: session.session_id().to_owned(),
+        }
+    }
+}
+
+/// The metadata of an inbound Megolm session, without the session key.
+#[derive(Clone, Debug)]
+pub struct InboundGroupSessionSnapshot {
+    /// The room the session is used in.
+    pub room_id: RoomId,
+    /// The curve25519 key of the device that created the session.
+    pub sender_key: String,
+    /// The unique id of the session.
+    pub session_id: String,
+    /// The public ed25519 keys that claim to have created the session.
+    pub signing_keys: BTreeMap<DeviceKeyAlgorithm, String>,
+    /// The first message index the session can decrypt.
+    pub first_known_index: u32,
+    /// The chain of curve25519 keys the session was forwarded through.
+    pub forwarding_key_chain: Vec<String>,
+}
+
+impl From<&InboundGroupSession> for InboundGroupSessionSnapshot {
+    fn from(session: &InboundGroupSession) -> Self {
+        Self {
+            room_id: session.room_id().clone(),
+            sender_key: session.sender_key().to_owned(),
+            session_id: session.session_id().to_owned(),
+            signing_keys: session.signing_keys().clone(),
+            first_known_index: session.first_known_index(),
+            forwarding_key_chain: session.forwarding_key_chain().to_vec(),
+        }
+    }
+}
+
+async fn load_user_entries<S: CryptoStore + ?Sized>(
+    store: &S,
+    user_id: UserId,
+) -> Result<Vec<SnapshotEntry>> {
+    let mut entries = Vec::new();
+
+    if let Some(identity) = store.get_user_identity(&user_id).await? {
+        entries.push(SnapshotEntry::Identity((&identity).into()));
+    }
+
+    for device in store.get_user_devices(&user_id).await?.values() {
+        entries.push(SnapshotEntry::Device(device.into()));
+
+        let sender_key = if let Some(k) = device.get_key(DeviceKeyAlgorithm::Curve25519) {
+            k
+        } else {
+            continue;
+        };
+
+        if let Some(sessions) = store.get_sessions(sender_key).await? {
+            for session in sessions.lock().await.iter() {
+                entries.push(SnapshotEntry::Session(SessionSnapshot::new(device, session)));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+fn flatten(entries: Result<Vec<SnapshotEntry>>) -> impl Stream<Item = Result<SnapshotEntry>> {
+    let entries: Vec<Result<SnapshotEntry>> = match entries {
+        Ok(e) => e.into_iter().map(Ok).collect(),
+        Err(e) => vec![Err(e)],
+    };
+
+    stream::iter(entries)
+}
+
+pub(crate) fn export<S: CryptoStore + ?Sized>(store: &S) -> SnapshotStream<'_> {
+    let users = store.tracked_users();
+
+    let user_entries = stream::iter(users)
+        .then(move |user_id| load_user_entries(store, user_id))
+        .flat_map(flatten);
+
+    let group_session_entries = stream::once(async move {
+        store
+            .get_inbound_group_sessions()
+            .await
+            .map(|s| s.iter().map(|s| SnapshotEntry::InboundGroupSession(s.into())).collect())
+    })
+    .flat_map(flatten);
+
+    Box::pin(user_entries.chain(group_session_entries))
+}
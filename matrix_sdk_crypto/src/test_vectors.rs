@@ -0,0 +1,656 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Test vectors to check the interoperability of other Matrix clients with
+//! this crate.
+//!
+//! The vectors cover encrypted room key exports, the SAS verification, i.e.
+//! the commitment, the MAC info strings, the MACs and the conversion of the
+//! SAS bytes into emojis and decimals, and the byte payloads of QR codes.
+//! Every vector can be checked against this crate with its `verify()` method,
+//! other implementations can use the public fields to check their own output.
+
+use std::{convert::TryFrom, ffi::CStr, fmt, io::Cursor};
+
+use matrix_qrcode::QrVerificationData;
+use ruma::{events::key::verification::start::StartToDeviceEventContent, DeviceId, UserId};
+use thiserror::Error;
+
+use crate::{
+    decrypt_key_export, decrypt_key_export_with_key,
+    utilities::decode,
+    verification::{
+        bytes_to_decimal, bytes_to_emoji_index, calculate_commitment, key_ids_mac_input, mac_info,
+        sas_info, StartContent,
+    },
+};
+
+/// A value that was calculated by this crate didn't match the value of a test
+/// vector.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("test vector \"{vector}\": {field} mismatch, expected {expected}, got {actual}")]
+pub struct VectorMismatch {
+    /// The description of the vector.
+    pub vector: &'static str,
+    /// The field of the vector that didn't match.
+    pub field: &'static str,
+    /// The expected value.
+    pub expected: String,
+    /// The value that was calculated.
+    pub actual: String,
+}
+
+fn check<T: PartialEq + fmt::Debug>(
+    vector: &'static str,
+    field: &'static str,
+    expected: T,
+    actual: T,
+) -> Result<(), VectorMismatch> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(VectorMismatch {
+            vector,
+            field,
+            expected: format!("{:?}", expected),
+            actual: format!("{:?}", actual),
+        })
+    }
+}
+
+/// An encrypted room key export.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyExportVector {
+    /// What the vector covers.
+    pub description: &'static str,
+    /// The export, including the header and footer lines.
+    pub export: &'static str,
+    /// The passphrase the export is encrypted with, if it's passphrase based.
+    pub passphrase: Option<&'static str>,
+    /// The raw key the export is encrypted with, if it's key based.
+    pub key: Option<[u8; 32]>,
+    /// The room id of every exported room key.
+    pub room_id: &'static str,
+    /// The session ids of the exported room keys, in order.
+    pub session_ids: &'static [&'static str],
+}
+
+impl KeyExportVector {
+    /// Decrypt the export with this crate and check the exported room keys.
+    pub fn verify(&self) -> Result<(), VectorMismatch> {
+        let input = Cursor::new(self.export);
+
+        let keys = match (self.passphrase, &self.key) {
+            (Some(passphrase), _) => decrypt_key_export(input, passphrase),
+            (None, Some(key)) => decrypt_key_export_with_key(input, key),
+            (None, None) => panic!("The key export vector {} has no secret", self.description),
+        }
+        .map_err(|e| VectorMismatch {
+            vector: self.description,
+            field: "export",
+            expected: "a decryptable export".to_owned(),
+            actual: e.to_string(),
+        })?;
+
+        let session_ids: Vec<&str> = keys.iter().map(|k| k.session_id.as_str()).collect();
+        check(self.description, "session_ids", self.session_ids, session_ids.as_slice())?;
+
+        for key in &keys {
+            check(self.description, "room_id", self.room_id, key.room_id.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The byte payload of a verification QR code.
+#[derive(Clone, Copy, Debug)]
+pub struct QrPayloadVector {
+    /// What the vector covers.
+    pub description: &'static str,
+    /// The bytes of the QR code, encoded as unpadded base64.
+    pub payload_base64: &'static str,
+    /// The mode of the QR code, `0` to verify another user, `1` and `2` for
+    /// self-verification.
+    pub mode: u8,
+    /// The flow id, i.e. the event or transaction id, of the verification.
+    pub flow_id: &'static str,
+    /// The first key, as unpadded base64.
+    pub first_key: &'static str,
+    /// The second key, as unpadded base64.
+    pub second_key: &'static str,
+    /// The shared secret, as unpadded base64.
+    pub secret: &'static str,
+}
+
+impl QrPayloadVector {
+    /// The raw bytes of the QR code.
+    pub fn payload(&self) -> Vec<u8> {
+        decode(self.payload_base64).expect("Invalid base64 in a QR code test vector")
+    }
+
+    /// Parse the payload with this crate, check the parsed fields and check
+    /// that encoding them again produces the same payload.
+    pub fn verify(&self) -> Result<(), VectorMismatch> {
+        let payload = self.payload();
+
+        let data = QrVerificationData::from_bytes(&payload).map_err(|e| VectorMismatch {
+            vector: self.description,
+            field: "payload",
+            expected: "a valid QR code payload".to_owned(),
+            actual: e.to_string(),
+        })?;
+
+        let mode = match data {
+            QrVerificationData::Verification(_) => 0,
+            QrVerificationData::SelfVerification(_) => 1,
+            QrVerificationData::SelfVerificationNoMasterKey(_) => 2,
+        };
+
+        check(self.description, "mode", self.mode, mode)?;
+        check(self.description, "flow_id", self.flow_id, data.flow_id())?;
+        check(self.description, "first_key", self.first_key, data.first_key())?;
+        check(self.description, "second_key", self.second_key, data.second_key())?;
+        check(self.description, "secret", self.secret, data.secret())?;
+
+        let encoded = data.to_bytes().map_err(|e| VectorMismatch {
+            vector: self.description,
+            field: "payload",
+            expected: "an encodable QR code".to_owned(),
+            actual: e.to_string(),
+        })?;
+
+        check(self.description, "payload", payload, encoded)
+    }
+}
+
+/// The commitment a SAS verification is accepted with.
+#[derive(Clone, Copy, Debug)]
+pub struct SasCommitmentVector {
+    /// What the vector covers.
+    pub description: &'static str,
+    /// The ephemeral Curve25519 public key of the accepting side.
+    pub public_key: &'static str,
+    /// The JSON of the content of the `m.key.verification.start` event.
+    pub start_content: &'static str,
+    /// The expected commitment.
+    pub commitment: &'static str,
+}
+
+impl SasCommitmentVector {
+    /// Calculate the commitment with this crate and compare it.
+    pub fn verify(&self) -> Result<(), VectorMismatch> {
+        let content: StartToDeviceEventContent = serde_json::from_str(self.start_content)
+            .expect("Invalid start content in a SAS commitment test vector");
+        let commitment = calculate_commitment(self.public_key, &StartContent::from(&content));
+
+        check(self.description, "commitment", self.commitment, commitment.as_str())
+    }
+}
+
+/// The extra info that is used to generate the SAS bytes and the MACs of a
+/// SAS verification.
+#[derive(Clone, Copy, Debug)]
+pub struct SasInfoVector {
+    /// What the vector covers.
+    pub description: &'static str,
+    /// The user that started the verification and sends the MACs.
+    pub sender: &'static str,
+    /// The device that started the verification and sends the MACs.
+    pub sender_device: &'static str,
+    /// The ephemeral public key of the sender.
+    pub sender_key: &'static str,
+    /// The user that receives the MACs.
+    pub receiver: &'static str,
+    /// The device that receives the MACs.
+    pub receiver_device: &'static str,
+    /// The ephemeral public key of the receiver.
+    pub receiver_key: &'static str,
+    /// The flow id, i.e. the event or transaction id, of the verification.
+    pub flow_id: &'static str,
+    /// The ids of the keys the sender sends MACs for, in any order.
+    pub key_ids: &'static [&'static str],
+    /// The expected info to generate the SAS bytes.
+    pub sas_info: &'static str,
+    /// The expected info of the MACs, the id of a key is appended to it to
+    /// get the info of the MAC of the key.
+    pub mac_info: &'static str,
+    /// The expected input of the MAC of the key ids, the info of that MAC is
+    /// the MAC info with `KEY_IDS` appended.
+    pub key_ids_input: &'static str,
+}
+
+impl SasInfoVector {
+    /// Calculate the info strings and the key ids input with this crate and
+    /// compare them.
+    pub fn verify(&self) -> Result<(), VectorMismatch> {
+        let sender = UserId::try_from(self.sender).expect("Invalid user id in a test vector");
+        let receiver = UserId::try_from(self.receiver).expect("Invalid user id in a test vector");
+        let sender_device: &DeviceId = self.sender_device.into();
+        let receiver_device: &DeviceId = self.receiver_device.into();
+
+        let info = sas_info(
+            (&sender, sender_device, self.sender_key),
+            (&receiver, receiver_device, self.receiver_key),
+            self.flow_id,
+        );
+        check(self.description, "sas_info", self.sas_info, info.as_str())?;
+
+        let info = mac_info(&sender, sender_device, &receiver, receiver_device, self.flow_id);
+        check(self.description, "mac_info", self.mac_info, info.as_str())?;
+
+        let input = key_ids_mac_input(self.key_ids.iter().copied());
+        check(self.description, "key_ids_input", self.key_ids_input, input.as_str())
+    }
+}
+
+/// A MAC of a SAS verification, calculated from fixed ephemeral keys.
+///
+/// The MAC is encoded the way libolm encodes it for the `hkdf-hmac-sha256`
+/// method. libolm encodes the MAC into base64 in place, which overwrites parts
+/// of the MAC before they are encoded, so only the first few characters match
+/// a regular base64 encoding of the HMAC. Other implementations need to
+/// reproduce this to interoperate.
+#[derive(Clone, Copy, Debug)]
+pub struct SasMacVector {
+    /// What the vector covers.
+    pub description: &'static str,
+    /// The ephemeral Curve25519 private key of the sender of the MAC.
+    pub sender_private_key: [u8; 32],
+    /// The ephemeral public key of the sender, as unpadded base64.
+    pub sender_key: &'static str,
+    /// The ephemeral Curve25519 private key of the receiver of the MAC.
+    pub receiver_private_key: [u8; 32],
+    /// The ephemeral public key of the receiver, as unpadded base64.
+    pub receiver_key: &'static str,
+    /// The info of the MAC, i.e. the MAC info with the id of a key or
+    /// `KEY_IDS` appended, see [`SasInfoVector`].
+    pub info: &'static str,
+    /// The input of the MAC, i.e. a public key or the list of key ids.
+    pub input: &'static str,
+    /// The expected MAC.
+    pub mac: &'static str,
+}
+
+impl SasMacVector {
+    /// Calculate the MAC with libolm on both sides of the verification and
+    /// compare it.
+    pub fn verify(&self) -> Result<(), VectorMismatch> {
+        let sender = RawSas::new(&self.sender_private_key);
+        let receiver = RawSas::new(&self.receiver_private_key);
+
+        check(self.description, "sender_key", self.sender_key, sender.public_key().as_str())?;
+        check(self.description, "receiver_key", self.receiver_key, receiver.public_key().as_str())?;
+
+        sender.set_their_key(self.receiver_key);
+        receiver.set_their_key(self.sender_key);
+
+        let mac = sender.calculate_mac(self.input, self.info);
+        check(self.description, "mac", self.mac, mac.as_str())?;
+
+        let mac = receiver.calculate_mac(self.input, self.info);
+        check(self.description, "mac", self.mac, mac.as_str())
+    }
+}
+
+/// A SAS object that is accessed through the libolm API directly, olm-rs
+/// only creates them from random private keys.
+struct RawSas {
+    /// The memory libolm keeps the SAS object in, it needs to outlive the
+    /// pointer.
+    _buffer: Vec<u8>,
+    sas: *mut olm_sys::OlmSAS,
+}
+
+impl RawSas {
+    fn new(private_key: &[u8; 32]) -> Self {
+        let mut buffer = vec![0; unsafe { olm_sys::olm_sas_size() }];
+        let sas = unsafe { olm_sys::olm_sas(buffer.as_mut_ptr() as *mut _) };
+        let raw = Self { _buffer: buffer, sas };
+
+        let mut private_key = private_key.to_vec();
+
+        raw.check(unsafe {
+            olm_sys::olm_create_sas(raw.sas, private_key.as_mut_ptr() as *mut _, private_key.len())
+        });
+
+        raw
+    }
+
+    fn public_key(&self) -> String {
+        let mut key = vec![0; unsafe { olm_sys::olm_sas_pubkey_length(self.sas) }];
+
+        self.check(unsafe {
+            olm_sys::olm_sas_get_pubkey(self.sas, key.as_mut_ptr() as *mut _, key.len())
+        });
+
+        String::from_utf8(key).expect("libolm created a public key that isn't valid UTF-8")
+    }
+
+    fn set_their_key(&self, key: &str) {
+        // libolm decodes the key in place.
+        let mut key = key.as_bytes().to_vec();
+
+        self.check(unsafe {
+            olm_sys::olm_sas_set_their_key(self.sas, key.as_mut_ptr() as *mut _, key.len())
+        });
+    }
+
+    fn calculate_mac(&self, input: &str, info: &str) -> String {
+        let mut mac = vec![0; unsafe { olm_sys::olm_sas_mac_length(self.sas) }];
+
+        self.check(unsafe {
+            olm_sys::olm_sas_calculate_mac(
+                self.sas,
+                input.as_ptr() as *const _,
+                input.len(),
+                info.as_ptr() as *const _,
+                info.len(),
+                mac.as_mut_ptr() as *mut _,
+                mac.len(),
+            )
+        });
+
+        String::from_utf8(mac).expect("libolm created a MAC that isn't valid UTF-8")
+    }
+
+    /// Check the return value of a libolm function.
+    ///
+    /// # Panics
+    ///
+    /// Panics if libolm returned an error, the keys of the vectors are
+    /// expected to be valid.
+    fn check(&self, result: usize) -> usize {
+        if result == unsafe { olm_sys::olm_error() } {
+            let error = unsafe { CStr::from_ptr(olm_sys::olm_sas_last_error(self.sas)) };
+            panic!("libolm failed to handle a SAS test vector: {}", error.to_string_lossy());
+        }
+
+        result
+    }
+}
+
+impl Drop for RawSas {
+    fn drop(&mut self) {
+        unsafe { olm_sys::olm_clear_sas(self.sas) };
+    }
+}
+
+/// The emojis and decimals that are shown for the given SAS bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct SasBytesVector {
+    /// The first six bytes that were generated from the shared secret.
+    pub bytes: [u8; 6],
+    /// The indices of the seven emojis in the emoji table of the spec.
+    pub emoji_indices: [u8; 7],
+    /// The three decimal numbers, generated from the first five bytes.
+    pub decimals: (u16, u16, u16),
+}
+
+impl SasBytesVector {
+    /// Convert the bytes with this crate and compare the result.
+    pub fn verify(&self) -> Result<(), VectorMismatch> {
+        let description = "SAS bytes";
+
+        check(
+            description,
+            "emoji_indices",
+            self.emoji_indices,
+            bytes_to_emoji_index(self.bytes.to_vec()),
+        )?;
+        check(description, "decimals", self.decimals, bytes_to_decimal(self.bytes[..5].to_vec()))
+    }
+}
+
+/// The id of the room all exported room keys of the key export vectors
+/// belong to.
+const EXPORT_ROOM_ID: &str = "!test:localhost";
+
+/// The id of the room key all key export vectors contain.
+const EXPORT_SESSION_ID: &str = "8sdNGgmLft8osylLK9ij3IotBLyQQufe/KngiW4Dpq0";
+
+/// Encrypted room key exports.
+pub const KEY_EXPORTS: &[KeyExportVector] = &[
+    KeyExportVector {
+        description: "version 1, PBKDF2 with a single round",
+        export: concat!(
+            "-----BEGIN MEGOLM SESSION DATA-----\n",
+            "Af7mGhlzQ+eGvHu93u0YXd3D/+vYMs3E7gQqOhuCtkvGAAAAASH7pEdWvFyAP1JUisAcpEo\n",
+            "Xke2Q7Kr9hVl/SCc6jXBNeJCZcrUbUV4D/tRQIl3E9L4fOk928YI1J+3z96qiH0uE7hpsCI\n",
+            "CkHKwjPU+0XTzFdIk1X8H7sZ+MD/2Sg/q3y8rtUjz7uEj4GUTnb+9SCOTVmJsRfqgUpM1CU\n",
+            "bDLytHf1JkohY4tWEgpsCc67xdzgodjr12qYrfg/zNm3LGpxlrffJknw4rk5QFTj4kMbqbD\n",
+            "ZZgDTni+HxRTDGge2J620lMOiznvXX+H09Rwruqx5aJvvaaKd86jWRpiO2oSFqHn4u5ONl9\n",
+            "41uzm62Sj0eIm6ZbA9NQs87jQw4LxsejhZVL+NdjIg80zVSBTWhTdo0DTnbFSNP4ReOiz0U\n",
+            "XosOF8A5T8Vdx2nvA0GXltfcHKVKQYh/LJAkNQ7P9UYL4ae/5TtQZkhB1KxCLTRWqADCl53\n",
+            "uBMGpG53EMgY6G6K2DEIOkcv7sdXQF5WpemiSWZqJRWj+cjfs9BpCTbkp/rszWFl2TniWpR\n",
+            "RqIbT2jORlN4rTvdtF0F4z1pqP4qWyR3sLNTkXm9CFRzWADNG0RDZKxbCoo6RPvtaCTfaHo\n",
+            "SwfvzBS6CjfAG+FOugpV48o7+XetaUUPZ6/tZSPhCdeV8eP9q5r0QwWeXFogzoNzWt4HYx9\n",
+            "MdXxzD+f0mtg5gzehrrEEARwI2bCvPpHxlt/Na9oW/GBpkjwR1LSKgg4CtpRyWngPjdEKpZ\n",
+            "GYW19pdjg0qdXNk/eqZsQTsNWVo6A\n",
+            "-----END MEGOLM SESSION DATA-----\n",
+        ),
+        passphrase: Some("1234"),
+        key: None,
+        room_id: EXPORT_ROOM_ID,
+        session_ids: &[EXPORT_SESSION_ID],
+    },
+    KeyExportVector {
+        description: "version 2, raw key",
+        export: concat!(
+            "-----BEGIN MEGOLM SESSION DATA-----\n",
+            "AgBAQUJDREVGR0hJSktMTU5PYGFiY2RlZmcAAAAAAAAAAFBhuow33dP3BXNd8XsuAuuax5IOWFgX\n",
+            "dlNDlzXoQTUxyOH0WYn0OgBTVGwwTK0UnV97TvuYrRIxOO9SYrodSIpKlK5oxiT4uE6vrmQkY209\n",
+            "9W4YuC+SkIqYc615bAzgyXs0G+hBngIjCYQesgbQ5Y6EDKmosaNeywg94dyy9OHQoqEfmlNQWhcz\n",
+            "vulKl/h5a71jmzva8evsdGz+8q3M0+qjUTTFUQtzg4GEm6NEgOznwKbHxafjuokDXNP60o1p8Lad\n",
+            "DK+yZHp8kgb99mYcu7QC0sDJ8A3JlVpHPF53UvneQYR/o8RXMk7zunUd7o6M5vyz5ovfdVTs4Hr+\n",
+            "5p2TVhXFCHPfyOm5Oj1YQiE3Yub1y1jjOlgyptD5Ta9m0duASayuUSD8c0+eAVR8RCoWUfLgWDp1\n",
+            "rGz/2wO3g3tdpPVB5D9L6fmBv0Nehwzq9LAh3sBiW/kOviQVMCwHSsRTJc2TSLhFk5tu+JLSXTMV\n",
+            "XLXqFh3VBzZVmMsEGG6wLWgQLsD/Es0wYuSIykInPRlwYvUc2Jn9dySMjjZ51y+4hsyw++l5Ev3f\n",
+            "oACMbtNTtcuaEWD7jWDIe8UkA5y2+3LFNBdJf/xRqaTfdNZ0fKRMvC4dOk7+dU9+uP4t73w6hf9l\n",
+            "A6UITJXyxBef9X9Z5SEeqMpLOAFv8/3OKPQ59/6SyMXxEsfokfdNbQ/pPfPj6wGGJADF2+nl22CI\n",
+            "DFBc+e98W1t5p/s2J8fXQO81rpFIegEqEMSyCU50g93DYw\n",
+            "-----END MEGOLM SESSION DATA-----\n",
+        ),
+        passphrase: None,
+        key: Some([
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31,
+        ]),
+        room_id: EXPORT_ROOM_ID,
+        session_ids: &[EXPORT_SESSION_ID],
+    },
+];
+
+/// QR code payloads, one for every mode.
+pub const QR_PAYLOADS: &[QrPayloadVector] = &[
+    QrPayloadVector {
+        description: "verification of another user",
+        payload_base64: "TUFUUklYAgAAHSQxMjM0NTY3ODkwYWJjZGVmOmV4YW1wbGUub3JnR4AZ21o7rVOMroX3n8Pr\
+                         dTcmadcTMfHBImvgHiwMD4RU4U5s6RiZ41y5xMfdpKW47UGrP5TTZ3pg9Orfw9bom2uF7z3/\
+                         e7b+p3zSuGYssZs",
+        mode: 0,
+        flow_id: "$1234567890abcdef:example.org",
+        first_key: "R4AZ21o7rVOMroX3n8PrdTcmadcTMfHBImvgHiwMD4Q",
+        second_key: "VOFObOkYmeNcucTH3aSluO1Bqz+U02d6YPTq38PW6Js",
+        secret: "a4XvPf97tv6nfNK4Ziyxmw",
+    },
+    QrPayloadVector {
+        description: "self-verification of a device that trusts the master key",
+        payload_base64: "TUFUUklYAgEAIDZiYmM1OGM0ZTBmODRjMDRiM2FlYWNkMmEwYzVhNWMxNRT3Fi8WC1Aeyl\
+                         EqmyUQQSpnNLpYFnprK+vimH1T7pnKcDcI2eb6Grz7bz1ZnbUHJWzwoCRUE7ScNGgcTL5T\
+                         IlSFy10DmBzDvZiSkKa+M8o",
+        mode: 1,
+        flow_id: "6bbc58c4e0f84c04b3aeacd2a0c5a5c1",
+        first_key: "NRT3Fi8WC1AeylEqmyUQQSpnNLpYFnprK+vimH1T7pk",
+        second_key: "ynA3CNnm+hq8+289WZ21ByVs8KAkVBO0nDRoHEy+UyI",
+        secret: "VIXLXQOYHMO9mJKQpr4zyg",
+    },
+    QrPayloadVector {
+        description: "self-verification of a device that doesn't trust the master key",
+        payload_base64: "TUFUUklYAgIAIDAxYjNkMWYwYjJjNjRlNDRhMmMxZjVjM2U5ZDVhN2I43Fclmf0kO+k/T0\
+                         5+i5Wxtvb6JWX3PWRx9Pvm+m73jc4Yl2KWlVDpSlmznryNr8CkexzpbDau7PyIIhMWFw3B\
+                         P97WwCpLwftxLztx78uIOtU",
+        mode: 2,
+        flow_id: "01b3d1f0b2c64e44a2c1f5c3e9d5a7b8",
+        first_key: "3Fclmf0kO+k/T05+i5Wxtvb6JWX3PWRx9Pvm+m73jc4",
+        second_key: "GJdilpVQ6UpZs568ja/ApHsc6Ww2ruz8iCITFhcNwT8",
+        secret: "3tbAKkvB+3EvO3Hvy4g61Q",
+    },
+];
+
+/// Commitments of SAS verifications.
+pub const SAS_COMMITMENTS: &[SasCommitmentVector] = &[SasCommitmentVector {
+    description: "to-device verification supporting both SAS methods",
+    public_key: "Q/NmNFEUS1fS+YeEmiZkjjblKTitrKOAk7cPEumcMlg",
+    start_content: r#"{
+        "from_device": "XOWLHHFSWM",
+        "transaction_id": "bYxBsirjUJO9osar6ST4i2M2NjrYLA7l",
+        "method": "m.sas.v1",
+        "key_agreement_protocols": ["curve25519-hkdf-sha256", "curve25519"],
+        "hashes": ["sha256"],
+        "message_authentication_codes": ["hkdf-hmac-sha256", "hmac-sha256"],
+        "short_authentication_string": ["decimal", "emoji"]
+    }"#,
+    commitment: "CCQmB4JCdB0FW21FdAnHj/Hu8+W9+Nb0vgwPEnZZQ4g",
+}];
+
+/// Info strings and MAC inputs of SAS verifications.
+pub const SAS_INFOS: &[SasInfoVector] = &[
+    SasInfoVector {
+        description: "to-device verification between two users",
+        sender: "@alice:example.org",
+        sender_device: "ALICEDEVICE",
+        sender_key: "Q/NmNFEUS1fS+YeEmiZkjjblKTitrKOAk7cPEumcMlg",
+        receiver: "@bob:example.org",
+        receiver_device: "BOBDEVICE",
+        receiver_key: "Ekf4KITYpmHNlaWiU1ozmqi5/Lk3jfHN0fPP6HSSCR4",
+        flow_id: "bYxBsirjUJO9osar6ST4i2M2NjrYLA7l",
+        key_ids: &["ed25519:ALICEDEVICE", "ed25519:Hk5U9VJGeg3GYvBFNRoHdCtMWhsPSrQ6FRZ5rRmOHUg"],
+        sas_info: "MATRIX_KEY_VERIFICATION_SAS|@alice:example.org|ALICEDEVICE|\
+                   Q/NmNFEUS1fS+YeEmiZkjjblKTitrKOAk7cPEumcMlg|@bob:example.org|BOBDEVICE|\
+                   Ekf4KITYpmHNlaWiU1ozmqi5/Lk3jfHN0fPP6HSSCR4|bYxBsirjUJO9osar6ST4i2M2NjrYLA7l",
+        mac_info: "MATRIX_KEY_VERIFICATION_MAC@alice:example.orgALICEDEVICE\
+                   @bob:example.orgBOBDEVICEbYxBsirjUJO9osar6ST4i2M2NjrYLA7l",
+        key_ids_input: "ed25519:ALICEDEVICE,ed25519:Hk5U9VJGeg3GYvBFNRoHdCtMWhsPSrQ6FRZ5rRmOHUg",
+    },
+    SasInfoVector {
+        description: "in-room self-verification, key ids out of order",
+        sender: "@alice:example.org",
+        sender_device: "NEWDEVICE",
+        sender_key: "Ekf4KITYpmHNlaWiU1ozmqi5/Lk3jfHN0fPP6HSSCR4",
+        receiver: "@alice:example.org",
+        receiver_device: "ALICEDEVICE",
+        receiver_key: "Q/NmNFEUS1fS+YeEmiZkjjblKTitrKOAk7cPEumcMlg",
+        flow_id: "$1234567890abcdef:example.org",
+        key_ids: &["ed25519:NEWDEVICE", "ed25519:ALICEDEVICE"],
+        sas_info: "MATRIX_KEY_VERIFICATION_SAS|@alice:example.org|NEWDEVICE|\
+                   Ekf4KITYpmHNlaWiU1ozmqi5/Lk3jfHN0fPP6HSSCR4|@alice:example.org|ALICEDEVICE|\
+                   Q/NmNFEUS1fS+YeEmiZkjjblKTitrKOAk7cPEumcMlg|$1234567890abcdef:example.org",
+        mac_info: "MATRIX_KEY_VERIFICATION_MAC@alice:example.orgNEWDEVICE\
+                   @alice:example.orgALICEDEVICE$1234567890abcdef:example.org",
+        key_ids_input: "ed25519:ALICEDEVICE,ed25519:NEWDEVICE",
+    },
+];
+
+/// The ephemeral private key of the sender of the SAS MAC vectors.
+const MAC_SENDER_PRIVATE_KEY: [u8; 32] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+    26, 27, 28, 29, 30, 31,
+];
+
+/// The ephemeral private key of the receiver of the SAS MAC vectors.
+const MAC_RECEIVER_PRIVATE_KEY: [u8; 32] = [
+    32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55,
+    56, 57, 58, 59, 60, 61, 62, 63,
+];
+
+/// MACs of SAS verifications, using the MAC info of the first vector of
+/// [`SAS_INFOS`].
+pub const SAS_MACS: &[SasMacVector] = &[
+    SasMacVector {
+        description: "MAC of a device key",
+        sender_private_key: MAC_SENDER_PRIVATE_KEY,
+        sender_key: "j0DFrbaPJWJK5bIU6nZ6bslNgp09e14a0bpvPiE4KF8",
+        receiver_private_key: MAC_RECEIVER_PRIVATE_KEY,
+        receiver_key: "NYBy1jZYgNGu6jKa35EhODhR7SGijjt16WXQ0s0WYlQ",
+        info: "MATRIX_KEY_VERIFICATION_MAC@alice:example.orgALICEDEVICE\
+               @bob:example.orgBOBDEVICEbYxBsirjUJO9osar6ST4i2M2NjrYLA7led25519:ALICEDEVICE",
+        input: "nE6W2fCblxDcOFmeEtCHNl8/l8bXcu7GKyAswA4r3mM",
+        mac: "jEVbYmfLZkx/a3gvYTNndllUTm5kbGxVVG01a2JHeFY",
+    },
+    SasMacVector {
+        description: "MAC of the key ids",
+        sender_private_key: MAC_SENDER_PRIVATE_KEY,
+        sender_key: "j0DFrbaPJWJK5bIU6nZ6bslNgp09e14a0bpvPiE4KF8",
+        receiver_private_key: MAC_RECEIVER_PRIVATE_KEY,
+        receiver_key: "NYBy1jZYgNGu6jKa35EhODhR7SGijjt16WXQ0s0WYlQ",
+        info: "MATRIX_KEY_VERIFICATION_MAC@alice:example.orgALICEDEVICE\
+               @bob:example.orgBOBDEVICEbYxBsirjUJO9osar6ST4i2M2NjrYLA7lKEY_IDS",
+        input: "ed25519:ALICEDEVICE,ed25519:Hk5U9VJGeg3GYvBFNRoHdCtMWhsPSrQ6FRZ5rRmOHUg",
+        mac: "juMlbOLCTEOARU9BUlU5QlVsVTVRbFZzVlRWUmJGWno",
+    },
+];
+
+/// Conversions of SAS bytes into emojis and decimals.
+pub const SAS_BYTES: &[SasBytesVector] = &[
+    SasBytesVector {
+        bytes: [0x00, 0x11, 0x22, 0x33, 0x44, 0x55],
+        emoji_indices: [0, 1, 4, 34, 12, 52, 17],
+        decimals: (1002, 2160, 7562),
+    },
+    SasBytesVector {
+        bytes: [0xde, 0xad, 0xbe, 0xef, 0x12, 0x34],
+        emoji_indices: [55, 42, 54, 62, 59, 49, 8],
+        decimals: (8125, 6883, 7025),
+    },
+    SasBytesVector {
+        bytes: [0x5a, 0xa5, 0x0f, 0xf0, 0x81, 0x7e],
+        emoji_indices: [22, 42, 20, 15, 60, 8, 5],
+        decimals: (3900, 6183, 7208),
+    },
+];
+
+/// Verify all the vectors of this module against this crate.
+pub fn verify_all() -> Result<(), VectorMismatch> {
+    KEY_EXPORTS.iter().try_for_each(KeyExportVector::verify)?;
+    QR_PAYLOADS.iter().try_for_each(QrPayloadVector::verify)?;
+    SAS_COMMITMENTS.iter().try_for_each(SasCommitmentVector::verify)?;
+    SAS_INFOS.iter().try_for_each(SasInfoVector::verify)?;
+    SAS_MACS.iter().try_for_each(SasMacVector::verify)?;
+    SAS_BYTES.iter().try_for_each(SasBytesVector::verify)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify_all, SasBytesVector, SasMacVector, VectorMismatch, SAS_BYTES, SAS_MACS};
+
+    #[test]
+    fn vectors() {
+        verify_all().unwrap();
+
+        // The MAC depends on the shared secret of both ephemeral keys.
+        let vector = SasMacVector {
+            receiver_private_key: SAS_MACS[0].sender_private_key,
+            receiver_key: SAS_MACS[0].sender_key,
+            ..SAS_MACS[0]
+        };
+        assert_eq!(vector.verify().unwrap_err().field, "mac");
+
+        let vector = SasBytesVector { decimals: (0, 0, 0), ..SAS_BYTES[0] };
+        let error: VectorMismatch = vector.verify().unwrap_err();
+
+        assert_eq!(error.field, "decimals");
+        assert_eq!(error.actual, "(1002, 2160, 7562)");
+    }
+}
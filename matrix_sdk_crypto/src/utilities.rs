@@ -12,8 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::future::Future;
+
 pub use base64::DecodeError;
 use base64::{decode_config, encode_config, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+#[cfg(not(target_arch = "wasm32"))]
+use futures::{channel::oneshot, executor::block_on};
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 /// Decode the input as base64 with no padding.
 pub fn decode(input: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodeError> {
@@ -34,3 +40,48 @@ pub fn encode(input: impl AsRef<[u8]>) -> String {
 pub fn encode_url_safe(input: impl AsRef<[u8]>) -> String {
     encode_config(input, URL_SAFE_NO_PAD)
 }
+
+/// Map the given items in parallel, on a background thread pool.
+///
+/// This is used for CPU heavy work on many items at once, e.g. pickling or
+/// importing thousands of group sessions, which would otherwise block the
+/// async executor. The returned items keep the order of the input.
+///
+/// The futures returned by `f` are driven to completion on the threads of the
+/// pool, they may only wait on locks, not on IO or timers of the executor.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn parallel_map<T, U, F, Fut>(items: Vec<T>, f: F) -> Vec<U>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = U>,
+{
+    let (sender, receiver) = oneshot::channel();
+
+    rayon::spawn(move || {
+        let result = items.into_par_iter().map(|item| block_on(f(item))).collect();
+        // The receiver only goes away if the caller stopped waiting for us.
+        let _ = sender.send(result);
+    });
+
+    receiver.await.expect("The parallel map task panicked")
+}
+
+/// Map the given items in parallel, on a background thread pool.
+///
+/// There are no threads on WASM, the items are mapped one after another.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn parallel_map<T, U, F, Fut>(items: Vec<T>, f: F) -> Vec<U>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = U>,
+{
+    let mut result = Vec::with_capacity(items.len());
+
+    for item in items {
+        result.push(f(item).await);
+    }
+
+    result
+}
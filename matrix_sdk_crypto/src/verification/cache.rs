@@ -19,7 +19,9 @@ use matrix_sdk_common::uuid::Uuid;
 use ruma::{DeviceId, UserId};
 
 use super::{event_enums::OutgoingContent, Sas, Verification};
-use crate::{OutgoingRequest, QrVerification, RoomMessageRequest, ToDeviceRequest};
+use crate::{
+    environment::random_uuid, OutgoingRequest, QrVerification, RoomMessageRequest, ToDeviceRequest,
+};
 
 #[derive(Clone, Debug)]
 pub struct VerificationCache {
@@ -69,6 +71,13 @@ impl VerificationCache {
         self.verification.get(sender).and_then(|m| m.get(flow_id).map(|v| v.clone()))
     }
 
+    pub fn get_all(&self) -> Vec<Verification> {
+        self.verification
+            .iter()
+            .flat_map(|m| m.iter().map(|v| v.value().clone()).collect::<Vec<_>>())
+            .collect()
+    }
+
     pub fn outgoing_requests(&self) -> Vec<OutgoingRequest> {
         self.outgoing_requests.iter().map(|r| (*r).clone()).collect()
     }
@@ -134,7 +143,7 @@ impl VerificationCache {
             }
 
             OutgoingContent::Room(r, c) => {
-                let request_id = Uuid::new_v4();
+                let request_id = random_uuid();
 
                 let request = OutgoingRequest {
                     request: Arc::new(
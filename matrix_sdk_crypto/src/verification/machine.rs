@@ -27,6 +27,7 @@ use super::{
     FlowId, Verification, VerificationResult,
 };
 use crate::{
+    environment::{self, random_uuid},
     olm::PrivateCrossSigningIdentity,
     requests::OutgoingRequest,
     store::{CryptoStore, CryptoStoreError},
@@ -76,7 +77,7 @@ impl VerificationMachine {
 
         let request = match content {
             OutgoingContent::Room(r, c) => {
-                RoomMessageRequest { room_id: r, txn_id: Uuid::new_v4(), content: c }.into()
+                RoomMessageRequest { room_id: r, txn_id: random_uuid(), content: c }.into()
             }
             OutgoingContent::ToDevice(c) => {
                 let request =
@@ -106,6 +107,14 @@ impl VerificationMachine {
             .unwrap_or_default()
     }
 
+    pub fn get_all_requests(&self) -> Vec<VerificationRequest> {
+        self.requests
+            .iter()
+            .flat_map(|v| v.iter().map(|i| i.value().clone()).collect::<Vec<_>>())
+            .filter(|r| !(r.is_done() || r.is_cancelled()))
+            .collect()
+    }
+
     fn insert_request(&self, request: VerificationRequest) {
         self.requests
             .entry(request.other_user().to_owned())
@@ -121,6 +130,14 @@ impl VerificationMachine {
         self.verifications.get_sas(user_id, flow_id)
     }
 
+    pub fn get_all_verifications(&self) -> Vec<Verification> {
+        self.verifications
+            .get_all()
+            .into_iter()
+            .filter(|v| !(v.is_done() || v.is_cancelled()))
+            .collect()
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn is_timestamp_valid(timestamp: &MilliSecondsSinceUnixEpoch) -> bool {
         // The event should be ignored if the event is older than 10 minutes
@@ -130,7 +147,7 @@ impl VerificationMachine {
         let timestamp_threshold: UInt = uint!(300);
 
         let timestamp = timestamp.as_secs();
-        let now = MilliSecondsSinceUnixEpoch::now().as_secs();
+        let now = environment::timestamp().as_secs();
 
         !(now.saturating_sub(timestamp) > old_timestamp_threshold
             || timestamp.saturating_sub(now) > timestamp_threshold)
@@ -523,6 +540,46 @@ mod test {
         assert!(bob.is_done());
     }
 
+    #[tokio::test]
+    async fn concurrent_flows() {
+        let (alice_machine, bob) = setup_verification_machine().await;
+        assert_eq!(alice_machine.get_all_verifications().len(), 1);
+
+        let bob_account = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let alice_account = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice_account).await;
+        let bob_store = MemoryStore::new();
+        bob_store.save_devices(vec![alice_device.clone()]).await;
+
+        let (second_sas, start_content) = Sas::start(
+            bob_account,
+            PrivateCrossSigningIdentity::empty(bob_id()),
+            alice_device,
+            Arc::new(bob_store),
+            None,
+            None,
+        );
+
+        alice_machine
+            .receive_any_event(&wrap_any_to_device_content(second_sas.user_id(), start_content))
+            .await
+            .unwrap();
+
+        assert_ne!(bob.flow_id(), second_sas.flow_id());
+        assert_eq!(alice_machine.get_all_verifications().len(), 2);
+        assert!(alice_machine.get_sas(bob.user_id(), bob.flow_id().as_str()).is_some());
+        assert!(alice_machine
+            .get_sas(second_sas.user_id(), second_sas.flow_id().as_str())
+            .is_some());
+
+        let alice = alice_machine.get_sas(bob.user_id(), bob.flow_id().as_str()).unwrap();
+        assert!(alice.cancel().is_some());
+
+        let active = alice_machine.get_all_verifications();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].flow_id(), second_sas.flow_id().as_str());
+    }
+
     #[cfg(target_os = "linux")]
     #[tokio::test]
     async fn timing_out() {
@@ -22,6 +22,8 @@ mod sas;
 use std::sync::Arc;
 
 use event_enums::OutgoingContent;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use event_enums::StartContent;
 pub use machine::VerificationMachine;
 pub use qrcode::QrVerification;
 pub use requests::VerificationRequest;
@@ -37,6 +39,11 @@ use ruma::{
     },
     DeviceId, EventId, RoomId, UserId,
 };
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use sas::{
+    bytes_to_decimal, bytes_to_emoji_index, calculate_commitment, key_ids_mac_input, mac_info,
+    sas_info,
+};
 pub use sas::{AcceptSettings, Sas};
 use tracing::{error, info, trace, warn};
 
@@ -16,9 +16,8 @@ use std::sync::{Arc, Mutex};
 
 use matrix_qrcode::{
     qrcode::QrCode, EncodingError, QrVerificationData, SelfVerificationData,
-    SelfVerificationNoMasterKey, VerificationData,
+    SelfVerificationNoMasterKey, TerminalStyle, VerificationData,
 };
-use matrix_sdk_common::uuid::Uuid;
 use ruma::{
     api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
     events::{
@@ -42,6 +41,7 @@ use super::{
     Cancelled, Done, FlowId, IdentitiesBeingVerified, VerificationResult,
 };
 use crate::{
+    environment::{fill_random, random_uuid},
     olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
     store::CryptoStore,
     CryptoStoreError, OutgoingVerificationRequest, ReadOnlyDevice, RoomMessageRequest,
@@ -154,6 +154,12 @@ impl QrVerification {
         self.inner.to_bytes()
     }
 
+    /// Render the QR code that is representing this verification flow as text
+    /// that can be printed in a terminal.
+    pub fn to_terminal_string(&self, style: TerminalStyle) -> Result<String, EncodingError> {
+        self.inner.to_terminal_string(style)
+    }
+
     /// Cancel the verification flow.
     pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
         self.cancel_with_code(CancelCode::User).map(|c| self.content_to_request(c))
@@ -198,7 +204,7 @@ impl QrVerification {
     fn content_to_request(&self, content: OutgoingContent) -> OutgoingVerificationRequest {
         match content {
             OutgoingContent::Room(room_id, content) => {
-                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+                RoomMessageRequest { room_id, txn_id: random_uuid(), content }.into()
             }
             OutgoingContent::ToDevice(c) => ToDeviceRequest::new(
                 self.identities.other_user_id(),
@@ -340,8 +346,7 @@ impl QrVerification {
 
     fn generate_secret() -> String {
         let mut shared_secret = [0u8; SECRET_SIZE];
-        getrandom::getrandom(&mut shared_secret)
-            .expect("Can't generate randomness for the shared secret");
+        fill_random(&mut shared_secret);
         crate::utilities::encode(shared_secret)
     }
 
@@ -17,7 +17,6 @@
 use std::sync::{Arc, Mutex};
 
 use matrix_qrcode::QrVerificationData;
-use matrix_sdk_common::uuid::Uuid;
 use ruma::{
     api::client::r0::to_device::DeviceIdOrAllDevices,
     events::{
@@ -31,7 +30,7 @@ use ruma::{
         room::message::KeyVerificationRequestEventContent,
         AnyMessageEventContent, AnyToDeviceEventContent,
     },
-    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
+    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventId, RoomId, UserId,
 };
 use tracing::{info, trace, warn};
 
@@ -44,6 +43,7 @@ use super::{
     Cancelled, FlowId, IdentitiesBeingVerified,
 };
 use crate::{
+    environment::{random_uuid, timestamp},
     olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
     store::CryptoStore,
     CryptoStoreError, OutgoingVerificationRequest, ReadOnlyDevice, RoomMessageRequest, Sas,
@@ -112,7 +112,7 @@ impl VerificationRequest {
         store: Arc<dyn CryptoStore>,
         other_user: &UserId,
     ) -> Self {
-        let flow_id = Uuid::new_v4().to_string().into();
+        let flow_id = random_uuid().to_string().into();
 
         let inner = Mutex::new(InnerRequest::Created(RequestState::new(
             account.clone(),
@@ -143,7 +143,7 @@ impl VerificationRequest {
             self.account.device_id().into(),
             self.flow_id().as_str().to_string(),
             SUPPORTED_METHODS.to_vec(),
-            MilliSecondsSinceUnixEpoch::now(),
+            timestamp(),
         )
     }
 
@@ -355,7 +355,7 @@ impl VerificationRequest {
                 ToDeviceRequest::new(&self.other_user(), inner.other_device_id(), content).into()
             }
             OutgoingContent::Room(room_id, content) => {
-                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+                RoomMessageRequest { room_id, txn_id: random_uuid(), content }.into()
             }
         })
     }
@@ -389,7 +389,7 @@ impl VerificationRequest {
                 ToDeviceRequest::new(&self.other_user(), inner.other_device_id(), content).into()
             }
             OutgoingContent::Room(room_id, content) => {
-                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+                RoomMessageRequest { room_id, txn_id: random_uuid(), content }.into()
             }
         })
     }
@@ -467,7 +467,7 @@ impl VerificationRequest {
                         )
                         .into(),
                         OutgoingContent::Room(room_id, content) => {
-                            RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+                            RoomMessageRequest { room_id, txn_id: random_uuid(), content }.into()
                         }
                     };
 
@@ -24,7 +24,7 @@ use ruma::{
         },
         AnyMessageEventContent, AnyToDeviceEventContent,
     },
-    DeviceKeyAlgorithm, DeviceKeyId, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
 };
 use sha2::{Digest, Sha256};
 use tracing::{trace, warn};
@@ -150,17 +150,46 @@ fn emoji_from_index(index: u8) -> (&'static str, &'static str) {
 ///
 /// * `flow_id` - The unique id that identifies this SAS verification process.
 fn extra_mac_info_receive(ids: &SasIds, flow_id: &str) -> String {
+    mac_info(
+        ids.other_device.user_id(),
+        ids.other_device.device_id(),
+        ids.account.user_id(),
+        ids.account.device_id(),
+        flow_id,
+    )
+}
+
+/// Get the extra info of the MACs the given sender calculates for the given
+/// receiver.
+///
+/// The id of a key is appended to this info when the MAC of the key is
+/// calculated, `KEY_IDS` is appended for the MAC of the list of key ids.
+pub fn mac_info(
+    sender: &UserId,
+    sender_device: &DeviceId,
+    receiver: &UserId,
+    receiver_device: &DeviceId,
+    flow_id: &str,
+) -> String {
     format!(
         "MATRIX_KEY_VERIFICATION_MAC{first_user}{first_device}\
         {second_user}{second_device}{transaction_id}",
-        first_user = ids.other_device.user_id(),
-        first_device = ids.other_device.device_id(),
-        second_user = ids.account.user_id(),
-        second_device = ids.account.device_id(),
+        first_user = sender,
+        first_device = sender_device,
+        second_user = receiver,
+        second_device = receiver_device,
         transaction_id = flow_id,
     )
 }
 
+/// Get the input of the MAC that covers the ids of all the keys a
+/// m.key.verification.mac event contains MACs for.
+pub fn key_ids_mac_input<'a>(key_ids: impl IntoIterator<Item = &'a str>) -> String {
+    let mut key_ids: Vec<_> = key_ids.into_iter().collect();
+    key_ids.sort_unstable();
+    key_ids.join(",")
+}
+
 /// Get the content for a m.key.verification.mac event.
 ///
 /// Returns a tuple that contains the list of verified devices and the list of
@@ -194,12 +223,10 @@ pub fn receive_mac_event(
         ids.other_device.device_id()
     );
 
-    let mut keys = content.mac().keys().map(|k| k.as_str()).collect::<Vec<_>>();
-    keys.sort_unstable();
+    let keys = key_ids_mac_input(content.mac().keys().map(|k| k.as_str()));
 
-    let keys = sas
-        .calculate_mac(&keys.join(","), &format!("{}KEY_IDS", &info))
-        .expect("Can't calculate SAS MAC");
+    let keys =
+        sas.calculate_mac(&keys, &format!("{}KEY_IDS", &info)).expect("Can't calculate SAS MAC");
 
     if keys != content.keys() {
         return Err(CancelCode::KeyMismatch);
@@ -268,14 +295,12 @@ pub fn receive_mac_event(
 ///
 /// * `flow_id` - The unique id that identifies this SAS verification process.
 fn extra_mac_info_send(ids: &SasIds, flow_id: &str) -> String {
-    format!(
-        "MATRIX_KEY_VERIFICATION_MAC{first_user}{first_device}\
-        {second_user}{second_device}{transaction_id}",
-        first_user = ids.account.user_id(),
-        first_device = ids.account.device_id(),
-        second_user = ids.other_device.user_id(),
-        second_device = ids.other_device.device_id(),
-        transaction_id = flow_id,
+    mac_info(
+        ids.account.user_id(),
+        ids.account.device_id(),
+        ids.other_device.user_id(),
+        ids.other_device.device_id(),
+        flow_id,
     )
 }
 
@@ -306,11 +331,9 @@ pub fn get_mac_content(sas: &OlmSas, ids: &SasIds, flow_id: &FlowId) -> Outgoing
 
     // TODO Add the cross signing master key here if we trust/have it.
 
-    let mut keys = mac.keys().cloned().collect::<Vec<String>>();
-    keys.sort();
-    let keys = sas
-        .calculate_mac(&keys.join(","), &format!("{}KEY_IDS", &info))
-        .expect("Can't calculate SAS MAC");
+    let keys = key_ids_mac_input(mac.keys().map(|k| k.as_str()));
+    let keys =
+        sas.calculate_mac(&keys, &format!("{}KEY_IDS", &info)).expect("Can't calculate SAS MAC");
 
     match flow_id {
         FlowId::ToDevice(s) => AnyToDeviceEventContent::KeyVerificationMac(
@@ -346,25 +369,42 @@ fn extra_info_sas(
     flow_id: &str,
     we_started: bool,
 ) -> String {
-    let our_info = format!("{}|{}|{}", ids.account.user_id(), ids.account.device_id(), own_pubkey);
-    let their_info =
-        format!("{}|{}|{}", ids.other_device.user_id(), ids.other_device.device_id(), their_pubkey);
+    let our_info = (ids.account.user_id(), ids.account.device_id(), own_pubkey);
+    let their_info = (ids.other_device.user_id(), ids.other_device.device_id(), their_pubkey);
 
     let (first_info, second_info) =
         if we_started { (our_info, their_info) } else { (their_info, our_info) };
 
-    let info = format!(
-        "MATRIX_KEY_VERIFICATION_SAS|{first_info}|{second_info}|{flow_id}",
-        first_info = first_info,
-        second_info = second_info,
-        flow_id = flow_id,
-    );
+    let info = sas_info(first_info, second_info, flow_id);
 
     trace!("Generated a SAS extra info: {}", info);
 
     info
 }
 
+/// Get the extra info that is used to generate the bytes of the short auth
+/// string.
+///
+/// # Arguments
+///
+/// * `first` - The user id, device id and ephemeral public key of the side
+/// that started the verification.
+///
+/// * `second` - The user id, device id and ephemeral public key of the other
+/// side.
+///
+/// * `flow_id` - The unique id that identifies this SAS verification process.
+pub fn sas_info(
+    (first_user, first_device, first_key): (&UserId, &DeviceId, &str),
+    (second_user, second_device, second_key): (&UserId, &DeviceId, &str),
+    flow_id: &str,
+) -> String {
+    format!(
+        "MATRIX_KEY_VERIFICATION_SAS|{}|{}|{}|{}|{}|{}|{}",
+        first_user, first_device, first_key, second_user, second_device, second_key, flow_id
+    )
+}
+
 /// Get the emoji version of the short authentication string.
 ///
 /// Returns seven tuples where the first element is the emoji and the
@@ -438,7 +478,7 @@ pub fn get_emoji_index(
     bytes_to_emoji_index(bytes)
 }
 
-fn bytes_to_emoji_index(bytes: Vec<u8>) -> [u8; 7] {
+pub fn bytes_to_emoji_index(bytes: Vec<u8>) -> [u8; 7] {
     let bytes: Vec<u64> = bytes.iter().map(|b| *b as u64).collect();
     // Join the 6 bytes into one 64 bit unsigned int. This u64 will contain 48
     // bits from our 6 bytes.
@@ -513,7 +553,7 @@ pub fn get_decimal(
     bytes_to_decimal(bytes)
 }
 
-fn bytes_to_decimal(bytes: Vec<u8>) -> (u16, u16, u16) {
+pub fn bytes_to_decimal(bytes: Vec<u8>) -> (u16, u16, u16) {
     let bytes: Vec<u16> = bytes.into_iter().map(|b| b as u16).collect();
 
     // This bitwise operation is taken from the [spec]
@@ -17,11 +17,15 @@ mod inner_sas;
 mod sas_state;
 
 use std::sync::{Arc, Mutex};
-#[cfg(test)]
-use std::time::Instant;
 
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use helpers::{
+    bytes_to_decimal, bytes_to_emoji_index, calculate_commitment, key_ids_mac_input, mac_info,
+    sas_info,
+};
 use inner_sas::InnerSas;
-use matrix_sdk_common::uuid::Uuid;
+#[cfg(test)]
+use matrix_sdk_common::instant::Instant;
 use ruma::{
     api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
     events::{
@@ -41,6 +45,7 @@ use super::{
     FlowId, IdentitiesBeingVerified, VerificationResult,
 };
 use crate::{
+    environment::random_uuid,
     identities::{ReadOnlyDevice, UserIdentities},
     olm::PrivateCrossSigningIdentity,
     requests::{OutgoingVerificationRequest, RoomMessageRequest},
@@ -278,7 +283,7 @@ impl Sas {
             }
             OwnedAcceptContent::Room(room_id, content) => RoomMessageRequest {
                 room_id,
-                txn_id: Uuid::new_v4(),
+                txn_id: random_uuid(),
                 content: AnyMessageEventContent::KeyVerificationAccept(content),
             }
             .into(),
@@ -310,7 +315,7 @@ impl Sas {
         let mac_request = content.map(|c| match c {
             OutgoingContent::ToDevice(c) => self.content_to_request(c).into(),
             OutgoingContent::Room(r, c) => {
-                RoomMessageRequest { room_id: r, txn_id: Uuid::new_v4(), content: c }.into()
+                RoomMessageRequest { room_id: r, txn_id: random_uuid(), content: c }.into()
             }
         });
 
@@ -356,7 +361,7 @@ impl Sas {
         *guard = sas;
         content.map(|c| match c {
             OutgoingContent::Room(room_id, content) => {
-                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+                RoomMessageRequest { room_id, txn_id: random_uuid(), content }.into()
             }
             OutgoingContent::ToDevice(c) => self.content_to_request(c).into(),
         })
@@ -16,10 +16,10 @@ use std::{
     convert::TryFrom,
     matches,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::instant::Instant;
 use olm_rs::sas::OlmSas;
 use ruma::{
     events::{
@@ -52,6 +52,7 @@ use super::{
     OutgoingContent,
 };
 use crate::{
+    environment::{now, random_uuid},
     identities::{ReadOnlyDevice, UserIdentities},
     verification::{
         event_enums::{
@@ -357,8 +358,7 @@ impl SasState<Created> {
         transaction_id: Option<String>,
     ) -> SasState<Created> {
         let started_from_request = transaction_id.is_some();
-        let flow_id =
-            FlowId::ToDevice(transaction_id.unwrap_or_else(|| Uuid::new_v4().to_string()));
+        let flow_id = FlowId::ToDevice(transaction_id.unwrap_or_else(|| random_uuid().to_string()));
         Self::new_helper(flow_id, account, other_device, other_identity, started_from_request)
     }
 
@@ -397,8 +397,8 @@ impl SasState<Created> {
             ids: SasIds { account, other_device, other_identity },
             verification_flow_id: flow_id.into(),
 
-            creation_time: Arc::new(Instant::now()),
-            last_event_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(now()),
+            last_event_time: Arc::new(now()),
             started_from_request,
 
             state: Arc::new(Created {
@@ -502,8 +502,8 @@ impl SasState<Started> {
         let canceled = || SasState {
             inner: Arc::new(Mutex::new(OlmSas::new())),
 
-            creation_time: Arc::new(Instant::now()),
-            last_event_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(now()),
+            last_event_time: Arc::new(now()),
             started_from_request,
 
             ids: SasIds {
@@ -533,8 +533,8 @@ impl SasState<Started> {
 
                     ids: SasIds { account, other_device, other_identity },
 
-                    creation_time: Arc::new(Instant::now()),
-                    last_event_time: Arc::new(Instant::now()),
+                    creation_time: Arc::new(now()),
+                    last_event_time: Arc::new(now()),
                     started_from_request,
 
                     verification_flow_id: flow_id,
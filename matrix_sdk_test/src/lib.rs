@@ -14,8 +14,11 @@ use serde_json::Value as JsonValue;
 
 #[cfg(feature = "appservice")]
 pub mod appservice;
+mod scripted_server;
 pub mod test_json;
 
+pub use scripted_server::{RecordedRequest, RequestMatcher, ScriptedServer};
+
 /// Embedded event files
 #[derive(Debug)]
 pub enum EventsJson {
@@ -0,0 +1,360 @@
+//! A scripted mock homeserver.
+//!
+//! The [`ScriptedServer`] answers the requests of a client with a predefined
+//! sequence of sync responses and with canned responses for the other
+//! endpoints. It works on plain [`http`] requests and responses, so it can be
+//! plugged into any HTTP layer of a client, e.g. a custom `HttpSend`
+//! implementation.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use http::{Method, Request, Response, StatusCode};
+use serde_json::{json, Value as JsonValue};
+
+/// The path prefix of the sync endpoint.
+const SYNC_PATH: &str = "/_matrix/client/r0/sync";
+
+/// The path prefix of the endpoint to send to-device events.
+const TO_DEVICE_PATH: &str = "/_matrix/client/r0/sendToDevice/";
+
+type BodyPredicate = dyn Fn(&JsonValue) -> bool + Send + Sync;
+
+/// Decides which requests an expectation or a canned response of a
+/// [`ScriptedServer`] applies to.
+#[derive(Clone)]
+pub struct RequestMatcher {
+    method: Method,
+    path_prefix: String,
+    body: Option<JsonValue>,
+    predicates: Vec<Arc<BodyPredicate>>,
+}
+
+impl fmt::Debug for RequestMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestMatcher")
+            .field("method", &self.method)
+            .field("path_prefix", &self.path_prefix)
+            .field("body", &self.body)
+            .field("predicates", &self.predicates.len())
+            .finish()
+    }
+}
+
+impl RequestMatcher {
+    /// Match requests with the given method whose path starts with the given
+    /// prefix, e.g. `/_matrix/client/r0/keys/upload`.
+    pub fn new(method: Method, path_prefix: &str) -> Self {
+        Self { method, path_prefix: path_prefix.to_owned(), body: None, predicates: Vec::new() }
+    }
+
+    /// Match requests that send to-device events of the given type, e.g.
+    /// `m.key.verification.request`.
+    pub fn to_device(event_type: &str) -> Self {
+        Self::new(Method::PUT, &format!("{}{}/", TO_DEVICE_PATH, event_type))
+    }
+
+    /// Only match requests whose JSON body contains the given JSON.
+    ///
+    /// Objects match if all of the given fields are contained in the body,
+    /// other values have to be equal.
+    pub fn body_contains(mut self, body: JsonValue) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    /// Only match to-device requests that send an event to the given device
+    /// of the given user whose content contains the given JSON, see
+    /// [`body_contains()`](#method.body_contains).
+    ///
+    /// The device id `*` matches events that are sent to all devices of the
+    /// user.
+    pub fn to_device_content(self, user_id: &str, device_id: &str, content: JsonValue) -> Self {
+        self.body_contains(json!({ "messages": { user_id: { device_id: content } } }))
+    }
+
+    /// Only match requests whose JSON body fulfills the given predicate.
+    pub fn body_matches(
+        mut self,
+        predicate: impl Fn(&JsonValue) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.predicates.push(Arc::new(predicate));
+        self
+    }
+
+    fn matches(&self, request: &RecordedRequest) -> bool {
+        request.method == self.method
+            && request.path.starts_with(&self.path_prefix)
+            && self.body.as_ref().map_or(true, |b| json_contains(&request.body, b))
+            && self.predicates.iter().all(|p| p(&request.body))
+    }
+}
+
+/// Does the given JSON value contain the expected one.
+fn json_contains(value: &JsonValue, expected: &JsonValue) -> bool {
+    match (value, expected) {
+        (JsonValue::Object(value), JsonValue::Object(expected)) => {
+            expected.iter().all(|(k, e)| value.get(k).map_or(false, |v| json_contains(v, e)))
+        }
+        (value, expected) => value == expected,
+    }
+}
+
+/// A request that was received by a [`ScriptedServer`].
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    /// The method of the request.
+    pub method: Method,
+    /// The path of the request, without the query string.
+    pub path: String,
+    /// The query string of the request, if there is one.
+    pub query: Option<String>,
+    /// The body of the request, `Null` if it's empty or not JSON.
+    pub body: JsonValue,
+}
+
+#[derive(Debug)]
+struct Expectation {
+    matcher: RequestMatcher,
+    status: StatusCode,
+    response: JsonValue,
+    expected: usize,
+    received: usize,
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    syncs: VecDeque<JsonValue>,
+    next_batch: Option<String>,
+    expectations: Vec<Expectation>,
+    canned: Vec<(RequestMatcher, StatusCode, JsonValue)>,
+    requests: Vec<RecordedRequest>,
+    unexpected: Vec<RecordedRequest>,
+}
+
+impl Script {
+    fn respond(&mut self, request: RecordedRequest) -> (StatusCode, JsonValue) {
+        self.requests.push(request.clone());
+
+        if request.method == Method::GET && request.path.starts_with(SYNC_PATH) {
+            return (StatusCode::OK, self.next_sync());
+        }
+
+        if let Some(e) = self
+            .expectations
+            .iter_mut()
+            .find(|e| e.received < e.expected && e.matcher.matches(&request))
+        {
+            e.received += 1;
+            return (e.status, e.response.clone());
+        }
+
+        if let Some((_, status, response)) =
+            self.canned.iter().find(|(m, _, _)| m.matches(&request))
+        {
+            return (*status, response.clone());
+        }
+
+        self.unexpected.push(request);
+
+        (
+            StatusCode::NOT_FOUND,
+            json!({ "errcode": "M_UNRECOGNIZED", "error": "Unexpected request" }),
+        )
+    }
+
+    /// The next scripted sync response, an empty one once all of them were
+    /// sent.
+    fn next_sync(&mut self) -> JsonValue {
+        match self.syncs.pop_front() {
+            Some(response) => {
+                if let Some(token) = response.get("next_batch").and_then(JsonValue::as_str) {
+                    self.next_batch = Some(token.to_owned());
+                }
+
+                response
+            }
+            None => json!({
+                "next_batch": self.next_batch.clone().unwrap_or_else(|| "empty".to_owned()),
+                "device_one_time_keys_count": {},
+            }),
+        }
+    }
+}
+
+/// A mock homeserver that replays a script.
+///
+/// Sync requests are answered with the queued sync responses in order, an
+/// empty sync response is sent once all of them were used up. Other requests
+/// are answered by the first expectation that matches and wasn't fulfilled
+/// yet, or by the first matching canned response. Requests that match neither
+/// are answered with a `404` and make [`verify()`](#method.verify) fail.
+///
+/// The server can be cloned cheaply, all clones share the same script.
+///
+/// # Example
+///
+/// ```rust
+/// use http::{Method, Request};
+/// use matrix_sdk_test::{test_json, RequestMatcher, ScriptedServer};
+/// use serde_json::json;
+///
+/// let server = ScriptedServer::new();
+///
+/// server
+///     .sync(test_json::SYNC.clone())
+///     .respond(RequestMatcher::new(Method::POST, "/_matrix/client/r0/keys/upload"), json!({
+///         "one_time_key_counts": {}
+///     }))
+///     .expect(
+///         RequestMatcher::to_device("m.key.verification.request").to_device_content(
+///             "@alice:localhost",
+///             "*",
+///             json!({ "transaction_id": "1234" }),
+///         ),
+///         json!({}),
+///     );
+///
+/// let request = Request::get("http://localhost/_matrix/client/r0/sync").body(Vec::new()).unwrap();
+/// let response = server.handle(&request);
+///
+/// assert_eq!(response.status(), 200);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedServer {
+    script: Arc<Mutex<Script>>,
+}
+
+impl ScriptedServer {
+    /// Create a new server with an empty script.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a sync response, e.g. one built by an
+    /// [`EventBuilder`](crate::EventBuilder).
+    pub fn sync(&self, response: JsonValue) -> &Self {
+        self.script.lock().unwrap().syncs.push_back(response);
+        self
+    }
+
+    /// Expect a single request matching the given matcher and answer it with
+    /// the given response.
+    pub fn expect(&self, matcher: RequestMatcher, response: JsonValue) -> &Self {
+        self.expect_times(matcher, 1, StatusCode::OK, response)
+    }
+
+    /// Expect the given number of requests matching the given matcher and
+    /// answer them with the given status and response.
+    pub fn expect_times(
+        &self,
+        matcher: RequestMatcher,
+        times: usize,
+        status: StatusCode,
+        response: JsonValue,
+    ) -> &Self {
+        self.script.lock().unwrap().expectations.push(Expectation {
+            matcher,
+            status,
+            response,
+            expected: times,
+            received: 0,
+        });
+        self
+    }
+
+    /// Answer any number of requests matching the given matcher with the
+    /// given response, the requests aren't required to happen.
+    pub fn respond(&self, matcher: RequestMatcher, response: JsonValue) -> &Self {
+        self.script.lock().unwrap().canned.push((matcher, StatusCode::OK, response));
+        self
+    }
+
+    /// Answer the given request according to the script.
+    pub fn handle<B: AsRef<[u8]>>(&self, request: &Request<B>) -> Response<Vec<u8>> {
+        let body = request.body().as_ref();
+
+        let recorded = RecordedRequest {
+            method: request.method().clone(),
+            path: request.uri().path().to_owned(),
+            query: request.uri().query().map(ToOwned::to_owned),
+            body: if body.is_empty() {
+                JsonValue::Null
+            } else {
+                serde_json::from_slice(body).unwrap_or(JsonValue::Null)
+            },
+        };
+
+        let (status, response) = self.script.lock().unwrap().respond(recorded);
+
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(response.to_string().into_bytes())
+            .unwrap()
+    }
+
+    /// All the requests the server received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.script.lock().unwrap().requests.clone()
+    }
+
+    /// The messages of all to-device events of the given type that were sent
+    /// so far, in the form of `(user_id, device_id, content)`.
+    pub fn to_device_messages(&self, event_type: &str) -> Vec<(String, String, JsonValue)> {
+        let matcher = RequestMatcher::to_device(event_type);
+
+        self.requests()
+            .into_iter()
+            .filter(|r| matcher.matches(r))
+            .filter_map(|r| r.body.get("messages").and_then(JsonValue::as_object).cloned())
+            .flat_map(|messages| {
+                messages.into_iter().flat_map(|(user_id, devices)| {
+                    devices
+                        .as_object()
+                        .cloned()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(move |(device_id, content)| (user_id.clone(), device_id, content))
+                })
+            })
+            .collect()
+    }
+
+    /// Has every queued sync response been sent.
+    pub fn syncs_done(&self) -> bool {
+        self.script.lock().unwrap().syncs.is_empty()
+    }
+
+    /// Check that all expected requests were received and that no unexpected
+    /// request was received.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a description of the missing and unexpected requests if
+    /// the script wasn't followed.
+    pub fn verify(&self) {
+        let script = self.script.lock().unwrap();
+
+        let missing: Vec<_> = script
+            .expectations
+            .iter()
+            .filter(|e| e.received < e.expected)
+            .map(|e| format!("{:?} ({} of {} received)", e.matcher, e.received, e.expected))
+            .collect();
+
+        let unexpected: Vec<_> =
+            script.unexpected.iter().map(|r| format!("{} {}", r.method, r.path)).collect();
+
+        if !missing.is_empty() || !unexpected.is_empty() {
+            panic!(
+                "The script of the server wasn't followed\nMissing requests: {:#?}\n\
+                 Unexpected requests: {:#?}",
+                missing, unexpected
+            );
+        }
+    }
+}